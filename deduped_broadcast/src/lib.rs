@@ -17,6 +17,8 @@ where
     total_unfiltered: Arc<AtomicUsize>,
     total_filtered: Arc<AtomicUsize>,
     total_broadcasts: Arc<AtomicUsize>,
+    /// items that were not sent because they were seen inside the window
+    total_suppressed: Arc<AtomicUsize>,
 }
 
 impl<T> DedupedBroadcaster<T>
@@ -24,36 +26,48 @@ where
     T: Clone + Debug + Eq + Hash + PartialEq + Send + Sync + 'static,
 {
     pub fn new(capacity: usize, cache_capacity: usize) -> Arc<Self> {
+        Self::with_window(capacity, cache_capacity, Duration::from_secs(10 * 60))
+    }
+
+    /// An item is a duplicate if it was seen less than `window` ago. Items that keep being seen never leave the window.
+    /// Items that were quiet for longer (or pushed out by `cache_capacity`) are sent again the next time they are seen
+    pub fn with_window(capacity: usize, cache_capacity: usize, window: Duration) -> Arc<Self> {
         let (broadcast_filtered_tx, _) = broadcast::channel(capacity);
 
         let cache = CacheBuilder::new(cache_capacity as u64)
-            .time_to_idle(Duration::from_secs(10 * 60))
+            .time_to_idle(window)
             .name("DedupedBroadcaster")
             .build();
 
         let total_unfiltered = Arc::new(AtomicUsize::new(0));
         let total_filtered = Arc::new(AtomicUsize::new(0));
         let total_broadcasts = Arc::new(AtomicUsize::new(0));
+        let total_suppressed = Arc::new(AtomicUsize::new(0));
 
         let x = Self {
             broadcast_filtered_tx,
             cache,
             total_broadcasts,
             total_filtered,
+            total_suppressed,
             total_unfiltered,
         };
 
         Arc::new(x)
     }
 
-    /// filter duplicates and send the rest to any subscribers
+    /// filter duplicates and send the rest to any subscribers. true if this item had not been seen recently
     /// TODO: change this to be `send` and put a moka cache here instead of lru. then the de-dupe load will be spread across senders
-    pub async fn send(&self, item: T) {
+    pub async fn send(&self, item: T) -> bool {
         // this is just a debug counter so Relaxed is probably fine
         self.total_unfiltered.fetch_add(1, Ordering::SeqCst);
 
+        let mut first = false;
+
         self.cache
             .get_with(item.clone(), async {
+                first = true;
+
                 // this is just a debug counter so Relaxed is probably fine
                 self.total_filtered.fetch_add(1, Ordering::SeqCst);
 
@@ -63,11 +77,40 @@ where
                 }
             })
             .await;
+
+        if !first {
+            // this is just a debug counter so Relaxed is probably fine
+            self.total_suppressed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        first
+    }
+
+    /// forget that this item was seen and send it again. true if there were subscribers
+    pub async fn resend(&self, item: T) -> bool {
+        self.cache.invalidate(&item).await;
+
+        self.send(item).await
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<T> {
         self.broadcast_filtered_tx.subscribe()
     }
+
+    /// number of items waiting to be received by the slowest subscriber
+    pub fn queued(&self) -> usize {
+        self.broadcast_filtered_tx.len()
+    }
+
+    /// number of recently seen items kept for deduplication
+    pub fn dedupe_entries(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    /// number of items that were not sent because they were duplicates
+    pub fn suppressed(&self) -> usize {
+        self.total_suppressed.load(Ordering::SeqCst)
+    }
 }
 
 impl<T> Debug for DedupedBroadcaster<T>
@@ -88,6 +131,10 @@ where
                 "total_broadcasts",
                 &self.total_broadcasts.load(Ordering::SeqCst),
             )
+            .field(
+                "total_suppressed",
+                &self.total_suppressed.load(Ordering::SeqCst),
+            )
             .field(
                 "subscriptions",
                 &self.broadcast_filtered_tx.receiver_count(),
@@ -104,7 +151,7 @@ where
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("DedupedBroadcaster", 4)?;
+        let mut state = serializer.serialize_struct("DedupedBroadcaster", 5)?;
 
         state.serialize_field(
             "total_unfiltered",
@@ -118,6 +165,10 @@ where
             "total_broadcasts",
             &self.total_broadcasts.load(Ordering::SeqCst),
         )?;
+        state.serialize_field(
+            "total_suppressed",
+            &self.total_suppressed.load(Ordering::SeqCst),
+        )?;
         state.serialize_field(
             "subscriptions",
             &self.broadcast_filtered_tx.receiver_count(),
@@ -140,13 +191,13 @@ mod tests {
         let mut receiver_1 = broadcaster.subscribe();
         let _receiver_2 = broadcaster.subscribe();
 
-        broadcaster.send(1).await;
-        broadcaster.send(1).await;
-        broadcaster.send(2).await;
-        broadcaster.send(1).await;
-        broadcaster.send(2).await;
-        broadcaster.send(3).await;
-        broadcaster.send(3).await;
+        assert!(broadcaster.send(1).await);
+        assert!(!broadcaster.send(1).await);
+        assert!(broadcaster.send(2).await);
+        assert!(!broadcaster.send(1).await);
+        assert!(!broadcaster.send(2).await);
+        assert!(broadcaster.send(3).await);
+        assert!(!broadcaster.send(3).await);
 
         yield_now().await;
 
@@ -159,5 +210,55 @@ mod tests {
         assert_eq!(broadcaster.total_unfiltered.load(Ordering::SeqCst), 7);
         assert_eq!(broadcaster.total_filtered.load(Ordering::SeqCst), 3);
         assert_eq!(broadcaster.total_broadcasts.load(Ordering::SeqCst), 6);
+        assert_eq!(broadcaster.suppressed(), 4);
+    }
+
+    #[tokio::test]
+    async fn same_item_from_many_senders() {
+        let broadcaster = DedupedBroadcaster::new(10, 10);
+
+        let mut receiver = broadcaster.subscribe();
+
+        // like several backends announcing the same pending transaction at once
+        let senders: Vec<_> = (0..5)
+            .map(|_| {
+                let broadcaster = broadcaster.clone();
+
+                tokio::spawn(async move { broadcaster.send(1).await })
+            })
+            .collect();
+
+        let mut firsts = 0;
+        for x in senders {
+            if x.await.unwrap() {
+                firsts += 1;
+            }
+        }
+
+        assert_eq!(firsts, 1);
+        assert_eq!(broadcaster.suppressed(), 4);
+
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn sent_again_after_the_window() {
+        let broadcaster = DedupedBroadcaster::with_window(10, 10, Duration::from_millis(100));
+
+        let mut receiver = broadcaster.subscribe();
+
+        assert!(broadcaster.send(1).await);
+        assert!(!broadcaster.send(1).await);
+
+        // moka's clock isn't tokio's, so this has to really sleep
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // quiet for longer than the window. it is announced again
+        assert!(broadcaster.send(1).await);
+
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        assert!(receiver.try_recv().is_err());
     }
 }
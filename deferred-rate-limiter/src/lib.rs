@@ -2,30 +2,102 @@
 use moka::future::{Cache, CacheBuilder};
 use redis_rate_limiter::{RedisRateLimitResult, RedisRateLimiter};
 use std::cmp::Eq;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::Hash;
 use std::sync::atomic::Ordering;
-use std::sync::{atomic::AtomicU64, Arc};
-use tokio::sync::Mutex;
-use tokio::time::{Duration, Instant};
-use tracing::error;
+use std::sync::{atomic::AtomicU64, Arc, Mutex, Weak};
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+use tracing::{error, trace};
+
+/// how often the background task sends batched increments to redis
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(50);
+
+/// how many increments a key may collect locally before a request waits for redis
+pub const DEFAULT_MAX_LOCAL_BURST: u64 = 100;
+
+/// Local state for one key.
+/// The hot path only touches these atomics. Redis is updated by a background task.
+#[derive(Debug, Default)]
+struct LocalCount {
+    /// the count that we think redis has (including `pending`)
+    count: AtomicU64,
+    /// increments that have not been sent to redis yet
+    pending: AtomicU64,
+    /// which period `count` is for
+    period: AtomicU64,
+    /// the limit from the most recent throttle. sent along with `pending`
+    max_requests_per_period: AtomicU64,
+}
+
+impl LocalCount {
+    fn new(period: u64) -> Self {
+        Self {
+            period: AtomicU64::new(period),
+            ..Default::default()
+        }
+    }
+
+    /// reset the counts if we have moved into a new period
+    fn maybe_rollover(&self, period: u64) {
+        let old_period = self.period.load(Ordering::Acquire);
+
+        if old_period != period
+            && self
+                .period
+                .compare_exchange(old_period, period, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Release);
+        }
+    }
+
+    /// set the count to what redis told us plus anything that came in while the request was in flight
+    fn reconcile(&self, redis_count: u64) {
+        let pending = self.pending.load(Ordering::Acquire);
+
+        self.count.store(redis_count + pending, Ordering::Release);
+    }
+}
+
+/// keys that have pending increments
+type DirtyKeys<K> = Mutex<HashMap<K, Arc<LocalCount>>>;
 
 /// A local cache that sits in front of a RedisRateLimiter
 /// Generic accross the key so it is simple to use with IPs or user keys
+///
+/// Throttling only touches local atomics. A background task batches the increments to redis every `sync_interval`
+/// and reconciles the local counts from the response. If a key collects more than `max_local_burst` increments
+/// between syncs, the request that crosses the line waits on redis.
 pub struct DeferredRateLimiter<K>
 where
     K: Send + Sync,
 {
-    local_cache: Cache<K, Arc<AtomicU64>>,
+    local_cache: Cache<K, Arc<LocalCount>>,
     prefix: String,
     rrl: RedisRateLimiter,
     /// if None, defers to the max on rrl
     default_max_requests_per_period: Option<u64>,
+    /// keys with increments that still need to be sent to redis
+    dirty_keys: Arc<DirtyKeys<K>>,
+    /// the maximum number of increments that a key may have pending before forcing a sync
+    max_local_burst: u64,
+}
+
+/// What is left of a key's limit in the current period.
+/// This is the local count, so other servers' usage only shows up after a sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitQuota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// when the period ends and the count starts over
+    pub reset: Instant,
 }
 
 pub enum DeferredRateLimitResult {
-    Allowed,
-    RetryAt(Instant),
+    Allowed(RateLimitQuota),
+    /// the quota's `reset` is when they can retry. `remaining` is always 0
+    RetryAt(RateLimitQuota),
     RetryNever,
 }
 
@@ -39,23 +111,123 @@ where
         prefix: &str,
         rrl: RedisRateLimiter,
         default_max_requests_per_second: Option<u64>,
+        sync_interval: Option<Duration>,
+        max_local_burst: Option<u64>,
     ) -> Self {
         let ttl = rrl.period as u64;
 
-        // TODO: time to live is not exactly right. we want this ttl counter to start only after redis is down. this works for now
         // TODO: what do these weigh?
         // TODO: allow skipping max_capacity
-        // TODO: prefix instead of a static str
         let local_cache = CacheBuilder::new(cache_size.try_into().unwrap())
             .time_to_live(Duration::from_secs(ttl))
             .name(&format!("DeferredRateLimiter-{}", prefix))
             .build();
 
+        let dirty_keys: Arc<DirtyKeys<K>> = Default::default();
+
+        // the task exits once the limiter (and so the last strong ref to dirty_keys) is dropped
+        tokio::spawn(Self::sync_loop(
+            Arc::downgrade(&dirty_keys),
+            prefix.to_string(),
+            rrl.clone(),
+            // a zero interval would panic
+            sync_interval
+                .unwrap_or(DEFAULT_SYNC_INTERVAL)
+                .max(Duration::from_millis(1)),
+        ));
+
         Self {
             local_cache,
             prefix: prefix.to_string(),
             rrl,
             default_max_requests_per_period: default_max_requests_per_second,
+            dirty_keys,
+            max_local_burst: max_local_burst.unwrap_or(DEFAULT_MAX_LOCAL_BURST).max(1),
+        }
+    }
+
+    /// number of keys with a local count
+    pub fn num_local_keys(&self) -> u64 {
+        self.local_cache.entry_count()
+    }
+
+    fn redis_label(prefix: &str, key: K) -> String {
+        format!("{}:{}", prefix, key)
+    }
+
+    fn current_period(&self) -> u64 {
+        (self.rrl.now_as_secs() / self.rrl.period) as u64
+    }
+
+    fn quota(&self, limit: u64, count: u64) -> RateLimitQuota {
+        RateLimitQuota {
+            limit,
+            remaining: limit.saturating_sub(count),
+            reset: self.rrl.next_period(self.rrl.now_as_secs()),
+        }
+    }
+
+    /// send all pending increments to redis in one pipeline
+    async fn sync_loop(
+        dirty_keys: Weak<DirtyKeys<K>>,
+        prefix: String,
+        rrl: RedisRateLimiter,
+        sync_interval: Duration,
+    ) {
+        let mut interval = interval(sync_interval);
+
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let batch = match dirty_keys.upgrade() {
+                None => break,
+                Some(dirty_keys) => std::mem::take(&mut *dirty_keys.lock().unwrap()),
+            };
+
+            if batch.is_empty() {
+                continue;
+            }
+
+            let mut labels = Vec::with_capacity(batch.len());
+            let mut locals = Vec::with_capacity(batch.len());
+
+            for (key, local) in batch.into_iter() {
+                let pending = local.pending.swap(0, Ordering::AcqRel);
+
+                if pending == 0 {
+                    continue;
+                }
+
+                let max_requests_per_period = local.max_requests_per_period.load(Ordering::Acquire);
+
+                labels.push((
+                    Self::redis_label(&prefix, key),
+                    max_requests_per_period,
+                    pending,
+                ));
+                locals.push(local);
+            }
+
+            trace!(num_keys = labels.len(), "syncing deferred rate limits");
+
+            match rrl.throttle_labels(&labels).await {
+                Ok(results) => {
+                    for (local, x) in locals.iter().zip(results) {
+                        match x {
+                            RedisRateLimitResult::Allowed(count)
+                            | RedisRateLimitResult::RetryAt(_, count) => local.reconcile(count),
+                            RedisRateLimitResult::RetryNever => {}
+                        }
+                    }
+                }
+                Err(err) => {
+                    // don't let redis errors block our users!
+                    // the increments stay in the local counts until the period rolls over
+                    error!(?err, "unable to sync deferred rate limits to redis");
+                }
+            }
         }
     }
 
@@ -76,138 +248,334 @@ where
             return Ok(DeferredRateLimitResult::RetryNever);
         }
 
-        // TODO: i do not like having this in a mutex at all
-        let deferred_rate_limit_result = Arc::new(Mutex::new(None));
-
-        let redis_key = format!("{}:{}", self.prefix, key);
-
-        // TODO: i'm sure this could be a lot better. but race conditions make this hard to think through. brain needs sleep
-        let local_key_count: Arc<AtomicU64> = {
-            // clone things outside of the `async move`
-            let deferred_rate_limit_result = deferred_rate_limit_result.clone();
-            let redis_key = redis_key.clone();
-            let rrl = Arc::new(self.rrl.clone());
-
-            // set arc_deferred_rate_limit_result and return the count
-            self.local_cache
-                .try_get_with_by_ref::<_, anyhow::Error, _>(&key, async move {
-                    // we do not use the try operator here because we want to be okay with redis errors
-                    let redis_count = match rrl
-                        .throttle_label(&redis_key, Some(max_requests_per_period), count)
-                        .await
-                    {
-                        Ok(RedisRateLimitResult::Allowed(count)) => {
-                            let _ = deferred_rate_limit_result
-                                .lock()
-                                .await
-                                .insert(DeferredRateLimitResult::Allowed);
-                            count
-                        }
-                        Ok(RedisRateLimitResult::RetryAt(retry_at, count)) => {
-                            let _ = deferred_rate_limit_result
-                                .lock()
-                                .await
-                                .insert(DeferredRateLimitResult::RetryAt(retry_at));
-                            count
-                        }
-                        Ok(RedisRateLimitResult::RetryNever) => {
-                            unreachable!();
-                        }
-                        Err(err) => {
-                            let _ = deferred_rate_limit_result
-                                .lock()
-                                .await
-                                .insert(DeferredRateLimitResult::Allowed);
-
-                            // if we get a redis error, just let the user through.
-                            // if users are sticky on a server, local caches will work well enough
-                            // though now that we do this, we need to reset rate limits every minute! cache must have ttl!
-                            error!("unable to rate limit! creating empty cache. err={:?}", err);
-                            0
-                        }
-                    };
+        let period = self.current_period();
+
+        // no redis here. a new key starts at 0 and gets reconciled on the next sync
+        let local = self
+            .local_cache
+            .get_with_by_ref(&key, async move { Arc::new(LocalCount::new(period)) })
+            .await;
+
+        local.maybe_rollover(period);
+
+        local
+            .max_requests_per_period
+            .store(max_requests_per_period, Ordering::Release);
+
+        let expected_key_count = local.count.fetch_add(count, Ordering::AcqRel) + count;
+
+        if expected_key_count > max_requests_per_period {
+            // rate limit overshot!
+            // do not fetch_sub. the next sync will set the count to whatever redis has
 
-                    Ok(Arc::new(AtomicU64::new(redis_count)))
+            // show that we are rate limited without even querying redis
+            return Ok(DeferredRateLimitResult::RetryAt(
+                self.quota(max_requests_per_period, expected_key_count),
+            ));
+        }
+
+        let pending = local.pending.fetch_add(count, Ordering::AcqRel) + count;
+
+        if pending >= self.max_local_burst {
+            // this key is bursting faster than we sync. wait on redis so other servers can't overshoot by much
+            return Ok(self
+                .force_sync(key, &local, max_requests_per_period, expected_key_count)
+                .await);
+        }
+
+        if pending == count {
+            // first pending increment since the last sync. mark this key for the background task
+            self.dirty_keys.lock().unwrap().insert(key, local.clone());
+        }
+
+        Ok(DeferredRateLimitResult::Allowed(
+            self.quota(max_requests_per_period, expected_key_count),
+        ))
+    }
+
+    /// send this key's pending increments to redis now instead of waiting for the background task.
+    /// `local_count` is only used for the quota if redis can't be reached
+    async fn force_sync(
+        &self,
+        key: K,
+        local: &LocalCount,
+        max_requests_per_period: u64,
+        local_count: u64,
+    ) -> DeferredRateLimitResult {
+        let pending = local.pending.swap(0, Ordering::AcqRel);
+
+        if pending == 0 {
+            // the background task beat us to it
+            return DeferredRateLimitResult::Allowed(
+                self.quota(max_requests_per_period, local_count),
+            );
+        }
+
+        let redis_label = Self::redis_label(&self.prefix, key);
+
+        match self
+            .rrl
+            .throttle_label(&redis_label, Some(max_requests_per_period), pending)
+            .await
+        {
+            Ok(RedisRateLimitResult::Allowed(count)) => {
+                local.reconcile(count);
+                DeferredRateLimitResult::Allowed(self.quota(max_requests_per_period, count))
+            }
+            Ok(RedisRateLimitResult::RetryAt(retry_at, count)) => {
+                local.reconcile(count);
+                DeferredRateLimitResult::RetryAt(RateLimitQuota {
+                    limit: max_requests_per_period,
+                    remaining: 0,
+                    reset: retry_at,
                 })
-                .await
-                .map_err(|x| anyhow::anyhow!("cache error! {}", x))?
+            }
+            Ok(RedisRateLimitResult::RetryNever) => DeferredRateLimitResult::RetryNever,
+            Err(err) => {
+                // don't let redis errors block our users!
+                error!(
+                    "unable to query rate limits, but local cache is available. key={} err={:?}",
+                    key, err,
+                );
+                DeferredRateLimitResult::Allowed(self.quota(max_requests_per_period, local_count))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis_rate_limiter::{DeadpoolRuntime, MemoryStore, RedisConfig};
+
+    fn memory_rrl(store: &Arc<MemoryStore>, max_requests_per_period: u64) -> RedisRateLimiter {
+        RedisRateLimiter::with_store(
+            "test",
+            "deferred",
+            max_requests_per_period,
+            60.0,
+            store.clone(),
+        )
+    }
+
+    /// a redis that never answers. connecting to it hangs until the timeout
+    fn blackhole_rrl(max_requests_per_period: u64) -> RedisRateLimiter {
+        let pool = RedisConfig::from_url("redis://10.255.255.1:6379")
+            .create_pool(Some(DeadpoolRuntime::Tokio1))
+            .unwrap();
+
+        RedisRateLimiter::new("test", "deferred", max_requests_per_period, 60.0, pool)
+    }
+
+    #[tokio::test]
+    async fn throttle_does_not_wait_on_redis() {
+        let drl = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            blackhole_rrl(1_000),
+            None,
+            Some(Duration::from_millis(10)),
+            Some(1_000),
+        )
+        .await;
+
+        let mut latencies = Vec::with_capacity(500);
+
+        for _ in 0..500 {
+            let start = Instant::now();
+
+            let x = drl.throttle(1, None, 1).await.unwrap();
+
+            latencies.push(start.elapsed());
+
+            assert!(matches!(x, DeferredRateLimitResult::Allowed(_)));
+        }
+
+        latencies.sort();
+
+        let p99 = latencies[latencies.len() * 99 / 100];
+
+        assert!(p99 < Duration::from_millis(5), "p99 was {:?}", p99);
+    }
+
+    #[tokio::test]
+    async fn throttle_limits_locally() {
+        let drl = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            blackhole_rrl(10),
+            None,
+            Some(Duration::from_secs(60)),
+            Some(1_000),
+        )
+        .await;
+
+        for _ in 0..10 {
+            assert!(matches!(
+                drl.throttle(1, None, 1).await.unwrap(),
+                DeferredRateLimitResult::Allowed(_)
+            ));
+        }
+
+        assert!(matches!(
+            drl.throttle(1, None, 1).await.unwrap(),
+            DeferredRateLimitResult::RetryAt(_)
+        ));
+
+        // other keys are unaffected
+        assert!(matches!(
+            drl.throttle(2, None, 1).await.unwrap(),
+            DeferredRateLimitResult::Allowed(_)
+        ));
+
+        assert!(matches!(
+            drl.throttle(3, Some(0), 1).await.unwrap(),
+            DeferredRateLimitResult::RetryNever
+        ));
+    }
+
+    #[tokio::test]
+    async fn throttle_returns_the_quota() {
+        let drl = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            blackhole_rrl(10),
+            None,
+            Some(Duration::from_secs(60)),
+            Some(1_000),
+        )
+        .await;
+
+        let DeferredRateLimitResult::Allowed(x) = drl.throttle(1, None, 3).await.unwrap() else {
+            panic!("should be allowed");
         };
 
-        let mut locked = deferred_rate_limit_result.lock().await;
-
-        if let Some(deferred_rate_limit_result) = locked.take() {
-            // new entry. redis was already incremented
-            // return the retry_at that we got from
-            Ok(deferred_rate_limit_result)
-        } else {
-            // we have a cached amount here
-            let cached_key_count = local_key_count.fetch_add(count, Ordering::SeqCst);
-
-            // assuming no other parallel futures incremented this key, this is the count that redis has
-            let expected_key_count = cached_key_count + count;
-
-            if expected_key_count > max_requests_per_period {
-                // rate limit overshot!
-                let now = self.rrl.now_as_secs();
-
-                // do not fetch_sub
-                // another row might have queued a redis throttle_label to keep our count accurate
-
-                // show that we are rate limited without even querying redis
-                let retry_at = self.rrl.next_period(now);
-                Ok(DeferredRateLimitResult::RetryAt(retry_at))
-            } else {
-                // local caches think rate limit should be okay
-
-                // prepare a future to update redis
-                let rate_limit_f = {
-                    let rrl = self.rrl.clone();
-                    async move {
-                        match rrl
-                            .throttle_label(&redis_key, Some(max_requests_per_period), count)
-                            .await
-                        {
-                            Ok(RedisRateLimitResult::Allowed(count)) => {
-                                local_key_count.store(count, Ordering::SeqCst);
-                                DeferredRateLimitResult::Allowed
-                            }
-                            Ok(RedisRateLimitResult::RetryAt(retry_at, count)) => {
-                                local_key_count.store(count, Ordering::SeqCst);
-                                DeferredRateLimitResult::RetryAt(retry_at)
-                            }
-                            Ok(RedisRateLimitResult::RetryNever) => {
-                                // TODO: what should we do to arc_key_count?
-                                DeferredRateLimitResult::RetryNever
-                            }
-                            Err(err) => {
-                                // don't let redis errors block our users!
-                                error!(
-                                    "unable to query rate limits, but local cache is available. key={} err={:?}",
-                                    key,
-                                    err,
-                                );
-                                // TODO: we need to start a timer that resets this count every minute
-                                DeferredRateLimitResult::Allowed
-                            }
-                        }
-                    }
-                };
-
-                // if close to max_per_period, wait for redis
-                // TODO: how close should we allow? depends on max expected concurent requests from one user
-                let limit: f64 = (max_requests_per_period as f64 * 0.99)
-                    .min(max_requests_per_period as f64 - 1.0);
-                if expected_key_count > limit as u64 {
-                    // close to period. don't risk it. wait on redis
-                    Ok(rate_limit_f.await)
-                } else {
-                    // rate limit has enough headroom that it should be safe to do this in the background
-                    // TODO: send an error here somewhere
-                    tokio::spawn(rate_limit_f);
-
-                    Ok(DeferredRateLimitResult::Allowed)
-                }
-            }
+        assert_eq!(x.limit, 10);
+        assert_eq!(x.remaining, 7);
+        assert!(x.reset > Instant::now());
+        assert!(x.reset <= Instant::now() + Duration::from_secs(60));
+
+        // the limit passed to throttle wins over the default
+        let DeferredRateLimitResult::Allowed(x) = drl.throttle(1, Some(20), 1).await.unwrap()
+        else {
+            panic!("should be allowed");
+        };
+
+        assert_eq!(x.limit, 20);
+        assert_eq!(x.remaining, 16);
+
+        let DeferredRateLimitResult::RetryAt(x) = drl.throttle(1, None, 7).await.unwrap() else {
+            panic!("should be limited");
+        };
+
+        assert_eq!(x.limit, 10);
+        assert_eq!(x.remaining, 0);
+    }
+
+    /// two servers sharing a store see each other's usage after a sync
+    #[tokio::test]
+    async fn sync_reconciles_from_store() {
+        let store = Arc::new(MemoryStore::default());
+
+        let a = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            memory_rrl(&store, 10),
+            None,
+            Some(Duration::from_millis(10)),
+            Some(1_000),
+        )
+        .await;
+
+        let b = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            memory_rrl(&store, 10),
+            None,
+            Some(Duration::from_millis(10)),
+            Some(1_000),
+        )
+        .await;
+
+        for _ in 0..6 {
+            assert!(matches!(
+                a.throttle(1, None, 1).await.unwrap(),
+                DeferredRateLimitResult::Allowed(_)
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // b hasn't seen anything locally, so these are allowed
+        for _ in 0..6 {
+            assert!(matches!(
+                b.throttle(1, None, 1).await.unwrap(),
+                DeferredRateLimitResult::Allowed(_)
+            ));
         }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // but the store said no, and b's local count now includes a's usage
+        assert!(matches!(
+            b.throttle(1, None, 1).await.unwrap(),
+            DeferredRateLimitResult::RetryAt(_)
+        ));
+
+        // a forced sync goes to the store too
+        let c = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            memory_rrl(&store, 10),
+            None,
+            Some(Duration::from_secs(60)),
+            Some(5),
+        )
+        .await;
+
+        assert!(matches!(
+            c.throttle(1, None, 5).await.unwrap(),
+            DeferredRateLimitResult::RetryAt(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn zero_sync_interval_still_syncs() {
+        let store = Arc::new(MemoryStore::default());
+
+        let a = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            memory_rrl(&store, 10),
+            None,
+            Some(Duration::ZERO),
+            Some(1_000),
+        )
+        .await;
+
+        for _ in 0..6 {
+            assert!(matches!(
+                a.throttle(1, None, 1).await.unwrap(),
+                DeferredRateLimitResult::Allowed(_)
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the sync task didn't panic. a's counts reached the store
+        let b = DeferredRateLimiter::<u64>::new(
+            100,
+            "test",
+            memory_rrl(&store, 10),
+            None,
+            Some(Duration::from_secs(60)),
+            Some(5),
+        )
+        .await;
+
+        assert!(matches!(
+            b.throttle(1, None, 5).await.unwrap(),
+            DeferredRateLimitResult::RetryAt(_)
+        ));
     }
 }
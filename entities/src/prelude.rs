@@ -15,5 +15,6 @@ pub use super::rpc_accounting_v2::Entity as RpcAccountingV2;
 pub use super::rpc_key::Entity as RpcKey;
 pub use super::secondary_user::Entity as SecondaryUser;
 pub use super::stripe_increase_balance_receipt::Entity as StripeIncreaseBalanceReceipt;
+pub use super::tx_origin::Entity as TxOrigin;
 pub use super::user::Entity as User;
 pub use super::user_tier::Entity as UserTier;
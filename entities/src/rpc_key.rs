@@ -29,6 +29,28 @@ pub struct Model {
     pub allowed_user_agents: Option<String>,
     #[sea_orm(column_type = "Double")]
     pub log_revert_chance: f64,
+    pub sign_responses: bool,
+    pub require_jsonrpc_2: bool,
+    pub allow_batches: bool,
+    pub allow_websocket: bool,
+    pub skip_chain_id_check: bool,
+    pub label: Option<String>,
+    /// json array of methods this key may call. `"debug_*"` matches every method starting with `debug_`. null allows all
+    #[sea_orm(column_type = "Text", nullable)]
+    pub allowed_methods: Option<String>,
+    /// json array of methods this key may not call. checked before `allowed_methods`
+    #[sea_orm(column_type = "Text", nullable)]
+    pub denied_methods: Option<String>,
+    /// requests allowed each utc day. null for no daily quota
+    pub max_requests_per_day: Option<u64>,
+    /// requests allowed each utc calendar month. null for no monthly quota
+    pub max_requests_per_month: Option<u64>,
+    /// go straight to a backend instead of waiting on an identical request that is already in flight. only honored if the tier allows it
+    pub skip_request_coalescing: bool,
+    /// only for admins. never shown to the key's owner
+    #[sea_orm(column_type = "Text", nullable)]
+    #[serde(skip)]
+    pub internal_tags: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -41,6 +63,8 @@ pub enum Relation {
     RpcAccountingV2,
     #[sea_orm(has_many = "super::secondary_user::Entity")]
     SecondaryUser,
+    #[sea_orm(has_many = "super::tx_origin::Entity")]
+    TxOrigin,
     #[sea_orm(
         belongs_to = "super::user::Entity",
         from = "Column::UserId",
@@ -75,6 +99,12 @@ impl Related<super::secondary_user::Entity> for Entity {
     }
 }
 
+impl Related<super::tx_origin::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::TxOrigin.def()
+    }
+}
+
 impl Related<super::user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::User.def()
@@ -12,6 +12,10 @@ pub struct Model {
     pub max_requests_per_period: Option<u64>,
     pub max_concurrent_requests: Option<u32>,
     pub downgrade_tier_id: Option<u64>,
+    pub allow_archive: bool,
+    pub allow_trace: bool,
+    /// if false, `rpc_key.skip_request_coalescing` is ignored
+    pub allow_skip_request_coalescing: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -43,6 +43,15 @@ mod m20230726_072845_default_premium_user_tier;
 mod m20230726_162138_drop_rpc_accounting_v2_fk;
 mod m20230726_225124_reduce_out_of_funds_tier_limits;
 mod m20230911_180520_high_concurrency_tier;
+mod m20231117_130213_tx_origin;
+mod m20231201_120000_rpc_key_sign_responses;
+mod m20231204_120000_rpc_key_protocol_requirements;
+mod m20231205_120000_rpc_key_skip_chain_id_check;
+mod m20231206_120000_rpc_key_labels;
+mod m20231206_130000_skip_request_coalescing;
+mod m20231207_120000_user_tier_entitlements;
+mod m20231208_120000_rpc_key_method_lists;
+mod m20231209_120000_rpc_key_quotas;
 
 pub struct Migrator;
 
@@ -93,6 +102,15 @@ impl MigratorTrait for Migrator {
             Box::new(m20230726_162138_drop_rpc_accounting_v2_fk::Migration),
             Box::new(m20230726_225124_reduce_out_of_funds_tier_limits::Migration),
             Box::new(m20230911_180520_high_concurrency_tier::Migration),
+            Box::new(m20231117_130213_tx_origin::Migration),
+            Box::new(m20231201_120000_rpc_key_sign_responses::Migration),
+            Box::new(m20231204_120000_rpc_key_protocol_requirements::Migration),
+            Box::new(m20231205_120000_rpc_key_skip_chain_id_check::Migration),
+            Box::new(m20231206_120000_rpc_key_labels::Migration),
+            Box::new(m20231206_130000_skip_request_coalescing::Migration),
+            Box::new(m20231207_120000_user_tier_entitlements::Migration),
+            Box::new(m20231208_120000_rpc_key_method_lists::Migration),
+            Box::new(m20231209_120000_rpc_key_quotas::Migration),
         ]
     }
 }
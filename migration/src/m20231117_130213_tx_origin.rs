@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // which rpc key sent a transaction. kept for a limited time for compliance lookups
+        manager
+            .create_table(
+                Table::create()
+                    .table(TxOrigin::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(TxOrigin::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(TxOrigin::TxHash).binary_len(32).not_null())
+                    // null for transactions sent without an rpc key
+                    .col(ColumnDef::new(TxOrigin::RpcKeyId).big_unsigned().null())
+                    .col(ColumnDef::new(TxOrigin::IpHash).binary_len(32).not_null())
+                    .col(ColumnDef::new(TxOrigin::ChainId).big_unsigned().not_null())
+                    .col(
+                        ColumnDef::new(TxOrigin::Timestamp)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(sea_query::Index::create().col(TxOrigin::TxHash))
+                    // the retention sweeper deletes by timestamp
+                    .index(sea_query::Index::create().col(TxOrigin::Timestamp))
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(TxOrigin::Table, TxOrigin::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(TxOrigin::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum TxOrigin {
+    Table,
+    Id,
+    TxHash,
+    RpcKeyId,
+    IpHash,
+    ChainId,
+    Timestamp,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+}
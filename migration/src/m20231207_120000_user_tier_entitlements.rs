@@ -0,0 +1,67 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // every existing tier keeps archive and trace access
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(
+                        ColumnDef::new(UserTier::AllowArchive)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .add_column(
+                        ColumnDef::new(UserTier::AllowTrace)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // except for users that haven't paid
+        for title in ["Free", "Premium Out Of Funds"] {
+            let update_tier = Query::update()
+                .table(UserTier::Table)
+                .values([
+                    (UserTier::AllowArchive, false.into()),
+                    (UserTier::AllowTrace, false.into()),
+                ])
+                .and_where(Expr::col(UserTier::Title).eq(title))
+                .to_owned();
+
+            manager.exec_stmt(update_tier).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::AllowArchive)
+                    .drop_column(UserTier::AllowTrace)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    Title,
+    AllowArchive,
+    AllowTrace,
+}
@@ -0,0 +1,140 @@
+use crate::memory::MemoryStore;
+use crate::store::{BoxFuture, RateLimitStore, ThrottleKey};
+use crate::RedisRateLimitResult;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// Counts in `primary` (usually redis). While it is failing, counts are kept in memory instead.
+///
+/// The in-memory counts are only for this process, so limits are looser across servers during an outage. That is
+/// better than no limits at all or failing every request.
+pub struct FallbackStore {
+    primary: Arc<dyn RateLimitStore>,
+    fallback: MemoryStore,
+    falling_back: AtomicBool,
+    /// checks that were answered in memory
+    pub fallbacks: AtomicU64,
+}
+
+impl FallbackStore {
+    pub fn new(primary: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            primary,
+            fallback: MemoryStore::default(),
+            falling_back: AtomicBool::new(false),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// true if the last check failed on the primary store
+    pub fn is_falling_back(&self) -> bool {
+        self.falling_back.load(Ordering::Relaxed)
+    }
+}
+
+impl RateLimitStore for FallbackStore {
+    fn throttle<'a>(
+        &'a self,
+        keys: &'a [ThrottleKey],
+        period: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<RedisRateLimitResult>>> {
+        Box::pin(async move {
+            match self.primary.throttle(keys, period).await {
+                Ok(x) => {
+                    if self.falling_back.swap(false, Ordering::Relaxed) {
+                        info!("rate limit store is back. limits are shared again");
+                    }
+
+                    Ok(x)
+                }
+                Err(err) => {
+                    // only log the start of an outage. every request would fail the same way
+                    if !self.falling_back.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            ?err,
+                            "rate limit store failed. limits are counted in memory on this server until it is back"
+                        );
+                    }
+
+                    self.fallbacks.fetch_add(1, Ordering::Relaxed);
+
+                    self.fallback.throttle(keys, period).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fails while `down` is set. otherwise everything is allowed
+    #[derive(Default)]
+    struct FlakyStore {
+        down: AtomicBool,
+    }
+
+    impl RateLimitStore for FlakyStore {
+        fn throttle<'a>(
+            &'a self,
+            keys: &'a [ThrottleKey],
+            _period: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<Vec<RedisRateLimitResult>>> {
+            let x = if self.down.load(Ordering::Relaxed) {
+                Err(anyhow::anyhow!("connection refused"))
+            } else {
+                Ok(keys
+                    .iter()
+                    .map(|_| RedisRateLimitResult::Allowed(1))
+                    .collect())
+            };
+
+            Box::pin(std::future::ready(x))
+        }
+    }
+
+    async fn allowed(store: &FallbackStore) -> bool {
+        let key = ThrottleKey {
+            key: "a".to_string(),
+            max_per_period: 2,
+            count: 1,
+        };
+
+        let mut x = store
+            .throttle(&[key], Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        matches!(x.pop().unwrap(), RedisRateLimitResult::Allowed(_))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn falls_back_to_memory() {
+        let primary = Arc::new(FlakyStore::default());
+
+        let store = FallbackStore::new(primary.clone());
+
+        // the primary allows everything
+        for _ in 0..5 {
+            assert!(allowed(&store).await);
+        }
+        assert!(!store.is_falling_back());
+
+        primary.down.store(true, Ordering::Relaxed);
+
+        // limits are still enforced while it is down
+        assert!(allowed(&store).await);
+        assert!(allowed(&store).await);
+        assert!(!allowed(&store).await);
+        assert!(store.is_falling_back());
+        assert_eq!(store.fallbacks.load(Ordering::Relaxed), 3);
+
+        primary.down.store(false, Ordering::Relaxed);
+
+        assert!(allowed(&store).await);
+        assert!(!store.is_falling_back());
+    }
+}
@@ -1,6 +1,7 @@
 //#![warn(missing_docs)]
 use anyhow::Context;
 use std::ops::Add;
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
 
 pub use deadpool_redis::redis;
@@ -9,6 +10,17 @@ pub use deadpool_redis::{
     Pool as RedisPool, PoolError as RedisPoolError, Runtime as DeadpoolRuntime,
 };
 
+mod fallback;
+mod memory;
+mod store;
+
+pub use fallback::FallbackStore;
+pub use memory::MemoryStore;
+pub use store::{BoxFuture, RateLimitStore, RedisStore, ThrottleKey};
+
+/// Counts requests per label over a period.
+///
+/// Despite the name, the counts can be kept anywhere that implements `RateLimitStore`. `new` keeps them in redis.
 #[derive(Clone)]
 pub struct RedisRateLimiter {
     key_prefix: String,
@@ -16,7 +28,7 @@ pub struct RedisRateLimiter {
     pub max_requests_per_period: u64,
     /// seconds
     pub period: f32,
-    pool: RedisPool,
+    store: Arc<dyn RateLimitStore>,
 }
 
 pub enum RedisRateLimitResult {
@@ -27,6 +39,21 @@ pub enum RedisRateLimitResult {
     RetryNever,
 }
 
+pub(crate) fn now_as_secs() -> f32 {
+    // TODO: if system time doesn't match redis, this won't work great
+    (chrono::Utc::now().timestamp_millis() as f32) / 1_000.0
+}
+
+pub(crate) fn period_id(now_as_secs: f32, period: f32) -> f32 {
+    (now_as_secs / period) % period
+}
+
+pub(crate) fn next_period(now_as_secs: f32, period: f32) -> Instant {
+    let seconds_left_in_period = period - (now_as_secs % period);
+
+    Instant::now().add(Duration::from_secs_f32(seconds_left_in_period))
+}
+
 impl RedisRateLimiter {
     pub fn new(
         app: &str,
@@ -34,11 +61,28 @@ impl RedisRateLimiter {
         max_requests_per_period: u64,
         period: f32,
         pool: RedisPool,
+    ) -> Self {
+        Self::with_store(
+            app,
+            label,
+            max_requests_per_period,
+            period,
+            Arc::new(RedisStore::new(pool)),
+        )
+    }
+
+    /// many limiters can share one store. the app and label keep their keys apart
+    pub fn with_store(
+        app: &str,
+        label: &str,
+        max_requests_per_period: u64,
+        period: f32,
+        store: Arc<dyn RateLimitStore>,
     ) -> Self {
         let key_prefix = format!("{}:rrl:{}", app, label);
 
         Self {
-            pool,
+            store,
             key_prefix,
             max_requests_per_period,
             period,
@@ -46,18 +90,19 @@ impl RedisRateLimiter {
     }
 
     pub fn now_as_secs(&self) -> f32 {
-        // TODO: if system time doesn't match redis, this won't work great
-        (chrono::Utc::now().timestamp_millis() as f32) / 1_000.0
+        now_as_secs()
     }
 
     pub fn period_id(&self, now_as_secs: f32) -> f32 {
-        (now_as_secs / self.period) % self.period
+        period_id(now_as_secs, self.period)
     }
 
     pub fn next_period(&self, now_as_secs: f32) -> Instant {
-        let seconds_left_in_period = self.period - (now_as_secs % self.period);
+        next_period(now_as_secs, self.period)
+    }
 
-        Instant::now().add(Duration::from_secs_f32(seconds_left_in_period))
+    fn throttle_key(&self, label: &str) -> String {
+        format!("{}:{}", self.key_prefix, label)
     }
 
     /// label might be an ip address or a rpc_key id.
@@ -74,48 +119,63 @@ impl RedisRateLimiter {
             return Ok(RedisRateLimitResult::RetryNever);
         }
 
-        let now = self.now_as_secs();
-
-        // if self.period is 60, period_id will be the minute of the current time
-        let period_id = self.period_id(now);
-
-        // TODO: include max per period in the throttle key?
-        let throttle_key = format!("{}:{}:{}", self.key_prefix, label, period_id);
-
-        let mut conn = self
-            .pool
-            .get()
-            .await
-            .context("get redis connection for rate limits")?;
-
-        // TODO: at high concurency, this gives "connection reset by peer" errors. at least they are off the hot path
-        // TODO: only set expire if this is a new key
-
-        // TODO: automatic retry
-        let x: Vec<_> = redis::pipe()
-            .atomic()
-            // we could get the key first, but that means an extra redis call for every check. this seems better
-            .incr(&throttle_key, count)
-            // set expiration each time we set the key. ignore the result
-            .expire(&throttle_key, 1 + self.period as usize)
-            // TODO: NX will make it only set the expiration the first time. works in redis, but not elasticache
-            // .arg("NX")
-            .ignore()
-            // do the query
-            .query_async(&mut *conn)
-            .await
-            .context("cannot increment rate limit or set expiration")?;
-
-        let new_count: u64 = *x.first().expect("check redis");
-
-        if new_count > max_per_period {
-            // TODO: this might actually be early if we are way over the count
-            let retry_at = self.next_period(now);
-
-            Ok(RedisRateLimitResult::RetryAt(retry_at, new_count))
+        let keys = [ThrottleKey {
+            key: self.throttle_key(label),
+            max_per_period,
+            count,
+        }];
+
+        let x = self
+            .store
+            .throttle(&keys, Duration::from_secs_f32(self.period))
+            .await?
+            .pop()
+            .context("rate limit store returned no results")?;
+
+        Ok(x)
+    }
+
+    /// throttle many labels in one round trip.
+    /// each item is the label, its max per period, and the count to add.
+    /// returns a result for each label in the same order they were given.
+    pub async fn throttle_labels(
+        &self,
+        labels: &[(String, u64, u64)],
+    ) -> anyhow::Result<Vec<RedisRateLimitResult>> {
+        // labels with a max of 0 never reach the store
+        let keys: Vec<_> = labels
+            .iter()
+            .filter(|(_, max_per_period, _)| *max_per_period > 0)
+            .map(|(label, max_per_period, count)| ThrottleKey {
+                key: self.throttle_key(label),
+                max_per_period: *max_per_period,
+                count: *count,
+            })
+            .collect();
+
+        let mut results = if keys.is_empty() {
+            vec![]
         } else {
-            Ok(RedisRateLimitResult::Allowed(new_count))
+            self.store
+                .throttle(&keys, Duration::from_secs_f32(self.period))
+                .await?
         }
+        .into_iter();
+
+        let x = labels
+            .iter()
+            .map(|(_, max_per_period, _)| {
+                if *max_per_period == 0 {
+                    Ok(RedisRateLimitResult::RetryNever)
+                } else {
+                    results
+                        .next()
+                        .context("rate limit store returned too few results")
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(x)
     }
 
     #[inline]
@@ -0,0 +1,206 @@
+use crate::store::{BoxFuture, RateLimitStore, ThrottleKey};
+use crate::RedisRateLimitResult;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// how often keys that have fully drained are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// In-process GCRA (generic cell rate algorithm).
+///
+/// Each key only stores its "theoretical arrival time". Every request pushes it forward by `period / max_per_period`
+/// and a request is allowed if that leaves it no more than one period ahead of now. Unlike redis' fixed windows,
+/// there is no burst of twice the limit across a window boundary.
+///
+/// Counts are per process. Only use this when a single server is handling the traffic.
+pub struct MemoryStore {
+    inner: Mutex<MemoryStoreInner>,
+}
+
+struct MemoryStoreInner {
+    tats: HashMap<String, Instant>,
+    last_prune: Instant,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(MemoryStoreInner {
+                tats: Default::default(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl MemoryStore {
+    /// number of keys that still have usage
+    pub fn num_keys(&self) -> usize {
+        self.inner.lock().unwrap().tats.len()
+    }
+}
+
+impl MemoryStoreInner {
+    fn throttle_one(
+        &mut self,
+        x: &ThrottleKey,
+        period: Duration,
+        now: Instant,
+    ) -> RedisRateLimitResult {
+        let emission_interval =
+            Duration::from_nanos((period.as_nanos() / x.max_per_period as u128).max(1) as u64);
+
+        let tat = self.tats.get(&x.key).copied().unwrap_or(now).max(now);
+
+        let new_tat =
+            tat + emission_interval.saturating_mul(x.count.try_into().unwrap_or(u32::MAX));
+
+        // roughly how many requests are in the last period
+        let count = (new_tat - now)
+            .as_nanos()
+            .div_ceil(emission_interval.as_nanos()) as u64;
+
+        if new_tat - now <= period {
+            self.tats.insert(x.key.clone(), new_tat);
+
+            RedisRateLimitResult::Allowed(count)
+        } else {
+            // denied requests don't use up any capacity
+            let retry_at = now + (new_tat - now - period);
+
+            RedisRateLimitResult::RetryAt(retry_at, count)
+        }
+    }
+
+    /// drop keys that have fully drained. they are the same as a missing key
+    fn maybe_prune(&mut self, now: Instant) {
+        if now - self.last_prune < PRUNE_INTERVAL {
+            return;
+        }
+
+        self.tats.retain(|_, tat| *tat > now);
+
+        self.last_prune = now;
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn throttle<'a>(
+        &'a self,
+        keys: &'a [ThrottleKey],
+        period: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<RedisRateLimitResult>>> {
+        let now = Instant::now();
+
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.maybe_prune(now);
+
+        let x = keys
+            .iter()
+            .map(|x| inner.throttle_one(x, period, now))
+            .collect();
+
+        Box::pin(std::future::ready(Ok(x)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str, max_per_period: u64, count: u64) -> ThrottleKey {
+        ThrottleKey {
+            key: key.to_string(),
+            max_per_period,
+            count,
+        }
+    }
+
+    async fn throttle(store: &MemoryStore, x: ThrottleKey) -> RedisRateLimitResult {
+        let mut x = store.throttle(&[x], Duration::from_secs(60)).await.unwrap();
+
+        x.pop().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gcra() {
+        let store = MemoryStore::default();
+
+        for i in 1..=10 {
+            match throttle(&store, key("a", 10, 1)).await {
+                RedisRateLimitResult::Allowed(count) => assert_eq!(count, i),
+                _ => panic!("request {} should be allowed", i),
+            }
+        }
+
+        let start = Instant::now();
+
+        match throttle(&store, key("a", 10, 1)).await {
+            RedisRateLimitResult::RetryAt(retry_at, count) => {
+                assert_eq!(count, 11);
+                // one request drains every 6 seconds
+                assert_eq!(retry_at - start, Duration::from_secs(6));
+            }
+            _ => panic!("request 11 should be limited"),
+        }
+
+        // other keys are unaffected
+        assert!(matches!(
+            throttle(&store, key("b", 10, 1)).await,
+            RedisRateLimitResult::Allowed(1)
+        ));
+
+        // capacity comes back gradually instead of all at once at the end of a window
+        tokio::time::advance(Duration::from_secs(6)).await;
+
+        assert!(matches!(
+            throttle(&store, key("a", 10, 1)).await,
+            RedisRateLimitResult::Allowed(10)
+        ));
+        assert!(matches!(
+            throttle(&store, key("a", 10, 1)).await,
+            RedisRateLimitResult::RetryAt(..)
+        ));
+
+        // a full period later, everything has drained
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        assert!(matches!(
+            throttle(&store, key("a", 10, 5)).await,
+            RedisRateLimitResult::Allowed(5)
+        ));
+
+        // drained keys are pruned
+        tokio::time::advance(Duration::from_secs(120)).await;
+
+        assert!(matches!(
+            throttle(&store, key("c", 10, 1)).await,
+            RedisRateLimitResult::Allowed(1)
+        ));
+        assert_eq!(store.num_keys(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_larger_than_limit() {
+        let store = MemoryStore::default();
+
+        let x = store
+            .throttle(
+                &[key("a", 10, 11), key("b", 10, 10)],
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(x[0], RedisRateLimitResult::RetryAt(_, 11)));
+        assert!(matches!(x[1], RedisRateLimitResult::Allowed(10)));
+
+        // the denied batch didn't use any capacity
+        assert!(matches!(
+            throttle(&store, key("a", 10, 10)).await,
+            RedisRateLimitResult::Allowed(10)
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{next_period, now_as_secs, period_id, RedisRateLimitResult};
+use anyhow::Context;
+use deadpool_redis::{redis, Pool as RedisPool};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::time::Duration;
+
+/// boxed so that stores can be used as trait objects without pulling in async-trait
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// One key to check in `RateLimitStore::throttle`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThrottleKey {
+    /// the full key. includes the limiter's prefix
+    pub key: String,
+    /// never 0. the limiter answers those with `RetryNever` without asking the store
+    pub max_per_period: u64,
+    pub count: u64,
+}
+
+/// Where rate limit counts are kept.
+///
+/// Redis shares the counts between every server. The in-memory store is only for a single server, but needs nothing
+/// else running.
+pub trait RateLimitStore: Send + Sync {
+    /// Add `count` to each key and compare it to that key's `max_per_period`.
+    /// Returns one result for each key in the same order they were given. Never returns `RetryNever`.
+    fn throttle<'a>(
+        &'a self,
+        keys: &'a [ThrottleKey],
+        period: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<RedisRateLimitResult>>>;
+}
+
+/// Fixed window counts in redis. The key for each window expires after the window ends.
+pub struct RedisStore {
+    pool: RedisPool,
+}
+
+impl RedisStore {
+    pub fn new(pool: RedisPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl RateLimitStore for RedisStore {
+    fn throttle<'a>(
+        &'a self,
+        keys: &'a [ThrottleKey],
+        period: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<RedisRateLimitResult>>> {
+        Box::pin(async move {
+            let period = period.as_secs_f32();
+
+            let now = now_as_secs();
+
+            // if period is 60, period_id will be the minute of the current time
+            let period_id = period_id(now, period);
+
+            let mut pipe = redis::pipe();
+
+            pipe.atomic();
+
+            // TODO: at high concurency, this gives "connection reset by peer" errors. at least they are off the hot path
+            // TODO: only set expire if this is a new key
+            for x in keys.iter() {
+                // TODO: include max per period in the throttle key?
+                let throttle_key = format!("{}:{}", x.key, period_id);
+
+                // we could get the key first, but that means an extra redis call for every check. this seems better
+                pipe.incr(&throttle_key, x.count)
+                    // set expiration each time we set the key. ignore the result
+                    // TODO: NX will make it only set the expiration the first time. works in redis, but not elasticache
+                    .expire(&throttle_key, 1 + period as usize)
+                    .ignore();
+            }
+
+            let mut conn = self
+                .pool
+                .get()
+                .await
+                .context("get redis connection for rate limits")?;
+
+            // TODO: automatic retry
+            let counts: Vec<u64> = pipe
+                .query_async(&mut *conn)
+                .await
+                .context("cannot increment rate limits or set expiration")?;
+
+            // TODO: this might actually be early if we are way over the count
+            let retry_at = next_period(now, period);
+
+            let x = keys
+                .iter()
+                .zip(counts)
+                .map(|(x, count)| {
+                    if count > x.max_per_period {
+                        RedisRateLimitResult::RetryAt(retry_at, count)
+                    } else {
+                        RedisRateLimitResult::Allowed(count)
+                    }
+                })
+                .collect();
+
+            Ok(x)
+        })
+    }
+}
@@ -0,0 +1,139 @@
+//! How long to wait before trying a failed request again.
+//!
+//! When the proxy sheds load, its error's `data` is a [`Backpressure`] that says why and how long to wait. The
+//! policy here waits for at least that long, and gives up on waits that are too long to be worth it.
+
+use crate::types::{Backpressure, ShedReason};
+use serde_json::Value;
+use std::time::Duration;
+
+impl Backpressure {
+    /// The backpressure in a jsonrpc error's `data`. None if the error wasn't from shed load
+    pub fn from_error_data(data: &Value) -> Option<Self> {
+        serde_json::from_value(data.clone()).ok()
+    }
+
+    /// The backpressure in a whole jsonrpc error response
+    pub fn from_error_body(body: &str) -> Option<Self> {
+        let x: Value = serde_json::from_str(body).ok()?;
+
+        Self::from_error_data(x.get("error")?.get("data")?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    /// The first wait when the proxy didn't say how long to wait. Each retry waits twice as long as the one before
+    pub initial_backoff: Duration,
+    /// Our own waits never grow past this. The proxy's `retry_after` can be longer
+    pub max_backoff: Duration,
+    /// Give up instead of waiting longer than this. Long maintenance windows and rate limits aren't worth waiting out
+    pub max_wait: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_wait: Duration::from_secs(30),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Exponential backoff for the 0-indexed `attempt`
+    pub fn exponential(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait before the next attempt. None if the request shouldn't be retried at all
+    pub fn delay(&self, backpressure: Option<&Backpressure>, attempt: u32) -> Option<Duration> {
+        let delay = match backpressure {
+            None => self.exponential(attempt),
+            Some(x) => match x.reason {
+                // the proxy knows when there will be room again. waiting longer doesn't help
+                ShedReason::RateLimited | ShedReason::Maintenance | ShedReason::Standby => {
+                    x.retry_after()
+                }
+                // everyone else is probably retrying too. spread out
+                ShedReason::ProxyOverloaded | ShedReason::BackendSaturated => {
+                    x.retry_after().max(self.exponential(attempt))
+                }
+            },
+        };
+
+        (delay <= self.max_wait).then_some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shed(reason: ShedReason, retry_after_ms: u64) -> Backpressure {
+        Backpressure::new(reason, Duration::from_millis(retry_after_ms), None)
+    }
+
+    #[test]
+    fn parse_error_body() {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {
+                "code": 429,
+                "message": "too many requests",
+                "data": {
+                    "reason": "rate_limited",
+                    "retry_after_ms": 1500,
+                    "retry_after": 2,
+                    "request_id": "abc",
+                    "ip": "127.0.0.1",
+                },
+            },
+        })
+        .to_string();
+
+        let x = Backpressure::from_error_body(&body).unwrap();
+
+        assert_eq!(x.reason, ShedReason::RateLimited);
+        assert_eq!(x.retry_after(), Duration::from_millis(1500));
+        assert_eq!(x.retry_after, 2);
+        assert_eq!(x.request_id.as_deref(), Some("abc"));
+
+        // other errors don't have backpressure
+        assert!(Backpressure::from_error_data(&json!({"request": null})).is_none());
+        assert!(Backpressure::from_error_body("not json").is_none());
+    }
+
+    #[test]
+    fn delays() {
+        let x = BackoffPolicy::default();
+
+        assert_eq!(x.delay(None, 0), Some(Duration::from_millis(100)));
+        assert_eq!(x.delay(None, 3), Some(Duration::from_millis(800)));
+        assert_eq!(x.delay(None, 30), Some(x.max_backoff));
+
+        // the proxy's wait is used as is
+        let rate_limited = shed(ShedReason::RateLimited, 2_000);
+        assert_eq!(
+            x.delay(Some(&rate_limited), 5),
+            Some(Duration::from_secs(2))
+        );
+
+        // overloaded waits at least as long as the proxy asked
+        let overloaded = shed(ShedReason::ProxyOverloaded, 1_000);
+        assert_eq!(x.delay(Some(&overloaded), 0), Some(Duration::from_secs(1)));
+        assert_eq!(
+            x.delay(Some(&overloaded), 5),
+            Some(Duration::from_secs(3) + Duration::from_millis(200))
+        );
+
+        // long maintenance isn't worth waiting for
+        let maintenance = shed(ShedReason::Maintenance, 3_600_000);
+        assert_eq!(x.delay(Some(&maintenance), 0), None);
+    }
+}
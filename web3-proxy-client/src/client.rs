@@ -0,0 +1,344 @@
+use crate::backoff::BackoffPolicy;
+use crate::errors::{ClientError, ClientResult};
+use crate::types::{
+    AdminBackends, AdminLogFilterPut, AdminResponseCacheDelete, AdminResponseCachePost,
+    AdminStandbyPost, AdminSummary, BackendDisabled, BackendScoresReport, CallCacheDeleted,
+    Estimate, LogFilterStatus, ResponseCacheDeleted, ResponseCacheStatus, ResponseCacheWrites,
+    StandbyStatus, TxStatus,
+};
+use ethers::types::{Address, TxHash};
+use reqwest::{Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, trace};
+use url::Url;
+
+/// Talks to one web3-proxy server.
+///
+/// Every endpoint here is safe to send twice, so failed requests are retried with exponential backoff. When the proxy
+/// sheds load, it says how long to wait and that is used instead.
+#[derive(Clone, Debug)]
+pub struct Web3ProxyClient {
+    base_url: Url,
+    http: reqwest::Client,
+    bearer_token: Option<String>,
+    rpc_key: Option<String>,
+    max_retries: u32,
+    backoff: BackoffPolicy,
+}
+
+impl Web3ProxyClient {
+    pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+    /// `base_url` is where the server's routes are mounted. Usually something like `https://example.com/`
+    pub fn new(base_url: Url) -> Self {
+        Self {
+            base_url,
+            http: reqwest::Client::new(),
+            bearer_token: None,
+            rpc_key: None,
+            max_retries: Self::DEFAULT_MAX_RETRIES,
+            backoff: Default::default(),
+        }
+    }
+
+    /// Use this reqwest client instead of a default one. Useful for setting timeouts
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// The bearer token from `/user/login`. The user and admin endpoints need one
+    pub fn with_bearer_token(mut self, bearer_token: impl Into<String>) -> Self {
+        self.bearer_token = Some(bearer_token.into());
+        self
+    }
+
+    /// The rpc key to use in `rpc_url`
+    pub fn with_rpc_key(mut self, rpc_key: impl Into<String>) -> Self {
+        self.rpc_key = Some(rpc_key.into());
+        self
+    }
+
+    /// 0 never retries. Each retry waits twice as long as the one before
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.backoff.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// How long to wait between retries, and which waits are too long to bother with
+    pub fn with_backoff_policy(mut self, backoff: BackoffPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Where to send jsonrpc requests. Uses the rpc key if there is one
+    pub fn rpc_url(&self) -> ClientResult<Url> {
+        match self.rpc_key.as_ref() {
+            Some(rpc_key) => self.url(&format!("rpc/{}", rpc_key)),
+            None => Ok(self.base_url.clone()),
+        }
+    }
+
+    /// `GET /status/tx/:tx_hash` -- Where a transaction relayed by this server is
+    pub async fn tx_status(&self, tx_hash: TxHash) -> ClientResult<TxStatus> {
+        self.request::<(), _>(
+            Method::GET,
+            &format!("status/tx/{:?}", tx_hash),
+            false,
+            None,
+        )
+        .await
+    }
+
+    /// `POST /user/estimate` -- What a jsonrpc request (or batch) would be charged. Nothing is sent or charged
+    pub async fn estimate<T: Serialize>(&self, request: &T) -> ClientResult<Estimate> {
+        self.request(Method::POST, "user/estimate", true, Some(request))
+            .await
+    }
+
+    /// `GET /admin/summary`
+    pub async fn admin_summary(&self) -> ClientResult<AdminSummary> {
+        self.request::<(), _>(Method::GET, "admin/summary", true, None)
+            .await
+    }
+
+    /// `GET /admin/backends`
+    pub async fn admin_backends(&self) -> ClientResult<AdminBackends> {
+        self.request::<(), _>(Method::GET, "admin/backends", true, None)
+            .await
+    }
+
+    /// `POST /admin/backends/:name/disable` -- Take a backend out of rotation until it is enabled or the server restarts
+    pub async fn admin_disable_backend(&self, name: &str) -> ClientResult<BackendDisabled> {
+        self.request::<(), _>(
+            Method::POST,
+            &format!("admin/backends/{}/disable", name),
+            true,
+            None,
+        )
+        .await
+    }
+
+    /// `POST /admin/backends/:name/enable`
+    pub async fn admin_enable_backend(&self, name: &str) -> ClientResult<BackendDisabled> {
+        self.request::<(), _>(
+            Method::POST,
+            &format!("admin/backends/{}/enable", name),
+            true,
+            None,
+        )
+        .await
+    }
+
+    /// `GET /admin/backends/scores` -- The latest backend scores and suggested config changes
+    pub async fn admin_backend_scores(&self) -> ClientResult<BackendScoresReport> {
+        self.request::<(), _>(Method::GET, "admin/backends/scores", true, None)
+            .await
+    }
+
+    /// `GET /admin/log_filter`
+    pub async fn admin_log_filter(&self) -> ClientResult<LogFilterStatus> {
+        self.request::<(), _>(Method::GET, "admin/log_filter", true, None)
+            .await
+    }
+
+    /// `PUT /admin/log_filter` -- Change the server's log filter. It goes back to the startup filter after `revert_after`
+    pub async fn admin_set_log_filter(
+        &self,
+        filter: impl Into<String>,
+        revert_after: Option<Duration>,
+    ) -> ClientResult<LogFilterStatus> {
+        let x = AdminLogFilterPut {
+            filter: filter.into(),
+            revert_after_secs: revert_after.map(|x| x.as_secs()),
+        };
+
+        self.request(Method::PUT, "admin/log_filter", true, Some(&x))
+            .await
+    }
+
+    /// `GET /admin/response_cache`
+    pub async fn admin_response_cache(&self) -> ClientResult<ResponseCacheStatus> {
+        self.request::<(), _>(Method::GET, "admin/response_cache", true, None)
+            .await
+    }
+
+    /// `POST /admin/response_cache` -- Pause or resume writes to the response cache
+    pub async fn admin_set_response_cache_writes(
+        &self,
+        writes: bool,
+    ) -> ClientResult<ResponseCacheWrites> {
+        self.request(
+            Method::POST,
+            "admin/response_cache",
+            true,
+            Some(&AdminResponseCachePost { writes }),
+        )
+        .await
+    }
+
+    /// `DELETE /admin/response_cache`
+    pub async fn admin_delete_response_cache(
+        &self,
+        x: &AdminResponseCacheDelete,
+    ) -> ClientResult<ResponseCacheDeleted> {
+        self.request(Method::DELETE, "admin/response_cache", true, Some(x))
+            .await
+    }
+
+    /// `POST /admin/cache/clear` -- Clear the whole response cache
+    pub async fn admin_clear_cache(&self) -> ClientResult<ResponseCacheDeleted> {
+        self.request::<(), _>(Method::POST, "admin/cache/clear", true, None)
+            .await
+    }
+
+    /// `GET /admin/standby`
+    pub async fn admin_standby(&self) -> ClientResult<StandbyStatus> {
+        self.request::<(), _>(Method::GET, "admin/standby", true, None)
+            .await
+    }
+
+    /// `POST /admin/standby` -- `false` promotes the server so that it serves jsonrpc. `true` demotes it
+    pub async fn admin_set_standby(&self, standby: bool) -> ClientResult<StandbyStatus> {
+        self.request(
+            Method::POST,
+            "admin/standby",
+            true,
+            Some(&AdminStandbyPost { standby }),
+        )
+        .await
+    }
+
+    /// `DELETE /admin/call_cache/:address`
+    pub async fn admin_delete_call_cache(
+        &self,
+        address: Address,
+    ) -> ClientResult<CallCacheDeleted> {
+        self.request::<(), _>(
+            Method::DELETE,
+            &format!("admin/call_cache/{:?}", address),
+            true,
+            None,
+        )
+        .await
+    }
+
+    fn url(&self, path: &str) -> ClientResult<Url> {
+        self.base_url
+            .join(path)
+            .map_err(|_| ClientError::InvalidBaseUrl(self.base_url.to_string()))
+    }
+
+    fn build<B: Serialize>(
+        &self,
+        method: Method,
+        url: Url,
+        bearer: bool,
+        body: Option<&B>,
+    ) -> ClientResult<RequestBuilder> {
+        let mut x = self.http.request(method, url);
+
+        if bearer {
+            let bearer_token = self
+                .bearer_token
+                .as_ref()
+                .ok_or(ClientError::NoBearerToken)?;
+
+            x = x.bearer_auth(bearer_token);
+        }
+
+        if let Some(body) = body {
+            x = x.json(body);
+        }
+
+        Ok(x)
+    }
+
+    async fn request<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        bearer: bool,
+        body: Option<&B>,
+    ) -> ClientResult<R> {
+        let url = self.url(path)?;
+
+        let mut attempt = 0;
+
+        loop {
+            let x = self.build(method.clone(), url.clone(), bearer, body)?;
+
+            let err = match send(x).await {
+                Ok(x) => return Ok(x),
+                Err(err) if err.is_retryable() && attempt < self.max_retries => err,
+                Err(err) => return Err(err),
+            };
+
+            let backpressure = err.backpressure();
+
+            let Some(delay) = self.backoff.delay(backpressure.as_ref(), attempt) else {
+                return Err(err);
+            };
+
+            attempt += 1;
+
+            debug!(?err, %url, attempt, ?delay, reason=?backpressure.map(|x| x.reason), "retrying");
+
+            sleep(delay).await;
+        }
+    }
+}
+
+async fn send<R: DeserializeOwned>(x: RequestBuilder) -> ClientResult<R> {
+    let response = x.send().await?;
+
+    let status = response.status();
+
+    trace!(%status, url=%response.url());
+
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+
+        return Err(ClientError::Status { status, body });
+    }
+
+    let x = response.json().await?;
+
+    Ok(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let x = Web3ProxyClient::new("http://127.0.0.1:8544/".parse().unwrap());
+
+        assert_eq!(x.rpc_url().unwrap().as_str(), "http://127.0.0.1:8544/");
+        assert_eq!(
+            x.url("admin/summary").unwrap().as_str(),
+            "http://127.0.0.1:8544/admin/summary"
+        );
+
+        let x = x.with_rpc_key("01H0000000000000000000000A");
+
+        assert_eq!(
+            x.rpc_url().unwrap().as_str(),
+            "http://127.0.0.1:8544/rpc/01H0000000000000000000000A"
+        );
+    }
+
+    #[tokio::test]
+    async fn bearer_is_required() {
+        let x = Web3ProxyClient::new("http://127.0.0.1:1/".parse().unwrap());
+
+        let err = x.admin_summary().await.unwrap_err();
+
+        assert!(matches!(err, ClientError::NoBearerToken), "{:?}", err);
+    }
+}
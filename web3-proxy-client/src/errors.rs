@@ -0,0 +1,51 @@
+use crate::types::Backpressure;
+use derive_more::{Display, Error, From};
+use reqwest::StatusCode;
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Display, Error, From)]
+pub enum ClientError {
+    /// the request never got a response, or the response wasn't what we expected
+    Http(reqwest::Error),
+    #[display(fmt = "the base url can't have paths joined to it: {}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    InvalidBaseUrl(String),
+    #[display(fmt = "this endpoint needs a bearer token")]
+    NoBearerToken,
+    /// the proxy answered with an error. `body` is usually json with the details
+    #[display(fmt = "{}: {}", status, body)]
+    #[from(ignore)]
+    Status { status: StatusCode, body: String },
+}
+
+impl ClientError {
+    /// The status code from the proxy. None if it never answered
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Http(err) => err.status(),
+            Self::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Why the proxy shed this request and how long it asked us to wait. None for other errors
+    pub fn backpressure(&self) -> Option<Backpressure> {
+        match self {
+            Self::Status { body, .. } => Backpressure::from_error_body(body),
+            _ => None,
+        }
+    }
+
+    /// Connection problems, timeouts, overloaded servers, and rate limits are worth trying again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Http(err) => err.is_connect() || err.is_timeout(),
+            Self::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! A typed client for web3-proxy's own endpoints. jsonrpc itself is better handled by ethers with `rpc_url`.
+//!
+//! The request and response types in `types` are the ones the server uses to build its responses.
+mod backoff;
+mod client;
+mod errors;
+pub mod types;
+
+pub use backoff::BackoffPolicy;
+pub use client::Web3ProxyClient;
+pub use errors::{ClientError, ClientResult};
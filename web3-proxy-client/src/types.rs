@@ -0,0 +1,463 @@
+//! Requests and responses for the proxy's own endpoints.
+//!
+//! The server builds its responses out of these same types. Changing a field here changes it on both sides.
+
+use chrono::{DateTime, Utc};
+use ethers::types::{Address, TxHash, H256, U64};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// `GET /status/tx/:tx_hash`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct TxStatus {
+    pub tx_hash: TxHash,
+    pub status: TrackedTx,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum TxState {
+    Pending,
+    Confirmed {
+        block_num: U64,
+        block_hash: H256,
+    },
+    /// the block that confirmed this transaction is no longer part of the chain
+    Orphaned {
+        block_num: U64,
+        block_hash: H256,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct TrackedTx {
+    #[serde(flatten)]
+    pub state: TxState,
+    pub first_seen: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+/// What a request would be charged, before it is sent. Uses the same prices as the accounting stats
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CostEstimate {
+    /// false if the method isn't in the cost table and gets the default price
+    pub known_method: bool,
+    pub archive_request: bool,
+    /// the price if the response is served from the cache. same as `cache_miss` for uncacheable requests
+    pub cache_hit: Decimal,
+    /// the price if a backend has to answer
+    pub cache_miss: Decimal,
+    /// some methods are priced by the size of their response. this much more is charged per byte (on a cache miss)
+    pub per_response_byte: Decimal,
+}
+
+/// One request of a `POST /user/estimate`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RequestEstimate {
+    pub id: Box<RawValue>,
+    pub method: String,
+    #[serde(flatten)]
+    pub estimate: CostEstimate,
+}
+
+/// `POST /user/estimate`. The totals are for the whole batch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Estimate {
+    pub cache_hit: Decimal,
+    pub cache_miss: Decimal,
+    pub requests: Vec<RequestEstimate>,
+    pub usd_per_cu: Decimal,
+}
+
+/// `GET /admin/summary`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AdminSummary {
+    pub backends: BackendSummaries,
+    pub chain_id: u64,
+    pub connectivity: Connectivity,
+    /// load balancer health checks and uptime bots. they aren't in `requests`
+    #[serde(default)]
+    pub exempt_traffic: ExemptTrafficSummary,
+    pub head_block_hash: Option<H256>,
+    pub head_block_num: Option<U64>,
+    pub hostname: Option<String>,
+    pub latency: LatencySummary,
+    pub requests: RecentRequestsSummary,
+    /// None if stats aren't saved anywhere
+    pub stat_buffer: Option<StatBufferSummary>,
+    pub synced: bool,
+    /// seconds
+    pub uptime: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BackendSummaries {
+    pub balanced: Vec<Web3RpcSummary>,
+    pub private: Vec<Web3RpcSummary>,
+}
+
+/// Requests that skipped rate limits and stats. Totals since the server started
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ExemptTrafficSummary {
+    pub head_or_options: u64,
+    pub health: u64,
+    pub cidr: u64,
+    pub user_agent: u64,
+}
+
+/// None means that it is not configured
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Connectivity {
+    pub db: Option<bool>,
+    pub influx: Option<bool>,
+    pub redis: Option<bool>,
+}
+
+/// A few numbers about one rpc for `/admin/summary`. Only in-memory state is read to build this
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Web3RpcSummary {
+    pub name: String,
+    pub backup: bool,
+    pub healthy: bool,
+    pub head_block_num: Option<U64>,
+    /// how many blocks this rpc is behind the consensus head. None if either head is unknown
+    pub lag: Option<u64>,
+    pub active_requests: usize,
+    pub peak_latency_ms: f32,
+}
+
+/// One rpc in `GET /admin/backends`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct BackendStatus {
+    pub name: String,
+    /// the scheme, host, and port. anything that might be a credential is hidden. None for ipc
+    pub url: Option<String>,
+    pub backup: bool,
+    pub head_block_num: Option<U64>,
+    pub soft_limit: u32,
+    /// requests per period. None if there is no hard limit
+    pub hard_limit: Option<u64>,
+    pub active_requests: usize,
+    pub healthy: bool,
+    pub quarantined: bool,
+    /// true while an admin has it out of rotation
+    pub disabled: bool,
+}
+
+/// `GET /admin/backends`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AdminBackends {
+    pub balanced: Vec<BackendStatus>,
+    pub private: Vec<BackendStatus>,
+    pub bundler_4337: Vec<BackendStatus>,
+}
+
+/// `POST /admin/backends/:name/disable` and `POST /admin/backends/:name/enable`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct BackendDisabled {
+    pub name: String,
+    /// "balanced", "private", or "bundler_4337"
+    pub group: String,
+    pub disabled: bool,
+    pub previous: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyClass {
+    /// served without asking a backend
+    CachedRead,
+    UncachedRead,
+    /// sending transactions. these wait on backends that we don't control, so there is no objective for them
+    Write,
+}
+
+impl LatencyClass {
+    pub fn new(method: &str, cache_hit: bool) -> Self {
+        match method {
+            "eth_sendBundle"
+            | "eth_sendPrivateTransaction"
+            | "eth_sendRawTransaction"
+            | "eth_sendRawTransactionConditional"
+            | "eth_sendUserOperation" => Self::Write,
+            _ if cache_hit => Self::CachedRead,
+            _ => Self::UncachedRead,
+        }
+    }
+}
+
+/// one row of `/admin/summary`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LatencyRow {
+    pub class: LatencyClass,
+    pub tier: String,
+    pub requests: u64,
+    pub p50_ms: Option<u64>,
+    /// at `percentile`
+    pub objective_latency_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    pub objective_ms: Option<u64>,
+    pub breached: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub window_secs: u64,
+    pub percentile: u8,
+    pub rows: Vec<LatencyRow>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct MethodCount {
+    pub method: String,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecentRequestsSummary {
+    pub window_secs: u64,
+    pub requests: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// None if there were no requests in the window
+    pub cache_hit_rate: Option<f64>,
+    pub rate_limited: u64,
+    pub rate_limited_per_second: f64,
+    pub in_flight: usize,
+    /// most requested first
+    pub top_methods: Vec<MethodCount>,
+    /// archive and trace requests from tiers that don't include them. most denied first
+    #[serde(default)]
+    pub denied_by_tier: Vec<MethodCount>,
+}
+
+/// What the stat buffer is doing
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct StatBufferSummary {
+    /// aggregated stats that are waiting to be saved
+    pub buffered: usize,
+    /// false if the last save to the relational database failed
+    pub relational_ok: bool,
+    /// accounting rows that failed to save and are waiting to be retried
+    pub relational_retry_queue: usize,
+    /// how long the oldest row in the retry queue has been waiting. None if the queue is empty
+    pub relational_retry_oldest_secs: Option<u64>,
+    /// accounting rows that were given up on because the retry queue was full
+    pub relational_dropped: u64,
+    /// false if the last write to the timeseries database failed
+    pub timeseries_ok: bool,
+}
+
+/// `GET /admin/response_cache`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ResponseCacheStatus {
+    pub entries: u64,
+    pub weighted_size: u64,
+    /// false while writes are paused
+    pub writes: bool,
+}
+
+/// `POST /admin/response_cache`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AdminResponseCachePost {
+    /// false pauses writes to the response cache. cached responses are still served
+    pub writes: bool,
+}
+
+/// `PUT /admin/log_filter`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AdminLogFilterPut {
+    /// same syntax as `RUST_LOG`. like `info,web3_proxy::rpcs=trace`
+    pub filter: String,
+    /// go back to the startup filter after this many seconds. None keeps it until the next change or a restart
+    #[serde(default)]
+    pub revert_after_secs: Option<u64>,
+}
+
+/// `GET /admin/log_filter`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct LogFilterStatus {
+    pub filter: String,
+    /// the filter from startup
+    pub default_filter: String,
+    /// None if the filter stays until the next change or a restart
+    pub revert_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ResponseCacheWrites {
+    pub previous: bool,
+    pub writes: bool,
+}
+
+/// `DELETE /admin/response_cache`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AdminResponseCacheDelete {
+    /// only remove the responses that came from the backend with this name. if None, remove everything
+    #[serde(default)]
+    pub backend: Option<String>,
+    /// only remove the response with this cache key. takes precedence over `backend`
+    #[serde(default)]
+    pub key: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ResponseCacheDeleted {
+    pub backend: Option<String>,
+    pub key: Option<u64>,
+    /// how many this server removed. when clearing everything, this is an estimate
+    pub removed: u64,
+}
+
+/// `DELETE /admin/call_cache/:address`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct CallCacheDeleted {
+    pub address: Address,
+    /// false if the contract isn't in `call_cache`. nothing would have been cached for it
+    pub configured: bool,
+    pub removed: u64,
+}
+
+/// What one backend did during one scoring window. The counters only cover the window
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BackendScoreInputs {
+    pub name: String,
+    pub backup: bool,
+    pub soft_limit: u32,
+    /// median request latency. None if it hasn't served any requests
+    pub latency_ms: Option<f64>,
+    /// how long after the fastest rpc this one usually sees a new head. None if it isn't subscribed to heads
+    pub head_delay_ms: Option<f64>,
+    /// how many blocks this rpc is behind the consensus head. None if either head is unknown
+    pub lag: Option<u64>,
+    pub requests: u64,
+    /// failed responses that were the rpc's fault. bad requests are not counted
+    pub errors: u64,
+    /// how many times its head subscription went quiet while the node kept advancing
+    pub silent_deaths: u64,
+    /// pending transactions it told us about. 0 if it isn't subscribed to them
+    pub pending_txs: u64,
+    /// pending transactions that it told us about before any other rpc did
+    pub unique_pending_txs: u64,
+}
+
+/// Each signal scored from 0.0 (bad) to 1.0 (good). None if there wasn't enough data for it
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScoreComponents {
+    pub latency: Option<f64>,
+    pub head_delay: Option<f64>,
+    pub lag: Option<f64>,
+    pub errors: Option<f64>,
+    pub silent_deaths: Option<f64>,
+    /// compared to the rpc that found the most unique pending transactions
+    pub mempool_uniqueness: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BackendScore {
+    pub name: String,
+    /// 0 to 100. The weighted average of the components that had data. None if none did
+    pub score: Option<f64>,
+    pub components: ScoreComponents,
+    pub inputs: BackendScoreInputs,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Change<T> {
+    pub from: T,
+    pub to: T,
+}
+
+/// Suggested edits to one rpc's config
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RpcConfigSuggestion {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_limit: Option<Change<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<Change<bool>>,
+    pub reasons: Vec<String>,
+}
+
+/// Suggested edits to `balanced_rpcs`. These are never applied automatically
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ConfigSuggestions {
+    pub balanced_rpcs: BTreeMap<String, RpcConfigSuggestion>,
+}
+
+/// `GET /admin/backends/scores`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BackendScoresReport {
+    pub generated_at: DateTime<Utc>,
+    /// how many seconds of data the inputs cover
+    pub window_secs: u64,
+    /// best first. rpcs without a score are last
+    pub backends: Vec<BackendScore>,
+    pub suggestions: ConfigSuggestions,
+}
+
+/// Why the proxy failed a request quickly instead of trying it
+#[derive(Clone, Copy, Debug, Deserialize, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShedReason {
+    /// the proxy itself is too busy
+    ProxyOverloaded,
+    /// every backend that could serve the request is busy or rate limiting us
+    BackendSaturated,
+    /// this ip or rpc key is over its limits
+    RateLimited,
+    /// the backends are down for planned maintenance
+    Maintenance,
+    /// this server is a warm standby. another server is serving
+    Standby,
+}
+
+/// The jsonrpc error's `data` when the proxy sheds load. Every reason has these same keys. Some reasons add a few more
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Backpressure {
+    pub reason: ShedReason,
+    /// how long to wait before trying again
+    pub retry_after_ms: u64,
+    /// `retry_after_ms` rounded up to whole seconds. This is also sent as the `Retry-After` header
+    pub retry_after: u64,
+    /// the `x-request-id` of the request that was shed. None if it wasn't known yet
+    pub request_id: Option<String>,
+}
+
+impl Backpressure {
+    pub fn new(reason: ShedReason, retry_after: Duration, request_id: Option<String>) -> Self {
+        let retry_after_ms = retry_after.as_millis() as u64;
+
+        Self {
+            reason,
+            retry_after_ms,
+            retry_after: retry_after_ms.div_ceil(1000),
+            request_id,
+        }
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_millis(self.retry_after_ms)
+    }
+}
+
+/// `POST /admin/standby`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AdminStandbyPost {
+    /// false promotes the server. true demotes it
+    pub standby: bool,
+}
+
+/// `GET /admin/standby` and `POST /admin/standby`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct StandbyStatus {
+    /// None for `GET`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<bool>,
+    pub standby: bool,
+    /// warm requests sent since the server started
+    pub warmed: u64,
+    pub warm_errors: u64,
+}
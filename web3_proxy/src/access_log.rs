@@ -0,0 +1,73 @@
+//! One event per proxied request.
+//!
+//! Every request gets an id when it arrives (see `frontend::request_id`). It is on the frontend's span, on every backend
+//! request's span, and in the `X-Request-Id` response header, so a client's report can be tied to the trace logs. The
+//! access log is how the slow or failed request is found in the first place. Batches log each of their requests with
+//! the batch's id.
+//!
+//! Events use the `web3_proxy::access_log` target. Filter it like any other target, or set `access_log_path` to give it
+//! a file of its own. Rpc keys are logged by their database id and ips by a salted hash. Raw keys and ips never are.
+
+use crate::globals::global_app;
+use crate::jsonrpc::{ErrorClass, ValidatedRequest};
+use crate::tx_origin::hash_ip;
+use ethers::types::H64;
+use tracing::info;
+
+pub const ACCESS_LOG_TARGET: &str = "web3_proxy::access_log";
+
+fn outcome(error_class: ErrorClass) -> &'static str {
+    match error_class {
+        ErrorClass::None => "ok",
+        ErrorClass::User => "user_error",
+        ErrorClass::Backend => "backend_error",
+        ErrorClass::Proxy => "proxy_error",
+    }
+}
+
+/// Log a finished request. Internal requests don't have a request id and are skipped
+pub fn record(web3_request: &ValidatedRequest) {
+    let Some(request_id) = web3_request.request_id.as_deref() else {
+        return;
+    };
+
+    let Some(app) = global_app(web3_request.chain_id) else {
+        return;
+    };
+
+    if !app.config.access_log {
+        return;
+    }
+
+    let authorization = &web3_request.authorization;
+
+    let salt = app
+        .config
+        .public_recent_ips_salt
+        .as_deref()
+        .unwrap_or_default();
+
+    // a short hash is plenty to tell callers apart
+    let ip = H64::from_slice(&hash_ip(salt, &authorization.ip)[..8]);
+
+    let key_id = authorization.checks.rpc_secret_key_id.map(|x| x.get());
+
+    let response = web3_request.response.lock();
+
+    let backend = response.backend_rpcs.last().map(|x| x.name.as_str());
+
+    info!(
+        target: ACCESS_LOG_TARGET,
+        request_id,
+        method = web3_request.inner.method(),
+        key_id,
+        ip = ?ip,
+        backend,
+        cache_hit = response.backend_rpcs.is_empty(),
+        archive = response.archive_request,
+        latency_ms = response.response_millis,
+        response_bytes = response.response_bytes,
+        outcome = outcome(response.error_class),
+        "request",
+    );
+}
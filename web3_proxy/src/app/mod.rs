@@ -1,39 +1,93 @@
 mod ws;
 
-use crate::caches::{RegisteredUserRateLimitKey, RpcSecretKeyCache, UserBalanceCache};
-use crate::config::{AppConfig, TopConfig};
+pub use ws::SubscriptionHandle;
+
+use crate::backend_scores::BackendScorer;
+use crate::bans::{Bans, Violation};
+use crate::block_number::CacheMode;
+use crate::block_tags::BlockTags;
+use crate::cache_invalidation::{CacheInvalidations, Invalidation};
+use crate::cache_revalidation::CacheRevalidation;
+use crate::caches::{
+    RegisteredUserRateLimitKey, RpcSecretKeyCache, RpcSecretKeyExpiry, UserBalanceCache,
+};
+use crate::call_cache::{CallCache, CallCacheTarget};
+use crate::canary::{Canary, CanaryCounts};
+use crate::compute_units::ComputeUnit;
+use crate::config::{AppConfig, PendingBlockPolicy, RateLimitStoreKind, TopConfig, UnknownMethods};
+use crate::config_reload::ConfigReloads;
+use crate::degraded::{Degraded, DegradedCounts};
+use crate::disk_cache::DiskCache;
 use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
-use crate::frontend::authorization::Authorization;
-use crate::globals::{global_db_conn, DatabaseError, APP, DB_CONN, DB_REPLICA};
+use crate::estimate_gas::combine_estimates;
+use crate::exempt_traffic::ExemptTraffic;
+use crate::frontend::authorization::{Authorization, AuthorizationType, RequestOrMethod};
+use crate::frontend::sse::SseClient;
+use crate::get_logs::{page_ranges, GetLogsLimits, PaginatedLogs};
+use crate::globals::{global_db_conn, set_global_app, DatabaseError, DB_CONN, DB_REPLICA};
+use crate::head_replay::HeadReplay;
+use crate::head_staleness::HeadStaleness;
+use crate::head_watermark::{HeadWatermarks, OlderHead};
+use crate::incoming_requests::{IncomingRequestCounts, IncomingRequests};
+use crate::introspection;
+use crate::jsonrpc::depth::{max_json_depth, set_max_json_depth};
 use crate::jsonrpc::{
-    self, JsonRpcErrorData, JsonRpcParams, JsonRpcRequestEnum, JsonRpcResultData, LooseId,
-    ParsedResponse, SingleRequest, SingleResponse, ValidatedRequest,
+    self, ErrorClass, JsonRpcErrorData, JsonRpcParams, JsonRpcRequestEnum, JsonRpcResultData,
+    LooseId, ParsedResponse, SingleRequest, SingleResponse, ValidatedRequest,
 };
+use crate::latency_slo::LatencySlo;
+use crate::memory::MemoryCounters;
+use crate::metrics::{RequestMetrics, RequestOutcome, TooLarge};
+use crate::param_chain_id::param_chain_id;
+use crate::quotas::Quotas;
+use crate::rate_limit_weights::RateLimitWeights;
+use crate::raw_transaction::{rejection_rank, RawTransaction};
+use crate::recent_errors::RecentErrors;
+use crate::recent_requests::RecentRequests;
 use crate::relational_db::{connect_db, migrate_db};
-use crate::response_cache::{ForwardedResponse, JsonRpcResponseCache, JsonRpcResponseWeigher};
+use crate::response_budget::ResponseBudget;
+use crate::response_cache::{
+    CachedResponse, CachedResponseExpiry, ForwardedResponse, JsonRpcResponseCache,
+    JsonRpcResponseWeigher,
+};
+use crate::response_rewrite::ResponseRewrites;
+use crate::response_signing::ResponseSigner;
+use crate::retry_policy::RetryPolicy;
+use crate::rpcs::block_queue::BlockQueueSender;
 use crate::rpcs::blockchain::BlockHeader;
 use crate::rpcs::consensus::RankedRpcs;
+use crate::rpcs::happy_eyeballs::HappyEyeballs;
+use crate::rpcs::maintenance::{RpcGroup, ScheduledMaintenanceState};
 use crate::rpcs::many::Web3Rpcs;
 use crate::rpcs::one::Web3Rpc;
 use crate::rpcs::provider::{connect_http, EthersHttpProvider};
-use crate::stats::{AppStat, FlushedStats, StatBuffer};
+use crate::secrets::RpcSecretKey;
+use crate::standby::Standby;
+use crate::stats::{AppStat, FlushedStats, StatBuffer, StatBufferStatus};
+use crate::tx_origin::TxOriginRecorder;
+use crate::tx_tracker::TxTracker;
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use axum::http::StatusCode;
 use chrono::Utc;
 use deduped_broadcast::DedupedBroadcaster;
 use deferred_rate_limiter::DeferredRateLimiter;
 use entities::user;
 use ethers::core::utils::keccak256;
-use ethers::prelude::{Address, Bytes, Transaction, TxHash, H256, U256, U64};
-use ethers::utils::rlp::{Decodable, Rlp};
+use ethers::prelude::{Address, Bytes, TxHash, H256, U256, U64};
 use futures::future::join_all;
-use futures::stream::FuturesUnordered;
+use futures::stream::{self, FuturesUnordered, StreamExt};
 use hashbrown::{HashMap, HashSet};
 use migration::sea_orm::{EntityTrait, PaginatorTrait};
 use moka::future::{Cache, CacheBuilder};
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use redis_rate_limiter::redis::AsyncCommands;
-use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter};
+use redis_rate_limiter::{
+    redis, DeadpoolRuntime, FallbackStore, MemoryStore, RateLimitStore, RedisConfig, RedisPool,
+    RedisRateLimiter, RedisStore,
+};
+use rust_decimal::Decimal;
 use serde::Serialize;
 use serde_json::json;
 use serde_json::value::RawValue;
@@ -41,14 +95,14 @@ use std::fmt;
 use std::net::IpAddr;
 use std::num::NonZeroU64;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{broadcast, mpsc, oneshot, watch, Semaphore};
-use tokio::task::{yield_now, JoinHandle};
-use tokio::time::{sleep, sleep_until, timeout_at, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout_at, Instant};
 use tokio::{pin, select};
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 // TODO: make this customizable?
 // TODO: include GIT_REF in here. i had trouble getting https://docs.rs/vergen/latest/vergen/ to work with a workspace. also .git is in .dockerignore
@@ -65,23 +119,77 @@ pub const BILLING_PERIOD_SECONDS: i64 = 60 * 60 * 24 * 7;
 /// Convenience type
 pub type Web3ProxyJoinHandle<T> = JoinHandle<Web3ProxyResult<T>>;
 
+/// Which rpcs get transactions. Chains without private rpcs send them to the balanced rpcs
+#[derive(Clone, Copy, Debug)]
+pub enum PrivateRpcs<'a> {
+    /// `private_rpcs` are configured. transactions only go to them
+    Dedicated(&'a Arc<Web3Rpcs>),
+    /// no `private_rpcs` are configured. transactions go to the best balanced rpc
+    SameAsBalanced,
+}
+
+impl PrivateRpcs<'_> {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dedicated(_) => "dedicated",
+            Self::SameAsBalanced => "same_as_balanced",
+        }
+    }
+}
+
 /// The application
 // TODO: i'm sure this is more arcs than necessary, but spawning futures makes references hard
 pub struct App {
     /// Send requests to the best server available
     pub balanced_rpcs: Arc<Web3Rpcs>,
+    /// scores the balanced rpcs and suggests config changes. None if `backend_scoring.interval_secs` is 0
+    pub backend_scorer: Option<Arc<BackendScorer>>,
+    /// compares our answers with a reference backend. None without `canary.reference_url`
+    pub canary: Option<Arc<Canary>>,
+    /// temporary bans for ips and keys that keep sending bad requests
+    pub bans: Bans,
+    /// the "finalized" and "safe" blocks. None if `block_tags_poll_secs` is 0
+    pub block_tags: Option<Arc<BlockTags>>,
     /// Send 4337 Abstraction Bundler requests to one of these servers
     pub bundler_4337_rpcs: Arc<Web3Rpcs>,
+    /// share cache invalidations with the other servers. None without redis
+    pub cache_invalidations: Option<Arc<CacheInvalidations>>,
+    /// sample cache hits and check them against a backend to see how often they go stale
+    pub cache_revalidation: CacheRevalidation,
+    /// `eth_call` responses for the contracts in `call_cache`. these outlive new heads
+    pub call_cache: CallCache,
     /// application config
     /// TODO: this will need a large refactor to handle reloads while running. maybe use a watch::Receiver and a task_local?
     pub config: AppConfig,
+    /// counters for config reloads and the last rejected config
+    pub config_reloads: ConfigReloads,
+    /// what keyed requests get while the database is down. also reconnects to it
+    pub degraded: Arc<Degraded>,
+    /// true once the frontend starts shutting down. websockets are closed when it flips
+    pub draining: watch::Sender<bool>,
     pub http_client: Option<reqwest::Client>,
+    /// picks between ipv4 and ipv6 for backends that have both. remembers which family connected last
+    pub happy_eyeballs: Arc<HappyEyeballs>,
+    /// counts the identical cacheable requests that shared one backend request
+    pub incoming_requests: IncomingRequests,
     /// track JSONRPC responses
     pub jsonrpc_response_cache: JsonRpcResponseCache,
+    /// responses for blocks past `archive_cache_confirmations`. None if `archive_cache_max_bytes` is 0
+    pub archive_response_cache: Option<JsonRpcResponseCache>,
+    /// the name of the backend that each cached response came from
+    pub jsonrpc_response_cache_sources: Cache<u64, Arc<str>>,
+    /// the hash of the block that each cached response was for. a reorg drops the ones for orphaned blocks
+    pub jsonrpc_response_cache_blocks: Cache<u64, H256>,
+    /// false while an admin has paused writes to the response cache. cached responses are still served
+    pub response_cache_writes: AtomicBool,
+    /// limits how many bytes of long backend responses are read into memory at once
+    pub response_budget: Arc<ResponseBudget>,
+    /// signs responses for rpc keys with `sign_responses`. None if `response_signing_key` is not set
+    pub response_signer: Option<ResponseSigner>,
+    /// while on, jsonrpc gets a 503 and the response cache is kept warm. admins promote and demote with `/admin/standby`
+    pub standby: Arc<Standby>,
     /// track JSONRPC cache keys that have failed caching
     pub jsonrpc_response_failed_cache_keys: Cache<u64, ()>,
-    /// de-dupe requests (but with easy timeouts)
-    pub jsonrpc_response_semaphores: Cache<u64, Arc<Semaphore>>,
     /// rpc clients that subscribe to newHeads use this channel
     /// don't drop this or the sender will stop working
     /// TODO: broadcast channel instead?
@@ -90,6 +198,14 @@ pub struct App {
     pub pending_txid_firehose: Arc<DedupedBroadcaster<TxHash>>,
     pub hostname: Option<String>,
     pub frontend_port: Arc<AtomicU16>,
+    /// where this chain is served on the frontend. empty if it is the only chain
+    pub frontend_path: String,
+    /// limits on eth_getLogs. these are swapped when the config changes
+    pub get_logs_limits: ArcSwap<GetLogsLimits>,
+    /// how much of a rate limit each method uses. changed by config reloads
+    pub rate_limit_weights: ArcSwap<RateLimitWeights>,
+    /// fixes for backends that answer in non-standard ways. these are swapped when the config changes
+    pub response_rewrites: ArcSwap<ResponseRewrites>,
     /// rate limit anonymous users
     pub frontend_public_rate_limiter: Option<DeferredRateLimiter<IpAddr>>,
     /// bonus rate limit for anonymous users
@@ -100,6 +216,8 @@ pub struct App {
     pub bonus_frontend_premium_rate_limiter: Option<RedisRateLimiter>,
     /// concurrent/parallel request limits for anonymous users
     pub ip_semaphores: Cache<IpAddr, Arc<Semaphore>>,
+    /// limit how many server-sent event streams each ip or rpc key has open
+    pub sse_semaphores: Cache<SseClient, Arc<Semaphore>>,
     /// give some bonus capacity to public users
     pub bonus_ip_concurrency: Arc<Semaphore>,
     /// the /debug/ rpc endpoints send detailed logging to kafka
@@ -108,12 +226,20 @@ pub struct App {
     /// rate limit the login endpoint
     /// we do this because each pending login is a row in the database
     pub login_rate_limiter: Option<RedisRateLimiter>,
+    /// counters for the memory report that don't come from a cache
+    pub memory: Arc<MemoryCounters>,
     /// Send private requests (like eth_sendRawTransaction) to all these servers
     pub protected_rpcs: Arc<Web3Rpcs>,
     pub prometheus_port: Arc<AtomicU16>,
+    /// daily and monthly request counts for rpc keys that have quotas
+    pub quotas: Arc<Quotas>,
     /// cache authenticated users so that we don't have to query the database on the hot path
     // TODO: should the key be our RpcSecretKey class instead of Ulid?
     pub rpc_secret_key_cache: RpcSecretKeyCache,
+    /// how long known and unknown keys stay in `rpc_secret_key_cache`
+    pub rpc_secret_key_expiry: RpcSecretKeyExpiry,
+    /// keys that are being reloaded in the background. invalidating a key removes it so the reload is thrown away
+    pub rpc_secret_key_refreshing: Mutex<HashSet<RpcSecretKey>>,
     /// cache user balances so we don't have to check downgrade logic every single time
     pub user_balance_cache: UserBalanceCache,
     /// concurrent/parallel RPC request limits for authenticated users
@@ -125,10 +251,34 @@ pub struct App {
     pub vredis_pool: Option<RedisPool>,
     /// channel for sending stats in a background task
     pub stat_sender: Option<mpsc::UnboundedSender<AppStat>>,
+    /// None if stats are not being collected
+    pub stat_buffer_status: Option<Arc<StatBufferStatus>>,
     /// when the app started
     pub start: Instant,
     /// limit the number of tx subscriptions
     pub tx_subscriptions: Semaphore,
+    /// which rpc key sent each relayed transaction. None unless `tx_origin_retention_days` is set
+    pub tx_origins: Option<Arc<TxOriginRecorder>>,
+    /// recent consensus heads for `web3proxy_resubscribe`. None if `head_replay_blocks` is 0
+    pub head_replay: Option<Arc<HeadReplay>>,
+    /// how long since the consensus head changed. past `stale_head_ms`, the head block isn't cached
+    pub head_staleness: Arc<HeadStaleness>,
+    /// the highest head served to each client. None if `head_watermark_ttl_secs` is 0
+    pub head_watermarks: Option<HeadWatermarks>,
+    /// pending, confirmed, or orphaned for each relayed transaction. None if `tx_tracker_retention_secs` is 0
+    pub tx_tracker: Option<Arc<TxTracker>>,
+    /// immutable responses saved across restarts. None if `disk_cache_path` isn't set
+    pub disk_cache: Option<Arc<DiskCache>>,
+    /// the last few errors sent to each rpc key. None if `recent_errors_per_key` is 0
+    pub recent_errors: Option<RecentErrors>,
+    /// request counters for the last few minutes. used by `/admin/summary`
+    pub recent_requests: RecentRequests,
+    /// labeled counters for prometheus
+    pub request_metrics: RequestMetrics,
+    /// health checks and uptime bots. they skip rate limits and stats
+    pub exempt_traffic: ExemptTraffic,
+    /// latency sketches per method class and tier. checked against the latency objectives once a minute
+    pub latency_slo: Arc<LatencySlo>,
 
     /// Optional time series database for making pretty graphs that load quickly
     influxdb_client: Option<influxdb2::Client>,
@@ -155,19 +305,136 @@ pub struct Web3ProxyAppSpawn {
     pub ranked_rpcs: watch::Receiver<Option<Arc<RankedRpcs>>>,
 }
 
+/// Connections that every chain served by the process shares. Their settings come from the top level `[app]`
+#[derive(Clone)]
+pub struct AppShared {
+    pub happy_eyeballs: Arc<HappyEyeballs>,
+    pub http_client: Option<reqwest::Client>,
+    /// frontend rate limits and backend hard limits are counted here
+    pub rate_limit_store: Option<Arc<dyn RateLimitStore>>,
+    pub vredis_pool: Option<RedisPool>,
+}
+
+impl AppShared {
+    /// If `http_client` is None, a new client is built.
+    pub async fn connect(
+        config: &AppConfig,
+        num_workers: usize,
+        http_client: Option<reqwest::Client>,
+    ) -> anyhow::Result<Self> {
+        // TODO: do this during apply_config so that we can change redis url while running
+        // create a connection pool for redis
+        // a failure to connect does NOT block the application from starting
+        let vredis_pool = match config.volatile_redis_url.as_ref() {
+            Some(redis_url) => {
+                // TODO: scrub credentials and then include the redis_url in logs
+                info!("Connecting to vredis");
+
+                // TODO: what is a good default?
+                let redis_max_connections = config
+                    .volatile_redis_max_connections
+                    .unwrap_or(num_workers * 2);
+
+                // TODO: what are reasonable timeouts?
+                let redis_pool = RedisConfig::from_url(redis_url)
+                    .builder()?
+                    .max_size(redis_max_connections)
+                    .runtime(DeadpoolRuntime::Tokio1)
+                    .build()?;
+
+                // test the redis pool
+                if let Err(err) = redis_pool.get().await {
+                    error!(
+                        "failed to connect to vredis. some features will be disabled. err={:?}",
+                        err
+                    );
+                };
+
+                Some(redis_pool)
+            }
+            None => {
+                warn!("no redis connection. some features will be disabled");
+                None
+            }
+        };
+
+        // make a http shared client
+        // TODO: can we configure the connection pool? should we?
+        // TODO: timeouts from config. defaults are hopefully good
+        // TODO: is always disabling compression a good idea?
+        let backend_connect_timeout = Duration::from_secs(5);
+
+        let happy_eyeballs = HappyEyeballs::new(config, backend_connect_timeout);
+
+        let http_client = match http_client {
+            Some(x) => Some(x),
+            None => Some(
+                reqwest::ClientBuilder::new()
+                    .connect_timeout(backend_connect_timeout)
+                    .dns_resolver(happy_eyeballs.clone())
+                    .no_brotli()
+                    .no_deflate()
+                    .no_gzip()
+                    .timeout(Duration::from_secs(5 * 60 - 2))
+                    .user_agent(APP_USER_AGENT)
+                    .build()?,
+            ),
+        };
+
+        let rate_limit_store: Option<Arc<dyn RateLimitStore>> = match config.rate_limit_store {
+            RateLimitStoreKind::Redis => match vredis_pool.clone() {
+                // counted in memory while redis is unreachable
+                Some(x) => Some(Arc::new(FallbackStore::new(Arc::new(RedisStore::new(x))))
+                    as Arc<dyn RateLimitStore>),
+                None => {
+                    warn!("no volatile_redis_url. rate limits and backend hard limits are counted in memory. they will not be shared with other servers");
+
+                    Some(Arc::new(MemoryStore::default()))
+                }
+            },
+            RateLimitStoreKind::Memory => {
+                if config.volatile_redis_url.is_some() {
+                    info!("rate limits are in memory. they will not be shared with other servers");
+                }
+
+                Some(Arc::new(MemoryStore::default()))
+            }
+        };
+
+        Ok(Self {
+            happy_eyeballs,
+            http_client,
+            rate_limit_store,
+            vredis_pool,
+        })
+    }
+}
+
 impl App {
-    /// The main entrypoint.
+    /// The main entrypoint. Most programs should use [`crate::embed::Web3ProxyBuilder`] instead of calling this directly.
+    ///
+    /// `shared` can be cloned for each chain that the process serves. `frontend_path` is where this chain is served. It is
+    /// empty if the process only serves this chain.
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
         frontend_port: Arc<AtomicU16>,
+        frontend_path: String,
         prometheus_port: Arc<AtomicU16>,
         mut top_config: TopConfig,
-        num_workers: usize,
         shutdown_sender: broadcast::Sender<()>,
         flush_stat_buffer_sender: mpsc::Sender<oneshot::Sender<FlushedStats>>,
         flush_stat_buffer_receiver: mpsc::Receiver<oneshot::Sender<FlushedStats>>,
+        shared: AppShared,
     ) -> anyhow::Result<Web3ProxyAppSpawn> {
+        let AppShared {
+            happy_eyeballs,
+            http_client,
+            rate_limit_store,
+            vredis_pool,
+        } = shared;
+
         let stat_buffer_shutdown_receiver = shutdown_sender.subscribe();
-        let mut config_watcher_shutdown_receiver = shutdown_sender.subscribe();
+        let config_watcher_shutdown_receiver = shutdown_sender.subscribe();
         let mut background_shutdown_receiver = shutdown_sender.subscribe();
 
         top_config.clean();
@@ -190,6 +457,17 @@ impl App {
             );
         }
 
+        let response_rewrites =
+            ResponseRewrites::try_new(&top_config, None).context("checking response_rewrites")?;
+
+        top_config
+            .check_maintenance_windows(Utc::now())
+            .context("checking maintenance_windows")?;
+
+        top_config
+            .check_vendor_methods()
+            .context("checking vendor_methods")?;
+
         // we must wait for these to end on their own (and they need to subscribe to shutdown_sender)
         // TODO: is FuturesUnordered what we need? I want to return when the first one returns
         let important_background_handles: FuturesUnordered<Web3ProxyJoinHandle<()>> =
@@ -220,43 +498,6 @@ impl App {
             }
         }
 
-        // TODO: do this during apply_config so that we can change redis url while running
-        // create a connection pool for redis
-        // a failure to connect does NOT block the application from starting
-        let vredis_pool = match top_config.app.volatile_redis_url.as_ref() {
-            Some(redis_url) => {
-                // TODO: scrub credentials and then include the redis_url in logs
-                info!("Connecting to vredis");
-
-                // TODO: what is a good default?
-                let redis_max_connections = top_config
-                    .app
-                    .volatile_redis_max_connections
-                    .unwrap_or(num_workers * 2);
-
-                // TODO: what are reasonable timeouts?
-                let redis_pool = RedisConfig::from_url(redis_url)
-                    .builder()?
-                    .max_size(redis_max_connections)
-                    .runtime(DeadpoolRuntime::Tokio1)
-                    .build()?;
-
-                // test the redis pool
-                if let Err(err) = redis_pool.get().await {
-                    error!(
-                        "failed to connect to vredis. some features will be disabled. err={:?}",
-                        err
-                    );
-                };
-
-                Some(redis_pool)
-            }
-            None => {
-                warn!("no redis connection. some features will be disabled");
-                None
-            }
-        };
-
         let influxdb_client = match top_config.app.influxdb_host.as_ref() {
             Some(influxdb_host) => {
                 let influxdb_org = top_config
@@ -290,10 +531,14 @@ impl App {
         // all the users are the same size, so no need for a weigher
         // if there is no database of users, there will be no keys and so this will be empty
         // TODO: max_capacity from config
-        // TODO: ttl from config
+        let rpc_secret_key_expiry = RpcSecretKeyExpiry {
+            known: Duration::from_secs(top_config.app.rpc_key_cache_ttl_secs),
+            unknown: Duration::from_secs(top_config.app.rpc_key_cache_negative_ttl_secs),
+        };
+
         let rpc_secret_key_cache = CacheBuilder::new(max_users)
             .name("rpc_secret_key")
-            .time_to_live(Duration::from_secs(600))
+            .expire_after(rpc_secret_key_expiry)
             .build();
 
         // TODO: TTL left low, this could also be a solution instead of modifiying the cache, that may be disgusting across threads / slow anyways
@@ -306,106 +551,126 @@ impl App {
         // create a channel for receiving stats
         // we do this in a channel so we don't slow down our response to the users
         // stats can be saved in mysql, influxdb, both, or none
-        let stat_sender = if let Some(spawned_stat_buffer) = StatBuffer::try_spawn(
-            BILLING_PERIOD_SECONDS,
-            top_config.app.chain_id,
-            120,
-            top_config.app.influxdb_bucket.clone(),
-            influxdb_client.clone(),
-            rpc_secret_key_cache.clone(),
-            user_balance_cache.clone(),
-            stat_buffer_shutdown_receiver,
-            10,
-            flush_stat_buffer_sender.clone(),
-            flush_stat_buffer_receiver,
-            top_config.app.unique_id,
-        )? {
+        let (stat_sender, stat_buffer_status) = if let Some(spawned_stat_buffer) =
+            StatBuffer::try_spawn(
+                BILLING_PERIOD_SECONDS,
+                top_config.app.chain_id,
+                120,
+                top_config.app.influxdb_bucket.clone(),
+                influxdb_client.clone(),
+                rpc_secret_key_cache.clone(),
+                user_balance_cache.clone(),
+                stat_buffer_shutdown_receiver,
+                10,
+                flush_stat_buffer_sender.clone(),
+                flush_stat_buffer_receiver,
+                top_config.app.unique_id,
+                top_config.app.stat_retry_max_entries,
+                Duration::from_millis(top_config.app.stat_retry_max_backoff_ms),
+            )? {
             // since the database entries are used for accounting, we want to be sure everything is saved before exiting
             important_background_handles.push(spawned_stat_buffer.background_handle);
 
-            Some(spawned_stat_buffer.stat_sender)
+            (
+                Some(spawned_stat_buffer.stat_sender),
+                Some(spawned_stat_buffer.status),
+            )
         } else {
             info!("stats will not be collected");
-            None
+            (None, None)
         };
 
-        // make a http shared client
-        // TODO: can we configure the connection pool? should we?
-        // TODO: timeouts from config. defaults are hopefully good
-        // TODO: is always disabling compression a good idea?
-        let http_client = Some(
-            reqwest::ClientBuilder::new()
-                .connect_timeout(Duration::from_secs(5))
-                .no_brotli()
-                .no_deflate()
-                .no_gzip()
-                .timeout(Duration::from_secs(5 * 60 - 2))
-                .user_agent(APP_USER_AGENT)
-                .build()?,
-        );
-
         // create rate limiters
-        // these are optional. they require redis
+        // these are optional. they require redis unless the in-memory store is configured
         let mut frontend_public_rate_limiter = None;
         let mut frontend_premium_rate_limiter = None;
         let mut login_rate_limiter = None;
         let mut bonus_frontend_public_rate_limiter: Option<RedisRateLimiter> = None;
         let mut bonus_frontend_premium_rate_limiter: Option<RedisRateLimiter> = None;
 
-        if let Some(ref redis_pool) = vredis_pool {
+        if let Some(ref rate_limit_store) = rate_limit_store {
             if let Some(public_requests_per_period) = top_config.app.public_requests_per_period {
-                // chain id is included in the app name so that rpc rate limits are per-chain
-                let rpc_rrl = RedisRateLimiter::new(
-                    &format!("web3_proxy:{}", top_config.app.chain_id),
+                // chain id is included in the app name so that rpc rate limits are per-chain unless they are shared
+                let rpc_rrl_name = if top_config.app.shared_rate_limits {
+                    "web3_proxy".to_string()
+                } else {
+                    format!("web3_proxy:{}", top_config.app.chain_id)
+                };
+
+                let rpc_rrl = RedisRateLimiter::with_store(
+                    &rpc_rrl_name,
                     "frontend",
                     public_requests_per_period,
                     60.0,
-                    redis_pool.clone(),
+                    rate_limit_store.clone(),
                 );
 
                 // these two rate limiters can share the base limiter
                 // these are deferred rate limiters because we don't want redis network requests on the hot path
                 // TODO: take cache_size from config
-                frontend_public_rate_limiter =
-                    Some(DeferredRateLimiter::new(20_000, "ip", rpc_rrl.clone(), None).await);
-                frontend_premium_rate_limiter =
-                    Some(DeferredRateLimiter::new(20_000, "key", rpc_rrl, None).await);
+                let sync_interval = Some(Duration::from_millis(
+                    top_config.app.deferred_rate_limit_sync_ms.max(1),
+                ));
+                let max_local_burst = Some(top_config.app.deferred_rate_limit_max_local_burst);
+
+                frontend_public_rate_limiter = Some(
+                    DeferredRateLimiter::new(
+                        20_000,
+                        "ip",
+                        rpc_rrl.clone(),
+                        None,
+                        sync_interval,
+                        max_local_burst,
+                    )
+                    .await,
+                );
+                frontend_premium_rate_limiter = Some(
+                    DeferredRateLimiter::new(
+                        20_000,
+                        "key",
+                        rpc_rrl,
+                        None,
+                        sync_interval,
+                        max_local_burst,
+                    )
+                    .await,
+                );
 
                 if top_config.app.bonus_frontend_public_rate_limit > 0 {
-                    bonus_frontend_public_rate_limiter = Some(RedisRateLimiter::new(
+                    bonus_frontend_public_rate_limiter = Some(RedisRateLimiter::with_store(
                         "web3_proxy",
                         "bonus_frontend_public",
                         top_config.app.bonus_frontend_public_rate_limit,
                         60.0,
-                        redis_pool.clone(),
+                        rate_limit_store.clone(),
                     ));
                 }
                 if top_config.app.bonus_frontend_premium_rate_limit > 0 {
-                    bonus_frontend_premium_rate_limiter = Some(RedisRateLimiter::new(
+                    bonus_frontend_premium_rate_limiter = Some(RedisRateLimiter::with_store(
                         "web3_proxy",
                         "bonus_frontend_premium",
                         top_config.app.bonus_frontend_premium_rate_limit,
                         60.0,
-                        redis_pool.clone(),
+                        rate_limit_store.clone(),
                     ));
                 }
             }
 
             // login rate limiter
-            login_rate_limiter = Some(RedisRateLimiter::new(
+            login_rate_limiter = Some(RedisRateLimiter::with_store(
                 "web3_proxy",
                 "login",
                 top_config.app.login_rate_limit_per_period,
                 60.0,
-                redis_pool.clone(),
+                rate_limit_store.clone(),
             ));
         }
 
         let (watch_consensus_head_sender, watch_consensus_head_receiver) = watch::channel(None);
 
         // responses can be very different in sizes, so this is a cache with a max capacity and a weigher
+        // methods in `response_cache_ttls` also expire after their ttl
         // TODO: we should emit stats to calculate a more accurate expected cache size
-        // TODO: do we actually want a TTL on this?
         // TODO: configurable max item weight insted of hard coding to .1% of the cache?
         let jsonrpc_weigher =
             JsonRpcResponseWeigher((top_config.app.response_cache_max_bytes / 1000) as u32);
@@ -414,18 +679,54 @@ impl App {
             CacheBuilder::new(top_config.app.response_cache_max_bytes)
                 .name("jsonrpc_response_cache")
                 .time_to_idle(Duration::from_secs(3600))
-                .weigher(move |k, v| jsonrpc_weigher.weigh(k, v))
+                .expire_after(CachedResponseExpiry)
+                .weigher(move |k, v: &CachedResponse| jsonrpc_weigher.weigh(k, &v.response))
                 .build();
 
+        // these never change, so they only leave when the cache is full or nobody asks for them for a day
+        let archive_response_cache = match top_config.app.archive_cache_max_bytes {
+            0 => None,
+            max_bytes => {
+                let archive_weigher = JsonRpcResponseWeigher((max_bytes / 1000) as u32);
+
+                let x: JsonRpcResponseCache = CacheBuilder::new(max_bytes)
+                    .name("archive_response_cache")
+                    .time_to_idle(Duration::from_secs(86_400))
+                    .expire_after(CachedResponseExpiry)
+                    .weigher(move |k, v: &CachedResponse| archive_weigher.weigh(k, &v.response))
+                    .build();
+
+                Some(x)
+            }
+        };
+
+        // the response cache is limited by bytes, not entries. this only holds names, so a generous count is fine
+        let jsonrpc_response_cache_sources = CacheBuilder::new(1_000_000)
+            .name("jsonrpc_response_cache_sources")
+            .time_to_idle(Duration::from_secs(3600))
+            .build();
+        let jsonrpc_response_cache_blocks = CacheBuilder::new(1_000_000)
+            .name("jsonrpc_response_cache_blocks")
+            .time_to_idle(Duration::from_secs(3600))
+            .build();
+
+        // only a handful of contracts are configured and their responses are small. a tenth of the response cache is plenty
+        let call_cache = CallCache::new(top_config.app.response_cache_max_bytes / 10);
+
         // create semaphores for concurrent connection limits
         // TODO: time-to-idle on these. need to make sure the arcs aren't anywhere though. so maybe arc isn't correct and it should be refs
         let ip_semaphores = CacheBuilder::new(max_users).name("ip_semaphores").build();
+        let sse_semaphores = CacheBuilder::new(max_users).name("sse_semaphores").build();
         let user_semaphores = CacheBuilder::new(max_users).name("user_semaphores").build();
 
         let chain_id = top_config.app.chain_id;
 
         // TODO: deduped_txid_firehose capacity from config
-        let deduped_txid_firehose = DedupedBroadcaster::new(100, 20_000);
+        let deduped_txid_firehose = DedupedBroadcaster::with_window(
+            100,
+            20_000,
+            Duration::from_secs(top_config.app.pending_txid_dedupe_window_secs),
+        );
 
         // TODO: remove this. it should only be done by apply_top_config
         let (balanced_rpcs, balanced_handle, consensus_connections_watcher) = Web3Rpcs::spawn(
@@ -483,96 +784,202 @@ impl App {
         let bonus_user_concurrency =
             Arc::new(Semaphore::new(top_config.app.bonus_premium_concurrency));
 
-        // TODO: what size?
-        let jsonrpc_response_semaphores = CacheBuilder::new(10_000)
-            .name("jsonrpc_response_semaphores")
-            .build();
-
         let jsonrpc_response_failed_cache_keys = CacheBuilder::new(100_000)
             .name("jsonrpc_response_failed_cache_keys")
             .build();
 
         let tx_subscriptions = Semaphore::new(1);
 
+        let bans = Bans::new(&top_config.app);
+
+        let cache_revalidation =
+            CacheRevalidation::new(top_config.app.cache_revalidation_chance, 100_000);
+
+        let tx_origins = TxOriginRecorder::spawn(&top_config.app);
+
+        let cache_invalidations = CacheInvalidations::spawn(&top_config.app, vredis_pool.as_ref());
+
+        let quotas = Quotas::spawn(&top_config.app, vredis_pool.as_ref());
+
+        let tx_tracker = TxTracker::spawn(
+            &top_config.app,
+            watch_consensus_head_receiver.clone(),
+            shutdown_sender.subscribe(),
+        )
+        .map(|(x, handle)| {
+            important_background_handles.push(handle);
+            x
+        });
+
+        let disk_cache =
+            DiskCache::spawn(&top_config.app, shutdown_sender.subscribe())?.map(|(x, handle)| {
+                important_background_handles.push(handle);
+                x
+            });
+
+        let head_replay = HeadReplay::spawn(&top_config.app, watch_consensus_head_receiver.clone());
+
+        let head_staleness =
+            HeadStaleness::spawn(&top_config.app, watch_consensus_head_receiver.clone());
+
+        let block_tags = BlockTags::spawn(
+            &top_config.app,
+            watch_consensus_head_receiver.clone(),
+            balanced_rpcs.clone(),
+        );
+
+        let recent_errors = RecentErrors::new(
+            top_config.app.recent_errors_max_keys,
+            top_config.app.recent_errors_per_key,
+        );
+
+        let latency_slo = LatencySlo::spawn(&top_config.app, http_client.clone());
+
+        let backend_scorer = BackendScorer::new(&top_config.app, influxdb_client.clone());
+
+        let canary = Canary::new(&top_config.app.canary, http_client.clone());
+
         let app = Self {
+            backend_scorer,
             balanced_rpcs,
+            bans,
+            block_tags,
             bonus_frontend_public_rate_limiter,
             bonus_frontend_premium_rate_limiter,
             bonus_ip_concurrency,
             bonus_user_concurrency,
             bundler_4337_rpcs,
+            cache_invalidations,
+            cache_revalidation,
+            call_cache,
+            canary,
             config: top_config.app.clone(),
+            config_reloads: Default::default(),
+            degraded: Degraded::new(&top_config.app),
+            draining: watch::channel(false).0,
+            exempt_traffic: ExemptTraffic::new(top_config.app.exempt_traffic.clone()),
             frontend_public_rate_limiter,
             frontend_port: frontend_port.clone(),
+            frontend_path,
             frontend_premium_rate_limiter,
+            get_logs_limits: ArcSwap::from_pointee(top_config.app.get_logs.clone()),
+            rate_limit_weights: ArcSwap::from_pointee(top_config.app.rate_limit_weights.clone()),
+            head_replay,
+            head_staleness,
+            head_watermarks: HeadWatermarks::try_new(&top_config.app),
             hostname,
             http_client,
+            happy_eyeballs,
+            incoming_requests: Default::default(),
             influxdb_client,
             internal_provider: Default::default(),
             ip_semaphores,
+            sse_semaphores,
             jsonrpc_response_cache,
+            archive_response_cache,
+            jsonrpc_response_cache_sources,
+            jsonrpc_response_cache_blocks,
             jsonrpc_response_failed_cache_keys,
-            jsonrpc_response_semaphores,
             #[cfg(feature = "rdkafka")]
             kafka_producer,
+            latency_slo,
             login_rate_limiter,
+            memory: Default::default(),
             pending_txid_firehose: deduped_txid_firehose,
             protected_rpcs: private_rpcs,
             prometheus_port: prometheus_port.clone(),
+            quotas,
+            recent_errors,
+            recent_requests: Default::default(),
+            request_metrics: Default::default(),
+            response_budget: Arc::new(ResponseBudget::new(
+                top_config.app.response_buffer_max_bytes,
+                Duration::from_millis(top_config.app.response_buffer_wait_ms),
+            )),
+            response_cache_writes: AtomicBool::new(true),
+            response_rewrites: ArcSwap::from_pointee(response_rewrites),
+            response_signer: top_config
+                .app
+                .response_signing_key
+                .as_deref()
+                .map(ResponseSigner::new),
             rpc_secret_key_cache,
+            rpc_secret_key_expiry,
+            rpc_secret_key_refreshing: Default::default(),
+            standby: Standby::new(&top_config.app),
             start: Instant::now(),
+            stat_buffer_status,
             stat_sender,
             user_balance_cache,
             user_semaphores,
             vredis_pool,
             watch_consensus_head_receiver,
+            tx_origins,
             tx_subscriptions,
+            tx_tracker,
+            disk_cache,
         };
 
         let app = Arc::new(app);
 
-        if let Err(app) = APP.set(app.clone()) {
-            error!(?app, "global APP can only be set once!");
+        if let Err(app) = set_global_app(app.clone()) {
+            error!(?app, "a global App for this chain was already set!");
         };
 
+        if let Some(x) = app.cache_invalidations.as_ref() {
+            x.subscribe(Arc::downgrade(&app));
+        }
+
+        if let Some(x) = app.backend_scorer.as_ref() {
+            x.start(Arc::downgrade(&app));
+        }
+
+        if let Some(x) = app.canary.as_ref() {
+            x.start(Arc::downgrade(&app));
+        }
+
+        app.standby.start(Arc::downgrade(&app));
+
         // TODO: do apply_top_config once we don't duplicate the db
+        // a database that is down doesn't stop the app from starting. the degraded task keeps trying it
         if let Err(err) = app.apply_top_config_db(&top_config).await {
             warn!(?err, "unable to fully apply config while starting!");
         };
 
+        if top_config.app.db_url.is_some() {
+            app.degraded
+                .start(Arc::downgrade(&app), new_top_config_sender.subscribe());
+        }
+
         // watch for config changes
-        // TODO: move this to its own function/struct
         {
             let app = app.clone();
             let config_handle = tokio::spawn(async move {
-                loop {
-                    let new_top_config = new_top_config_receiver.borrow_and_update().to_owned();
-
-                    // TODO: compare new and old here? the sender should be doing that already but maybe its better here
-
-                    if let Err(err) = app.apply_top_config_rpcs(&new_top_config).await {
-                        error!(?err, "unable to apply config! Retrying in 10 seconds (or if the config changes)");
-
-                        select! {
-                            _ = config_watcher_shutdown_receiver.recv() => {
-                                break;
-                            }
-                            _ = sleep(Duration::from_secs(10)) => {}
-                            _ = new_top_config_receiver.changed() => {}
-                        }
-                    } else {
-                        // configs applied successfully. wait for configs to change or for the app to exit
-                        select! {
-                            _ = config_watcher_shutdown_receiver.recv() => {
-                                break;
+                app.config_reloads
+                    .watch(
+                        new_top_config_receiver,
+                        config_watcher_shutdown_receiver,
+                        |x| Duration::from_secs(x.app.config_reload_min_interval_secs),
+                        |new_top_config| {
+                            let app = app.clone();
+                            async move {
+                                new_top_config
+                                    .check_maintenance_windows(Utc::now())
+                                    .web3_context("checking maintenance_windows")?;
+
+                                new_top_config
+                                    .check_vendor_methods()
+                                    .web3_context("checking vendor_methods")?;
+
+                                app.apply_top_config_rewrites(&new_top_config)?;
+
+                                app.apply_top_config_limits(&new_top_config);
+
+                                app.apply_top_config_rpcs(&new_top_config).await
                             }
-                            _ = new_top_config_receiver.changed() => {}
-                        }
-                    }
-
-                    // TODO: add a min time between config changes
-                    yield_now().await;
-                }
+                        },
+                    )
+                    .await;
 
                 Ok(())
             });
@@ -606,6 +1013,21 @@ impl App {
     pub async fn apply_top_config(&self, new_top_config: &TopConfig) -> Web3ProxyResult<()> {
         // TODO: update self.config from new_top_config.app (or move it entirely to a global)
 
+        // scheduled maintenance that drains too many rpcs at once rejects the whole config
+        new_top_config
+            .check_maintenance_windows(Utc::now())
+            .web3_context("checking maintenance_windows")?;
+
+        // vendor methods can't name rpcs that are gone
+        new_top_config
+            .check_vendor_methods()
+            .web3_context("checking vendor_methods")?;
+
+        // invalid rules reject the whole config
+        self.apply_top_config_rewrites(new_top_config)?;
+
+        self.apply_top_config_limits(new_top_config);
+
         // connect to the db first
         let db = self.apply_top_config_db(new_top_config).await;
 
@@ -620,6 +1042,55 @@ impl App {
         Ok(())
     }
 
+    /// limits that we tune during incidents. these apply immediately without a restart
+    fn apply_top_config_limits(&self, new_top_config: &TopConfig) {
+        if **self.get_logs_limits.load() != new_top_config.app.get_logs {
+            info!(get_logs=?new_top_config.app.get_logs, "applying new eth_getLogs limits");
+
+            self.get_logs_limits
+                .store(Arc::new(new_top_config.app.get_logs.clone()));
+        }
+
+        if **self.rate_limit_weights.load() != new_top_config.app.rate_limit_weights {
+            info!(rate_limit_weights=?new_top_config.app.rate_limit_weights, "applying new rate limit weights");
+
+            self.rate_limit_weights
+                .store(Arc::new(new_top_config.app.rate_limit_weights.clone()));
+        }
+
+        if *self.exempt_traffic.config() != new_top_config.app.exempt_traffic {
+            info!(exempt_traffic=?new_top_config.app.exempt_traffic, "applying new exempt traffic");
+
+            self.exempt_traffic
+                .set_config(new_top_config.app.exempt_traffic.clone());
+        }
+
+        if max_json_depth() != new_top_config.app.max_json_depth {
+            info!(
+                max_json_depth = new_top_config.app.max_json_depth,
+                "applying new json depth limit"
+            );
+
+            set_max_json_depth(new_top_config.app.max_json_depth);
+        }
+    }
+
+    /// rewrite rules are checked again on every change since they can name rpcs. rules that keep their name keep their counts
+    fn apply_top_config_rewrites(&self, new_top_config: &TopConfig) -> Web3ProxyResult<()> {
+        let old = self.response_rewrites.load();
+
+        let new = ResponseRewrites::try_new(new_top_config, Some(&old))
+            .web3_context("checking response_rewrites")?;
+
+        if old.config() != new.config() {
+            info!(num = new.config().len(), "applying new response rewrites");
+        }
+
+        self.response_rewrites.store(Arc::new(new));
+
+        Ok(())
+    }
+
     async fn apply_top_config_rpcs(&self, new_top_config: &TopConfig) -> Web3ProxyResult<()> {
         info!("applying new config");
 
@@ -646,6 +1117,19 @@ impl App {
         protected?;
         bundler_4337?;
 
+        if new_top_config.app.pending_block_policy == PendingBlockPolicy::RouteToDesignated {
+            match new_top_config.app.pending_block_rpc.as_ref() {
+                Some(x) if new_top_config.balanced_rpcs.contains_key(x) => {}
+                x => {
+                    return Err(anyhow::anyhow!(
+                        "pending_block_policy is route_to_designated, but pending_block_rpc ({:?}) is not a balanced rpc",
+                        x
+                    )
+                    .into())
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -744,6 +1228,13 @@ impl App {
             info!("set global db connections");
         } else if new_top_config.app.db_replica_url.is_some() {
             return Err(anyhow::anyhow!("db_replica_url set, but no db_url set!").into());
+        } else if let Ok(db_conn) = global_db_conn() {
+            // the embedding program gave us a pool
+            if let Err(err) = migrate_db(&db_conn, false).await {
+                error!(?err, "unable to migrate!");
+            }
+
+            info!("using the given db connection");
         } else {
             warn!("no database. some features will be disabled");
         };
@@ -777,7 +1268,7 @@ impl App {
             }
 
             let internal_provider = connect_http(
-                format!("http://127.0.0.1:{}", frontend_port)
+                format!("http://127.0.0.1:{}{}", frontend_port, self.frontend_path)
                     .parse()
                     .unwrap(),
                 self.http_client.clone(),
@@ -935,18 +1426,148 @@ impl App {
         };
 
         #[derive(Serialize)]
-        struct CombinedMetrics {
+        struct BanCounts {
+            active: u64,
+            total_bans: u64,
+            total_violations: u64,
+        }
+
+        let ban_counts = BanCounts {
+            active: self.bans.num_active() as u64,
+            total_bans: self.bans.total_bans.load(Ordering::Relaxed),
+            total_violations: self.bans.total_violations.load(Ordering::Relaxed),
+        };
+
+        #[derive(Serialize)]
+        struct WebsocketCounts {
+            open: u64,
+            queued_messages: u64,
+            queued_bytes: u64,
+            dropped_messages: u64,
+            slow_disconnects: u64,
+        }
+
+        let websocket_counts = WebsocketCounts {
+            open: self.memory.websockets.load(Ordering::Relaxed) as u64,
+            queued_messages: self
+                .memory
+                .websocket_queued_messages
+                .load(Ordering::Relaxed) as u64,
+            queued_bytes: self.memory.websocket_queued_bytes.load(Ordering::Relaxed) as u64,
+            dropped_messages: self
+                .memory
+                .websocket_dropped_messages
+                .load(Ordering::Relaxed),
+            slow_disconnects: self
+                .memory
+                .websocket_slow_disconnects
+                .load(Ordering::Relaxed),
+        };
+
+        #[derive(Default, Serialize)]
+        struct TxOriginCounts {
+            sampled_out: u64,
+            dropped: u64,
+        }
+
+        let tx_origin_counts = self
+            .tx_origins
+            .as_ref()
+            .map(|x| TxOriginCounts {
+                sampled_out: x.sampled_out.load(Ordering::Relaxed),
+                dropped: x.dropped.load(Ordering::Relaxed),
+            })
+            .unwrap_or_default();
+
+        #[derive(Default, Serialize)]
+        struct CacheInvalidationCounts {
+            published: u64,
+            publish_errors: u64,
+            applied: u64,
+            apply_errors: u64,
+        }
+
+        let cache_invalidation_counts = self
+            .cache_invalidations
+            .as_ref()
+            .map(|x| CacheInvalidationCounts {
+                published: x.published.load(Ordering::Relaxed),
+                publish_errors: x.publish_errors.load(Ordering::Relaxed),
+                applied: x.applied.load(Ordering::Relaxed),
+                apply_errors: x.apply_errors.load(Ordering::Relaxed),
+            })
+            .unwrap_or_default();
+
+        // head subscriptions that went quiet while their node kept advancing
+        let silent_subscription_deaths: u64 = self
+            .balanced_rpcs
+            .by_name
+            .read()
+            .values()
+            .map(|x| x.silent_subscription_deaths.load(Ordering::Relaxed))
+            .sum();
+
+        #[derive(Default, Serialize)]
+        struct ScheduledMaintenanceCounts {
+            draining: u64,
+            awaiting_health_check: u64,
+        }
+
+        let mut scheduled_maintenance_counts = ScheduledMaintenanceCounts::default();
+
+        for rpc in self.balanced_rpcs.by_name.read().values() {
+            match rpc.scheduled_maintenance.as_ref().map(|x| x.state()) {
+                Some(ScheduledMaintenanceState::Draining { .. }) => {
+                    scheduled_maintenance_counts.draining += 1
+                }
+                Some(ScheduledMaintenanceState::AwaitingHealthCheck) => {
+                    scheduled_maintenance_counts.awaiting_health_check += 1
+                }
+                _ => {}
+            }
+        }
+
+        #[derive(Serialize)]
+        struct CombinedMetrics<'a> {
+            ban_counts: BanCounts,
+            block_queue: &'a BlockQueueSender,
+            cache_invalidation_counts: CacheInvalidationCounts,
+            canary_counts: CanaryCounts,
+            degraded_counts: DegradedCounts,
+            incoming_request_counts: IncomingRequestCounts,
+            latency_slo: &'a LatencySlo,
             recent_ip_counts: RecentCounts,
             recent_user_id_counts: RecentCounts,
             recent_tx_counts: RecentCounts,
+            response_budget: &'a ResponseBudget,
+            response_rewrites: &'a ResponseRewrites,
+            scheduled_maintenance_counts: ScheduledMaintenanceCounts,
+            silent_subscription_deaths: u64,
+            tx_origin_counts: TxOriginCounts,
             user_count: UserCount,
+            websocket_counts: WebsocketCounts,
         }
 
+        let response_rewrites = self.response_rewrites.load();
+
         let metrics = CombinedMetrics {
+            ban_counts,
+            block_queue: &self.balanced_rpcs.block_and_rpc_sender,
+            cache_invalidation_counts,
+            canary_counts: self.canary.as_ref().map(|x| x.counts()).unwrap_or_default(),
+            degraded_counts: self.degraded.counts(),
+            incoming_request_counts: self.incoming_requests.counts(),
+            latency_slo: &self.latency_slo,
             recent_ip_counts,
             recent_user_id_counts,
             recent_tx_counts,
+            response_budget: &self.response_budget,
+            response_rewrites: &response_rewrites,
+            scheduled_maintenance_counts,
+            silent_subscription_deaths,
+            tx_origin_counts,
             user_count,
+            websocket_counts,
         };
 
         // TODO: i don't like this library. it doesn't include HELP or TYPE lines and so our prometheus server fails to parse it
@@ -978,8 +1599,8 @@ impl App {
         let request =
             SingleRequest::new(LooseId::Number(1), method.to_string().into(), json!(params))?;
 
-        let (_, response, _) = self
-            .proxy_request(request, authorization, None, request_id)
+        let (_, response, _, _, _) = self
+            .proxy_request(request, authorization, None, None, request_id)
             .await;
 
         // TODO: error handling?
@@ -992,29 +1613,69 @@ impl App {
     }
 
     /// send the request or batch of requests to the approriate RPCs
+    /// the `OlderHead` is set if the client was answered with an older head than it already saw
+    /// the `Decimal` is what the request (or the whole batch) is charged
+    /// `pin_batch` is false if the client asked for its batch to be spread across backends
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
         authorization: Arc<Authorization>,
         request: JsonRpcRequestEnum,
         request_id: Option<String>,
-    ) -> Web3ProxyResult<(StatusCode, jsonrpc::Response, Vec<Arc<Web3Rpc>>)> {
+        pin_batch: bool,
+    ) -> Web3ProxyResult<(
+        StatusCode,
+        jsonrpc::Response,
+        Vec<Arc<Web3Rpc>>,
+        Option<OlderHead>,
+        Decimal,
+    )> {
         // trace!(?request, "proxy_web3_rpc");
 
         let response = match request {
             JsonRpcRequestEnum::Single(request) => {
-                let (status_code, response, rpcs) = self
-                    .proxy_request(request, authorization.clone(), None, request_id)
+                let (status_code, response, rpcs, older_head, cost) = self
+                    .proxy_request(request, authorization.clone(), None, None, request_id)
                     .await;
 
-                (status_code, jsonrpc::Response::Single(response), rpcs)
+                (
+                    status_code,
+                    jsonrpc::Response::Single(response),
+                    rpcs,
+                    older_head,
+                    cost,
+                )
             }
             JsonRpcRequestEnum::Batch(requests) => {
-                let (responses, rpcs) = self
-                    .proxy_web3_rpc_requests(&authorization, requests, request_id)
+                if requests.len() > self.config.max_batch_size {
+                    self.request_metrics.record_too_large(TooLarge::Batch);
+
+                    self.bans
+                        .record_authorization(&authorization, Violation::OversizedBatch)
+                        .await;
+
+                    return Err(Web3ProxyError::BatchTooLarge {
+                        len: requests.len(),
+                        max: self.config.max_batch_size,
+                    });
+                }
+
+                let (responses, rpcs, older_head, cost) = self
+                    .proxy_web3_rpc_requests(
+                        &authorization,
+                        requests,
+                        request_id,
+                        pin_batch && self.config.pin_batches,
+                    )
                     .await?;
 
                 // TODO: real status code. if an error happens, i don't think we are following the spec here
-                (StatusCode::OK, jsonrpc::Response::Batch(responses), rpcs)
+                (
+                    StatusCode::OK,
+                    jsonrpc::Response::Batch(responses),
+                    rpcs,
+                    older_head,
+                    cost,
+                )
             }
         };
 
@@ -1022,36 +1683,56 @@ impl App {
     }
 
     /// cut up the request and send to potentually different servers
-    /// TODO: make sure this isn't a problem
+    /// a pinned batch prefers one server that has the batch's head block. the others are only used if it can't answer
     async fn proxy_web3_rpc_requests(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
         requests: Vec<SingleRequest>,
         request_id: Option<String>,
-    ) -> Web3ProxyResult<(Vec<jsonrpc::ParsedResponse>, Vec<Arc<Web3Rpc>>)> {
+        pin_batch: bool,
+    ) -> Web3ProxyResult<(
+        Vec<jsonrpc::ParsedResponse>,
+        Vec<Arc<Web3Rpc>>,
+        Option<OlderHead>,
+        Decimal,
+    )> {
         // TODO: we should probably change ethers-rs to support this directly. they pushed this off to v2 though
         let num_requests = requests.len();
 
         if num_requests == 0 {
-            return Ok((vec![], vec![]));
+            return Ok((vec![], vec![], None, Decimal::ZERO));
         }
 
         // get the head block now so that any requests that need it all use the same block
+        // without a head, each request gets its own error instead of failing the whole batch
         // TODO: this still has an edge condition if there is a reorg in the middle of the request!!!
-        let head_block: BlockHeader = self
-            .balanced_rpcs
-            .head_block()
-            .ok_or(Web3ProxyError::NoServersSynced)?;
+        let head_block = self.balanced_rpcs.head_block();
 
-        // TODO: use streams and buffers so we don't overwhelm our server
-        let responses = join_all(
-            requests
+        let batch_rpc = if pin_batch && num_requests > 1 {
+            head_block.as_ref().and_then(|head_block| {
+                self.balanced_rpcs
+                    .watch_ranked_rpcs
+                    .borrow()
+                    .as_ref()
+                    .and_then(|x| x.batch_rpc(head_block))
+            })
+        } else {
+            None
+        };
+
+        // errors are returned per request and need the request's id
+        let ids: Vec<_> = requests.iter().map(|x| x.id.clone()).collect();
+
+        // TODO: use streams and buffers so we don't overwhelm our server
+        let responses = join_all(
+            requests
                 .into_iter()
                 .map(|request| {
                     self.proxy_request(
                         request,
                         authorization.clone(),
-                        Some(head_block.clone()),
+                        head_block.clone(),
+                        batch_rpc.as_ref().map(|x| x.name.as_str()),
                         request_id.clone(),
                     )
                 })
@@ -1062,12 +1743,26 @@ impl App {
         let mut collected: Vec<jsonrpc::ParsedResponse> = Vec::with_capacity(num_requests);
         let mut collected_rpc_names: HashSet<String> = HashSet::new();
         let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
-        for response in responses {
+        let mut collected_older_head = None;
+        let mut collected_cost = Decimal::ZERO;
+        for (response, id) in responses.into_iter().zip(ids) {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
-            let (_status_code, response, rpcs) = response;
+            let (_status_code, response, rpcs, older_head, cost) = response;
 
-            // TODO: individual error handling
-            collected.push(response.parsed().await?);
+            collected_older_head = collected_older_head.or(older_head);
+            collected_cost += cost;
+
+            // a long response can still fail while it is read. only that entry gets the error
+            let response = match response.parsed().await {
+                Ok(x) => x,
+                Err(err) => {
+                    let (_, response_data) = err.as_response_parts(None::<RequestForError>);
+
+                    jsonrpc::ParsedResponse::from_response_data(response_data, id)
+                }
+            };
+
+            collected.push(response);
             collected_rpcs.extend(rpcs.into_iter().filter(|x| {
                 if collected_rpc_names.contains(&x.name) {
                     false
@@ -1080,7 +1775,12 @@ impl App {
             // TODO: what should we do with the status code? check the jsonrpc spec
         }
 
-        Ok((collected, collected_rpcs))
+        Ok((
+            collected,
+            collected_rpcs,
+            collected_older_head,
+            collected_cost,
+        ))
     }
 
     pub async fn redis_conn(&self) -> Web3ProxyResult<redis_rate_limiter::RedisConnection> {
@@ -1095,6 +1795,20 @@ impl App {
         }
     }
 
+    /// error if a request is for a different chain. keys with `skip_chain_id_check` are trusted to know what they are doing
+    fn check_chain_id(&self, web3_request: &ValidatedRequest, found: U64) -> Web3ProxyResult<()> {
+        if found.as_u64() == self.config.chain_id
+            || web3_request.authorization.checks.skip_chain_id_check
+        {
+            return Ok(());
+        }
+
+        Err(Web3ProxyError::ChainIdMismatch {
+            expected: self.config.chain_id,
+            found,
+        })
+    }
+
     /// try to send transactions to the best available rpcs with protected/private mempools
     /// if no protected rpcs are configured (and protected_only is false), then public rpcs are used instead
     /// TODO: should this return an H256 instead of an Arc<RawValue>?
@@ -1119,97 +1833,72 @@ impl App {
         let bytes = Bytes::from_str(params)
             .map_err(|_| Web3ProxyError::BadRequest("Unable to parse params as bytes".into()))?;
 
-        if bytes.is_empty() {
-            return Err(Web3ProxyError::BadRequest("empty bytes".into()));
-        }
-
-        let rlp = Rlp::new(bytes.as_ref());
+        // this understands typed envelopes, including blob transactions with their sidecars
+        let tx = RawTransaction::decode(bytes.as_ref())?;
 
-        let tx = Transaction::decode(&rlp).map_err(|_| {
-            Web3ProxyError::BadRequest("failed to parse rlp into transaction".into())
-        })?;
+        // the request still holds the blobs, but we don't need them after this
+        drop(bytes);
 
         if let Some(chain_id) = tx.chain_id {
-            if self.config.chain_id != chain_id.as_u64() {
-                return Err(Web3ProxyError::BadRequest(
-                    format!(
-                        "unexpected chain_id. {} != {}",
-                        chain_id, self.config.chain_id
-                    )
-                    .into(),
-                ));
-            }
+            self.check_chain_id(web3_request, chain_id)?;
+        }
+
+        // nodes only accept blob transactions in the network form. without the blobs, every backend would reject it
+        if tx.is_blob() && !tx.has_sidecar {
+            return Err(Web3ProxyError::BadRequest(
+                "blob transactions must be sent with their blobs, commitments, and proofs".into(),
+            ));
         }
 
         // TODO: return now if already confirmed
         // TODO: error if the nonce is way far in the future
 
-        let mut response = if protected_only {
-            if self.protected_rpcs.is_empty() {
+        let txid = tx.hash;
+
+        let response = match self.private_rpcs() {
+            // every private rpc gets the transaction. the first to accept it answers
+            PrivateRpcs::Dedicated(x) => x.broadcast_raw_transaction(web3_request, txid).await?,
+            PrivateRpcs::SameAsBalanced if protected_only => {
                 // TODO: different error?
                 return Err(Web3ProxyError::NoServersSynced);
             }
-            self.protected_rpcs
-                .request_with_metadata(web3_request)
-                .await
-        } else if self.protected_rpcs.is_empty() {
-            self.balanced_rpcs.request_with_metadata(web3_request).await
-        } else {
-            self.protected_rpcs
-                .request_with_metadata(web3_request)
-                .await
-        };
+            // this is a best-server send like any other request. relaying to every public rpc would waste capacity
+            PrivateRpcs::SameAsBalanced => {
+                let mut response = self.balanced_rpcs.request_with_metadata(web3_request).await;
+
+                // TODO: helper for doing parsed() inside a response?
+                if let Ok(SingleResponse::Stream(x)) = response {
+                    response = x
+                        .read()
+                        .await
+                        .map(SingleResponse::Parsed)
+                        .map_err(Into::into);
+                }
 
-        // TODO: helper for doing parsed() inside a response?
-        if let Ok(SingleResponse::Stream(x)) = response {
-            response = x
-                .read()
-                .await
-                .map(SingleResponse::Parsed)
-                .map_err(Into::into);
-        }
+                let response = ForwardedResponse::try_from(response);
 
-        let mut response = response.try_into()?;
-
-        let txid = tx.hash();
-
-        // sometimes we get an error that the transaction is already known by our nodes,
-        // that's not really an error. Return the hash like a successful response would.
-        // TODO: move this to a helper function. probably part of try_send_protected
-        if let ForwardedResponse::RpcError { error_data, .. } = &response {
-            let acceptable_error_messages = [
-                "already known",
-                "ALREADY_EXISTS: already known",
-                "INTERNAL_ERROR: existing tx with same hash",
-                "",
-            ];
-            if acceptable_error_messages.contains(&error_data.message.as_ref()) {
-                response = ForwardedResponse::from(json!(txid));
+                // "already known" and friends mean the backend has it. that's not really an error
+                // some backends answer with an array. the sender only cares about the hash
+                if rejection_rank(&response).is_none() {
+                    ForwardedResponse::from(json!(txid))
+                } else {
+                    response?
+                }
             }
-        }
+        };
 
         // if successful, send the txid to the pending transaction firehose
-        if let ForwardedResponse::Result { value, .. } = &response {
-            // no idea how we got an array here, but lets force this to just the txid
-            // TODO: think about this more
-            if value.get().starts_with('[') {
-                let backend_rpcs = web3_request.backend_rpcs_used();
-
-                let backend_rpcs = backend_rpcs
-                    .iter()
-                    .map(|x| x.name.as_str())
-                    .collect::<Vec<_>>();
-
-                error!(
-                    ?value,
-                    ?txid,
-                    ?backend_rpcs,
-                    "unexpected array response from sendRawTransaction"
-                );
-                response = ForwardedResponse::from(json!(txid));
+        // subscribers see it now instead of when a backend's mempool gossips it back to us
+        if let ForwardedResponse::Result { .. } = &response {
+            self.pending_txid_firehose.send(txid).await;
+
+            if let Some(tx_origins) = self.tx_origins.as_ref() {
+                tx_origins.record(txid, &web3_request.authorization);
             }
 
-            self.pending_txid_firehose.send(txid).await;
+            if let Some(tx_tracker) = self.tx_tracker.as_ref() {
+                tx_tracker.sent(txid);
+            }
 
             // emit transaction count stats
             // TODO: different salt for ips and transactions?
@@ -1248,13 +1937,23 @@ impl App {
     }
 
     /// proxy request with up to 3 tries.
+    /// `batch_rpc` is tried first. the other requests in this one's batch were sent there too
     async fn proxy_request(
         self: &Arc<Self>,
         request: SingleRequest,
         authorization: Arc<Authorization>,
         head_block: Option<BlockHeader>,
+        batch_rpc: Option<&str>,
         request_id: Option<String>,
-    ) -> (StatusCode, jsonrpc::SingleResponse, Vec<Arc<Web3Rpc>>) {
+    ) -> (
+        StatusCode,
+        jsonrpc::SingleResponse,
+        Vec<Arc<Web3Rpc>>,
+        Option<OlderHead>,
+        Decimal,
+    ) {
+        let _in_flight = self.recent_requests.start();
+
         // TODO: this clone is only for an error response. refactor to not need it
         let error_id = request.id.clone();
 
@@ -1269,6 +1968,15 @@ impl App {
             head_block
         };
 
+        // don't let this client see the chain go backwards
+        let (head_block, older_head) = match self.head_watermarks.as_ref() {
+            Some(x) => {
+                x.guard(&authorization, head_block, &self.balanced_rpcs)
+                    .await
+            }
+            None => (head_block, None),
+        };
+
         let web3_request = match ValidatedRequest::new_with_app(
             self,
             authorization.clone(),
@@ -1287,17 +1995,23 @@ impl App {
 
                 let rpcs = vec![];
 
-                return (a, b, rpcs);
+                // the request never became a ValidatedRequest, so no stat is sent for it
+                return (a, b, rpcs, older_head, Decimal::ZERO);
             }
         };
 
+        if let Some(batch_rpc) = batch_rpc {
+            web3_request.response.lock().batch_rpc = Some(batch_rpc.to_string());
+        }
+
         let mut last_success = None;
         let mut last_error = None;
 
-        let latest_start = sleep_until(Instant::now() + Duration::from_secs(3));
+        let retry_policy = RetryPolicy::from(&self.config);
+
+        let latest_start = sleep_until(web3_request.start_instant + retry_policy.deadline);
         pin!(latest_start);
 
-        // TODO: how many retries?
         loop {
             // TODO: refresh the request here?
 
@@ -1307,8 +2021,26 @@ impl App {
                     last_success = Some(response_data);
                     break;
                 }
+                // retrying would get the same response
+                Err(err @ Web3ProxyError::ResponseTooLarge { .. }) => {
+                    last_error = Some(err);
+                    break;
+                }
                 Err(err) => {
                     last_error = Some(err);
+
+                    // the next best servers were already tried. this only waits for the rankings to change
+                    let failed = web3_request.backend_rpcs_used().len();
+
+                    if failed > 0
+                        && !retry_policy.should_retry(
+                            web3_request.inner.method(),
+                            failed,
+                            web3_request.start_instant,
+                        )
+                    {
+                        break;
+                    }
                 }
             }
 
@@ -1318,7 +2050,7 @@ impl App {
                     ranked_rpcs_recv.borrow_and_update();
                 }
                 _ = &mut latest_start => {
-                    // do not retry if we've already been trying for 3 seconds
+                    // do not retry past the deadline
                     break;
                 }
             }
@@ -1332,35 +2064,42 @@ impl App {
             Err(last_error.unwrap_or(anyhow::anyhow!("no success or error").into()))
         };
 
+        // the backend answered. the answer was just too big
+        let backend_error = last_response.is_err()
+            && !matches!(last_response, Err(Web3ProxyError::ResponseTooLarge { .. }));
+
         let (code, response) = match last_response {
             Ok(response_data) => {
-                let user_error_response = response_data.is_jsonrpc_err();
-
-                let mut response_lock = web3_request.response.lock();
-
-                // TODO: i really don't like this logic here. it should be inside add_response
-                response_lock.error_response = false;
-
                 // TODO: is it true that all jsonrpc errors are user errors?
-                response_lock.user_error_response = user_error_response;
+                let error_class = if response_data.is_jsonrpc_err() {
+                    ErrorClass::User
+                } else {
+                    ErrorClass::None
+                };
 
-                drop(response_lock);
+                // TODO: i really don't like this logic here. it should be inside add_response
+                web3_request.response.lock().set_error_class(error_class);
 
                 (StatusCode::OK, response_data)
             }
             Err(err) => {
                 // max tries exceeded. return the error
 
-                let mut response_lock = web3_request.response.lock();
+                let (code, response) =
+                    err.as_json_response_parts(web3_request.id(), Some(web3_request.as_ref()));
 
                 // TODO: i really don't like this logic here. it should be inside add_error_response
                 // TODO: what if this is an ethers wrapped error? those should have already been handled, but our error types are too broad
-                response_lock.error_response = true;
-                response_lock.user_error_response = false;
+                web3_request
+                    .response
+                    .lock()
+                    .set_error_class(ErrorClass::new(&err, code));
 
-                drop(response_lock);
+                if let Some(recent_errors) = self.recent_errors.as_ref() {
+                    recent_errors.record(&web3_request, code, &response).await;
+                }
 
-                err.as_json_response_parts(web3_request.id(), Some(web3_request.as_ref()))
+                (code, response)
             }
         };
 
@@ -1368,11 +2107,555 @@ impl App {
 
         let rpcs = web3_request.backend_rpcs_used();
 
-        (code, response, rpcs)
+        self.recent_requests
+            .record_request(web3_request.inner.method(), rpcs.is_empty());
+
+        if web3_request.authorization.authorization_type != AuthorizationType::Internal {
+            let outcome = if backend_error {
+                RequestOutcome::BackendError
+            } else if rpcs.is_empty() {
+                RequestOutcome::CacheHit
+            } else {
+                RequestOutcome::CacheMiss
+            };
+
+            self.request_metrics.record(
+                web3_request.inner.method(),
+                outcome,
+                web3_request.start_instant.elapsed(),
+            );
+
+            let backends: Vec<_> = rpcs.iter().map(|x| x.name.as_str()).collect();
+
+            self.latency_slo.record(
+                web3_request.inner.method(),
+                rpcs.is_empty(),
+                web3_request.authorization.checks.user_tier_title.as_deref(),
+                &backends,
+                web3_request.start_instant.elapsed(),
+            );
+        }
+
+        let cost = web3_request.compute_unit_cost();
+
+        (code, response, rpcs, older_head, cost)
+    }
+
+    /// Split an eth_getLogs that is too large for one backend request into pages and merge their logs.
+    /// Each page is its own request with its own stats. If any page fails, the whole query fails.
+    async fn proxy_paginated_logs(
+        self: &Arc<Self>,
+        web3_request: &Arc<ValidatedRequest>,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse> {
+        let CacheMode::Range {
+            from_block,
+            to_block,
+            ..
+        } = &web3_request.cache_mode
+        else {
+            return Err(Web3ProxyError::BadRequest(
+                "auto pagination needs a block range".into(),
+            ));
+        };
+
+        let limits = self.get_logs_limits.load_full();
+
+        let filter = web3_request.inner.params()[0].clone();
+
+        let page_size = limits.page_size(&filter, web3_request.started_active_premium);
+
+        let pages = page_ranges(from_block.num(), to_block.num(), page_size);
+
+        trace!(num_pages = pages.len(), page_size, "paginating eth_getLogs");
+
+        // pages finish in order so that an error is for the first range that failed
+        let mut responses = stream::iter(pages)
+            .map(|(from, to)| {
+                let mut filter = filter.clone();
+
+                filter["fromBlock"] = json!(from);
+                filter["toBlock"] = json!(to);
+
+                async move { (from, to, self.proxy_logs_page(web3_request, filter).await) }
+            })
+            .buffered(limits.pagination_concurrency.max(1));
+
+        let mut logs = PaginatedLogs::default();
+
+        while let Some((from, to, result)) = responses.next().await {
+            let result = result.map_err(|err| Web3ProxyError::GetLogsPageFailed {
+                from,
+                to,
+                err: Box::new(err),
+            })?;
+
+            logs.add_page(&limits, from, to, &result)?;
+        }
+
+        let logs = serde_json::Value::Array(logs.into_sorted());
+
+        Ok(jsonrpc::ParsedResponse::from_value(logs, web3_request.id()).into())
+    }
+
+    /// one page of an auto paginated eth_getLogs. `filter` must already be small enough to pass the limits
+    async fn proxy_logs_page(
+        self: &Arc<Self>,
+        web3_request: &Arc<ValidatedRequest>,
+        filter: serde_json::Value,
+    ) -> Web3ProxyResult<Arc<RawValue>> {
+        let request =
+            SingleRequest::new(LooseId::Number(1), "eth_getLogs".into(), json!([filter]))?;
+
+        let page_request = ValidatedRequest::new_with_app(
+            self,
+            web3_request.authorization.clone(),
+            Some(
+                web3_request
+                    .expire_at()
+                    .saturating_duration_since(Instant::now()),
+            ),
+            None,
+            request.into(),
+            web3_request.head_block.clone(),
+            web3_request.request_id.clone(),
+        )
+        .await?;
+
+        let response = timeout_at(
+            web3_request.expire_at(),
+            self.balanced_rpcs
+                .try_proxy_connection::<Arc<RawValue>>(&page_request),
+        )
+        .await??;
+
+        let result = response.parsed().await?.into_result()?;
+
+        page_request.set_response(result.get().len() as u64);
+
+        Ok(result)
     }
 
     /// main logic for proxy_cached_request but in a dedicated function so the try operator is easy to use
     /// TODO: how can we make this generic?
+    /// fetch a cached request from a backend in the background and record if the cached value was stale
+    fn spawn_cache_revalidation(
+        self: &Arc<Self>,
+        web3_request: &ValidatedRequest,
+        cached: ForwardedResponse<Arc<RawValue>>,
+        age: Duration,
+    ) {
+        let request = match &web3_request.inner {
+            RequestOrMethod::Request(x) => x.clone(),
+            _ => return,
+        };
+
+        let app = self.clone();
+        let head_block = web3_request.head_block.clone();
+        let method = web3_request.inner.method().to_string();
+
+        tokio::spawn(async move {
+            let fresh = async {
+                let authorization = Arc::new(Authorization::internal()?);
+
+                let revalidate_request = ValidatedRequest::new_with_app(
+                    &app,
+                    authorization,
+                    None,
+                    None,
+                    request.into(),
+                    head_block,
+                    None,
+                )
+                .await?;
+
+                let response = timeout_at(
+                    revalidate_request.expire_at(),
+                    app.balanced_rpcs
+                        .try_proxy_connection::<Arc<RawValue>>(&revalidate_request),
+                )
+                .await?;
+
+                ForwardedResponse::try_from(response)
+            }
+            .await;
+
+            match fresh {
+                Ok(fresh) => app.cache_revalidation.record(&method, age, &cached, &fresh),
+                Err(err) => {
+                    trace!(?err, %method, "cache revalidation failed");
+                    app.cache_revalidation.record_error(&method);
+                }
+            }
+        });
+    }
+
+    /// The archive cache if the request's blocks are past the reorg horizon. Otherwise the normal response cache
+    pub fn response_cache(&self, web3_request: &ValidatedRequest) -> &JsonRpcResponseCache {
+        match self.archive_response_cache.as_ref() {
+            Some(x) if web3_request.past_reorg_horizon(self.config.archive_cache_confirmations) => {
+                x
+            }
+            _ => &self.jsonrpc_response_cache,
+        }
+    }
+
+    async fn disk_cache_get(
+        &self,
+        web3_request: &ValidatedRequest,
+    ) -> Option<ForwardedResponse<Arc<RawValue>>> {
+        self.disk_cache.as_ref()?.get(web3_request).await
+    }
+
+    /// Responses bigger than this would be evicted from `response_cache` as soon as they were inserted
+    fn max_cached_response_bytes(&self, web3_request: &ValidatedRequest) -> u64 {
+        match self.archive_response_cache.as_ref() {
+            Some(_) if web3_request.past_reorg_horizon(self.config.archive_cache_confirmations) => {
+                self.config.archive_cache_max_bytes / 1000
+            }
+            _ => self.config.response_cache_max_bytes / 1000,
+        }
+    }
+
+    /// Save a response in the cache. Nothing is saved while writes are paused, for a stale head block, or if the backend that answered isn't cacheable.
+    async fn cache_response(
+        &self,
+        cache_key: u64,
+        web3_request: &ValidatedRequest,
+        response: ForwardedResponse<Arc<RawValue>>,
+    ) -> bool {
+        if !self.should_cache_response(web3_request) {
+            return false;
+        }
+
+        self.response_cache(web3_request)
+            .insert(cache_key, self.cached_response(web3_request, response))
+            .await;
+
+        self.response_cached(cache_key, web3_request).await;
+
+        true
+    }
+
+    /// False while writes are paused, for a stale head block, or if the backend that answered isn't cacheable
+    fn should_cache_response(&self, web3_request: &ValidatedRequest) -> bool {
+        if !self.response_cache_writes.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        // a stale head would keep serving this long after the chain moves on
+        if self.head_staleness.skip_cache(web3_request) {
+            trace!("not caching a response for a stale head");
+            return false;
+        }
+
+        // the last rpc used is the one that answered
+        if let Some(rpc) = web3_request.backend_rpcs_used().pop() {
+            if !rpc.cacheable {
+                trace!(rpc=%rpc.name, "not caching a response from an uncacheable rpc");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// `cache_response` for a response that came from a backend. Immutable ones are also saved to disk
+    async fn cache_forwarded_response(
+        &self,
+        cache_key: u64,
+        web3_request: &ValidatedRequest,
+        response: ForwardedResponse<Arc<RawValue>>,
+    ) {
+        if self
+            .cache_response(cache_key, web3_request, response.clone())
+            .await
+        {
+            self.disk_cache_save(web3_request, &response);
+        }
+    }
+
+    fn disk_cache_save(
+        &self,
+        web3_request: &ValidatedRequest,
+        response: &ForwardedResponse<Arc<RawValue>>,
+    ) {
+        if let Some(disk_cache) = self.disk_cache.as_ref() {
+            disk_cache.save(web3_request, response);
+        }
+    }
+
+    /// The response with its method's ttl from `response_cache_ttls`
+    fn cached_response(
+        &self,
+        web3_request: &ValidatedRequest,
+        response: ForwardedResponse<Arc<RawValue>>,
+    ) -> CachedResponse {
+        let ttl = self
+            .config
+            .response_cache_ttls
+            .get(web3_request.inner.method())
+            .map(|x| Duration::from_secs(*x));
+
+        CachedResponse { response, ttl }
+    }
+
+    /// Everything that tracks a cached response. Called after the response is inserted into the cache
+    async fn response_cached(&self, cache_key: u64, web3_request: &ValidatedRequest) {
+        if let Some(rpc) = web3_request.backend_rpcs_used().pop() {
+            self.jsonrpc_response_cache_sources
+                .insert(cache_key, rpc.name.as_str().into())
+                .await;
+        }
+
+        if let Some(block) = web3_request.cache_mode.cache_block() {
+            self.jsonrpc_response_cache_blocks
+                .insert(cache_key, *block.hash())
+                .await;
+        }
+
+        self.cache_revalidation.cached(cache_key).await;
+    }
+
+    /// Send a cacheable request to a backend. Also returns the response to cache if it is small enough and not a stream.
+    /// Cache keys that can't be cached are remembered so that identical requests skip the cache next time.
+    async fn forward_cacheable(
+        &self,
+        cache_key: u64,
+        web3_request: &ValidatedRequest,
+        max_response_cache_bytes: u64,
+    ) -> Web3ProxyResult<(SingleResponse, Option<ForwardedResponse<Arc<RawValue>>>)> {
+        let response_data = timeout_at(
+            web3_request.expire_at(),
+            self.balanced_rpcs
+                .try_proxy_connection::<Arc<RawValue>>(web3_request),
+        )
+        .await?;
+
+        match response_data {
+            Ok(mut x) => {
+                let mut cached = None;
+
+                match &x {
+                    SingleResponse::Parsed(x) => {
+                        let response = ForwardedResponse::from(x.payload.clone());
+
+                        if response.num_bytes() <= max_response_cache_bytes {
+                            cached = Some(response);
+                        } else {
+                            self.jsonrpc_response_failed_cache_keys
+                                .insert(cache_key, ())
+                                .await;
+                        }
+                    }
+                    SingleResponse::Stream(..) => {
+                        self.jsonrpc_response_failed_cache_keys
+                            .insert(cache_key, ())
+                            .await;
+                    }
+                }
+
+                x.set_id(web3_request.id());
+
+                Ok((x, cached))
+            }
+            Err(err) => {
+                if web3_request.cache_jsonrpc_errors() {
+                    // we got an error, but we are supposed to cache jsonrpc errors.
+                    let x: Result<ForwardedResponse<Arc<RawValue>>, Web3ProxyError> =
+                        err.try_into();
+
+                    if x.is_err() {
+                        // we still have an Err. it must not have been a jsonrpc error
+                        self.jsonrpc_response_failed_cache_keys
+                            .insert(cache_key, ())
+                            .await;
+                    }
+
+                    // TODO: needing multiple into/try_into/from must be inefficient. investigate this
+                    Ok((
+                        ParsedResponse::from_response_data(x?, web3_request.id()).into(),
+                        None,
+                    ))
+                } else {
+                    // we got an error, and we are not supposed to cache jsonrpc errors. exit early
+                    self.jsonrpc_response_failed_cache_keys
+                        .insert(cache_key, ())
+                        .await;
+
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Save an `eth_call` response for its target's ttl. Only successes are kept, and the same pauses apply as for the response cache.
+    async fn cache_call(
+        &self,
+        target: &CallCacheTarget,
+        web3_request: &ValidatedRequest,
+        response: &SingleResponse,
+    ) -> bool {
+        if !self.response_cache_writes.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let SingleResponse::Parsed(parsed) = response else {
+            return false;
+        };
+
+        if !matches!(parsed.payload, jsonrpc::ResponsePayload::Success { .. }) {
+            return false;
+        }
+
+        if let Some(rpc) = web3_request.backend_rpcs_used().pop() {
+            if !rpc.cacheable {
+                trace!(rpc=%rpc.name, "not caching a call from an uncacheable rpc");
+                return false;
+            }
+        }
+
+        self.call_cache
+            .insert(target, ForwardedResponse::from(parsed.payload.clone()))
+            .await;
+
+        true
+    }
+
+    /// Where transactions are sent. This is checked on every send because a config reload can add or remove private rpcs
+    pub fn private_rpcs(&self) -> PrivateRpcs<'_> {
+        if self.protected_rpcs.is_empty() {
+            PrivateRpcs::SameAsBalanced
+        } else {
+            PrivateRpcs::Dedicated(&self.protected_rpcs)
+        }
+    }
+
+    /// The rpcs that make up a group
+    pub fn rpc_group(&self, group: RpcGroup) -> &Arc<Web3Rpcs> {
+        match group {
+            RpcGroup::Balanced => &self.balanced_rpcs,
+            RpcGroup::Private => &self.protected_rpcs,
+            RpcGroup::Bundler4337 => &self.bundler_4337_rpcs,
+        }
+    }
+
+    /// Tell the other servers to drop something from their caches. Does nothing without redis
+    pub fn publish_invalidation(&self, invalidation: Invalidation) {
+        if let Some(x) = self.cache_invalidations.as_ref() {
+            x.publish(invalidation);
+        }
+    }
+
+    /// Forget a key's cached checks so that changes to it (like disabling it) apply to the very next request.
+    /// The other servers are told to forget the owner's keys too
+    pub async fn invalidate_rpc_key(&self, rpc_secret_key: &RpcSecretKey, user_id: u64) {
+        // a refresh that is already loading must not put the old checks back
+        self.rpc_secret_key_refreshing.lock().remove(rpc_secret_key);
+
+        self.rpc_secret_key_cache.invalidate(rpc_secret_key).await;
+
+        self.publish_invalidation(Invalidation::User { user_id });
+    }
+
+    /// Drop something from this server's caches because another server said to. Nothing is published
+    pub async fn apply_invalidation(&self, invalidation: &Invalidation) -> Web3ProxyResult<()> {
+        match invalidation {
+            Invalidation::Reorg { number, orphaned } => {
+                self.balanced_rpcs.forget_blocks_from(*number).await;
+                self.purge_orphaned_responses(orphaned).await;
+            }
+            Invalidation::ResponseCache {
+                key: Some(key),
+                backend: _,
+            } => {
+                self.jsonrpc_response_cache.invalidate(key).await;
+                if let Some(x) = self.archive_response_cache.as_ref() {
+                    x.invalidate(key).await;
+                }
+                self.jsonrpc_response_cache_sources.invalidate(key).await;
+                self.jsonrpc_response_cache_blocks.invalidate(key).await;
+            }
+            Invalidation::ResponseCache { key: None, backend } => {
+                self.purge_response_cache(backend.as_deref()).await;
+            }
+            Invalidation::CallCache { address } => {
+                self.call_cache.purge(*address).await;
+            }
+            Invalidation::User { user_id } => {
+                self.user_balance_cache
+                    .invalidate_local(user_id, &global_db_conn()?, &self.rpc_secret_key_cache)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove cached responses. If `backend` is set, only the responses that came from it are removed.
+    /// Returns how many entries were removed. When clearing everything, this is moka's estimate.
+    pub async fn purge_response_cache(&self, backend: Option<&str>) -> u64 {
+        let Some(backend) = backend else {
+            let mut count = self.jsonrpc_response_cache.entry_count();
+
+            self.jsonrpc_response_cache.invalidate_all();
+            if let Some(x) = self.archive_response_cache.as_ref() {
+                count += x.entry_count();
+                x.invalidate_all();
+            }
+            self.jsonrpc_response_cache_sources.invalidate_all();
+            self.jsonrpc_response_cache_blocks.invalidate_all();
+
+            return count;
+        };
+
+        let keys: Vec<u64> = self
+            .jsonrpc_response_cache_sources
+            .iter()
+            .filter(|(_, v)| v.as_ref() == backend)
+            .map(|(k, _)| *k)
+            .collect();
+
+        for key in keys.iter() {
+            self.jsonrpc_response_cache.invalidate(key).await;
+            if let Some(x) = self.archive_response_cache.as_ref() {
+                x.invalidate(key).await;
+            }
+            self.jsonrpc_response_cache_sources.invalidate(key).await;
+            self.jsonrpc_response_cache_blocks.invalidate(key).await;
+        }
+
+        keys.len() as u64
+    }
+
+    /// Remove cached responses for blocks that a reorg replaced. Returns how many were removed
+    pub async fn purge_orphaned_responses(&self, orphaned: &[H256]) -> u64 {
+        if orphaned.is_empty() {
+            return 0;
+        }
+
+        let keys: Vec<u64> = self
+            .jsonrpc_response_cache_blocks
+            .iter()
+            .filter(|(_, v)| orphaned.contains(v))
+            .map(|(k, _)| *k)
+            .collect();
+
+        for key in keys.iter() {
+            self.jsonrpc_response_cache.invalidate(key).await;
+            if let Some(x) = self.archive_response_cache.as_ref() {
+                x.invalidate(key).await;
+            }
+            self.jsonrpc_response_cache_sources.invalidate(key).await;
+            self.jsonrpc_response_cache_blocks.invalidate(key).await;
+        }
+
+        if !keys.is_empty() {
+            debug!(count = keys.len(), "purged responses for orphaned blocks");
+        }
+
+        keys.len() as u64
+    }
+
     async fn _proxy_request_with_caching(
         self: &Arc<Self>,
         web3_request: &Arc<ValidatedRequest>,
@@ -1421,6 +2704,9 @@ impl App {
             | "eth_sendTransaction"
             | "eth_sign"
             | "eth_signTransaction"
+            | "eth_signTypedData"
+            | "eth_signTypedData_v3"
+            | "eth_signTypedData_v4"
             | "eth_submitHashrate"
             | "eth_submitWork"
             | "les_addBalance"
@@ -1453,6 +2739,10 @@ impl App {
             | "wallet_getEthereumChains"
             | "wallet_getSnaps"
             | "wallet_requestSnaps") => {
+                self.bans
+                    .record_authorization(&web3_request.authorization, Violation::BlockedMethod)
+                    .await;
+
                 return Err(Web3ProxyError::MethodNotFound(method.to_owned().into()));
             }
             // TODO: implement these commands
@@ -1493,16 +2783,42 @@ impl App {
                 jsonrpc::ParsedResponse::from_value(json!(Address::zero()), web3_request.id()).into()
             }
             "eth_estimateGas" => {
+                let fanout = &self.config.estimate_gas_fanout;
+
+                let max_rpcs = fanout.max_rpcs_for(web3_request.authorization.checks.user_tier_title.as_deref());
+
                 // TODO: timeout
-                let mut gas_estimate = self
-                    .balanced_rpcs
-                    .try_proxy_connection::<U256>(
-                        web3_request,
-                    )
-                    .await?
-                    .parsed()
-                    .await?
-                    .into_result()?;
+                let mut gas_estimate = if let Some(max_rpcs) = max_rpcs {
+                    let responses = self
+                        .balanced_rpcs
+                        .request_with_metadata_from_many::<U256>(web3_request, max_rpcs)
+                        .await?;
+
+                    let mut results = Vec::with_capacity(responses.len());
+                    for response in responses {
+                        let result = match response {
+                            Ok(x) => x.parsed().await.and_then(|x| x.into_result()),
+                            Err(err) => Err(err),
+                        };
+                        results.push(result);
+                    }
+
+                    let (gas_estimate, dispersion_bps) = combine_estimates(results, fanout.combine)?;
+
+                    web3_request.response.lock().estimate_gas_dispersion_bps = dispersion_bps;
+
+                    gas_estimate
+                } else {
+                    self
+                        .balanced_rpcs
+                        .try_proxy_connection::<U256>(
+                            web3_request,
+                        )
+                        .await?
+                        .parsed()
+                        .await?
+                        .into_result()?
+                };
 
                 let gas_increase = if let Some(gas_increase_percent) =
                     self.config.gas_increase_percent
@@ -1524,6 +2840,11 @@ impl App {
                 jsonrpc::ParsedResponse::from_value(json!(gas_estimate), request_id).into()
             }
             "eth_getTransactionReceipt" | "eth_getTransactionByHash" => {
+                if let Some(data) = self.disk_cache_get(web3_request).await {
+                    // saved once it was mined long enough ago that a reorg can't change it
+                    return Ok(jsonrpc::ParsedResponse::from_response_data(data, web3_request.id()).into());
+                }
+
                 // try to get the transaction without specifying a min_block_height
                 // TODO: timeout
                 // TODO: change this to send serially until we get a success
@@ -1560,7 +2881,7 @@ impl App {
                     Err(..) => true,
                 };
 
-                if try_archive {
+                let result = if try_archive {
                     {
                         let mut response_lock = web3_request.response.lock();
 
@@ -1584,9 +2905,17 @@ impl App {
                     // TODO: if result is an error, return a null instead?
 
                     result?
+                };
+
+                // the response cache never keeps these. the disk cache does once they are confirmed
+                if let (Some(disk_cache), SingleResponse::Parsed(x)) = (self.disk_cache.as_ref(), &result) {
+                    disk_cache.save(web3_request, &ForwardedResponse::from(x.payload.clone()));
                 }
+
+                result
             }
             // TODO: eth_gasPrice that does awesome magic to predict the future
+            "eth_getLogs" if web3_request.auto_paginate => self.proxy_paginated_logs(web3_request).await?,
             "eth_hashrate" => jsonrpc::ParsedResponse::from_value(json!(U64::zero()), web3_request.id()).into(),
             "eth_mining" => jsonrpc::ParsedResponse::from_value(serde_json::Value::Bool(false), web3_request.id()).into(),
             "eth_sendRawTransaction" => {
@@ -1598,11 +2927,22 @@ impl App {
 
                 jsonrpc::ParsedResponse::from_response_data(x, web3_request.id()).into()
             }
-            "eth_syncing" => {
+            "eth_syncing" if self.config.node_introspection.eth_syncing.is_local() => {
                 // no stats on this. its cheap
-                // TODO: return a real response if all backends are syncing or if no servers in sync
-                // TODO: const
-                jsonrpc::ParsedResponse::from_value(serde_json::Value::Bool(false), web3_request.id()).into()
+                let head_block = self.balanced_rpcs.head_block();
+
+                let highest_block = self
+                    .balanced_rpcs
+                    .summaries(None)
+                    .into_iter()
+                    .filter_map(|x| x.head_block_num)
+                    .max();
+
+                let max_head_age = self.config.node_introspection.syncing_max_head_age(self.balanced_rpcs.max_head_block_age());
+
+                let syncing = introspection::eth_syncing(head_block.as_ref(), max_head_age, highest_block);
+
+                jsonrpc::ParsedResponse::from_value(syncing, web3_request.id()).into()
             }
             "eth_subscribe" => jsonrpc::ParsedResponse::from_error(JsonRpcErrorData {
                 message: "notifications not supported. eth_subscribe is only available over a websocket".into(),
@@ -1614,17 +2954,20 @@ impl App {
                 code: -32601,
                 data: None,
             }, web3_request.id()).into(),
-            "net_listening" => {
+            "net_listening" if self.config.node_introspection.net_listening.is_local() => {
                 // TODO: only true if there are some backends on balanced_rpcs?
-                // TODO: const
                 jsonrpc::ParsedResponse::from_value(serde_json::Value::Bool(true), web3_request.id()).into()
             }
-            "net_peerCount" =>
-                jsonrpc::ParsedResponse::from_value(json!(U64::from(self.balanced_rpcs.num_synced_rpcs())), web3_request.id()).into()
-            ,
-            "web3_clientVersion" =>
-                jsonrpc::ParsedResponse::from_value(serde_json::Value::String(APP_USER_AGENT.to_string()), web3_request.id()).into()
-            ,
+            "net_peerCount" if self.config.node_introspection.net_peer_count.is_local() => {
+                let peer_count = self.config.node_introspection.net_peer_count(self.balanced_rpcs.num_synced_rpcs());
+
+                jsonrpc::ParsedResponse::from_value(json!(peer_count), web3_request.id()).into()
+            }
+            "web3_clientVersion" if self.config.node_introspection.web3_client_version.is_local() => {
+                let client_version = self.config.node_introspection.web3_client_version(APP_USER_AGENT);
+
+                jsonrpc::ParsedResponse::from_value(json!(client_version), web3_request.id()).into()
+            }
             "web3_sha3" => {
                 // returns Keccak-256 (not the standardized SHA3-256) of the given data.
                 // TODO: timeout
@@ -1681,14 +3024,30 @@ impl App {
             method => {
                 if method.starts_with("admin_") {
                     // TODO: emit a stat? will probably just be noise
+                    self.bans
+                        .record_authorization(&web3_request.authorization, Violation::BlockedMethod)
+                        .await;
+
                     return Err(Web3ProxyError::AccessDenied("admin methods are not allowed".into()));
                 }
-                if method.starts_with("alchemy_") {
+                // vendor methods were checked against the config. they skip the unknown method policy
+                let vendor_method = web3_request.vendor_method.is_some();
+                if method.starts_with("alchemy_") && !vendor_method {
                     return Err(JsonRpcErrorData::from(format!(
                         "the method {} does not exist/is not available",
                         method
                     )).into());
                 }
+                if self.config.unknown_methods == UnknownMethods::Block
+                    && !vendor_method
+                    && !ComputeUnit::is_known_method(method, self.config.chain_id)
+                {
+                    return Err(Web3ProxyError::MethodNotFound(method.to_owned().into()));
+                }
+                if let Some(chain_id) = param_chain_id(method, web3_request.inner.params())? {
+                    self.check_chain_id(web3_request, chain_id)?;
+                }
+                self.head_staleness.check_request(web3_request)?;
                 // debug methods require premium
                 if method.starts_with("debug_") && !(self.config.free_subscriptions
                         || web3_request.authorization.active_premium().await) {
@@ -1697,16 +3056,43 @@ impl App {
                         ));
                     }
 
-                if web3_request.cache_mode.is_some() {
-                    // don't cache anything larger than 16 MiB
-                    let max_response_cache_bytes = 16 * (1024 ^ 2);  // self.config.max_response_cache_bytes;
+                if let Some(target) = web3_request.call_cache.as_ref() {
+                    if let Some(data) = self.call_cache.get(target).await {
+                        jsonrpc::ParsedResponse::from_response_data(data, web3_request.id()).into()
+                    } else {
+                        let mut x = timeout_at(
+                            web3_request.expire_at(),
+                            self.balanced_rpcs
+                            .try_proxy_connection::<Arc<RawValue>>(
+                                web3_request,
+                            )
+                        ).await??;
+
+                        self.cache_call(target, web3_request, &x).await;
+
+                        x.set_id(web3_request.id());
+
+                        x
+                    }
+                } else if web3_request.cache_mode.is_some() {
+                    // anything bigger would be evicted as soon as it was inserted
+                    let max_response_cache_bytes = self.max_cached_response_bytes(web3_request);
 
                     let cache_key = web3_request.cache_key().expect("key must exist if cache_mode does");
 
                     // TODO: try to fetch out of s3
 
-                    let x: SingleResponse = if let Some(data) = self.jsonrpc_response_cache.get(&cache_key).await {
+                    let x: SingleResponse = if let Some(CachedResponse { response: data, .. }) = self.response_cache(web3_request).get(&cache_key).await {
+                        if let Some(age) = self.cache_revalidation.should_revalidate(cache_key).await {
+                            self.spawn_cache_revalidation(web3_request, data.clone(), age);
+                        }
+
                         // it was cached! easy!
+                        jsonrpc::ParsedResponse::from_response_data(data, web3_request.id()).into()
+                    } else if let Some(data) = self.disk_cache_get(web3_request).await {
+                        // saved before a restart. keep it in memory so the next one doesn't need the disk
+                        self.cache_response(cache_key, web3_request, data.clone()).await;
+
                         jsonrpc::ParsedResponse::from_response_data(data, web3_request.id()).into()
                     } else if self.jsonrpc_response_failed_cache_keys.contains_key(&cache_key) {
                         // this is a request that we have previously failed to cache. don't try the cache again
@@ -1719,58 +3105,67 @@ impl App {
                                 web3_request,
                             )
                         ).await??
+                    } else if web3_request.authorization.checks.skip_request_coalescing {
+                        // latency-critical keys go straight to a backend. they still checked the cache above
+                        self.incoming_requests.skipped();
+
+                        let _in_flight = self.incoming_requests.start(cache_key);
+
+                        let (x, cached) = self.forward_cacheable(cache_key, web3_request, max_response_cache_bytes).await?;
+
+                        if let Some(cached) = cached {
+                            self.cache_forwarded_response(cache_key, web3_request, cached).await;
+                        }
+
+                        x
                     } else {
-                        // we used to have a semaphore here, but its faster to just allow duplicate requests while the first is still in flight
-                        // we might do some duplicate requests here, but it seems worth it to get rid of the Arc errors.
-                        let response_data = timeout_at(
-                            web3_request.expire_at(),
-                            self.balanced_rpcs
-                            .try_proxy_connection::<Arc<RawValue>>(
-                                web3_request,
-                            )
-                        ).await?;
-
-                        match response_data {
-                            Ok(mut x) => {
-                                match &x {
-                                    SingleResponse::Parsed(x) => {
-                                        // TODO: don't serialize here! we should already know the size!
-                                        let len = serde_json::to_string(&x).unwrap().len();
-
-                                        if len <= max_response_cache_bytes {
-                                            let cached = ForwardedResponse::from(x.payload.clone());
-
-                                            self.jsonrpc_response_cache.insert(cache_key, cached).await;
-                                        } else {
-                                            self.jsonrpc_response_failed_cache_keys.insert(cache_key, ()).await;
-                                        }
-                                    }
-                                    SingleResponse::Stream(..) => {
-                                        self.jsonrpc_response_failed_cache_keys.insert(cache_key, ()).await;
-                                    }
-                                }
+                        // identical requests that arrive while this one is in flight wait for the cache's loader and share its response
+                        let _in_flight = self.incoming_requests.start(cache_key);
+
+                        let mut forwarded = None;
+
+                        let entry = self.response_cache(web3_request)
+                            .entry(cache_key)
+                            .or_optionally_insert_with(async {
+                                let x = self.forward_cacheable(cache_key, web3_request, max_response_cache_bytes).await;
 
-                                x.set_id(web3_request.id());
+                                let cached = match &x {
+                                    Ok((_, Some(cached))) if self.should_cache_response(web3_request) => Some(self.cached_response(web3_request, cached.clone())),
+                                    _ => None,
+                                };
+
+                                forwarded = Some(x);
+
+                                cached
+                            })
+                            .await;
+
+                        match (forwarded, entry) {
+                            (Some(x), entry) => {
+                                let (x, cached) = x?;
+
+                                // this request ran the loader
+                                if let (Some(_), Some(cached)) = (entry, cached) {
+                                    self.response_cached(cache_key, web3_request).await;
+                                    self.disk_cache_save(web3_request, &cached);
+                                }
 
                                 x
                             }
-                            Err(err) => {
-                                if web3_request.cache_jsonrpc_errors() {
-                                    // we got an error, but we are supposed to cache jsonrpc errors. 
-                                    let x: Result<ForwardedResponse<Arc<RawValue>>, Web3ProxyError> = err.try_into();
-
-                                    if x.is_err() {
-                                        // we still have an Err. it must not have been a jsonrpc error
-                                        self.jsonrpc_response_failed_cache_keys.insert(cache_key, ()).await;
-                                    }
-
-                                    // TODO: needing multiple into/try_into/from must be inefficient. investigate this
-                                    ParsedResponse::from_response_data(x?, web3_request.id()).into()
-                                } else {
-                                    // we got an error, and we are not supposed to cache jsonrpc errors. exit early
-                                    self.jsonrpc_response_failed_cache_keys.insert(cache_key, ()).await;
-                                    return Err(err);
+                            (None, Some(entry)) => {
+                                self.incoming_requests.coalesced();
+
+                                jsonrpc::ParsedResponse::from_response_data(entry.into_value().response, web3_request.id()).into()
+                            }
+                            (None, None) => {
+                                // the request we waited on failed or its response wasn't cacheable. this one goes to a backend itself
+                                let (x, cached) = self.forward_cacheable(cache_key, web3_request, max_response_cache_bytes).await?;
+
+                                if let Some(cached) = cached {
+                                    self.cache_forwarded_response(cache_key, web3_request, cached).await;
                                 }
+
+                                x
                             }
                         }
                     };
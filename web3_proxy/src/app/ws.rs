@@ -2,24 +2,132 @@
 
 use super::App;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
-use crate::frontend::authorization::RequestOrMethod;
+use crate::frontend::authorization::{Authorization, RequestOrMethod};
+use crate::frontend::ws_queue::OutboundQueue;
+use crate::get_logs::{LogFilter, RecentLogs};
 use crate::jsonrpc::{self, ValidatedRequest};
 use crate::response_cache::ForwardedResponse;
+use crate::rpcs::blockchain::BlockHeader;
 use axum::extract::ws::{CloseFrame, Message};
 use deferred_rate_limiter::DeferredRateLimitResult;
-use ethers::types::U64;
-use futures::future::AbortHandle;
+use ethers::types::{Log, H256, U64};
 use futures::future::Abortable;
+use futures::future::{AbortHandle, AbortRegistration};
 use futures::stream::StreamExt;
 use http::StatusCode;
+use serde::Deserialize;
 use serde_json::json;
+use std::collections::BTreeMap;
 use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::wrappers::WatchStream;
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
+
+/// how many heads a logs subscription remembers. logs in blocks older than this are not marked removed if they reorg
+const LOGS_REORG_DEPTH: usize = 64;
+
+/// A subscription's task on one websocket. Aborting it stops the notifications without closing the websocket
+pub struct SubscriptionHandle {
+    abort_handle: AbortHandle,
+    join_handle: JoinHandle<()>,
+}
+
+impl SubscriptionHandle {
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+
+    /// true once the task has exited. that happens after an abort or when the websocket can't keep up
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.is_finished()
+    }
+}
+
+/// What an `eth_subscribe` is for. The params are `[kind, options]`. The options are optional
+#[derive(Debug, PartialEq)]
+pub enum SubscribeParams {
+    NewHeads,
+    NewPendingTransactions,
+    Logs(LogFilter),
+}
+
+/// Options that some clients send with newHeads and newPendingTransactions.
+/// geth takes a bare bool for full transactions. others take an object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TransactionOptions {
+    FullTransactions(bool),
+    #[serde(rename_all = "camelCase")]
+    Object {
+        #[serde(default)]
+        include_transactions: bool,
+    },
+}
+
+impl TransactionOptions {
+    fn parse(options: Option<&serde_json::Value>) -> Web3ProxyResult<bool> {
+        let Some(options) = options else {
+            return Ok(false);
+        };
+
+        let x = Self::deserialize(options).map_err(|_| {
+            Web3ProxyError::InvalidParams("subscription options must be a bool or an object".into())
+        })?;
+
+        match x {
+            Self::FullTransactions(x)
+            | Self::Object {
+                include_transactions: x,
+            } => Ok(x),
+        }
+    }
+}
+
+impl SubscribeParams {
+    pub fn parse(params: &serde_json::Value) -> Web3ProxyResult<Self> {
+        let (kind, options) = match params {
+            serde_json::Value::Array(x) => (x.first(), x.get(1)),
+            // a bare string is the kind without any options
+            x @ serde_json::Value::String(_) => (Some(x), None),
+            _ => (None, None),
+        };
+
+        let kind = kind.and_then(|x| x.as_str()).ok_or_else(|| {
+            Web3ProxyError::InvalidParams(
+                "eth_subscribe needs the kind of subscription as its first param".into(),
+            )
+        })?;
+
+        // null options are the same as no options
+        let options = options.filter(|x| !x.is_null());
+
+        match kind {
+            "newHeads" => {
+                // there are no transactions in our heads, so this is only checked for errors
+                TransactionOptions::parse(options)?;
+
+                Ok(Self::NewHeads)
+            }
+            "newPendingTransactions" => {
+                // sending hashes to a client that asked for transactions would break it
+                if TransactionOptions::parse(options)? {
+                    return Err(Web3ProxyError::InvalidParams(
+                        "newPendingTransactions only sends transaction hashes".into(),
+                    ));
+                }
+
+                Ok(Self::NewPendingTransactions)
+            }
+            "logs" => Ok(Self::Logs(LogFilter::from_param(options)?)),
+            // TODO: make sure this gets a CU cost of unimplemented instead of the normal eth_subscribe cost?
+            x => Err(Web3ProxyError::MethodNotFound(x.to_owned().into())),
+        }
+    }
+}
 
 impl App {
     pub async fn eth_subscribe<'a>(
@@ -27,21 +135,14 @@ impl App {
         web3_request: Arc<ValidatedRequest>,
         subscription_count: &'a AtomicU64,
         // TODO: taking a sender for Message instead of the exact json we are planning to send feels wrong, but its easier for now
-        response_sender: mpsc::Sender<Message>,
-    ) -> Web3ProxyResult<(AbortHandle, jsonrpc::ParsedResponse)> {
-        let subscribe_to = web3_request
-            .inner
-            .params()
-            .get(0)
-            .and_then(|x| x.as_str())
-            .ok_or_else(|| {
-                Web3ProxyError::BadRequest("unable to subscribe using these params".into())
-            })?;
+        response_sender: Arc<OutboundQueue>,
+    ) -> Web3ProxyResult<(SubscriptionHandle, jsonrpc::ParsedResponse)> {
+        let subscribe_to = SubscribeParams::parse(web3_request.inner.params())?;
 
         // anyone can subscribe to newHeads
         // only premium users are allowed to subscribe to the other things
         if !(self.config.free_subscriptions
-            || subscribe_to == "newHeads"
+            || subscribe_to == SubscribeParams::NewHeads
             || web3_request.authorization.active_premium().await)
         {
             return Err(Web3ProxyError::AccessDenied(
@@ -51,7 +152,7 @@ impl App {
 
         let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
 
-        // TODO: this only needs to be unique per connection. we don't need it globably unique
+        // ids are only unique per connection. each websocket has its own counter
         // TODO: have a max number of subscriptions per key/ip. have a global max number of subscriptions? how should this be calculated?
         let subscription_id = subscription_count.fetch_add(1, atomic::Ordering::SeqCst);
         let subscription_id = U64::from(subscription_id);
@@ -59,96 +160,35 @@ impl App {
         // TODO: calling `json!` on every request is probably not fast. but it works for now
         // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
         // TODO: DRY This up. lots of duplication between newHeads and newPendingTransactions
-        match subscribe_to {
-            "newHeads" => {
+        let join_handle = match subscribe_to {
+            SubscribeParams::NewHeads => {
                 // we clone the watch before spawning so that theres less chance of missing anything
                 // TODO: watch receivers can miss a block. is that okay?
                 let head_block_receiver = self.watch_consensus_head_receiver.clone();
-                let app = self.clone();
-                let authorization = web3_request.authorization.clone();
-
-                tokio::spawn(async move {
-                    trace!("newHeads subscription {:?}", subscription_id);
-
-                    let mut head_block_receiver = Abortable::new(
-                        WatchStream::new(head_block_receiver),
-                        subscription_registration,
-                    );
 
-                    while let Some(new_head) = head_block_receiver.next().await {
-                        let new_head = if let Some(new_head) = new_head {
-                            new_head
-                        } else {
-                            continue;
-                        };
-
-                        // todo!(this needs a permit)
-                        let subscription_web3_request = ValidatedRequest::new_with_app(
-                            &app,
-                            authorization.clone(),
-                            None,
-                            None,
-                            RequestOrMethod::Method("eth_subscribe(newHeads)".into(), 0),
-                            Some(new_head),
-                            None,
-                        )
-                        .await;
-
-                        match subscription_web3_request {
-                            Err(err) => {
-                                error!(?err, "error creating subscription_web3_request");
-                                // TODO: send them an error message before closing
-                                break;
-                            }
-                            Ok(subscription_web3_request) => {
-                                if let Some(close_message) = app
-                                    .rate_limit_close_websocket(&subscription_web3_request)
-                                    .await
-                                {
-                                    // TODO: send them a message so they know they were rate limited
-                                    let _ = response_sender.send(close_message).await;
-                                    break;
-                                }
-
-                                // TODO: make a struct for this? using our SingleForwardedResponse won't work because it needs an id
-                                let response_json = json!({
-                                    "jsonrpc": "2.0",
-                                    "method":"eth_subscription",
-                                    "params": {
-                                        "subscription": subscription_id,
-                                        // TODO: option to include full transaction objects instead of just the hashes?
-                                        "result": subscription_web3_request.head_block.as_ref().map(|x| &x.0),
-                                    },
-                                });
-
-                                let response_str = serde_json::to_string(&response_json)
-                                    .expect("this should always be valid json");
-
-                                // we could use ForwardedResponse::num_bytes() here, but since we already have the string, this is easier
-                                let response_bytes = response_str.len() as u64;
-
-                                // TODO: do clients support binary messages?
-                                // TODO: can we check a content type header?
-                                let response_msg = Message::Text(response_str);
-
-                                if response_sender.send(response_msg).await.is_err() {
-                                    // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
-                                    // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
-                                    break;
-                                };
-
-                                subscription_web3_request.set_response(response_bytes);
-                            }
-                        }
-                    }
-
-                    let _ = response_sender.send(Message::Close(None)).await;
+                self.spawn_new_heads(
+                    web3_request.authorization.clone(),
+                    subscription_id,
+                    subscription_registration,
+                    response_sender,
+                    head_block_receiver,
+                    None,
+                )
+            }
+            SubscribeParams::Logs(filter) => {
+                let head_block_receiver = self.watch_consensus_head_receiver.clone();
 
-                    trace!("closed newHeads subscription {:?}", subscription_id);
-                });
+                self.spawn_logs(
+                    web3_request.authorization.clone(),
+                    subscription_id,
+                    subscription_registration,
+                    response_sender,
+                    head_block_receiver,
+                    filter,
+                )
             }
             // TODO: bring back the other custom subscription types that had the full transaction object
-            "newPendingTransactions" => {
+            SubscribeParams::NewPendingTransactions => {
                 // we subscribe before spawning so that theres less chance of missing anything
                 let pending_txid_firehose = self.pending_txid_firehose.subscribe();
                 let app = self.clone();
@@ -197,7 +237,7 @@ impl App {
                                             .rate_limit_close_websocket(&subscription_web3_request)
                                             .await
                                         {
-                                            let _ = response_sender.send(close_message).await;
+                                            response_sender.close(Some(close_message));
                                             break;
                                         }
 
@@ -223,7 +263,11 @@ impl App {
                                         // TODO: can we check a content type header?
                                         let response_msg = Message::Text(response_str);
 
-                                        if response_sender.send(response_msg).await.is_err() {
+                                        // slow clients miss some pending transactions instead of buffering all of them
+                                        if response_sender
+                                            .send_droppable(subscription_id, response_msg)
+                                            .is_err()
+                                        {
                                             // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
                                             // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
                                             break;
@@ -234,24 +278,19 @@ impl App {
                         }
                     }
 
-                    let _ = response_sender.send(Message::Close(None)).await;
+                    // an unsubscribe only ends this subscription. anything else means the websocket is done
+                    if !pending_txid_firehose.is_aborted() {
+                        response_sender.close(Some(Message::Close(None)));
+                    }
 
                     trace!(
                         "closed newPendingTransactions subscription {:?}",
                         subscription_id
                     );
-                });
-            }
-            _ => {
-                // TODO: make sure this gets a CU cost of unimplemented instead of the normal eth_subscribe cost?
-                return Err(Web3ProxyError::MethodNotFound(
-                    subscribe_to.to_owned().into(),
-                ));
+                })
             }
         };
 
-        // TODO: do something with subscription_join_handle?
-
         let response_data = ForwardedResponse::from(json!(subscription_id));
 
         let response =
@@ -263,11 +302,399 @@ impl App {
         web3_request.set_response(&response);
         let response = response.parsed().await.expect("Response already parsed");
 
-        // TODO: make a `SubscriptonHandle(AbortHandle, JoinHandle)` struct?
-        Ok((subscription_abort_handle, response))
+        let handle = SubscriptionHandle {
+            abort_handle: subscription_abort_handle,
+            join_handle,
+        };
+
+        Ok((handle, response))
+    }
+
+    /// `web3proxy_resubscribe` -- start a newHeads subscription for a client that is reconnecting.
+    /// The result has the new subscription's id and every head after `last_block` that is still in `head_replay`.
+    /// Live heads resume after that.
+    pub async fn web3proxy_resubscribe<'a>(
+        self: &'a Arc<Self>,
+        web3_request: Arc<ValidatedRequest>,
+        subscription_count: &'a AtomicU64,
+        response_sender: Arc<OutboundQueue>,
+    ) -> Web3ProxyResult<(SubscriptionHandle, jsonrpc::ParsedResponse)> {
+        #[derive(Deserialize)]
+        struct ResubscribeParams {
+            #[serde(rename = "type")]
+            subscribe_to: String,
+            last_block: U64,
+        }
+
+        // params can be the object or a list with the object
+        let params = web3_request.inner.params();
+        let params = params.get(0).unwrap_or(params).clone();
+
+        let params: ResubscribeParams = serde_json::from_value(params).map_err(|err| {
+            Web3ProxyError::BadRequest(
+                format!("unexpected params given for web3proxy_resubscribe: {}", err).into(),
+            )
+        })?;
+
+        if params.subscribe_to != "newHeads" {
+            return Err(Web3ProxyError::BadRequest(
+                "only newHeads subscriptions can be resubscribed".into(),
+            ));
+        }
+
+        let head_replay = self.head_replay.as_ref().ok_or_else(|| {
+            Web3ProxyError::BadRequest("resubscribes are disabled on this server".into())
+        })?;
+
+        // clone the watch before checking the buffer so that nothing falls between them
+        let head_block_receiver = self.watch_consensus_head_receiver.clone();
+
+        let missed = head_replay.since(params.last_block);
+
+        let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
+
+        let subscription_id = subscription_count.fetch_add(1, atomic::Ordering::SeqCst);
+        let subscription_id = U64::from(subscription_id);
+
+        let join_handle = self.spawn_new_heads(
+            web3_request.authorization.clone(),
+            subscription_id,
+            subscription_registration,
+            response_sender,
+            head_block_receiver,
+            missed.heads.last().map(|x| *x.hash()),
+        );
+
+        let heads: Vec<_> = missed.heads.iter().map(|x| &x.0).collect();
+
+        let response_data = ForwardedResponse::from(json!({
+            "subscription": subscription_id,
+            "heads": heads,
+            "gap": missed.gap,
+        }));
+
+        let response =
+            jsonrpc::ParsedResponse::from_response_data(response_data, web3_request.id());
+
+        let response = jsonrpc::SingleResponse::Parsed(response);
+        web3_request.set_response(&response);
+        let response = response.parsed().await.expect("Response already parsed");
+
+        let handle = SubscriptionHandle {
+            abort_handle: subscription_abort_handle,
+            join_handle,
+        };
+
+        Ok((handle, response))
+    }
+
+    /// send every new consensus head to the websocket until the subscription is aborted
+    fn spawn_new_heads(
+        self: &Arc<Self>,
+        authorization: Arc<Authorization>,
+        subscription_id: U64,
+        subscription_registration: AbortRegistration,
+        response_sender: Arc<OutboundQueue>,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        mut skip_head: Option<H256>,
+    ) -> JoinHandle<()> {
+        let app = self.clone();
+
+        tokio::spawn(async move {
+            trace!("newHeads subscription {:?}", subscription_id);
+
+            let mut head_block_receiver = Abortable::new(
+                WatchStream::new(head_block_receiver),
+                subscription_registration,
+            );
+
+            while let Some(new_head) = head_block_receiver.next().await {
+                let new_head = if let Some(new_head) = new_head {
+                    new_head
+                } else {
+                    continue;
+                };
+
+                // the client already got this head in their resubscribe response
+                if let Some(skip) = skip_head.take() {
+                    if skip == *new_head.hash() {
+                        continue;
+                    }
+                }
+
+                // todo!(this needs a permit)
+                let subscription_web3_request = ValidatedRequest::new_with_app(
+                    &app,
+                    authorization.clone(),
+                    None,
+                    None,
+                    RequestOrMethod::Method("eth_subscribe(newHeads)".into(), 0),
+                    Some(new_head),
+                    None,
+                )
+                .await;
+
+                match subscription_web3_request {
+                    Err(err) => {
+                        error!(?err, "error creating subscription_web3_request");
+                        // TODO: send them an error message before closing
+                        break;
+                    }
+                    Ok(subscription_web3_request) => {
+                        if let Some(close_message) = app
+                            .rate_limit_close_websocket(&subscription_web3_request)
+                            .await
+                        {
+                            // TODO: send them a message so they know they were rate limited
+                            response_sender.close(Some(close_message));
+                            break;
+                        }
+
+                        // TODO: make a struct for this? using our SingleForwardedResponse won't work because it needs an id
+                        let response_json = json!({
+                            "jsonrpc": "2.0",
+                            "method":"eth_subscription",
+                            "params": {
+                                "subscription": subscription_id,
+                                // TODO: option to include full transaction objects instead of just the hashes?
+                                "result": subscription_web3_request.head_block.as_ref().map(|x| &x.0),
+                            },
+                        });
+
+                        let response_str = serde_json::to_string(&response_json)
+                            .expect("this should always be valid json");
+
+                        // we could use ForwardedResponse::num_bytes() here, but since we already have the string, this is easier
+                        let response_bytes = response_str.len() as u64;
+
+                        // TODO: do clients support binary messages?
+                        // TODO: can we check a content type header?
+                        let response_msg = Message::Text(response_str);
+
+                        // newHeads can't have gaps. if this doesn't fit, the client is disconnected
+                        if response_sender.send(response_msg).is_err() {
+                            // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
+                            // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
+                            break;
+                        };
+
+                        subscription_web3_request.set_response(response_bytes);
+                    }
+                }
+            }
+
+            // an unsubscribe only ends this subscription. anything else means the websocket is done
+            if !head_block_receiver.is_aborted() {
+                response_sender.close(Some(Message::Close(None)));
+            }
+
+            trace!("closed newHeads subscription {:?}", subscription_id);
+        })
+    }
+
+    /// Send the logs in every new consensus head that match the filter until the subscription is aborted.
+    /// Each block's logs are fetched by hash, so subscriptions with different filters share one cached query.
+    /// When a head replaces blocks that were already sent, their logs are sent again with `removed: true`.
+    /// Blocks that the watch skipped, or whose logs couldn't be fetched, are sent along with the next head.
+    fn spawn_logs(
+        self: &Arc<Self>,
+        authorization: Arc<Authorization>,
+        subscription_id: U64,
+        subscription_registration: AbortRegistration,
+        response_sender: Arc<OutboundQueue>,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        filter: LogFilter,
+    ) -> JoinHandle<()> {
+        let app = self.clone();
+
+        tokio::spawn(async move {
+            trace!(?filter, "logs subscription {:?}", subscription_id);
+
+            let mut head_block_receiver = Abortable::new(
+                WatchStream::new(head_block_receiver),
+                subscription_registration,
+            );
+
+            let mut recent_logs = RecentLogs::new(LOGS_REORG_DEPTH);
+
+            // the watch starts with the current head. like eth_subscribe on a node, only logs from later blocks are sent
+            let mut first = true;
+
+            while let Some(new_head) = head_block_receiver.next().await {
+                let new_head = if let Some(new_head) = new_head {
+                    new_head
+                } else {
+                    continue;
+                };
+
+                let new_head_num = new_head.number();
+
+                if recent_logs.contains(new_head_num, new_head.hash()) {
+                    continue;
+                }
+
+                if first {
+                    first = false;
+                    recent_logs.push(new_head_num, *new_head.hash(), vec![]);
+                    continue;
+                }
+
+                let canonical = app.canonical_hashes(&new_head, &recent_logs).await;
+
+                let mut logs = recent_logs.rewind(&canonical);
+
+                // watch receivers can skip heads. every canonical block that wasn't sent yet is fetched, oldest first
+                for (num, hash) in canonical {
+                    if recent_logs.contains(num, &hash) {
+                        continue;
+                    }
+
+                    let block_logs: Vec<Log> = match app
+                        .internal_request::<_, Vec<Log>>(
+                            "eth_getLogs",
+                            [json!({"blockHash": hash})],
+                        )
+                        .await
+                    {
+                        Ok(x) => x.into_iter().filter(|x| filter.matches(x)).collect(),
+                        Err(err) => {
+                            // this block (and any after it) stays out of recent_logs so that the next head fetches it again
+                            warn!(?err, block=%num, "unable to get logs for the logs subscription");
+                            break;
+                        }
+                    };
+
+                    logs.extend(block_logs.iter().cloned());
+
+                    recent_logs.push(num, hash, block_logs);
+                }
+
+                if logs.is_empty() {
+                    continue;
+                }
+
+                // todo!(this needs a permit)
+                let subscription_web3_request = match ValidatedRequest::new_with_app(
+                    &app,
+                    authorization.clone(),
+                    None,
+                    None,
+                    RequestOrMethod::Method("eth_subscribe(logs)".into(), 0),
+                    Some(new_head),
+                    None,
+                )
+                .await
+                {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!(?err, "error creating subscription_web3_request");
+                        break;
+                    }
+                };
+
+                if let Some(close_message) = app
+                    .rate_limit_close_websocket(&subscription_web3_request)
+                    .await
+                {
+                    response_sender.close(Some(close_message));
+                    break;
+                }
+
+                let mut response_bytes = 0;
+                let mut send_failed = false;
+
+                for log in logs {
+                    let response_json = json!({
+                        "jsonrpc": "2.0",
+                        "method":"eth_subscription",
+                        "params": {
+                            "subscription": subscription_id,
+                            "result": log,
+                        },
+                    });
+
+                    let response_str = serde_json::to_string(&response_json)
+                        .expect("this should always be valid json");
+
+                    response_bytes += response_str.len() as u64;
+
+                    // logs can't have gaps. if this doesn't fit, the client is disconnected
+                    if response_sender.send(Message::Text(response_str)).is_err() {
+                        send_failed = true;
+                        break;
+                    }
+                }
+
+                subscription_web3_request.set_response(response_bytes);
+
+                if send_failed {
+                    break;
+                }
+            }
+
+            // an unsubscribe only ends this subscription. anything else means the websocket is done
+            if !head_block_receiver.is_aborted() {
+                response_sender.close(Some(Message::Close(None)));
+            }
+
+            trace!("closed logs subscription {:?}", subscription_id);
+        })
+    }
+
+    /// The new chain's hashes by number, from `new_head` back until it joins the blocks in `recent_logs`.
+    /// Stops early if a parent isn't in the block cache
+    async fn canonical_hashes(
+        &self,
+        new_head: &BlockHeader,
+        recent_logs: &RecentLogs,
+    ) -> BTreeMap<U64, H256> {
+        let mut x = BTreeMap::new();
+
+        x.insert(new_head.number(), *new_head.hash());
+
+        let Some(oldest) = recent_logs.oldest() else {
+            return x;
+        };
+
+        let mut num = new_head.number();
+        let mut parent_hash = *new_head.parent_hash();
+
+        while num > oldest {
+            num -= U64::one();
+
+            x.insert(num, parent_hash);
+
+            // this is where the new chain joins the one that was already sent
+            if recent_logs.contains(num, &parent_hash) {
+                break;
+            }
+
+            match self.balanced_rpcs.blocks_by_hash.get(&parent_hash).await {
+                Some(parent) => parent_hash = *parent.parent_hash(),
+                None => break,
+            }
+        }
+
+        x
     }
 
     async fn rate_limit_close_websocket(&self, web3_request: &ValidatedRequest) -> Option<Message> {
+        let reason = self.subscription_rate_limited(web3_request).await?;
+
+        let close_frame = CloseFrame {
+            code: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            reason: reason.into(),
+        };
+
+        Some(Message::Close(Some(close_frame)))
+    }
+
+    /// Subscription messages count against the public rate limit. Returns why the subscription should stop, if it should.
+    /// Shared by websockets and server-sent events
+    pub(crate) async fn subscription_rate_limited(
+        &self,
+        web3_request: &ValidatedRequest,
+    ) -> Option<String> {
         let authorization = &web3_request.authorization;
 
         if !authorization.active_premium().await {
@@ -281,26 +708,15 @@ impl App {
                     .await
                 {
                     Ok(DeferredRateLimitResult::RetryNever) => {
-                        let close_frame = CloseFrame {
-                            code: StatusCode::TOO_MANY_REQUESTS.as_u16(),
-                            reason:
-                                "rate limited. upgrade to premium for unlimited websocket messages"
-                                    .into(),
-                        };
-
-                        return Some(Message::Close(Some(close_frame)));
+                        return Some(
+                            "rate limited. upgrade to premium for unlimited websocket messages"
+                                .to_string(),
+                        );
                     }
-                    Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-                        let retry_at = retry_at.duration_since(Instant::now());
+                    Ok(DeferredRateLimitResult::RetryAt(quota)) => {
+                        let retry_at = quota.reset.duration_since(Instant::now());
 
-                        let reason = format!("rate limited. upgrade to premium for unlimited websocket messages. retry in {}s", retry_at.as_secs_f32());
-
-                        let close_frame = CloseFrame {
-                            code: StatusCode::TOO_MANY_REQUESTS.as_u16(),
-                            reason: reason.into(),
-                        };
-
-                        return Some(Message::Close(Some(close_frame)));
+                        return Some(format!("rate limited. upgrade to premium for unlimited websocket messages. retry in {}s", retry_at.as_secs_f32()));
                     }
                     Ok(_) => {}
                     Err(err) => {
@@ -314,3 +730,80 @@ impl App {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(params: &str) -> Web3ProxyResult<SubscribeParams> {
+        SubscribeParams::parse(&serde_json::from_str(params).unwrap())
+    }
+
+    #[test]
+    fn subscribe_params() {
+        for x in [
+            r#"["newHeads"]"#,
+            r#"[ "newHeads" ]"#,
+            "[\n  \"newHeads\"\n]",
+            r#""newHeads""#,
+            r#"["newHeads", {}]"#,
+            r#"["newHeads", null]"#,
+            r#"["newHeads", {"includeTransactions": true}]"#,
+            r#"["newHeads", true]"#,
+        ] {
+            assert_eq!(parse(x).unwrap(), SubscribeParams::NewHeads, "{}", x);
+        }
+
+        for x in [
+            r#"["newPendingTransactions"]"#,
+            r#"["newPendingTransactions", {}]"#,
+            r#"["newPendingTransactions", false]"#,
+            r#"["newPendingTransactions", {"includeTransactions": false}]"#,
+        ] {
+            assert_eq!(
+                parse(x).unwrap(),
+                SubscribeParams::NewPendingTransactions,
+                "{}",
+                x
+            );
+        }
+
+        // full transactions aren't sent. hashes would confuse a client that asked for them
+        assert!(matches!(
+            parse(r#"["newPendingTransactions", true]"#),
+            Err(Web3ProxyError::InvalidParams(_))
+        ));
+
+        assert_eq!(
+            parse(r#"["logs"]"#).unwrap(),
+            SubscribeParams::Logs(Default::default())
+        );
+
+        let SubscribeParams::Logs(filter) =
+            parse(r#"["logs", {"address": "0x0000000000000000000000000000000000000001"}]"#)
+                .unwrap()
+        else {
+            panic!("should be logs");
+        };
+        assert_eq!(filter.addresses.len(), 1);
+
+        // missing params
+        for x in ["null", "[]", "{}", "[1]", r#"[{"kind": "newHeads"}]"#] {
+            assert!(
+                matches!(parse(x), Err(Web3ProxyError::InvalidParams(_))),
+                "{}",
+                x
+            );
+        }
+
+        assert!(matches!(
+            parse(r#"["newHeads", "yes"]"#),
+            Err(Web3ProxyError::InvalidParams(_))
+        ));
+
+        assert!(matches!(
+            parse(r#"["syncing"]"#),
+            Err(Web3ProxyError::MethodNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,908 @@
+//! One score for each balanced rpc, and the config changes those scores suggest.
+//!
+//! Every `interval_secs`, the scorer compares each rpc's running totals to the ones it saw last time. Latency, head
+//! delay, lag, error rate, silent head subscriptions, and how often it was first to a pending transaction are each
+//! scored from 0 to 1 and then combined with the configured weights. Signals without data are left out of the average
+//! instead of counting as perfect or terrible.
+//!
+//! The scores suggest soft_limit changes and which rpcs should be backups. Nothing is ever applied automatically.
+//! With influx, every window's inputs are saved so that the `backend_scores` subcommand can build the same report later.
+
+use crate::app::App;
+use crate::config::AppConfig;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures::stream;
+use hashbrown::HashMap;
+use influxdb2::api::query::FluxRecord;
+use influxdb2::models::{DataPoint, Query};
+use influxdb2_structmap::value::Value;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, trace, warn};
+
+pub use web3_proxy_client::types::{
+    BackendScore, BackendScoreInputs, BackendScoresReport, Change, ConfigSuggestions,
+    RpcConfigSuggestion, ScoreComponents,
+};
+
+/// the influx measurement that the inputs are saved to
+pub const MEASUREMENT: &str = "backend_scores";
+
+/// Weights and thresholds for scoring the balanced rpcs. Weights are relative to each other. 0 ignores a signal.
+/// Scores only suggest soft_limit and tier changes. Nothing here changes how requests are routed.
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendScoring {
+    /// How often (in seconds) the rpcs are scored. Each score covers the time since the one before. 0 turns scoring off.
+    #[serde_inline_default(60u64)]
+    pub interval_secs: u64,
+
+    #[serde_inline_default(30u32)]
+    pub weight_latency: u32,
+    #[serde_inline_default(15u32)]
+    pub weight_head_delay: u32,
+    #[serde_inline_default(20u32)]
+    pub weight_lag: u32,
+    #[serde_inline_default(25u32)]
+    pub weight_errors: u32,
+    #[serde_inline_default(5u32)]
+    pub weight_silent_deaths: u32,
+    #[serde_inline_default(5u32)]
+    pub weight_mempool_uniqueness: u32,
+
+    /// A median latency this slow (or slower) scores 0. Faster is better in a straight line down to 0ms.
+    #[serde_inline_default(2_000u64)]
+    pub latency_zero_ms: u64,
+    /// A head delay this long (or longer) scores 0.
+    #[serde_inline_default(2_000u64)]
+    pub head_delay_zero_ms: u64,
+    /// Being this many blocks behind (or more) scores 0.
+    #[serde_inline_default(5u64)]
+    pub lag_zero_blocks: u64,
+    /// An error rate this high (in basis points) scores 0. The default of 1,000 is 10%.
+    #[serde_inline_default(1_000u64)]
+    pub errors_zero_bps: u64,
+    /// This many silent head subscriptions in one window scores 0.
+    #[serde_inline_default(3u64)]
+    pub silent_deaths_zero: u64,
+
+    /// Rpcs that served fewer requests than this in a window don't get an error score or any suggestions.
+    #[serde_inline_default(100u64)]
+    pub min_requests: u64,
+    /// Suggest making rpcs that score below this backups. At least one rpc is always left out of the backups.
+    #[serde_inline_default(40u8)]
+    pub demote_below: u8,
+    /// Suggest lowering the soft_limit of rpcs that score below this.
+    #[serde_inline_default(60u8)]
+    pub lower_soft_limit_below: u8,
+    /// Suggest raising the soft_limit of rpcs that score at least this.
+    #[serde_inline_default(85u8)]
+    pub raise_soft_limit_above: u8,
+    /// How much (in percent) a suggestion raises or lowers a soft_limit.
+    #[serde_inline_default(20u8)]
+    pub soft_limit_step_percent: u8,
+}
+
+impl Default for BackendScoring {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+/// 1.0 at 0, falling in a straight line to 0.0 at `zero` and beyond
+fn falloff(x: f64, zero: u64) -> f64 {
+    (1.0 - x / zero.max(1) as f64).clamp(0.0, 1.0)
+}
+
+impl BackendScoring {
+    /// `most_unique_pending_txs` is the most unique pending transactions that any rpc found in the same window
+    pub fn components(
+        &self,
+        x: &BackendScoreInputs,
+        most_unique_pending_txs: u64,
+    ) -> ScoreComponents {
+        let errors = (x.requests > 0 && x.requests >= self.min_requests).then(|| {
+            let bps = x.errors as f64 * 10_000.0 / x.requests as f64;
+
+            falloff(bps, self.errors_zero_bps)
+        });
+
+        let mempool_uniqueness = (x.pending_txs > 0 && most_unique_pending_txs > 0)
+            .then(|| x.unique_pending_txs as f64 / most_unique_pending_txs as f64);
+
+        ScoreComponents {
+            latency: x.latency_ms.map(|ms| falloff(ms, self.latency_zero_ms)),
+            head_delay: x
+                .head_delay_ms
+                .map(|ms| falloff(ms, self.head_delay_zero_ms)),
+            lag: x
+                .lag
+                .map(|blocks| falloff(blocks as f64, self.lag_zero_blocks)),
+            errors,
+            silent_deaths: Some(falloff(x.silent_deaths as f64, self.silent_deaths_zero)),
+            mempool_uniqueness,
+        }
+    }
+
+    /// 0 to 100. None if none of the weighted components had data
+    pub fn weighted(&self, x: &ScoreComponents) -> Option<f64> {
+        let weighted = [
+            (x.latency, self.weight_latency),
+            (x.head_delay, self.weight_head_delay),
+            (x.lag, self.weight_lag),
+            (x.errors, self.weight_errors),
+            (x.silent_deaths, self.weight_silent_deaths),
+            (x.mempool_uniqueness, self.weight_mempool_uniqueness),
+        ];
+
+        let mut sum = 0.0;
+        let mut total_weight = 0u64;
+
+        for (value, weight) in weighted {
+            if let Some(value) = value {
+                sum += value * weight as f64;
+                total_weight += weight as u64;
+            }
+        }
+
+        (total_weight > 0).then(|| 100.0 * sum / total_weight as f64)
+    }
+
+    /// Best first. Rpcs without a score are last
+    pub fn score_all(&self, inputs: Vec<BackendScoreInputs>) -> Vec<BackendScore> {
+        let most_unique_pending_txs = inputs
+            .iter()
+            .map(|x| x.unique_pending_txs)
+            .max()
+            .unwrap_or_default();
+
+        let mut scores: Vec<_> = inputs
+            .into_iter()
+            .map(|inputs| {
+                let components = self.components(&inputs, most_unique_pending_txs);
+
+                BackendScore {
+                    name: inputs.name.clone(),
+                    score: self.weighted(&components),
+                    components,
+                    inputs,
+                }
+            })
+            .collect();
+
+        scores.sort_by(|a, b| {
+            let a_score = a.score.unwrap_or(-1.0);
+            let b_score = b.score.unwrap_or(-1.0);
+
+            b_score
+                .total_cmp(&a_score)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        scores
+    }
+
+    /// Soft limit changes and backup demotions. `scores` must be sorted best first
+    pub fn suggestions(&self, scores: &[BackendScore]) -> ConfigSuggestions {
+        let mut suggestions = ConfigSuggestions::default();
+
+        let scored = || {
+            scores.iter().filter_map(|x| {
+                let score = x.score?;
+
+                (x.inputs.requests >= self.min_requests).then_some((x, score))
+            })
+        };
+
+        let primaries = scores.iter().filter(|x| !x.inputs.backup).count();
+
+        let mut demote: Vec<&str> = scored()
+            .filter(|(x, score)| !x.inputs.backup && *score < self.demote_below as f64)
+            .map(|(x, _)| x.name.as_str())
+            .collect();
+
+        if !demote.is_empty() && demote.len() >= primaries {
+            // never suggest an empty primary tier. keep the best of the bad ones
+            demote.remove(0);
+        }
+
+        for (x, score) in scored() {
+            let mut suggestion = RpcConfigSuggestion::default();
+
+            if demote.contains(&x.name.as_str()) {
+                suggestion.backup = Some(Change {
+                    from: false,
+                    to: true,
+                });
+                suggestion.reasons.push(format!(
+                    "score {:.0} is below demote_below ({})",
+                    score, self.demote_below
+                ));
+            } else if x.inputs.soft_limit > 0 {
+                let from = x.inputs.soft_limit;
+                let step = (from as u64 * self.soft_limit_step_percent as u64 / 100).max(1) as u32;
+
+                if score >= self.raise_soft_limit_above as f64 {
+                    suggestion.soft_limit = Some(Change {
+                        from,
+                        to: from.saturating_add(step),
+                    });
+                    suggestion.reasons.push(format!(
+                        "score {:.0} is at least raise_soft_limit_above ({})",
+                        score, self.raise_soft_limit_above
+                    ));
+                } else if score < self.lower_soft_limit_below as f64 && from > 1 {
+                    suggestion.soft_limit = Some(Change {
+                        from,
+                        to: from.saturating_sub(step).max(1),
+                    });
+                    suggestion.reasons.push(format!(
+                        "score {:.0} is below lower_soft_limit_below ({})",
+                        score, self.lower_soft_limit_below
+                    ));
+                }
+            }
+
+            if !suggestion.reasons.is_empty() {
+                suggestions.balanced_rpcs.insert(x.name.clone(), suggestion);
+            }
+        }
+
+        suggestions
+    }
+
+    pub fn report(
+        &self,
+        inputs: Vec<BackendScoreInputs>,
+        window_secs: u64,
+        generated_at: DateTime<Utc>,
+    ) -> BackendScoresReport {
+        let backends = self.score_all(inputs);
+
+        let suggestions = self.suggestions(&backends);
+
+        BackendScoresReport {
+            generated_at,
+            window_secs,
+            backends,
+            suggestions,
+        }
+    }
+}
+
+/// Running totals for one rpc. `since` turns two of these into the inputs for one window
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendCounters {
+    pub backup: bool,
+    pub soft_limit: u32,
+    pub latency_ms: Option<f64>,
+    pub head_delay_ms: Option<f64>,
+    pub lag: Option<u64>,
+    pub requests: u64,
+    pub errors: u64,
+    pub silent_deaths: u64,
+    pub pending_txs: u64,
+    pub unique_pending_txs: u64,
+}
+
+impl BackendCounters {
+    /// The counters are only for the time since `previous`. If this rpc is new (or was reloaded), they are since it started
+    pub fn since(&self, name: &str, previous: Option<&Self>) -> BackendScoreInputs {
+        let previous = previous.cloned().unwrap_or_default();
+
+        // a reloaded rpc starts back at 0
+        let delta = |now: u64, before: u64| {
+            if now >= before {
+                now - before
+            } else {
+                now
+            }
+        };
+
+        BackendScoreInputs {
+            name: name.to_string(),
+            backup: self.backup,
+            soft_limit: self.soft_limit,
+            latency_ms: self.latency_ms,
+            head_delay_ms: self.head_delay_ms,
+            lag: self.lag,
+            requests: delta(self.requests, previous.requests),
+            errors: delta(self.errors, previous.errors),
+            silent_deaths: delta(self.silent_deaths, previous.silent_deaths),
+            pending_txs: delta(self.pending_txs, previous.pending_txs),
+            unique_pending_txs: delta(self.unique_pending_txs, previous.unique_pending_txs),
+        }
+    }
+}
+
+/// Merge many windows (and servers) into one set of inputs per rpc. Counters are added up and latencies are averaged.
+/// `backup` and `soft_limit` come from the last row for each rpc, so `rows` should be oldest first.
+pub fn combine(rows: impl IntoIterator<Item = BackendScoreInputs>) -> Vec<BackendScoreInputs> {
+    #[derive(Default)]
+    struct Mean {
+        sum: f64,
+        count: u64,
+    }
+
+    impl Mean {
+        fn add(&mut self, x: Option<f64>) {
+            if let Some(x) = x {
+                self.sum += x;
+                self.count += 1;
+            }
+        }
+
+        fn get(&self) -> Option<f64> {
+            (self.count > 0).then(|| self.sum / self.count as f64)
+        }
+    }
+
+    let mut combined: HashMap<String, (BackendScoreInputs, Mean, Mean, Mean)> = HashMap::new();
+
+    for row in rows {
+        let (x, latency, head_delay, lag) = combined.entry(row.name.clone()).or_default();
+
+        x.name = row.name;
+        x.backup = row.backup;
+        x.soft_limit = row.soft_limit;
+        x.requests += row.requests;
+        x.errors += row.errors;
+        x.silent_deaths += row.silent_deaths;
+        x.pending_txs += row.pending_txs;
+        x.unique_pending_txs += row.unique_pending_txs;
+
+        latency.add(row.latency_ms);
+        head_delay.add(row.head_delay_ms);
+        lag.add(row.lag.map(|x| x as f64));
+    }
+
+    let mut combined: Vec<_> = combined
+        .into_values()
+        .map(|(mut x, latency, head_delay, lag)| {
+            x.latency_ms = latency.get();
+            x.head_delay_ms = head_delay.get();
+            x.lag = lag.get().map(|x| x.round() as u64);
+            x
+        })
+        .collect();
+
+    combined.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    combined
+}
+
+/// Scores the balanced rpcs in the background and keeps the latest report
+pub struct BackendScorer {
+    config: BackendScoring,
+    chain_id: u64,
+    unique_id: i64,
+    /// the client and bucket to save inputs to
+    influxdb: Option<(influxdb2::Client, String)>,
+    latest: RwLock<Option<Arc<BackendScoresReport>>>,
+}
+
+impl BackendScorer {
+    /// None if `backend_scoring.interval_secs` is 0. Call `start` once the app exists
+    pub fn new(
+        config: &AppConfig,
+        influxdb_client: Option<influxdb2::Client>,
+    ) -> Option<Arc<Self>> {
+        if config.backend_scoring.interval_secs == 0 {
+            return None;
+        }
+
+        let influxdb = influxdb_client.zip(config.influxdb_bucket.clone());
+
+        let x = Self {
+            config: config.backend_scoring.clone(),
+            chain_id: config.chain_id,
+            unique_id: config.unique_id,
+            influxdb,
+            latest: Default::default(),
+        };
+
+        Some(Arc::new(x))
+    }
+
+    /// Score the app's rpcs until the app is dropped
+    pub fn start(self: &Arc<Self>, app: Weak<App>) {
+        tokio::spawn(score_loop(self.clone(), app));
+    }
+
+    /// None until the second time the rpcs are checked. The first time only sets the starting counters
+    pub fn latest(&self) -> Option<Arc<BackendScoresReport>> {
+        self.latest.read().clone()
+    }
+
+    fn data_point(
+        &self,
+        x: &BackendScoreInputs,
+        score: Option<f64>,
+        timestamp_ns: i64,
+    ) -> anyhow::Result<DataPoint> {
+        let mut builder = DataPoint::builder(MEASUREMENT)
+            .tag("chain_id", self.chain_id.to_string())
+            .tag("rpc", x.name.clone())
+            .field("backup", x.backup)
+            .field("soft_limit", x.soft_limit as i64)
+            .field("requests", x.requests as i64)
+            .field("errors", x.errors as i64)
+            .field("silent_deaths", x.silent_deaths as i64)
+            .field("pending_txs", x.pending_txs as i64)
+            .field("unique_pending_txs", x.unique_pending_txs as i64);
+
+        if let Some(latency_ms) = x.latency_ms {
+            builder = builder.field("latency_ms", latency_ms);
+        }
+
+        if let Some(head_delay_ms) = x.head_delay_ms {
+            builder = builder.field("head_delay_ms", head_delay_ms);
+        }
+
+        if let Some(lag) = x.lag {
+            builder = builder.field("lag", lag as i64);
+        }
+
+        if let Some(score) = score {
+            builder = builder.field("score", score);
+        }
+
+        let point = builder.timestamp(timestamp_ns).build()?;
+
+        Ok(point)
+    }
+
+    async fn save(&self, report: &BackendScoresReport) {
+        let Some((influxdb_client, influxdb_bucket)) = self.influxdb.as_ref() else {
+            return;
+        };
+
+        // add the unique id so that servers don't overwrite each other's points
+        let timestamp_ns = report.generated_at.timestamp() * 1_000_000_000
+            + self.unique_id.rem_euclid(1_000_000_000);
+
+        let mut points = Vec::with_capacity(report.backends.len());
+
+        for x in report.backends.iter() {
+            match self.data_point(&x.inputs, x.score, timestamp_ns) {
+                Ok(point) => points.push(point),
+                Err(err) => warn!(?err, rpc=%x.name, "unable to build backend score point"),
+            }
+        }
+
+        if let Err(err) = influxdb_client
+            .write(influxdb_bucket, stream::iter(points))
+            .await
+        {
+            warn!(?err, "unable to save backend scores");
+        }
+    }
+}
+
+async fn score_loop(scorer: Arc<BackendScorer>, app: Weak<App>) {
+    let mut score_interval = interval(Duration::from_secs(scorer.config.interval_secs));
+    score_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut last: Option<(Instant, HashMap<String, BackendCounters>)> = None;
+
+    loop {
+        score_interval.tick().await;
+
+        let counters = {
+            let Some(app) = app.upgrade() else {
+                break;
+            };
+
+            let head_block_num = app
+                .watch_consensus_head_receiver
+                .borrow()
+                .as_ref()
+                .map(|x| x.number());
+
+            app.balanced_rpcs.score_counters(head_block_num)
+        };
+
+        let now = Instant::now();
+
+        if let Some((then, previous)) = last.as_ref() {
+            let inputs = counters
+                .iter()
+                .map(|(name, x)| x.since(name, previous.get(name)))
+                .collect();
+
+            let window_secs = now.duration_since(*then).as_secs();
+
+            let report = scorer.config.report(inputs, window_secs, Utc::now());
+
+            debug!(backends=?report.backends.iter().map(|x| (&x.name, x.score)).collect::<Vec<_>>(), "scored backends");
+
+            scorer.save(&report).await;
+
+            *scorer.latest.write() = Some(Arc::new(report));
+        }
+
+        last = Some((now, counters));
+    }
+
+    trace!("backend scorer exited");
+}
+
+fn as_u64(x: Option<&Value>) -> u64 {
+    match x {
+        Some(Value::Long(x)) => (*x).max(0) as u64,
+        Some(Value::UnsignedLong(x)) => *x,
+        Some(Value::Double(x)) => f64::from(*x).max(0.0) as u64,
+        _ => 0,
+    }
+}
+
+fn as_f64(x: Option<&Value>) -> Option<f64> {
+    match x {
+        Some(Value::Long(x)) => Some(*x as f64),
+        Some(Value::UnsignedLong(x)) => Some(*x as f64),
+        Some(Value::Double(x)) => Some(f64::from(*x)),
+        _ => None,
+    }
+}
+
+/// One saved window. None if the row doesn't have an rpc name
+fn inputs_from_record(record: &FluxRecord) -> Option<BackendScoreInputs> {
+    let values = &record.values;
+
+    let name = match values.get("rpc") {
+        Some(Value::String(x)) => x.clone(),
+        _ => return None,
+    };
+
+    let backup = matches!(values.get("backup"), Some(Value::Bool(true)));
+
+    Some(BackendScoreInputs {
+        name,
+        backup,
+        soft_limit: as_u64(values.get("soft_limit")) as u32,
+        latency_ms: as_f64(values.get("latency_ms")),
+        head_delay_ms: as_f64(values.get("head_delay_ms")),
+        lag: as_f64(values.get("lag")).map(|x| x.max(0.0) as u64),
+        requests: as_u64(values.get("requests")),
+        errors: as_u64(values.get("errors")),
+        silent_deaths: as_u64(values.get("silent_deaths")),
+        pending_txs: as_u64(values.get("pending_txs")),
+        unique_pending_txs: as_u64(values.get("unique_pending_txs")),
+    })
+}
+
+/// The inputs saved by every server for `chain_id` during the last `window`, oldest first
+pub async fn query_inputs(
+    influxdb_client: &influxdb2::Client,
+    influxdb_bucket: &str,
+    chain_id: u64,
+    window: Duration,
+) -> anyhow::Result<Vec<BackendScoreInputs>> {
+    let query = format!(
+        r#"
+        from(bucket: "{}")
+            |> range(start: -{}s)
+            |> filter(fn: (r) => r._measurement == "{}" and r.chain_id == "{}")
+            |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+            |> group()
+            |> sort(columns: ["_time"])
+        "#,
+        influxdb_bucket,
+        window.as_secs().max(1),
+        MEASUREMENT,
+        chain_id
+    );
+
+    trace!(%query, "backend scores");
+
+    let records: Vec<FluxRecord> = influxdb_client
+        .query_raw(Some(Query::new(query)))
+        .await
+        .context("querying backend scores")?;
+
+    Ok(records.iter().filter_map(inputs_from_record).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn inputs(name: &str) -> BackendScoreInputs {
+        BackendScoreInputs {
+            name: name.to_string(),
+            soft_limit: 100,
+            latency_ms: Some(0.0),
+            head_delay_ms: Some(0.0),
+            lag: Some(0),
+            requests: 1_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let x = BackendScoring::default();
+
+        assert_eq!(x.interval_secs, 60);
+        assert_eq!(x.weight_latency, 30);
+        assert_eq!(x.errors_zero_bps, 1_000);
+        assert_eq!(x.demote_below, 40);
+
+        // typos are an error
+        assert!(serde_json::from_value::<BackendScoring>(json!({"weight_latncy": 1})).is_err());
+    }
+
+    #[test]
+    fn falloff_is_linear_and_clamped() {
+        assert_eq!(falloff(0.0, 100), 1.0);
+        assert_eq!(falloff(25.0, 100), 0.75);
+        assert_eq!(falloff(100.0, 100), 0.0);
+        assert_eq!(falloff(1_000.0, 100), 0.0);
+        // a zero of 0 is treated as 1 instead of dividing by 0
+        assert_eq!(falloff(0.0, 0), 1.0);
+    }
+
+    #[test]
+    fn perfect_and_weighted_scores() {
+        let config = BackendScoring::default();
+
+        let perfect = config.score_all(vec![inputs("a")]);
+        assert_eq!(perfect[0].score, Some(100.0));
+
+        // half of the latency budget costs half of the latency weight
+        let mut x = inputs("b");
+        x.latency_ms = Some(1_000.0);
+
+        let c = config.components(&x, 0);
+        assert_eq!(c.latency, Some(0.5));
+        assert_eq!(c.mempool_uniqueness, None);
+
+        // latency 30, head 15, lag 20, errors 25, silent 5. mempool has no data and is left out
+        let expected = 100.0 * (0.5 * 30.0 + 15.0 + 20.0 + 25.0 + 5.0) / 95.0;
+        assert!((config.weighted(&c).unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn error_rate_needs_enough_requests() {
+        let config = BackendScoring::default();
+
+        let mut x = inputs("a");
+        x.requests = 1_000;
+        x.errors = 50;
+
+        // 5% is half of the default 10%
+        assert_eq!(config.components(&x, 0).errors, Some(0.5));
+
+        x.requests = 99;
+        x.errors = 99;
+        assert_eq!(config.components(&x, 0).errors, None);
+    }
+
+    #[test]
+    fn mempool_uniqueness_is_relative_to_the_best() {
+        let config = BackendScoring::default();
+
+        let mut a = inputs("a");
+        a.pending_txs = 1_000;
+        a.unique_pending_txs = 400;
+
+        let mut b = inputs("b");
+        b.pending_txs = 1_000;
+        b.unique_pending_txs = 100;
+
+        // not subscribed
+        let c = inputs("c");
+
+        let scores = config.score_all(vec![c, b, a]);
+
+        let by_name = |name: &str| scores.iter().find(|x| x.name == name).unwrap();
+
+        assert_eq!(by_name("a").components.mempool_uniqueness, Some(1.0));
+        assert_eq!(by_name("b").components.mempool_uniqueness, Some(0.25));
+        assert_eq!(by_name("c").components.mempool_uniqueness, None);
+
+        // a and c are both perfect. ties are sorted by name
+        assert_eq!(
+            scores.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            ["a", "c", "b"]
+        );
+    }
+
+    #[test]
+    fn no_signals_means_no_score() {
+        let config: BackendScoring = serde_json::from_value(json!({
+            "weight_silent_deaths": 0,
+        }))
+        .unwrap();
+
+        let x = BackendScoreInputs {
+            name: "a".to_string(),
+            ..Default::default()
+        };
+
+        let scores = config.score_all(vec![x, inputs("b")]);
+
+        assert_eq!(scores[0].name, "b");
+        assert_eq!(scores[1].score, None);
+    }
+
+    #[test]
+    fn suggestion_thresholds() {
+        let config = BackendScoring::default();
+
+        // perfect
+        let fast = inputs("fast");
+
+        // latency 0.5, lag 0.6, everything else perfect. (15 + 15 + 12 + 25 + 5) / 95 = ~75.8
+        let mut okay = inputs("okay");
+        okay.latency_ms = Some(1_000.0);
+        okay.lag = Some(2);
+
+        // latency and lag are 0, errors are 0.5. (15 + 12.5 + 5) / 95 = ~34.2
+        let mut slow = inputs("slow");
+        slow.latency_ms = Some(5_000.0);
+        slow.lag = Some(10);
+        slow.errors = 50;
+
+        // as bad as slow, but it hasn't served enough requests to judge
+        let mut quiet = slow.clone();
+        quiet.name = "quiet".to_string();
+        quiet.requests = 10;
+
+        // latency 0.25, head 0, lag 1.0, errors 1.0. (7.5 + 20 + 25 + 5) / 95 = ~60.5, but it is a backup
+        let mut backup = inputs("backup");
+        backup.backup = true;
+        backup.latency_ms = Some(1_500.0);
+        backup.head_delay_ms = Some(5_000.0);
+
+        let report = config.report(vec![fast, okay, slow, quiet, backup], 60, Utc::now());
+
+        let suggestions = &report.suggestions.balanced_rpcs;
+
+        assert_eq!(
+            suggestions["fast"].soft_limit,
+            Some(Change { from: 100, to: 120 })
+        );
+        assert_eq!(suggestions["fast"].backup, None);
+
+        // between the thresholds
+        assert!(!suggestions.contains_key("okay"));
+
+        assert_eq!(
+            suggestions["slow"].backup,
+            Some(Change {
+                from: false,
+                to: true
+            })
+        );
+        assert_eq!(suggestions["slow"].soft_limit, None);
+
+        assert!(!suggestions.contains_key("quiet"));
+
+        // backups are never demoted again, but their soft limit can still change. 60.5 is above 60
+        assert!(!suggestions.contains_key("backup"));
+    }
+
+    #[test]
+    fn lowering_soft_limits() {
+        let config = BackendScoring::default();
+
+        // latency is 0 and errors are 0.4. (15 + 20 + 10 + 5) / 95 = ~52.6
+        let mut x = inputs("a");
+        x.latency_ms = Some(2_000.0);
+        x.errors = 60;
+
+        let mut best = inputs("best");
+        best.soft_limit = 0;
+
+        let report = config.report(vec![x, best], 60, Utc::now());
+
+        assert_eq!(
+            report.suggestions.balanced_rpcs["a"].soft_limit,
+            Some(Change { from: 100, to: 80 })
+        );
+
+        // a soft limit of 0 is never changed
+        assert!(!report.suggestions.balanced_rpcs.contains_key("best"));
+    }
+
+    #[test]
+    fn never_demote_every_primary() {
+        let config = BackendScoring::default();
+
+        let mut a = inputs("a");
+        a.latency_ms = Some(5_000.0);
+        a.lag = Some(10);
+        a.errors = 50;
+
+        let mut b = a.clone();
+        b.name = "b".to_string();
+        b.errors = 80;
+
+        let report = config.report(vec![a, b], 60, Utc::now());
+
+        let suggestions = &report.suggestions.balanced_rpcs;
+
+        // a is the better of the two, so it stays
+        assert!(suggestions["b"].backup.is_some());
+        assert_eq!(suggestions["a"].backup, None);
+        assert!(suggestions["a"].soft_limit.is_some());
+    }
+
+    #[test]
+    fn counters_since() {
+        let before = BackendCounters {
+            requests: 100,
+            errors: 5,
+            pending_txs: 10,
+            unique_pending_txs: 3,
+            ..Default::default()
+        };
+
+        let now = BackendCounters {
+            soft_limit: 10,
+            latency_ms: Some(20.0),
+            requests: 150,
+            errors: 6,
+            pending_txs: 30,
+            unique_pending_txs: 4,
+            ..Default::default()
+        };
+
+        let x = now.since("a", Some(&before));
+
+        assert_eq!(x.name, "a");
+        assert_eq!(x.soft_limit, 10);
+        assert_eq!(x.latency_ms, Some(20.0));
+        assert_eq!(x.requests, 50);
+        assert_eq!(x.errors, 1);
+        assert_eq!(x.pending_txs, 20);
+        assert_eq!(x.unique_pending_txs, 1);
+
+        // a reloaded rpc starts over
+        let x = before.since("a", Some(&now));
+        assert_eq!(x.requests, 100);
+
+        // new rpcs count everything
+        let x = now.since("a", None);
+        assert_eq!(x.requests, 150);
+    }
+
+    #[test]
+    fn combine_windows() {
+        let mut a1 = inputs("a");
+        a1.latency_ms = Some(10.0);
+        a1.lag = None;
+        a1.errors = 1;
+
+        let mut a2 = inputs("a");
+        a2.soft_limit = 200;
+        a2.latency_ms = Some(30.0);
+        a2.lag = Some(3);
+        a2.errors = 2;
+
+        let b = inputs("b");
+
+        let combined = combine(vec![a1, b, a2]);
+
+        assert_eq!(combined.len(), 2);
+
+        let a = &combined[0];
+        assert_eq!(a.name, "a");
+        assert_eq!(a.soft_limit, 200);
+        assert_eq!(a.latency_ms, Some(20.0));
+        // windows without a lag are left out of the average
+        assert_eq!(a.lag, Some(3));
+        assert_eq!(a.requests, 2_000);
+        assert_eq!(a.errors, 3);
+
+        assert_eq!(combined[1].name, "b");
+    }
+}
@@ -0,0 +1,447 @@
+//! Escalating time-outs for clients that keep sending requests we reject.
+//!
+//! Violations add to a score that decays over time. Once the score crosses a threshold, the ip or key is banned.
+//! Each ban inside the offense window doubles the length of the next one.
+
+use crate::config::AppConfig;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::authorization::{Authorization, AuthorizationType};
+use moka::future::{Cache, CacheBuilder};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::borrow::Cow;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, trace};
+
+/// What a ban (or a violation score) is attached to
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKey {
+    Ip(IpAddr),
+    RpcKey(NonZeroU64),
+}
+
+impl fmt::Display for BanKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(x) => write!(f, "ip:{}", x),
+            Self::RpcKey(x) => write!(f, "rpc_key:{}", x),
+        }
+    }
+}
+
+impl BanKey {
+    /// authenticated requests are tracked by their key. everything else is tracked by ip
+    pub fn from_authorization(authorization: &Authorization) -> Option<Self> {
+        if authorization.authorization_type == AuthorizationType::Internal {
+            return None;
+        }
+
+        match authorization.checks.rpc_secret_key_id {
+            Some(x) => Some(Self::RpcKey(x)),
+            None => Some(Self::Ip(authorization.ip)),
+        }
+    }
+}
+
+/// Things that a client can do to earn a ban
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    /// the key in the url could not be parsed
+    InvalidKey,
+    /// the request failed validation before it was sent anywhere
+    InvalidRequest,
+    /// a method that we never forward (db_*, personal_*, admin_*, ...)
+    BlockedMethod,
+    /// more requests in a batch than we allow
+    OversizedBatch,
+    /// the key parsed, but isn't in our database
+    UnknownKey,
+}
+
+impl Violation {
+    /// how much a single violation adds to the score
+    pub fn weight(&self) -> f64 {
+        match self {
+            Self::BlockedMethod => 5.0,
+            Self::InvalidKey => 10.0,
+            Self::InvalidRequest => 10.0,
+            Self::OversizedBatch => 25.0,
+            Self::UnknownKey => 10.0,
+        }
+    }
+}
+
+/// An active ban
+#[derive(Clone, Debug)]
+pub struct BanEntry {
+    pub until: Instant,
+    /// how many times this key has been banned inside the offense window (including this one)
+    pub offenses: u32,
+    pub reason: Cow<'static, str>,
+}
+
+impl BanEntry {
+    pub fn remaining(&self) -> Duration {
+        self.until.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until > Instant::now()
+    }
+}
+
+#[derive(Debug)]
+struct ViolationScore {
+    score: f64,
+    updated_at: Instant,
+}
+
+/// exponential decay. after `half_life`, half of the score is gone
+pub fn decayed_score(score: f64, elapsed: Duration, half_life: Duration) -> f64 {
+    if half_life.is_zero() {
+        return 0.0;
+    }
+
+    score * 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+}
+
+/// each repeat offense doubles the ban length. `offenses` starts at 1
+pub fn ban_duration(offenses: u32, base: Duration, max: Duration) -> Duration {
+    let multiplier = 2u32.saturating_pow(offenses.saturating_sub(1));
+
+    base.saturating_mul(multiplier).min(max)
+}
+
+/// Violation scores and active bans for ips and rpc keys.
+/// Everything is local to this server. Clients that spread their abuse across servers will take longer to get banned.
+pub struct Bans {
+    threshold: f64,
+    half_life: Duration,
+    base_duration: Duration,
+    max_duration: Duration,
+    scores: Cache<BanKey, Arc<Mutex<ViolationScore>>>,
+    /// how many times a key has been banned recently. used for escalation
+    offenses: Cache<BanKey, u32>,
+    active: Cache<BanKey, BanEntry>,
+    pub total_bans: AtomicU64,
+    pub total_violations: AtomicU64,
+}
+
+impl Bans {
+    pub fn new(config: &AppConfig) -> Self {
+        let half_life = Duration::from_secs(config.ban_score_half_life_secs);
+        let base_duration = Duration::from_secs(config.ban_base_duration_secs);
+        let max_duration = Duration::from_secs(config.ban_max_duration_secs);
+        let offense_window = Duration::from_secs(config.ban_offense_window_secs);
+
+        // after 10 half lives, the score is less than 0.1% of what it was
+        let scores = CacheBuilder::new(100_000)
+            .name("ban_scores")
+            .time_to_idle(half_life.saturating_mul(10).max(Duration::from_secs(1)))
+            .build();
+
+        let offenses = CacheBuilder::new(100_000)
+            .name("ban_offenses")
+            .time_to_live(offense_window.max(Duration::from_secs(1)))
+            .build();
+
+        // entries are checked against `until`. the ttl just keeps the cache from growing forever
+        let active = CacheBuilder::new(100_000)
+            .name("bans")
+            .time_to_live(max_duration.max(Duration::from_secs(1)))
+            .build();
+
+        Self {
+            threshold: config.ban_score_threshold as f64,
+            half_life,
+            base_duration,
+            max_duration,
+            scores,
+            offenses,
+            active,
+            total_bans: AtomicU64::new(0),
+            total_violations: AtomicU64::new(0),
+        }
+    }
+
+    /// error if the given key is currently banned
+    pub async fn check(&self, key: BanKey) -> Web3ProxyResult<()> {
+        if let Some(entry) = self.active.get(&key).await {
+            if entry.is_active() {
+                return Err(Web3ProxyError::Banned(entry.until));
+            }
+
+            self.active.invalidate(&key).await;
+        }
+
+        Ok(())
+    }
+
+    /// authenticated requests only check their key. a noisy neighbor behind the same ip should not affect them
+    pub async fn check_authorization(&self, authorization: &Authorization) -> Web3ProxyResult<()> {
+        match BanKey::from_authorization(authorization) {
+            Some(key) => self.check(key).await,
+            None => Ok(()),
+        }
+    }
+
+    /// add a violation to the key's score. returns the new ban if this violation crossed the threshold
+    pub async fn record(&self, key: BanKey, violation: Violation) -> Option<BanEntry> {
+        self.total_violations.fetch_add(1, Ordering::Relaxed);
+
+        if self.threshold <= 0.0 {
+            // bans are disabled
+            return None;
+        }
+
+        let now = Instant::now();
+
+        let score = self
+            .scores
+            .get_with(key, async move {
+                Arc::new(Mutex::new(ViolationScore {
+                    score: 0.0,
+                    updated_at: now,
+                }))
+            })
+            .await;
+
+        let crossed = {
+            let mut score = score.lock();
+
+            score.score = decayed_score(
+                score.score,
+                now.saturating_duration_since(score.updated_at),
+                self.half_life,
+            ) + violation.weight();
+            score.updated_at = now;
+
+            trace!(%key, ?violation, score = score.score, "violation");
+
+            if score.score >= self.threshold {
+                // start over so that the next ban needs a fresh set of violations
+                score.score = 0.0;
+                true
+            } else {
+                false
+            }
+        };
+
+        if !crossed {
+            return None;
+        }
+
+        let offenses = self.offenses.get(&key).await.unwrap_or_default() + 1;
+
+        self.offenses.insert(key, offenses).await;
+
+        let duration = ban_duration(offenses, self.base_duration, self.max_duration);
+
+        let entry = BanEntry {
+            until: now + duration,
+            offenses,
+            reason: format!("{:?}", violation).into(),
+        };
+
+        info!(%key, ?violation, offenses, ?duration, "banning");
+
+        self.insert(key, entry.clone()).await;
+
+        Some(entry)
+    }
+
+    pub async fn record_authorization(&self, authorization: &Authorization, violation: Violation) {
+        if let Some(key) = BanKey::from_authorization(authorization) {
+            self.record(key, violation).await;
+        }
+    }
+
+    /// When a manual ban for `duration_secs` ends. Bans longer than `ban_max_duration_secs` would be cut short by the
+    /// cache, so they are rejected instead
+    pub fn manual_ban_until(&self, duration_secs: u64) -> Web3ProxyResult<Instant> {
+        let duration = Duration::from_secs(duration_secs);
+
+        if duration > self.max_duration {
+            return Err(Web3ProxyError::BadRequest(
+                format!(
+                    "duration_secs must be at most {} (ban_max_duration_secs)",
+                    self.max_duration.as_secs()
+                )
+                .into(),
+            ));
+        }
+
+        Instant::now()
+            .checked_add(duration)
+            .ok_or_else(|| Web3ProxyError::BadRequest("duration_secs is too large".into()))
+    }
+
+    /// manually ban a key. does not count as an offense
+    pub async fn insert(&self, key: BanKey, entry: BanEntry) {
+        self.total_bans.fetch_add(1, Ordering::Relaxed);
+
+        self.active.insert(key, entry).await;
+    }
+
+    /// lift a ban and forget the key's score and offenses. returns true if a ban was active
+    pub async fn remove(&self, key: &BanKey) -> bool {
+        self.scores.invalidate(key).await;
+        self.offenses.invalidate(key).await;
+
+        self.active
+            .remove(key)
+            .await
+            .map(|x| x.is_active())
+            .unwrap_or_default()
+    }
+
+    pub fn list(&self) -> Vec<(BanKey, BanEntry)> {
+        self.active
+            .iter()
+            .filter(|(_, v)| v.is_active())
+            .map(|(k, v)| (*k, v))
+            .collect()
+    }
+
+    pub fn num_active(&self) -> usize {
+        self.active.iter().filter(|(_, v)| v.is_active()).count()
+    }
+
+    pub fn as_json(&self) -> serde_json::Value {
+        let bans: Vec<_> = self
+            .list()
+            .into_iter()
+            .map(|(key, entry)| {
+                json!({
+                    "key": key,
+                    "offenses": entry.offenses,
+                    "reason": entry.reason,
+                    "remaining_secs": entry.remaining().as_secs(),
+                })
+            })
+            .collect();
+
+        json!({
+            "bans": bans,
+            "total_bans": self.total_bans.load(Ordering::Relaxed),
+            "total_violations": self.total_violations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl fmt::Debug for Bans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bans")
+            .field("threshold", &self.threshold)
+            .field("total_bans", &self.total_bans)
+            .field("total_violations", &self.total_violations)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_decays_by_half_life() {
+        let half_life = Duration::from_secs(60);
+
+        assert_eq!(decayed_score(100.0, Duration::ZERO, half_life), 100.0);
+        assert_eq!(decayed_score(100.0, half_life, half_life), 50.0);
+        assert_eq!(decayed_score(100.0, half_life * 2, half_life), 25.0);
+        assert_eq!(decayed_score(100.0, half_life, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn escalation_steps() {
+        let base = Duration::from_secs(60);
+        let max = Duration::from_secs(600);
+
+        assert_eq!(ban_duration(1, base, max), Duration::from_secs(60));
+        assert_eq!(ban_duration(2, base, max), Duration::from_secs(120));
+        assert_eq!(ban_duration(3, base, max), Duration::from_secs(240));
+        assert_eq!(ban_duration(4, base, max), Duration::from_secs(480));
+        assert_eq!(ban_duration(5, base, max), max);
+        assert_eq!(ban_duration(u32::MAX, base, max), max);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manual_bans_are_capped() {
+        let config = AppConfig {
+            ban_max_duration_secs: 3600,
+            ..Default::default()
+        };
+
+        let bans = Bans::new(&config);
+
+        let until = bans.manual_ban_until(3600).unwrap();
+        assert_eq!(until - Instant::now(), Duration::from_secs(3600));
+
+        // longer than the cache keeps bans
+        assert!(matches!(
+            bans.manual_ban_until(3601),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+
+        // used to overflow the Instant and panic
+        assert!(matches!(
+            bans.manual_ban_until(u64::MAX),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn threshold_and_escalation() {
+        let config = AppConfig {
+            ban_score_threshold: 30,
+            ban_base_duration_secs: 60,
+            ban_max_duration_secs: 3600,
+            ..Default::default()
+        };
+
+        let bans = Bans::new(&config);
+
+        let bad = BanKey::Ip("1.2.3.4".parse().unwrap());
+        let good = BanKey::RpcKey(NonZeroU64::new(1).unwrap());
+
+        // one violation is not enough
+        assert!(bans.record(good, Violation::InvalidRequest).await.is_none());
+        assert!(bans.check(good).await.is_ok());
+
+        assert!(bans.record(bad, Violation::InvalidKey).await.is_none());
+        assert!(bans.record(bad, Violation::InvalidKey).await.is_none());
+
+        let first = bans.record(bad, Violation::InvalidKey).await.unwrap();
+        assert_eq!(first.offenses, 1);
+        assert_eq!(first.remaining(), Duration::from_secs(60));
+
+        assert!(bans.check(bad).await.is_err());
+        assert!(bans.check(good).await.is_ok());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+
+        assert!(bans.check(bad).await.is_ok());
+
+        // a repeat offender gets a longer ban
+        for _ in 0..2 {
+            assert!(bans.record(bad, Violation::InvalidKey).await.is_none());
+        }
+        let second = bans.record(bad, Violation::InvalidKey).await.unwrap();
+        assert_eq!(second.offenses, 2);
+        assert_eq!(second.remaining(), Duration::from_secs(120));
+
+        // lifting the ban resets everything
+        assert!(bans.remove(&bad).await);
+        assert!(bans.check(bad).await.is_ok());
+    }
+}
@@ -1,5 +1,6 @@
 //! Helper functions for turning ether's BlockNumber into numbers and updating incoming queries to match.
 use crate::app::App;
+use crate::block_tags::{BlockTag, BlockTags};
 use crate::jsonrpc::SingleRequest;
 use crate::{
     errors::{Web3ProxyError, Web3ProxyResult},
@@ -66,6 +67,22 @@ impl From<&BlockHeader> for BlockNumAndHash {
     }
 }
 
+/// The block to cache a response under when only its number is known.
+/// Near the head, the block at a number can still change, so the head is part of the key. Past `confirmations` it won't,
+/// so the number alone is enough and new heads don't change the key. `None` if there is no app to configure this
+fn cache_block_for_num(
+    num: U64,
+    head_block: &BlockHeader,
+    confirmations: Option<u64>,
+) -> BlockNumAndHash {
+    match confirmations {
+        Some(x) if num.saturating_add(U64::from(x)) <= head_block.number() => {
+            BlockNumAndHash(num, H256::zero())
+        }
+        _ => head_block.into(),
+    }
+}
+
 /// modify params to always have a block hash and not "latest"
 /// TODO: it would be nice to replace "latest" with the hash, but not all methods support that
 pub async fn clean_block_number<'a>(
@@ -201,7 +218,22 @@ pub async fn clean_block_number<'a>(
     }
 }
 
-#[derive(Debug, From, Hash, Eq, PartialEq, Serialize)]
+/// the tracked block for a "finalized" or "safe" param. None for any other param or if the tagged block isn't known yet
+fn tagged_block(
+    x: &serde_json::Value,
+    app: Option<&App>,
+) -> Web3ProxyResult<Option<BlockNumAndHash>> {
+    let Some(tag) = BlockTag::from_param(x) else {
+        return Ok(None);
+    };
+
+    match app.and_then(|x| x.block_tags.as_deref()) {
+        Some(block_tags) => block_tags.get(tag),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug, From, Hash, Eq, PartialEq, Serialize)]
 pub enum BlockNumOrHash {
     Num(U64),
     And(BlockNumAndHash),
@@ -256,6 +288,7 @@ fn get_block_param_id(method: &str) -> Option<usize> {
         "eth_call" => Some(1),
         "eth_estimateGas" => Some(1),
         "eth_feeHistory" => Some(1),
+        "eth_getAccount" => Some(1),
         "eth_getBalance" => Some(1),
         "eth_getBlockByNumber" => Some(0),
         "eth_getBlockReceipts" => Some(0),
@@ -266,6 +299,7 @@ fn get_block_param_id(method: &str) -> Option<usize> {
         "eth_getTransactionCount" => Some(1),
         "eth_getUncleByBlockNumberAndIndex" => Some(0),
         "eth_getUncleCountByBlockNumber" => Some(0),
+        "eth_simulateV1" => Some(1),
         "trace_block" => Some(0),
         "trace_call" => Some(2),
         "trace_callMany" => Some(1),
@@ -273,6 +307,40 @@ fn get_block_param_id(method: &str) -> Option<usize> {
     }
 }
 
+/// The block param of a request if it is the "pending" tag
+fn pending_block_param<'a>(
+    method: &str,
+    params: &'a mut serde_json::Value,
+) -> Option<&'a mut serde_json::Value> {
+    let block_param_id = get_block_param_id(method)?;
+
+    params
+        .get_mut(block_param_id)
+        .filter(|x| x.as_str() == Some("pending"))
+}
+
+/// true if the request asks for the "pending" block
+pub fn uses_pending_block(request: &SingleRequest) -> bool {
+    let Some(block_param_id) = get_block_param_id(&request.method) else {
+        return false;
+    };
+
+    request.params.get(block_param_id).and_then(|x| x.as_str()) == Some("pending")
+}
+
+/// Replace a "pending" block param with "latest". Returns true if the request was changed.
+/// This runs before the `CacheMode` so that "latest" then gets replaced with the head block's number.
+pub fn rewrite_pending_to_latest(request: &mut SingleRequest) -> bool {
+    match pending_block_param(&request.method, &mut request.params) {
+        Some(x) => {
+            trace!(method=%request.method, "rewriting pending to latest");
+            *x = json!("latest");
+            true
+        }
+        None => false,
+    }
+}
+
 impl CacheMode {
     /// like `try_new`, but instead of erroring if things can't be cached, it will default to caching with the head block
     /// this will still error if something is wrong about the request (like the range is too large or invalid)
@@ -293,6 +361,7 @@ impl CacheMode {
             }
             err @ Err(Web3ProxyError::RangeTooLarge { .. }) => return err,
             err @ Err(Web3ProxyError::RangeInvalid { .. }) => return err,
+            err @ Err(Web3ProxyError::BlockTagUnsupported(_)) => return err,
             Err(err) => {
                 error!(
                     method = %request.method,
@@ -360,6 +429,15 @@ impl CacheMode {
                 cache_block: head_block.into(),
                 cache_errors: true,
             }),
+            "eth_blobBaseFee" => Ok(Self::Standard {
+                block_needed: head_block.into(),
+                cache_block: head_block.into(),
+                cache_errors: true,
+            }),
+            "eth_createAccessList" => {
+                // the access list depends on pending state. caching it by block can give users a list that fails
+                Ok(Self::Never)
+            }
             "eth_gasPrice" => Ok(Self::Never),
             "eth_getBlockByHash" => {
                 // TODO: double check that any node can serve this
@@ -386,12 +464,15 @@ impl CacheMode {
                     Ok(Self::SuccessForever)
                 } else {
                     let from_block = if let Some(x) = obj.get_mut("fromBlock") {
-                        // TODO: use .take instead of clone
-                        // what if its a hash?
-                        let block_num: BlockNumber = serde_json::from_value(x.clone())?;
+                        let block_num = if let Some(block) = tagged_block(x, app)? {
+                            block.num()
+                        } else {
+                            // TODO: use .take instead of clone
+                            // what if its a hash?
+                            let block_num: BlockNumber = serde_json::from_value(x.clone())?;
 
-                        let (block_num, change) =
-                            BlockNumber_to_U64(block_num, head_block.number());
+                            BlockNumber_to_U64(block_num, head_block.number()).0
+                        };
 
                         // TODO: double check this. it scares me
                         // we always change because some clients send U64 with padding and erigon doesn't like that
@@ -404,57 +485,59 @@ impl CacheMode {
                     };
 
                     let to_block = if let Some(x) = obj.get_mut("toBlock") {
-                        // TODO: use .take instead of clone
-                        // what if its a hash?
-                        let block_num: BlockNumber = serde_json::from_value(x.clone())?;
-
-                        // sometimes people request `from_block=head+1, to_block="latest"`. latest becomes head and then theres a problem
-                        // TODO: if this is in the future, this cache key won't be very likely to be used again
-                        // TODO: delay here until the app has this block?
-                        let latest_block = head_block.number().max(from_block.num());
-
-                        let (block_num, change) = BlockNumber_to_U64(block_num, latest_block);
-
-                        // TODO: double check this. it scares me but i think we need it
-                        trace!("changing toBlock in eth_getLogs. {} -> {}", x, block_num);
-                        *x = json!(block_num);
+                        if let Some(block) = tagged_block(x, app)? {
+                            trace!("changing toBlock in eth_getLogs. {} -> {}", x, block.num());
+                            *x = json!(block.num());
 
-                        if let Some(app) = app {
-                            // TODO: make a jsonrpc query here? cache rates will be better but it adds a network request
-                            if let Some(block_hash) =
-                                app.balanced_rpcs.blocks_by_number.get(&block_num).await
-                            {
-                                BlockNumOrHash::And(BlockNumAndHash(block_num, block_hash))
+                            BlockNumOrHash::And(block)
+                        } else {
+                            // TODO: use .take instead of clone
+                            // what if its a hash?
+                            let block_num: BlockNumber = serde_json::from_value(x.clone())?;
+
+                            // sometimes people request `from_block=head+1, to_block="latest"`. latest becomes head and then theres a problem
+                            // TODO: if this is in the future, this cache key won't be very likely to be used again
+                            // TODO: delay here until the app has this block?
+                            let latest_block = head_block.number().max(from_block.num());
+
+                            let (block_num, change) = BlockNumber_to_U64(block_num, latest_block);
+
+                            // TODO: double check this. it scares me but i think we need it
+                            trace!("changing toBlock in eth_getLogs. {} -> {}", x, block_num);
+                            *x = json!(block_num);
+
+                            if let Some(app) = app {
+                                // TODO: make a jsonrpc query here? cache rates will be better but it adds a network request
+                                if let Some(block_hash) =
+                                    app.balanced_rpcs.blocks_by_number.get(&block_num).await
+                                {
+                                    BlockNumOrHash::And(BlockNumAndHash(block_num, block_hash))
+                                } else {
+                                    BlockNumOrHash::Num(block_num)
+                                }
                             } else {
                                 BlockNumOrHash::Num(block_num)
                             }
-                        } else {
-                            BlockNumOrHash::Num(block_num)
                         }
                     } else {
                         BlockNumOrHash::And(head_block.into())
                     };
 
-                    if let Some(range) = to_block.num().checked_sub(from_block.num()) {
-                        if range.as_u64() > 200_000 {
-                            return Err(Web3ProxyError::RangeTooLarge {
-                                from: from_block,
-                                to: to_block,
-                                requested: range,
-                                allowed: 200_000.into(),
-                            });
-                        }
-                    } else {
+                    // the size of the range is checked by `GetLogsLimits` once we know who is asking
+                    if to_block.num() < from_block.num() {
                         return Err(Web3ProxyError::RangeInvalid {
                             from: from_block,
                             to: to_block,
                         });
                     }
 
-                    let cache_block = if let BlockNumOrHash::And(x) = &to_block {
-                        x.clone()
-                    } else {
-                        BlockNumAndHash::from(head_block)
+                    let cache_block = match &to_block {
+                        BlockNumOrHash::And(x) => x.clone(),
+                        BlockNumOrHash::Num(x) => cache_block_for_num(
+                            *x,
+                            head_block,
+                            app.map(|app| app.config.archive_cache_confirmations),
+                        ),
                     };
 
                     Ok(Self::Range {
@@ -489,16 +572,34 @@ impl CacheMode {
                 Ok(Self::Never)
             }
             "eth_sendRawTransaction" => Ok(Self::Never),
+            "eth_sendRawTransactionConditional" => Ok(Self::Never),
             "net_listening" => Ok(Self::SuccessForever),
             "net_version" => Ok(Self::SuccessForever),
             method => match get_block_param_id(method) {
                 Some(block_param_id) => {
+                    if let Some(tag) = params.get(block_param_id).and_then(BlockTag::from_param) {
+                        let param = params
+                            .get_mut(block_param_id)
+                            .expect("param was just checked");
+
+                        return Self::for_block_tag(
+                            param,
+                            tag,
+                            head_block,
+                            app.and_then(|x| x.block_tags.as_deref()),
+                        );
+                    }
+
                     let block_needed =
                         clean_block_number(params, block_param_id, head_block, app).await?;
 
                     let cache_block = match &block_needed {
                         BlockNumOrHash::And(block) => block.clone(),
-                        BlockNumOrHash::Num(_) => head_block.into(),
+                        BlockNumOrHash::Num(x) => cache_block_for_num(
+                            *x,
+                            head_block,
+                            app.map(|app| app.config.archive_cache_confirmations),
+                        ),
                     };
 
                     Ok(Self::Standard {
@@ -512,6 +613,35 @@ impl CacheMode {
         }
     }
 
+    /// Requests for "finalized" or "safe" are cached under the tagged block instead of the head.
+    /// The param is replaced with the tagged block's number so that every backend answers for the same block.
+    pub fn for_block_tag(
+        param: &mut serde_json::Value,
+        tag: BlockTag,
+        head_block: &BlockHeader,
+        block_tags: Option<&BlockTags>,
+    ) -> Web3ProxyResult<Self> {
+        let Some(block_tags) = block_tags else {
+            // nothing is tracking the tags. let the backend resolve it
+            return Ok(Self::Never);
+        };
+
+        match block_tags.get(tag)? {
+            Some(block) if block.num() <= head_block.number() => {
+                trace!(%tag, num=%block.num(), "changing block tag");
+                *param = json!(block.num());
+
+                Ok(Self::Standard {
+                    block_needed: block.clone().into(),
+                    cache_block: block,
+                    cache_errors: true,
+                })
+            }
+            // not probed yet, or our head is behind the rpc that answered the probe
+            _ => Ok(Self::Never),
+        }
+    }
+
     #[inline]
     pub fn cache_jsonrpc_errors(&self) -> bool {
         match self {
@@ -564,10 +694,16 @@ impl CacheMode {
 
 #[cfg(test)]
 mod test {
-    use super::CacheMode;
+    use super::{
+        cache_block_for_num, rewrite_pending_to_latest, uses_pending_block, BlockNumAndHash,
+        CacheMode,
+    };
     use crate::{
+        block_tags::{BlockTag, BlockTags},
         errors::Web3ProxyError,
+        frontend::authorization::RequestOrMethod,
         jsonrpc::{LooseId, SingleRequest},
+        response_cache::JsonRpcQueryCacheKey,
         rpcs::blockchain::BlockHeader,
     };
     use ethers::types::{Block, H256, U64};
@@ -684,6 +820,292 @@ mod test {
         matches!(x, CacheMode::Never);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_newer_methods() {
+        let head_block = Block {
+            number: Some(18173997.into()),
+            hash: Some(H256::random()),
+            ..Default::default()
+        };
+
+        let head_block = BlockHeader::try_new(Arc::new(head_block)).unwrap();
+
+        let head_block_cache = CacheMode::Standard {
+            block_needed: (&head_block).into(),
+            cache_block: (&head_block).into(),
+            cache_errors: true,
+        };
+
+        let tx = json!({"data": "0xdeadbeef", "to": "0x0000000000000000000000000000000000000000"});
+
+        // access lists must never be cached
+        let mut request = SingleRequest::new(
+            1.into(),
+            "eth_createAccessList".into(),
+            json!([tx, "latest"]),
+        )
+        .unwrap();
+
+        let x = CacheMode::try_new(&mut request, Some(&head_block), None)
+            .await
+            .unwrap();
+
+        assert_eq!(x, CacheMode::Never);
+
+        // blob base fee is cached by head block
+        let mut request =
+            SingleRequest::new(1.into(), "eth_blobBaseFee".into(), json!([])).unwrap();
+
+        let x = CacheMode::try_new(&mut request, Some(&head_block), None)
+            .await
+            .unwrap();
+
+        assert_eq!(x, head_block_cache);
+
+        // these have a block param in the second position
+        for (method, params) in [
+            (
+                "eth_getAccount",
+                json!(["0x0000000000000000000000000000000000000000", "latest"]),
+            ),
+            ("eth_simulateV1", json!([{"blockStateCalls": []}, "latest"])),
+        ] {
+            let mut request = SingleRequest::new(1.into(), method.into(), params).unwrap();
+
+            let x = CacheMode::try_new(&mut request, Some(&head_block), None)
+                .await
+                .unwrap();
+
+            assert_eq!(x, head_block_cache, "{}", method);
+
+            assert_eq!(
+                request.params.get(1),
+                Some(&json!(head_block.number())),
+                "{}",
+                method
+            );
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pending_rewritten_to_latest() {
+        let head_block = Block {
+            number: Some(18173997.into()),
+            hash: Some(H256::random()),
+            ..Default::default()
+        };
+
+        let head_block = BlockHeader::try_new(Arc::new(head_block)).unwrap();
+
+        let address = "0x0000000000000000000000000000000000000000";
+
+        let mut request = SingleRequest::new(
+            1.into(),
+            "eth_getTransactionCount".into(),
+            json!([address, "pending"]),
+        )
+        .unwrap();
+
+        assert!(uses_pending_block(&request));
+        assert!(rewrite_pending_to_latest(&mut request));
+        assert!(!uses_pending_block(&request));
+        assert_eq!(request.params, json!([address, "latest"]));
+
+        let x = CacheMode::try_new(&mut request, Some(&head_block), None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            x,
+            CacheMode::Standard {
+                block_needed: (&head_block).into(),
+                cache_block: (&head_block).into(),
+                cache_errors: true
+            }
+        );
+        assert_eq!(request.params.get(1), Some(&json!(head_block.number())));
+
+        // other block params are left alone
+        let mut request =
+            SingleRequest::new(1.into(), "eth_getBalance".into(), json!([address, "0x10"]))
+                .unwrap();
+
+        assert!(!uses_pending_block(&request));
+        assert!(!rewrite_pending_to_latest(&mut request));
+        assert_eq!(request.params, json!([address, "0x10"]));
+
+        // so are methods without a block param
+        let mut request = SingleRequest::new(
+            1.into(),
+            "eth_getTransactionByHash".into(),
+            json!(["pending"]),
+        )
+        .unwrap();
+
+        assert!(!rewrite_pending_to_latest(&mut request));
+    }
+
+    fn head(num: u64) -> BlockHeader {
+        let block = Block {
+            number: Some(num.into()),
+            hash: Some(H256::random()),
+            ..Default::default()
+        };
+
+        BlockHeader::try_new(Arc::new(block)).unwrap()
+    }
+
+    /// the cache key for `eth_getBalance` at "finalized"
+    fn finalized_balance_key(
+        head_block: &BlockHeader,
+        block_tags: Option<&BlockTags>,
+    ) -> Result<(CacheMode, u64), Web3ProxyError> {
+        let mut request = SingleRequest::new(
+            1.into(),
+            "eth_getBalance".into(),
+            json!(["0x0000000000000000000000000000000000000000", "finalized"]),
+        )
+        .unwrap();
+
+        let cache_mode = CacheMode::for_block_tag(
+            request.params.get_mut(1).unwrap(),
+            BlockTag::Finalized,
+            head_block,
+            block_tags,
+        )?;
+
+        let request = RequestOrMethod::Request(request);
+
+        let key = JsonRpcQueryCacheKey::new(&cache_mode, &request).hash();
+
+        Ok((cache_mode, key))
+    }
+
+    #[test]
+    fn test_finalized_cache_keys() {
+        let block_tags = BlockTags::default();
+
+        // nothing is known yet. forward the tag and don't cache
+        let (x, _) = finalized_balance_key(&head(100), Some(&block_tags)).unwrap();
+        assert_eq!(x, CacheMode::Never);
+
+        let (x, _) = finalized_balance_key(&head(100), None).unwrap();
+        assert_eq!(x, CacheMode::Never);
+
+        // finalized moves much slower than the head
+        let finalized: BlockNumAndHash = (U64::from(90), H256::random()).into();
+        block_tags.set(BlockTag::Finalized, Some(finalized.clone()));
+
+        let (x, first_key) = finalized_balance_key(&head(100), Some(&block_tags)).unwrap();
+
+        assert_eq!(
+            x,
+            CacheMode::Standard {
+                block_needed: finalized.clone().into(),
+                cache_block: finalized,
+                cache_errors: true,
+            }
+        );
+
+        // new heads don't change the key
+        for num in 101..105 {
+            let (_, key) = finalized_balance_key(&head(num), Some(&block_tags)).unwrap();
+            assert_eq!(key, first_key, "{}", num);
+        }
+
+        // but moving finalized does
+        block_tags.set(
+            BlockTag::Finalized,
+            Some((U64::from(96), H256::random()).into()),
+        );
+
+        let (_, key) = finalized_balance_key(&head(105), Some(&block_tags)).unwrap();
+        assert_ne!(key, first_key);
+
+        // even a re-org of the same number does
+        block_tags.set(
+            BlockTag::Finalized,
+            Some((U64::from(96), H256::random()).into()),
+        );
+
+        let (_, reorg_key) = finalized_balance_key(&head(105), Some(&block_tags)).unwrap();
+        assert_ne!(reorg_key, key);
+
+        // our head is behind the rpc that told us about finalized
+        let (x, _) = finalized_balance_key(&head(95), Some(&block_tags)).unwrap();
+        assert_eq!(x, CacheMode::Never);
+    }
+
+    #[test]
+    fn test_finalized_rewritten() {
+        let head_block = head(100);
+
+        let block_tags = BlockTags::default();
+        block_tags.set(
+            BlockTag::Finalized,
+            Some((U64::from(90), H256::random()).into()),
+        );
+
+        let address = "0x0000000000000000000000000000000000000000";
+
+        let mut params = json!([address, "finalized"]);
+
+        CacheMode::for_block_tag(
+            params.get_mut(1).unwrap(),
+            BlockTag::Finalized,
+            &head_block,
+            Some(&block_tags),
+        )
+        .unwrap();
+
+        assert_eq!(params, json!([address, U64::from(90)]));
+
+        // pre-merge chains reject the tags instead of forwarding them
+        block_tags.set_unsupported();
+
+        let mut request =
+            SingleRequest::new(1.into(), "eth_getBalance".into(), json!([address, "safe"]))
+                .unwrap();
+
+        let err = CacheMode::for_block_tag(
+            request.params.get_mut(1).unwrap(),
+            BlockTag::Safe,
+            &head_block,
+            Some(&block_tags),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Web3ProxyError::BlockTagUnsupported(BlockTag::Safe)
+        ));
+        assert_eq!(request.params, json!([address, "safe"]));
+    }
+
+    #[test]
+    fn test_archive_cache_block() {
+        let num = U64::from(1_000);
+
+        // near the head, the head is part of the key
+        let near = head(1_050);
+        assert_eq!(
+            cache_block_for_num(num, &near, Some(64)),
+            BlockNumAndHash::from(&near)
+        );
+
+        // past the horizon, new heads don't change the key
+        let x = cache_block_for_num(num, &head(1_064), Some(64));
+        assert_eq!(x, BlockNumAndHash(num, H256::zero()));
+        assert_eq!(cache_block_for_num(num, &head(1_065), Some(64)), x);
+
+        // without an app there is no horizon
+        let far = head(2_000);
+        assert_eq!(
+            cache_block_for_num(num, &far, None),
+            BlockNumAndHash::from(&far)
+        );
+    }
+
     #[test]
     fn test_serializing_padded_ints() {
         let x: U64 = "0x001234".parse().unwrap();
@@ -0,0 +1,238 @@
+//! The "finalized" and "safe" blocks.
+//!
+//! The head subscription only tells us about "latest". On new heads (at most once every `block_tags_poll_secs`), the
+//! balanced rpcs are asked for both tagged blocks. Requests for a tag are then rewritten to that block's number and
+//! cached under its hash. The finalized block moves much less often than the head, so those cache entries keep getting
+//! hits across many heads. When it does move, the new hash gives new cache keys and the old entries idle out.
+//!
+//! Chains without the tags (pre-merge) answer the probe with an error. Requests with the tags are then rejected
+//! instead of being forwarded to backends that would fail them anyway.
+
+use crate::block_number::BlockNumAndHash;
+use crate::config::AppConfig;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::rpcs::blockchain::BlockHeader;
+use crate::rpcs::many::Web3Rpcs;
+use ethers::types::{Block, TxHash};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info, trace, warn};
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockTag {
+    Finalized,
+    Safe,
+}
+
+impl BlockTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Finalized => "finalized",
+            Self::Safe => "safe",
+        }
+    }
+
+    /// Some if this block param is one of the tags
+    pub fn from_param(x: &serde_json::Value) -> Option<Self> {
+        match x.as_str()? {
+            "finalized" => Some(Self::Finalized),
+            "safe" => Some(Self::Safe),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BlockTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tagged {
+    finalized: Option<BlockNumAndHash>,
+    safe: Option<BlockNumAndHash>,
+    /// None until the first probe finishes
+    supported: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockTags {
+    tagged: RwLock<Tagged>,
+}
+
+impl BlockTags {
+    /// None if `block_tags_poll_secs` is 0. Then tagged requests are forwarded and not cached
+    pub fn spawn(
+        config: &AppConfig,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        rpcs: Arc<Web3Rpcs>,
+    ) -> Option<Arc<Self>> {
+        if config.block_tags_poll_secs == 0 {
+            return None;
+        }
+
+        let x = Arc::new(Self::default());
+
+        tokio::spawn(x.clone().follow(
+            head_block_receiver,
+            rpcs,
+            Duration::from_secs(config.block_tags_poll_secs),
+        ));
+
+        Some(x)
+    }
+
+    async fn follow(
+        self: Arc<Self>,
+        mut head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        rpcs: Arc<Web3Rpcs>,
+        poll_interval: Duration,
+    ) {
+        let mut last_probe: Option<Instant> = None;
+
+        while head_block_receiver.changed().await.is_ok() {
+            if head_block_receiver.borrow_and_update().is_none() {
+                continue;
+            }
+
+            // the tagged blocks only ever move when the head does, but they move much less often
+            if last_probe.is_some_and(|x| x.elapsed() < poll_interval) {
+                continue;
+            }
+
+            last_probe = Some(Instant::now());
+
+            for tag in [BlockTag::Finalized, BlockTag::Safe] {
+                self.probe(&rpcs, tag).await;
+            }
+        }
+    }
+
+    async fn probe(&self, rpcs: &Web3Rpcs, tag: BlockTag) {
+        // this goes straight to the rpcs. going through the app would resolve the tag with what we have now
+        let x = rpcs
+            .internal_request::<_, Option<Block<TxHash>>>(
+                "eth_getBlockByNumber".into(),
+                &(tag.as_str(), false),
+                Some(Duration::from_secs(5)),
+            )
+            .await;
+
+        match x {
+            Ok(Some(block)) => match (block.number, block.hash) {
+                (Some(num), Some(hash)) => self.set(tag, Some((num, hash).into())),
+                _ => warn!(%tag, "tagged block is missing its number or hash"),
+            },
+            // the chain hasn't finalized anything yet
+            Ok(None) => self.set(tag, None),
+            Err(Web3ProxyError::JsonRpcErrorData(err)) => {
+                debug!(%tag, ?err, "block tag not supported");
+                self.set_unsupported();
+            }
+            Err(err) => {
+                // timeouts and connection errors don't tell us anything about the chain. keep what we had
+                warn!(%tag, ?err, "failed checking tagged block");
+            }
+        }
+    }
+
+    pub fn set(&self, tag: BlockTag, block: Option<BlockNumAndHash>) {
+        let mut tagged = self.tagged.write();
+
+        if tagged.supported != Some(true) {
+            info!("block tags are supported");
+            tagged.supported = Some(true);
+        }
+
+        let old = match tag {
+            BlockTag::Finalized => &mut tagged.finalized,
+            BlockTag::Safe => &mut tagged.safe,
+        };
+
+        if *old != block {
+            trace!(%tag, ?block, "tagged block moved");
+            *old = block;
+        }
+    }
+
+    pub fn set_unsupported(&self) {
+        let mut tagged = self.tagged.write();
+
+        if tagged.supported != Some(false) {
+            warn!("block tags are not supported by the backends. requests using them will be rejected");
+        }
+
+        *tagged = Tagged {
+            supported: Some(false),
+            ..Default::default()
+        };
+    }
+
+    /// Ok(None) if the tagged block isn't known yet. Errors if the backends don't support the tag
+    pub fn get(&self, tag: BlockTag) -> Web3ProxyResult<Option<BlockNumAndHash>> {
+        let tagged = self.tagged.read();
+
+        if tagged.supported == Some(false) {
+            return Err(Web3ProxyError::BlockTagUnsupported(tag));
+        }
+
+        let x = match tag {
+            BlockTag::Finalized => tagged.finalized.clone(),
+            BlockTag::Safe => tagged.safe.clone(),
+        };
+
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{H256, U64};
+    use serde_json::json;
+
+    #[test]
+    fn from_param() {
+        assert_eq!(
+            BlockTag::from_param(&json!("finalized")),
+            Some(BlockTag::Finalized)
+        );
+        assert_eq!(BlockTag::from_param(&json!("safe")), Some(BlockTag::Safe));
+        assert_eq!(BlockTag::from_param(&json!("latest")), None);
+        assert_eq!(BlockTag::from_param(&json!(1)), None);
+    }
+
+    #[test]
+    fn supported_and_unsupported() {
+        let x = BlockTags::default();
+
+        // nothing probed yet
+        assert_eq!(x.get(BlockTag::Finalized).unwrap(), None);
+
+        let finalized: BlockNumAndHash = (U64::from(90), H256::repeat_byte(1)).into();
+
+        x.set(BlockTag::Finalized, Some(finalized.clone()));
+
+        assert_eq!(x.get(BlockTag::Finalized).unwrap(), Some(finalized.clone()));
+        assert_eq!(x.get(BlockTag::Safe).unwrap(), None);
+
+        x.set_unsupported();
+
+        assert!(matches!(
+            x.get(BlockTag::Safe),
+            Err(Web3ProxyError::BlockTagUnsupported(BlockTag::Safe))
+        ));
+
+        // the backends were upgraded
+        x.set(BlockTag::Safe, Some(finalized.clone()));
+
+        assert_eq!(x.get(BlockTag::Safe).unwrap(), Some(finalized));
+        assert_eq!(x.get(BlockTag::Finalized).unwrap(), None);
+    }
+}
@@ -0,0 +1,316 @@
+//! Cache invalidations shared between servers.
+//!
+//! Every server behind the load balancer has its own caches. When one of them sees a reorg or an admin clears one of
+//! its caches, the others would keep serving what they had cached. Invalidations are published on a redis channel that
+//! every server subscribes to, and each server applies the ones that came from other servers. Every message has the id
+//! of the server that sent it, so a server never applies (or re-publishes) its own.
+//!
+//! Delivery is best-effort. A server that is disconnected from redis misses whatever was published in the meantime.
+//! Without `volatile_redis_url`, caches are only invalidated on the server where it happened.
+
+use crate::app::App;
+use crate::config::AppConfig;
+use ethers::types::{Address, H256, U64};
+use futures::StreamExt;
+use redis_rate_limiter::redis::{self, AsyncCommands};
+use redis_rate_limiter::RedisPool;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, info, trace, warn};
+use ulid::Ulid;
+
+/// invalidations waiting to be published. if redis is slow enough for this to fill, new ones are dropped
+pub const MAX_PENDING: usize = 1_000;
+
+/// the longest wait between attempts to resubscribe
+pub const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Something that every server should drop from its caches
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Invalidation {
+    /// the chain rolled back (or uncled) at this height. block hashes at or above it are forgotten.
+    /// responses cached for the orphaned heads are dropped
+    Reorg {
+        number: U64,
+        #[serde(default)]
+        orphaned: Vec<H256>,
+    },
+    /// cached responses. one key, the ones from one backend, or (if both are None) everything
+    ResponseCache {
+        key: Option<u64>,
+        backend: Option<String>,
+    },
+    /// cached `eth_call`s to a contract
+    CallCache { address: Address },
+    /// a user's balance and rpc keys
+    User { user_id: u64 },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct InvalidationMessage {
+    /// the server that published this
+    instance: Ulid,
+    #[serde(flatten)]
+    invalidation: Invalidation,
+}
+
+pub struct CacheInvalidations {
+    channel: String,
+    instance: Ulid,
+    redis_url: String,
+    sender: mpsc::Sender<Invalidation>,
+    /// sent to redis
+    pub published: AtomicU64,
+    /// dropped because the queue was full or redis returned an error
+    pub publish_errors: AtomicU64,
+    /// received from other servers and applied here
+    pub applied: AtomicU64,
+    /// received from other servers but they couldn't be parsed or applied
+    pub apply_errors: AtomicU64,
+}
+
+impl CacheInvalidations {
+    /// None unless `volatile_redis_url` is set. Publishing happens in a background task.
+    /// Call `subscribe` once the app exists to apply invalidations from the other servers.
+    pub fn spawn(config: &AppConfig, redis_pool: Option<&RedisPool>) -> Option<Arc<Self>> {
+        let redis_url = config.volatile_redis_url.clone()?;
+        let redis_pool = redis_pool?.clone();
+
+        let (sender, receiver) = mpsc::channel(MAX_PENDING);
+
+        let x = Arc::new(Self {
+            channel: format!("web3_proxy:{}:invalidations", config.chain_id),
+            instance: Ulid::new(),
+            redis_url,
+            sender,
+            published: AtomicU64::new(0),
+            publish_errors: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            apply_errors: AtomicU64::new(0),
+        });
+
+        tokio::spawn(publish_loop(Arc::downgrade(&x), redis_pool, receiver));
+
+        info!(channel=%x.channel, instance=%x.instance, "sharing cache invalidations");
+
+        Some(x)
+    }
+
+    /// Tell the other servers. This never waits on redis
+    pub fn publish(&self, invalidation: Invalidation) {
+        trace!(?invalidation, "publishing");
+
+        if let Err(err) = self.sender.try_send(invalidation) {
+            self.publish_errors.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                ?err,
+                "cache invalidation queue is full. other servers will not see this"
+            );
+        }
+    }
+
+    /// Apply invalidations from the other servers until the app is dropped
+    pub fn subscribe(self: &Arc<Self>, app: Weak<App>) {
+        tokio::spawn(subscribe_loop(self.clone(), app));
+    }
+
+    fn message(&self, invalidation: Invalidation) -> String {
+        let x = InvalidationMessage {
+            instance: self.instance,
+            invalidation,
+        };
+
+        serde_json::to_string(&x).expect("invalidations should always serialize")
+    }
+
+    /// None if the message is from this server
+    fn parse(&self, payload: &str) -> serde_json::Result<Option<Invalidation>> {
+        let x: InvalidationMessage = serde_json::from_str(payload)?;
+
+        if x.instance == self.instance {
+            Ok(None)
+        } else {
+            Ok(Some(x.invalidation))
+        }
+    }
+}
+
+async fn publish_loop(
+    invalidations: Weak<CacheInvalidations>,
+    redis_pool: RedisPool,
+    mut receiver: mpsc::Receiver<Invalidation>,
+) {
+    while let Some(invalidation) = receiver.recv().await {
+        let Some(invalidations) = invalidations.upgrade() else {
+            break;
+        };
+
+        let message = invalidations.message(invalidation);
+
+        let published = async {
+            let mut conn = redis_pool.get().await?;
+
+            conn.publish::<_, _, ()>(&invalidations.channel, &message)
+                .await?;
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        match published {
+            Ok(()) => {
+                invalidations.published.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                invalidations.publish_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(?err, %message, "failed publishing cache invalidation");
+            }
+        }
+    }
+
+    trace!("cache invalidation publisher exited");
+}
+
+async fn subscribe_loop(invalidations: Arc<CacheInvalidations>, app: Weak<App>) {
+    let mut backoff = Duration::from_secs(1);
+
+    while app.strong_count() > 0 {
+        match subscribe_once(&invalidations, &app, &mut backoff).await {
+            Ok(()) => debug!("cache invalidation subscription ended"),
+            Err(err) => warn!(?err, "cache invalidation subscription failed"),
+        }
+
+        // anything published while we were disconnected is missed
+        sleep(backoff).await;
+
+        backoff = (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF);
+    }
+
+    trace!("cache invalidation subscriber exited");
+}
+
+async fn subscribe_once(
+    invalidations: &CacheInvalidations,
+    app: &Weak<App>,
+    backoff: &mut Duration,
+) -> anyhow::Result<()> {
+    // pubsub needs a connection of its own. pooled connections are shared with everything else
+    let client = redis::Client::open(invalidations.redis_url.as_str())?;
+
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+
+    pubsub.subscribe(&invalidations.channel).await?;
+
+    info!(channel=%invalidations.channel, "subscribed to cache invalidations");
+
+    *backoff = Duration::from_secs(1);
+
+    let mut messages = pubsub.on_message();
+
+    while let Some(message) = messages.next().await {
+        let Some(app) = app.upgrade() else {
+            break;
+        };
+
+        let payload: String = match message.get_payload() {
+            Ok(x) => x,
+            Err(err) => {
+                invalidations.apply_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(?err, "unreadable cache invalidation");
+                continue;
+            }
+        };
+
+        let invalidation = match invalidations.parse(&payload) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(err) => {
+                invalidations.apply_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(?err, %payload, "invalid cache invalidation");
+                continue;
+            }
+        };
+
+        debug!(
+            ?invalidation,
+            "applying cache invalidation from another server"
+        );
+
+        match app.apply_invalidation(&invalidation).await {
+            Ok(()) => {
+                invalidations.applied.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                invalidations.apply_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(?err, ?invalidation, "failed applying cache invalidation");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalidations(config: &AppConfig) -> CacheInvalidations {
+        let (sender, _) = mpsc::channel(1);
+
+        CacheInvalidations {
+            channel: format!("web3_proxy:{}:invalidations", config.chain_id),
+            instance: Ulid::new(),
+            redis_url: "redis://localhost".into(),
+            sender,
+            published: AtomicU64::new(0),
+            publish_errors: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            apply_errors: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn ignores_own_messages() {
+        let config = AppConfig::default();
+
+        let a = invalidations(&config);
+        let b = invalidations(&config);
+
+        for x in [
+            Invalidation::Reorg {
+                number: 100.into(),
+                orphaned: vec![H256::repeat_byte(2)],
+            },
+            Invalidation::ResponseCache {
+                key: Some(42),
+                backend: None,
+            },
+            Invalidation::CallCache {
+                address: Address::repeat_byte(1),
+            },
+            Invalidation::User { user_id: 7 },
+        ] {
+            let message = a.message(x.clone());
+
+            assert_eq!(a.parse(&message).unwrap(), None);
+            assert_eq!(b.parse(&message).unwrap(), Some(x));
+        }
+
+        assert!(b.parse(r#"{"kind":"nope"}"#).is_err());
+
+        // from servers that don't send orphaned hashes
+        let message = r#"{"instance":"01HH0000000000000000000000","kind":"reorg","number":"0x64"}"#;
+        assert_eq!(
+            b.parse(message).unwrap(),
+            Some(Invalidation::Reorg {
+                number: 100.into(),
+                orphaned: vec![],
+            })
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Sampled revalidation of the jsonrpc response cache.
+//!
+//! A small fraction of cache hits are also fetched from a backend. Comparing the two tells us how often each method's
+//! cached values go stale and how old they were when they did. From that we can suggest a ttl for each method.
+
+use crate::response_cache::ForwardedResponse;
+use hashbrown::HashMap;
+use moka::future::{Cache, CacheBuilder};
+use nanorand::Rng;
+use parking_lot::Mutex;
+use serde_json::value::RawValue;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// don't suggest anything until a method has this many samples
+pub const MIN_SAMPLES_FOR_SUGGESTION: u64 = 20;
+
+/// suggested ttls aim to serve a stale value less than this often
+pub const TARGET_STALE_CHANCE: f64 = 0.01;
+
+/// Revalidation counts for one method
+#[derive(Clone, Debug, Default)]
+pub struct MethodRevalidationStats {
+    /// cache hits that were also fetched from a backend
+    pub samples: u64,
+    /// samples where the backend disagreed with the cache
+    pub changed: u64,
+    /// samples where the backend request failed. these are not counted in `samples`
+    pub errors: u64,
+    /// sum of the cached values' ages at the time they were compared
+    pub total_age: Duration,
+    /// the youngest cached value that was found to be stale
+    pub min_changed_age: Option<Duration>,
+    /// the oldest cached value that was found to still be correct
+    pub max_unchanged_age: Duration,
+}
+
+impl MethodRevalidationStats {
+    pub fn record(&mut self, age: Duration, changed: bool) {
+        self.samples += 1;
+        self.total_age += age;
+
+        if changed {
+            self.changed += 1;
+            self.min_changed_age = Some(self.min_changed_age.map_or(age, |x| x.min(age)));
+        } else {
+            self.max_unchanged_age = self.max_unchanged_age.max(age);
+        }
+    }
+
+    /// fraction of samples that were stale
+    pub fn change_rate(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.changed as f64 / self.samples as f64
+        }
+    }
+
+    /// Assume values go stale at a constant rate (changes per second of age) and pick the ttl that keeps the chance of
+    /// serving a stale value under `TARGET_STALE_CHANCE`.
+    /// If nothing has changed yet, the oldest value that we saw still be correct is a safe suggestion.
+    pub fn suggested_ttl(&self) -> Option<Duration> {
+        if self.samples < MIN_SAMPLES_FOR_SUGGESTION {
+            return None;
+        }
+
+        if self.changed == 0 {
+            return Some(self.max_unchanged_age);
+        }
+
+        let total_age_secs = self.total_age.as_secs_f64();
+
+        if total_age_secs <= 0.0 {
+            // values change before we can even cache them
+            return Some(Duration::ZERO);
+        }
+
+        let changes_per_sec = self.changed as f64 / total_age_secs;
+
+        let ttl_secs = -(1.0 - TARGET_STALE_CHANCE).ln() / changes_per_sec;
+
+        Some(Duration::from_secs_f64(ttl_secs))
+    }
+}
+
+/// Tracks when responses were cached and what happened when we revalidated them
+pub struct CacheRevalidation {
+    /// chance (out of u16::MAX) that a cache hit is revalidated
+    chance: u16,
+    /// when each cache key was last inserted. only tracked if revalidation is enabled
+    cached_at: Cache<u64, Instant>,
+    methods: Mutex<HashMap<String, MethodRevalidationStats>>,
+}
+
+impl CacheRevalidation {
+    pub fn new(chance: u16, max_keys: u64) -> Self {
+        // the response cache uses the same time to idle
+        let cached_at = CacheBuilder::new(max_keys)
+            .name("cache_revalidation_cached_at")
+            .time_to_idle(Duration::from_secs(3600))
+            .build();
+
+        Self {
+            chance,
+            cached_at,
+            methods: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.chance > 0
+    }
+
+    /// call this every time a response is inserted into the response cache
+    pub async fn cached(&self, cache_key: u64) {
+        if self.enabled() {
+            self.cached_at.insert(cache_key, Instant::now()).await;
+        }
+    }
+
+    /// roll the dice for a cache hit. returns how old the cached value is if it should be revalidated
+    pub async fn should_revalidate(&self, cache_key: u64) -> Option<Duration> {
+        if self.chance == 0 {
+            return None;
+        }
+
+        if self.chance != u16::MAX
+            && nanorand::tls_rng().generate_range(0u16..u16::MAX) >= self.chance
+        {
+            return None;
+        }
+
+        // if we don't know when it was cached (it was cached before revalidation was enabled), we can't learn from it
+        self.cached_at.get(&cache_key).await.map(|x| x.elapsed())
+    }
+
+    /// compare a cached value with what the backend just gave us
+    pub fn record(
+        &self,
+        method: &str,
+        age: Duration,
+        cached: &ForwardedResponse<Arc<RawValue>>,
+        fresh: &ForwardedResponse<Arc<RawValue>>,
+    ) {
+        let changed = !cached.canonical_eq(fresh);
+
+        self.methods
+            .lock()
+            .entry_ref(method)
+            .or_default()
+            .record(age, changed);
+    }
+
+    pub fn record_error(&self, method: &str) {
+        self.methods.lock().entry_ref(method).or_default().errors += 1;
+    }
+
+    /// per-method stats and suggested ttls
+    pub fn as_json(&self) -> serde_json::Value {
+        let methods = self.methods.lock();
+
+        let report: serde_json::Map<_, _> = methods
+            .iter()
+            .map(|(method, stats)| {
+                let x = serde_json::json!({
+                    "samples": stats.samples,
+                    "changed": stats.changed,
+                    "errors": stats.errors,
+                    "change_rate": stats.change_rate(),
+                    "mean_age_secs": stats.total_age.as_secs_f64() / stats.samples.max(1) as f64,
+                    "min_changed_age_secs": stats.min_changed_age.map(|x| x.as_secs_f64()),
+                    "max_unchanged_age_secs": stats.max_unchanged_age.as_secs_f64(),
+                    "suggested_ttl_secs": stats.suggested_ttl().map(|x| x.as_secs_f64()),
+                });
+
+                (method.clone(), x)
+            })
+            .collect();
+
+        serde_json::json!({
+            "chance": self.chance as f64 / u16::MAX as f64,
+            "min_samples_for_suggestion": MIN_SAMPLES_FOR_SUGGESTION,
+            "target_stale_chance": TARGET_STALE_CHANCE,
+            "methods": report,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(x: &str) -> ForwardedResponse<Arc<RawValue>> {
+        RawValue::from_string(x.to_string()).unwrap().into()
+    }
+
+    #[test]
+    fn canonical_comparison() {
+        assert!(raw(r#"{"a":1,"b":[1,2]}"#).canonical_eq(&raw(r#"{ "b": [1, 2], "a": 1 }"#)));
+        assert!(!raw(r#"{"a":1}"#).canonical_eq(&raw(r#"{"a":2}"#)));
+        assert!(!raw(r#"[1,2]"#).canonical_eq(&raw(r#"[2,1]"#)));
+    }
+
+    #[test]
+    fn suggested_ttls() {
+        let mut stats = MethodRevalidationStats::default();
+
+        for _ in 0..(MIN_SAMPLES_FOR_SUGGESTION - 1) {
+            stats.record(Duration::from_secs(10), false);
+        }
+
+        // not enough data yet
+        assert_eq!(stats.suggested_ttl(), None);
+
+        stats.record(Duration::from_secs(30), false);
+
+        // nothing has changed. the oldest correct value is safe
+        assert_eq!(stats.suggested_ttl(), Some(Duration::from_secs(30)));
+
+        // 1 change over 1000 seconds of age
+        let mut stats = MethodRevalidationStats::default();
+
+        for _ in 0..99 {
+            stats.record(Duration::from_secs(10), false);
+        }
+        stats.record(Duration::from_secs(10), true);
+
+        assert_eq!(stats.change_rate(), 0.01);
+
+        // -ln(0.99) * 1000 is just over 10 seconds
+        let ttl = stats.suggested_ttl().unwrap();
+        assert!(ttl > Duration::from_secs(10) && ttl < Duration::from_secs(11));
+    }
+}
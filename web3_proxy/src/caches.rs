@@ -1,14 +1,18 @@
 use crate::balance::Balance;
+use crate::cache_invalidation::Invalidation;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::authorization::AuthorizationChecks;
+use crate::globals::global_apps;
 use crate::secrets::RpcSecretKey;
 use derive_more::From;
 use entities::rpc_key;
 use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use moka::future::Cache;
+use moka::Expiry;
 use std::fmt;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock as AsyncRwLock;
 use tracing::trace;
 
@@ -16,6 +20,50 @@ use tracing::trace;
 /// TODO: try Ulid/u128 instead of RpcSecretKey in case my hash method is broken
 pub type RpcSecretKeyCache = Cache<RpcSecretKey, AuthorizationChecks>;
 
+/// Known keys are cached for `known`. Unknown keys are only cached for `unknown` so that a new key works quickly
+#[derive(Clone, Copy, Debug)]
+pub struct RpcSecretKeyExpiry {
+    pub known: Duration,
+    pub unknown: Duration,
+}
+
+impl RpcSecretKeyExpiry {
+    pub fn ttl(&self, checks: &AuthorizationChecks) -> Duration {
+        if checks.rpc_secret_key_id.is_some() {
+            self.known
+        } else {
+            self.unknown
+        }
+    }
+
+    /// known keys that were loaded longer ago than this are reloaded in the background
+    pub fn refresh_after(&self) -> Duration {
+        self.known * 3 / 4
+    }
+}
+
+impl Expiry<RpcSecretKey, AuthorizationChecks> for RpcSecretKeyExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &RpcSecretKey,
+        value: &AuthorizationChecks,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.ttl(value))
+    }
+
+    /// a refresh replaces the entry. it gets a full ttl for what it is now
+    fn expire_after_update(
+        &self,
+        _key: &RpcSecretKey,
+        value: &AuthorizationChecks,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.ttl(value))
+    }
+}
+
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
 pub struct RegisteredUserRateLimitKey(pub u64, pub IpAddr);
 
@@ -58,11 +106,38 @@ impl UserBalanceCache {
         Ok(x)
     }
 
+    /// Forget the user's balance and rpc keys on this server and tell the other servers to do the same
     pub async fn invalidate(
         &self,
         user_id: &u64,
         db_conn: &DatabaseConnection,
         rpc_secret_key_cache: &RpcSecretKeyCache,
+    ) -> Web3ProxyResult<()> {
+        let apps = global_apps();
+
+        for app in apps.iter() {
+            app.publish_invalidation(Invalidation::User { user_id: *user_id });
+        }
+
+        self.invalidate_local(user_id, db_conn, rpc_secret_key_cache)
+            .await?;
+
+        // every chain that this process serves has caches of its own. `self` is usually one of them. clearing it twice is fine
+        for app in apps {
+            app.user_balance_cache
+                .invalidate_local(user_id, db_conn, &app.rpc_secret_key_cache)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget the user's balance and rpc keys on this server only
+    pub async fn invalidate_local(
+        &self,
+        user_id: &u64,
+        db_conn: &DatabaseConnection,
+        rpc_secret_key_cache: &RpcSecretKeyCache,
     ) -> Web3ProxyResult<()> {
         self.0.invalidate(user_id).await;
 
@@ -80,9 +155,39 @@ impl UserBalanceCache {
 
             trace!(%user_id, %rpc_key_id, ?secret_key, "invalidating");
 
+            // a refresh that is already loading must not put the old checks back
+            for app in global_apps() {
+                app.rpc_secret_key_refreshing.lock().remove(&secret_key);
+            }
+
             rpc_secret_key_cache.invalidate(&secret_key).await;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn unknown_keys_expire_sooner() {
+        let expiry = RpcSecretKeyExpiry {
+            known: Duration::from_secs(60),
+            unknown: Duration::from_secs(5),
+        };
+
+        let unknown = AuthorizationChecks::default();
+
+        let known = AuthorizationChecks {
+            rpc_secret_key_id: NonZeroU64::new(1),
+            ..Default::default()
+        };
+
+        assert_eq!(expiry.ttl(&unknown), Duration::from_secs(5));
+        assert_eq!(expiry.ttl(&known), Duration::from_secs(60));
+        assert_eq!(expiry.refresh_after(), Duration::from_secs(45));
+    }
+}
@@ -0,0 +1,288 @@
+//! Longer caching for `eth_call`s to contracts that rarely change.
+//!
+//! Responses are normally cached by block, so a view call against "latest" misses the cache on every new head. Calls
+//! to the contracts listed in `call_cache` (token metadata, ENS resolvers, ...) are instead kept for that contract's
+//! ttl no matter how many blocks go by. When one of those contracts is upgraded, `DELETE /admin/call_cache/:address`
+//! removes everything cached for it.
+
+use crate::response_cache::ForwardedResponse;
+use ethers::types::Address;
+use hashbrown::HashMap;
+use moka::future::{Cache, CacheBuilder};
+use moka::Expiry;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// An `eth_call` that is cached by its target's ttl instead of by block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallCacheTarget {
+    pub to: Address,
+    pub ttl: Duration,
+    /// hash of the call without its block
+    pub key: u64,
+}
+
+/// The `to` address of an `eth_call`.
+///
+/// Accepts `[{"to": ..}, block]`, a bare `{"to": ..}`, and the legacy positional `[to, data, block]`.
+pub fn call_to(params: &Value) -> Option<Address> {
+    let call = match params {
+        Value::Array(x) => x.first()?,
+        x => x,
+    };
+
+    let to = match call {
+        Value::Object(x) => x.get("to")?,
+        x @ Value::String(_) => x,
+        _ => return None,
+    };
+
+    serde_json::from_value(to.clone()).ok()
+}
+
+/// Only calls against the latest block skip the normal per-block caching
+fn is_latest(block: Option<&Value>) -> bool {
+    match block {
+        None | Some(Value::Null) => true,
+        Some(Value::String(x)) => x == "latest",
+        _ => false,
+    }
+}
+
+/// Check the params of an `eth_call` against the configured ttls. This must run before the block in the params is
+/// replaced with a number.
+pub fn call_cache_target(params: &Value, ttls: &HashMap<Address, u64>) -> Option<CallCacheTarget> {
+    if ttls.is_empty() {
+        return None;
+    }
+
+    let to = call_to(params)?;
+
+    let ttl = *ttls.get(&to)?;
+
+    if ttl == 0 {
+        return None;
+    }
+
+    let mut hasher = DefaultHasher::new();
+
+    match params {
+        Value::Array(x) => {
+            // the positional form has the block after the data
+            let block_index = if matches!(x.first(), Some(Value::String(_))) {
+                2
+            } else {
+                1
+            };
+
+            if !is_latest(x.get(block_index)) {
+                return None;
+            }
+
+            // anything else (like state overrides) is part of the key
+            for (i, x) in x.iter().enumerate() {
+                if i != block_index {
+                    x.to_string().hash(&mut hasher);
+                }
+            }
+        }
+        x => x.to_string().hash(&mut hasher),
+    }
+
+    Some(CallCacheTarget {
+        to,
+        ttl: Duration::from_secs(ttl),
+        key: hasher.finish(),
+    })
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CachedCall {
+    to: Address,
+    ttl: Duration,
+    response: ForwardedResponse<Arc<RawValue>>,
+}
+
+/// every entry expires after its own target's ttl
+struct CallCacheExpiry;
+
+impl Expiry<u64, CachedCall> for CallCacheExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &u64,
+        value: &CachedCall,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &u64,
+        value: &CachedCall,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+pub struct CallCache {
+    cache: Cache<u64, CachedCall>,
+}
+
+impl CallCache {
+    pub fn new(max_bytes: u64) -> Self {
+        let cache = CacheBuilder::new(max_bytes)
+            .name("call_cache")
+            .expire_after(CallCacheExpiry)
+            .weigher(|_k, v: &CachedCall| v.response.num_bytes().try_into().unwrap_or(u32::MAX))
+            .build();
+
+        Self { cache }
+    }
+
+    pub async fn get(&self, target: &CallCacheTarget) -> Option<ForwardedResponse<Arc<RawValue>>> {
+        self.cache.get(&target.key).await.map(|x| x.response)
+    }
+
+    pub async fn insert(
+        &self,
+        target: &CallCacheTarget,
+        response: ForwardedResponse<Arc<RawValue>>,
+    ) {
+        self.cache
+            .insert(
+                target.key,
+                CachedCall {
+                    to: target.to,
+                    ttl: target.ttl,
+                    response,
+                },
+            )
+            .await;
+    }
+
+    /// Remove every cached call to `to`. Returns how many were removed.
+    pub async fn purge(&self, to: Address) -> u64 {
+        let keys: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|(_, v)| v.to == to)
+            .map(|(k, _)| *k)
+            .collect();
+
+        for key in keys.iter() {
+            self.cache.invalidate(key).await;
+        }
+
+        keys.len() as u64
+    }
+
+    pub(crate) fn cache(&self) -> &Cache<u64, CachedCall> {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TOKEN: &str = "0x6b175474e89094c44da98b954eedeac495271d0f";
+    const DATA: &str = "0x06fdde03";
+
+    fn ttls() -> HashMap<Address, u64> {
+        HashMap::from([(TOKEN.parse().unwrap(), 86_400)])
+    }
+
+    #[test]
+    fn params_forms() {
+        let token: Address = TOKEN.parse().unwrap();
+
+        assert_eq!(
+            call_to(&json!([{"to": TOKEN, "data": DATA}, "latest"])),
+            Some(token)
+        );
+        assert_eq!(call_to(&json!({"to": TOKEN, "data": DATA})), Some(token));
+        assert_eq!(call_to(&json!([TOKEN, DATA, "latest"])), Some(token));
+        assert_eq!(call_to(&json!([{"data": DATA}, "latest"])), None);
+        assert_eq!(call_to(&json!([])), None);
+        assert_eq!(call_to(&json!(null)), None);
+
+        let ttls = ttls();
+
+        for params in [
+            json!([{"to": TOKEN, "data": DATA}, "latest"]),
+            json!([{"to": TOKEN, "data": DATA}]),
+            json!({"to": TOKEN, "data": DATA}),
+            json!([TOKEN, DATA, "latest"]),
+            json!([TOKEN, DATA]),
+        ] {
+            let x = call_cache_target(&params, &ttls).unwrap();
+            assert_eq!(x.to, token);
+            assert_eq!(x.ttl, Duration::from_secs(86_400));
+        }
+
+        // specific blocks are already cached by block
+        assert_eq!(
+            call_cache_target(&json!([{"to": TOKEN, "data": DATA}, "0x10"]), &ttls),
+            None
+        );
+
+        // other contracts are not affected
+        assert_eq!(
+            call_cache_target(
+                &json!([{"to": "0x0000000000000000000000000000000000000001", "data": DATA}, "latest"]),
+                &ttls
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn key_ignores_latest() {
+        let ttls = ttls();
+
+        let a = call_cache_target(&json!([{"to": TOKEN, "data": DATA}, "latest"]), &ttls).unwrap();
+        let b = call_cache_target(&json!([{"to": TOKEN, "data": DATA}]), &ttls).unwrap();
+        assert_eq!(a.key, b.key);
+
+        let c = call_cache_target(&json!([{"to": TOKEN, "data": "0x95d89b41"}]), &ttls).unwrap();
+        assert_ne!(a.key, c.key);
+
+        // state overrides change the answer
+        let d = call_cache_target(
+            &json!([{"to": TOKEN, "data": DATA}, "latest", {TOKEN: {"code": "0x00"}}]),
+            &ttls,
+        )
+        .unwrap();
+        assert_ne!(a.key, d.key);
+    }
+
+    #[tokio::test]
+    async fn survives_new_heads_until_purged() {
+        let ttls = ttls();
+        let cache = CallCache::new(1_000_000);
+
+        let target =
+            call_cache_target(&json!([{"to": TOKEN, "data": DATA}, "latest"]), &ttls).unwrap();
+
+        cache.insert(&target, json!("0x01").into()).await;
+
+        // every new head rewrites "latest" to a new number after the target is found. the target doesn't change
+        for _ in 0..5 {
+            let again =
+                call_cache_target(&json!([{"to": TOKEN, "data": DATA}, "latest"]), &ttls).unwrap();
+            assert!(cache.get(&again).await.is_some());
+        }
+
+        assert_eq!(cache.purge(Address::zero()).await, 0);
+        assert_eq!(cache.purge(target.to).await, 1);
+        assert!(cache.get(&target).await.is_none());
+    }
+}
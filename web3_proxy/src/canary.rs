@@ -0,0 +1,448 @@
+//! An always-on self-check.
+//!
+//! Every `interval_secs`, a small battery of requests is sent through the whole proxy (caches, routing, rewrites) and
+//! straight to a reference backend that is not one of the balanced rpcs. The two answers are compared as canonical
+//! json. A mismatch usually means a poisoned cache or a routing bug. It is logged with both payloads and counted in
+//! the metrics, so we hear about it before our users do.
+//!
+//! The canary's requests are internal. They skip rate limits and are never saved as stats.
+
+use crate::app::App;
+use crate::errors::Web3ProxyError;
+use crate::frontend::authorization::Authorization;
+use crate::jsonrpc::{ParsedResponse, ResponsePayload};
+use crate::response_cache::ForwardedResponse;
+use derivative::Derivative;
+use ethers::types::U64;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, trace, warn};
+
+/// One request in the battery
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryCheck {
+    pub method: String,
+    /// Leave it out for methods without params
+    #[serde(default)]
+    pub params: Value,
+    /// For methods that return a block number. The two answers can be this many blocks apart
+    pub block_tolerance: Option<u64>,
+}
+
+impl CanaryCheck {
+    fn params(&self) -> Value {
+        match &self.params {
+            Value::Null => json!([]),
+            x => x.clone(),
+        }
+    }
+
+    /// true if the answers are the same. Whitespace and key order don't matter
+    pub fn matches(
+        &self,
+        proxy: &ForwardedResponse<Arc<RawValue>>,
+        reference: &ForwardedResponse<Arc<RawValue>>,
+    ) -> bool {
+        if let (
+            Some(tolerance),
+            ForwardedResponse::Result { value: a, .. },
+            ForwardedResponse::Result { value: b, .. },
+        ) = (self.block_tolerance, proxy, reference)
+        {
+            return match (
+                serde_json::from_str::<U64>(a.get()),
+                serde_json::from_str::<U64>(b.get()),
+            ) {
+                (Ok(a), Ok(b)) => a.max(b) - a.min(b) <= U64::from(tolerance),
+                _ => false,
+            };
+        }
+
+        proxy.canonical_eq(reference)
+    }
+}
+
+fn default_checks() -> Vec<CanaryCheck> {
+    vec![
+        CanaryCheck {
+            method: "eth_blockNumber".to_string(),
+            params: Value::Null,
+            block_tolerance: Some(2),
+        },
+        CanaryCheck {
+            method: "eth_chainId".to_string(),
+            params: Value::Null,
+            block_tolerance: None,
+        },
+    ]
+}
+
+/// The `[app.canary]` table. A few known requests are sent through the proxy and to a reference backend now and then,
+/// and answers that disagree are counted.
+#[serde_inline_default]
+#[derive(Clone, Derivative, Deserialize, PartialEq, Eq, Serialize)]
+#[derivative(Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// The backend to compare with. It should not be one of the balanced rpcs. Without it, the canary is off
+    #[derivative(Debug(format_with = "crate::config::redact_url"))]
+    pub reference_url: Option<String>,
+
+    /// How often (in seconds) the battery is sent. 0 turns the canary off.
+    #[serde_inline_default(60u64)]
+    pub interval_secs: u64,
+
+    /// The proxy answering this much slower than the reference is logged and counted
+    #[serde_inline_default(2_000u64)]
+    pub max_latency_divergence_ms: u64,
+
+    /// Payloads in the mismatch logs are cut to this many bytes
+    #[serde_inline_default(1_024usize)]
+    pub max_logged_bytes: usize,
+
+    /// A fixed historical eth_getBlockByHash and a known eth_call are good additions. The defaults work on any chain
+    #[serde_inline_default(default_checks())]
+    pub checks: Vec<CanaryCheck>,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+/// Totals since the server started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CanaryCounts {
+    pub runs: u64,
+    pub checks: u64,
+    pub mismatches: u64,
+    /// answers through the proxy that were more than `max_latency_divergence_ms` slower than the reference
+    pub slow: u64,
+    /// either side failed, so nothing could be compared
+    pub errors: u64,
+}
+
+/// The most recent mismatch. The payloads are truncated
+#[derive(Clone, Debug, Serialize)]
+pub struct CanaryMismatch {
+    pub method: String,
+    pub at: i64,
+    pub proxy: String,
+    pub reference: String,
+}
+
+pub struct Canary {
+    config: CanaryConfig,
+    reference_url: String,
+    client: reqwest::Client,
+    runs: AtomicU64,
+    checks: AtomicU64,
+    mismatches: AtomicU64,
+    slow: AtomicU64,
+    errors: AtomicU64,
+    last_mismatch: Mutex<Option<CanaryMismatch>>,
+}
+
+impl Canary {
+    /// None if there is no reference backend or no checks
+    pub fn new(config: &CanaryConfig, http_client: Option<reqwest::Client>) -> Option<Arc<Self>> {
+        let reference_url = config.reference_url.clone()?;
+
+        if config.interval_secs == 0 || config.checks.is_empty() {
+            return None;
+        }
+
+        let x = Self {
+            config: config.clone(),
+            reference_url,
+            client: http_client.unwrap_or_default(),
+            runs: Default::default(),
+            checks: Default::default(),
+            mismatches: Default::default(),
+            slow: Default::default(),
+            errors: Default::default(),
+            last_mismatch: Default::default(),
+        };
+
+        Some(Arc::new(x))
+    }
+
+    pub fn counts(&self) -> CanaryCounts {
+        CanaryCounts {
+            runs: self.runs.load(Ordering::Relaxed),
+            checks: self.checks.load(Ordering::Relaxed),
+            mismatches: self.mismatches.load(Ordering::Relaxed),
+            slow: self.slow.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Check the app until it is dropped
+    pub fn start(self: &Arc<Self>, app: Weak<App>) {
+        tokio::spawn(canary_loop(self.clone(), app));
+    }
+
+    fn truncated(&self, x: &ForwardedResponse<Arc<RawValue>>) -> String {
+        let mut x = match x {
+            ForwardedResponse::Result { value, .. } => value.get().to_string(),
+            ForwardedResponse::RpcError { error_data, .. } => {
+                json!({ "error": error_data }).to_string()
+            }
+        };
+
+        if x.len() > self.config.max_logged_bytes {
+            let mut end = self.config.max_logged_bytes;
+            while !x.is_char_boundary(end) {
+                end -= 1;
+            }
+            x.truncate(end);
+            x.push_str("...");
+        }
+
+        x
+    }
+
+    /// The request as the reference backend answers it
+    async fn reference_request(
+        &self,
+        check: &CanaryCheck,
+    ) -> anyhow::Result<ForwardedResponse<Arc<RawValue>>> {
+        let response: ParsedResponse<Arc<RawValue>> = self
+            .client
+            .post(&self.reference_url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": check.method,
+                "params": check.params(),
+            }))
+            .timeout(Duration::from_secs(self.config.interval_secs.clamp(1, 30)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.payload.into())
+    }
+
+    /// The request as our users would get it
+    async fn proxy_request(
+        &self,
+        app: &Arc<App>,
+        authorization: Arc<Authorization>,
+        check: &CanaryCheck,
+    ) -> anyhow::Result<ForwardedResponse<Arc<RawValue>>> {
+        let payload = match app
+            .authorized_request::<_, Arc<RawValue>>(
+                &check.method,
+                check.params(),
+                authorization,
+                None,
+            )
+            .await
+        {
+            Ok(result) => ResponsePayload::Success { result },
+            // jsonrpc errors are answers. the reference should give the same one
+            Err(Web3ProxyError::JsonRpcErrorData(error)) => ResponsePayload::Error { error },
+            Err(err) => return Err(anyhow::anyhow!("{:?}", err)),
+        };
+
+        Ok(payload.into())
+    }
+
+    async fn check(&self, app: &Arc<App>, authorization: Arc<Authorization>, check: &CanaryCheck) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+
+        let (proxy, reference) = tokio::join!(
+            async {
+                let start = Instant::now();
+                let x = self.proxy_request(app, authorization, check).await;
+                (x, start.elapsed())
+            },
+            async {
+                let start = Instant::now();
+                let x = self.reference_request(check).await;
+                (x, start.elapsed())
+            },
+        );
+
+        let ((proxy, proxy_latency), (reference, reference_latency)) = match (proxy, reference) {
+            ((Ok(a), a_latency), (Ok(b), b_latency)) => ((a, a_latency), (b, b_latency)),
+            ((proxy, _), (reference, _)) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    method = %check.method,
+                    proxy_err = ?proxy.err(),
+                    reference_err = ?reference.err(),
+                    "canary request failed"
+                );
+                return;
+            }
+        };
+
+        if !check.matches(&proxy, &reference) {
+            self.mismatches.fetch_add(1, Ordering::Relaxed);
+
+            let mismatch = CanaryMismatch {
+                method: check.method.clone(),
+                at: chrono::Utc::now().timestamp(),
+                proxy: self.truncated(&proxy),
+                reference: self.truncated(&reference),
+            };
+
+            warn!(
+                method = %mismatch.method,
+                proxy = %mismatch.proxy,
+                reference = %mismatch.reference,
+                "canary mismatch! the proxy and the reference disagree"
+            );
+
+            *self.last_mismatch.lock() = Some(mismatch);
+        }
+
+        let max_divergence = Duration::from_millis(self.config.max_latency_divergence_ms);
+
+        if proxy_latency.saturating_sub(reference_latency) > max_divergence {
+            self.slow.fetch_add(1, Ordering::Relaxed);
+
+            warn!(
+                method = %check.method,
+                proxy_ms = proxy_latency.as_millis() as u64,
+                reference_ms = reference_latency.as_millis() as u64,
+                "canary is much slower through the proxy than at the reference"
+            );
+        }
+    }
+}
+
+impl Serialize for Canary {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("Canary", 2)?;
+
+        s.serialize_field("counts", &self.counts())?;
+        s.serialize_field("last_mismatch", &*self.last_mismatch.lock())?;
+
+        s.end()
+    }
+}
+
+async fn canary_loop(canary: Arc<Canary>, app: Weak<App>) {
+    let mut canary_interval = interval(Duration::from_secs(canary.config.interval_secs));
+    canary_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let authorization = match Authorization::internal_without_stats() {
+        Ok(x) => Arc::new(x),
+        Err(err) => {
+            warn!(?err, "canary could not authorize itself");
+            return;
+        }
+    };
+
+    loop {
+        canary_interval.tick().await;
+
+        let Some(app) = app.upgrade() else {
+            break;
+        };
+
+        for check in canary.config.checks.iter() {
+            canary.check(&app, authorization.clone(), check).await;
+        }
+
+        canary.runs.fetch_add(1, Ordering::Relaxed);
+
+        debug!(
+            mismatches = canary.mismatches.load(Ordering::Relaxed),
+            "canary checked the proxy"
+        );
+    }
+
+    trace!("canary exited");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(x: &str) -> ForwardedResponse<Arc<RawValue>> {
+        ResponsePayload::<Arc<RawValue>>::Success {
+            result: RawValue::from_string(x.to_string()).unwrap().into(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn default_battery() {
+        let x = CanaryConfig::default();
+
+        assert_eq!(x.reference_url, None);
+        assert_eq!(x.interval_secs, 60);
+        assert_eq!(x.checks.len(), 2);
+        assert!(Canary::new(&x, None).is_none());
+
+        let x: CanaryConfig = serde_json::from_value(json!({
+            "reference_url": "https://reference.example.com/secret",
+            "checks": [{"method": "eth_getBlockByHash", "params": ["0xabc", false]}],
+        }))
+        .unwrap();
+
+        assert!(!format!("{:?}", x).contains("secret"));
+        assert!(Canary::new(&x, None).is_some());
+
+        assert!(serde_json::from_value::<CanaryConfig>(json!({
+            "checks": [{"method": "eth_chainId", "parms": []}],
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn comparisons() {
+        let exact = CanaryCheck {
+            method: "eth_getBlockByHash".to_string(),
+            params: json!(["0xabc", false]),
+            block_tolerance: None,
+        };
+
+        assert!(exact.matches(
+            &response(r#"{"a":1,"b":2}"#),
+            &response(r#"{ "b": 2, "a": 1 }"#)
+        ));
+        assert!(!exact.matches(&response(r#"{"a":1}"#), &response(r#"{"a":2}"#)));
+        assert!(!exact.matches(&response("null"), &response(r#"{"a":1}"#)));
+
+        let block_number = &default_checks()[0];
+
+        assert!(block_number.matches(&response(r#""0x10""#), &response(r#""0x12""#)));
+        assert!(block_number.matches(&response(r#""0x12""#), &response(r#""0x10""#)));
+        assert!(!block_number.matches(&response(r#""0x10""#), &response(r#""0x13""#)));
+        assert!(!block_number.matches(&response("null"), &response(r#""0x10""#)));
+    }
+
+    #[test]
+    fn mismatches_are_truncated() {
+        let x: CanaryConfig = serde_json::from_value(json!({
+            "reference_url": "http://localhost:8545",
+            "max_logged_bytes": 8,
+        }))
+        .unwrap();
+
+        let x = Canary::new(&x, None).unwrap();
+
+        assert_eq!(x.truncated(&response(r#""0x1234""#)), r#""0x1234""#);
+        assert_eq!(x.truncated(&response(r#""0x123456789""#)), r#""0x12345..."#);
+    }
+}
@@ -6,10 +6,13 @@
 //! TODO: pricing on compute units
 //! TODO: script that queries influx and calculates observed relative costs
 
+use crate::jsonrpc::ErrorClass;
 use migration::sea_orm::prelude::Decimal;
 use std::{ops::Add, ops::Mul, str::FromStr};
 use tracing::{trace, warn};
 
+pub use web3_proxy_client::types::CostEstimate;
+
 /// TODO: i don't like how we use this inside the config and also have it available publicly. we should only getting this value from the config
 pub fn default_usd_per_cu(chain_id: u64) -> Decimal {
     match chain_id {
@@ -58,6 +61,77 @@ where
 impl ComputeUnit {
     /// costs can vary widely depending on method and chain
     pub fn new(method: &str, chain_id: u64, response_bytes: u64) -> Self {
+        if let Some(x) = Self::try_new(method, chain_id, response_bytes) {
+            return x;
+        }
+
+        warn!(%response_bytes, "unknown method {}", method);
+        Self::unknown_method(chain_id, method, response_bytes)
+    }
+
+    /// `fixed_cu` is the configured price of a vendor method. It replaces the price from our cost table
+    pub fn for_request(
+        method: &str,
+        chain_id: u64,
+        fixed_cu: Option<u64>,
+        response_bytes: u64,
+    ) -> Self {
+        match fixed_cu {
+            Some(cu) => Self(cu.into()),
+            None => Self::new(method, chain_id, response_bytes),
+        }
+    }
+
+    /// the price for a method that isn't in our cost table
+    fn unknown_method(chain_id: u64, method: &str, response_bytes: u64) -> Self {
+        Self::unimplemented() + Self::variable_price(chain_id, method, response_bytes).0
+    }
+
+    /// what `cost` would return for a successful request, without knowing the response yet
+    pub fn estimate(
+        method: &str,
+        chain_id: u64,
+        fixed_cu: Option<u64>,
+        archive_request: bool,
+        cacheable: bool,
+        usd_per_cu: &Decimal,
+    ) -> CostEstimate {
+        let known_method = fixed_cu.is_some() || Self::is_known_method(method, chain_id);
+
+        let price = |response_bytes: u64, cache_hit: bool| {
+            fixed_cu
+                .map(|cu| Self(cu.into()))
+                .or_else(|| Self::try_new(method, chain_id, response_bytes))
+                .unwrap_or_else(|| Self::unknown_method(chain_id, method, response_bytes))
+                .cost(archive_request, cache_hit, ErrorClass::None, usd_per_cu)
+        };
+
+        let cache_miss = price(0, false);
+
+        let cache_hit = if cacheable {
+            price(0, true)
+        } else {
+            cache_miss
+        };
+
+        let per_response_byte = price(1, false) - cache_miss;
+
+        CostEstimate {
+            known_method,
+            archive_request,
+            cache_hit,
+            cache_miss,
+            per_response_byte,
+        }
+    }
+
+    /// true if the method is in our cost table. unknown methods get a default price
+    pub fn is_known_method(method: &str, chain_id: u64) -> bool {
+        Self::try_new(method, chain_id, 0).is_some()
+    }
+
+    /// None if the method is not in our cost table
+    fn try_new(method: &str, chain_id: u64, response_bytes: u64) -> Option<Self> {
         let cu = match (chain_id, method) {
             (1101, "zkevm_batchNumber") => 0,
             (1101, "zkevm_batchNumberByBlockNumber") => 0,
@@ -74,16 +148,16 @@ impl ComputeUnit {
             (137, "bor_getRootHash") => 10,
             (137, "bor_getSignersAtHash") => 10,
             (_, "debug_traceBlockByHash") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 497
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 497);
             }
             (_, "debug_traceBlockByNumber") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 497
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 497);
             }
             (_, "debug_traceCall") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 309
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 309);
             }
             (_, "debug_traceTransaction") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 309
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 309);
             }
             (_, "erigon_forks") => 24,
             (_, "erigon_getHeaderByHash") => 24,
@@ -91,14 +165,17 @@ impl ComputeUnit {
             (_, "erigon_getLogsByHash") => 24,
             (_, "erigon_issuance") => 24,
             (_, "eth_accounts") => 10,
+            (_, "eth_blobBaseFee") => 10,
             (_, "eth_blockNumber") => 10,
             (_, "eth_call") => 26,
+            (_, "eth_callMany") => 26 * 3,
             (_, "eth_chainId") => 0,
             (_, "eth_createAccessList") => 10,
             (_, "eth_estimateGas") => 87,
             (_, "eth_estimateUserOperationGas") => 500,
             (_, "eth_feeHistory") => 10,
             (_, "eth_gasPrice") => 19,
+            (_, "eth_getAccount") => 26,
             (_, "eth_getBalance") => 19,
             (_, "eth_getBlockByHash") => 21,
             (_, "eth_getBlockByNumber") => 16,
@@ -125,22 +202,27 @@ impl ComputeUnit {
             (_, "eth_maxPriorityFeePerGas") => 10,
             (_, "eth_newBlockFilter") => {
                 // TODO: 20
-                return Self::unimplemented();
+                return Some(Self::unimplemented());
             }
             (_, "eth_newFilter") => {
                 // TODO: 20
-                return Self::unimplemented();
+                return Some(Self::unimplemented());
             }
             (_, "eth_newPendingTransactionFilter") => {
                 // TODO: 20
-                return Self::unimplemented();
+                return Some(Self::unimplemented());
             }
             (_, "eth_pollSubscriptions") => {
-                return Self::unimplemented();
+                return Some(Self::unimplemented());
             }
             (_, "eth_protocolVersion") => 0,
             (_, "eth_sendRawTransaction") => 250,
+            (_, "eth_sendRawTransactionConditional") => 250,
             (_, "eth_sendUserOperation") => 1000,
+            (_, "eth_simulateV1") => {
+                // simulating multiple blocks of calls is as heavy as tracing them
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 497);
+            }
             (_, "eth_subscribe") => 10,
             (_, "eth_supportedEntryPoints") => 5,
             (_, "eth_syncing") => 0,
@@ -159,45 +241,45 @@ impl ComputeUnit {
             (_, "trace_replayTransaction") => 2983,
             (_, "trace_transaction") => 26,
             (_, "txpool_content") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 1000;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 1000);
             }
             (_, "invalid_method") => 100,
             (_, "web3_clientVersion") => 15,
             (_, "web3_bundlerVersion") => 15,
             (_, "web3_sha3") => 15,
             (_, "ots_getInternalOperations") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_hasCode") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_getTransactionError") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_traceTransaction") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_getBlockDetails") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_getBlockDetailsByHash") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_getBlockTransactions") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_searchTransactionsBefore") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_searchTransactionsAfter") => {
-                return Self::variable_price(chain_id, method, response_bytes) + 100;
+                return Some(Self::variable_price(chain_id, method, response_bytes) + 100);
             }
             (_, "ots_getTransactionBySenderAndNonce") => 1000,
             (_, "ots_getContractCreator") => 1000,
             (_, method) => {
                 // TODO: this works, but this is fragile. think of a better way to check the method is a subscription
                 if method.ends_with(')') {
-                    return Self::variable_price(chain_id, method, response_bytes);
+                    return Some(Self::variable_price(chain_id, method, response_bytes));
                 }
 
                 if method.starts_with("admin_")
@@ -205,7 +287,7 @@ impl ComputeUnit {
                     || method == "personal_unlockAccount"
                 {
                     // charge extra since they are doing things they aren't supposed to
-                    return Self::unimplemented() * 10;
+                    return Some(Self::unimplemented() * 10);
                 }
 
                 if method.starts_with("alchemy_")
@@ -214,12 +296,10 @@ impl ComputeUnit {
                     || method.starts_with("db_")
                 {
                     // maybe charge extra since they are doing things they aren't supposed to
-                    return Self::unimplemented();
+                    return Some(Self::unimplemented());
                 }
 
-                warn!(%response_bytes, "unknown method {}", method);
-                return Self::unimplemented()
-                    + Self::variable_price(chain_id, method, response_bytes).0;
+                return None;
             }
         };
 
@@ -227,7 +307,7 @@ impl ComputeUnit {
 
         trace!(%cu);
 
-        Self(cu)
+        Some(Self(cu))
     }
 
     /// notifications and subscription responses cost per-byte
@@ -253,11 +333,12 @@ impl ComputeUnit {
         &self,
         archive_request: bool,
         cache_hit: bool,
-        error_response: bool,
+        error_class: ErrorClass,
         usd_per_cu: &Decimal,
     ) -> Decimal {
-        if error_response {
-            trace!("error responses are free");
+        if error_class.is_app_error() {
+            // the user still pays for invalid requests and jsonrpc errors
+            trace!(?error_class, "our errors are free");
             return 0.into();
         }
 
@@ -285,3 +366,117 @@ impl ComputeUnit {
         cost
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ComputeUnit, CostEstimate, ErrorClass};
+    use migration::sea_orm::prelude::Decimal;
+
+    #[test]
+    fn newer_methods_are_known() {
+        for method in [
+            "eth_blobBaseFee",
+            "eth_callMany",
+            "eth_createAccessList",
+            "eth_feeHistory",
+            "eth_getAccount",
+            "eth_getBlockReceipts",
+            "eth_maxPriorityFeePerGas",
+            "eth_sendRawTransactionConditional",
+            "eth_simulateV1",
+        ] {
+            assert!(ComputeUnit::is_known_method(method, 1), "{}", method);
+        }
+
+        assert!(!ComputeUnit::is_known_method("eth_somethingNew", 1));
+    }
+
+    #[test]
+    fn simulate_costs_like_a_trace() {
+        let simulate = ComputeUnit::new("eth_simulateV1", 1, 1_000);
+        let trace = ComputeUnit::new("debug_traceBlockByNumber", 1, 1_000);
+        let call = ComputeUnit::new("eth_call", 1, 1_000);
+
+        assert_eq!(simulate.0, trace.0);
+        assert!(simulate.0 > call.0);
+    }
+
+    #[test]
+    fn archive_and_error_costs() {
+        let usd_per_cu: Decimal = "0.10".parse().unwrap();
+
+        // 10 CU
+        let cu = ComputeUnit::new("eth_blockNumber", 1, 100);
+        assert_eq!(cu.0, 10.into());
+
+        let base = cu.cost(false, false, ErrorClass::None, &usd_per_cu);
+        assert_eq!(base, 1.into());
+
+        let archive = cu.cost(true, false, ErrorClass::None, &usd_per_cu);
+        assert_eq!(archive, "2.5".parse().unwrap());
+
+        let cached_archive = cu.cost(true, true, ErrorClass::None, &usd_per_cu);
+        assert_eq!(cached_archive, "1.875".parse().unwrap());
+
+        // reverts and bad requests are the user's fault
+        assert_eq!(cu.cost(false, false, ErrorClass::User, &usd_per_cu), base);
+
+        // backend and proxy faults are ours
+        assert_eq!(
+            cu.cost(true, false, ErrorClass::Backend, &usd_per_cu),
+            0.into()
+        );
+        assert_eq!(
+            cu.cost(false, false, ErrorClass::Proxy, &usd_per_cu),
+            0.into()
+        );
+    }
+
+    #[test]
+    fn estimate_matches_cost() {
+        let usd_per_cu: Decimal = "0.10".parse().unwrap();
+
+        let estimate = ComputeUnit::estimate("eth_getBalance", 1, None, true, true, &usd_per_cu);
+
+        let cu = ComputeUnit::new("eth_getBalance", 1, 1_000);
+
+        assert_eq!(
+            estimate,
+            CostEstimate {
+                known_method: true,
+                archive_request: true,
+                cache_hit: cu.cost(true, true, ErrorClass::None, &usd_per_cu),
+                cache_miss: cu.cost(true, false, ErrorClass::None, &usd_per_cu),
+                per_response_byte: 0.into(),
+            }
+        );
+
+        // uncacheable requests always pay full price
+        let estimate =
+            ComputeUnit::estimate("eth_sendRawTransaction", 1, None, false, false, &usd_per_cu);
+        assert_eq!(estimate.cache_hit, estimate.cache_miss);
+
+        // traces are priced by size
+        let estimate =
+            ComputeUnit::estimate("debug_traceTransaction", 1, None, false, true, &usd_per_cu);
+        let cu = ComputeUnit::new("debug_traceTransaction", 1, 1_000);
+        assert_eq!(
+            estimate.cache_miss + estimate.per_response_byte * Decimal::from(1_000),
+            cu.cost(false, false, ErrorClass::None, &usd_per_cu)
+        );
+
+        let estimate = ComputeUnit::estimate("eth_somethingNew", 1, None, false, true, &usd_per_cu);
+        assert!(!estimate.known_method);
+        assert_eq!(
+            estimate.cache_miss,
+            ComputeUnit::unimplemented().0 * usd_per_cu
+        );
+
+        // vendor methods can be given a price
+        let estimate =
+            ComputeUnit::estimate("ots_getApiLevel", 1, Some(50), false, false, &usd_per_cu);
+        assert!(estimate.known_method);
+        assert_eq!(estimate.cache_miss, Decimal::from(50) * usd_per_cu);
+        assert_eq!(estimate.per_response_byte, Decimal::ZERO);
+    }
+}
@@ -0,0 +1,289 @@
+//! Applying config changes without hammering the backends.
+//!
+//! Every applied config respawns the rpc connections. A config file that is rewritten in a loop would otherwise have
+//! us reconnect to every backend every few seconds. Applied reloads are kept at least a minimum interval apart, and
+//! versions that arrive in between are skipped in favor of the latest one. If a new config can't connect to any of its
+//! backends, the last config that worked is applied again and the new one is shown as rejected in `/status`.
+
+use crate::errors::Web3ProxyResult;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::select;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{error, info};
+
+/// How long to wait before trying again when there is no working config to roll back to
+pub const RETRY_WITHOUT_ROLLBACK: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RejectedConfig {
+    pub timestamp: DateTime<Utc>,
+    pub error: String,
+}
+
+/// Counters for config reloads
+#[derive(Debug, Default)]
+pub struct ConfigReloads {
+    /// configs that we tried to apply. skipped intermediate versions are not counted
+    pub attempts: AtomicU64,
+    /// configs that applied without errors
+    pub applied: AtomicU64,
+    /// configs that failed to apply
+    pub rejected: AtomicU64,
+    /// times that the last working config was applied again after a new one was rejected
+    pub rollbacks: AtomicU64,
+    /// the most recently rejected config. cleared once a new config applies
+    last_rejected: Mutex<Option<RejectedConfig>>,
+}
+
+impl ConfigReloads {
+    pub fn last_rejected(&self) -> Option<RejectedConfig> {
+        self.last_rejected.lock().clone()
+    }
+
+    /// Apply every config sent to `receiver` until shutdown. The current value is applied immediately.
+    ///
+    /// `min_interval` is read from each config as it is applied. `apply` returns an error if the config should be
+    /// rejected.
+    pub async fn watch<T, F, Fut>(
+        &self,
+        mut receiver: watch::Receiver<T>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+        min_interval: impl Fn(&T) -> Duration,
+        mut apply: F,
+    ) where
+        T: Clone,
+        F: FnMut(T) -> Fut,
+        Fut: Future<Output = Web3ProxyResult<()>>,
+    {
+        let mut last_good: Option<T> = None;
+        let mut next_allowed: Option<Instant> = None;
+
+        loop {
+            if let Some(next_allowed) = next_allowed {
+                // any versions sent while we wait are coalesced by the watch channel. only the latest is applied
+                select! {
+                    _ = shutdown_receiver.recv() => break,
+                    _ = sleep_until(next_allowed) => {}
+                }
+            }
+
+            let new_config = receiver.borrow_and_update().clone();
+
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+
+            next_allowed = Some(Instant::now() + min_interval(&new_config));
+
+            match apply(new_config.clone()).await {
+                Ok(()) => {
+                    self.applied.fetch_add(1, Ordering::Relaxed);
+
+                    *self.last_rejected.lock() = None;
+
+                    last_good = Some(new_config);
+                }
+                Err(err) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+
+                    *self.last_rejected.lock() = Some(RejectedConfig {
+                        timestamp: Utc::now(),
+                        error: err.to_string(),
+                    });
+
+                    if let Some(last_good) = last_good.clone() {
+                        error!(
+                            ?err,
+                            "new config was rejected. rolling back to the last working config"
+                        );
+
+                        match apply(last_good).await {
+                            Ok(()) => {
+                                info!("rolled back config");
+                                self.rollbacks.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(err) => {
+                                error!(?err, "unable to roll back config");
+                            }
+                        }
+                    } else {
+                        error!(?err, "unable to apply config! Retrying in 10 seconds (or if the config changes)");
+
+                        select! {
+                            _ = shutdown_receiver.recv() => break,
+                            _ = sleep(RETRY_WITHOUT_ROLLBACK) => continue,
+                            x = receiver.changed() => {
+                                if x.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // wait for configs to change or for the app to exit
+            select! {
+                _ = shutdown_receiver.recv() => break,
+                x = receiver.changed() => {
+                    if x.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for ConfigReloads {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ConfigReloads", 5)?;
+
+        state.serialize_field("attempts", &self.attempts.load(Ordering::Relaxed))?;
+        state.serialize_field("applied", &self.applied.load(Ordering::Relaxed))?;
+        state.serialize_field("rejected", &self.rejected.load(Ordering::Relaxed))?;
+        state.serialize_field("rollbacks", &self.rollbacks.load(Ordering::Relaxed))?;
+        state.serialize_field("last_rejected", &self.last_rejected())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Web3ProxyError;
+    use std::sync::Arc;
+
+    /// a config is just a version number and whether its backends are reachable
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct FakeConfig {
+        version: u64,
+        reachable: bool,
+    }
+
+    fn spawn_watch(
+        initial: FakeConfig,
+    ) -> (
+        Arc<ConfigReloads>,
+        Arc<Mutex<Vec<u64>>>,
+        watch::Sender<FakeConfig>,
+        broadcast::Sender<()>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let reloads = Arc::new(ConfigReloads::default());
+        let applied = Arc::new(Mutex::new(vec![]));
+        let (config_sender, config_receiver) = watch::channel(initial);
+        let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        let handle = {
+            let reloads = reloads.clone();
+            let applied = applied.clone();
+
+            tokio::spawn(async move {
+                reloads
+                    .watch(
+                        config_receiver,
+                        shutdown_receiver,
+                        |_| Duration::from_secs(10),
+                        |x: FakeConfig| {
+                            let applied = applied.clone();
+                            async move {
+                                if !x.reachable {
+                                    return Err(Web3ProxyError::NoServersSynced);
+                                }
+                                applied.lock().push(x.version);
+                                Ok(())
+                            }
+                        },
+                    )
+                    .await
+            })
+        };
+
+        (reloads, applied, config_sender, shutdown_sender, handle)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_rewrites_are_coalesced() {
+        let (reloads, applied, config_sender, shutdown_sender, handle) = spawn_watch(FakeConfig {
+            version: 0,
+            reachable: true,
+        });
+
+        // a rewrite every 2 seconds for a minute
+        for version in 1..=30 {
+            sleep(Duration::from_secs(2)).await;
+            config_sender.send_replace(FakeConfig {
+                version,
+                reachable: true,
+            });
+        }
+
+        sleep(Duration::from_secs(20)).await;
+
+        let applied = applied.lock().clone();
+
+        // at most one reload per 10 seconds, and the last version always wins
+        assert!(applied.len() <= 8, "{:?}", applied);
+        assert_eq!(applied.first(), Some(&0));
+        assert_eq!(applied.last(), Some(&30));
+        assert_eq!(
+            reloads.attempts.load(Ordering::Relaxed),
+            applied.len() as u64
+        );
+        assert_eq!(reloads.rejected.load(Ordering::Relaxed), 0);
+
+        shutdown_sender.send(()).unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unreachable_backends_roll_back() {
+        let (reloads, applied, config_sender, shutdown_sender, handle) = spawn_watch(FakeConfig {
+            version: 0,
+            reachable: true,
+        });
+
+        sleep(Duration::from_secs(1)).await;
+
+        config_sender.send_replace(FakeConfig {
+            version: 1,
+            reachable: false,
+        });
+
+        sleep(Duration::from_secs(20)).await;
+
+        // the working config was applied again
+        assert_eq!(*applied.lock(), vec![0, 0]);
+        assert_eq!(reloads.rejected.load(Ordering::Relaxed), 1);
+        assert_eq!(reloads.rollbacks.load(Ordering::Relaxed), 1);
+        assert!(reloads.last_rejected().is_some());
+
+        let status = serde_json::to_value(&*reloads).unwrap();
+        assert_eq!(status["rollbacks"], 1);
+        assert!(status["last_rejected"]["error"].is_string());
+
+        // a fixed config clears the rejection
+        config_sender.send_replace(FakeConfig {
+            version: 2,
+            reachable: true,
+        });
+
+        sleep(Duration::from_secs(20)).await;
+
+        assert_eq!(*applied.lock(), vec![0, 0, 2]);
+        assert!(reloads.last_rejected().is_none());
+
+        shutdown_sender.send(()).unwrap();
+        handle.await.unwrap();
+    }
+}
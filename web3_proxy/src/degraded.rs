@@ -0,0 +1,473 @@
+//! Keep serving while the database is down.
+//!
+//! The app starts even if the database is unreachable. Anonymous traffic never needs the database, so it is served like
+//! always. Keyed requests that aren't in the `rpc_secret_key_cache` follow `degraded_auth`. A background task keeps
+//! trying to connect and leaves degraded mode on its own. Nothing needs a restart.
+//!
+//! The "snapshot" policy remembers the models behind every key that was checked while the database was up. They are
+//! saved to `degraded_auth_snapshot_path` so that a server that starts during an outage can still authorize them.
+
+use crate::app::App;
+use crate::balance::Balance;
+use crate::config::{AppConfig, TopConfig};
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::authorization::AuthorizationChecks;
+use crate::frontend::rpc_proxy_ws::ProxyMode;
+use crate::globals::{DatabaseError, DB_CONN};
+use crate::secrets::RpcSecretKey;
+use entities::{rpc_key, user_tier};
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize, Serializer};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{watch, RwLock as AsyncRwLock};
+use tokio::time::sleep;
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+/// What happens to keyed requests while the database is down
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DegradedAuth {
+    /// limit them like anonymous requests
+    #[default]
+    IpLimits,
+    /// answer "auth unavailable"
+    Reject,
+    /// allow the keys in the snapshot. other keys get "auth unavailable"
+    Snapshot,
+}
+
+/// Everything needed to rebuild a key's `AuthorizationChecks` without the database
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SnapshotKey {
+    pub rpc_key: rpc_key::Model,
+    /// the tier after any downgrade
+    pub user_tier: user_tier::Model,
+    pub paid_credits_used: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DegradedCounts {
+    /// 1 while the database is down
+    pub db_down: u64,
+    pub reconnects: u64,
+    pub reconnect_errors: u64,
+    pub rejected_keys: u64,
+    pub snapshot_keys: u64,
+    pub snapshot_keys_allowed: u64,
+}
+
+pub struct Degraded {
+    policy: DegradedAuth,
+    reconnect_interval: Duration,
+    snapshot_path: Option<PathBuf>,
+    snapshot: RwLock<HashMap<Uuid, SnapshotKey>>,
+    /// the snapshot changed since it was last saved
+    snapshot_changed: AtomicBool,
+    reconnects: AtomicU64,
+    reconnect_errors: AtomicU64,
+    rejected_keys: AtomicU64,
+    snapshot_keys_allowed: AtomicU64,
+}
+
+impl Degraded {
+    pub fn new(config: &AppConfig) -> Arc<Self> {
+        let snapshot = match (
+            config.degraded_auth,
+            config.degraded_auth_snapshot_path.as_ref(),
+        ) {
+            (DegradedAuth::Snapshot, Some(path)) => load_snapshot(path),
+            _ => Default::default(),
+        };
+
+        let x = Self {
+            policy: config.degraded_auth,
+            // zero would spin
+            reconnect_interval: Duration::from_secs(config.db_reconnect_secs.max(1)),
+            snapshot_path: config.degraded_auth_snapshot_path.clone(),
+            snapshot: RwLock::new(snapshot),
+            snapshot_changed: Default::default(),
+            reconnects: Default::default(),
+            reconnect_errors: Default::default(),
+            rejected_keys: Default::default(),
+            snapshot_keys_allowed: Default::default(),
+        };
+
+        Arc::new(x)
+    }
+
+    /// true if a database is configured but couldn't be connected to
+    pub fn is_on(&self) -> bool {
+        matches!(*DB_CONN.read(), Err(DatabaseError::Connect(_)))
+    }
+
+    pub fn policy(&self) -> DegradedAuth {
+        self.policy
+    }
+
+    /// Remember a key that was just checked against the database. Only the "snapshot" policy keeps anything
+    pub fn record(
+        &self,
+        rpc_key: &rpc_key::Model,
+        user_tier: &user_tier::Model,
+        paid_credits_used: bool,
+    ) {
+        if self.policy != DegradedAuth::Snapshot {
+            return;
+        }
+
+        let x = SnapshotKey {
+            rpc_key: rpc_key.clone(),
+            user_tier: user_tier.clone(),
+            paid_credits_used,
+        };
+
+        let old = self.snapshot.write().insert(rpc_key.secret_key, x.clone());
+
+        if old.as_ref() != Some(&x) {
+            self.snapshot_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Forget a key that the database no longer has. Deactivated keys stop working during the next outage
+    pub fn forget(&self, rpc_secret_key: &RpcSecretKey) {
+        if self.policy != DegradedAuth::Snapshot {
+            return;
+        }
+
+        if self
+            .snapshot
+            .write()
+            .remove(&Uuid::from(*rpc_secret_key))
+            .is_some()
+        {
+            self.snapshot_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Checks for a key while the database is down. None means the key should get ip limits
+    pub fn authorization_checks(
+        &self,
+        proxy_mode: ProxyMode,
+        rpc_secret_key: &RpcSecretKey,
+    ) -> Web3ProxyResult<Option<AuthorizationChecks>> {
+        let x = match self.policy {
+            DegradedAuth::IpLimits => return Ok(None),
+            DegradedAuth::Reject => None,
+            DegradedAuth::Snapshot => self
+                .snapshot
+                .read()
+                .get(&Uuid::from(*rpc_secret_key))
+                .cloned(),
+        };
+
+        let Some(x) = x else {
+            self.rejected_keys.fetch_add(1, Ordering::Relaxed);
+            return Err(Web3ProxyError::AuthUnavailable);
+        };
+
+        self.snapshot_keys_allowed.fetch_add(1, Ordering::Relaxed);
+
+        // the real balance is in the database. this one is only updated by stats until the database is back
+        let latest_balance = Balance {
+            user_id: x.rpc_key.user_id,
+            user_tier_id: x.user_tier.id,
+            downgrade_tier_id: x.user_tier.downgrade_tier_id,
+            ..Default::default()
+        };
+
+        AuthorizationChecks::try_from_models(
+            x.rpc_key,
+            x.user_tier,
+            Arc::new(AsyncRwLock::new(latest_balance)),
+            x.paid_credits_used,
+            proxy_mode,
+            rpc_secret_key,
+        )
+        .map(Some)
+    }
+
+    pub fn counts(&self) -> DegradedCounts {
+        DegradedCounts {
+            db_down: self.is_on() as u64,
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            reconnect_errors: self.reconnect_errors.load(Ordering::Relaxed),
+            rejected_keys: self.rejected_keys.load(Ordering::Relaxed),
+            snapshot_keys: self.snapshot.read().len() as u64,
+            snapshot_keys_allowed: self.snapshot_keys_allowed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reconnect to the database and save the snapshot until the app is dropped.
+    /// The latest config is used so that a reloaded `db_url` is picked up
+    pub fn start(self: &Arc<Self>, app: Weak<App>, top_config: watch::Receiver<TopConfig>) {
+        tokio::spawn(reconnect_loop(self.clone(), app, top_config));
+    }
+
+    async fn save_snapshot(&self) {
+        let Some(path) = self.snapshot_path.as_ref() else {
+            return;
+        };
+
+        if !self.snapshot_changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let x: Vec<SnapshotKey> = self.snapshot.read().values().cloned().collect();
+
+        let x = match serde_json::to_vec(&x) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, "unable to serialize the auth snapshot");
+                return;
+            }
+        };
+
+        // write somewhere else first so that a crash never leaves half a snapshot
+        let tmp_path = path.with_extension("tmp");
+
+        if let Err(err) = write_private(&tmp_path, &x).await {
+            warn!(?err, path=%tmp_path.display(), "unable to save the auth snapshot");
+            self.snapshot_changed.store(true, Ordering::Relaxed);
+            return;
+        }
+
+        if let Err(err) = tokio::fs::rename(&tmp_path, path).await {
+            warn!(?err, path=%path.display(), "unable to save the auth snapshot");
+            self.snapshot_changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The snapshot has rpc secret keys in it, so only our user can read it
+async fn write_private(path: &Path, x: &[u8]) -> std::io::Result<()> {
+    // the mode only applies to new files. a leftover from a crash might have other permissions
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut f = options.open(path).await?;
+
+    f.write_all(x).await?;
+
+    f.sync_all().await
+}
+
+impl Serialize for Degraded {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct DegradedStatus {
+            policy: DegradedAuth,
+            #[serde(flatten)]
+            counts: DegradedCounts,
+        }
+
+        DegradedStatus {
+            policy: self.policy,
+            counts: self.counts(),
+        }
+        .serialize(serializer)
+    }
+}
+
+fn load_snapshot(path: &Path) -> HashMap<Uuid, SnapshotKey> {
+    let x = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Default::default(),
+        Err(err) => {
+            warn!(?err, path=%path.display(), "unable to read the auth snapshot");
+            return Default::default();
+        }
+    };
+
+    match serde_json::from_slice::<Vec<SnapshotKey>>(&x) {
+        Ok(x) => {
+            info!(keys = x.len(), "loaded the auth snapshot");
+
+            x.into_iter().map(|x| (x.rpc_key.secret_key, x)).collect()
+        }
+        Err(err) => {
+            warn!(?err, path=%path.display(), "unable to parse the auth snapshot");
+            Default::default()
+        }
+    }
+}
+
+async fn reconnect_loop(
+    degraded: Arc<Degraded>,
+    app: Weak<App>,
+    top_config: watch::Receiver<TopConfig>,
+) {
+    loop {
+        sleep(degraded.reconnect_interval).await;
+
+        let Some(app) = app.upgrade() else {
+            break;
+        };
+
+        if !degraded.is_on() {
+            // only save while the database is up. a snapshot from during an outage has nothing new in it
+            degraded.save_snapshot().await;
+            continue;
+        }
+
+        let new_top_config = top_config.borrow().clone();
+
+        match app.apply_top_config_db(&new_top_config).await {
+            Ok(()) => {
+                degraded.reconnects.fetch_add(1, Ordering::Relaxed);
+
+                info!("database is back. leaving degraded mode");
+            }
+            Err(err) => {
+                degraded.reconnect_errors.fetch_add(1, Ordering::Relaxed);
+
+                debug!(?err, "database is still down");
+            }
+        }
+    }
+
+    trace!("database reconnect loop exited");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot_key(id: u64) -> SnapshotKey {
+        serde_json::from_value(json!({
+            "rpc_key": {
+                "id": id,
+                "user_id": 7,
+                "secret_key": "01H0000000000000000000000A",
+                "description": null,
+                "private_txs": false,
+                "active": true,
+                "allowed_ips": null,
+                "allowed_origins": null,
+                "allowed_referers": null,
+                "allowed_user_agents": null,
+                "log_revert_chance": 0.0,
+                "sign_responses": false,
+                "require_jsonrpc_2": false,
+                "allow_batches": true,
+                "allow_websocket": true,
+                "skip_chain_id_check": false,
+                "skip_request_coalescing": false,
+                "label": "prod",
+            },
+            "user_tier": {
+                "id": 2,
+                "title": "Premium",
+                "max_requests_per_period": 100,
+                "max_concurrent_requests": null,
+                "downgrade_tier_id": 1,
+                "allow_archive": true,
+                "allow_trace": false,
+                "allow_skip_request_coalescing": false,
+            },
+            "paid_credits_used": true,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_restart() {
+        let path = std::env::temp_dir().join(format!("auth_snapshot_{}.json", ulid::Ulid::new()));
+
+        let config: AppConfig = serde_json::from_value(json!({
+            "chain_id": 1,
+            "degraded_auth": "snapshot",
+            "degraded_auth_snapshot_path": path,
+        }))
+        .unwrap();
+
+        let x = snapshot_key(3);
+        let rpc_secret_key = RpcSecretKey::Uuid(x.rpc_key.secret_key);
+
+        let degraded = Degraded::new(&config);
+        degraded.record(&x.rpc_key, &x.user_tier, x.paid_credits_used);
+        degraded.save_snapshot().await;
+
+        // it has secret keys in it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // a new server reads the snapshot
+        let degraded = Degraded::new(&config);
+        assert_eq!(degraded.counts().snapshot_keys, 1);
+
+        let checks = degraded
+            .authorization_checks(ProxyMode::Best, &rpc_secret_key)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(checks.rpc_secret_key_id.unwrap().get(), 3);
+        assert_eq!(checks.user_id, 7);
+        assert_eq!(checks.max_requests_per_period, Some(100));
+        assert_eq!(checks.user_tier_title.as_deref(), Some("Premium"));
+        assert!(!checks.entitlements.allow_trace);
+        assert!(checks.paid_credits_used);
+
+        // keys the database stopped returning are forgotten
+        degraded.forget(&rpc_secret_key);
+        assert!(matches!(
+            degraded.authorization_checks(ProxyMode::Best, &rpc_secret_key),
+            Err(Web3ProxyError::AuthUnavailable)
+        ));
+        assert_eq!(degraded.counts().rejected_keys, 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn policies() {
+        let x = snapshot_key(3);
+        let rpc_secret_key = RpcSecretKey::Uuid(x.rpc_key.secret_key);
+
+        // ip limits is the default and never remembers anything
+        let degraded = Degraded::new(&AppConfig::default());
+        assert_eq!(degraded.policy(), DegradedAuth::IpLimits);
+        degraded.record(&x.rpc_key, &x.user_tier, x.paid_credits_used);
+        assert_eq!(degraded.counts().snapshot_keys, 0);
+        assert!(degraded
+            .authorization_checks(ProxyMode::Best, &rpc_secret_key)
+            .unwrap()
+            .is_none());
+
+        let config: AppConfig = serde_json::from_value(json!({
+            "chain_id": 1,
+            "degraded_auth": "reject",
+        }))
+        .unwrap();
+
+        let degraded = Degraded::new(&config);
+        assert!(matches!(
+            degraded.authorization_checks(ProxyMode::Best, &rpc_secret_key),
+            Err(Web3ProxyError::AuthUnavailable)
+        ));
+        assert_eq!(degraded.counts().rejected_keys, 1);
+    }
+}
@@ -0,0 +1,805 @@
+//! Responses that can never change, saved to disk so that a restart doesn't start with an empty cache.
+//!
+//! Only successful responses are saved, and only when they can't change:
+//! - requests the response cache keeps forever, like blocks by hash
+//! - requests for a block past the reorg horizon (`archive_cache_confirmations`), like eth_getCode at a fixed block
+//! - transactions and receipts by hash, once the block they were mined in is past the reorg horizon. the response cache
+//!   never keeps these because they are null until the transaction is mined
+//!
+//! Each response is saved under a hash of the request. The response cache's keys are seeded per process, so they can't
+//! be used here. `disk_cache_store` picks one file per response or an embedded sled database. Sled needs the "sled"
+//! feature.
+//!
+//! The disk is only read after a response cache miss. A missing or unreadable response is treated like any other miss.
+//! Writes are queued for a background task and dropped if the queue is full, so they never slow down a response.
+//! Once the responses take up more than `disk_cache_max_bytes`, the oldest are removed.
+//!
+//! The store remembers its chain id. Starting with a different `chain_id` is an error, so blocks from another chain are
+//! never served.
+
+use crate::app::Web3ProxyJoinHandle;
+use crate::block_number::CacheMode;
+use crate::config::AppConfig;
+use crate::errors::Web3ProxyResult;
+use crate::jsonrpc::ValidatedRequest;
+use crate::response_cache::ForwardedResponse;
+use anyhow::Context;
+use ethers::core::utils::keccak256;
+use ethers::types::{H256, U64};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, trace, warn};
+
+/// writes past this many are dropped instead of waiting
+const MAX_QUEUED: usize = 10_000;
+
+/// the chain id that the saved responses are for
+const CHAIN_ID: &str = "chain_id";
+
+/// Where `disk_cache_path` keeps its responses
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskCacheStore {
+    /// one file per response
+    #[default]
+    Files,
+    /// an embedded sled database. web3_proxy must be built with the "sled" feature
+    Sled,
+}
+
+pub struct DiskCache {
+    store: Store,
+    /// requests at least this many blocks behind the head are saved
+    confirmations: u64,
+    sender: mpsc::Sender<(H256, Arc<RawValue>)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// unreadable responses. they are treated as misses
+    errors: AtomicU64,
+    writes: AtomicU64,
+    /// writes skipped because the queue was full
+    dropped: AtomicU64,
+    /// responses removed to stay under the max size
+    evicted: AtomicU64,
+    bytes: AtomicU64,
+    responses: AtomicU64,
+}
+
+impl DiskCache {
+    /// None if `disk_cache_path` isn't set. Errors if the store is for another chain
+    pub fn spawn(
+        config: &AppConfig,
+        shutdown_receiver: broadcast::Receiver<()>,
+    ) -> anyhow::Result<Option<(Arc<Self>, Web3ProxyJoinHandle<()>)>> {
+        let Some(dir) = config.disk_cache_path.as_ref() else {
+            return Ok(None);
+        };
+
+        let store = Store::open(config.disk_cache_store, dir)?;
+
+        check_chain_id(&store, config.chain_id)?;
+
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED);
+
+        let x = Arc::new(Self {
+            store,
+            confirmations: config.archive_cache_confirmations,
+            sender,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            responses: AtomicU64::new(0),
+        });
+
+        let handle = tokio::spawn(x.clone().write_loop(
+            receiver,
+            shutdown_receiver,
+            config.disk_cache_max_bytes,
+        ));
+
+        info!(
+            path = %dir.display(),
+            store = ?config.disk_cache_store,
+            max_bytes = config.disk_cache_max_bytes,
+            "saving immutable responses to disk"
+        );
+
+        Ok(Some((x, handle)))
+    }
+
+    /// true if the request alone shows that its response can never change
+    fn fixed(&self, web3_request: &ValidatedRequest) -> bool {
+        matches!(web3_request.cache_mode, CacheMode::SuccessForever)
+            || web3_request.past_reorg_horizon(self.confirmations)
+    }
+
+    /// true if a saved response might be found for this request.
+    /// transactions are only saved once they are confirmed, so any saved one can be used
+    fn wants(&self, web3_request: &ValidatedRequest) -> bool {
+        self.fixed(web3_request) || is_transaction(web3_request.inner.method())
+    }
+
+    /// true if this response can never change
+    fn immutable(&self, web3_request: &ValidatedRequest, value: &RawValue) -> bool {
+        if !is_transaction(web3_request.inner.method()) {
+            return self.fixed(web3_request);
+        }
+
+        // the cache mode of a transaction has no block. the block is in the response
+        match (mined_in(value), web3_request.head_block.as_ref()) {
+            (Some(block_num), Some(head_block)) => {
+                block_num.saturating_add(U64::from(self.confirmations)) <= head_block.number()
+            }
+            _ => false,
+        }
+    }
+
+    /// Look for a saved response. Anything that goes wrong is a miss
+    pub async fn get(
+        &self,
+        web3_request: &ValidatedRequest,
+    ) -> Option<ForwardedResponse<Arc<RawValue>>> {
+        if !self.wants(web3_request) {
+            return None;
+        }
+
+        let key = key(web3_request);
+
+        let x = match self.store.read(&key).await {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            Err(err) => {
+                warn!(?err, ?key, "unable to read a cached response");
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        match RawValue::from_string(x) {
+            Ok(x) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+
+                let value: Arc<RawValue> = x.into();
+                let num_bytes = value.get().len() as u64;
+
+                Some(ForwardedResponse::Result { value, num_bytes })
+            }
+            Err(err) => {
+                // probably cut off by a crash. the next write will replace it
+                warn!(?err, ?key, "unable to parse a cached response");
+                self.errors.fetch_add(1, Ordering::Relaxed);
+
+                let _ = self.store.remove(&key).await;
+
+                None
+            }
+        }
+    }
+
+    /// Queue a response to be saved. This never waits
+    pub fn save(
+        &self,
+        web3_request: &ValidatedRequest,
+        response: &ForwardedResponse<Arc<RawValue>>,
+    ) {
+        // errors might be the backend's fault. null might be a block we haven't seen yet
+        let ForwardedResponse::Result { value, .. } = response else {
+            return;
+        };
+
+        if response.is_null() || !self.immutable(web3_request, value) {
+            return;
+        }
+
+        if self
+            .sender
+            .try_send((key(web3_request), value.clone()))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn write_loop(
+        self: Arc<Self>,
+        mut receiver: mpsc::Receiver<(H256, Arc<RawValue>)>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+        max_bytes: u64,
+    ) -> Web3ProxyResult<()> {
+        // the responses from earlier runs. this can take a while, so writes queue up until it is done
+        let store = self.store.clone();
+        let mut saved = tokio::task::spawn_blocking(move || store.scan())
+            .await
+            .unwrap_or_default();
+
+        self.responses.store(saved.len() as u64, Ordering::Relaxed);
+        self.bytes
+            .store(saved.iter().map(|x| x.1).sum(), Ordering::Relaxed);
+
+        info!(responses = saved.len(), "found cached responses on disk");
+
+        // the max might have been lowered since the last run
+        self.evict(max_bytes, &mut saved).await;
+
+        loop {
+            select! {
+                x = receiver.recv() => {
+                    let Some((key, value)) = x else {
+                        break;
+                    };
+
+                    self.write(key, value, &mut saved).await;
+
+                    self.evict(max_bytes, &mut saved).await;
+                }
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+            }
+        }
+
+        if let Err(err) = self.store.flush().await {
+            warn!(?err, "unable to flush the disk cache");
+        }
+
+        trace!("disk cache exited");
+
+        Ok(())
+    }
+
+    async fn write(&self, key: H256, value: Arc<RawValue>, saved: &mut VecDeque<(H256, u64)>) {
+        // an earlier request already saved it
+        if self.store.contains(&key).await {
+            return;
+        }
+
+        let x = value.get();
+
+        if let Err(err) = self.store.write(&key, x).await {
+            warn!(?err, ?key, "unable to save a cached response");
+            return;
+        }
+
+        saved.push_back((key, x.len() as u64));
+
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(x.len() as u64, Ordering::Relaxed);
+    }
+
+    /// remove the oldest responses until they fit in `max_bytes`
+    async fn evict(&self, max_bytes: u64, saved: &mut VecDeque<(H256, u64)>) {
+        while self.bytes.load(Ordering::Relaxed) > max_bytes {
+            let Some((key, num_bytes)) = saved.pop_front() else {
+                break;
+            };
+
+            if let Err(err) = self.store.remove_oldest(&key).await {
+                warn!(?err, ?key, "unable to remove a cached response");
+            }
+
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+            self.responses.fetch_sub(1, Ordering::Relaxed);
+            self.bytes.fetch_sub(num_bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Serialize for DiskCache {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("DiskCache", 8)?;
+
+        state.serialize_field("hits", &self.hits.load(Ordering::Relaxed))?;
+        state.serialize_field("misses", &self.misses.load(Ordering::Relaxed))?;
+        state.serialize_field("errors", &self.errors.load(Ordering::Relaxed))?;
+        state.serialize_field("writes", &self.writes.load(Ordering::Relaxed))?;
+        state.serialize_field("dropped", &self.dropped.load(Ordering::Relaxed))?;
+        state.serialize_field("evicted", &self.evicted.load(Ordering::Relaxed))?;
+        state.serialize_field("bytes", &self.bytes.load(Ordering::Relaxed))?;
+        state.serialize_field("responses", &self.responses.load(Ordering::Relaxed))?;
+
+        state.end()
+    }
+}
+
+/// The saved responses
+#[derive(Clone)]
+enum Store {
+    Files(PathBuf),
+    #[cfg(feature = "sled")]
+    Sled(SledStore),
+}
+
+#[cfg(feature = "sled")]
+#[derive(Clone)]
+struct SledStore {
+    dir: PathBuf,
+    db: sled::Db,
+    responses: sled::Tree,
+    /// response keys by when they were written. evictions take the oldest
+    order: sled::Tree,
+}
+
+impl Store {
+    fn open(store: DiskCacheStore, dir: &Path) -> anyhow::Result<Self> {
+        // the scan for files would delete sled's files
+        if store == DiskCacheStore::Files && dir.join("conf").is_file() && dir.join("db").is_file()
+        {
+            return Err(anyhow::anyhow!(
+                "{} is a sled database. set disk_cache_store = \"sled\"",
+                dir.display()
+            ));
+        }
+
+        if store == DiskCacheStore::Sled && dir.join(CHAIN_ID).is_file() {
+            return Err(anyhow::anyhow!(
+                "{} has one file per response. set disk_cache_store = \"files\"",
+                dir.display()
+            ));
+        }
+
+        match store {
+            DiskCacheStore::Files => {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("unable to create {}", dir.display()))?;
+
+                Ok(Self::Files(dir.to_path_buf()))
+            }
+            #[cfg(feature = "sled")]
+            DiskCacheStore::Sled => {
+                let db =
+                    sled::open(dir).with_context(|| format!("unable to open {}", dir.display()))?;
+
+                let responses = db.open_tree("responses")?;
+                let order = db.open_tree("order")?;
+
+                Ok(Self::Sled(SledStore {
+                    dir: dir.to_path_buf(),
+                    db,
+                    responses,
+                    order,
+                }))
+            }
+            #[cfg(not(feature = "sled"))]
+            DiskCacheStore::Sled => Err(anyhow::anyhow!(
+                "disk_cache_store = \"sled\" needs web3_proxy built with the \"sled\" feature"
+            )),
+        }
+    }
+
+    fn dir(&self) -> &Path {
+        match self {
+            Self::Files(dir) => dir,
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => &x.dir,
+        }
+    }
+
+    fn read_chain_id(&self) -> io::Result<Option<String>> {
+        match self {
+            Self::Files(dir) => match std::fs::read_to_string(dir.join(CHAIN_ID)) {
+                Ok(x) => Ok(Some(x)),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            },
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => x.db.get(CHAIN_ID)?.map(utf8).transpose(),
+        }
+    }
+
+    fn write_chain_id(&self, chain_id: u64) -> io::Result<()> {
+        match self {
+            Self::Files(dir) => std::fs::write(dir.join(CHAIN_ID), chain_id.to_string()),
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                x.db.insert(CHAIN_ID, chain_id.to_string().as_bytes())?;
+                x.db.flush()?;
+
+                Ok(())
+            }
+        }
+    }
+
+    // sled keeps recently used pages in memory and flushes in the background, so its calls are fast enough to make
+    // from async code
+
+    /// None if nothing is saved for this key
+    async fn read(&self, key: &H256) -> io::Result<Option<String>> {
+        match self {
+            Self::Files(dir) => match tokio::fs::read_to_string(file_path(dir, key)).await {
+                Ok(x) => Ok(Some(x)),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            },
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => x.responses.get(key.as_bytes())?.map(utf8).transpose(),
+        }
+    }
+
+    async fn contains(&self, key: &H256) -> bool {
+        match self {
+            Self::Files(dir) => tokio::fs::try_exists(file_path(dir, key))
+                .await
+                .unwrap_or(false),
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => x.responses.contains_key(key.as_bytes()).unwrap_or(false),
+        }
+    }
+
+    async fn write(&self, key: &H256, value: &str) -> io::Result<()> {
+        match self {
+            Self::Files(dir) => {
+                let path = file_path(dir, key);
+
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                // write somewhere else first so that a crash never leaves half a response
+                let tmp_path = path.with_extension("tmp");
+
+                tokio::fs::write(&tmp_path, value).await?;
+
+                if let Err(err) = tokio::fs::rename(&tmp_path, &path).await {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(err);
+                }
+
+                Ok(())
+            }
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                // ids only go up, so the order tree is oldest first
+                let id = x.db.generate_id()?;
+
+                x.responses.insert(key.as_bytes(), value.as_bytes())?;
+                x.order.insert(id.to_be_bytes(), key.as_bytes())?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// remove an unparsable response
+    async fn remove(&self, key: &H256) -> io::Result<()> {
+        match self {
+            Self::Files(dir) => match tokio::fs::remove_file(file_path(dir, key)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                x.responses.remove(key.as_bytes())?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// remove the oldest response. `key` is the front of the queue from `scan` and `write`
+    async fn remove_oldest(&self, key: &H256) -> io::Result<()> {
+        match self {
+            Self::Files(_) => {}
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                x.order.pop_min()?;
+            }
+        }
+
+        self.remove(key).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        match self {
+            Self::Files(_) => Ok(()),
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                x.db.flush_async().await?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// every saved response and its size, oldest first
+    fn scan(&self) -> VecDeque<(H256, u64)> {
+        match self {
+            Self::Files(dir) => scan_files(dir),
+            #[cfg(feature = "sled")]
+            Self::Sled(x) => {
+                let mut saved = VecDeque::new();
+
+                for (id, key) in x.order.iter().flatten() {
+                    let len = x
+                        .responses
+                        .get(&key)
+                        .ok()
+                        .flatten()
+                        .map(|value| value.len() as u64);
+
+                    match len {
+                        Some(len) if key.len() == 32 => {
+                            saved.push_back((H256::from_slice(&key), len))
+                        }
+                        _ => {
+                            // removed because it didn't parse
+                            let _ = x.order.remove(id);
+                        }
+                    }
+                }
+
+                saved
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sled")]
+fn utf8(x: sled::IVec) -> io::Result<String> {
+    String::from_utf8(x.to_vec()).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// eth_getTransactionByHash and eth_getTransactionReceipt are null until the transaction is mined, and a reorg can move
+/// the transaction to another block
+fn is_transaction(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getTransactionByHash" | "eth_getTransactionReceipt"
+    )
+}
+
+/// the block that a transaction or receipt is in. None if it is still pending
+fn mined_in(value: &RawValue) -> Option<U64> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Mined {
+        block_number: Option<U64>,
+    }
+
+    serde_json::from_str::<Mined>(value.get())
+        .ok()?
+        .block_number
+}
+
+/// A hash of the request that is the same in every process
+fn key(web3_request: &ValidatedRequest) -> H256 {
+    let cache_mode = &web3_request.cache_mode;
+
+    let x = json!([
+        web3_request.inner.method(),
+        web3_request.inner.params(),
+        cache_mode.from_block(),
+        cache_mode.to_block(),
+        cache_mode.cache_block(),
+    ]);
+
+    keccak256(x.to_string()).into()
+}
+
+/// Remember the chain on the first run. Refuse to start on any other chain
+fn check_chain_id(store: &Store, chain_id: u64) -> anyhow::Result<()> {
+    let dir = store.dir();
+
+    let found = store
+        .read_chain_id()
+        .with_context(|| format!("unable to read the chain id in {}", dir.display()))?;
+
+    let Some(found) = found else {
+        return store
+            .write_chain_id(chain_id)
+            .with_context(|| format!("unable to write the chain id in {}", dir.display()));
+    };
+
+    let found: u64 = found
+        .trim()
+        .parse()
+        .with_context(|| format!("{} has an invalid chain id", dir.display()))?;
+
+    if found != chain_id {
+        return Err(anyhow::anyhow!(
+            "{} has responses for chain {}, not {}. use a different disk_cache_path",
+            dir.display(),
+            found,
+            chain_id
+        ));
+    }
+
+    Ok(())
+}
+
+fn file_path(dir: &Path, key: &H256) -> PathBuf {
+    let hex = format!("{:x}", key);
+
+    // a level of directories so that no one directory gets too big
+    dir.join(&hex[..2]).join(hex)
+}
+
+/// every saved file, oldest first
+fn scan_files(dir: &Path) -> VecDeque<(H256, u64)> {
+    let mut files = Vec::new();
+
+    let Ok(subdirs) = std::fs::read_dir(dir) else {
+        return Default::default();
+    };
+
+    for subdir in subdirs.flatten() {
+        let Ok(entries) = std::fs::read_dir(subdir.path()) else {
+            // the chain_id file
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            let key = path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|x| H256::from_str(x).ok());
+
+            let Some(key) = key else {
+                // leftover from a crash in the middle of a write
+                let _ = std::fs::remove_file(&path);
+                continue;
+            };
+
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+            files.push((modified, key, metadata.len()));
+        }
+    }
+
+    files.sort_unstable_by_key(|x| x.0);
+
+    files.into_iter().map(|(_, key, len)| (key, len)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_id() {
+        let dir = std::env::temp_dir().join(format!("disk_cache_chain_id_{}", std::process::id()));
+        let store = Store::open(DiskCacheStore::Files, &dir).unwrap();
+
+        check_chain_id(&store, 1).unwrap();
+        check_chain_id(&store, 1).unwrap();
+
+        let err = check_chain_id(&store, 137).unwrap_err();
+        assert!(err.to_string().contains("chain 1, not 137"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("disk_cache_scan_{}", std::process::id()));
+
+        let a = H256::from_low_u64_be(1);
+        let b = H256::from_low_u64_be(2);
+
+        for (key, body) in [(a, "\"a\""), (b, "\"bb\"")] {
+            let path = file_path(&dir, &key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, body).unwrap();
+
+            // make sure the modified times are different
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // a write that never finished
+        let tmp = file_path(&dir, &a).with_extension("tmp");
+        std::fs::write(&tmp, "\"a").unwrap();
+
+        std::fs::write(dir.join(CHAIN_ID), "1").unwrap();
+
+        let files = scan_files(&dir);
+
+        assert_eq!(files, VecDeque::from([(a, 3), (b, 4)]));
+        assert!(!tmp.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transactions_are_saved_once_mined() {
+        assert!(is_transaction("eth_getTransactionReceipt"));
+        assert!(is_transaction("eth_getTransactionByHash"));
+        assert!(!is_transaction("eth_getCode"));
+
+        let mined =
+            RawValue::from_string(r#"{"hash":"0x01","blockNumber":"0x10"}"#.into()).unwrap();
+        assert_eq!(mined_in(&mined), Some(U64::from(16)));
+
+        let pending =
+            RawValue::from_string(r#"{"hash":"0x01","blockNumber":null}"#.into()).unwrap();
+        assert_eq!(mined_in(&pending), None);
+    }
+
+    #[test]
+    fn stores_do_not_mix() {
+        let dir = std::env::temp_dir().join(format!("disk_cache_mix_{}", std::process::id()));
+        let store = Store::open(DiskCacheStore::Files, &dir).unwrap();
+
+        check_chain_id(&store, 1).unwrap();
+
+        let err = Store::open(DiskCacheStore::Sled, &dir).err().unwrap();
+        assert!(err.to_string().contains("one file per response"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(not(feature = "sled"))]
+    #[test]
+    fn sled_needs_the_feature() {
+        let dir = std::env::temp_dir().join(format!("disk_cache_no_sled_{}", std::process::id()));
+
+        let err = Store::open(DiskCacheStore::Sled, &dir).err().unwrap();
+        assert!(err.to_string().contains("\"sled\" feature"), "{}", err);
+    }
+
+    #[cfg(feature = "sled")]
+    #[tokio::test]
+    async fn sled_store() {
+        let dir = std::env::temp_dir().join(format!("disk_cache_sled_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let a = H256::from_low_u64_be(1);
+        let b = H256::from_low_u64_be(2);
+
+        {
+            let store = Store::open(DiskCacheStore::Sled, &dir).unwrap();
+
+            check_chain_id(&store, 1).unwrap();
+
+            // b is written first, so it is the oldest even though its key sorts last
+            store.write(&b, "\"bb\"").await.unwrap();
+            store.write(&a, "\"a\"").await.unwrap();
+
+            assert!(store.contains(&a).await);
+            assert_eq!(store.read(&a).await.unwrap().as_deref(), Some("\"a\""));
+
+            store.flush().await.unwrap();
+        }
+
+        // everything is still there after a restart
+        let store = Store::open(DiskCacheStore::Sled, &dir).unwrap();
+
+        assert!(check_chain_id(&store, 137).is_err());
+
+        assert_eq!(store.scan(), VecDeque::from([(b, 4), (a, 3)]));
+
+        store.remove_oldest(&b).await.unwrap();
+
+        assert_eq!(store.read(&b).await.unwrap(), None);
+        assert_eq!(store.scan(), VecDeque::from([(a, 3)]));
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,643 @@
+//! Run the proxy inside another program.
+//!
+//! The `web3_proxy_cli proxyd` binary is a thin wrapper around this. Embedders build a [`TopConfig`] in code, optionally
+//! hand over a `reqwest::Client` and database pool they already have, and get back a [`Web3ProxyHandle`].
+//!
+//! The app keeps some state in globals (see [`crate::globals`]), so only one proxy should be spawned per process. One
+//! proxy can serve several chains. See [`TopConfig::chains`].
+//!
+//! The example needs `anvil`, so it only runs with the `tests-needing-docker` feature. Otherwise it is only compiled.
+//!
+#![cfg_attr(feature = "tests-needing-docker", doc = "```")]
+#![cfg_attr(not(feature = "tests-needing-docker"), doc = "```no_run")]
+//! use web3_proxy::config::{AppConfig, TopConfig, Web3RpcConfig};
+//! use web3_proxy::embed::Web3ProxyBuilder;
+//! use web3_proxy::prelude::hashbrown::HashMap;
+//! use web3_proxy::prelude::serde_json::{self, json};
+//! use web3_proxy::test_utils::TestAnvil;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> web3_proxy::errors::Web3ProxyResult<()> {
+//! let anvil = TestAnvil::spawn(31337).await;
+//!
+//! let app: AppConfig = serde_json::from_value(json!({
+//!     "chain_id": 31337,
+//!     "min_sum_soft_limit": 1,
+//!     "min_synced_rpcs": 1,
+//! }))?;
+//!
+//! let top_config = TopConfig {
+//!     app,
+//!     balanced_rpcs: HashMap::from([(
+//!         "anvil".to_string(),
+//!         Web3RpcConfig {
+//!             http_url: Some(anvil.instance.endpoint()),
+//!             ws_url: Some(anvil.instance.ws_endpoint()),
+//!             ..Default::default()
+//!         },
+//!     )]),
+//!     private_rpcs: Default::default(),
+//!     bundler_4337_rpcs: Default::default(),
+//!     chains: Default::default(),
+//!     extra: Default::default(),
+//! };
+//!
+//! // port 0 (the default) lets the os pick a free port
+//! let proxy = Web3ProxyBuilder::new(top_config).spawn().await?;
+//!
+//! let health = reqwest::get(format!("http://{}/health", proxy.local_addr())).await?;
+//! assert!(health.status().is_success());
+//!
+//! proxy.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::app::{App, AppShared};
+use crate::config::TopConfig;
+use crate::errors::Web3ProxyResult;
+use crate::frontend::ServedChain;
+use crate::globals::{global_db_conn, DB_CONN, DB_REPLICA};
+use crate::stats::FlushedStats;
+use crate::{frontend, prometheus};
+use futures::stream::{FuturesUnordered, StreamExt};
+use migration::sea_orm::DatabaseConnection;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{error, info, trace, warn};
+
+/// Configure and start a proxy
+pub struct Web3ProxyBuilder {
+    top_config: TopConfig,
+    port: u16,
+    prometheus_port: u16,
+    num_workers: usize,
+    http_client: Option<reqwest::Client>,
+    db_conn: Option<DatabaseConnection>,
+    head_block_timeout: Duration,
+}
+
+impl Web3ProxyBuilder {
+    pub fn new(top_config: TopConfig) -> Self {
+        Self {
+            top_config,
+            port: 0,
+            prometheus_port: 0,
+            num_workers: 4,
+            http_client: None,
+            db_conn: None,
+            head_block_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// what port the proxy should listen on. 0 (the default) lets the os choose
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// what port the proxy should expose prometheus stats on. 0 (the default) lets the os choose
+    pub fn prometheus_port(mut self, port: u16) -> Self {
+        self.prometheus_port = port;
+        self
+    }
+
+    /// used to size some of the app's caches and connection pools
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// share a client (and its connection pool) with the rest of the program
+    pub fn http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// use a database pool that is already connected instead of connecting to `db_url`.
+    /// Migrations are still run on it.
+    pub fn db_conn(mut self, db_conn: DatabaseConnection) -> Self {
+        self.db_conn = Some(db_conn);
+        self
+    }
+
+    /// how long to wait for the first head block before giving up
+    pub fn head_block_timeout(mut self, head_block_timeout: Duration) -> Self {
+        self.head_block_timeout = head_block_timeout;
+        self
+    }
+
+    /// Start an app for each chain, wait for their head blocks, and then start serving requests.
+    pub async fn spawn(self) -> Web3ProxyResult<Web3ProxyHandle> {
+        if let Some(db_conn) = self.db_conn {
+            *DB_CONN.write() = Ok(db_conn.clone());
+            *DB_REPLICA.write() = Ok(db_conn.into());
+        }
+
+        // `from_toml` already checked this, but the config might have been built in code
+        self.top_config.check_chains()?;
+
+        let frontend_port = Arc::new(AtomicU16::new(self.port));
+        let prometheus_port = Arc::new(AtomicU16::new(self.prometheus_port));
+
+        // the frontend is shut down first. everything else is told to stop once it is done
+        let (frontend_shutdown_sender, frontend_shutdown_receiver) = broadcast::channel(1);
+        let (app_shutdown_sender, _app_shutdown_receiver) = broadcast::channel(1);
+        let (frontend_shutdown_complete_sender, frontend_shutdown_complete_receiver) =
+            broadcast::channel(1);
+
+        // the chains share these. their settings come from the top level `[app]`
+        let shared =
+            AppShared::connect(&self.top_config.app, self.num_workers, self.http_client).await?;
+
+        // a config without `[chains]` is one chain that is served at `/`
+        let chain_configs: Vec<_> = if self.top_config.chains.is_empty() {
+            vec![(None, vec![], self.top_config.clone())]
+        } else {
+            self.top_config
+                .chains
+                .iter()
+                .map(|(name, x)| (Some(name.clone()), x.hosts.clone(), x.top_config.clone()))
+                .collect()
+        };
+
+        let mut chains = vec![];
+        let mut spawned_apps = vec![];
+        let mut new_top_config_senders = vec![];
+        let mut flush_stat_buffer_senders = vec![];
+
+        for (name, hosts, top_config) in chain_configs {
+            let (flush_stat_buffer_sender, flush_stat_buffer_receiver) = mpsc::channel(8);
+
+            let frontend_path = name.as_ref().map(|x| format!("/{}", x)).unwrap_or_default();
+
+            let spawned_app = match App::spawn(
+                frontend_port.clone(),
+                frontend_path,
+                prometheus_port.clone(),
+                top_config,
+                app_shutdown_sender.clone(),
+                flush_stat_buffer_sender.clone(),
+                flush_stat_buffer_receiver,
+                shared.clone(),
+            )
+            .await
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    // stop the chains that already started
+                    let _ = app_shutdown_sender.send(());
+
+                    return Err(match name {
+                        None => err,
+                        Some(name) => err.context(format!("spawning chains.{}", name)),
+                    }
+                    .into());
+                }
+            };
+
+            new_top_config_senders.push((name.clone(), spawned_app.new_top_config.clone()));
+            flush_stat_buffer_senders.push(flush_stat_buffer_sender);
+
+            chains.push(ServedChain {
+                name,
+                hosts,
+                app: spawned_app.app.clone(),
+            });
+
+            spawned_apps.push(spawned_app);
+        }
+
+        // the ports and the start and shutdown scripts are shared by every chain
+        let app = chains[0].app.clone();
+
+        let prometheus_handle = tokio::spawn(prometheus::serve(
+            chains.clone(),
+            app_shutdown_sender.subscribe(),
+        ));
+
+        info!(timeout=?self.head_block_timeout, "waiting for a head block");
+        let max_wait_until = Instant::now() + self.head_block_timeout;
+        for chain in chains.iter() {
+            let mut head_block_receiver = chain.app.head_block_receiver();
+
+            loop {
+                if let Some(head_block) = head_block_receiver.borrow_and_update().as_ref() {
+                    info!(chain=?chain.name, head_hash=?head_block.hash(), head_num=%head_block.number());
+                    break;
+                }
+
+                select! {
+                    _ = sleep_until(max_wait_until) => {
+                        let _ = app_shutdown_sender.send(());
+
+                        return Err(anyhow::anyhow!(
+                            "no head block for chain {} after {:?}",
+                            chain.app.config.chain_id,
+                            self.head_block_timeout
+                        )
+                        .into());
+                    }
+                    x = head_block_receiver.changed() => {
+                        x?;
+                    }
+                }
+            }
+        }
+
+        let mut frontend_handle = tokio::spawn(frontend::serve(
+            chains.clone(),
+            frontend_shutdown_receiver,
+            frontend_shutdown_complete_sender,
+        ));
+
+        // the frontend stores the port it actually bound to. wait until something can connect to it
+        let local_addr = loop {
+            if frontend_handle.is_finished() {
+                return match (&mut frontend_handle).await? {
+                    Ok(()) => Err(anyhow::anyhow!("frontend exited while starting").into()),
+                    Err(err) => Err(err),
+                };
+            }
+
+            let port = app.frontend_port.load(Ordering::SeqCst);
+
+            if port != 0 {
+                let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+                if TcpStream::connect(addr).await.is_ok() {
+                    break addr;
+                }
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        };
+
+        let prometheus_addr = loop {
+            let port = app.prometheus_port.load(Ordering::SeqCst);
+
+            if port != 0 || prometheus_handle.is_finished() {
+                break SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        };
+
+        if let Some(start_script) = app.config.start_script.as_ref() {
+            match Command::new(start_script)
+                .args(&app.config.start_script_args)
+                .spawn()
+            {
+                Ok(x) => match x.wait_with_output().await {
+                    Ok(x) => info!(?x, "start script finished"),
+                    Err(err) => error!(?err, "start script failed"),
+                },
+                Err(err) => error!(?err, "failed to execute start script"),
+            }
+        }
+
+        // with several chains, the handle takes whole configs and flushes every chain's stats
+        let (new_top_config, flush_stat_buffer_sender) = if self.top_config.chains.is_empty() {
+            (
+                new_top_config_senders.remove(0).1,
+                flush_stat_buffer_senders.remove(0),
+            )
+        } else {
+            let new_top_config_senders = new_top_config_senders
+                .into_iter()
+                .map(|(name, x)| (name.unwrap_or_default(), x))
+                .collect();
+
+            let (new_top_config, new_top_config_receiver) = watch::channel(self.top_config);
+
+            forward_chain_configs(new_top_config_receiver, new_top_config_senders);
+
+            let (flush_stat_buffer_sender, flush_stat_buffer_receiver) = mpsc::channel(8);
+
+            flush_every_chain(flush_stat_buffer_receiver, flush_stat_buffer_senders);
+
+            (Arc::new(new_top_config), flush_stat_buffer_sender)
+        };
+
+        let supervisor = {
+            let frontend_shutdown_sender = frontend_shutdown_sender.clone();
+
+            tokio::spawn(async move {
+                let mut balanced_handles = FuturesUnordered::new();
+                let mut background_handles = FuturesUnordered::new();
+                let mut other_handles = vec![];
+                let mut apps = vec![];
+
+                for spawned_app in spawned_apps {
+                    balanced_handles.push(spawned_app.balanced_handle);
+                    background_handles.extend(spawned_app.background_handles);
+                    other_handles.push(spawned_app.private_handle);
+                    other_handles.push(spawned_app.bundler_4337_rpcs_handle);
+                    apps.push(spawned_app.app);
+                }
+
+                // if everything is working, these should all run forever
+                let mut exited_with_err = false;
+                let mut frontend_exited = false;
+                select! {
+                    x = balanced_handles.next() => {
+                        match x {
+                            Some(Ok(_)) => info!("balanced_handle exited"),
+                            Some(Err(e)) => {
+                                error!("balanced_handle exited: {:#?}", e);
+                                exited_with_err = true;
+                            }
+                            None => warn!("no balanced handles"),
+                        }
+                    }
+                    x = frontend_handle => {
+                        frontend_exited = true;
+                        match x {
+                            Ok(Ok(_)) => info!("frontend exited"),
+                            Ok(Err(e)) => {
+                                error!("frontend exited: {:#?}", e);
+                                exited_with_err = true;
+                            }
+                            Err(e) => {
+                                error!(?e, "join on frontend failed");
+                                exited_with_err = true;
+                            }
+                        }
+                    }
+                    x = prometheus_handle => {
+                        match x {
+                            Ok(Ok(_)) => info!("prometheus exited"),
+                            Ok(Err(e)) => {
+                                error!("prometheus exited: {:#?}", e);
+                                exited_with_err = true;
+                            }
+                            Err(e) => {
+                                error!(?e, "join on prometheus failed");
+                                exited_with_err = true;
+                            }
+                        }
+                    }
+                    x = background_handles.next() => {
+                        match x {
+                            Some(Ok(_)) => info!("quiting from background handles"),
+                            Some(Err(e)) => {
+                                error!("quiting from background handle error: {:#?}", e);
+                                exited_with_err = true;
+                            }
+                            None => {
+                                // TODO: is this an error?
+                                warn!("background handles exited");
+                            }
+                        }
+                    }
+                };
+
+                // if a future above completed, make sure the frontend knows to start turning off
+                if !frontend_exited {
+                    if let Err(err) = frontend_shutdown_sender.send(()) {
+                        // this is expected if the frontend is already shut down
+                        warn!(?err, "shutdown sender");
+                    };
+                }
+
+                let mut frontend_shutdown_complete_receiver = frontend_shutdown_complete_receiver;
+                if let Err(err) = frontend_shutdown_complete_receiver.recv().await {
+                    warn!(?err, "shutdown completition");
+                } else {
+                    info!("frontend exited gracefully");
+                }
+
+                // now that the frontend is complete, tell all the other futures to finish
+                if let Err(err) = app_shutdown_sender.send(()) {
+                    warn!(?err, "backend sender");
+                };
+
+                info!(
+                    "waiting on {} important background tasks",
+                    background_handles.len()
+                );
+                let mut background_errors = 0;
+                while let Some(x) = background_handles.next().await {
+                    match x {
+                        Err(e) => {
+                            error!("{:?}", e);
+                            background_errors += 1;
+                        }
+                        Ok(Err(e)) => {
+                            error!("{:?}", e);
+                            background_errors += 1;
+                        }
+                        Ok(Ok(_)) => {
+                            // TODO: how can we know which handle exited?
+                            trace!("a background handle exited");
+                            continue;
+                        }
+                    }
+                }
+
+                // nothing is left to send requests to the rpcs. stop their connections
+                for app in apps.iter() {
+                    app.balanced_rpcs.disconnect_all();
+                    app.protected_rpcs.disconnect_all();
+                    app.bundler_4337_rpcs.disconnect_all();
+                }
+
+                for x in balanced_handles.iter().chain(other_handles.iter()) {
+                    x.abort();
+                }
+
+                if let Ok(db_conn) = global_db_conn() {
+                    /*
+                    From the sqlx docs:
+
+                    We recommend calling .close().await to gracefully close the pool and its connections when you are done using it.
+                    This will also wake any tasks that are waiting on an .acquire() call,
+                    so for long-lived applications it’s a good idea to call .close() during shutdown.
+                    */
+                    db_conn.close().await?;
+                }
+
+                if background_errors == 0 && !exited_with_err {
+                    info!("finished");
+                    Ok(())
+                } else {
+                    // TODO: collect all the errors here instead?
+                    Err(anyhow::anyhow!("finished with errors!").into())
+                }
+            })
+        };
+
+        Ok(Web3ProxyHandle {
+            app,
+            chains,
+            local_addr,
+            prometheus_addr,
+            new_top_config,
+            flush_stat_buffer_sender,
+            shutdown_sender: frontend_shutdown_sender,
+            supervisor: Some(supervisor),
+        })
+    }
+}
+
+/// Each chain's App watches a config of its own. Send each one its section of every new config.
+/// Chains can't be added or removed without a restart
+fn forward_chain_configs(
+    mut receiver: watch::Receiver<TopConfig>,
+    senders: Vec<(String, Arc<watch::Sender<TopConfig>>)>,
+) {
+    tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let new_top_config = receiver.borrow_and_update().clone();
+
+            for (name, sender) in senders.iter() {
+                let Some(chain) = new_top_config.chains.get(name) else {
+                    warn!(%name, "chain is no longer in the config. it is served until a restart");
+                    continue;
+                };
+
+                // unchanged chains don't need to reload
+                sender.send_if_modified(|x| {
+                    if *x == chain.top_config {
+                        false
+                    } else {
+                        *x = chain.top_config.clone();
+                        true
+                    }
+                });
+            }
+
+            for name in new_top_config.chains.keys() {
+                if !senders.iter().any(|(x, _)| x == name) {
+                    warn!(%name, "chain was added to the config. it is not served until a restart");
+                }
+            }
+        }
+    });
+}
+
+/// Each chain has a stat buffer of its own. Flush all of them and add up what they saved
+fn flush_every_chain(
+    mut receiver: mpsc::Receiver<oneshot::Sender<FlushedStats>>,
+    senders: Vec<mpsc::Sender<oneshot::Sender<FlushedStats>>>,
+) {
+    tokio::spawn(async move {
+        while let Some(flushed_sender) = receiver.recv().await {
+            let mut flushed = FlushedStats::default();
+
+            for sender in senders.iter() {
+                let (tx, rx) = oneshot::channel();
+
+                // chains without a stat buffer have nothing to flush
+                if sender.send(tx).await.is_err() {
+                    continue;
+                }
+
+                if let Ok(x) = rx.await {
+                    flushed += x;
+                }
+            }
+
+            let _ = flushed_sender.send(flushed);
+        }
+    });
+}
+
+/// A running proxy. Dropping this does not stop it. Use [`Web3ProxyHandle::shutdown`].
+pub struct Web3ProxyHandle {
+    app: Arc<App>,
+    chains: Vec<ServedChain>,
+    local_addr: SocketAddr,
+    prometheus_addr: SocketAddr,
+    new_top_config: Arc<watch::Sender<TopConfig>>,
+    flush_stat_buffer_sender: mpsc::Sender<oneshot::Sender<FlushedStats>>,
+    shutdown_sender: broadcast::Sender<()>,
+    /// None once it has been joined
+    supervisor: Option<JoinHandle<Web3ProxyResult<()>>>,
+}
+
+impl Web3ProxyHandle {
+    /// The first chain's app. A config with `[chains]` has others. See `chains`
+    pub fn app(&self) -> &Arc<App> {
+        &self.app
+    }
+
+    /// every chain that is served and where
+    pub fn chains(&self) -> &[ServedChain] {
+        &self.chains
+    }
+
+    /// where the frontend is listening. it binds to every interface, but this is always on localhost
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn prometheus_addr(&self) -> SocketAddr {
+        self.prometheus_addr
+    }
+
+    /// send a new config here to apply it without restarting. each chain is sent its own section
+    pub fn new_top_config(&self) -> &Arc<watch::Sender<TopConfig>> {
+        &self.new_top_config
+    }
+
+    pub fn flush_stat_buffer_sender(&self) -> &mpsc::Sender<oneshot::Sender<FlushedStats>> {
+        &self.flush_stat_buffer_sender
+    }
+
+    /// sending on this starts a graceful shutdown
+    pub fn shutdown_sender(&self) -> &broadcast::Sender<()> {
+        &self.shutdown_sender
+    }
+
+    /// write any buffered stats to the databases
+    pub async fn flush_stats(&self) -> Web3ProxyResult<FlushedStats> {
+        let (tx, rx) = oneshot::channel();
+
+        self.flush_stat_buffer_sender
+            .send(tx)
+            .await
+            .map_err(|_| anyhow::anyhow!("stat buffer is not running"))?;
+
+        let x = rx
+            .await
+            .map_err(|_| anyhow::anyhow!("stat buffer exited before flushing"))?;
+
+        Ok(x)
+    }
+
+    /// start a graceful shutdown. use `join` to wait for it to finish.
+    /// in-flight http requests get `shutdown_grace_secs` to finish. websockets are closed right away
+    pub fn stop(&self) {
+        // an error here means the frontend is already stopped
+        let _ = self.shutdown_sender.send(());
+    }
+
+    /// wait for the proxy to exit. this is safe to use in a `select!`
+    pub async fn join(&mut self) -> Web3ProxyResult<()> {
+        let Some(supervisor) = self.supervisor.as_mut() else {
+            return Ok(());
+        };
+
+        let x = supervisor.await;
+
+        self.supervisor = None;
+
+        x?
+    }
+
+    /// stop gracefully and wait for everything to finish
+    pub async fn shutdown(mut self) -> Web3ProxyResult<()> {
+        self.stop();
+
+        self.join().await
+    }
+}
@@ -1,12 +1,15 @@
 //! Utlities for logging errors for admins and displaying errors to users.
 
 use crate::block_number::BlockNumOrHash;
-use crate::frontend::authorization::Authorization;
+use crate::block_tags::BlockTag;
+use crate::frontend::authorization::{insert_rate_limit_headers, Authorization, Entitlement};
 use crate::jsonrpc::{
     self, JsonRpcErrorData, ParsedResponse, SingleRequest, StreamResponse, ValidatedRequest,
 };
+use crate::quotas::{QuotaExceeded, QuotaPeriod};
 use crate::response_cache::ForwardedResponse;
 use crate::rpcs::blockchain::BlockHeader;
+use crate::rpcs::maintenance::MaintenanceWindow;
 use crate::rpcs::one::Web3Rpc;
 use crate::rpcs::provider::EthersHttpProvider;
 use axum::extract::rejection::JsonRejection;
@@ -17,10 +20,11 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::Utc;
 use derive_more::{Display, Error, From};
 use ethers::prelude::ContractError;
 use ethers::types::{H256, U64};
-use http::header::InvalidHeaderValue;
+use http::header::{InvalidHeaderValue, RETRY_AFTER};
 use http::uri::InvalidUri;
 use ipnet::AddrParseError;
 use migration::sea_orm::DbErr;
@@ -37,6 +41,7 @@ use std::time::Duration;
 use std::{borrow::Cow, net::IpAddr};
 use tokio::{sync::AcquireError, task::JoinError, time::Instant};
 use tracing::{debug, error, trace, warn};
+use web3_proxy_client::types::{Backpressure, ShedReason};
 
 pub type Web3ProxyResult<T> = Result<T, Web3ProxyError>;
 // TODO: take "IntoResponse" instead of Response?
@@ -63,6 +68,13 @@ pub enum Web3ProxyError {
         min: Option<U64>,
         max: Option<U64>,
     },
+    /// the database is down and `degraded_auth` doesn't allow this key. see `crate::degraded`
+    AuthUnavailable,
+    /// every backend that could serve the request is rate limiting us until at least this time
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    BackendSaturated(Instant),
     #[error(ignore)]
     #[from(ignore)]
     BadRequest(Cow<'static, str>),
@@ -70,6 +82,35 @@ pub enum Web3ProxyError {
     #[from(ignore)]
     BadResponse(Cow<'static, str>),
     BadRouting,
+    /// a batch has more than `max_batch_size` requests
+    #[display(fmt = "{} > {}", len, max)]
+    #[from(ignore)]
+    BatchTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// every backend has pruned the state for this block
+    #[display(fmt = "{} < {}", requested, oldest)]
+    #[from(ignore)]
+    BeyondPruningHorizon {
+        requested: U64,
+        oldest: U64,
+    },
+    /// the backends don't know about "finalized" or "safe". probably a pre-merge chain
+    #[error(ignore)]
+    #[from(ignore)]
+    BlockTagUnsupported(BlockTag),
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    Banned(Instant),
+    /// the request's params are for a different chain than this proxy serves
+    #[display(fmt = "{} != {}", found, expected)]
+    #[from(ignore)]
+    ChainIdMismatch {
+        expected: u64,
+        found: U64,
+    },
     Contract(ContractError<EthersHttpProvider>),
     Database(DbErr),
     DatabaseArc(Arc<DbErr>),
@@ -84,6 +125,15 @@ pub enum Web3ProxyError {
         requested: U64,
     },
     GasEstimateNotU256,
+    /// one page of an auto paginated eth_getLogs failed
+    #[display(fmt = "{}..={}: {}", from, to, err)]
+    #[error(ignore)]
+    #[from(ignore)]
+    GetLogsPageFailed {
+        from: U64,
+        to: U64,
+        err: Box<Web3ProxyError>,
+    },
     HdrRecord(hdrhistogram::errors::RecordError),
     Headers(headers::Error),
     HeaderToString(ToStrError),
@@ -99,6 +149,10 @@ pub enum Web3ProxyError {
     InvalidHeaderValue(InvalidHeaderValue),
     InvalidEip,
     InvalidInviteCode,
+    /// the method is fine but its params aren't. jsonrpc's -32602
+    #[error(ignore)]
+    #[from(ignore)]
+    InvalidParams(Cow<'static, str>),
     Io(std::io::Error),
     UnknownReferralCode,
     InvalidReferer,
@@ -137,9 +191,33 @@ pub enum Web3ProxyError {
         needed: u32,
     },
     NotFound,
+    /// the user's tier doesn't include this kind of request
+    #[display(fmt = "{} {} {:?}", entitlement, method, tier)]
+    #[error(ignore)]
+    #[from(ignore)]
+    NotEntitled {
+        entitlement: Entitlement,
+        method: String,
+        tier: Option<String>,
+    },
+    /// an admin put this group of rpcs into planned maintenance
+    #[display(fmt = "{} {:?}", _0, _1)]
+    #[error(ignore)]
+    #[from(ignore)]
+    Maintenance(Cow<'static, str>, MaintenanceWindow),
+    /// the rpc key's method lists don't allow this method
+    #[error(ignore)]
+    #[from(ignore)]
+    MethodNotAllowed(String),
     #[error(ignore)]
     #[from(ignore)]
     MethodNotFound(Cow<'static, str>),
+    /// some backends support this method, but none of them are available right now
+    #[error(ignore)]
+    #[from(ignore)]
+    MethodTemporarilyUnavailable(Cow<'static, str>),
+    /// the path and the Authorization header have different rpc keys
+    MultipleRpcKeys,
     NoVolatileRedisDatabase,
     #[error(ignore)]
     #[from(ignore)]
@@ -154,6 +232,33 @@ pub enum Web3ProxyError {
     ParseBytesError(Option<ethers::types::ParseBytesError>),
     ParseMsgError(siwe::ParseError),
     ParseAddressError,
+    /// the rpc key doesn't allow this kind of request
+    #[error(ignore)]
+    #[from(ignore)]
+    ProtocolNotAllowed(Cow<'static, str>),
+    #[display(fmt = "{} results, {} bytes", results, bytes)]
+    #[error(ignore)]
+    #[from(ignore)]
+    PaginatedLogsTooLarge {
+        from: U64,
+        to: U64,
+        results: u64,
+        bytes: u64,
+        max_results: u64,
+        max_bytes: u64,
+    },
+    /// a daily or monthly quota is used up. not the per-period rate limit
+    #[display(fmt = "{:?}", _1)]
+    #[error(ignore)]
+    #[from(ignore)]
+    QuotaExceeded(Authorization, QuotaExceeded),
+    #[display(fmt = "{:?} > {}", to, head)]
+    #[error(ignore)]
+    #[from(ignore)]
+    RangeInFuture {
+        to: BlockNumOrHash,
+        head: U64,
+    },
     #[display(fmt = "{:?} > {:?}", from, to)]
     RangeInvalid {
         from: BlockNumOrHash,
@@ -168,8 +273,9 @@ pub enum Web3ProxyError {
         requested: U64,
         allowed: U64,
     },
-    #[display(fmt = "{:?}, {:?}", _0, _1)]
-    RateLimited(Authorization, Option<Instant>),
+    /// when they can retry and how much of their limit this request would have used
+    #[display(fmt = "{:?}, {:?}, {}", _0, _1, _2)]
+    RateLimited(Authorization, Option<Instant>, u64),
     Redis(RedisError),
     RedisDeadpool(RedisPoolError),
     RefererRequired,
@@ -177,10 +283,39 @@ pub enum Web3ProxyError {
     #[error(ignore)]
     #[from(ignore)]
     RefererNotAllowed(headers::Referer),
+    /// the request body or websocket message is bigger than `max_request_bytes`
+    #[display(fmt = "max {} bytes", max)]
+    #[from(ignore)]
+    RequestTooLarge {
+        max: u64,
+    },
     Reqwest(reqwest::Error),
+    /// a backend's response is bigger than `max_response_bytes`. it was cut off instead of read
+    #[display(fmt = "{}/{} bytes", bytes, max)]
+    #[from(ignore)]
+    ResponseTooLarge {
+        bytes: u64,
+        max: u64,
+    },
+    /// too many long responses are being read into memory at once
+    #[display(fmt = "needed {} bytes. {}/{} buffered", needed, buffered, max)]
+    #[error(ignore)]
+    #[from(ignore)]
+    ResponseBufferFull {
+        needed: u64,
+        buffered: u64,
+        max: u64,
+    },
     SemaphoreAcquireError(AcquireError),
     SerdeJson(serde_json::Error),
+    /// this server is a warm standby. an admin has to promote it before it serves jsonrpc
+    Standby,
     SiweVerification(VerificationError),
+    /// the consensus head hasn't changed in this long. "latest" would be an old block
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    StaleHead(Duration),
     /// simple way to return an error message to the user and an anyhow to our logs
     #[display(fmt = "{}, {}, {:?}", _0, _1, _2)]
     StatusCode(StatusCode, Cow<'static, str>, Option<serde_json::Value>),
@@ -205,6 +340,13 @@ pub enum Web3ProxyError {
     UnknownKey,
     #[error(ignore)]
     UnhandledMethod(Cow<'static, str>),
+    #[display(fmt = "{} > {}", requested, allowed)]
+    #[error(ignore)]
+    #[from(ignore)]
+    UnfilteredRangeTooLarge {
+        requested: U64,
+        allowed: U64,
+    },
     UserAgentRequired,
     #[error(ignore)]
     UserAgentNotAllowed(headers::UserAgent),
@@ -213,6 +355,11 @@ pub enum Web3ProxyError {
     WatchRecvError(tokio::sync::watch::error::RecvError),
     WatchSendError,
     WebsocketOnly,
+    /// the websocket to a backend closed before it answered. the request is safe to retry on another rpc
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    WebsocketDisconnected(String),
     #[display(fmt = "{:?}, {}", _0, _1)]
     #[error(ignore)]
     WithContext(Option<Box<Web3ProxyError>>, Cow<'static, str>),
@@ -232,6 +379,9 @@ pub enum RequestForError<'a> {
     SingleRequest(&'a SingleRequest),
     // sometimes we have json for a batch of requests
     // Batch(&'a BatchRequest),
+    /// sometimes all we know is the request's id. the body hasn't been read yet
+    #[from(ignore)]
+    RequestId(&'a str),
     /// assuming things went well, we have a validated request
     Validated(&'a ValidatedRequest),
 }
@@ -244,6 +394,58 @@ impl RequestForError<'_> {
             _ => false,
         }
     }
+
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Self::Validated(x) => x.request_id.as_deref(),
+            Self::RequestId(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Every error for shed load is built here so that clients can always find the same [`Backpressure`] keys in `data`.
+/// `extra` is only for keys that are specific to the reason
+fn shed_response(
+    reason: ShedReason,
+    retry_after: Duration,
+    message: Cow<'static, str>,
+    request_for_error: &RequestForError,
+    extra: serde_json::Value,
+) -> (StatusCode, JsonRpcErrorData) {
+    let status_code = match reason {
+        ShedReason::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ShedReason::ProxyOverloaded
+        | ShedReason::BackendSaturated
+        | ShedReason::Maintenance
+        | ShedReason::Standby => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    let backpressure = Backpressure::new(
+        reason,
+        retry_after,
+        request_for_error.request_id().map(ToString::to_string),
+    );
+
+    let mut data =
+        serde_json::to_value(backpressure).expect("backpressure should always serialize");
+
+    let x = data.as_object_mut().expect("backpressure is a struct");
+
+    if let serde_json::Value::Object(extra) = extra {
+        x.extend(extra);
+    }
+
+    x.insert("request".to_string(), json!(request_for_error));
+
+    (
+        status_code,
+        JsonRpcErrorData {
+            message,
+            code: status_code.as_u16().into(),
+            data: Some(data),
+        },
+    )
 }
 
 impl Web3ProxyError {
@@ -309,7 +511,8 @@ impl Web3ProxyError {
                 (
                     StatusCode::OK,
                     JsonRpcErrorData {
-                        message: "Archive data required".into(),
+                        message: "Archive data required. the backends that were tried have pruned this block"
+                            .into(),
                         code: StatusCode::OK.as_u16().into(),
                         data: Some(json!({
                             "request": request_for_error,
@@ -337,6 +540,30 @@ impl Web3ProxyError {
             Self::Arc(err) => {
                 return err.as_response_parts(Some(request_for_error));
             }
+            Self::AuthUnavailable => {
+                trace!("AuthUnavailable");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: "auth unavailable".into(),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+                        data: Some(json!({
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::BackendSaturated(retry_at) => {
+                warn!(?retry_at, "BackendSaturated");
+
+                shed_response(
+                    ShedReason::BackendSaturated,
+                    retry_at.saturating_duration_since(Instant::now()),
+                    "backend rpcs are all rate limited".into(),
+                    &request_for_error,
+                    json!({}),
+                )
+            }
             Self::BadRequest(err) => {
                 trace!(?err, "BAD_REQUEST");
                 (
@@ -379,6 +606,97 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::BatchTooLarge { len, max } => {
+                trace!(%len, %max, "BatchTooLarge");
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcErrorData {
+                        message: format!("batch has {} requests. the limit is {}", len, max).into(),
+                        // Invalid Request
+                        code: -32600,
+                        data: Some(json!({
+                            "batch_size": len,
+                            "max_batch_size": max,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::BeyondPruningHorizon { requested, oldest } => {
+                trace!(%requested, %oldest, "BeyondPruningHorizon");
+                (
+                    StatusCode::OK,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "block {} is beyond the pruning horizon. the oldest block with state is {}",
+                            requested, oldest
+                        )
+                        .into(),
+                        // Resource unavailable
+                        code: -32002,
+                        data: Some(json!({
+                            "request": request_for_error,
+                            "requested_block": requested,
+                            "oldest_block": oldest,
+                        })),
+                    },
+                )
+            }
+            Self::BlockTagUnsupported(tag) => {
+                trace!(%tag, "BlockTagUnsupported");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "the \"{}\" block tag is not supported on this chain. use \"latest\" or a block number",
+                            tag
+                        )
+                        .into(),
+                        // Invalid params
+                        code: -32602,
+                        data: Some(json!({
+                            "tag": tag,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::Banned(until) => {
+                trace!(?until, "Banned");
+
+                let retry_after = until.saturating_duration_since(Instant::now()).as_secs();
+
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: "banned for repeated invalid requests".into(),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: Some(json!({
+                            "retry_after": retry_after,
+                        })),
+                    },
+                )
+            }
+            Self::ChainIdMismatch { expected, found } => {
+                trace!(%expected, %found, "ChainIdMismatch");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "chain id mismatch. this endpoint serves chain {} but the request is for chain {}",
+                            expected, found
+                        )
+                        .into(),
+                        // Invalid params
+                        code: -32602,
+                        data: Some(json!({
+                            "expected": expected,
+                            "found": found,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::Contract(err) => {
                 warn!(?err, "Contract Error: {}", err);
                 (
@@ -524,6 +842,42 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::GetLogsPageFailed { from, to, err } => {
+                trace!(%from, %to, ?err, "GetLogsPageFailed");
+
+                let (code, response) = err.as_response_parts(None::<RequestForError>);
+
+                let (message, inner_code, inner_data) = match response {
+                    ForwardedResponse::RpcError { error_data, .. } => {
+                        (error_data.message, error_data.code, error_data.data)
+                    }
+                    ForwardedResponse::Result { .. } => (
+                        "unknown error".into(),
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+                        None,
+                    ),
+                };
+
+                (
+                    code,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "eth_getLogs failed for blocks {}..={}: {}",
+                            from, to, message
+                        )
+                        .into(),
+                        code: inner_code,
+                        data: Some(json!({
+                            "failed_range": {
+                                "from": from,
+                                "to": to,
+                            },
+                            "err": inner_data,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::HdrRecord(err) => {
                 warn!(?err, "HdrRecord");
                 (
@@ -687,6 +1041,20 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::InvalidParams(err) => {
+                trace!(?err, "InvalidParams");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: err.clone(),
+                        // Invalid params
+                        code: -32602,
+                        data: Some(json!({
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::Io(err) => {
                 warn!(?err, "std io");
                 (
@@ -829,6 +1197,89 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::Maintenance(group, window) => {
+                debug!(%group, ?window, "Maintenance");
+
+                let retry_after = Duration::from_secs(window.retry_after(Instant::now()));
+
+                shed_response(
+                    ShedReason::Maintenance,
+                    retry_after,
+                    format!("{} rpcs are temporarily unavailable for maintenance", group).into(),
+                    &request_for_error,
+                    json!({
+                        "group": group,
+                        "message": window.message,
+                    }),
+                )
+            }
+            Self::RequestTooLarge { max } => {
+                trace!(%max, "RequestTooLarge");
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcErrorData {
+                        message: format!("request is larger than {} bytes", max).into(),
+                        // Invalid Request
+                        code: -32600,
+                        data: Some(json!({
+                            "max_request_bytes": max,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::ResponseTooLarge { bytes, max } => {
+                warn!(%bytes, %max, "ResponseTooLarge");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "response is larger than {} bytes. narrow your query",
+                            max
+                        )
+                        .into(),
+                        // Limit exceeded
+                        code: -32005,
+                        data: Some(json!({
+                            "response_bytes": bytes,
+                            "max_response_bytes": max,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::ResponseBufferFull {
+                needed,
+                buffered,
+                max,
+            } => {
+                warn!(needed, buffered, max, "ResponseBufferFull");
+
+                shed_response(
+                    ShedReason::ProxyOverloaded,
+                    Duration::from_secs(1),
+                    "server overloaded. narrow your query".into(),
+                    &request_for_error,
+                    json!({
+                        "response_bytes": needed,
+                    }),
+                )
+            }
+            Self::MethodNotAllowed(method) => {
+                trace!(%method, "MethodNotAllowed");
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: format!("{} is not allowed for this rpc key", method).into(),
+                        // Method not found
+                        code: -32601,
+                        data: Some(json!({
+                            "method": method,
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::MethodNotFound(method) => {
                 warn!("MethodNotFound: {}", method);
                 (
@@ -843,6 +1294,20 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::MethodTemporarilyUnavailable(method) => {
+                warn!("MethodTemporarilyUnavailable: {}", method);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: "Method temporarily unavailable".into(),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+                        data: Some(json!({
+                            "method": method,
+                            "extra": "the servers that support this method are busy or not synced. try again soon",
+                        })),
+                    },
+                )
+            }
             Self::NoBlockNumberOrHash => {
                 warn!("NoBlockNumberOrHash");
                 (
@@ -909,6 +1374,18 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::MultipleRpcKeys => {
+                trace!("MultipleRpcKeys");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: "the rpc key in the url and the rpc key in the Authorization header are different"
+                            .into(),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::NoVolatileRedisDatabase => {
                 error!("no volatile redis database configured");
                 (
@@ -1055,6 +1532,74 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::ProtocolNotAllowed(msg) => {
+                trace!(%msg, "ProtocolNotAllowed");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: msg.clone(),
+                        // Invalid Request
+                        code: -32600,
+                        data: Some(json!({
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::PaginatedLogsTooLarge {
+                from,
+                to,
+                results,
+                bytes,
+                max_results,
+                max_bytes,
+            } => {
+                trace!(%from, %to, %results, %bytes, "PaginatedLogsTooLarge");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: "too many logs for one auto paginated query".into(),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: Some(json!({
+                            "exceeded_in_range": {
+                                "from": from,
+                                "to": to,
+                            },
+                            "results": results,
+                            "bytes": bytes,
+                            "max_results": max_results,
+                            "max_bytes": max_bytes,
+                            "suggestion": "query a smaller range or add an address or topics",
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::NotEntitled {
+                entitlement,
+                method,
+                tier,
+            } => {
+                trace!(%entitlement, %method, ?tier, "NotEntitled");
+                (
+                    StatusCode::PAYMENT_REQUIRED,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "{} requests are not included in your plan. activate premium to use {}",
+                            entitlement, method
+                        )
+                        .into(),
+                        code: StatusCode::PAYMENT_REQUIRED.as_u16().into(),
+                        data: Some(json!({
+                            "entitlement": entitlement,
+                            "method": method,
+                            "tier": tier,
+                            "required_plan": "Premium",
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::PaymentRequired => {
                 trace!("PaymentRequiredError");
                 (
@@ -1066,6 +1611,47 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::QuotaExceeded(authorization, exceeded) => {
+                trace!(?exceeded, "QuotaExceeded");
+
+                let message = match exceeded.period {
+                    QuotaPeriod::Day => "daily request quota exceeded",
+                    QuotaPeriod::Month => "monthly request quota exceeded",
+                };
+
+                let retry_after = (exceeded.reset - Utc::now()).num_seconds().max(1);
+
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: message.into(),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(json!({
+                            "key_id": authorization.checks.rpc_secret_key_id,
+                            "limit": exceeded.limit,
+                            "period": exceeded.period,
+                            "reset": exceeded.reset.timestamp(),
+                            "retry_after": retry_after,
+                        })),
+                    },
+                )
+            }
+            Self::RangeInFuture { to, head } => {
+                trace!(?to, %head, "RangeInFuture");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: "block range extends past the head block".into(),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: Some(json!({
+                            "to": to,
+                            "head": head,
+                            "suggestion": "use \"latest\" or a block number that has been mined",
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::RangeInvalid { from, to } => {
                 trace!(?from, ?to, "RangeInvalid");
                 (
@@ -1098,36 +1684,36 @@ impl Web3ProxyError {
                             "to": to,
                             "requested": requested,
                             "allowed": allowed,
+                            "suggestion": format!("split this request into ranges of at most {} blocks", allowed),
                             "request": request_for_error,
                         })),
                     },
                 )
             }
             // TODO: this should actually by the id of the key. multiple users might control one key
-            Self::RateLimited(authorization, retry_at) => {
+            Self::RateLimited(authorization, retry_at, weight) => {
                 // TODO: emit a stat
 
                 let retry_after = if let Some(retry_at) = retry_at {
-                    retry_at.duration_since(Instant::now()).as_secs()
+                    retry_at.saturating_duration_since(Instant::now())
                 } else {
                     // TODO: what should we default to?
-                    60
+                    Duration::from_secs(60)
                 };
 
-                // create a string with either the IP or the rpc_key_id
-                let retry_data = if authorization.checks.rpc_secret_key_id.is_none() {
-                    json!({"retry_after": retry_after, "ip": authorization.ip, "request": request_for_error,})
+                // include either the IP or the rpc_key_id
+                let extra = if let Some(key_id) = authorization.checks.rpc_secret_key_id {
+                    json!({"ip": authorization.ip, "key_id": key_id, "weight": weight})
                 } else {
-                    json!({"retry_after": retry_after, "ip": authorization.ip, "key_id": authorization.checks.rpc_secret_key_id.unwrap(), "request": request_for_error,})
+                    json!({"ip": authorization.ip, "weight": weight})
                 };
 
-                (
-                    StatusCode::TOO_MANY_REQUESTS,
-                    JsonRpcErrorData {
-                        message: "too many requests".into(),
-                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
-                        data: Some(retry_data),
-                    },
+                shed_response(
+                    ShedReason::RateLimited,
+                    retry_after,
+                    "too many requests".into(),
+                    &request_for_error,
+                    extra,
                 )
             }
             Self::Redis(err) => {
@@ -1232,6 +1818,35 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::StaleHead(age) => {
+                trace!(?age, "StaleHead");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "the latest block is stale. no new heads for {}s",
+                            age.as_secs()
+                        )
+                        .into(),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+                        data: Some(json!({
+                            "head_age_secs": age.as_secs(),
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
+            Self::Standby => {
+                trace!("Standby");
+
+                shed_response(
+                    ShedReason::Standby,
+                    Duration::from_secs(5),
+                    "this server is on standby. try another".into(),
+                    &request_for_error,
+                    json!({}),
+                )
+            }
             Self::StatusCode(status_code, err_msg, data) => {
                 // different status codes should get different error levels. 500s should warn. 400s should stat
                 let code = status_code.as_u16();
@@ -1313,6 +1928,23 @@ impl Web3ProxyError {
                     method
                 );
             }
+            Self::UnfilteredRangeTooLarge { requested, allowed } => {
+                trace!(%requested, %allowed, "UnfilteredRangeTooLarge");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: "block range too large for a query without an address or topics"
+                            .into(),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: Some(json!({
+                            "requested": requested,
+                            "allowed": allowed,
+                            "suggestion": format!("add an address or topic, or split this request into ranges of at most {} blocks", allowed),
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::UnknownBlockHash(hash) => {
                 debug!(%hash, "UnknownBlockHash");
                 (
@@ -1430,6 +2062,19 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::WebsocketDisconnected(rpc) => {
+                debug!(%rpc, "WebsocketDisconnected");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    JsonRpcErrorData {
+                        message: "backend websocket disconnected".into(),
+                        code: StatusCode::BAD_GATEWAY.as_u16().into(),
+                        data: Some(json!({
+                            "request": request_for_error,
+                        })),
+                    },
+                )
+            }
             Self::WithContext(err, msg) => match err {
                 Some(err) => {
                     warn!(?err, %msg, "error w/ context");
@@ -1462,13 +2107,32 @@ impl Web3ProxyError {
     where
         R: Into<RequestForError<'a>>,
     {
+        let rate_limit = match &self {
+            Self::RateLimited(authorization, ..) => authorization.rate_limit,
+            _ => None,
+        };
+
         let (status_code, response_data) = self.as_response_parts(request_for_error);
 
         let id = id.unwrap_or_default();
 
         let response = ParsedResponse::from_response_data(response_data, id);
 
-        (status_code, Json(response)).into_response()
+        let retry_after = response.retry_after();
+
+        let mut response = (status_code, Json(response)).into_response();
+
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.into());
+        }
+
+        if let Some(rate_limit) = rate_limit {
+            insert_rate_limit_headers(response.headers_mut(), &rate_limit);
+        }
+
+        response
     }
 
     /// some things should keep going even if the db is down
@@ -1552,3 +2216,164 @@ impl Web3ProxyError {
         Message::Text(msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deferred_rate_limiter::RateLimitQuota;
+
+    /// the status code and the whole jsonrpc error that a client would see
+    fn shed(err: Web3ProxyError) -> (StatusCode, serde_json::Value) {
+        let (status_code, response) =
+            err.as_response_parts(Some(RequestForError::RequestId("01HABCDEF")));
+
+        let ForwardedResponse::RpcError { error_data, .. } = response else {
+            panic!("shed load should always be an error");
+        };
+
+        (status_code, serde_json::to_value(error_data).unwrap())
+    }
+
+    #[test]
+    fn shed_response_snapshots() {
+        let authorization = Authorization::internal().unwrap();
+
+        assert_eq!(
+            shed(Web3ProxyError::RateLimited(authorization, None, 1)),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "code": 429,
+                    "message": "too many requests",
+                    "data": {
+                        "reason": "rate_limited",
+                        "retry_after_ms": 60_000,
+                        "retry_after": 60,
+                        "request_id": "01HABCDEF",
+                        "ip": "127.0.0.1",
+                        "weight": 1,
+                        "request": {"RequestId": "01HABCDEF"},
+                    },
+                })
+            )
+        );
+
+        assert_eq!(
+            shed(Web3ProxyError::ResponseBufferFull {
+                needed: 5_000,
+                buffered: 90_000,
+                max: 90_000,
+            }),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": 503,
+                    "message": "server overloaded. narrow your query",
+                    "data": {
+                        "reason": "proxy_overloaded",
+                        "retry_after_ms": 1_000,
+                        "retry_after": 1,
+                        "request_id": "01HABCDEF",
+                        "response_bytes": 5_000,
+                        "request": {"RequestId": "01HABCDEF"},
+                    },
+                })
+            )
+        );
+
+        let window = MaintenanceWindow {
+            message: Some("upgrading erigon".to_string()),
+            backends: vec![],
+            since: Instant::now(),
+            until: None,
+        };
+
+        assert_eq!(
+            shed(Web3ProxyError::Maintenance("balanced".into(), window)),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": 503,
+                    "message": "balanced rpcs are temporarily unavailable for maintenance",
+                    "data": {
+                        "reason": "maintenance",
+                        "retry_after_ms": 60_000,
+                        "retry_after": 60,
+                        "request_id": "01HABCDEF",
+                        "group": "balanced",
+                        "message": "upgrading erigon",
+                        "request": {"RequestId": "01HABCDEF"},
+                    },
+                })
+            )
+        );
+
+        // the backends were free again before the error was built
+        assert_eq!(
+            shed(Web3ProxyError::BackendSaturated(Instant::now())),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": 503,
+                    "message": "backend rpcs are all rate limited",
+                    "data": {
+                        "reason": "backend_saturated",
+                        "retry_after_ms": 0,
+                        "retry_after": 0,
+                        "request_id": "01HABCDEF",
+                        "request": {"RequestId": "01HABCDEF"},
+                    },
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn shed_responses_have_backpressure() {
+        for err in [
+            Web3ProxyError::BackendSaturated(Instant::now() + Duration::from_millis(1_500)),
+            Web3ProxyError::ResponseBufferFull {
+                needed: 1,
+                buffered: 1,
+                max: 1,
+            },
+        ] {
+            let (_, response) = err.as_response_parts(None::<RequestForError>);
+
+            let ForwardedResponse::RpcError { error_data, .. } = response else {
+                panic!("shed load should always be an error");
+            };
+
+            let x = Backpressure::from_error_data(error_data.data.as_ref().unwrap()).unwrap();
+
+            assert!(x.retry_after_ms <= 1_500);
+            assert_eq!(x.retry_after, x.retry_after_ms.div_ceil(1000));
+            assert_eq!(x.request_id, None);
+        }
+    }
+
+    #[test]
+    fn rate_limited_headers() {
+        let mut authorization = Authorization::internal().unwrap();
+
+        let reset = Instant::now() + Duration::from_millis(2_500);
+
+        authorization.rate_limit = Some(RateLimitQuota {
+            limit: 10,
+            remaining: 0,
+            reset,
+        });
+
+        let response = Web3ProxyError::RateLimited(authorization, Some(reset), 8)
+            .into_response_with_id(None, None::<RequestForError>);
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let headers = response.headers();
+
+        assert_eq!(headers[RETRY_AFTER], "3");
+        assert_eq!(headers["X-RateLimit-Limit"], "10");
+        assert_eq!(headers["X-RateLimit-Remaining"], "0");
+        assert_eq!(headers["X-RateLimit-Reset"], "3");
+    }
+}
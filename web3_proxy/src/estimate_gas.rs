@@ -0,0 +1,196 @@
+//! Ask more than one backend for `eth_estimateGas`.
+//!
+//! Backends sometimes disagree about gas estimates, and a single node that is slightly behind or buggy can hand out
+//! an estimate that is too low to land. When this is turned on, the estimate is requested from several backends at
+//! once and their answers are combined. A revert only counts once every backend that answered agrees on it. Timeouts
+//! and other infrastructure errors from a single backend are ignored as long as another backend gave an estimate.
+
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+
+/// How the successful estimates from different backends are combined into one response
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EstimateGasCombine {
+    /// the middle estimate. with an even number of estimates, the higher of the two middle ones
+    #[default]
+    Median,
+    /// the largest estimate
+    Max,
+}
+
+/// Settings for sending `eth_estimateGas` to more than one backend
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EstimateGasFanout {
+    /// How many backends to ask at the same time. 0 or 1 turns this off.
+    #[serde_inline_default(0usize)]
+    pub max_rpcs: usize,
+
+    /// How to combine the estimates.
+    #[serde(default = "Default::default")]
+    pub combine: EstimateGasCombine,
+
+    /// Only users in these tiers (by title) get the fan out. Empty allows everyone, including anonymous users.
+    #[serde_inline_default(vec![])]
+    pub tiers: Vec<String>,
+}
+
+impl Default for EstimateGasFanout {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+impl EstimateGasFanout {
+    /// How many backends a request from a user in this tier should be sent to. None if the fan out is off for them.
+    pub fn max_rpcs_for(&self, user_tier_title: Option<&str>) -> Option<usize> {
+        if self.max_rpcs <= 1 {
+            return None;
+        }
+
+        if !self.tiers.is_empty() {
+            let user_tier_title = user_tier_title?;
+
+            if !self.tiers.iter().any(|x| x == user_tier_title) {
+                return None;
+            }
+        }
+
+        Some(self.max_rpcs)
+    }
+}
+
+/// Combine the results from every backend that was asked.
+///
+/// Returns the estimate and, if at least two backends gave one, the spread between the largest and smallest
+/// estimates in basis points of the returned estimate.
+pub fn combine_estimates(
+    results: Vec<Web3ProxyResult<U256>>,
+    combine: EstimateGasCombine,
+) -> Web3ProxyResult<(U256, Option<u64>)> {
+    let mut estimates = Vec::with_capacity(results.len());
+    let mut reverted = None;
+    let mut other_error = None;
+
+    for result in results {
+        match result {
+            Ok(x) => estimates.push(x),
+            Err(err @ Web3ProxyError::JsonRpcErrorData(_)) => {
+                reverted.get_or_insert(err);
+            }
+            Err(err) => {
+                other_error.get_or_insert(err);
+            }
+        }
+    }
+
+    if estimates.is_empty() {
+        // a jsonrpc error is the backend telling the user something about their transaction. prefer it
+        return Err(reverted
+            .or(other_error)
+            .unwrap_or(Web3ProxyError::NoServersSynced));
+    }
+
+    estimates.sort_unstable();
+
+    let estimate = match combine {
+        EstimateGasCombine::Median => estimates[estimates.len() / 2],
+        EstimateGasCombine::Max => estimates[estimates.len() - 1],
+    };
+
+    let dispersion_bps = if estimates.len() < 2 || estimate.is_zero() {
+        None
+    } else {
+        let spread = estimates[estimates.len() - 1] - estimates[0];
+
+        let bps = spread.saturating_mul(U256::from(10_000)) / estimate;
+
+        Some(if bps > U256::from(u64::MAX) {
+            u64::MAX
+        } else {
+            bps.as_u64()
+        })
+    };
+
+    Ok((estimate, dispersion_bps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::JsonRpcErrorData;
+
+    fn reverted() -> Web3ProxyError {
+        Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            code: 3,
+            message: "execution reverted".into(),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn combine() {
+        let results = || {
+            vec![
+                Ok(U256::from(21_000)),
+                Err(Web3ProxyError::Timeout(None)),
+                Ok(U256::from(25_000)),
+                Err(reverted()),
+                Ok(U256::from(22_000)),
+            ]
+        };
+
+        let (median, dispersion) =
+            combine_estimates(results(), EstimateGasCombine::Median).unwrap();
+        assert_eq!(median, U256::from(22_000));
+        assert_eq!(dispersion, Some(4_000 * 10_000 / 22_000));
+
+        let (max, _) = combine_estimates(results(), EstimateGasCombine::Max).unwrap();
+        assert_eq!(max, U256::from(25_000));
+
+        // a single estimate has nothing to compare against
+        let (x, dispersion) = combine_estimates(
+            vec![Ok(U256::from(21_000)), Err(Web3ProxyError::Timeout(None))],
+            EstimateGasCombine::Median,
+        )
+        .unwrap();
+        assert_eq!(x, U256::from(21_000));
+        assert_eq!(dispersion, None);
+    }
+
+    #[test]
+    fn errors() {
+        // the revert is the real answer even if another backend had trouble
+        let err = combine_estimates(
+            vec![Err(Web3ProxyError::Timeout(None)), Err(reverted())],
+            EstimateGasCombine::Median,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Web3ProxyError::JsonRpcErrorData(_)));
+
+        let err = combine_estimates(
+            vec![Err(Web3ProxyError::Timeout(None))],
+            EstimateGasCombine::Median,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Web3ProxyError::Timeout(_)));
+    }
+
+    #[test]
+    fn tiers() {
+        let mut x = EstimateGasFanout::default();
+        assert_eq!(x.max_rpcs_for(None), None);
+
+        x.max_rpcs = 3;
+        assert_eq!(x.max_rpcs_for(None), Some(3));
+
+        x.tiers = vec!["Premium".to_string()];
+        assert_eq!(x.max_rpcs_for(None), None);
+        assert_eq!(x.max_rpcs_for(Some("Free")), None);
+        assert_eq!(x.max_rpcs_for(Some("Premium")), Some(3));
+    }
+}
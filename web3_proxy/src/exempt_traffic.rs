@@ -0,0 +1,230 @@
+//! Load balancer health checks and uptime bots.
+//!
+//! These used to be handled like any other anonymous request. They showed up in stats, and sometimes they hit the
+//! public rate limit. Then the load balancer marked this server as down. Exempt requests skip rate limits and stats
+//! completely. `frontend::exempt` answers them from memory, and each one is counted here so that their volume still
+//! shows up in `/admin/summary`.
+
+use arc_swap::ArcSwap;
+use http::Method;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub use web3_proxy_client::types::ExemptTrafficSummary;
+
+/// Which requests are exempt besides HEAD, OPTIONS, and `/health`. Changes to these are applied without a restart.
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExemptTrafficConfig {
+    /// Requests to the public rpc from these networks. Usually the load balancer's subnet.
+    /// Only a few methods are answered for them, so don't list networks that real clients use.
+    #[serde_inline_default(vec![])]
+    pub cidrs: Vec<IpNet>,
+
+    /// Requests to the public rpc with a user agent that starts with one of these. Matching ignores case.
+    #[serde_inline_default(vec![])]
+    pub user_agent_prefixes: Vec<String>,
+}
+
+impl Default for ExemptTrafficConfig {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExemptReason {
+    /// HEAD and OPTIONS never get proxied to the backends
+    HeadOrOptions,
+    Health,
+    Cidr,
+    UserAgent,
+}
+
+impl ExemptTrafficConfig {
+    /// None if the request should be handled like any other.
+    /// The cidrs and user agents only exempt the public rpc. Keyed requests are billed to their key no matter who sends them
+    pub fn classify(
+        &self,
+        method: &Method,
+        path: &str,
+        ip: IpAddr,
+        user_agent: Option<&str>,
+    ) -> Option<ExemptReason> {
+        if method == Method::HEAD || method == Method::OPTIONS {
+            return Some(ExemptReason::HeadOrOptions);
+        }
+
+        let path = path.trim_end_matches('/');
+
+        if path == "/health" {
+            return Some(ExemptReason::Health);
+        }
+
+        if !path.is_empty() {
+            return None;
+        }
+
+        if self.cidrs.iter().any(|x| x.contains(&ip)) {
+            return Some(ExemptReason::Cidr);
+        }
+
+        if let Some(user_agent) = user_agent {
+            if self.user_agent_prefixes.iter().any(|x| {
+                user_agent
+                    .get(..x.len())
+                    .is_some_and(|y| y.eq_ignore_ascii_case(x))
+            }) {
+                return Some(ExemptReason::UserAgent);
+            }
+        }
+
+        None
+    }
+}
+
+/// The classifier and its counters. The counters are totals since the server started
+#[derive(Default)]
+pub struct ExemptTraffic {
+    config: ArcSwap<ExemptTrafficConfig>,
+    head_or_options: AtomicU64,
+    health: AtomicU64,
+    cidr: AtomicU64,
+    user_agent: AtomicU64,
+}
+
+impl ExemptTraffic {
+    pub fn new(config: ExemptTrafficConfig) -> Self {
+        Self {
+            config: ArcSwap::from_pointee(config),
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> Arc<ExemptTrafficConfig> {
+        self.config.load_full()
+    }
+
+    pub fn set_config(&self, config: ExemptTrafficConfig) {
+        self.config.store(Arc::new(config));
+    }
+
+    /// Counts the request if it is exempt
+    pub fn classify(
+        &self,
+        method: &Method,
+        path: &str,
+        ip: IpAddr,
+        user_agent: Option<&str>,
+    ) -> Option<ExemptReason> {
+        let reason = self.config.load().classify(method, path, ip, user_agent)?;
+
+        let counter = match reason {
+            ExemptReason::HeadOrOptions => &self.head_or_options,
+            ExemptReason::Health => &self.health,
+            ExemptReason::Cidr => &self.cidr,
+            ExemptReason::UserAgent => &self.user_agent,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Some(reason)
+    }
+
+    pub fn summary(&self) -> ExemptTrafficSummary {
+        ExemptTrafficSummary {
+            head_or_options: self.head_or_options.load(Ordering::Relaxed),
+            health: self.health.load(Ordering::Relaxed),
+            cidr: self.cidr.load(Ordering::Relaxed),
+            user_agent: self.user_agent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[test]
+    fn classify() {
+        let x = ExemptTraffic::new(
+            serde_json::from_value(json!({
+                "cidrs": ["10.0.0.0/8"],
+                "user_agent_prefixes": ["ELB-HealthChecker"],
+            }))
+            .unwrap(),
+        );
+
+        let lb: IpAddr = "10.1.2.3".parse().unwrap();
+
+        assert_eq!(
+            x.classify(&Method::HEAD, "/rpc/abc", localhost(), None),
+            Some(ExemptReason::HeadOrOptions)
+        );
+        assert_eq!(
+            x.classify(&Method::OPTIONS, "/", localhost(), None),
+            Some(ExemptReason::HeadOrOptions)
+        );
+        assert_eq!(
+            x.classify(&Method::GET, "/health/", localhost(), None),
+            Some(ExemptReason::Health)
+        );
+        assert_eq!(
+            x.classify(&Method::POST, "/", lb, None),
+            Some(ExemptReason::Cidr)
+        );
+        assert_eq!(
+            x.classify(
+                &Method::POST,
+                "/",
+                localhost(),
+                Some("elb-healthchecker/2.0")
+            ),
+            Some(ExemptReason::UserAgent)
+        );
+
+        // everyone else is a user
+        assert_eq!(x.classify(&Method::POST, "/", localhost(), None), None);
+        assert_eq!(
+            x.classify(&Method::POST, "/", localhost(), Some("ELB")),
+            None
+        );
+
+        // keyed requests are billed to the key
+        assert_eq!(x.classify(&Method::POST, "/rpc/abc", lb, None), None);
+        assert_eq!(x.classify(&Method::GET, "/status", lb, None), None);
+
+        assert_eq!(
+            x.summary(),
+            ExemptTrafficSummary {
+                head_or_options: 2,
+                health: 1,
+                cidr: 1,
+                user_agent: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_cidrs_are_an_error() {
+        assert!(serde_json::from_value::<ExemptTrafficConfig>(json!({
+            "cidrs": ["10.0.0.0/33"],
+        }))
+        .is_err());
+
+        assert_eq!(
+            serde_json::from_value::<ExemptTrafficConfig>(json!({})).unwrap(),
+            ExemptTrafficConfig::default()
+        );
+    }
+}
@@ -1,13 +1,20 @@
 //! Handle admin helper logic
 
 use super::authorization::login_is_authorized;
+use super::client_ip::ClientIp;
 use crate::admin_queries::query_admin_modify_usertier;
 use crate::app::App;
+use crate::bans::{BanEntry, BanKey};
+use crate::cache_invalidation::Invalidation;
 use crate::errors::Web3ProxyResponse;
-use crate::errors::{Web3ProxyError, Web3ProxyErrorContext};
+use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::users::authentication::PostLogin;
-use crate::globals::{global_db_conn, global_db_replica_conn};
+use crate::globals::{global_db_conn, global_db_replica_conn, DatabaseError};
+use crate::log_filter::LogFilter;
+use crate::memory::memory_report;
 use crate::premium::{get_user_and_tier_from_address, grant_premium_tier};
+use crate::rpcs::maintenance::{MaintenanceWindow, RpcGroup};
+use crate::secrets::RpcSecretKey;
 use crate::user_token::UserBearerToken;
 use axum::{
     extract::{Path, Query, State},
@@ -15,18 +22,21 @@ use axum::{
     response::IntoResponse,
     Json, TypedHeader,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
 use entities::{
-    admin, admin_increase_balance_receipt, admin_trail, login, pending_login, rpc_key, user,
+    admin, admin_increase_balance_receipt, admin_trail, login, pending_login, rpc_key, tx_origin,
+    user,
+};
+use ethers::{
+    prelude::Address,
+    types::{Bytes, TxHash},
 };
-use ethers::{prelude::Address, types::Bytes};
 use hashbrown::HashMap;
 use http::StatusCode;
 use migration::sea_orm::prelude::{Decimal, Uuid};
 use migration::sea_orm::{
-    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
     TransactionTrait,
 };
 use serde::{Deserialize, Serialize};
@@ -34,10 +44,51 @@ use serde_json::json;
 use siwe::{Message, VerificationOpts};
 use std::ops::Add;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use time::{Duration, OffsetDateTime};
 use tracing::{info, trace, warn};
 use ulid::Ulid;
+use web3_proxy_client::types::{
+    AdminBackends, AdminLogFilterPut, AdminResponseCacheDelete, AdminResponseCachePost,
+    AdminStandbyPost, AdminSummary, BackendDisabled, BackendSummaries, CallCacheDeleted,
+    Connectivity, ResponseCacheDeleted, ResponseCacheStatus, ResponseCacheWrites,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminBanPost {
+    pub key: BanKey,
+    pub duration_secs: u64,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminBanDelete {
+    pub key: BanKey,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminMaintenancePost {
+    pub group: RpcGroup,
+    /// only put these backends into maintenance. if empty, the whole group is. balanced rpcs must name some
+    #[serde(default)]
+    pub backends: Vec<String>,
+    /// end the maintenance automatically after this long. if None, it lasts until it is deleted
+    pub duration_secs: Option<u64>,
+    /// shown to users in the error
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminMaintenanceDelete {
+    pub group: RpcGroup,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminRpcKeyTagsPost {
+    /// replaces all of the key's tags. empty clears them
+    pub internal_tags: Vec<String>,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AdminIncreaseBalancePost {
@@ -134,7 +185,7 @@ pub async fn admin_change_user_roles(
 #[debug_handler]
 pub async fn admin_imitate_login_get(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Path(mut params): Path<HashMap<String, String>>,
 ) -> Web3ProxyResponse {
     // First check if the login is authorized
@@ -291,7 +342,7 @@ pub async fn admin_imitate_login_get(
 #[debug_handler]
 pub async fn admin_imitate_login_post(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Json(payload): Json<PostLogin>,
 ) -> Web3ProxyResponse {
     login_is_authorized(&app, ip).await?;
@@ -457,3 +508,714 @@ pub async fn admin_imitate_login_post(
 
     Ok(response)
 }
+
+/// error unless the bearer token belongs to an admin
+async fn bearer_is_admin(app: &App, bearer: Bearer) -> Web3ProxyResult<user::Model> {
+    let caller = app
+        .bearer_is_authorized(bearer)
+        .await?
+        .ok_or(Web3ProxyError::InvalidUserKey)?;
+
+    let db_replica = global_db_replica_conn()?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::AccessDenied("not an admin".into()))?;
+
+    Ok(caller)
+}
+
+/// `GET /admin/bans` -- As an admin, list the active bans on this server
+#[debug_handler]
+pub async fn admin_bans_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    Ok(Json(app.bans.as_json()).into_response())
+}
+
+/// `POST /admin/bans` -- As an admin, ban an ip or rpc key on this server
+///
+/// - duration_secs is how long the ban lasts. at most `ban_max_duration_secs`
+/// - duration_secs is how long the ban lasts
+#[debug_handler]
+pub async fn admin_bans_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminBanPost>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let entry = BanEntry {
+        until: app.bans.manual_ban_until(payload.duration_secs)?,
+        offenses: 0,
+        reason: payload.reason.unwrap_or_else(|| "admin".into()).into(),
+    };
+
+    info!(admin=%caller.id, key=%payload.key, duration_secs=payload.duration_secs, "admin ban");
+
+    app.bans.insert(payload.key, entry).await;
+
+    let out = json!({
+        "key": payload.key,
+        "duration_secs": payload.duration_secs,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `DELETE /admin/bans` -- As an admin, lift a ban on this server and forget its violations
+#[debug_handler]
+pub async fn admin_bans_delete(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminBanDelete>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let was_banned = app.bans.remove(&payload.key).await;
+
+    info!(admin=%caller.id, key=%payload.key, was_banned, "admin unban");
+
+    let out = json!({
+        "key": payload.key,
+        "was_banned": was_banned,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/backends` -- As an admin, see every backend on this server with its limits, head block, and health
+#[debug_handler]
+pub async fn admin_backends_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let out = AdminBackends {
+        balanced: app.balanced_rpcs.backend_statuses(),
+        private: app.protected_rpcs.backend_statuses(),
+        bundler_4337: app.bundler_4337_rpcs.backend_statuses(),
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `POST /admin/backends/:name/disable` -- As an admin, take a backend out of rotation on this server.
+/// It keeps following the chain. It stays disabled through config reloads until it is enabled or the server restarts.
+/// At least one balanced rpc must stay enabled.
+#[debug_handler]
+pub async fn admin_backend_disable_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+) -> Web3ProxyResponse {
+    admin_backend_set_disabled(app, bearer, name, true).await
+}
+
+/// `POST /admin/backends/:name/enable` -- As an admin, put a disabled backend back into rotation on this server
+#[debug_handler]
+pub async fn admin_backend_enable_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(name): Path<String>,
+) -> Web3ProxyResponse {
+    admin_backend_set_disabled(app, bearer, name, false).await
+}
+
+async fn admin_backend_set_disabled(
+    app: Arc<App>,
+    bearer: Bearer,
+    name: String,
+    disabled: bool,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    for group in [RpcGroup::Balanced, RpcGroup::Private, RpcGroup::Bundler4337] {
+        let keep_one = group == RpcGroup::Balanced;
+
+        let Some(previous) = app
+            .rpc_group(group)
+            .set_admin_disabled(&name, disabled, keep_one)?
+        else {
+            continue;
+        };
+
+        warn!(admin=%caller.id, %group, backend=%name, disabled, previous, "admin toggled a backend");
+
+        let out = BackendDisabled {
+            name,
+            group: group.to_string(),
+            disabled,
+            previous,
+        };
+
+        return Ok(Json(out).into_response());
+    }
+
+    Err(Web3ProxyError::StatusCode(
+        StatusCode::NOT_FOUND,
+        format!("unknown backend: {}", name).into(),
+        None,
+    ))
+}
+
+/// `GET /admin/backends/scores` -- As an admin, see how each balanced rpc scored over the last window and the config
+/// changes that those scores suggest. Nothing is applied automatically.
+#[debug_handler]
+pub async fn admin_backend_scores_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let backend_scorer = app.backend_scorer.as_ref().ok_or_else(|| {
+        Web3ProxyError::StatusCode(
+            StatusCode::NOT_IMPLEMENTED,
+            "backend scoring is off".into(),
+            None,
+        )
+    })?;
+
+    let report = backend_scorer.latest().ok_or_else(|| {
+        Web3ProxyError::StatusCode(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the backends have not been scored yet".into(),
+            None,
+        )
+    })?;
+
+    Ok(Json(report.as_ref()).into_response())
+}
+
+/// `GET /admin/cache_revalidation` -- As an admin, see how often cached responses go stale and suggested ttls per method
+#[debug_handler]
+pub async fn admin_cache_revalidation_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    Ok(Json(app.cache_revalidation.as_json()).into_response())
+}
+
+/// `GET /admin/response_cache` -- As an admin, see the size of the response cache and if writes are paused on this server
+#[debug_handler]
+pub async fn admin_response_cache_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let out = ResponseCacheStatus {
+        entries: app.jsonrpc_response_cache.entry_count(),
+        weighted_size: app.jsonrpc_response_cache.weighted_size(),
+        writes: app.response_cache_writes.load(Ordering::Relaxed),
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `POST /admin/response_cache` -- As an admin, pause or resume writes to the response cache on this server.
+/// During an incident, this keeps bad answers out of the cache without sending every request to the backends.
+#[debug_handler]
+pub async fn admin_response_cache_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminResponseCachePost>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let previous = app
+        .response_cache_writes
+        .swap(payload.writes, Ordering::Relaxed);
+
+    warn!(admin=%caller.id, writes=payload.writes, previous, "admin set response cache writes");
+
+    let out = ResponseCacheWrites {
+        previous,
+        writes: payload.writes,
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `DELETE /admin/response_cache` -- As an admin, clear the response cache.
+/// `{"backend": "name"}` only removes the responses that came from that backend. `{"key": 123}` only removes one response.
+/// `removed` counts this server. With redis, the other servers are told to do the same.
+#[debug_handler]
+pub async fn admin_response_cache_delete(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminResponseCacheDelete>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let removed = match payload.key {
+        Some(key) => {
+            let mut removed = app.jsonrpc_response_cache.remove(&key).await.is_some();
+
+            if let Some(x) = app.archive_response_cache.as_ref() {
+                removed |= x.remove(&key).await.is_some();
+            }
+
+            app.jsonrpc_response_cache_sources.invalidate(&key).await;
+
+            removed as u64
+        }
+        None => app.purge_response_cache(payload.backend.as_deref()).await,
+    };
+
+    app.publish_invalidation(Invalidation::ResponseCache {
+        key: payload.key,
+        backend: payload.backend.clone(),
+    });
+
+    warn!(admin=%caller.id, backend=?payload.backend, key=?payload.key, removed, "admin cleared the response cache");
+
+    let out = ResponseCacheDeleted {
+        backend: payload.backend,
+        key: payload.key,
+        removed,
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `POST /admin/cache/clear` -- As an admin, clear the whole response cache. The same as `DELETE /admin/response_cache` with an empty body
+#[debug_handler]
+pub async fn admin_cache_clear_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let removed = app.purge_response_cache(None).await;
+
+    app.publish_invalidation(Invalidation::ResponseCache {
+        key: None,
+        backend: None,
+    });
+
+    warn!(admin=%caller.id, removed, "admin cleared the response cache");
+
+    let out = ResponseCacheDeleted {
+        backend: None,
+        key: None,
+        removed,
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/standby` -- As an admin, see if this server is a warm standby and how its cache warming is going
+#[debug_handler]
+pub async fn admin_standby_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    Ok(Json(app.standby.status(None)).into_response())
+}
+
+/// `POST /admin/standby` -- As an admin, promote (`{"standby": false}`) or demote (`{"standby": true}`) this server.
+/// Only a flag changes. The backends stay connected, so a promoted server serves its next request.
+/// Websockets that are already open when a server is demoted are not closed.
+#[debug_handler]
+pub async fn admin_standby_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminStandbyPost>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let previous = app.standby.set(payload.standby);
+
+    warn!(admin=%caller.id, standby=payload.standby, previous, "admin set standby");
+
+    Ok(Json(app.standby.status(Some(previous))).into_response())
+}
+
+/// `DELETE /admin/call_cache/:address` -- As an admin, remove every cached `eth_call` to a contract. Use this after the contract is upgraded.
+#[debug_handler]
+pub async fn admin_call_cache_delete(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(address): Path<Address>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let removed = app.call_cache.purge(address).await;
+
+    app.publish_invalidation(Invalidation::CallCache { address });
+
+    warn!(admin=%caller.id, ?address, removed, "admin cleared the call cache for a contract");
+
+    let out = CallCacheDeleted {
+        address,
+        configured: app.config.call_cache.contains_key(&address),
+        removed,
+    };
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/maintenance` -- As an admin, see which groups of rpcs are in planned maintenance on this server
+#[debug_handler]
+pub async fn admin_maintenance_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let out: HashMap<_, _> = [RpcGroup::Balanced, RpcGroup::Private, RpcGroup::Bundler4337]
+        .into_iter()
+        .map(|x| (x, app.rpc_group(x).maintenance()))
+        .collect();
+
+    Ok(Json(out).into_response())
+}
+
+/// `POST /admin/maintenance` -- As an admin, put a group of rpcs (or some of its backends) into planned maintenance on this server.
+/// Requests for a group in maintenance get a 503 with a retry hint instead of going to any other group.
+/// The balanced rpcs can only have some of their backends in maintenance. At least one must remain.
+#[debug_handler]
+pub async fn admin_maintenance_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminMaintenancePost>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let since = tokio::time::Instant::now();
+
+    let window = MaintenanceWindow {
+        message: payload.message,
+        backends: payload.backends,
+        since,
+        until: payload
+            .duration_secs
+            .map(|x| since + std::time::Duration::from_secs(x)),
+    };
+
+    let keep_one = payload.group == RpcGroup::Balanced;
+
+    let previous = app
+        .rpc_group(payload.group)
+        .start_maintenance(window.clone(), keep_one)?;
+
+    warn!(admin=%caller.id, group=%payload.group, backends=?window.backends, duration_secs=?payload.duration_secs, "admin started maintenance");
+
+    let out = json!({
+        "group": payload.group,
+        "maintenance": window,
+        "previous": previous,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `DELETE /admin/maintenance` -- As an admin, end planned maintenance on a group of rpcs on this server
+#[debug_handler]
+pub async fn admin_maintenance_delete(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminMaintenanceDelete>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let ended = app.rpc_group(payload.group).end_maintenance();
+
+    warn!(admin=%caller.id, group=%payload.group, was_active=ended.is_some(), "admin ended maintenance");
+
+    let out = json!({
+        "group": payload.group,
+        "ended": ended,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/log_filter` -- As an admin, see this server's log filter and when it goes back to the startup filter
+#[debug_handler]
+pub async fn admin_log_filter_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let out = LogFilter::global()?.status();
+
+    Ok(Json(out).into_response())
+}
+
+/// `PUT /admin/log_filter` -- As an admin, change this server's log filter without restarting.
+/// The filter uses `RUST_LOG` syntax and applies to every log layer. `revert_after_secs` puts the startup filter back.
+/// Every change is saved in the admin trail.
+#[debug_handler]
+pub async fn admin_log_filter_put(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminLogFilterPut>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let log_filter = LogFilter::global()?;
+
+    let filter = payload.filter.trim();
+
+    // check before saving the trail so that typos don't clutter it
+    LogFilter::validate(filter)?;
+
+    let db_conn = global_db_conn()?;
+
+    let trail = admin_trail::ActiveModel {
+        caller: sea_orm::Set(caller.id),
+        imitating_user: sea_orm::Set(None),
+        endpoint: sea_orm::Set("admin_log_filter_put".to_string()),
+        payload: sea_orm::Set(serde_json::to_string(&payload)?),
+        ..Default::default()
+    };
+
+    // the change is not allowed unless it is audited
+    trail
+        .save(&db_conn)
+        .await
+        .web3_context("saving admin trail for log filter change")?;
+
+    let out = log_filter.set(
+        filter,
+        payload
+            .revert_after_secs
+            .map(std::time::Duration::from_secs),
+    )?;
+
+    warn!(admin=%caller.id, filter=%out.filter, revert_at=?out.revert_at, "admin changed the log filter");
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/memory` -- As an admin, see entry counts and estimated bytes for every large in-memory structure
+#[debug_handler]
+pub async fn admin_memory_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    Ok(Json(memory_report(&app, true)).into_response())
+}
+
+/// `GET /admin/summary` -- As an admin, see the numbers a dashboard needs for this server in one request.
+/// Everything comes from state that the app already keeps in memory. Nothing here waits on a backend or a database.
+#[debug_handler]
+pub async fn admin_summary_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    Ok(Json(admin_summary(&app)).into_response())
+}
+
+/// This is not async on purpose. It must stay fast no matter what the backends and databases are doing
+pub fn admin_summary(app: &App) -> AdminSummary {
+    let head_block = app.watch_consensus_head_receiver.borrow().clone();
+
+    let head_block_num = head_block.as_ref().map(|x| x.number());
+
+    // None means that it is not configured
+    let db = match global_db_conn() {
+        Ok(_) => Some(
+            app.stat_buffer_status
+                .as_ref()
+                .map(|x| x.relational_ok.load(Ordering::Relaxed))
+                .unwrap_or(true),
+        ),
+        Err(DatabaseError::NotConfigured) => None,
+        Err(_) => Some(false),
+    };
+
+    let influx = app.influxdb_client().ok().map(|_| {
+        app.stat_buffer_status
+            .as_ref()
+            .map(|x| x.timeseries_ok.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    });
+
+    // the pool drops connections that fail
+    let redis = app.vredis_pool.as_ref().map(|x| x.status().size > 0);
+
+    AdminSummary {
+        backends: BackendSummaries {
+            balanced: app.balanced_rpcs.summaries(head_block_num),
+            private: app.protected_rpcs.summaries(head_block_num),
+        },
+        chain_id: app.config.chain_id,
+        connectivity: Connectivity { db, influx, redis },
+        exempt_traffic: app.exempt_traffic.summary(),
+        head_block_hash: head_block.as_ref().map(|x| *x.hash()),
+        head_block_num,
+        hostname: app.hostname.clone(),
+        latency: app.latency_slo.summary(),
+        requests: app.recent_requests.summary(),
+        stat_buffer: app.stat_buffer_status.as_ref().map(|x| x.summary()),
+        synced: app.balanced_rpcs.synced(),
+        uptime: app.start.elapsed().as_secs(),
+    }
+}
+
+/// `GET /admin/rpc_key_tags/:key_id` -- As an admin, see an rpc key's label and internal tags
+#[debug_handler]
+pub async fn admin_rpc_key_tags_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    bearer_is_admin(&app, bearer).await?;
+
+    let db_replica = global_db_replica_conn()?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(key_id)
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::BadRequest("rpc key not found".into()))?;
+
+    Ok(Json(rpc_key_tags_json(&rpc_key)).into_response())
+}
+
+/// `POST /admin/rpc_key_tags/:key_id` -- As an admin, set an rpc key's internal tags.
+/// These show up in our request logs but are never shown to the key's owner.
+#[debug_handler]
+pub async fn admin_rpc_key_tags_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(key_id): Path<u64>,
+    Json(payload): Json<AdminRpcKeyTagsPost>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let db_conn = global_db_conn()?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(key_id)
+        .one(&db_conn)
+        .await?
+        .ok_or_else(|| Web3ProxyError::BadRequest("rpc key not found".into()))?;
+
+    let internal_tags: Vec<_> = payload
+        .internal_tags
+        .iter()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    for tag in internal_tags.iter() {
+        if tag.contains(',') {
+            return Err(Web3ProxyError::BadRequest(
+                format!("tags cannot contain commas: {}", tag).into(),
+            ));
+        }
+    }
+
+    let internal_tags = if internal_tags.is_empty() {
+        None
+    } else {
+        Some(internal_tags.join(","))
+    };
+
+    info!(admin=%caller.id, %key_id, ?internal_tags, "admin rpc key tags");
+
+    let mut rpc_key = rpc_key.into_active_model();
+
+    rpc_key.internal_tags = sea_orm::Set(internal_tags);
+
+    let rpc_key = rpc_key.update(&db_conn).await?;
+
+    // the request logs read the tags from the cached authorization checks
+    let secret_key: RpcSecretKey = rpc_key.secret_key.into();
+
+    app.invalidate_rpc_key(&secret_key, rpc_key.user_id).await;
+
+    Ok(Json(rpc_key_tags_json(&rpc_key)).into_response())
+}
+
+fn rpc_key_tags_json(rpc_key: &rpc_key::Model) -> serde_json::Value {
+    let internal_tags: Vec<_> = rpc_key
+        .internal_tags
+        .as_deref()
+        .map(|x| x.split(',').collect())
+        .unwrap_or_default();
+
+    json!({
+        "internal_tags": internal_tags,
+        "key_id": rpc_key.id,
+        "label": rpc_key.label,
+        "user_id": rpc_key.user_id,
+    })
+}
+
+/// `GET /admin/tx_origin/:hash` -- As an admin, see which rpc keys sent a transaction through this proxy.
+/// Only transactions sent within `tx_origin_retention_days` are known. Every lookup is saved in the admin trail.
+#[debug_handler]
+pub async fn admin_tx_origin_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(tx_hash): Path<TxHash>,
+) -> Web3ProxyResponse {
+    let caller = bearer_is_admin(&app, bearer).await?;
+
+    let db_conn = global_db_conn()?;
+
+    info!(admin=%caller.id, ?tx_hash, "admin tx origin lookup");
+
+    let trail = admin_trail::ActiveModel {
+        caller: sea_orm::Set(caller.id),
+        imitating_user: sea_orm::Set(None),
+        endpoint: sea_orm::Set("admin_tx_origin_get".to_string()),
+        payload: sea_orm::Set(format!("{:?}", tx_hash)),
+        ..Default::default()
+    };
+
+    // the lookup is not allowed unless it is audited
+    trail
+        .save(&db_conn)
+        .await
+        .web3_context("saving admin trail for tx origin lookup")?;
+
+    let db_replica = global_db_replica_conn()?;
+
+    let origins = tx_origin::Entity::find()
+        .filter(tx_origin::Column::TxHash.eq(tx_hash.as_bytes()))
+        .order_by_asc(tx_origin::Column::Timestamp)
+        .find_also_related(rpc_key::Entity)
+        .all(db_replica.as_ref())
+        .await?;
+
+    let origins: Vec<_> = origins
+        .into_iter()
+        .map(|(origin, rpc_key)| {
+            json!({
+                "chain_id": origin.chain_id,
+                "ip_hash": Bytes::from(origin.ip_hash),
+                "rpc_key_id": origin.rpc_key_id,
+                "timestamp": origin.timestamp,
+                "user_id": rpc_key.map(|x| x.user_id),
+            })
+        })
+        .collect();
+
+    let out = json!({
+        "origins": origins,
+        "recording": app.tx_origins.is_some(),
+        "retention_days": app.config.tx_origin_retention_days,
+        "tx_hash": tx_hash,
+    });
+
+    Ok(Json(out).into_response())
+}
@@ -1,26 +1,36 @@
 //! Utilities for authorization of logged in and anonymous users.
 
+use super::client_ip::ClientIp;
 use super::rpc_proxy_ws::ProxyMode;
 use crate::app::{App, APP_USER_AGENT};
 use crate::balance::Balance;
+use crate::bans::{BanKey, Violation};
 use crate::caches::RegisteredUserRateLimitKey;
 use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
-use crate::globals::global_db_replica_conn;
-use crate::jsonrpc::{self, SingleRequest};
+use crate::globals::{global_app, global_db_replica_conn};
+use crate::jsonrpc::{self, JsonRpcRequestEnum, SingleRequest};
+use crate::quotas::QuotaExceeded;
 use crate::secrets::RpcSecretKey;
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
-use axum::headers::authorization::Bearer;
-use axum::headers::{Header, Origin, Referer, UserAgent};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, State};
+use axum::headers::authorization::{Basic, Bearer};
+use axum::headers::{Authorization as AuthorizationHeader, Header, Origin, Referer, UserAgent};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::TypedHeader;
 use chrono::Utc;
-use deferred_rate_limiter::{DeferredRateLimitResult, DeferredRateLimiter};
+use deferred_rate_limiter::{DeferredRateLimitResult, DeferredRateLimiter, RateLimitQuota};
 use derive_more::From;
 use entities::{login, rpc_key, user, user_tier};
 use ethers::types::Bytes;
 use ethers::utils::keccak256;
 use futures::TryFutureExt;
 use hashbrown::HashMap;
-use http::HeaderValue;
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use http::{HeaderMap, HeaderValue, Request};
 use ipnet::IpNet;
 use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use redis_rate_limiter::redis::AsyncCommands;
@@ -30,15 +40,36 @@ use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::fmt::{Debug, Display};
 use std::hash::{Hash, Hasher};
+use std::iter::once;
 use std::num::NonZeroU64;
 use std::{net::IpAddr, str::FromStr, sync::Arc};
 use tokio::sync::RwLock as AsyncRwLock;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::time::Instant;
-use tracing::{error, trace, warn};
+use tracing::{debug, error, trace, warn};
 use ulid::Ulid;
 use uuid::Uuid;
 
+/// the ip or key+ip limit for the current period
+pub const RATE_LIMIT_LIMIT_HEADER: &str = "X-RateLimit-Limit";
+/// how many more requests fit in the current period. weighted methods use up more than one
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+/// seconds until the period ends, rounded up
+pub const RATE_LIMIT_RESET_HEADER: &str = "X-RateLimit-Reset";
+
+/// Tell clients how much of their limit is left so that they can slow down before they get a 429
+pub fn insert_rate_limit_headers(headers: &mut HeaderMap, quota: &RateLimitQuota) {
+    let reset = quota
+        .reset
+        .saturating_duration_since(Instant::now())
+        .as_millis()
+        .div_ceil(1000) as u64;
+
+    headers.insert(RATE_LIMIT_LIMIT_HEADER, quota.limit.into());
+    headers.insert(RATE_LIMIT_REMAINING_HEADER, quota.remaining.into());
+    headers.insert(RATE_LIMIT_RESET_HEADER, reset.into());
+}
+
 /// TODO: should this have IpAddr and Origin or AuthorizationChecks?
 #[derive(Debug)]
 pub enum RateLimitResult {
@@ -48,6 +79,8 @@ pub enum RateLimitResult {
         /// when their rate limit resets and they can try more requests
         Option<Instant>,
     ),
+    /// The key used up its daily or monthly quota
+    QuotaExceeded(Authorization, QuotaExceeded),
     /// This key is not in our database. Deny access!
     UnknownKey,
 }
@@ -73,6 +106,10 @@ pub struct AuthorizationChecks {
     pub rpc_secret_key_id: Option<NonZeroU64>,
     /// if None, allow unlimited queries. inherited from the user_tier
     pub max_requests_per_period: Option<u64>,
+    /// if None, there is no daily quota. see `quotas`
+    pub max_requests_per_day: Option<u64>,
+    /// if None, there is no monthly quota
+    pub max_requests_per_month: Option<u64>,
     // if None, allow unlimited concurrent requests. inherited from the user_tier
     pub max_concurrent_requests: Option<u32>,
     /// if None, allow any Origin
@@ -91,10 +128,358 @@ pub struct AuthorizationChecks {
     /// IMPORTANT! Once confirmed by a miner, they will be public on the blockchain!
     pub private_txs: bool,
     pub proxy_mode: ProxyMode,
+    /// what this key's client has to follow. anonymous users get the permissive default
+    pub protocol: ProtocolRequirements,
+    /// if true, http responses get a signature header. see `response_signing`
+    pub sign_responses: bool,
+    /// if true, requests whose params are for another chain are forwarded anyway. see `param_chain_id`
+    pub skip_chain_id_check: bool,
+    /// if true, requests go straight to a backend instead of waiting on an identical request that is already in flight.
+    /// only set if the key asked for it and its tier allows it
+    pub skip_request_coalescing: bool,
+    /// if true, requests are never saved as stats. only background requests like the canary's set this
+    pub skip_stats: bool,
+    /// set by the key's owner. included in their stats and in our request logs
+    pub label: Option<String>,
+    /// set by admins. included in our request logs, but never shown to the key's owner
+    pub internal_tags: Option<String>,
     /// if the account had premium when this request metadata was created
     /// they might spend slightly more than they've paid, but we are okay with that
     /// TODO: we could price the request now and if its too high, downgrade. but thats more complex than we need
     pub paid_credits_used: bool,
+    /// title of the user's tier after any downgrade. None if anon
+    pub user_tier_title: Option<String>,
+    /// which expensive requests the user's tier allows. anonymous users get the permissive default
+    pub entitlements: TierEntitlements,
+    /// which methods the key's owner allows. anonymous users get the permissive default
+    pub methods: MethodRules,
+    /// when these checks were read from the database. used to reload cached keys before they expire
+    pub loaded_at: Option<Instant>,
+}
+
+impl AuthorizationChecks {
+    /// `user_tier_model` is the tier after any downgrade
+    pub fn try_from_models(
+        rpc_key_model: rpc_key::Model,
+        user_tier_model: user_tier::Model,
+        latest_balance: Arc<AsyncRwLock<Balance>>,
+        paid_credits_used: bool,
+        proxy_mode: ProxyMode,
+        rpc_secret_key: &RpcSecretKey,
+    ) -> Web3ProxyResult<Self> {
+        // before any fields are moved out of the model
+        let protocol = (&rpc_key_model).into();
+        let methods = MethodRules::try_from(&rpc_key_model)?;
+
+        // TODO: can we have sea orm handle this for us?
+        let allowed_ips: Option<Vec<IpNet>> = if let Some(allowed_ips) = rpc_key_model.allowed_ips {
+            let x = allowed_ips
+                .split(',')
+                .map(|x| x.trim().parse::<IpNet>())
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(x)
+        } else {
+            None
+        };
+
+        let allowed_origins: Option<Vec<Origin>> =
+            if let Some(allowed_origins) = rpc_key_model.allowed_origins {
+                // TODO: do this without collecting twice?
+                let x = allowed_origins
+                    .split(',')
+                    .map(|x| HeaderValue::from_str(x.trim()))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .map(|x| Origin::decode(&mut [x].iter()))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Some(x)
+            } else {
+                None
+            };
+
+        let allowed_referers: Option<Vec<Referer>> =
+            if let Some(allowed_referers) = rpc_key_model.allowed_referers {
+                let x = allowed_referers
+                    .split(',')
+                    .map(|x| {
+                        x.trim()
+                            .parse::<Referer>()
+                            .or(Err(Web3ProxyError::InvalidReferer))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Some(x)
+            } else {
+                None
+            };
+
+        let allowed_user_agents: Option<Vec<UserAgent>> =
+            if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
+                let x: Result<Vec<_>, _> = allowed_user_agents
+                    .split(',')
+                    .map(|x| {
+                        x.trim()
+                            .parse::<UserAgent>()
+                            .or(Err(Web3ProxyError::InvalidUserAgent))
+                    })
+                    .collect();
+
+                Some(x?)
+            } else {
+                None
+            };
+
+        let rpc_key_id = Some(rpc_key_model.id.try_into().context("db ids are never 0")?);
+
+        Ok(Self {
+            allowed_ips,
+            allowed_origins,
+            allowed_referers,
+            allowed_user_agents,
+            latest_balance,
+            loaded_at: Some(Instant::now()),
+            // TODO: is floating point math going to scale this correctly?
+            log_revert_chance: (rpc_key_model.log_revert_chance * u16::MAX as f64) as u16,
+            max_concurrent_requests: user_tier_model.max_concurrent_requests,
+            max_requests_per_day: rpc_key_model.max_requests_per_day,
+            max_requests_per_month: rpc_key_model.max_requests_per_month,
+            max_requests_per_period: user_tier_model.max_requests_per_period,
+            private_txs: rpc_key_model.private_txs,
+            protocol,
+            proxy_mode,
+            rpc_secret_key: Some(*rpc_secret_key),
+            rpc_secret_key_id: rpc_key_id,
+            sign_responses: rpc_key_model.sign_responses,
+            skip_chain_id_check: rpc_key_model.skip_chain_id_check,
+            skip_request_coalescing: rpc_key_model.skip_request_coalescing
+                && user_tier_model.allow_skip_request_coalescing,
+            skip_stats: false,
+            label: rpc_key_model.label,
+            internal_tags: rpc_key_model.internal_tags,
+            user_id: rpc_key_model.user_id,
+            entitlements: (&user_tier_model).into(),
+            methods,
+            user_tier_title: Some(user_tier_model.title),
+            paid_credits_used,
+        })
+    }
+}
+
+/// Per-key strictness. Some old clients need leniency and some users want their staging to fail loudly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtocolRequirements {
+    /// reject requests that don't say `"jsonrpc": "2.0"`. a request without the key is parsed as 2.0
+    pub require_jsonrpc_2: bool,
+    pub allow_batches: bool,
+    pub allow_websocket: bool,
+}
+
+impl Default for ProtocolRequirements {
+    fn default() -> Self {
+        Self {
+            require_jsonrpc_2: false,
+            allow_batches: true,
+            allow_websocket: true,
+        }
+    }
+}
+
+impl From<&rpc_key::Model> for ProtocolRequirements {
+    fn from(x: &rpc_key::Model) -> Self {
+        Self {
+            require_jsonrpc_2: x.require_jsonrpc_2,
+            allow_batches: x.allow_batches,
+            allow_websocket: x.allow_websocket,
+        }
+    }
+}
+
+impl ProtocolRequirements {
+    pub fn check_request(&self, request: &JsonRpcRequestEnum) -> Web3ProxyResult<()> {
+        match request {
+            JsonRpcRequestEnum::Batch(requests) => {
+                if !self.allow_batches {
+                    return Err(Web3ProxyError::ProtocolNotAllowed(
+                        "batch requests are disabled for this key. send one request at a time"
+                            .into(),
+                    ));
+                }
+
+                requests.iter().try_for_each(|x| self.check_single(x))
+            }
+            JsonRpcRequestEnum::Single(request) => self.check_single(request),
+        }
+    }
+
+    pub fn check_single(&self, request: &SingleRequest) -> Web3ProxyResult<()> {
+        if self.require_jsonrpc_2 && request.jsonrpc != "2.0" {
+            return Err(Web3ProxyError::ProtocolNotAllowed(
+                format!(
+                    "this key requires \"jsonrpc\": \"2.0\". got {:?}",
+                    request.jsonrpc
+                )
+                .into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_websocket(&self) -> Web3ProxyResult<()> {
+        if self.allow_websocket {
+            Ok(())
+        } else {
+            Err(Web3ProxyError::ProtocolNotAllowed(
+                "websockets are disabled for this key. use http instead".into(),
+            ))
+        }
+    }
+}
+
+/// Something that only some user tiers are allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Entitlement {
+    /// requests for blocks older than `archive_depth`
+    Archive,
+    /// `trace_*` and `debug_trace*`
+    Trace,
+}
+
+impl Display for Entitlement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Archive => write!(f, "archive"),
+            Self::Trace => write!(f, "trace"),
+        }
+    }
+}
+
+/// Per-tier access to expensive requests. Inherited from the user_tier after any downgrade.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TierEntitlements {
+    pub allow_archive: bool,
+    pub allow_trace: bool,
+}
+
+impl Default for TierEntitlements {
+    fn default() -> Self {
+        Self {
+            allow_archive: true,
+            allow_trace: true,
+        }
+    }
+}
+
+impl From<&user_tier::Model> for TierEntitlements {
+    fn from(x: &user_tier::Model) -> Self {
+        Self {
+            allow_archive: x.allow_archive,
+            allow_trace: x.allow_trace,
+        }
+    }
+}
+
+impl TierEntitlements {
+    pub fn is_trace_method(method: &str) -> bool {
+        method.starts_with("trace_") || method.starts_with("debug_trace")
+    }
+
+    /// `archive_request` should come from the same detection that routes the request to archive servers
+    pub fn check(
+        &self,
+        method: &str,
+        archive_request: bool,
+        user_tier_title: Option<&str>,
+    ) -> Web3ProxyResult<()> {
+        let entitlement = if !self.allow_trace && Self::is_trace_method(method) {
+            Entitlement::Trace
+        } else if !self.allow_archive && archive_request {
+            Entitlement::Archive
+        } else {
+            return Ok(());
+        };
+
+        Err(Web3ProxyError::NotEntitled {
+            entitlement,
+            method: method.to_string(),
+            tier: user_tier_title.map(|x| x.to_string()),
+        })
+    }
+}
+
+/// Per-key method lists. Keys handed to other teams can be kept away from expensive methods.
+/// A pattern ending in `*` matches every method that starts with the rest of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MethodRules {
+    /// if None, allow any method that isn't denied
+    pub allowed: Option<Vec<String>>,
+    pub denied: Vec<String>,
+}
+
+impl TryFrom<&rpc_key::Model> for MethodRules {
+    type Error = Web3ProxyError;
+
+    fn try_from(x: &rpc_key::Model) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allowed: Self::parse_list(x.allowed_methods.as_deref())?,
+            denied: Self::parse_list(x.denied_methods.as_deref())?.unwrap_or_default(),
+        })
+    }
+}
+
+impl MethodRules {
+    /// the columns are json arrays of patterns
+    pub fn parse_list(x: Option<&str>) -> Web3ProxyResult<Option<Vec<String>>> {
+        match x {
+            None => Ok(None),
+            Some(x) => {
+                let x: Vec<String> = serde_json::from_str(x)?;
+
+                Ok(Some(x))
+            }
+        }
+    }
+
+    /// `*` is only allowed at the end
+    pub fn validate_pattern(pattern: &str) -> Web3ProxyResult<()> {
+        let name = pattern.strip_suffix('*').unwrap_or(pattern);
+
+        if pattern.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(Web3ProxyError::BadRequest(
+                format!(
+                    "invalid method pattern {:?}. use a method name or a prefix ending in *",
+                    pattern
+                )
+                .into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn pattern_matches(pattern: &str, method: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => pattern == method,
+        }
+    }
+
+    pub fn check(&self, method: &str) -> Web3ProxyResult<()> {
+        let denied = self.denied.iter().any(|x| Self::pattern_matches(x, method));
+
+        let allowed = self
+            .allowed
+            .as_ref()
+            .map_or(true, |x| x.iter().any(|x| Self::pattern_matches(x, method)));
+
+        if denied || !allowed {
+            Err(Web3ProxyError::MethodNotAllowed(method.to_string()))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// TODO: include the authorization checks in this?
@@ -106,6 +491,8 @@ pub struct Authorization {
     pub referer: Option<Referer>,
     pub user_agent: Option<UserAgent>,
     pub authorization_type: AuthorizationType,
+    /// what is left of the ip or key+ip limit after this request. None if no limit was checked
+    pub rate_limit: Option<RateLimitQuota>,
 }
 
 /// Ulids and Uuids matching the same bits hash the same
@@ -289,6 +676,16 @@ impl Authorization {
         )
     }
 
+    /// Background requests (like the canary's) go through the whole proxy like any other internal request, but they are
+    /// never saved as stats
+    pub fn internal_without_stats() -> Web3ProxyResult<Self> {
+        let mut x = Self::internal()?;
+
+        x.checks.skip_stats = true;
+
+        Ok(x)
+    }
+
     pub fn external(
         allowed_origin_requests_per_period: &HashMap<String, u64>,
         ip: &IpAddr,
@@ -391,6 +788,7 @@ impl Authorization {
             referer: referer.cloned(),
             user_agent: user_agent.cloned(),
             authorization_type,
+            rate_limit: None,
         })
     }
 }
@@ -401,7 +799,7 @@ pub async fn login_is_authorized(app: &App, ip: IpAddr) -> Web3ProxyResult<Autho
     let authorization = match app.rate_limit_login(ip, ProxyMode::Best).await? {
         RateLimitResult::Allowed(authorization) => authorization,
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, 1));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_login shouldn't ever see these: {:?}", x),
@@ -418,13 +816,18 @@ pub async fn ip_is_authorized(
     origin: Option<&Origin>,
     proxy_mode: ProxyMode,
 ) -> Web3ProxyResult<Authorization> {
+    app.bans.check(BanKey::Ip(*ip)).await?;
+
     // TODO: i think we could write an `impl From` for this
     // TODO: move this to an AuthorizedUser extrator
     let authorization = match app.rate_limit_public(ip, origin, proxy_mode).await? {
         RateLimitResult::Allowed(authorization) => authorization,
         RateLimitResult::RateLimited(authorization, retry_at) => {
             // TODO: in the background, emit a stat (maybe simplest to use a channel?)
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            app.recent_requests.record_rate_limited();
+            app.request_metrics.record_rate_limited();
+
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, 1));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_by_ip shouldn't ever see these: {:?}", x),
@@ -471,7 +874,9 @@ pub async fn ip_is_authorized(
 }
 
 /// like app.rate_limit_by_rpc_key but converts to a Web3ProxyError;
-/// keep the semaphore alive until the user's request is entirely complete
+/// keep the semaphore alive until the user's request is entirely complete.
+/// `ban_unknown_keys` records unknown keys as a violation by the ip
+#[allow(clippy::too_many_arguments)]
 pub async fn key_is_authorized(
     app: &Arc<App>,
     rpc_key: &RpcSecretKey,
@@ -480,20 +885,38 @@ pub async fn key_is_authorized(
     proxy_mode: ProxyMode,
     referer: Option<&Referer>,
     user_agent: Option<&UserAgent>,
+    ban_unknown_keys: bool,
 ) -> Web3ProxyResult<Authorization> {
     // check the rate limits. error if over the limit
     // TODO: i think this should be in an "impl From" or "impl Into"
     let authorization = match app
-        .rate_limit_premium(ip, origin, proxy_mode, referer, rpc_key, user_agent)
+        .rate_limit_premium(
+            ip,
+            origin,
+            proxy_mode,
+            referer,
+            rpc_key,
+            user_agent,
+            ban_unknown_keys,
+        )
         .await?
     {
         RateLimitResult::Allowed(authorization) => authorization,
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            app.recent_requests.record_rate_limited();
+            app.request_metrics.record_rate_limited();
+
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, 1));
+        }
+        RateLimitResult::QuotaExceeded(authorization, exceeded) => {
+            return Err(Web3ProxyError::QuotaExceeded(authorization, exceeded));
         }
         RateLimitResult::UnknownKey => return Err(Web3ProxyError::UnknownKey),
     };
 
+    // keyed requests are only checked by their key. other users behind the same ip are not our concern here
+    app.bans.check_authorization(&authorization).await?;
+
     // TODO: DRY and maybe optimize the hashing
     // in the background, add the ip to a recent_users map
     if app.config.public_recent_ips_salt.is_some() {
@@ -534,6 +957,209 @@ pub async fn key_is_authorized(
     Ok(authorization)
 }
 
+/// Parse the key from the url. Keys that don't parse count against the ip.
+pub async fn parse_rpc_key(app: &App, ip: &IpAddr, rpc_key: &str) -> Web3ProxyResult<RpcSecretKey> {
+    match rpc_key.parse() {
+        Ok(x) => Ok(x),
+        Err(err) => {
+            let key = BanKey::Ip(*ip);
+
+            app.bans.record(key, Violation::InvalidKey).await;
+
+            // once banned, tell them so that they back off
+            app.bans.check(key).await?;
+
+            Err(err)
+        }
+    }
+}
+
+/// Everything about a request that goes into its [`Authorization`].
+///
+/// HTTP requests, websocket handshakes, and server-sent event streams are all read this way so that they can't
+/// disagree about who is asking. The proxy mode comes from the route (`/debug/..`, `/fastest/..`, `/versus/..`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthorizationRequest {
+    pub ip: IpAddr,
+    pub origin: Option<Origin>,
+    pub referer: Option<Referer>,
+    pub user_agent: Option<UserAgent>,
+    /// the `:rpc_key` path param or the key in the `Authorization` header. None for public requests
+    pub rpc_key: Option<String>,
+    /// the key only came from the `Authorization` header. unknown keys there are not held against the ip
+    pub rpc_key_in_header: bool,
+    pub proxy_mode: ProxyMode,
+}
+
+/// The rpc key in an `Authorization` header. Clients that can't put it in the url send "Bearer <key>" or basic auth
+/// with the key as the password.
+/// Gateways in front of the public routes can add `Authorization` headers of their own, so values that aren't rpc keys
+/// are ignored
+fn authorization_header_key(headers: &HeaderMap) -> Web3ProxyResult<Option<String>> {
+    let mut found: Option<(RpcSecretKey, String)> = None;
+
+    for value in headers.get_all(AUTHORIZATION).iter() {
+        let token = if let Ok(x) = AuthorizationHeader::<Bearer>::decode(&mut once(value)) {
+            x.token().to_string()
+        } else if let Ok(x) = AuthorizationHeader::<Basic>::decode(&mut once(value)) {
+            x.password().to_string()
+        } else {
+            continue;
+        };
+
+        let Ok(key) = token.parse::<RpcSecretKey>() else {
+            continue;
+        };
+
+        // the same key twice is fine
+        match found.as_ref().map(|(x, _)| *x == key) {
+            None => found = Some((key, token)),
+            Some(true) => {}
+            Some(false) => return Err(Web3ProxyError::MultipleRpcKeys),
+        }
+    }
+
+    Ok(found.map(|(_, x)| x))
+}
+
+/// The path's key wins, but a different key in the header is a mistake that the client should hear about
+fn pick_rpc_key(path: Option<String>, header: Option<String>) -> Web3ProxyResult<Option<String>> {
+    match (path, header) {
+        (Some(path), Some(header)) => {
+            // ulids and uuids for the same key are the same key
+            let same = match (path.parse::<RpcSecretKey>(), header.parse::<RpcSecretKey>()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => path == header,
+            };
+
+            if same {
+                Ok(Some(path))
+            } else {
+                Err(Web3ProxyError::MultipleRpcKeys)
+            }
+        }
+        (path, header) => Ok(path.or(header)),
+    }
+}
+
+async fn optional_header<H, S>(parts: &mut Parts, state: &S) -> Option<H>
+where
+    H: Header + Send + 'static,
+    S: Send + Sync,
+{
+    Option::<TypedHeader<H>>::from_request_parts(parts, state)
+        .await
+        .ok()
+        .flatten()
+        .map(|x| x.0)
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthorizationRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+
+        let path_key =
+            Path::<std::collections::HashMap<String, String>>::from_request_parts(parts, state)
+                .await
+                .ok()
+                .and_then(|Path(mut x)| x.remove("rpc_key"));
+
+        let header_key = authorization_header_key(&parts.headers)?;
+
+        let rpc_key_in_header = path_key.is_none() && header_key.is_some();
+
+        let rpc_key = pick_rpc_key(path_key, header_key)?;
+
+        Ok(Self {
+            ip,
+            origin: optional_header(parts, state).await,
+            referer: optional_header(parts, state).await,
+            user_agent: optional_header(parts, state).await,
+            rpc_key,
+            rpc_key_in_header,
+            proxy_mode: ProxyMode::from_path(parts.uri.path()),
+        })
+    }
+}
+
+impl AuthorizationRequest {
+    /// Rate limit by key if there is one and by ip otherwise
+    pub async fn authorize(&self, app: &App) -> Web3ProxyResult<Authorization> {
+        match self.rpc_key.as_deref() {
+            None => ip_is_authorized(app, &self.ip, self.origin.as_ref(), self.proxy_mode).await,
+            Some(rpc_key) => {
+                let rpc_key = if self.rpc_key_in_header {
+                    // only values that parse were taken from the header
+                    rpc_key.parse()?
+                } else {
+                    parse_rpc_key(app, &self.ip, rpc_key).await?
+                };
+
+                key_is_authorized(
+                    app,
+                    &rpc_key,
+                    &self.ip,
+                    self.origin.as_ref(),
+                    self.proxy_mode,
+                    self.referer.as_ref(),
+                    self.user_agent.as_ref(),
+                    !self.rpc_key_in_header,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// The [`Authorization`] for a request. Built once per request (or websocket connection) and shared from then on.
+#[derive(Clone, Debug)]
+pub struct Authorized(pub Arc<Authorization>);
+
+#[async_trait]
+impl FromRequestParts<Arc<App>> for Authorized {
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app: &Arc<App>,
+    ) -> Result<Self, Self::Rejection> {
+        let authorization = AuthorizationRequest::from_request_parts(parts, app)
+            .await?
+            .authorize(app)
+            .await?;
+
+        Ok(Self(Arc::new(authorization)))
+    }
+}
+
+/// Reject banned ips before their request body is read.
+/// Only the public rpc routes are checked here. Keyed requests (including ones with the key in the `Authorization`
+/// header) are checked by key in `key_is_authorized`.
+pub async fn reject_banned_ips<B>(
+    State(app): State<Arc<App>>,
+    ClientIp(ip): ClientIp,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if matches!(
+        request.uri().path().trim_end_matches('/'),
+        "" | "/fastest" | "/versus"
+    ) && !matches!(authorization_header_key(request.headers()), Ok(Some(_)))
+    {
+        if let Err(err) = app.bans.check(BanKey::Ip(ip)).await {
+            return err.into_response_with_id(None, None::<RequestForError>);
+        }
+    }
+
+    next.run(request).await
+}
+
 impl App {
     /// Limit the number of concurrent requests from the given ip address.
     /// TODO: should this take an Authorization isntead of an IpAddr?
@@ -726,7 +1352,7 @@ impl App {
 
             Ok(x)
         } else {
-            // TODO: if no redis, rate limit with a local cache? "warn!" probably isn't right
+            // no public_requests_per_period. without redis, limits are counted in memory, so this isn't a missing store
             Ok(RateLimitResult::Allowed(authorization))
         }
     }
@@ -737,164 +1363,165 @@ impl App {
         proxy_mode: ProxyMode,
         rpc_secret_key: &RpcSecretKey,
     ) -> Web3ProxyResult<AuthorizationChecks> {
-        // TODO: move onto a helper function
+        if let Some(x) = self.rpc_secret_key_cache.get(rpc_secret_key).await {
+            self.refresh_authorization_checks(rpc_secret_key, &x);
+
+            return Ok(x);
+        }
 
         let x = self
             .rpc_secret_key_cache
-            .try_get_with_by_ref(rpc_secret_key, async move {
-                let db_replica = global_db_replica_conn()?;
-
-                // TODO: join the user table to this to return the User? we don't always need it
-                // TODO: join on secondary users
-                // TODO: join on user tier
-                match rpc_key::Entity::find()
-                    .filter(rpc_key::Column::SecretKey.eq(<Uuid>::from(*rpc_secret_key)))
-                    .filter(rpc_key::Column::Active.eq(true))
+            .try_get_with_by_ref(
+                rpc_secret_key,
+                self.load_authorization_checks(proxy_mode, rpc_secret_key),
+            )
+            .await?;
+
+        Ok(x)
+    }
+
+    /// Reload a known key's checks in the background if they are close to expiring.
+    /// Unknown keys are not reloaded. They expire quickly instead
+    fn refresh_authorization_checks(
+        &self,
+        rpc_secret_key: &RpcSecretKey,
+        checks: &AuthorizationChecks,
+    ) {
+        if checks.rpc_secret_key_id.is_none() {
+            return;
+        }
+
+        let Some(loaded_at) = checks.loaded_at else {
+            return;
+        };
+
+        if loaded_at.elapsed() < self.rpc_secret_key_expiry.refresh_after() {
+            return;
+        }
+
+        if !self
+            .rpc_secret_key_refreshing
+            .lock()
+            .insert(*rpc_secret_key)
+        {
+            // another request already started the reload
+            return;
+        }
+
+        let rpc_secret_key = *rpc_secret_key;
+        let proxy_mode = checks.proxy_mode;
+        let chain_id = self.config.chain_id;
+
+        tokio::spawn(async move {
+            let Some(app) = global_app(chain_id) else {
+                return;
+            };
+
+            let x = app
+                .load_authorization_checks(proxy_mode, &rpc_secret_key)
+                .await;
+
+            if !app.rpc_secret_key_refreshing.lock().remove(&rpc_secret_key) {
+                // the key was invalidated while this was loading. what we loaded might be from before the change
+                return;
+            }
+
+            match x {
+                Ok(x) => app.rpc_secret_key_cache.insert(rpc_secret_key, x).await,
+                Err(err) => {
+                    // the cached checks are used until they expire. then a request will try the database itself
+                    debug!(?err, "failed refreshing rpc key");
+                }
+            }
+        });
+    }
+
+    /// Query the database for a key's checks. Unknown keys get the default checks
+    async fn load_authorization_checks(
+        &self,
+        proxy_mode: ProxyMode,
+        rpc_secret_key: &RpcSecretKey,
+    ) -> Web3ProxyResult<AuthorizationChecks> {
+        let db_replica = global_db_replica_conn()?;
+
+        // TODO: join the user table to this to return the User? we don't always need it
+        // TODO: join on secondary users
+        // TODO: join on user tier
+        match rpc_key::Entity::find()
+            .filter(rpc_key::Column::SecretKey.eq(<Uuid>::from(*rpc_secret_key)))
+            .filter(rpc_key::Column::Active.eq(true))
+            .one(db_replica.as_ref())
+            .await?
+        {
+            Some(rpc_key_model) => {
+                // Get the user_tier
+                let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
                     .one(db_replica.as_ref())
                     .await?
-                {
-                    Some(rpc_key_model) => {
-                        // TODO: move these splits into helper functions
-                        // TODO: can we have sea orm handle this for us?
-                        let allowed_ips: Option<Vec<IpNet>> =
-                            if let Some(allowed_ips) = rpc_key_model.allowed_ips {
-                                let x = allowed_ips
-                                    .split(',')
-                                    .map(|x| x.trim().parse::<IpNet>())
-                                    .collect::<Result<Vec<_>, _>>()?;
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_origins: Option<Vec<Origin>> =
-                            if let Some(allowed_origins) = rpc_key_model.allowed_origins {
-                                // TODO: do this without collecting twice?
-                                let x = allowed_origins
-                                    .split(',')
-                                    .map(|x| HeaderValue::from_str(x.trim()))
-                                    .collect::<Result<Vec<_>, _>>()?
-                                    .into_iter()
-                                    .map(|x| Origin::decode(&mut [x].iter()))
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_referers: Option<Vec<Referer>> =
-                            if let Some(allowed_referers) = rpc_key_model.allowed_referers {
-                                let x = allowed_referers
-                                    .split(',')
-                                    .map(|x| {
-                                        x.trim()
-                                            .parse::<Referer>()
-                                            .or(Err(Web3ProxyError::InvalidReferer))
-                                    })
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_user_agents: Option<Vec<UserAgent>> =
-                            if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
-                                let x: Result<Vec<_>, _> = allowed_user_agents
-                                    .split(',')
-                                    .map(|x| {
-                                        x.trim()
-                                            .parse::<UserAgent>()
-                                            .or(Err(Web3ProxyError::InvalidUserAgent))
-                                    })
-                                    .collect();
-
-                                Some(x?)
-                            } else {
-                                None
-                            };
-
-                        // Get the user_tier
-                        let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
+                    .web3_context(
+                        "user model was not found, but every rpc_key should have a user",
+                    )?;
+
+                let mut user_tier_model = user_tier::Entity::find_by_id(user_model.user_tier_id)
+                    .one(db_replica.as_ref())
+                    .await?
+                    .web3_context(
+                        "related user tier not found, but every user should have a tier",
+                    )?;
+
+                let latest_balance = self
+                    .user_balance_cache
+                    .get_or_insert(db_replica.as_ref(), rpc_key_model.user_id)
+                    .await?;
+
+                let paid_credits_used: bool;
+                if let Some(downgrade_user_tier) = user_tier_model.downgrade_tier_id {
+                    trace!("user belongs to a premium tier. checking balance");
+
+                    let active_premium = latest_balance.read().await.active_premium();
+
+                    // only consider the user premium if they have paid at least $10 and have a balance > $.01
+                    // otherwise, set user_tier_model to the downograded tier
+                    if active_premium {
+                        paid_credits_used = true;
+                    } else {
+                        paid_credits_used = false;
+
+                        // TODO: include boolean to mark that the user is downgraded
+                        user_tier_model = user_tier::Entity::find_by_id(downgrade_user_tier)
                             .one(db_replica.as_ref())
                             .await?
-                            .web3_context(
-                                "user model was not found, but every rpc_key should have a user",
-                            )?;
-
-                        let mut user_tier_model = user_tier::Entity::find_by_id(
-                            user_model.user_tier_id,
-                        )
-                        .one(db_replica.as_ref())
-                        .await?
-                        .web3_context(
-                            "related user tier not found, but every user should have a tier",
-                        )?;
-
-                        let latest_balance = self
-                            .user_balance_cache
-                            .get_or_insert(db_replica.as_ref(), rpc_key_model.user_id)
-                            .await?;
-
-                        let paid_credits_used: bool;
-                        if let Some(downgrade_user_tier) = user_tier_model.downgrade_tier_id {
-                            trace!("user belongs to a premium tier. checking balance");
-
-                            let active_premium = latest_balance.read().await.active_premium();
-
-                            // only consider the user premium if they have paid at least $10 and have a balance > $.01
-                            // otherwise, set user_tier_model to the downograded tier
-                            if active_premium {
-                                paid_credits_used = true;
-                            } else {
-                                paid_credits_used = false;
-
-                                // TODO: include boolean to mark that the user is downgraded
-                                user_tier_model =
-                                    user_tier::Entity::find_by_id(downgrade_user_tier)
-                                        .one(db_replica.as_ref())
-                                        .await?
-                                        .web3_context(format!(
-                                            "downgrade user tier ({}) is missing!",
-                                            downgrade_user_tier
-                                        ))?;
-                            }
-                        } else {
-                            paid_credits_used = false;
-                        }
-
-                        let rpc_key_id =
-                            Some(rpc_key_model.id.try_into().context("db ids are never 0")?);
-
-                        Ok::<_, Web3ProxyError>(AuthorizationChecks {
-                            allowed_ips,
-                            allowed_origins,
-                            allowed_referers,
-                            allowed_user_agents,
-                            latest_balance,
-                            // TODO: is floating point math going to scale this correctly?
-                            log_revert_chance: (rpc_key_model.log_revert_chance * u16::MAX as f64)
-                                as u16,
-                            max_concurrent_requests: user_tier_model.max_concurrent_requests,
-                            max_requests_per_period: user_tier_model.max_requests_per_period,
-                            private_txs: rpc_key_model.private_txs,
-                            proxy_mode,
-                            rpc_secret_key: Some(*rpc_secret_key),
-                            rpc_secret_key_id: rpc_key_id,
-                            user_id: rpc_key_model.user_id,
-                            paid_credits_used,
-                        })
+                            .web3_context(format!(
+                                "downgrade user tier ({}) is missing!",
+                                downgrade_user_tier
+                            ))?;
                     }
-                    None => Ok(AuthorizationChecks::default()),
+                } else {
+                    paid_credits_used = false;
                 }
-            })
-            .await?;
 
-        Ok(x)
+                self.degraded
+                    .record(&rpc_key_model, &user_tier_model, paid_credits_used);
+
+                AuthorizationChecks::try_from_models(
+                    rpc_key_model,
+                    user_tier_model,
+                    latest_balance,
+                    paid_credits_used,
+                    proxy_mode,
+                    rpc_secret_key,
+                )
+            }
+            None => {
+                self.degraded.forget(rpc_secret_key);
+
+                Ok(AuthorizationChecks::default())
+            }
+        }
     }
 
     /// Authorize the key/ip/origin/referer/useragent and handle rate and concurrency limits
+    #[allow(clippy::too_many_arguments)]
     pub async fn rate_limit_premium(
         &self,
         ip: &IpAddr,
@@ -903,26 +1530,43 @@ impl App {
         referer: Option<&Referer>,
         rpc_key: &RpcSecretKey,
         user_agent: Option<&UserAgent>,
+        ban_unknown_keys: bool,
     ) -> Web3ProxyResult<RateLimitResult> {
         let authorization_checks = match self.authorization_checks(proxy_mode, rpc_key).await {
             Ok(x) => x,
             Err(err) => {
-                if let Ok(_err) = err.ok_db_errors() {
-                    // // TODO: this is too verbose during an outage. the warnings on the config reloader should be fine
-                    // warn!(
-                    //     ?err,
-                    //     "db is down. cannot check rpc key. fallback to ip rate limits"
-                    // );
-                    return self.rate_limit_public(ip, origin, proxy_mode).await;
-                }
+                if let Ok(err) = err.ok_db_errors() {
+                    // without a database, keys can never be checked
+                    if matches!(err, Web3ProxyError::NoDatabaseConfigured) {
+                        return self.rate_limit_public(ip, origin, proxy_mode).await;
+                    }
 
-                return Err(err);
+                    // the database is down
+                    match self.degraded.authorization_checks(proxy_mode, rpc_key)? {
+                        Some(x) => x,
+                        None => {
+                            // // TODO: this is too verbose during an outage. the warnings on the config reloader should be fine
+                            // warn!(
+                            //     ?err,
+                            //     "db is down. cannot check rpc key. fallback to ip rate limits"
+                            // );
+                            return self.rate_limit_public(ip, origin, proxy_mode).await;
+                        }
+                    }
+                } else {
+                    return Err(err);
+                }
             }
         };
 
         // if no rpc_key_id matching the given rpc was found, then we can't rate limit by key
         if authorization_checks.rpc_secret_key_id.is_none() {
             trace!("unknown key. falling back to free limits");
+            if ban_unknown_keys {
+                self.bans
+                    .record(BanKey::Ip(*ip), Violation::UnknownKey)
+                    .await;
+            }
             return self.rate_limit_public(ip, origin, proxy_mode).await;
         }
 
@@ -979,13 +1623,91 @@ impl App {
 
                 debug_assert!(!matches!(x, RateLimitResult::UnknownKey));
 
-                return Ok(x);
+                return Ok(self.count_quotas(x));
             } else {
-                // TODO: if no redis, rate limit with just a local cache?
+                // the limiter is only missing if public_requests_per_period isn't set
             }
         }
 
-        Ok(RateLimitResult::Allowed(authorization))
+        Ok(self.count_quotas(RateLimitResult::Allowed(authorization)))
+    }
+
+    /// Count an allowed request against its key's daily and monthly quotas.
+    /// Requests that were already rate limited don't use up any of the quotas
+    fn count_quotas(&self, x: RateLimitResult) -> RateLimitResult {
+        match x {
+            RateLimitResult::Allowed(authorization) => {
+                match self.quotas.count(&authorization.checks, Utc::now()) {
+                    Ok(()) => RateLimitResult::Allowed(authorization),
+                    Err(exceeded) => RateLimitResult::QuotaExceeded(authorization, exceeded),
+                }
+            }
+            x => x,
+        }
+    }
+
+    /// Count the rest of a request's weight against the limit that authorized it. Authorizing already counted 1.
+    /// Only the ip or key+ip limit is used here. The bonus pools are only for requests that count once.
+    /// Like the other rate limits, this fails open.
+    /// Returns what is left of the limit if anything more was counted
+    pub async fn rate_limit_weight(
+        &self,
+        authorization: &Authorization,
+        weight: u64,
+    ) -> Web3ProxyResult<Option<RateLimitQuota>> {
+        if weight <= 1 || authorization.authorization_type == AuthorizationType::Internal {
+            return Ok(None);
+        }
+
+        let extra = weight - 1;
+
+        let x = if authorization.checks.rpc_secret_key_id.is_some() {
+            match (
+                authorization.checks.max_requests_per_period,
+                &self.frontend_premium_rate_limiter,
+            ) {
+                (Some(max_requests_per_period), Some(rate_limiter)) => {
+                    let key =
+                        RegisteredUserRateLimitKey(authorization.checks.user_id, authorization.ip);
+
+                    rate_limiter
+                        .throttle(key, Some(max_requests_per_period), extra)
+                        .await
+                }
+                _ => return Ok(None),
+            }
+        } else if let Some(rate_limiter) = &self.frontend_public_rate_limiter {
+            rate_limiter
+                .throttle(
+                    authorization.ip,
+                    authorization.checks.max_requests_per_period,
+                    extra,
+                )
+                .await
+        } else {
+            return Ok(None);
+        };
+
+        let mut authorization = authorization.clone();
+
+        let retry_at = match x {
+            Ok(DeferredRateLimitResult::Allowed(quota)) => return Ok(Some(quota)),
+            Ok(DeferredRateLimitResult::RetryAt(quota)) => {
+                authorization.rate_limit = Some(quota);
+
+                Some(quota.reset)
+            }
+            Ok(DeferredRateLimitResult::RetryNever) => None,
+            Err(err) => {
+                error!(?err, "rate limiter is unhappy. allowing request");
+                return Ok(None);
+            }
+        };
+
+        self.recent_requests.record_rate_limited();
+        self.request_metrics.record_rate_limited();
+
+        Err(Web3ProxyError::RateLimited(authorization, retry_at, weight))
     }
 }
 
@@ -1004,6 +1726,7 @@ impl Authorization {
                 self.checks.proxy_mode,
                 self.referer.as_ref(),
                 self.user_agent.as_ref(),
+                true,
             )
             .await?;
 
@@ -1029,7 +1752,7 @@ impl Authorization {
 /// this never includes a semaphore! if you want one, add it after this call
 /// if `max_requests_per_period` is none, the limit in the authorization is used
 pub async fn deferred_redis_rate_limit<K>(
-    authorization: Authorization,
+    mut authorization: Authorization,
     key: K,
     max_requests_per_period: Option<u64>,
     rate_limiter: &DeferredRateLimiter<K>,
@@ -1041,14 +1764,19 @@ where
         max_requests_per_period.or(authorization.checks.max_requests_per_period);
 
     let x = match rate_limiter.throttle(key, max_requests_per_period, 1).await {
-        Ok(DeferredRateLimitResult::Allowed) => RateLimitResult::Allowed(authorization),
-        Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-            // TODO: set headers so they know when they can retry
+        Ok(DeferredRateLimitResult::Allowed(quota)) => {
+            authorization.rate_limit = Some(quota);
+
+            RateLimitResult::Allowed(authorization)
+        }
+        Ok(DeferredRateLimitResult::RetryAt(quota)) => {
             // TODO: debug or trace?
             // this is too verbose, but a stat might be good
             // TODO: emit a stat
-            // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
-            RateLimitResult::RateLimited(authorization, Some(retry_at))
+            // trace!(?rpc_key, "rate limit exceeded until {:?}", quota.reset);
+            authorization.rate_limit = Some(quota);
+
+            RateLimitResult::RateLimited(authorization, Some(quota.reset))
         }
         Ok(DeferredRateLimitResult::RetryNever) => {
             // TODO: keys are secret. don't log them!
@@ -1106,3 +1834,340 @@ pub async fn redis_rate_limit(
 
     Ok(x)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::client_ip::TrustedProxies;
+    use axum::body::Body;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::routing::{get, post};
+    use axum::{Extension, Router};
+    use http::StatusCode;
+    use parking_lot::Mutex;
+    use std::net::SocketAddr;
+    use tower_service::Service;
+
+    type Seen = Arc<Mutex<Vec<AuthorizationRequest>>>;
+
+    async fn record(Extension(seen): Extension<Seen>, request: AuthorizationRequest) {
+        seen.lock().push(request);
+    }
+
+    fn request(method: &str, uri: &str, websocket: bool) -> Request<Body> {
+        let mut x = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("origin", "https://example.com")
+            .header("referer", "https://example.com/app")
+            .header("user-agent", "test-wallet/1.0");
+
+        if websocket {
+            x = x
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header("sec-websocket-version", "13");
+        } else {
+            x = x.header("content-type", "application/json");
+        }
+
+        x.body(Body::from(
+            r#"{"jsonrpc":"2.0","method":"eth_chainId","id":1}"#,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn protocol_requirements() {
+        let old_client: JsonRpcRequestEnum =
+            serde_json::from_str(r#"{"jsonrpc":"1.0","method":"eth_chainId","id":1}"#).unwrap();
+
+        let batch: JsonRpcRequestEnum = serde_json::from_str(
+            r#"[{"jsonrpc":"2.0","method":"eth_chainId","id":1},{"jsonrpc":"2.0","method":"eth_blockNumber","id":2}]"#,
+        )
+        .unwrap();
+
+        let permissive = ProtocolRequirements::default();
+
+        permissive.check_request(&old_client).unwrap();
+        permissive.check_request(&batch).unwrap();
+        permissive.check_websocket().unwrap();
+
+        let strict = ProtocolRequirements {
+            require_jsonrpc_2: true,
+            allow_batches: false,
+            allow_websocket: false,
+        };
+
+        assert!(matches!(
+            strict.check_request(&old_client),
+            Err(Web3ProxyError::ProtocolNotAllowed(_))
+        ));
+        assert!(matches!(
+            strict.check_request(&batch),
+            Err(Web3ProxyError::ProtocolNotAllowed(_))
+        ));
+        assert!(matches!(
+            strict.check_websocket(),
+            Err(Web3ProxyError::ProtocolNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn tier_entitlements() {
+        let permissive = TierEntitlements::default();
+
+        permissive.check("eth_call", true, None).unwrap();
+        permissive.check("trace_block", false, None).unwrap();
+
+        let free = TierEntitlements {
+            allow_archive: false,
+            allow_trace: false,
+        };
+
+        free.check("eth_call", false, Some("Free")).unwrap();
+
+        match free.check("eth_call", true, Some("Free")) {
+            Err(Web3ProxyError::NotEntitled {
+                entitlement: Entitlement::Archive,
+                method,
+                tier,
+            }) => {
+                assert_eq!(method, "eth_call");
+                assert_eq!(tier.as_deref(), Some("Free"));
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // trace is checked even for recent blocks
+        assert!(matches!(
+            free.check("debug_traceTransaction", false, Some("Free")),
+            Err(Web3ProxyError::NotEntitled {
+                entitlement: Entitlement::Trace,
+                ..
+            })
+        ));
+
+        let archive_only = TierEntitlements {
+            allow_archive: true,
+            allow_trace: false,
+        };
+
+        archive_only.check("eth_getBalance", true, None).unwrap();
+        assert!(archive_only.check("trace_call", true, None).is_err());
+    }
+
+    #[test]
+    fn method_rules() {
+        MethodRules::default()
+            .check("debug_traceTransaction")
+            .unwrap();
+
+        let x = MethodRules {
+            allowed: None,
+            denied: vec!["eth_getLogs".to_string(), "debug_*".to_string()],
+        };
+
+        x.check("eth_call").unwrap();
+        x.check("eth_getLogsButNotReally").unwrap();
+        assert!(matches!(
+            x.check("eth_getLogs"),
+            Err(Web3ProxyError::MethodNotAllowed(method)) if method == "eth_getLogs"
+        ));
+        assert!(x.check("debug_traceTransaction").is_err());
+        assert!(x.check("debug_").is_err());
+
+        // denied wins over allowed
+        let x = MethodRules {
+            allowed: Some(vec!["eth_*".to_string(), "net_version".to_string()]),
+            denied: vec!["eth_getLogs".to_string()],
+        };
+
+        x.check("eth_blockNumber").unwrap();
+        x.check("net_version").unwrap();
+        assert!(x.check("net_listening").is_err());
+        assert!(x.check("eth_getLogs").is_err());
+        assert!(x.check("trace_block").is_err());
+
+        // "*" alone matches everything
+        let x = MethodRules {
+            allowed: None,
+            denied: vec!["*".to_string()],
+        };
+
+        assert!(x.check("eth_chainId").is_err());
+
+        MethodRules::validate_pattern("debug_*").unwrap();
+        MethodRules::validate_pattern("*").unwrap();
+        assert!(MethodRules::validate_pattern("").is_err());
+        assert!(MethodRules::validate_pattern("*_traceTransaction").is_err());
+        assert!(MethodRules::validate_pattern("eth_call, eth_getLogs").is_err());
+
+        assert_eq!(
+            MethodRules::parse_list(Some(r#"["eth_call","debug_*"]"#)).unwrap(),
+            Some(vec!["eth_call".to_string(), "debug_*".to_string()])
+        );
+        assert_eq!(MethodRules::parse_list(None).unwrap(), None);
+    }
+
+    /// the extractor's side. `test_http_and_websocket_authorize_the_same` in web3_proxy_cli authorizes a real key both ways
+    #[tokio::test]
+    async fn http_and_websocket_agree() {
+        let seen = Seen::default();
+
+        let mut router = Router::new()
+            .route("/", post(record).get(record))
+            .route("/rpc/:rpc_key", post(record).get(record))
+            .route("/fastest/:rpc_key", post(record).get(record))
+            .layer(Extension(seen.clone()))
+            // the request comes through a load balancer that says who the client is
+            .layer(Extension(TrustedProxies::new(vec!["10.0.0.0/8"
+                .parse()
+                .unwrap()])))
+            .layer(MockConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4567))));
+
+        let key = "01H9QZ8ZC0W0J3A5NEXRHP3V5H";
+
+        for (uri, websocket) in [
+            (format!("/rpc/{}", key), false),
+            (format!("/rpc/{}", key), true),
+            (format!("/fastest/{}", key), true),
+            ("/".to_string(), true),
+        ] {
+            let method = if websocket { "GET" } else { "POST" };
+
+            let response = router.call(request(method, &uri, websocket)).await.unwrap();
+            assert!(response.status().is_success(), "{} {:?}", uri, response);
+        }
+
+        let seen = seen.lock();
+
+        // the same key over both transports is the same request
+        assert_eq!(seen[0], seen[1]);
+        assert_eq!(seen[0].rpc_key.as_deref(), Some(key));
+        assert_eq!(seen[0].ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            seen[0].origin,
+            Origin::try_from_parts("https", "example.com", None).ok()
+        );
+        assert_eq!(
+            seen[0].user_agent,
+            UserAgent::from_str("test-wallet/1.0").ok()
+        );
+        assert_eq!(seen[0].proxy_mode, ProxyMode::Best);
+
+        assert_eq!(seen[2].rpc_key.as_deref(), Some(key));
+        assert_eq!(seen[2].proxy_mode, ProxyMode::Fastest(0));
+
+        assert_eq!(seen[3].rpc_key, None);
+        assert_eq!(seen[3].proxy_mode, ProxyMode::Best);
+    }
+
+    #[tokio::test]
+    async fn rpc_key_positions() {
+        let seen = Seen::default();
+
+        let mut router = Router::new()
+            .route("/", post(record).get(record))
+            .route("/rpc/:rpc_key", post(record).get(record))
+            .layer(Extension(seen.clone()))
+            .layer(MockConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4567))));
+
+        let key = "01H9QZ8ZC0W0J3A5NEXRHP3V5H";
+        // the same key as a uuid
+        let uuid_key = "018a6ff4-7d80-e024-3516-aeee2361ecb1";
+        let other_key = "01H9QZ8ZC0W0J3A5NEXRHP3V5J";
+
+        let path = format!("/rpc/{}", key);
+        let bearer = format!("Bearer {}", key);
+        let uuid_bearer = format!("Bearer {}", uuid_key);
+        // "curl:<key>"
+        let basic = "Basic Y3VybDowMUg5UVo4WkMwVzBKM0E1TkVYUkhQM1Y1SA==";
+
+        for (uri, authorization, websocket, in_header) in [
+            (path.as_str(), None, false, false),
+            ("/", Some(bearer.as_str()), false, true),
+            ("/", Some(basic), false, true),
+            ("/", Some(bearer.as_str()), true, true),
+            // the path wins. the header agrees, so this is fine
+            (path.as_str(), Some(uuid_bearer.as_str()), false, false),
+        ] {
+            let method = if websocket { "GET" } else { "POST" };
+
+            let mut request = request(method, uri, websocket);
+            if let Some(authorization) = authorization {
+                request
+                    .headers_mut()
+                    .insert(AUTHORIZATION, authorization.parse().unwrap());
+            }
+
+            let response = router.call(request).await.unwrap();
+            assert!(
+                response.status().is_success(),
+                "{} {:?} {:?}",
+                uri,
+                authorization,
+                response
+            );
+
+            let x = seen.lock().pop().unwrap();
+            assert_eq!(x.rpc_key.as_deref(), Some(key));
+            assert_eq!(
+                x.rpc_key_in_header, in_header,
+                "{} {:?}",
+                uri, authorization
+            );
+        }
+
+        // a different key in the header
+        let mut request = request("POST", &path, false);
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            format!("Bearer {}", other_key).parse().unwrap(),
+        );
+
+        let response = router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // gateways in front of the public routes add their own headers. they are not rpc keys
+        for authorization in [
+            format!("Token {}", key),
+            "Bearer ".to_string(),
+            "Bearer eyJhbGciOiJIUzI1NiJ9.e30.ZRrHA1JJJW8opsbCGfG_HACGpVUMN_a9IV7pAx_Zmeo"
+                .to_string(),
+            "Basic not-base64!".to_string(),
+            // "user:hunter2"
+            "Basic dXNlcjpodW50ZXIy".to_string(),
+        ] {
+            let mut request = request("POST", "/", false);
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.parse().unwrap());
+
+            let response = router.call(request).await.unwrap();
+            assert!(
+                response.status().is_success(),
+                "{} {:?}",
+                authorization,
+                response
+            );
+
+            let x = seen.lock().pop().unwrap();
+            assert_eq!(x.rpc_key, None, "{}", authorization);
+            assert!(!x.rpc_key_in_header);
+        }
+
+        assert!(matches!(
+            pick_rpc_key(Some(key.to_string()), Some(other_key.to_string())),
+            Err(Web3ProxyError::MultipleRpcKeys)
+        ));
+        assert_eq!(
+            pick_rpc_key(None, Some(key.to_string()))
+                .unwrap()
+                .as_deref(),
+            Some(key)
+        );
+    }
+}
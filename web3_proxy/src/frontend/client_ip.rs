@@ -0,0 +1,199 @@
+//! Who actually sent a request.
+//!
+//! Behind nginx or a cloud load balancer, every connection comes from the load balancer. It says who the client was in
+//! `X-Forwarded-For` or `X-Real-IP`, but anyone can send those headers. They are only believed when the connection
+//! comes from one of `trusted_proxies`.
+
+use crate::errors::Web3ProxyError;
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use http::request::Parts;
+use http::{HeaderMap, StatusCode};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_REAL_IP: &str = "x-real-ip";
+
+/// The networks whose forwarded headers are believed. The router adds this as an extension
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn new(cidrs: Vec<IpNet>) -> Self {
+        Self(Arc::new(cidrs))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|x| x.contains(ip))
+    }
+
+    /// The client's ip for a connection from `peer`.
+    /// Walks `X-Forwarded-For` from the right and stops at the first hop that isn't a trusted proxy
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(&peer) {
+            return peer;
+        }
+
+        let mut hops = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+
+        if hops.is_empty() {
+            return headers
+                .get(X_REAL_IP)
+                .and_then(|x| x.to_str().ok())
+                .and_then(parse_hop)
+                .unwrap_or(peer);
+        }
+
+        let mut client = peer;
+
+        while let Some(hop) = hops.pop() {
+            // anything left of garbage could have been written by anyone
+            let Some(hop) = parse_hop(hop) else {
+                break;
+            };
+
+            client = hop;
+
+            if !self.contains(&hop) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// proxies sometimes include the port
+fn parse_hop(x: &str) -> Option<IpAddr> {
+    let x = x.trim();
+
+    x.parse()
+        .ok()
+        .or_else(|| x.parse::<SocketAddr>().ok().map(|x| x.ip()))
+}
+
+/// The ip that rate limits, bans, and logs use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                Web3ProxyError::StatusCode(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string().into(),
+                    None,
+                )
+            })?;
+
+        let ip = match parts.extensions.get::<TrustedProxies>() {
+            Some(x) => x.client_ip(peer.ip(), &parts.headers),
+            None => peer.ip(),
+        };
+
+        Ok(Self(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> TrustedProxies {
+        TrustedProxies::new(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "192.0.2.1/32".parse().unwrap(),
+        ])
+    }
+
+    fn headers(xs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (k, v) in xs {
+            headers.append(*k, v.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn untrusted_peers_are_not_believed() {
+        let x = trusted();
+
+        let peer: IpAddr = "198.51.100.9".parse().unwrap();
+
+        let h = headers(&[(X_FORWARDED_FOR, "203.0.113.7"), (X_REAL_IP, "203.0.113.8")]);
+
+        assert_eq!(x.client_ip(peer, &h), peer);
+
+        // nothing is trusted by default
+        assert_eq!(
+            TrustedProxies::default().client_ip("10.0.0.1".parse().unwrap(), &h),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn rightmost_untrusted_hop() {
+        let x = trusted();
+
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // the client made up the first hop. the load balancers added the rest
+        let h = headers(&[(X_FORWARDED_FOR, "1.1.1.1, 203.0.113.7, 192.0.2.1")]);
+        assert_eq!(
+            x.client_ip(peer, &h),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // repeated headers are one list
+        let h = headers(&[
+            (X_FORWARDED_FOR, "1.1.1.1"),
+            (X_FORWARDED_FOR, "203.0.113.7:4567, 10.1.2.3"),
+        ]);
+        assert_eq!(
+            x.client_ip(peer, &h),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // every hop is trusted. the leftmost is as far back as we can see
+        let h = headers(&[(X_FORWARDED_FOR, "10.9.9.9, 10.1.2.3")]);
+        assert_eq!(x.client_ip(peer, &h), "10.9.9.9".parse::<IpAddr>().unwrap());
+
+        // garbage stops the walk
+        let h = headers(&[(X_FORWARDED_FOR, "203.0.113.7, nonsense, 10.1.2.3")]);
+        assert_eq!(x.client_ip(peer, &h), "10.1.2.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn real_ip_without_forwarded_for() {
+        let x = trusted();
+
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let h = headers(&[(X_REAL_IP, "2001:db8::1")]);
+        assert_eq!(
+            x.client_ip(peer, &h),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        assert_eq!(x.client_ip(peer, &HeaderMap::new()), peer);
+    }
+}
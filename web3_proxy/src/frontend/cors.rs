@@ -0,0 +1,174 @@
+//! Which browser origins may call the proxy.
+//!
+//! Preflights are answered by tower's `CorsLayer`. Browsers enforce its answer, but anything else can send any Origin
+//! header, so requests from origins that aren't allowed are also rejected here with a jsonrpc error. Requests without
+//! an Origin header are not browsers and are not affected. Rpc keys can narrow this further with their own
+//! `allowed_origins`.
+
+use super::request_id::{RequestId, REQUEST_ID_HEADER};
+use crate::app::App;
+use crate::errors::{RequestForError, Web3ProxyError};
+use axum::extract::State;
+use axum::headers::Origin;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::TypedHeader;
+use http::{HeaderName, Request};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+use tracing::{trace, warn};
+
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// "*" allows every origin. A "*" inside an origin matches anything there, like "https://*.example.com"
+    #[serde_inline_default(vec!["*".to_string()])]
+    pub allowed_origins: Vec<String>,
+
+    /// Request headers that browsers may send. Empty allows whatever the preflight asks for
+    #[serde(default = "Default::default")]
+    pub allowed_headers: Vec<String>,
+
+    /// How long (in seconds) browsers may remember a preflight. 0 leaves it up to the browser
+    #[serde_inline_default(0u64)]
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+impl CorsConfig {
+    fn allows_any(&self) -> bool {
+        self.allowed_origins.iter().any(|x| x == "*")
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| origin_matches(pattern, origin))
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let allow_origin = if self.allows_any() {
+            AllowOrigin::mirror_request()
+        } else {
+            let x = self.clone();
+
+            AllowOrigin::predicate(move |origin, _| {
+                origin
+                    .to_str()
+                    .map(|origin| x.allows(origin))
+                    .unwrap_or(false)
+            })
+        };
+
+        let allow_headers = if self.allowed_headers.is_empty() {
+            AllowHeaders::mirror_request()
+        } else {
+            AllowHeaders::list(self.allowed_headers.iter().filter_map(|x| {
+                HeaderName::from_str(x)
+                    .map_err(|err| warn!(?err, header=%x, "invalid header in cors.allowed_headers"))
+                    .ok()
+            }))
+        };
+
+        let layer = CorsLayer::new()
+            .allow_credentials(true)
+            .allow_headers(allow_headers)
+            .allow_methods(AllowMethods::mirror_request())
+            .allow_origin(allow_origin)
+            .expose_headers([HeaderName::from_static(REQUEST_ID_HEADER)]);
+
+        if self.max_age_secs > 0 {
+            layer.max_age(Duration::from_secs(self.max_age_secs))
+        } else {
+            layer
+        }
+    }
+}
+
+/// `pattern` may have one "*" in it. It matches at least one character
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern.eq_ignore_ascii_case(origin),
+        Some((prefix, suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin
+                    .get(..prefix.len())
+                    .map_or(false, |x| x.eq_ignore_ascii_case(prefix))
+                && origin
+                    .get(origin.len() - suffix.len()..)
+                    .map_or(false, |x| x.eq_ignore_ascii_case(suffix))
+        }
+    }
+}
+
+/// Reject requests from origins that `CorsConfig` doesn't allow. Preflights never get here
+pub async fn reject_disallowed_origins<B>(
+    State(app): State<Arc<App>>,
+    origin: Option<TypedHeader<Origin>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(TypedHeader(origin)) = origin {
+        let cors = &app.config.cors;
+
+        if !cors.allows_any() && !cors.allows(&origin.to_string()) {
+            trace!(%origin, "origin not allowed by cors");
+
+            let request_id = request
+                .extensions()
+                .get::<RequestId>()
+                .map(|x| x.0.as_str());
+
+            return Web3ProxyError::OriginNotAllowed(origin)
+                .into_response_with_id(None, request_id.map(RequestForError::RequestId));
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        let x: CorsConfig = serde_json::from_value(serde_json::json!({
+            "allowed_origins": ["https://app.example.com", "https://*.example.org"],
+        }))
+        .unwrap();
+
+        assert!(x.allows("https://app.example.com"));
+        assert!(x.allows("HTTPS://APP.EXAMPLE.COM"));
+        assert!(!x.allows("https://example.com"));
+        assert!(!x.allows("http://app.example.com"));
+
+        assert!(x.allows("https://a.example.org"));
+        assert!(x.allows("https://a.b.example.org"));
+        assert!(!x.allows("https://.example.org"));
+        assert!(!x.allows("https://example.org"));
+        assert!(!x.allows("https://evil-example.org.com"));
+
+        assert!(!x.allows_any());
+    }
+
+    #[test]
+    fn defaults() {
+        let x = CorsConfig::default();
+
+        assert!(x.allows_any());
+        assert!(x.allows("https://anything.example.com"));
+        assert!(x.allowed_headers.is_empty());
+        assert_eq!(x.max_age_secs, 0);
+    }
+}
@@ -0,0 +1,138 @@
+//! Answer load balancer health checks and uptime bots without rate limits or stats. See `crate::exempt_traffic`.
+//!
+//! Everything here comes from memory. The backends are never asked.
+
+use super::client_ip::ClientIp;
+use super::status::health_status;
+use crate::app::App;
+use crate::errors::{RequestForError, Web3ProxyError};
+use crate::exempt_traffic::ExemptReason;
+use crate::jsonrpc::{self, JsonRpcErrorData, JsonRpcRequestEnum, SingleRequest};
+use axum::body::{Body, HttpBody};
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ethers::types::U64;
+use http::header::{ALLOW, CONTENT_TYPE, UPGRADE, USER_AGENT};
+use http::{Method, Request, StatusCode};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Health checks send tiny bodies. Anything larger than this is rejected without reading the rest
+pub const MAX_EXEMPT_BODY_BYTES: usize = 16 * 1024;
+
+/// The only methods that exempt traffic can call
+pub const EXEMPT_METHODS: [&str; 3] = ["eth_blockNumber", "eth_chainId", "net_version"];
+
+/// Exempt requests are answered here. Everything else goes on to the normal routes.
+/// This runs before `reject_banned_ips` so that health checks never wait on the ban list.
+pub async fn exempt_traffic(
+    State(app): State<Arc<App>>,
+    ClientIp(ip): ClientIp,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // websockets from the load balancer's network are real clients
+    if request.headers().contains_key(UPGRADE) {
+        return next.run(request).await;
+    }
+
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|x| x.to_str().ok());
+
+    match app
+        .exempt_traffic
+        .classify(request.method(), request.uri().path(), ip, user_agent)
+    {
+        None => next.run(request).await,
+        // the health route is already minimal and cached
+        Some(ExemptReason::Health) => next.run(request).await,
+        Some(_) => exempt_response(&app, request).await,
+    }
+}
+
+async fn exempt_response(app: &App, request: Request<Body>) -> Response {
+    match *request.method() {
+        Method::OPTIONS => (
+            StatusCode::NO_CONTENT,
+            [(ALLOW, "GET, HEAD, OPTIONS, POST")],
+        )
+            .into_response(),
+        Method::HEAD => health_status(app).0.into_response(),
+        Method::GET => {
+            let (code, content_type, body) = health_status(app);
+
+            (code, [(CONTENT_TYPE, content_type)], body).into_response()
+        }
+        Method::POST => match read_body(request.into_body()).await {
+            Ok(body) => answer_jsonrpc(app, &body),
+            Err(response) => response,
+        },
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Response> {
+    let mut buf = vec![];
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+
+        if buf.len() + chunk.len() > MAX_EXEMPT_BODY_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf)
+}
+
+fn answer_jsonrpc(app: &App, body: &[u8]) -> Response {
+    let payload: JsonRpcRequestEnum = match serde_json::from_slice(body) {
+        Ok(x) => x,
+        Err(err) => {
+            return Web3ProxyError::BadRequest(format!("invalid json: {}", err).into())
+                .into_response_with_id(None, None::<RequestForError>)
+        }
+    };
+
+    let response: jsonrpc::Response = match payload {
+        JsonRpcRequestEnum::Single(x) => answer(app, x).into(),
+        JsonRpcRequestEnum::Batch(x) => {
+            jsonrpc::Response::Batch(x.into_iter().map(|x| answer(app, x)).collect())
+        }
+    };
+
+    response.into_response()
+}
+
+fn answer(app: &App, request: SingleRequest) -> jsonrpc::ParsedResponse {
+    let result = match request.method.as_ref() {
+        "eth_blockNumber" => match app.balanced_rpcs.head_block() {
+            Some(head_block) => Ok(json!(head_block.number())),
+            None => Err(JsonRpcErrorData {
+                message: "no servers synced".into(),
+                code: StatusCode::BAD_GATEWAY.as_u16().into(),
+                data: None,
+            }),
+        },
+        "eth_chainId" => Ok(json!(U64::from(app.config.chain_id))),
+        "net_version" => Ok(json!(app.config.chain_id.to_string())),
+        method => Err(JsonRpcErrorData {
+            message: "health checks can only call a few methods".into(),
+            code: -32601,
+            data: Some(json!({
+                "method": method,
+                "allowed": EXEMPT_METHODS,
+            })),
+        }),
+    };
+
+    match result {
+        Ok(x) => jsonrpc::ParsedResponse::from_value(x, request.id),
+        Err(err) => jsonrpc::ParsedResponse::from_error(err, request.id),
+    }
+}
@@ -6,32 +6,47 @@
 // TODO: these are only public so docs are generated. What's a better way to do this?
 pub mod admin;
 pub mod authorization;
+pub mod client_ip;
+pub mod cors;
 pub mod errors;
+pub mod exempt;
 pub mod request_id;
 pub mod rpc_proxy_http;
 pub mod rpc_proxy_ws;
+pub mod sse;
+pub mod standby;
 pub mod status;
 pub mod users;
+pub mod ws_queue;
 
 use crate::app::App;
 use crate::errors::Web3ProxyResult;
 use axum::{
-    routing::{get, post},
+    extract::{ConnectInfo, DefaultBodyLimit},
+    middleware,
+    routing::{delete, future::RouteFuture, get, post},
     Extension, Router,
 };
-use http::{header::AUTHORIZATION, Request, StatusCode};
+use client_ip::TrustedProxies;
+use hashbrown::HashMap;
+use http::header::{AUTHORIZATION, HOST};
+use http::uri::Authority;
+use http::{Request, StatusCode};
 use hyper::Body;
 use request_id::RequestId;
 
 use moka::future::{Cache, CacheBuilder};
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::{iter::once, time::Duration};
 use std::{net::SocketAddr, sync::atomic::Ordering};
 use strum::{EnumCount, EnumIter};
-use tokio::{process::Command, sync::broadcast};
+use tokio::{pin, process::Command, select, sync::broadcast, time::sleep};
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
-use tower_http::{cors::CorsLayer, normalize_path::NormalizePathLayer, trace::TraceLayer};
-use tracing::{error, error_span, info, trace_span};
+use tower_http::{normalize_path::NormalizePathLayer, trace::TraceLayer};
+use tower_service::Service;
+use tracing::{error, error_span, info, trace_span, warn};
 
 #[cfg(feature = "listenfd")]
 use listenfd::ListenFd;
@@ -41,11 +56,97 @@ use listenfd::ListenFd;
 pub enum ResponseCacheKey {
     BackupsNeeded,
     Health,
+    HealthJson,
     Status,
 }
 
 pub type ResponseCache = Cache<ResponseCacheKey, (StatusCode, &'static str, axum::body::Bytes)>;
 
+/// One chain's App and the requests that are sent to it
+#[derive(Clone)]
+pub struct ServedChain {
+    /// requests for `/<name>/` go to this chain. None if it is the only chain. Then it is served at `/`
+    pub name: Option<String>,
+    /// requests with one of these Host headers go to this chain without the `/<name>` prefix
+    pub hosts: Vec<String>,
+    pub app: Arc<App>,
+}
+
+/// The only chain is served at `/`. With several chains, each one is at `/<name>/` and at `/` for its hosts.
+/// `/health` is then the health of every chain
+pub fn make_chains_router(chains: &[ServedChain]) -> Router<()> {
+    if let [chain] = chains {
+        if chain.name.is_none() {
+            return make_router(chain.app.clone());
+        }
+    }
+
+    let names: Vec<_> = chains
+        .iter()
+        .map(|x| (x.name.clone().unwrap_or_default(), x.app.clone()))
+        .collect();
+
+    let mut by_path = Router::new()
+        .route("/health", get(status::health_all))
+        .with_state(Arc::new(names.clone()))
+        .fallback(errors::handler_404);
+
+    let mut by_host = HashMap::new();
+
+    for (chain, (name, app)) in chains.iter().zip(names) {
+        let router = make_router(app);
+
+        for host in chain.hosts.iter() {
+            by_host.insert(host.to_ascii_lowercase(), router.clone());
+        }
+
+        by_path = by_path.nest_service(&format!("/{}", name), router);
+    }
+
+    Router::new().fallback_service(HostRouter {
+        by_host: Arc::new(by_host),
+        by_path,
+    })
+}
+
+/// Sends requests for one of a chain's hosts to that chain. Everything else is routed by its path
+#[derive(Clone)]
+struct HostRouter {
+    by_host: Arc<HashMap<String, Router>>,
+    by_path: Router,
+}
+
+impl Service<Request<Body>> for HostRouter {
+    type Response = axum::response::Response;
+    type Error = Infallible;
+    type Future = RouteFuture<Body, Infallible>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        match request_host(&request).and_then(|x| self.by_host.get(&x)) {
+            Some(router) => router.clone().call(request),
+            None => self.by_path.call(request),
+        }
+    }
+}
+
+/// the lowercase host that the request was sent to. the port is not included
+fn request_host(request: &Request<Body>) -> Option<String> {
+    let host = match request.uri().host() {
+        Some(x) => x.to_string(),
+        None => {
+            let x: Authority = request.headers().get(HOST)?.to_str().ok()?.parse().ok()?;
+
+            x.host().to_string()
+        }
+    };
+
+    Some(host.to_ascii_lowercase())
+}
+
 /// build our axum Router
 pub fn make_router(app: Arc<App>) -> Router<()> {
     // setup caches for whatever the frontend needs
@@ -60,6 +161,8 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
 
     let response_cache = Arc::new(response_cache);
 
+    let trusted_proxies = TrustedProxies::new(app.config.trusted_proxies.clone());
+
     #[allow(unused_mut)]
     let mut router = Router::<Arc<App>>::new()
         // TODO: i think these routes could be done a lot better
@@ -77,68 +180,65 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
         // authenticated with and without trailing slash
         .route(
             "/rpc/:rpc_key",
-            post(rpc_proxy_http::proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/rpc/:rpc_key/",
-            post(rpc_proxy_http::proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         // authenticated debug route
         .route(
             "/debug/:rpc_key",
-            post(rpc_proxy_http::debug_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::debug_websocket_handler_with_key),
+            post(rpc_proxy_http::debug_proxy_web3_rpc).get(rpc_proxy_ws::debug_websocket_handler),
         )
         .route(
             "/debug/:rpc_key/",
-            post(rpc_proxy_http::debug_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::debug_websocket_handler_with_key),
+            post(rpc_proxy_http::debug_proxy_web3_rpc).get(rpc_proxy_ws::debug_websocket_handler),
         )
         // public fastest
         .route(
             "/fastest",
-            post(rpc_proxy_http::fastest_proxy_web3_rpc)
-                .get(rpc_proxy_ws::fastest_websocket_handler),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/fastest/",
-            post(rpc_proxy_http::fastest_proxy_web3_rpc)
-                .get(rpc_proxy_ws::fastest_websocket_handler),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         // authenticated fastest with and without trailing slash
         .route(
             "/fastest/:rpc_key",
-            post(rpc_proxy_http::fastest_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::fastest_websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/fastest/:rpc_key/",
-            post(rpc_proxy_http::fastest_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::fastest_websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         // public versus
         .route(
             "/versus",
-            post(rpc_proxy_http::versus_proxy_web3_rpc).get(rpc_proxy_ws::versus_websocket_handler),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/versus/",
-            post(rpc_proxy_http::versus_proxy_web3_rpc).get(rpc_proxy_ws::versus_websocket_handler),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         // authenticated versus
         .route(
             "/versus/:rpc_key",
-            post(rpc_proxy_http::versus_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::versus_websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         .route(
             "/versus/:rpc_key/",
-            post(rpc_proxy_http::versus_proxy_web3_rpc_with_key)
-                .get(rpc_proxy_ws::versus_websocket_handler_with_key),
+            post(rpc_proxy_http::proxy_web3_rpc).get(rpc_proxy_ws::websocket_handler),
         )
         //
+        // Server-sent events
+        //
+        .route("/sse/heads", get(sse::sse_heads))
+        .route("/sse/heads/:rpc_key", get(sse::sse_heads))
+        .route("/sse/pending_txs", get(sse::sse_pending_txs))
+        .route("/sse/pending_txs/:rpc_key", get(sse::sse_pending_txs))
+        //
         // System things
         //
         // TODO: response_cache should probably be inside State
@@ -154,6 +254,7 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
             "/status/backups_needed",
             get(status::backups_needed).route_layer(Extension(response_cache.clone())),
         )
+        .route("/status/tx/:tx_hash", get(status::tx_status))
         .route(
             "/status/debug_request",
             get(status::debug_request).route_layer(Extension(response_cache.clone())),
@@ -208,6 +309,8 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
                 .post(users::rpc_keys::rpc_keys_management)
                 .put(users::rpc_keys::rpc_keys_management),
         )
+        .route("/user/connect", get(users::rpc_keys::rpc_keys_connect_get))
+        .route("/user/estimate", post(users::estimate::user_estimate_post))
         // .route("/user/referral/:referral_link", get(users::user_referral_link_get))
         .route(
             "/user/referral",
@@ -221,6 +324,7 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
             "/user/referral/stats/shared-codes",
             get(users::referral::user_shared_referral_stats),
         )
+        .route("/user/errors", get(users::stats::user_errors_get))
         .route("/user/revert_logs", get(users::stats::user_revert_logs_get))
         .route(
             "/user/stats/aggregate",
@@ -258,7 +362,62 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
         .route(
             "/admin/imitate_login",
             post(admin::admin_imitate_login_post),
-        );
+        )
+        .route(
+            "/admin/bans",
+            get(admin::admin_bans_get)
+                .post(admin::admin_bans_post)
+                .delete(admin::admin_bans_delete),
+        )
+        .route("/admin/backends", get(admin::admin_backends_get))
+        .route(
+            "/admin/backends/scores",
+            get(admin::admin_backend_scores_get),
+        )
+        .route(
+            "/admin/backends/:name/disable",
+            post(admin::admin_backend_disable_post),
+        )
+        .route(
+            "/admin/backends/:name/enable",
+            post(admin::admin_backend_enable_post),
+        )
+        .route("/admin/cache/clear", post(admin::admin_cache_clear_post))
+        .route(
+            "/admin/cache_revalidation",
+            get(admin::admin_cache_revalidation_get),
+        )
+        .route(
+            "/admin/call_cache/:address",
+            delete(admin::admin_call_cache_delete),
+        )
+        .route(
+            "/admin/maintenance",
+            get(admin::admin_maintenance_get)
+                .post(admin::admin_maintenance_post)
+                .delete(admin::admin_maintenance_delete),
+        )
+        .route(
+            "/admin/log_filter",
+            get(admin::admin_log_filter_get).put(admin::admin_log_filter_put),
+        )
+        .route("/admin/memory", get(admin::admin_memory_get))
+        .route(
+            "/admin/response_cache",
+            get(admin::admin_response_cache_get)
+                .post(admin::admin_response_cache_post)
+                .delete(admin::admin_response_cache_delete),
+        )
+        .route(
+            "/admin/rpc_key_tags/:key_id",
+            get(admin::admin_rpc_key_tags_get).post(admin::admin_rpc_key_tags_post),
+        )
+        .route(
+            "/admin/standby",
+            get(admin::admin_standby_get).post(admin::admin_standby_post),
+        )
+        .route("/admin/summary", get(admin::admin_summary_get))
+        .route("/admin/tx_origin/:hash", get(admin::admin_tx_origin_get));
 
     #[cfg(feature = "stripe")]
     {
@@ -272,13 +431,35 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
     // layers are ordered bottom up
     // the last layer is first for requests and last for responses
     let router: Router<(), _> = router
+        // Bodies bigger than this are rejected while they are read. They are never parsed
+        .layer(DefaultBodyLimit::max(app.config.max_request_bytes as usize))
+        // Browsers on origins that `cors` doesn't allow. Their preflights were already answered by the CorsLayer
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            cors::reject_disallowed_origins,
+        ))
+        // Reject banned ips before we spend any time reading their request
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            authorization::reject_banned_ips,
+        ))
+        // A warm standby doesn't serve jsonrpc until it is promoted
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            standby::reject_standby,
+        ))
+        // Health checks and uptime bots skip rate limits and stats. CORS preflights are answered before this
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            exempt::exempt_traffic,
+        ))
         // Remove trailing slashes
         // TODO: this isn't working for me. why?
         .layer(NormalizePathLayer::trim_trailing_slash())
         // Mark the `Authorization` request header as sensitive so it doesn't show in logs
         .layer(SetSensitiveRequestHeadersLayer::new(once(AUTHORIZATION)))
-        // handle cors. we expect queries from all sorts of places
-        .layer(CorsLayer::very_permissive())
+        // handle cors. by default, we expect queries from all sorts of places
+        .layer(app.config.cors.layer())
         // request id
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
@@ -295,12 +476,25 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
                 */
                 let request_id = &request.extensions().get::<RequestId>().unwrap().0;
 
+                // the real client. not the load balancer
+                let ip = match (
+                    request.extensions().get::<ConnectInfo<SocketAddr>>(),
+                    request.extensions().get::<TrustedProxies>(),
+                ) {
+                    (Some(ConnectInfo(peer)), Some(x)) => {
+                        Some(x.client_ip(peer.ip(), request.headers()))
+                    }
+                    (Some(ConnectInfo(peer)), None) => Some(peer.ip()),
+                    (None, _) => None,
+                };
+
                 // And then we put it along with other information into the `request` span
                 // TODO: what other info should we attach? how can we attach an error and a tracing span here?
                 // TODO: how can we do a tracing_span OR an error_span?
                 let s = trace_span!(
                     "request",
                     id = %request_id,
+                    ?ip,
                     method = %request.method(),
                     path = %request.uri().path(),
                 );
@@ -315,7 +509,11 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
                 }
             }), // .on_failure(|| todo!("on failure that has the request and response body so we can debug more easily")),
         )
+        // return the request id
+        .layer(middleware::from_fn(request_id::add_request_id_header))
         .layer(request_id::RequestIdLayer)
+        // `ClientIp` only believes forwarded headers from these
+        .layer(Extension(trusted_proxies))
         // 404 for any unknown routes
         .fallback(errors::handler_404)
         .with_state(app);
@@ -323,14 +521,17 @@ pub fn make_router(app: Arc<App>) -> Router<()> {
     router
 }
 
-/// Start the frontend server.
+/// Start the frontend server. Every chain shares the port, so `chains` must have at least one
 pub async fn serve(
-    app: Arc<App>,
+    chains: Vec<ServedChain>,
     mut shutdown_receiver: broadcast::Receiver<()>,
     shutdown_complete_sender: broadcast::Sender<()>,
 ) -> Web3ProxyResult<()> {
     // TODO: read config for if fastest/versus should be available publicly. default off
-    let router = make_router(app.clone());
+    let router = make_chains_router(&chains);
+
+    // the port and the shutdown settings are shared by every chain
+    let app = chains[0].app.clone();
 
     // TODO: https://docs.rs/tower-http/latest/tower_http/propagate_header/index.html
 
@@ -355,13 +556,13 @@ pub async fn serve(
         axum::Server::try_bind(&addr)?
     };
 
-    // into_make_service is enough if we always run behind a proxy
+    // `ClientIp` needs the peer address even behind a proxy. it decides if the forwarded headers can be believed
     /*
-    It sequentially looks for an IP in:
-      - x-forwarded-for header (de-facto standard)
+    It uses the first of:
+      - x-forwarded-for header (rightmost hop that isn't a trusted proxy)
       - x-real-ip header
-      - forwarded header (new standard)
-      - axum::extract::ConnectInfo (if not behind proxy)
+      - axum::extract::ConnectInfo
+    The headers are only read if ConnectInfo is one of `trusted_proxies`
     */
     let make_service = {
         info!("connectinfo feature enabled");
@@ -384,11 +585,20 @@ pub async fn serve(
 
     app.frontend_port.store(port, Ordering::SeqCst);
 
+    // in-flight requests only get so long to finish
+    let mut grace_receiver = shutdown_receiver.resubscribe();
+    let grace = Duration::from_secs(app.config.shutdown_grace_secs);
+
     let server = server
         // TODO: option to use with_connect_info. we want it in dev, but not when running behind a proxy, but not
         .with_graceful_shutdown(async move {
             let _ = shutdown_receiver.recv().await;
 
+            // upgraded websockets aren't tracked by the server. tell them to close
+            for chain in chains.iter() {
+                chain.app.draining.send_replace(true);
+            }
+
             if let Some(shutdown_script) = app.config.shutdown_script.as_ref() {
                 let shutdown_script = Command::new(shutdown_script)
                     .args(&app.config.shutdown_script_args)
@@ -404,9 +614,22 @@ pub async fn serve(
                     }
                 };
             }
-        })
-        .await
-        .map_err(Into::into);
+        });
+
+    pin!(server);
+
+    let server = select! {
+        x = &mut server => x.map_err(Into::into),
+        _ = async {
+            let _ = grace_receiver.recv().await;
+
+            sleep(grace).await;
+        } => {
+            warn!(?grace, "requests were still running after the shutdown grace period");
+
+            Ok(())
+        }
+    };
 
     let _ = shutdown_complete_sender.send(());
 
@@ -1,9 +1,14 @@
 use std::task::{Context, Poll};
 
-use http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http::{HeaderValue, Request};
 use tower_service::Service;
 use ulid::Ulid;
 
+/// the request id is returned to the client in this header. it is in the access log and on every span for the request
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
 /// RequestId from x-amzn-trace-id header or new Ulid
 #[derive(Clone, Debug)]
 pub struct RequestId(pub String);
@@ -50,3 +55,19 @@ where
         self.inner.call(req)
     }
 }
+
+/// Return the request id to the client so that their reports can be matched to our logs
+pub async fn add_request_id_header<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|x| HeaderValue::from_str(&x.0).ok());
+
+    let mut response = next.run(request).await;
+
+    if let Some(request_id) = request_id {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+
+    response
+}
@@ -1,128 +1,210 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
-use super::authorization::{ip_is_authorized, key_is_authorized};
+use super::authorization::{insert_rate_limit_headers, Authorized};
+use super::client_ip::ClientIp;
 use super::request_id::RequestId;
-use super::rpc_proxy_ws::ProxyMode;
-use crate::errors::{RequestForError, Web3ProxyError};
+use crate::block_number::uses_pending_block;
+use crate::config::PendingBlockPolicy;
+use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyResult};
+use crate::get_logs::AUTO_PAGINATE_HEADER;
+use crate::jsonrpc;
+use crate::metrics::TooLarge;
+use crate::response_signing::{
+    ResponseSigner, SIGNATURE_HEADER, SIGNATURE_REQUEST_ID_HEADER, SIGNATURE_TIMESTAMP_HEADER,
+};
 use crate::{app::App, jsonrpc::JsonRpcRequestEnum};
 use axum::extract::rejection::JsonRejection;
-use axum::extract::{Path, State};
-use axum::headers::{Origin, Referer, UserAgent};
+use axum::extract::State;
 use axum::response::Response;
+use axum::Extension;
 use axum::{response::IntoResponse, Json};
-use axum::{Extension, TypedHeader};
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
-use http::HeaderMap;
+use chrono::Utc;
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
+use http::{HeaderMap, StatusCode};
 use itertools::Itertools;
-use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-/// POST /rpc -- Public entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
-/// Defaults to rate limiting by IP address, but can also read the Authorization header for a bearer token.
-/// If possible, please use a WebSocket instead.
-#[debug_handler]
-pub async fn proxy_web3_rpc(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    _proxy_web3_rpc(
-        app,
-        &ip,
-        origin.as_deref(),
-        payload,
-        ProxyMode::Best,
-        request_id,
-    )
-    .await
+/// the credits charged for a request. batches get the sum of their requests
+pub const COST_HEADER: &str = "X-W3P-COST";
+
+/// clients send this header (with "true") to spread their batch across backends instead of pinning it to one
+pub const UNPINNED_BATCH_HEADER: &str = "x-web3-proxy-unpinned-batch";
+
+/// true if the client sent the header that turns on eth_getLogs auto pagination
+fn wants_auto_paginate(request_headers: &HeaderMap) -> bool {
+    request_headers
+        .get(AUTO_PAGINATE_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map_or(false, |x| x.eq_ignore_ascii_case("true"))
 }
 
-#[debug_handler]
-pub async fn fastest_proxy_web3_rpc(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    // TODO: read the fastest number from params
-    // TODO: check that the app allows this without authentication
-    _proxy_web3_rpc(
-        app,
-        &ip,
-        origin.as_deref(),
-        payload,
-        ProxyMode::Fastest(0),
-        request_id,
-    )
-    .await
+/// false if the client sent the header that turns off batch pinning
+fn wants_pinned_batch(request_headers: &HeaderMap) -> bool {
+    !request_headers
+        .get(UNPINNED_BATCH_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map_or(false, |x| x.eq_ignore_ascii_case("true"))
 }
 
+/// Buffer the whole response and sign it. The signature covers the uncompressed body.
+async fn signed_response(
+    app: &App,
+    signer: &ResponseSigner,
+    status_code: StatusCode,
+    response: jsonrpc::Response,
+    request_id: &str,
+) -> Web3ProxyResult<Response> {
+    let body = response.to_json_string(app).await?;
+
+    let timestamp = Utc::now().timestamp() as u64;
+
+    let signature = signer.sign(body.as_bytes(), timestamp, request_id)?;
+
+    let mut response = (status_code, [(CONTENT_TYPE, "application/json")], body).into_response();
+
+    let response_headers = response.headers_mut();
+
+    response_headers.insert(SIGNATURE_HEADER, signature.parse()?);
+    response_headers.insert(SIGNATURE_TIMESTAMP_HEADER, timestamp.into());
+    response_headers.insert(SIGNATURE_REQUEST_ID_HEADER, request_id.parse()?);
+
+    Ok(response)
+}
+
+/// POST /rpc -- Entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
+/// Public routes are rate limited by ip. Routes with an rpc key are rate limited and billed by that key and can
+/// optionally be authorized based on origin, referer, or user agent.
+/// If possible, please use a WebSocket instead.
 #[debug_handler]
-pub async fn versus_proxy_web3_rpc(
+pub async fn proxy_web3_rpc(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
+    authorized: Result<Authorized, Web3ProxyError>,
     Extension(RequestId(request_id)): Extension<RequestId>,
+    request_headers: HeaderMap,
+    // body extractors always have to be last
     payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    _proxy_web3_rpc(
-        app,
-        &ip,
-        origin.as_deref(),
-        payload,
-        ProxyMode::Versus,
-        request_id,
-    )
-    .await
-}
-
-/// TODO: refactor this to use the builder pattern
-async fn _proxy_web3_rpc(
-    app: Arc<App>,
-    ip: &IpAddr,
-    origin: Option<&Origin>,
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-    proxy_mode: ProxyMode,
-    request_id: String,
 ) -> Result<Response, Response> {
     // TODO: create a stat if they error. (but we haven't parsed rpc_key yet, so it needs some thought)
-    let payload = payload
-        .map_err(|e| Web3ProxyError::from(e).into_response_with_id(None, None::<RequestForError>))?
+    let mut payload = payload
+        .map_err(|e| {
+            // the body was cut off at max_request_bytes. it was never parsed
+            let e = if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                app.request_metrics.record_too_large(TooLarge::Request);
+
+                Web3ProxyError::RequestTooLarge {
+                    max: app.config.max_request_bytes,
+                }
+            } else {
+                Web3ProxyError::from(e)
+            };
+
+            e.into_response_with_id(None, None::<RequestForError>)
+        })?
         .0;
 
+    if wants_auto_paginate(&request_headers) {
+        payload.default_auto_paginate();
+    }
+
     let first_id = payload.first_id();
 
-    let authorization = ip_is_authorized(&app, ip, origin, proxy_mode)
-        .await
+    let weight = app.rate_limit_weights.load().request_weight(&payload);
+
+    // the authorization was checked before the body was read, but errors are returned with the request's id.
+    // rate limits are checked here, so include the request id for clients that report their backoff
+    let Authorized(authorization) = authorized.map_err(|e| {
+        // authorizing only counted 1. report what the whole request would have used
+        let e = match e {
+            Web3ProxyError::RateLimited(a, retry_at, _) => {
+                Web3ProxyError::RateLimited(a, retry_at, weight)
+            }
+            e => e,
+        };
+
+        e.into_response_with_id(
+            first_id.clone(),
+            Some(RequestForError::RequestId(&request_id)),
+        )
+    })?;
+
+    // anonymous users wait longer for their invalid requests
+    let tarpit = if authorization.checks.rpc_secret_key_id.is_some() {
+        Duration::from_secs(2)
+    } else {
+        Duration::from_secs(5)
+    };
+
+    payload.tarpit_invalid(&app, &authorization, tarpit).await?;
+
+    authorization
+        .checks
+        .protocol
+        .check_request(&payload)
         .map_err(|e| e.into_response_with_id(first_id.clone(), None::<RequestForError>))?;
 
-    let authorization = Arc::new(authorization);
+    let rate_limit = app
+        .rate_limit_weight(&authorization, weight)
+        .await
+        .map_err(|e| {
+            e.into_response_with_id(
+                first_id.clone(),
+                Some(RequestForError::RequestId(&request_id)),
+            )
+        })?
+        .or(authorization.rate_limit);
+
+    let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
 
-    payload
-        .tarpit_invalid(&app, &authorization, Duration::from_secs(5))
-        .await?;
+    let signer = app
+        .response_signer
+        .clone()
+        .filter(|_| authorization.checks.sign_responses);
 
     // TODO: calculate payload bytes here (before turning into serde_json::Value). that will save serializing later
 
     // TODO: is first_id the right thing to attach to this error?
     // TODO: i think we want to attach the web3_request here. but that means we need to create it here
-    let (status_code, response, rpcs) = app
-        .proxy_web3_rpc(authorization, payload, Some(request_id))
+    let (status_code, response, rpcs, older_head, cost) = app
+        .proxy_web3_rpc(
+            authorization,
+            payload,
+            Some(request_id.clone()),
+            wants_pinned_batch(&request_headers),
+        )
         .await
-        .map_err(|e| e.into_response_with_id(first_id, None::<RequestForError>))?;
-
-    let mut response = (status_code, response).into_response();
+        .map_err(|e| {
+            e.into_response_with_id(
+                first_id.clone(),
+                Some(RequestForError::RequestId(&request_id)),
+            )
+        })?;
+
+    let retry_after = response.retry_after();
+
+    let mut response = match signer {
+        None => response
+            .into_http_response(status_code, &app)
+            .await
+            .map_err(|e| e.into_response_with_id(first_id, None::<RequestForError>))?,
+        Some(signer) => signed_response(&app, &signer, status_code, response, &request_id)
+            .await
+            .map_err(|e| e.into_response_with_id(first_id, None::<RequestForError>))?,
+    };
 
-    // TODO: DRY this up. it is the same code for public and private queries
     let response_headers = response.headers_mut();
 
+    if let Some(retry_after) = retry_after {
+        response_headers.insert(RETRY_AFTER, retry_after.into());
+    }
+
+    if let Some(rate_limit) = rate_limit {
+        insert_rate_limit_headers(response_headers, &rate_limit);
+    }
+
     // TODO: this might be slow. think about this more
-    // TODO: special string if no rpcs were used (cache hit)?
+    // TODO: special string if no rpcs were used (cache hit)? or is an empty string fine? maybe the rpc name + "cached"
     let mut backup_used = false;
 
     let rpcs: String = rpcs
@@ -148,65 +230,87 @@ async fn _proxy_web3_rpc(
             .expect("W3P-BACKEND-RPCS should always parse"),
     );
 
-    Ok(response)
-}
+    // what was charged for this request (or the whole batch). the same amount is saved to the accounting stats
+    response_headers.insert(
+        COST_HEADER,
+        cost.normalize()
+            .to_string()
+            .parse()
+            .expect("X-W3P-COST should always parse"),
+    );
 
-/// Authenticated entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
-/// Rate limit and billing based on the api key in the url.
-/// Can optionally authorized based on origin, referer, or user agent.
-/// If possible, please use a WebSocket instead.
-#[debug_handler]
-#[allow(clippy::too_many_arguments)]
-pub async fn proxy_web3_rpc_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
-    Path(rpc_key): Path<String>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    // body extractors always have to be last
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    _proxy_web3_rpc_with_key(
-        app,
-        &ip,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        rpc_key,
-        payload,
-        ProxyMode::Best,
-        request_id,
-    )
-    .await
+    // every backend was behind a head that this client already saw. "served/seen"
+    if let Some(older_head) = older_head {
+        response_headers.insert(
+            "X-W3P-OLDER-HEAD",
+            older_head
+                .to_string()
+                .parse()
+                .expect("X-W3P-OLDER-HEAD should always parse"),
+        );
+    }
+
+    // no new heads in a while. "latest" in this response may be old
+    if let Some(age) = app.head_staleness.stale_age() {
+        response_headers.insert(
+            "X-W3P-STALE-HEAD",
+            age.as_secs()
+                .to_string()
+                .parse()
+                .expect("X-W3P-STALE-HEAD should always parse"),
+        );
+    }
+
+    if let Some(rpc_secret_key_id) = rpc_secret_key_id {
+        response_headers.insert(
+            "X-W3P-KEY-ID",
+            rpc_secret_key_id
+                .to_string()
+                .parse()
+                .expect("X-CLIENT-IP should always parse"),
+        );
+    }
+
+    // TODO: user tier in the header
+
+    Ok(response)
 }
 
 // TODO: if a /debug/ request gets rejected by an invalid request, there won't be any kafka log
+/// Same as `proxy_web3_rpc`, but with some extra headers that are useful while debugging
 #[debug_handler]
-#[allow(clippy::too_many_arguments)]
-pub async fn debug_proxy_web3_rpc_with_key(
+pub async fn debug_proxy_web3_rpc(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
+    ClientIp(ip): ClientIp,
+    authorized: Result<Authorized, Web3ProxyError>,
+    request_id: Extension<RequestId>,
     request_headers: HeaderMap,
-    Path(rpc_key): Path<String>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
     // body extractors always have to be last
     payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
 ) -> Result<Response, Response> {
-    let mut response = match _proxy_web3_rpc_with_key(
-        app,
-        &ip,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        rpc_key,
-        payload,
-        ProxyMode::Debug,
+    // the params are rewritten while proxying, so check for "pending" now
+    let pending_block = match &payload {
+        Ok(Json(JsonRpcRequestEnum::Single(x))) => uses_pending_block(x),
+        Ok(Json(JsonRpcRequestEnum::Batch(x))) => x.iter().any(uses_pending_block),
+        Err(_) => false,
+    };
+
+    let pending_block = pending_block.then(|| match app.config.pending_block_policy {
+        PendingBlockPolicy::PendingState if app.balanced_rpcs.has_pending_state() => {
+            "pending_state"
+        }
+        PendingBlockPolicy::PendingState => "downgraded_to_latest",
+        PendingBlockPolicy::RewriteToLatest => "rewritten_to_latest",
+        PendingBlockPolicy::RouteToDesignated => "route_to_designated",
+        PendingBlockPolicy::ForwardAsIs => "forward_as_is",
+    });
+
+    let mut response = match proxy_web3_rpc(
+        State(app),
+        authorized,
         request_id,
+        request_headers.clone(),
+        payload,
     )
     .await
     {
@@ -228,147 +332,9 @@ pub async fn debug_proxy_web3_rpc_with_key(
 
     response_headers.insert("client-ip", ip.to_string().parse().unwrap());
 
-    Ok(response)
-}
-
-#[debug_handler]
-#[allow(clippy::too_many_arguments)]
-pub async fn fastest_proxy_web3_rpc_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    Path(rpc_key): Path<String>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    // body extractors always have to be last
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    _proxy_web3_rpc_with_key(
-        app,
-        &ip,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        rpc_key,
-        payload,
-        ProxyMode::Fastest(0),
-        request_id,
-    )
-    .await
-}
-
-#[allow(clippy::too_many_arguments)]
-#[debug_handler]
-pub async fn versus_proxy_web3_rpc_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    Path(rpc_key): Path<String>,
-    Extension(RequestId(request_id)): Extension<RequestId>,
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-) -> Result<Response, Response> {
-    _proxy_web3_rpc_with_key(
-        app,
-        &ip,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        rpc_key,
-        payload,
-        ProxyMode::Versus,
-        request_id,
-    )
-    .await
-}
-
-/// TODO: refactor this to use the builder pattern
-#[allow(clippy::too_many_arguments)]
-async fn _proxy_web3_rpc_with_key(
-    app: Arc<App>,
-    ip: &IpAddr,
-    origin: Option<&Origin>,
-    referer: Option<&Referer>,
-    user_agent: Option<&UserAgent>,
-    rpc_key: String,
-    payload: Result<Json<JsonRpcRequestEnum>, JsonRejection>,
-    proxy_mode: ProxyMode,
-    request_id: String,
-) -> Result<Response, Response> {
-    // TODO: DRY w/ proxy_web3_rpc
-    // TODO: create a stat if they error. (but we haven't parsed rpc_key yet, so it needs some thought)
-    let payload = payload
-        .map_err(|e| Web3ProxyError::from(e).into_response_with_id(None, None::<RequestForError>))?
-        .0;
-
-    let first_id = payload.first_id();
-
-    let rpc_key = rpc_key.parse().map_err(|e: Web3ProxyError| {
-        e.into_response_with_id(first_id.clone(), None::<RequestForError>)
-    })?;
-
-    let authorization =
-        key_is_authorized(&app, &rpc_key, ip, origin, proxy_mode, referer, user_agent)
-            .await
-            .map_err(|e| e.into_response_with_id(first_id.clone(), None::<RequestForError>))?;
-
-    let authorization = Arc::new(authorization);
-
-    payload
-        .tarpit_invalid(&app, &authorization, Duration::from_secs(2))
-        .await?;
-
-    let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
-
-    // TODO: pass web3_request to the map_err
-    let (status_code, response, rpcs) = app
-        .proxy_web3_rpc(authorization, payload, Some(request_id))
-        .await
-        .map_err(|e| e.into_response_with_id(first_id, None::<RequestForError>))?;
-
-    let mut response = (status_code, response).into_response();
-
-    let headers = response.headers_mut();
-
-    let mut backup_used = false;
-
-    // TODO: special string if no rpcs were used (cache hit)? or is an empty string fine? maybe the rpc name + "cached"
-    let rpcs: String = rpcs
-        .into_iter()
-        .map(|x| {
-            if x.backup {
-                backup_used = true;
-            }
-            x.name.clone()
-        })
-        .join(",");
-
-    headers.insert(
-        "X-W3P-BACKEND-RPCs",
-        rpcs.parse().expect("W3P-BACKEND-RPCS should always parse"),
-    );
-
-    headers.insert(
-        "X-W3P-BACKUP-RPC",
-        backup_used
-            .to_string()
-            .parse()
-            .expect("W3P-BACKEND-RPCS should always parse"),
-    );
-
-    if let Some(rpc_secret_key_id) = rpc_secret_key_id {
-        headers.insert(
-            "X-W3P-KEY-ID",
-            rpc_secret_key_id
-                .to_string()
-                .parse()
-                .expect("X-CLIENT-IP should always parse"),
-        );
+    if let Some(x) = pending_block {
+        response_headers.insert("X-W3P-PENDING-BLOCK", x.parse().unwrap());
     }
 
-    // TODO: user tier in the header
-
     Ok(response)
 }
@@ -2,39 +2,42 @@
 //!
 //! WebSockets are the preferred method of receiving requests, but not all clients have good support.
 
-use super::authorization::{ip_is_authorized, key_is_authorized, Authorization};
+use super::authorization::{Authorization, Authorized};
+use super::client_ip::ClientIp;
+use super::ws_queue::OutboundQueue;
+use crate::app::SubscriptionHandle;
 use crate::errors::{RequestForError, Web3ProxyError, Web3ProxyResponse};
 use crate::jsonrpc::{self, ParsedResponse, ValidatedRequest};
+use crate::memory::WebsocketMemoryGuard;
 use crate::{app::App, errors::Web3ProxyResult, jsonrpc::SingleRequest};
-use axum::headers::{Origin, Referer, UserAgent};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::{Path, State},
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::State,
     response::{IntoResponse, Redirect},
     TypedHeader,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use ethers::types::U64;
+use futures::stream::{SplitSink, SplitStream, StreamExt};
 use futures::SinkExt;
-use futures::{
-    future::AbortHandle,
-    stream::{SplitSink, SplitStream, StreamExt},
-};
 use handlebars::Handlebars;
 use hashbrown::HashMap;
 use http::{HeaderMap, StatusCode};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::net::IpAddr;
 use std::str::from_utf8_mut;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use tokio::select;
-use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock as AsyncRwLock};
+use tokio::sync::{broadcast, OwnedSemaphorePermit, RwLock as AsyncRwLock};
 use tracing::trace;
 
+/// one websocket's subscriptions by id
+type Subscriptions = AsyncRwLock<HashMap<U64, SubscriptionHandle>>;
+
 /// How to select backend servers for a request
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum ProxyMode {
     /// send to the "best" synced server. on error, try the next
     #[default]
@@ -50,214 +53,41 @@ pub enum ProxyMode {
     Debug,
 }
 
-/// Public entrypoint for WebSocket JSON-RPC requests.
-/// Queries a single server at a time
-#[debug_handler]
-pub async fn websocket_handler(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    _websocket_handler(ProxyMode::Best, app, &ip, origin.as_deref(), ws_upgrade).await
-}
-
-/// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
-/// Queries all synced backends with every request! This might get expensive!
-// #[debug_handler]
-pub async fn fastest_websocket_handler(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    // TODO: get the fastest number from the url params (default to 0/all)
-    // TODO: config to disable this
-    _websocket_handler(
-        ProxyMode::Fastest(0),
-        app,
-        &ip,
-        origin.as_deref(),
-        ws_upgrade,
-    )
-    .await
-}
-
-/// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
-/// Queries **all** backends with every request! This might get expensive!
-#[debug_handler]
-pub async fn versus_websocket_handler(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    origin: Option<TypedHeader<Origin>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    // TODO: config to disable this
-    _websocket_handler(ProxyMode::Versus, app, &ip, origin.as_deref(), ws_upgrade).await
-}
-
-async fn _websocket_handler(
-    proxy_mode: ProxyMode,
-    app: Arc<App>,
-    ip: &IpAddr,
-    origin: Option<&Origin>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    let authorization = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
-
-    let authorization = Arc::new(authorization);
-
-    match ws_upgrade {
-        Some(ws) => Ok(ws
-            .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket))
-            .into_response()),
-        None => {
-            if let Some(redirect) = &app.config.redirect_public_url {
-                // this is not a websocket. redirect to a friendly page
-                Ok(Redirect::permanent(redirect).into_response())
-            } else {
-                Err(Web3ProxyError::WebsocketOnly)
-            }
+impl ProxyMode {
+    /// `/debug/..`, `/fastest/..`, and `/versus/..` have their own modes. Every other route is `Best`
+    pub fn from_path(path: &str) -> Self {
+        match path.trim_start_matches('/').split('/').next() {
+            Some("debug") => Self::Debug,
+            // TODO: read the fastest number from the url
+            Some("fastest") => Self::Fastest(0),
+            Some("versus") => Self::Versus,
+            _ => Self::Best,
         }
     }
 }
 
-/// Authenticated entrypoint for WebSocket JSON-RPC requests. Web3 wallets use this.
-/// Rate limit and billing based on the api key in the url.
-/// Can optionally authorized based on origin, referer, or user agent.
+/// Entrypoint for WebSocket JSON-RPC requests. Web3 wallets use this.
+/// Public routes are rate limited by ip. Routes with an rpc key are rate limited and billed by that key and can
+/// optionally be authorized based on origin, referer, or user agent.
+/// `/fastest` and `/versus` query more than one server with every request! This might get expensive!
 #[debug_handler]
-pub async fn websocket_handler_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    Path(rpc_key): Path<String>,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    _websocket_handler_with_key(
-        ProxyMode::Best,
-        app,
-        &ip,
-        rpc_key,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        ws_upgrade,
-    )
-    .await
-}
-
-#[debug_handler]
-#[allow(clippy::too_many_arguments)]
-pub async fn debug_websocket_handler_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    Path(rpc_key): Path<String>,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    headers: HeaderMap,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    let mut response = _websocket_handler_with_key(
-        ProxyMode::Debug,
-        app,
-        &ip,
-        rpc_key,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        ws_upgrade,
-    )
-    .await?;
-
-    // add some headers that might be useful while debugging
-    let response_headers = response.headers_mut();
-
-    if let Some(x) = headers.get("x-amzn-trace-id").cloned() {
-        response_headers.insert("x-amzn-trace-id", x);
-    }
-
-    if let Some(x) = headers.get("x-balance-id").cloned() {
-        response_headers.insert("x-balance-id", x);
-    }
-
-    response_headers.insert("client-ip", ip.to_string().parse().unwrap());
-
-    Ok(response)
-}
-
-#[debug_handler]
-pub async fn fastest_websocket_handler_with_key(
-    State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    Path(rpc_key): Path<String>,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    // TODO: get the fastest number from the url params (default to 0/all)
-    _websocket_handler_with_key(
-        ProxyMode::Fastest(0),
-        app,
-        &ip,
-        rpc_key,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        ws_upgrade,
-    )
-    .await
-}
-
-#[debug_handler]
-pub async fn versus_websocket_handler_with_key(
+pub async fn websocket_handler(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
-    Path(rpc_key): Path<String>,
-    origin: Option<TypedHeader<Origin>>,
-    referer: Option<TypedHeader<Referer>>,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    ws_upgrade: Option<WebSocketUpgrade>,
-) -> Web3ProxyResponse {
-    _websocket_handler_with_key(
-        ProxyMode::Versus,
-        app,
-        &ip,
-        rpc_key,
-        origin.as_deref(),
-        referer.as_deref(),
-        user_agent.as_deref(),
-        ws_upgrade,
-    )
-    .await
-}
-
-#[allow(clippy::too_many_arguments)]
-async fn _websocket_handler_with_key(
-    proxy_mode: ProxyMode,
-    app: Arc<App>,
-    ip: &IpAddr,
-    rpc_key: String,
-    origin: Option<&Origin>,
-    referer: Option<&Referer>,
-    user_agent: Option<&UserAgent>,
+    Authorized(authorization): Authorized,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
-    let rpc_key = rpc_key.parse()?;
-
-    let authorization =
-        key_is_authorized(&app, &rpc_key, ip, origin, proxy_mode, referer, user_agent).await?;
-
-    trace!("websocket_handler_with_key {:?}", authorization);
-
-    let authorization = Arc::new(authorization);
+    trace!(?authorization, "websocket_handler");
 
     match ws_upgrade {
         Some(ws_upgrade) => {
-            Ok(ws_upgrade.on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket)))
+            authorization.checks.protocol.check_websocket()?;
+
+            // bigger messages close the socket
+            let max_message_size = app.config.max_request_bytes as usize;
+
+            Ok(ws_upgrade
+                .max_message_size(max_message_size)
+                .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket)))
         }
         None => {
             // if no websocket upgrade, this is probably a user loading the url with their browser
@@ -266,14 +96,11 @@ async fn _websocket_handler_with_key(
                 &app.config.redirect_rpc_key_url,
                 authorization.checks.rpc_secret_key_id,
             ) {
-                (None, None, _) => Err(Web3ProxyError::StatusCode(
-                    StatusCode::BAD_REQUEST,
-                    "this page is for rpcs".into(),
-                    None,
-                )),
                 (Some(redirect_public_url), _, None) => {
+                    // this is not a websocket. redirect to a friendly page
                     Ok(Redirect::permanent(redirect_public_url).into_response())
                 }
+                (None, _, None) => Err(Web3ProxyError::WebsocketOnly),
                 (_, Some(redirect_rpc_key_url), Some(rpc_key_id)) => {
                     let reg = Handlebars::new();
 
@@ -295,28 +122,86 @@ async fn _websocket_handler_with_key(
     }
 }
 
+/// Same as `websocket_handler`, but with some extra headers that are useful while debugging
+#[debug_handler]
+pub async fn debug_websocket_handler(
+    State(app): State<Arc<App>>,
+    ClientIp(ip): ClientIp,
+    authorized: Authorized,
+    headers: HeaderMap,
+    ws_upgrade: Option<WebSocketUpgrade>,
+) -> Web3ProxyResponse {
+    let mut response = websocket_handler(State(app), authorized, ws_upgrade).await?;
+
+    // add some headers that might be useful while debugging
+    let response_headers = response.headers_mut();
+
+    if let Some(x) = headers.get("x-amzn-trace-id").cloned() {
+        response_headers.insert("x-amzn-trace-id", x);
+    }
+
+    if let Some(x) = headers.get("x-balance-id").cloned() {
+        response_headers.insert("x-balance-id", x);
+    }
+
+    response_headers.insert("client-ip", ip.to_string().parse().unwrap());
+
+    Ok(response)
+}
+
 async fn proxy_web3_socket(app: Arc<App>, authorization: Arc<Authorization>, socket: WebSocket) {
     // split the websocket so we can read and write concurrently
     let (ws_tx, ws_rx) = socket.split();
 
-    let buffer = authorization.checks.max_concurrent_requests.unwrap_or(2048) as usize;
+    // our reader and subscriptions queue messages here for the writer. it is bounded by messages and bytes
+    let response_sender = OutboundQueue::new(
+        app.config.websocket_max_queued_messages,
+        app.config.websocket_max_queued_bytes,
+        app.memory.clone(),
+    );
 
-    // create a channel for our reader and writer can communicate. todo: benchmark different channels
-    // TODO: this should be bounded. async blocking on too many messages would be fine
-    let (response_sender, response_receiver) = mpsc::channel::<Message>(buffer);
+    let memory_guard = app.memory.websocket_opened();
 
-    tokio::spawn(write_web3_socket(response_receiver, ws_tx));
+    tokio::spawn(write_web3_socket(
+        response_sender.clone(),
+        ws_tx,
+        memory_guard,
+    ));
     tokio::spawn(read_web3_socket(app, authorization, ws_rx, response_sender));
 }
 
+/// keyed users see what each request was charged next to its result
+#[derive(Serialize)]
+struct CostedResponse {
+    #[serde(flatten)]
+    response: ParsedResponse,
+    w3p_cost: Decimal,
+}
+
+/// Also forget any subscriptions whose tasks have already exited so that long lived connections don't collect them
+async fn insert_subscription(
+    subscriptions: &Subscriptions,
+    subscription_id: U64,
+    handle: SubscriptionHandle,
+) {
+    let mut x = subscriptions.write().await;
+
+    x.retain(|_, x| !x.is_finished());
+
+    x.insert(subscription_id, handle);
+}
+
+/// the `Decimal` is what the request was charged. subscription management methods don't return it
 async fn websocket_proxy_web3_rpc(
     app: &Arc<App>,
     authorization: Arc<Authorization>,
     json_request: SingleRequest,
-    response_sender: &mpsc::Sender<Message>,
+    response_sender: &Arc<OutboundQueue>,
     subscription_count: &AtomicU64,
-    subscriptions: &AsyncRwLock<HashMap<U64, AbortHandle>>,
-) -> Web3ProxyResult<jsonrpc::Response> {
+    subscriptions: &Subscriptions,
+) -> Web3ProxyResult<(jsonrpc::Response, Option<Decimal>)> {
+    authorization.checks.protocol.check_single(&json_request)?;
+
     match &json_request.method[..] {
         "eth_subscribe" => {
             // todo!(this needs a permit)
@@ -341,18 +226,46 @@ async fn websocket_proxy_web3_rpc(
                         result: ref subscription_id,
                     } = response.payload
                     {
-                        let mut x = subscriptions.write().await;
-
                         let key: U64 = serde_json::from_str(subscription_id.get()).unwrap();
 
-                        x.insert(key, handle);
+                        insert_subscription(subscriptions, key, handle).await;
                     }
 
-                    Ok(response.into())
+                    Ok((response.into(), None))
                 }
                 Err(err) => Err(err),
             }
         }
+        "web3proxy_resubscribe" => {
+            // todo!(this needs a permit)
+            let web3_request = ValidatedRequest::new_with_app(
+                app,
+                authorization,
+                None,
+                None,
+                json_request.into(),
+                None,
+                None,
+            )
+            .await?;
+
+            let (handle, response) = app
+                .web3proxy_resubscribe(web3_request, subscription_count, response_sender.clone())
+                .await?;
+
+            if let jsonrpc::ResponsePayload::Success { result: ref x } = response.payload {
+                #[derive(Deserialize)]
+                struct Resubscribed {
+                    subscription: U64,
+                }
+
+                let x: Resubscribed = serde_json::from_str(x.get()).unwrap();
+
+                insert_subscription(subscriptions, x.subscription, handle).await;
+            }
+
+            Ok((response.into(), None))
+        }
         "eth_unsubscribe" => {
             // todo!(this needs a permit)
             let web3_request = ValidatedRequest::new_with_app(
@@ -384,13 +297,14 @@ async fn websocket_proxy_web3_rpc(
                 }
             };
 
-            // TODO: is this the right response?
+            // true if this connection had the subscription. ids from other connections are never found
             let partial_response = {
                 let mut x = subscriptions.write().await;
                 match x.remove(&subscription_id) {
                     None => false,
                     Some(handle) => {
                         handle.abort();
+                        response_sender.unsubscribed(subscription_id);
                         true
                     }
                 }
@@ -404,12 +318,12 @@ async fn websocket_proxy_web3_rpc(
             web3_request.set_response(&response);
             let response = response.parsed().await.expect("Response already parsed");
 
-            Ok(response.into())
+            Ok((response.into(), None))
         }
         _ => app
-            .proxy_web3_rpc(authorization, json_request.into(), None)
+            .proxy_web3_rpc(authorization, json_request.into(), None, true)
             .await
-            .map(|(_, response, _)| response),
+            .map(|(_, response, _, _, cost)| (response, Some(cost))),
     }
 }
 
@@ -418,9 +332,9 @@ async fn handle_socket_payload(
     app: &Arc<App>,
     authorization: &Arc<Authorization>,
     payload: &str,
-    response_sender: &mpsc::Sender<Message>,
+    response_sender: &Arc<OutboundQueue>,
     subscription_count: &AtomicU64,
-    subscriptions: Arc<AsyncRwLock<HashMap<U64, AbortHandle>>>,
+    subscriptions: Arc<Subscriptions>,
 ) -> Web3ProxyResult<(Message, Option<OwnedSemaphorePermit>)> {
     let (authorization, semaphore) = authorization.check_again(app).await?;
 
@@ -429,16 +343,23 @@ async fn handle_socket_payload(
         Ok(json_request) => {
             let request_id = json_request.id.clone();
 
+            let weight = app.rate_limit_weights.load().weight(&json_request.method);
+
             // TODO: move this to a seperate function so we can use the try operator
-            let x = websocket_proxy_web3_rpc(
-                app,
-                authorization.clone(),
-                json_request,
-                response_sender,
-                subscription_count,
-                &subscriptions,
-            )
-            .await;
+            let x = match app.rate_limit_weight(&authorization, weight).await {
+                Ok(_) => {
+                    websocket_proxy_web3_rpc(
+                        app,
+                        authorization.clone(),
+                        json_request,
+                        response_sender,
+                        subscription_count,
+                        &subscriptions,
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            };
 
             (request_id, x)
         }
@@ -446,7 +367,17 @@ async fn handle_socket_payload(
     };
 
     let response_str = match response {
-        Ok(x) => x.to_json_string().await?,
+        Ok((jsonrpc::Response::Single(x), Some(cost)))
+            if authorization.checks.rpc_secret_key_id.is_some() =>
+        {
+            let x = CostedResponse {
+                response: x.parsed().await?,
+                w3p_cost: cost.normalize(),
+            };
+
+            serde_json::to_string(&x).expect("to_string should always work here")
+        }
+        Ok((x, _)) => x.to_json_string(app).await?,
         Err(err) => {
             let (_, response_data) = err.as_response_parts(None::<RequestForError>);
 
@@ -463,13 +394,16 @@ async fn read_web3_socket(
     app: Arc<App>,
     authorization: Arc<Authorization>,
     mut ws_rx: SplitStream<WebSocket>,
-    response_sender: mpsc::Sender<Message>,
+    response_sender: Arc<OutboundQueue>,
 ) {
-    let subscriptions = Arc::new(AsyncRwLock::new(HashMap::new()));
+    let subscriptions: Arc<Subscriptions> = Default::default();
+    // subscription ids only need to be unique on this connection
     let subscription_count = Arc::new(AtomicU64::new(1));
 
     let (close_sender, mut close_receiver) = broadcast::channel(1);
 
+    let mut draining = app.draining.subscribe();
+
     loop {
         select! {
             msg = ws_rx.next() => {
@@ -513,7 +447,6 @@ async fn read_web3_socket(
                             }
                             Message::Close(_) => {
                                 trace!("closing websocket connection");
-                                // TODO: do something to close subscriptions?
                                 let _ = close_sender.send(true);
                                 return;
                             }
@@ -548,7 +481,7 @@ async fn read_web3_socket(
                             }
                         };
 
-                        if response_sender.send(response_msg).await.is_err() {
+                        if response_sender.send(response_msg).is_err() {
                             let _ = close_sender.send(true);
                         };
                     };
@@ -561,16 +494,34 @@ async fn read_web3_socket(
             _ = close_receiver.recv() => {
                 break;
             }
+            _ = draining.wait_for(|x| *x) => {
+                trace!("closing websocket connection for shutdown");
+
+                response_sender.close(Some(Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "server is shutting down".into(),
+                }))));
+
+                break;
+            }
         }
     }
+
+    // don't leave the subscriptions running until their next send fails
+    for (subscription_id, handle) in subscriptions.write().await.drain() {
+        trace!(?subscription_id, "aborting subscription on close");
+        handle.abort();
+    }
+
+    // aborted subscriptions don't close the queue. close it here so the writer exits once it has sent what is queued
+    response_sender.close(None);
 }
 
 async fn write_web3_socket(
-    mut response_rx: mpsc::Receiver<Message>,
+    response_rx: Arc<OutboundQueue>,
     mut ws_tx: SplitSink<WebSocket, Message>,
+    _memory_guard: WebsocketMemoryGuard,
 ) {
-    // TODO: increment counter for open websockets
-
     while let Some(msg) = response_rx.recv().await {
         // a response is ready
 
@@ -584,7 +535,8 @@ async fn write_web3_socket(
         };
     }
 
-    // TODO: decrement counter for open websockets
+    // stop subscriptions from queueing messages that will never be written
+    response_rx.close(None);
 }
 
 #[cfg(test)]
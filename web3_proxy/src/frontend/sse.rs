@@ -0,0 +1,293 @@
+//! Server-sent events for consumers that just want to hear about new blocks or pending transactions.
+//!
+//! These are fed by the same sources as `eth_subscribe` and follow the same auth and rate limits as websockets.
+//! Head events use the block number as their id. A client that reconnects with `Last-Event-ID` gets a `gap` event
+//! first if any blocks went by while it was away.
+
+use super::authorization::{Authorization, Authorized};
+use crate::app::App;
+use crate::errors::{Web3ProxyError, Web3ProxyResponse, Web3ProxyResult};
+use crate::frontend::authorization::RequestOrMethod;
+use crate::jsonrpc::ValidatedRequest;
+use async_stream::stream;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{extract::State, response::IntoResponse};
+use axum_macros::debug_handler;
+use ethers::types::U64;
+use futures::stream::{Stream, StreamExt};
+use http::{HeaderMap, StatusCode};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tracing::error;
+
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// proxies and load balancers close quiet connections. this keeps them busy
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Who a server-sent event connection counts against
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SseClient {
+    Ip(IpAddr),
+    RpcKey(NonZeroU64),
+}
+
+impl From<&Authorization> for SseClient {
+    fn from(authorization: &Authorization) -> Self {
+        match authorization.checks.rpc_secret_key_id {
+            Some(x) => Self::RpcKey(x),
+            None => Self::Ip(authorization.ip),
+        }
+    }
+}
+
+/// The block numbers (inclusive) that a client missed between the last block it saw and `next`
+pub fn missed_blocks(last_seen: Option<U64>, next: U64) -> Option<(U64, U64)> {
+    let last_seen = last_seen?;
+
+    if next > last_seen + 1 {
+        Some((last_seen + 1, next - 1))
+    } else {
+        None
+    }
+}
+
+/// Last-Event-ID is the decimal block number we sent. hex is accepted too
+pub fn parse_last_event_id(headers: &HeaderMap) -> Option<U64> {
+    let x = headers.get(LAST_EVENT_ID_HEADER)?.to_str().ok()?.trim();
+
+    if let Some(x) = x.strip_prefix("0x") {
+        U64::from_str_radix(x, 16).ok()
+    } else {
+        x.parse::<u64>().ok().map(U64::from)
+    }
+}
+
+/// Server-sent events for new heads. Public routes are rate limited by ip, routes with an rpc key by that key
+#[debug_handler]
+pub async fn sse_heads(
+    State(app): State<Arc<App>>,
+    Authorized(authorization): Authorized,
+    headers: HeaderMap,
+) -> Web3ProxyResponse {
+    _sse_heads(app, authorization, &headers).await
+}
+
+/// Server-sent events for pending transaction hashes. Public routes are rate limited by ip, routes with an rpc key by that key
+#[debug_handler]
+pub async fn sse_pending_txs(
+    State(app): State<Arc<App>>,
+    Authorized(authorization): Authorized,
+) -> Web3ProxyResponse {
+    _sse_pending_txs(app, authorization).await
+}
+
+/// held for as long as the stream is open
+async fn sse_permit(
+    app: &App,
+    authorization: &Authorization,
+) -> Web3ProxyResult<OwnedSemaphorePermit> {
+    let max_connections = app.config.sse_max_connections_per_client as usize;
+
+    let semaphore = app
+        .sse_semaphores
+        .get_with(authorization.into(), async move {
+            Arc::new(Semaphore::new(max_connections))
+        })
+        .await;
+
+    semaphore.try_acquire_owned().map_err(|_| {
+        Web3ProxyError::StatusCode(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many open server-sent event streams".into(),
+            None,
+        )
+    })
+}
+
+fn sse_response<S>(stream: S) -> Web3ProxyResponse
+where
+    S: Stream<Item = Result<Event, Infallible>> + Send + 'static,
+{
+    let keep_alive = KeepAlive::new()
+        .interval(KEEP_ALIVE_INTERVAL)
+        .text("keep-alive");
+
+    Ok(Sse::new(stream).keep_alive(keep_alive).into_response())
+}
+
+async fn _sse_heads(
+    app: Arc<App>,
+    authorization: Arc<Authorization>,
+    headers: &HeaderMap,
+) -> Web3ProxyResponse {
+    let permit = sse_permit(&app, &authorization).await?;
+
+    let last_event_id = parse_last_event_id(headers);
+
+    // we clone the watch before streaming so that theres less chance of missing anything
+    let mut head_block_receiver = WatchStream::new(app.watch_consensus_head_receiver.clone());
+
+    let stream = stream! {
+        let _permit = permit;
+
+        let mut last_seen = last_event_id;
+        let mut first = true;
+
+        while let Some(new_head) = head_block_receiver.next().await {
+            let Some(new_head) = new_head else {
+                continue;
+            };
+
+            let num = new_head.number();
+
+            // a reconnecting client might already have our current head
+            if first && last_seen.map_or(false, |x| num <= x) {
+                first = false;
+                continue;
+            }
+            first = false;
+
+            // a reconnecting (or slow) client can skip blocks. tell them instead of pretending nothing happened
+            if let Some((first_missed, last_missed)) = missed_blocks(last_seen, num) {
+                yield Ok(Event::default().event("gap").data(json!({
+                    "first": first_missed,
+                    "last": last_missed,
+                }).to_string()));
+            }
+
+            last_seen = Some(num);
+
+            let data = serde_json::to_string(&new_head.0).expect("blocks should always serialize");
+
+            let web3_request = ValidatedRequest::new_with_app(
+                &app,
+                authorization.clone(),
+                None,
+                None,
+                RequestOrMethod::Method("eth_subscribe(newHeads)".into(), 0),
+                Some(new_head),
+                None,
+            )
+            .await;
+
+            let web3_request = match web3_request {
+                Ok(x) => x,
+                Err(err) => {
+                    error!(?err, "error creating sse web3_request");
+                    break;
+                }
+            };
+
+            if let Some(reason) = app.subscription_rate_limited(&web3_request).await {
+                yield Ok(Event::default().event("error").data(reason));
+                break;
+            }
+
+            web3_request.set_response(data.len() as u64);
+
+            yield Ok(Event::default().id(num.as_u64().to_string()).event("newHeads").data(data));
+        }
+    };
+
+    sse_response(stream)
+}
+
+async fn _sse_pending_txs(app: Arc<App>, authorization: Arc<Authorization>) -> Web3ProxyResponse {
+    // same rules as eth_subscribe
+    if !(app.config.free_subscriptions || authorization.active_premium().await) {
+        return Err(Web3ProxyError::AccessDenied(
+            "pending transactions require an active premium account".into(),
+        ));
+    }
+
+    let permit = sse_permit(&app, &authorization).await?;
+
+    // we subscribe before streaming so that theres less chance of missing anything
+    let mut pending_txid_firehose = BroadcastStream::new(app.pending_txid_firehose.subscribe());
+
+    let stream = stream! {
+        let _permit = permit;
+
+        while let Some(maybe_txid) = pending_txid_firehose.next().await {
+            let txid = match maybe_txid {
+                Ok(x) => x,
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    // slow clients miss some pending transactions instead of buffering all of them
+                    yield Ok(Event::default().event("gap").data(json!({ "missed": missed }).to_string()));
+                    continue;
+                }
+            };
+
+            let web3_request = ValidatedRequest::new_with_app(
+                &app,
+                authorization.clone(),
+                None,
+                None,
+                RequestOrMethod::Method("eth_subscribe(newPendingTransactions)".into(), 0),
+                None,
+                None,
+            )
+            .await;
+
+            let web3_request = match web3_request {
+                Ok(x) => x,
+                Err(err) => {
+                    error!(?err, "error creating sse web3_request");
+                    break;
+                }
+            };
+
+            if let Some(reason) = app.subscription_rate_limited(&web3_request).await {
+                yield Ok(Event::default().event("error").data(reason));
+                break;
+            }
+
+            let data = json!(txid).to_string();
+
+            web3_request.set_response(data.len() as u64);
+
+            yield Ok(Event::default().event("newPendingTransactions").data(data));
+        }
+    };
+
+    sse_response(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps() {
+        assert_eq!(missed_blocks(None, 10.into()), None);
+        assert_eq!(missed_blocks(Some(9.into()), 10.into()), None);
+        assert_eq!(missed_blocks(Some(10.into()), 10.into()), None);
+        assert_eq!(
+            missed_blocks(Some(5.into()), 10.into()),
+            Some((6.into(), 9.into()))
+        );
+    }
+
+    #[test]
+    fn last_event_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_last_event_id(&headers), None);
+
+        headers.insert(LAST_EVENT_ID_HEADER, "42".parse().unwrap());
+        assert_eq!(parse_last_event_id(&headers), Some(42.into()));
+
+        headers.insert(LAST_EVENT_ID_HEADER, "0x2a".parse().unwrap());
+        assert_eq!(parse_last_event_id(&headers), Some(42.into()));
+
+        headers.insert(LAST_EVENT_ID_HEADER, "junk".parse().unwrap());
+        assert_eq!(parse_last_event_id(&headers), None);
+    }
+}
@@ -0,0 +1,72 @@
+//! Keep jsonrpc away from a warm standby. See `crate::standby`.
+
+use super::request_id::RequestId;
+use crate::app::App;
+use crate::errors::{RequestForError, Web3ProxyError};
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::Response;
+use http::Request;
+use std::sync::Arc;
+
+/// true for the routes that proxy jsonrpc, websockets, and server-sent events
+pub fn serves_jsonrpc(path: &str) -> bool {
+    let first = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+
+    matches!(first, "" | "rpc" | "debug" | "fastest" | "versus" | "sse")
+}
+
+/// While on standby, jsonrpc gets a 503 before anything else is done with it. Admin, health, status, and user routes
+/// are unaffected so that admins can still log in and promote this server.
+pub async fn reject_standby<B>(
+    State(app): State<Arc<App>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if app.standby.is_on() && serves_jsonrpc(request.uri().path()) {
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(|x| x.0.as_str());
+
+        return Web3ProxyError::Standby
+            .into_response_with_id(None, request_id.map(RequestForError::RequestId));
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jsonrpc_routes() {
+        for path in [
+            "/",
+            "",
+            "/rpc/01H0000000000000000000000A",
+            "/debug/01H0000000000000000000000A/",
+            "/fastest",
+            "/versus/01H0000000000000000000000A",
+            "/sse/heads",
+        ] {
+            assert!(serves_jsonrpc(path), "{}", path);
+        }
+
+        for path in [
+            "/health",
+            "/status",
+            "/backups_needed",
+            "/admin/standby",
+            "/user/login/0x0000000000000000000000000000000000000001",
+            "/rpcs",
+        ] {
+            assert!(!serves_jsonrpc(path), "{}", path);
+        }
+    }
+}
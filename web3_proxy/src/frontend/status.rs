@@ -3,21 +3,24 @@
 //! For ease of development, users can currently access these endponts.
 //! They will eventually move to another port.
 
+use super::client_ip::ClientIp;
 use super::{ResponseCache, ResponseCacheKey};
 use crate::{
     app::{App, APP_USER_AGENT},
-    errors::Web3ProxyError,
+    errors::{Web3ProxyError, Web3ProxyResponse},
+    memory::memory_report,
 };
 use axum::{
     body::{Bytes, Full},
-    extract::State,
+    extract::{Path, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension, Json,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
+use ethers::types::TxHash;
 use hashbrown::HashMap;
+use http::header::ACCEPT;
 use http::HeaderMap;
 use moka::future::Cache;
 use once_cell::sync::Lazy;
@@ -26,9 +29,13 @@ use serde_json::json;
 use std::{sync::Arc, time::Duration};
 use tokio::time::timeout;
 use tracing::trace;
+use web3_proxy_client::types::TxStatus;
 
 static HEALTH_OK: Lazy<Bytes> = Lazy::new(|| Bytes::from("OK\n"));
 static HEALTH_NOT_OK: Lazy<Bytes> = Lazy::new(|| Bytes::from(":(\n"));
+static HEALTH_STALE_HEAD: Lazy<Bytes> = Lazy::new(|| Bytes::from("stale head\n"));
+static HEALTH_STANDBY: Lazy<Bytes> = Lazy::new(|| Bytes::from("standby\n"));
+static HEALTH_DB_DOWN: Lazy<Bytes> = Lazy::new(|| Bytes::from("OK\ndatabase down\n"));
 
 static BACKUPS_NEEDED_TRUE: Lazy<Bytes> = Lazy::new(|| Bytes::from("true\n"));
 static BACKUPS_NEEDED_FALSE: Lazy<Bytes> = Lazy::new(|| Bytes::from("false\n"));
@@ -36,10 +43,33 @@ static BACKUPS_NEEDED_FALSE: Lazy<Bytes> = Lazy::new(|| Bytes::from("false\n"));
 static CONTENT_TYPE_JSON: &str = "application/json";
 static CONTENT_TYPE_PLAIN: &str = "text/plain";
 
+/// Where a transaction relayed by this server is. Other transactions are not known
+#[debug_handler]
+pub async fn tx_status(
+    State(app): State<Arc<App>>,
+    Path(tx_hash): Path<TxHash>,
+) -> Web3ProxyResponse {
+    let tx_tracker = app.tx_tracker.as_ref().ok_or_else(|| {
+        Web3ProxyError::StatusCode(
+            StatusCode::NOT_IMPLEMENTED,
+            "transaction tracking is off".into(),
+            None,
+        )
+    })?;
+
+    let tracked = tx_tracker.get(&tx_hash).ok_or(Web3ProxyError::NotFound)?;
+
+    Ok(Json(TxStatus {
+        tx_hash,
+        status: tracked,
+    })
+    .into_response())
+}
+
 #[debug_handler]
 pub async fn debug_request(
     State(app): State<Arc<App>>,
-    ip: InsecureClientIp,
+    ip: ClientIp,
     headers: HeaderMap,
 ) -> impl IntoResponse {
     let (_, _, status) = _status(app).await;
@@ -75,16 +105,33 @@ pub async fn debug_request(
 }
 
 /// Health check page for load balancers to use.
+/// Clients that accept json get the head block and how many rpcs are synced. Everyone else gets a line of text
 #[debug_handler]
 pub async fn health(
     State(app): State<Arc<App>>,
     Extension(cache): Extension<Arc<ResponseCache>>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, Web3ProxyError> {
-    let (code, content_type, body) = timeout(
-        Duration::from_secs(1),
-        cache.get_with(ResponseCacheKey::Health, async move { _health(app).await }),
-    )
-    .await?;
+    let accepts_json = headers
+        .get(ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.contains(CONTENT_TYPE_JSON));
+
+    let f = async move {
+        if accepts_json {
+            cache
+                .get_with(ResponseCacheKey::HealthJson, async move {
+                    _health_json(app).await
+                })
+                .await
+        } else {
+            cache
+                .get_with(ResponseCacheKey::Health, async move { _health(app).await })
+                .await
+        }
+    };
+
+    let (code, content_type, body) = timeout(Duration::from_secs(1), f).await?;
 
     let x = Response::builder()
         .status(code)
@@ -95,19 +142,121 @@ pub async fn health(
     Ok(x)
 }
 
+/// `/health` for a process that serves several chains. It is only healthy if every chain is.
+/// An unhealthy chain's health is returned with the chain's name in front. Each chain's own health is at `/<name>/health`
+pub async fn health_all(State(chains): State<Arc<Vec<(String, Arc<App>)>>>) -> Response {
+    let mut first = None;
+
+    for (name, app) in chains.iter() {
+        let (code, content_type, body) = health_status(app);
+
+        if !code.is_success() {
+            let body = format!("{}: {}", name, String::from_utf8_lossy(&body));
+
+            return (code, [("content-type", content_type)], body).into_response();
+        }
+
+        first.get_or_insert((code, content_type, body));
+    }
+
+    let (code, content_type, body) = first.expect("at least one chain is always served");
+
+    (code, [("content-type", content_type)], body).into_response()
+}
+
 // TODO: _health doesn't need to be async, but _quick_cache_ttl needs an async function
 #[inline]
 async fn _health(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
     trace!("health is not cached");
 
-    if app.balanced_rpcs.synced() {
-        (StatusCode::OK, CONTENT_TYPE_PLAIN, HEALTH_OK.clone())
-    } else {
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
-            CONTENT_TYPE_PLAIN,
-            HEALTH_NOT_OK.clone(),
-        )
+    health_status(&app)
+}
+
+#[inline]
+async fn _health_json(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
+    trace!("health json is not cached");
+
+    let health = Health::check(&app);
+
+    let head_block = app.watch_consensus_head_receiver.borrow().clone();
+
+    let body = json!({
+        "chain_id": app.config.chain_id,
+        "head_block_hash": head_block.as_ref().map(|x| x.hash()),
+        "head_block_num": head_block.as_ref().map(|x| x.number()),
+        "min_synced_rpcs": app.balanced_rpcs.min_head_rpcs(),
+        "status": health.as_str(),
+        "synced_rpcs": app.balanced_rpcs.num_synced_rpcs(),
+        "total_rpcs": app.balanced_rpcs.len(),
+    });
+
+    let body = Bytes::from(body.to_string().into_bytes());
+
+    (health.code(), CONTENT_TYPE_JSON, body)
+}
+
+/// Also used for exempt traffic, which doesn't go through the response cache.
+pub(super) fn health_status(app: &App) -> (StatusCode, &'static str, Bytes) {
+    let health = Health::check(app);
+
+    (health.code(), CONTENT_TYPE_PLAIN, health.text())
+}
+
+/// Checked in order. Only memory is read, so this is cheap enough for load balancers to poll every second
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Health {
+    /// fewer than `min_synced_rpcs` are on the consensus head
+    NotSynced,
+    StaleHead,
+    /// A healthy standby is still a 503 so that load balancers don't send it traffic
+    Standby,
+    /// A server without its database still serves anonymous requests, so it is a 200 with the details on a second line
+    DbDown,
+    Ok,
+}
+
+impl Health {
+    fn check(app: &App) -> Self {
+        let rpcs = &app.balanced_rpcs;
+
+        if !rpcs.synced() || rpcs.num_synced_rpcs() < rpcs.min_head_rpcs() {
+            Self::NotSynced
+        } else if app.head_staleness.is_stale() {
+            Self::StaleHead
+        } else if app.standby.is_on() {
+            Self::Standby
+        } else if app.degraded.is_on() {
+            Self::DbDown
+        } else {
+            Self::Ok
+        }
+    }
+
+    fn code(self) -> StatusCode {
+        match self {
+            Self::NotSynced | Self::StaleHead | Self::Standby => StatusCode::SERVICE_UNAVAILABLE,
+            Self::DbDown | Self::Ok => StatusCode::OK,
+        }
+    }
+
+    fn text(self) -> Bytes {
+        match self {
+            Self::NotSynced => HEALTH_NOT_OK.clone(),
+            Self::StaleHead => HEALTH_STALE_HEAD.clone(),
+            Self::Standby => HEALTH_STANDBY.clone(),
+            Self::DbDown => HEALTH_DB_DOWN.clone(),
+            Self::Ok => HEALTH_OK.clone(),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::NotSynced => "not_synced",
+            Self::StaleHead => "stale_head",
+            Self::Standby => "standby",
+            Self::DbDown => "database_down",
+            Self::Ok => "ok",
+        }
     }
 }
 
@@ -197,19 +346,34 @@ async fn _status(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
         "balanced_rpcs": app.balanced_rpcs,
         "bundler_4337_rpcs": app.bundler_4337_rpcs,
         "caches": [
+            app.archive_response_cache.as_ref().map(MokaCacheSerializer),
+            app.head_watermarks.as_ref().map(|x| MokaCacheSerializer(&x.heads)),
             MokaCacheSerializer(&app.ip_semaphores),
             MokaCacheSerializer(&app.jsonrpc_response_cache),
             MokaCacheSerializer(&app.rpc_secret_key_cache),
             MokaCacheSerializer(&app.user_balance_cache.0),
             MokaCacheSerializer(&app.user_semaphores),
         ],
+        "canary": app.canary,
         "chain_id": app.config.chain_id,
+        "config_reloads": app.config_reloads,
+        "degraded": app.degraded,
+        "disk_cache": app.disk_cache,
         "head_block_hash": head_block.as_ref().map(|x| x.hash()),
         "head_block_num": head_block.as_ref().map(|x| x.number()),
+        "head_stale_secs": app.head_staleness.stale_age().map(|x| x.as_secs()),
         "hostname": app.hostname,
+        "memory": memory_report(&app, false),
+        "min_synced_rpcs": app.balanced_rpcs.min_head_rpcs(),
         "payment_factory_address": app.config.deposit_factory_contract,
         "pending_txid_firehose": app.pending_txid_firehose,
         "private_rpcs": app.protected_rpcs,
+        "private_rpcs_mode": app.private_rpcs().as_str(),
+        "standby": app.standby,
+        "stat_buffer": app.stat_buffer_status.as_deref(),
+        "synced_rpcs": app.balanced_rpcs.num_synced_rpcs(),
+        "total_rpcs": app.balanced_rpcs.len(),
+        "tx_tracker": app.tx_tracker,
         "uptime": app.start.elapsed().as_secs(),
         "version": APP_USER_AGENT,
     });
@@ -218,10 +382,10 @@ async fn _status(app: Arc<App>) -> (StatusCode, &'static str, Bytes) {
 
     let body = Bytes::from(body);
 
-    let code = if app.balanced_rpcs.synced() {
-        StatusCode::OK
+    let code = if Health::check(&app) == Health::NotSynced {
+        StatusCode::SERVICE_UNAVAILABLE
     } else {
-        StatusCode::INTERNAL_SERVER_ERROR
+        StatusCode::OK
     };
 
     (code, CONTENT_TYPE_JSON, body)
@@ -2,6 +2,7 @@
 use crate::app::App;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
 use crate::frontend::authorization::login_is_authorized;
+use crate::frontend::client_ip::ClientIp;
 use crate::globals::{global_db_conn, global_db_replica_conn};
 use crate::secrets::RpcSecretKey;
 use crate::user_token::UserBearerToken;
@@ -11,7 +12,6 @@ use axum::{
     response::IntoResponse,
     Json, TypedHeader,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
 use entities::{self, login, pending_login, referee, referrer, rpc_key, user};
@@ -21,7 +21,7 @@ use http::StatusCode;
 use migration::sea_orm::prelude::{Decimal, Uuid};
 use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseTransaction, EntityTrait, IntoActiveModel,
-    QueryFilter, TransactionTrait,
+    PaginatorTrait, QueryFilter, QuerySelect, TransactionTrait,
 };
 use serde::{Deserialize, Serialize};
 use siwe::{Message, VerificationOpts};
@@ -30,7 +30,7 @@ use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
 use time::{Duration, OffsetDateTime};
-use tracing::{error, trace, warn};
+use tracing::{error, info, trace, warn};
 use ulid::Ulid;
 
 /// Query params for our `post_login` handler.
@@ -77,7 +77,7 @@ pub struct LoginPostResponse {
 #[debug_handler]
 pub async fn user_login_get(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     // TODO: what does axum's error handling look like if the path fails to parse?
     Path(mut params): Path<HashMap<String, String>>,
 ) -> Web3ProxyResponse {
@@ -202,10 +202,21 @@ pub async fn register_new_user(
     let new_user = new_user.insert(txn).await?;
 
     // create the user's first api key
+    let user_rpc_key = create_default_rpc_key(txn, new_user.id).await?;
+
+    Ok((new_user, user_rpc_key))
+}
+
+/// A key with the default settings. Its limits come from the user's tier.
+/// you MUST commit the `txn` after calling this function!
+async fn create_default_rpc_key(
+    txn: &DatabaseTransaction,
+    user_id: u64,
+) -> anyhow::Result<rpc_key::Model> {
     let rpc_secret_key = RpcSecretKey::new();
 
     let user_rpc_key = rpc_key::ActiveModel {
-        user_id: sea_orm::Set(new_user.id),
+        user_id: sea_orm::Set(user_id),
         secret_key: sea_orm::Set(rpc_secret_key.into()),
         description: sea_orm::Set(None),
         ..Default::default()
@@ -216,7 +227,33 @@ pub async fn register_new_user(
         .await
         .web3_context("Failed saving new user key")?;
 
-    Ok((new_user, user_rpc_key))
+    Ok(user_rpc_key)
+}
+
+/// Give a user with no keys at all their default key. Users with any keys (even inactive ones) are left alone.
+/// Returns None if the user already had a key.
+async fn ensure_default_rpc_key(
+    txn: &DatabaseTransaction,
+    user_id: u64,
+) -> anyhow::Result<Option<rpc_key::Model>> {
+    // lock the user so that two logins at once can't both create a key
+    user::Entity::find_by_id(user_id)
+        .lock_exclusive()
+        .one(txn)
+        .await?;
+
+    let num_keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user_id))
+        .count(txn)
+        .await?;
+
+    if num_keys > 0 {
+        return Ok(None);
+    }
+
+    let x = create_default_rpc_key(txn, user_id).await?;
+
+    Ok(Some(x))
 }
 
 /// `POST /user/login` - Register or login by posting a signed "siwe" message.
@@ -225,7 +262,7 @@ pub async fn register_new_user(
 #[debug_handler]
 pub async fn user_login_post(
     State(app): State<Arc<App>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Query(query): Query<PostLoginQuery>,
     Json(payload): Json<PostLogin>,
 ) -> Web3ProxyResponse {
@@ -383,7 +420,15 @@ pub async fn user_login_post(
                 txn.commit().await?;
             }
 
-            // the user is already registered
+            // the user is already registered. they might not have any keys if they were created some other way
+            let txn = db_conn.begin().await?;
+
+            if let Some(x) = ensure_default_rpc_key(&txn, caller.id).await? {
+                info!(user_id=%caller.id, rpc_key_id=%x.id, "created a default rpc key at login");
+            }
+
+            txn.commit().await?;
+
             let user_rpc_keys = rpc_key::Entity::find()
                 .filter(rpc_key::Column::UserId.eq(caller.id))
                 .all(&db_conn)
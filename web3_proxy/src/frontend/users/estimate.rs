@@ -0,0 +1,102 @@
+//! Tell users what their requests cost before they send them.
+use crate::app::App;
+use crate::compute_units::ComputeUnit;
+use crate::errors::{Web3ProxyError, Web3ProxyResponse};
+use crate::jsonrpc::{JsonRpcRequestEnum, ValidatedRequest};
+use axum::{
+    extract::State,
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use rust_decimal::Decimal;
+use std::sync::Arc;
+use web3_proxy_client::types::{Estimate, RequestEstimate};
+
+/// `POST /user/estimate` -- Use a bearer token to see what a jsonrpc request (or batch) would be charged.
+///
+/// Nothing is sent to a backend and nothing is charged. Block params are checked against the current head the same
+/// way a real request's are, so archive requests are priced as archive requests.
+/// Whether a request will be served from the cache isn't known ahead of time, so both prices are given.
+/// Methods priced by the size of their response also give the price per byte.
+#[debug_handler]
+pub async fn user_estimate_post(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> Web3ProxyResponse {
+    app.bearer_is_authorized(bearer)
+        .await?
+        .ok_or(Web3ProxyError::InvalidUserKey)?;
+
+    let requests = match payload {
+        JsonRpcRequestEnum::Single(x) => vec![x],
+        JsonRpcRequestEnum::Batch(x) => x,
+    };
+
+    let authorization = Arc::new(crate::frontend::authorization::Authorization::internal()?);
+
+    let head_block = app.balanced_rpcs.head_block();
+
+    let usd_per_cu = app.config.usd_per_cu.unwrap_or_default();
+
+    let mut cache_hit = Decimal::ZERO;
+    let mut cache_miss = Decimal::ZERO;
+    let mut estimates = Vec::with_capacity(requests.len());
+
+    for request in requests {
+        let id = request.id.clone();
+        let method = request.method.to_string();
+
+        let web3_request = ValidatedRequest::new_with_app(
+            &app,
+            authorization.clone(),
+            None,
+            None,
+            request.into(),
+            head_block.clone(),
+            None,
+        )
+        .await?;
+
+        // this request is never sent. it must not be saved as a stat
+        let mut web3_request =
+            Arc::into_inner(web3_request).expect("a new request should only have one reference");
+        web3_request.stat_sender.take();
+
+        let cacheable = web3_request.cache_mode.is_some() || web3_request.call_cache.is_some();
+
+        let archive_request = web3_request.response.lock().archive_request;
+
+        let estimate = ComputeUnit::estimate(
+            &method,
+            app.config.chain_id,
+            web3_request
+                .vendor_method
+                .as_ref()
+                .and_then(|x| x.compute_units),
+            archive_request,
+            cacheable,
+            &usd_per_cu,
+        );
+
+        cache_hit += estimate.cache_hit;
+        cache_miss += estimate.cache_miss;
+
+        estimates.push(RequestEstimate {
+            id,
+            method,
+            estimate,
+        });
+    }
+
+    let response = Estimate {
+        cache_hit,
+        cache_miss,
+        requests: estimates,
+        usd_per_cu,
+    };
+
+    Ok(Json(response).into_response())
+}
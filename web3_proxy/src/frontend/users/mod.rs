@@ -1,5 +1,6 @@
 //! Handle registration, logins, and managing account data.
 pub mod authentication;
+pub mod estimate;
 pub mod payment;
 pub mod referral;
 pub mod rpc_keys;
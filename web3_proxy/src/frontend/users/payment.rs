@@ -2,6 +2,7 @@ use crate::app::App;
 use crate::balance::Balance;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult};
 use crate::frontend::authorization::login_is_authorized;
+use crate::frontend::client_ip::ClientIp;
 use crate::frontend::users::authentication::register_new_user;
 use crate::globals::{global_db_conn, global_db_replica_conn};
 use crate::premium::{get_user_and_tier_from_address, grant_premium_tier};
@@ -12,7 +13,6 @@ use axum::{
     response::IntoResponse,
     Json, TypedHeader,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use entities::{
     admin_increase_balance_receipt, increase_on_chain_balance_receipt,
@@ -194,7 +194,7 @@ pub async fn user_admin_deposits_get(
 #[debug_handler]
 pub async fn user_balance_post(
     State(app): State<Arc<App>>,
-    ip: Option<InsecureClientIp>,
+    ip: Option<ClientIp>,
     Path(mut params): Path<HashMap<String, String>>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> Web3ProxyResponse {
@@ -202,7 +202,7 @@ pub async fn user_balance_post(
     // rate limit by bearer token **OR** IP address
     if let Some(TypedHeader(Authorization(bearer))) = bearer {
         app.bearer_is_authorized(bearer).await?;
-    } else if let Some(InsecureClientIp(ip)) = ip {
+    } else if let Some(ClientIp(ip)) = ip {
         login_is_authorized(&app, ip).await?;
     } else {
         return Err(Web3ProxyError::AccessDenied("no bearer token or ip".into()));
@@ -454,7 +454,7 @@ pub async fn user_balance_post(
 #[debug_handler]
 pub async fn user_balance_uncle_post(
     State(app): State<Arc<App>>,
-    ip: Option<InsecureClientIp>,
+    ip: Option<ClientIp>,
     Path(mut params): Path<HashMap<String, String>>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> Web3ProxyResponse {
@@ -462,7 +462,7 @@ pub async fn user_balance_uncle_post(
     // rate limit by bearer token **OR** IP address
     if let Some(TypedHeader(Authorization(bearer))) = bearer {
         app.bearer_is_authorized(bearer).await?;
-    } else if let Some(InsecureClientIp(ip)) = ip {
+    } else if let Some(ClientIp(ip)) = ip {
         login_is_authorized(&app, ip).await?;
     } else {
         return Err(Web3ProxyError::AccessDenied("no bearer token or ip".into()));
@@ -20,7 +20,7 @@ use tracing::{debug, error, warn};
 #[debug_handler]
 pub async fn user_balance_stripe_post(
     State(app): State<Arc<App>>,
-    // InsecureClientIp(ip): InsecureClientIp,
+    // ClientIp(ip): ClientIp,
     headers: HeaderMap,
     payload: String,
 ) -> Web3ProxyResponse {
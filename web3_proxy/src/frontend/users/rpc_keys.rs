@@ -1,6 +1,8 @@
 //! Handle registration, logins, and managing account data.
 use crate::app::App;
-use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
+use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult};
+use crate::frontend::authorization::MethodRules;
+use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::globals::{global_db_conn, global_db_replica_conn};
 use crate::secrets::RpcSecretKey;
 use axum::headers::{Header, Origin, Referer, UserAgent};
@@ -15,7 +17,7 @@ use entities;
 use entities::sea_orm_active_enums::Role;
 use entities::{rpc_key, secondary_user};
 use hashbrown::HashMap;
-use http::HeaderValue;
+use http::{HeaderValue, StatusCode};
 use ipnet::IpNet;
 use itertools::Itertools;
 use migration::sea_orm::{
@@ -53,6 +55,17 @@ pub async fn rpc_keys_get(
         allowed_referers: Option<String>,
         allowed_user_agents: Option<String>,
         log_revert_chance: f64,
+        sign_responses: bool,
+        require_jsonrpc_2: bool,
+        allow_batches: bool,
+        allow_websocket: bool,
+        skip_chain_id_check: bool,
+        skip_request_coalescing: bool,
+        label: Option<String>,
+        allowed_methods: Option<Vec<String>>,
+        denied_methods: Option<Vec<String>>,
+        max_requests_per_day: Option<u64>,
+        max_requests_per_month: Option<u64>,
         // Addition
         // role is optional only to handle an inconsistent database. it should always be set
         role: Option<&'a Role>,
@@ -64,21 +77,34 @@ pub async fn rpc_keys_get(
         .await
         .web3_context("failed loading user's key")?
         .into_iter()
-        .map(|x| ReturnType {
-            id: x.id,
-            user_id: x.user_id,
-            secret_key: x.secret_key.into(),
-            description: x.description,
-            private_txs: x.private_txs,
-            active: x.active,
-            allowed_ips: x.allowed_ips,
-            allowed_origins: x.allowed_origins,
-            allowed_referers: x.allowed_referers,
-            allowed_user_agents: x.allowed_user_agents,
-            log_revert_chance: x.log_revert_chance,
-            role: Some(&Role::Owner),
+        .map(|x| {
+            Ok(ReturnType {
+                id: x.id,
+                user_id: x.user_id,
+                secret_key: x.secret_key.into(),
+                description: x.description,
+                private_txs: x.private_txs,
+                active: x.active,
+                allowed_ips: x.allowed_ips,
+                allowed_origins: x.allowed_origins,
+                allowed_referers: x.allowed_referers,
+                allowed_user_agents: x.allowed_user_agents,
+                log_revert_chance: x.log_revert_chance,
+                sign_responses: x.sign_responses,
+                require_jsonrpc_2: x.require_jsonrpc_2,
+                allow_batches: x.allow_batches,
+                allow_websocket: x.allow_websocket,
+                skip_chain_id_check: x.skip_chain_id_check,
+                skip_request_coalescing: x.skip_request_coalescing,
+                label: x.label,
+                allowed_methods: MethodRules::parse_list(x.allowed_methods.as_deref())?,
+                denied_methods: MethodRules::parse_list(x.denied_methods.as_deref())?,
+                max_requests_per_day: x.max_requests_per_day,
+                max_requests_per_month: x.max_requests_per_month,
+                role: Some(&Role::Owner),
+            })
         })
-        .collect::<Vec<_>>();
+        .collect::<Web3ProxyResult<Vec<_>>>()?;
 
     let secondary_user_entities = secondary_user::Entity::find()
         .filter(secondary_user::Column::UserId.eq(user.id))
@@ -96,21 +122,34 @@ pub async fn rpc_keys_get(
         .all(db_replica.as_ref())
         .await?
         .into_iter()
-        .map(|x| ReturnType {
-            id: x.id,
-            user_id: x.user_id,
-            secret_key: x.secret_key.into(),
-            description: x.description,
-            private_txs: x.private_txs,
-            active: x.active,
-            allowed_ips: x.allowed_ips,
-            allowed_origins: x.allowed_origins,
-            allowed_referers: x.allowed_referers,
-            allowed_user_agents: x.allowed_user_agents,
-            log_revert_chance: x.log_revert_chance,
-            role: secondary_user_entities.get(&x.id).map(|x| &x.role),
+        .map(|x| {
+            Ok(ReturnType {
+                id: x.id,
+                user_id: x.user_id,
+                secret_key: x.secret_key.into(),
+                description: x.description,
+                private_txs: x.private_txs,
+                active: x.active,
+                allowed_ips: x.allowed_ips,
+                allowed_origins: x.allowed_origins,
+                allowed_referers: x.allowed_referers,
+                allowed_user_agents: x.allowed_user_agents,
+                log_revert_chance: x.log_revert_chance,
+                sign_responses: x.sign_responses,
+                require_jsonrpc_2: x.require_jsonrpc_2,
+                allow_batches: x.allow_batches,
+                allow_websocket: x.allow_websocket,
+                skip_chain_id_check: x.skip_chain_id_check,
+                skip_request_coalescing: x.skip_request_coalescing,
+                label: x.label,
+                allowed_methods: MethodRules::parse_list(x.allowed_methods.as_deref())?,
+                denied_methods: MethodRules::parse_list(x.denied_methods.as_deref())?,
+                max_requests_per_day: x.max_requests_per_day,
+                max_requests_per_month: x.max_requests_per_month,
+                role: secondary_user_entities.get(&x.id).map(|x| &x.role),
+            })
         })
-        .collect::<Vec<_>>();
+        .collect::<Web3ProxyResult<Vec<_>>>()?;
 
     let response_json = json!({
         "user_id": user.id,
@@ -124,6 +163,102 @@ pub async fn rpc_keys_get(
     Ok(Json(response_json).into_response())
 }
 
+/// The http and websocket urls for a key. `base_url` is like "https://rpc.example.com"
+pub fn rpc_key_urls(
+    base_url: &str,
+    rpc_secret_key: RpcSecretKey,
+) -> Web3ProxyResult<(String, String)> {
+    let base_url = base_url.trim_end_matches('/');
+
+    let ws_base_url = if let Some(x) = base_url.strip_prefix("https://") {
+        format!("wss://{}", x)
+    } else if let Some(x) = base_url.strip_prefix("http://") {
+        format!("ws://{}", x)
+    } else {
+        return Err(Web3ProxyError::Anyhow(anyhow::anyhow!(
+            "public_base_url must start with http:// or https://"
+        )));
+    };
+
+    let http_url = format!("{}/rpc/{}", base_url, rpc_secret_key);
+    let ws_url = format!("{}/rpc/{}", ws_base_url, rpc_secret_key);
+
+    Ok((http_url, ws_url))
+}
+
+/// `GET /user/connect` -- Use a bearer token to get ready-to-use rpc urls and the current limits for each of the user's keys.
+/// Keys with daily or monthly quotas include how much of them has been used.
+#[debug_handler]
+pub async fn rpc_keys_connect_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let user = app
+        .bearer_is_authorized(bearer)
+        .await?
+        .ok_or(Web3ProxyError::InvalidUserKey)?;
+
+    let base_url = app.config.public_base_url.as_ref().ok_or_else(|| {
+        Web3ProxyError::StatusCode(
+            StatusCode::NOT_IMPLEMENTED,
+            "public_base_url is not configured".into(),
+            None,
+        )
+    })?;
+
+    let db_replica = global_db_replica_conn()?;
+
+    let user_rpc_keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .all(db_replica.as_ref())
+        .await
+        .web3_context("failed loading user's key")?;
+
+    let mut rpc_keys = Vec::with_capacity(user_rpc_keys.len());
+
+    for x in user_rpc_keys.into_iter() {
+        let rpc_secret_key: RpcSecretKey = x.secret_key.into();
+
+        let (http_url, ws_url) = rpc_key_urls(base_url, rpc_secret_key)?;
+
+        // the same checks that requests get, so downgraded tiers show their downgraded limits
+        let limits = if x.active {
+            let checks = app
+                .authorization_checks(ProxyMode::Best, &rpc_secret_key)
+                .await?;
+
+            // used and remaining for the current day and month. empty if the key has no quotas
+            let quotas = app.quotas.usage(&checks).await?;
+
+            Some(json!({
+                "max_concurrent_requests": checks.max_concurrent_requests,
+                "max_requests_per_period": checks.max_requests_per_period,
+                "quotas": quotas,
+                "user_tier": checks.user_tier_title,
+            }))
+        } else {
+            None
+        };
+
+        rpc_keys.push(json!({
+            "id": x.id,
+            "active": x.active,
+            "description": x.description,
+            "label": x.label,
+            "http_url": http_url,
+            "ws_url": ws_url,
+            "limits": limits,
+        }));
+    }
+
+    let response_json = json!({
+        "user_id": user.id,
+        "rpc_keys": rpc_keys,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
 /// `DELETE /user/keys` -- Use a bearer token to delete an existing key.
 #[debug_handler]
 pub async fn rpc_keys_delete(
@@ -152,6 +287,42 @@ pub struct UserKeyManagement {
     description: Option<String>,
     // TODO: enable log_revert_trace: Option<f64>,
     private_txs: Option<bool>,
+    /// add a signature header to every response for this key
+    sign_responses: Option<bool>,
+    /// reject requests that aren't `"jsonrpc": "2.0"`
+    require_jsonrpc_2: Option<bool>,
+    allow_batches: Option<bool>,
+    allow_websocket: Option<bool>,
+    /// forward requests whose params are for another chain
+    skip_chain_id_check: Option<bool>,
+    /// don't wait on identical requests that are already in flight. only honored if the user's tier allows it
+    skip_request_coalescing: Option<bool>,
+    /// shown in the key's stats and in our request logs. an empty string clears it
+    label: Option<String>,
+    /// only these methods are allowed. `"debug_*"` matches every method starting with `debug_`. an empty list clears it
+    allowed_methods: Option<Vec<String>>,
+    /// these methods are never allowed, even if they are in `allowed_methods`. an empty list clears it
+    denied_methods: Option<Vec<String>>,
+}
+
+/// check every pattern and store them as a json array. an empty list is stored as null
+fn method_list(x: Vec<String>) -> Web3ProxyResult<Option<String>> {
+    if x.is_empty() {
+        return Ok(None);
+    }
+
+    let x = x
+        .into_iter()
+        .map(|x| {
+            let x = x.trim().to_string();
+
+            MethodRules::validate_pattern(&x)?;
+
+            Ok(x)
+        })
+        .collect::<Web3ProxyResult<Vec<_>>>()?;
+
+    Ok(Some(serde_json::to_string(&x)?))
 }
 
 /// `POST /user/keys` or `PUT /user/keys` -- Use a bearer token to create or update an existing key.
@@ -238,10 +409,56 @@ pub async fn rpc_keys_management(
         }
     }
 
+    if let Some(label) = payload.label {
+        let label = label.trim();
+
+        if label.is_empty() {
+            uk.label = sea_orm::Set(None);
+        } else if label.len() > 64 {
+            return Err(Web3ProxyError::BadRequest(
+                "label must be 64 bytes or less".into(),
+            ));
+        } else {
+            uk.label = sea_orm::Set(Some(label.to_string()));
+        }
+    }
+
+    if let Some(allowed_methods) = payload.allowed_methods {
+        uk.allowed_methods = sea_orm::Set(method_list(allowed_methods)?);
+    }
+
+    if let Some(denied_methods) = payload.denied_methods {
+        uk.denied_methods = sea_orm::Set(method_list(denied_methods)?);
+    }
+
     if let Some(private_txs) = payload.private_txs {
         uk.private_txs = sea_orm::Set(private_txs);
     }
 
+    if let Some(sign_responses) = payload.sign_responses {
+        uk.sign_responses = sea_orm::Set(sign_responses);
+    }
+
+    if let Some(require_jsonrpc_2) = payload.require_jsonrpc_2 {
+        uk.require_jsonrpc_2 = sea_orm::Set(require_jsonrpc_2);
+    }
+
+    if let Some(allow_batches) = payload.allow_batches {
+        uk.allow_batches = sea_orm::Set(allow_batches);
+    }
+
+    if let Some(allow_websocket) = payload.allow_websocket {
+        uk.allow_websocket = sea_orm::Set(allow_websocket);
+    }
+
+    if let Some(skip_chain_id_check) = payload.skip_chain_id_check {
+        uk.skip_chain_id_check = sea_orm::Set(skip_chain_id_check);
+    }
+
+    if let Some(skip_request_coalescing) = payload.skip_request_coalescing {
+        uk.skip_request_coalescing = sea_orm::Set(skip_request_coalescing);
+    }
+
     if let Some(active) = payload.active {
         uk.active = sea_orm::Set(active);
     }
@@ -359,5 +576,44 @@ pub async fn rpc_keys_management(
 
     let uk = uk.try_into_model()?;
 
+    // the cached authorization checks for this key are stale now
+    let secret_key: RpcSecretKey = uk.secret_key.into();
+
+    app.invalidate_rpc_key(&secret_key, uk.user_id).await;
+
     Ok(Json(uk).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ulid::Ulid;
+
+    #[test]
+    fn urls() {
+        let key: RpcSecretKey = Ulid::from_string("01HGPYX3WBMZTZ3KQGJWZ0Q6V8")
+            .unwrap()
+            .into();
+
+        let (http_url, ws_url) = rpc_key_urls("https://rpc.example.com/", key).unwrap();
+
+        assert_eq!(
+            http_url,
+            "https://rpc.example.com/rpc/01HGPYX3WBMZTZ3KQGJWZ0Q6V8"
+        );
+        assert_eq!(
+            ws_url,
+            "wss://rpc.example.com/rpc/01HGPYX3WBMZTZ3KQGJWZ0Q6V8"
+        );
+
+        let (http_url, ws_url) = rpc_key_urls("http://127.0.0.1:8544", key).unwrap();
+
+        assert_eq!(
+            http_url,
+            "http://127.0.0.1:8544/rpc/01HGPYX3WBMZTZ3KQGJWZ0Q6V8"
+        );
+        assert_eq!(ws_url, "ws://127.0.0.1:8544/rpc/01HGPYX3WBMZTZ3KQGJWZ0Q6V8");
+
+        assert!(rpc_key_urls("rpc.example.com", key).is_err());
+    }
+}
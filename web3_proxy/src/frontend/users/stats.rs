@@ -125,6 +125,61 @@ pub async fn user_revert_logs_get(
     Ok(Json(response).into_response())
 }
 
+/// `GET /user/errors` -- Use a bearer token to get the most recent errors sent to one of the user's rpc keys.
+/// Secondary users of the key can see them too. These are only kept in memory and do not include params.
+#[debug_handler]
+pub async fn user_errors_get(
+    State(app): State<Arc<App>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Web3ProxyResponse {
+    let user = app
+        .bearer_is_authorized(bearer)
+        .await?
+        .ok_or(Web3ProxyError::InvalidUserKey)?;
+
+    let rpc_key_id: u64 = params
+        .get("rpc_key_id")
+        .ok_or_else(|| Web3ProxyError::BadRequest("rpc_key_id is required".into()))?
+        .parse()
+        .map_err(|_| Web3ProxyError::BadRequest("rpc_key_id must be a number".into()))?;
+
+    let db_replica = global_db_replica_conn()?;
+
+    let is_owner = rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .one(db_replica.as_ref())
+        .await?
+        .is_some();
+
+    let is_secondary_user = !is_owner
+        && secondary_user::Entity::find()
+            .filter(secondary_user::Column::UserId.eq(user.id))
+            .filter(secondary_user::Column::RpcSecretKeyId.eq(rpc_key_id))
+            .one(db_replica.as_ref())
+            .await?
+            .is_some();
+
+    if !is_owner && !is_secondary_user {
+        return Err(Web3ProxyError::AccessDenied(
+            "rpc_key_id is not one of your keys".into(),
+        ));
+    }
+
+    let (errors, max_errors) = match app.recent_errors.as_ref() {
+        Some(x) => (x.get(rpc_key_id).await, x.per_key()),
+        None => (vec![], 0),
+    };
+
+    let response = json!({
+        "errors": errors,
+        "max_errors": max_errors,
+        "rpc_key_id": rpc_key_id,
+    });
+
+    Ok(Json(response).into_response())
+}
+
 /// `GET /user/stats/aggregate` -- Public endpoint for aggregate stats such as bandwidth used and methods requested.
 #[debug_handler]
 pub async fn user_influx_stats_aggregated_get(
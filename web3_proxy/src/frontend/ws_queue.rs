@@ -0,0 +1,460 @@
+//! A bounded outbound queue for one websocket connection.
+//!
+//! A bounded channel would block the subscription tasks when a client reads slowly, and an unbounded one would buffer
+//! megabytes of pending transactions for them. Instead, every message is queued with its size and overflow is handled
+//! by what kind of message it is:
+//! - pending transaction notifications drop the oldest pending transaction notifications. The client gets an
+//!   occasional `proxy_subscriptionGap` notification saying how many they missed.
+//! - newHeads and responses are never dropped. If they don't fit, the client is too slow and is disconnected. A
+//!   response bigger than the byte limit still goes into an empty queue.
+
+use crate::memory::MemoryCounters;
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use ethers::types::U64;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// don't tell a client about dropped messages more often than this (per subscription)
+pub const GAP_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the queue was closed. either the client disconnected or it was too slow
+#[derive(Debug)]
+pub struct QueueClosed;
+
+struct Queued {
+    msg: Message,
+    num_bytes: usize,
+    /// Some if this message may be dropped to make room
+    droppable: Option<U64>,
+}
+
+#[derive(Default)]
+struct SubscriptionGap {
+    dropped: u64,
+    total_dropped: u64,
+    last_notified: Option<Instant>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    queue: VecDeque<Queued>,
+    num_bytes: usize,
+    closed: bool,
+    gaps: HashMap<U64, SubscriptionGap>,
+}
+
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    max_messages: usize,
+    max_bytes: usize,
+    counters: Arc<MemoryCounters>,
+}
+
+fn message_bytes(msg: &Message) -> usize {
+    match msg {
+        Message::Text(x) => x.len(),
+        Message::Binary(x) | Message::Ping(x) | Message::Pong(x) => x.len(),
+        Message::Close(x) => x.as_ref().map(|x| 2 + x.reason.len()).unwrap_or(0),
+    }
+}
+
+impl QueueState {
+    /// An empty queue always has room. Otherwise a response bigger than the byte limit (like a large eth_getLogs)
+    /// could never be sent, even to a client that keeps up
+    fn is_full(&self, max_messages: usize, max_bytes: usize, extra_bytes: usize) -> bool {
+        !self.queue.is_empty()
+            && (self.queue.len() >= max_messages || self.num_bytes + extra_bytes > max_bytes)
+    }
+}
+
+impl OutboundQueue {
+    pub fn new(max_messages: usize, max_bytes: usize, counters: Arc<MemoryCounters>) -> Arc<Self> {
+        let x = Self {
+            state: Default::default(),
+            notify: Notify::new(),
+            max_messages: max_messages.max(1),
+            max_bytes,
+            counters,
+        };
+
+        Arc::new(x)
+    }
+
+    fn push(&self, state: &mut QueueState, msg: Message, droppable: Option<U64>) {
+        let num_bytes = message_bytes(&msg);
+
+        state.num_bytes += num_bytes;
+        state.queue.push_back(Queued {
+            msg,
+            num_bytes,
+            droppable,
+        });
+
+        self.counters
+            .websocket_queued_messages
+            .fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .websocket_queued_bytes
+            .fetch_add(num_bytes, Ordering::Relaxed);
+
+        self.notify.notify_one();
+    }
+
+    fn remove(&self, state: &mut QueueState, i: usize) -> Option<Queued> {
+        let x = state.queue.remove(i)?;
+
+        state.num_bytes -= x.num_bytes;
+
+        self.counters
+            .websocket_queued_messages
+            .fetch_sub(1, Ordering::Relaxed);
+        self.counters
+            .websocket_queued_bytes
+            .fetch_sub(x.num_bytes, Ordering::Relaxed);
+
+        Some(x)
+    }
+
+    /// drop the oldest droppable message. returns false if there was nothing to drop
+    fn drop_oldest(&self, state: &mut QueueState) -> bool {
+        let Some(i) = state.queue.iter().position(|x| x.droppable.is_some()) else {
+            return false;
+        };
+
+        let x = self.remove(state, i).expect("position is in the queue");
+
+        let subscription_id = x.droppable.expect("only droppable messages are dropped");
+
+        self.record_drop(state, subscription_id);
+
+        true
+    }
+
+    fn record_drop(&self, state: &mut QueueState, subscription_id: U64) {
+        let gap = state.gaps.entry(subscription_id).or_default();
+        gap.dropped += 1;
+        gap.total_dropped += 1;
+
+        self.counters
+            .websocket_dropped_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// clear everything queued and queue only the close message
+    fn close_locked(&self, state: &mut QueueState, msg: Option<Message>) {
+        if state.closed {
+            return;
+        }
+
+        while !state.queue.is_empty() {
+            self.remove(state, 0);
+        }
+
+        if let Some(msg) = msg {
+            self.push(state, msg, None);
+        }
+
+        state.closed = true;
+
+        self.notify.notify_one();
+    }
+
+    /// Queue a message that must not be dropped (responses and newHeads).
+    /// If it doesn't fit even after dropping every droppable message, the client is disconnected.
+    pub fn send(&self, msg: Message) -> Result<(), QueueClosed> {
+        let mut state = self.state.lock();
+
+        if state.closed {
+            return Err(QueueClosed);
+        }
+
+        let num_bytes = message_bytes(&msg);
+
+        while state.is_full(self.max_messages, self.max_bytes, num_bytes) {
+            if !self.drop_oldest(&mut state) {
+                self.counters
+                    .websocket_slow_disconnects
+                    .fetch_add(1, Ordering::Relaxed);
+
+                let close_frame = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "websocket is reading too slowly. messages would be lost".into(),
+                };
+
+                self.close_locked(&mut state, Some(Message::Close(Some(close_frame))));
+
+                return Err(QueueClosed);
+            }
+        }
+
+        self.push(&mut state, msg, None);
+
+        Ok(())
+    }
+
+    /// Queue a notification for a subscription that can tolerate gaps (pending transactions).
+    /// If the queue is full, the oldest droppable message is dropped to make room.
+    pub fn send_droppable(&self, subscription_id: U64, msg: Message) -> Result<(), QueueClosed> {
+        let mut state = self.state.lock();
+
+        if state.closed {
+            return Err(QueueClosed);
+        }
+
+        let num_bytes = message_bytes(&msg);
+
+        if num_bytes > self.max_bytes {
+            // this could never fit. drop it instead of everything else
+            self.record_drop(&mut state, subscription_id);
+
+            return Ok(());
+        }
+
+        while state.is_full(self.max_messages, self.max_bytes, num_bytes) {
+            if !self.drop_oldest(&mut state) {
+                // the queue is full of messages that can't be dropped. drop this one
+                self.record_drop(&mut state, subscription_id);
+
+                return Ok(());
+            }
+        }
+
+        // tell the client that they missed some. the notification is tiny and rate limited, so it skips the limits
+        let now = Instant::now();
+
+        if let Some(gap) = state.gaps.get_mut(&subscription_id) {
+            if gap.dropped > 0
+                && gap
+                    .last_notified
+                    .map_or(true, |x| now.duration_since(x) >= GAP_NOTIFICATION_INTERVAL)
+            {
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "proxy_subscriptionGap",
+                    "params": {
+                        "subscription": subscription_id,
+                        "dropped": gap.dropped,
+                        "total_dropped": gap.total_dropped,
+                        "max_queued_bytes": self.max_bytes,
+                        "max_queued_messages": self.max_messages,
+                    },
+                });
+
+                gap.dropped = 0;
+                gap.last_notified = Some(now);
+
+                self.push(&mut state, Message::Text(notification.to_string()), None);
+            }
+        }
+
+        self.push(&mut state, msg, Some(subscription_id));
+
+        Ok(())
+    }
+
+    /// Stop accepting messages. If `msg` is given, it is the last thing the client receives.
+    pub fn close(&self, msg: Option<Message>) {
+        let mut state = self.state.lock();
+
+        self.close_locked(&mut state, msg);
+    }
+
+    /// forget about a subscription's dropped messages. call this when it is unsubscribed
+    pub fn unsubscribed(&self, subscription_id: U64) {
+        self.state.lock().gaps.remove(&subscription_id);
+    }
+
+    /// Wait for the next message to write. None once the queue is closed and empty
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock();
+
+                if let Some(x) = self.remove(&mut state, 0) {
+                    return Some(x.msg);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    /// messages and bytes waiting to be written
+    pub fn depth(&self) -> (usize, usize) {
+        let state = self.state.lock();
+
+        (state.queue.len(), state.num_bytes)
+    }
+}
+
+impl Drop for OutboundQueue {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+
+        self.counters
+            .websocket_queued_messages
+            .fetch_sub(state.queue.len(), Ordering::Relaxed);
+        self.counters
+            .websocket_queued_bytes
+            .fetch_sub(state.num_bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(x: &str) -> Message {
+        Message::Text(x.to_string())
+    }
+
+    fn recv_now(queue: &OutboundQueue) -> Option<Message> {
+        let mut state = queue.state.lock();
+
+        queue.remove(&mut state, 0).map(|x| x.msg)
+    }
+
+    #[test]
+    fn pending_txs_drop_oldest() {
+        let counters = Arc::new(MemoryCounters::default());
+
+        let queue = OutboundQueue::new(3, 1024, counters.clone());
+
+        let sub = U64::from(1);
+
+        for i in 0..5 {
+            queue.send_droppable(sub, text(&i.to_string())).unwrap();
+        }
+
+        // "0" made room for "3" (which came with a gap notification). "1" and "2" made room for "4"
+        assert_eq!(
+            counters.websocket_dropped_messages.load(Ordering::Relaxed),
+            3
+        );
+
+        let mut received = vec![];
+        while let Some(Message::Text(x)) = recv_now(&queue) {
+            received.push(x);
+        }
+
+        assert!(received.iter().any(|x| x.contains("proxy_subscriptionGap")));
+        assert_eq!(received.last().unwrap(), "4");
+
+        assert_eq!(
+            counters.websocket_queued_messages.load(Ordering::Relaxed),
+            0
+        );
+        assert_eq!(counters.websocket_queued_bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn heads_disconnect_slow_clients() {
+        let counters = Arc::new(MemoryCounters::default());
+
+        let queue = OutboundQueue::new(2, 1024, counters.clone());
+
+        let sub = U64::from(1);
+
+        // droppable messages make room for heads
+        queue.send_droppable(sub, text("tx")).unwrap();
+        queue.send(text("head 1")).unwrap();
+        queue.send(text("head 2")).unwrap();
+
+        assert_eq!(queue.depth().0, 2);
+
+        // nothing left to drop
+        assert!(queue.send(text("head 3")).is_err());
+        assert!(queue.send_droppable(sub, text("tx")).is_err());
+
+        assert_eq!(
+            counters.websocket_slow_disconnects.load(Ordering::Relaxed),
+            1
+        );
+
+        // only the close frame is left
+        assert!(matches!(recv_now(&queue), Some(Message::Close(Some(_)))));
+        assert!(recv_now(&queue).is_none());
+    }
+
+    #[test]
+    fn big_responses_fit_in_an_empty_queue() {
+        let counters = Arc::new(MemoryCounters::default());
+
+        let queue = OutboundQueue::new(100, 10, counters.clone());
+
+        // bigger than the byte limit, but nothing else is waiting
+        queue.send(text("a big eth_getLogs response")).unwrap();
+
+        assert!(
+            matches!(recv_now(&queue), Some(Message::Text(x)) if x == "a big eth_getLogs response")
+        );
+
+        // pending transactions are dropped to empty the queue for it
+        queue.send_droppable(1.into(), text("tx")).unwrap();
+        queue.send(text("another big response")).unwrap();
+
+        assert_eq!(queue.depth().0, 1);
+        assert_eq!(
+            counters.websocket_dropped_messages.load(Ordering::Relaxed),
+            1
+        );
+
+        // it doesn't fit behind a response that can't be dropped
+        assert!(queue.send(text("a third big response")).is_err());
+        assert_eq!(
+            counters.websocket_slow_disconnects.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn byte_limit() {
+        let counters = Arc::new(MemoryCounters::default());
+
+        let queue = OutboundQueue::new(100, 10, counters.clone());
+
+        queue.send_droppable(1.into(), text("12345")).unwrap();
+        queue.send_droppable(1.into(), text("12345")).unwrap();
+        queue.send_droppable(1.into(), text("12345")).unwrap();
+
+        // the first message was dropped and a gap notification was added
+        let (num_messages, num_bytes) = queue.depth();
+
+        assert_eq!(num_messages, 3);
+        assert_eq!(
+            counters.websocket_dropped_messages.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            counters.websocket_queued_bytes.load(Ordering::Relaxed),
+            num_bytes
+        );
+
+        // too big to ever fit
+        queue
+            .send_droppable(1.into(), text("this is too big"))
+            .unwrap();
+
+        assert_eq!(queue.depth(), (num_messages, num_bytes));
+        assert_eq!(
+            counters.websocket_dropped_messages.load(Ordering::Relaxed),
+            2
+        );
+
+        drop(queue);
+
+        assert_eq!(counters.websocket_queued_bytes.load(Ordering::Relaxed), 0);
+    }
+}
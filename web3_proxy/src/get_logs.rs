@@ -0,0 +1,710 @@
+//! Sanity checks for `eth_getLogs` before it is sent to a backend.
+//!
+//! Unbounded log queries can take minutes and usually fail anyway. These limits run after the block numbers in the
+//! filter have been normalized, so "latest", hex, and decimal all look the same here.
+//!
+//! Clients that would rather not split up their own queries can opt in to auto pagination. Ranges that are too large
+//! are then split into pages that are each small enough to pass these limits, and the pages are merged into one
+//! response.
+//!
+//! `LogFilter` and `RecentLogs` are for `eth_subscribe("logs")`, which filters each new block's logs itself.
+
+use crate::block_number::BlockNumOrHash;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use ethers::types::{Address, Log, H256, U64};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, VecDeque};
+
+/// clients can send this header (with "true") instead of setting `web3ProxyOptions.autoPaginate` on every filter
+pub const AUTO_PAGINATE_HEADER: &str = "x-web3-proxy-auto-paginate";
+
+/// the key in an eth_getLogs filter that holds our non-standard options
+pub const WEB3_PROXY_OPTIONS_KEY: &str = "web3ProxyOptions";
+
+/// Limits for `eth_getLogs`. These can be changed without restarting. 0 disables a limit.
+/// Ranges that are too large are split into pages for clients that opt in to auto pagination and rejected otherwise.
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GetLogsLimits {
+    /// The largest block range that anonymous and free users can query at once.
+    #[serde_inline_default(200_000u64)]
+    pub max_range_public: u64,
+
+    /// The largest block range that users with premium can query at once.
+    #[serde_inline_default(200_000u64)]
+    pub max_range_premium: u64,
+
+    /// Queries with neither an address nor a topic must have a range at most this large.
+    #[serde_inline_default(10_000u64)]
+    pub max_unfiltered_range: u64,
+
+    /// How far past our head block `toBlock` may be. A little slack is allowed because a client's node may be slightly
+    /// ahead of ours.
+    #[serde_inline_default(3u64)]
+    pub max_blocks_past_head: u64,
+
+    /// Auto paginated queries fail once they have collected more logs than this.
+    #[serde_inline_default(100_000u64)]
+    pub max_paginated_results: u64,
+
+    /// Auto paginated queries fail once their logs are larger than this many bytes.
+    #[serde_inline_default(64 * 1024 * 1024u64)]
+    pub max_paginated_bytes: u64,
+
+    /// How many pages of one auto paginated query are requested at the same time.
+    #[serde_inline_default(4usize)]
+    pub pagination_concurrency: usize,
+}
+
+impl Default for GetLogsLimits {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+/// true if the filter has an address or at least one topic
+fn has_address_or_topic(filter: &serde_json::Value) -> bool {
+    fn not_empty(x: &serde_json::Value) -> bool {
+        match x {
+            serde_json::Value::Null => false,
+            serde_json::Value::Array(x) => x.iter().any(not_empty),
+            _ => true,
+        }
+    }
+
+    filter.get("address").map_or(false, not_empty) || filter.get("topics").map_or(false, not_empty)
+}
+
+/// Non-standard options that clients can put in an eth_getLogs filter under `web3ProxyOptions`.
+/// They are removed before the filter is sent to a backend.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Web3ProxyOptions {
+    /// split ranges that are too large into multiple queries instead of returning an error
+    #[serde(default)]
+    pub auto_paginate: bool,
+}
+
+impl Web3ProxyOptions {
+    /// remove the options from the filter in the first param
+    pub fn take(params: &mut serde_json::Value) -> Web3ProxyResult<Self> {
+        let Some(x) = params
+            .get_mut(0)
+            .and_then(|x| x.as_object_mut())
+            .and_then(|x| x.remove(WEB3_PROXY_OPTIONS_KEY))
+        else {
+            return Ok(Default::default());
+        };
+
+        serde_json::from_value(x).map_err(|err| {
+            Web3ProxyError::BadRequest(
+                format!("invalid {}: {}", WEB3_PROXY_OPTIONS_KEY, err).into(),
+            )
+        })
+    }
+
+    /// turn on auto pagination unless the filter already says otherwise. used for the header
+    pub fn default_auto_paginate(params: &mut serde_json::Value) {
+        let Some(filter) = params.get_mut(0).and_then(|x| x.as_object_mut()) else {
+            return;
+        };
+
+        if let Some(options) = filter
+            .entry(WEB3_PROXY_OPTIONS_KEY)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()))
+            .as_object_mut()
+        {
+            options
+                .entry("autoPaginate")
+                .or_insert(serde_json::Value::Bool(true));
+        }
+    }
+}
+
+/// (blockNumber, logIndex) of a log. missing or invalid fields sort first
+fn log_position(log: &serde_json::Value) -> (U64, U64) {
+    let field = |key| {
+        log.get(key)
+            .and_then(|x| serde_json::from_value::<U64>(x.clone()).ok())
+            .unwrap_or_default()
+    };
+
+    (field("blockNumber"), field("logIndex"))
+}
+
+/// inclusive block ranges of at most `page_size` blocks that cover `from..=to`. 0 means a single page
+pub fn page_ranges(from: U64, to: U64, page_size: u64) -> Vec<(U64, U64)> {
+    if page_size == 0 {
+        return vec![(from, to)];
+    }
+
+    let mut x = vec![];
+
+    let mut page_from = from;
+
+    while page_from <= to {
+        let page_to = (page_from + page_size - 1).min(to);
+
+        x.push((page_from, page_to));
+
+        page_from = page_to + 1;
+    }
+
+    x
+}
+
+/// Logs from the pages of an auto paginated query. Pages may arrive in any order
+#[derive(Debug, Default)]
+pub struct PaginatedLogs {
+    pages: BTreeMap<U64, Vec<serde_json::Value>>,
+    num_results: u64,
+    num_bytes: u64,
+}
+
+impl PaginatedLogs {
+    /// add the result of the query for `from..=to`. errors if the total is now over the limits
+    pub fn add_page(
+        &mut self,
+        limits: &GetLogsLimits,
+        from: U64,
+        to: U64,
+        result: &RawValue,
+    ) -> Web3ProxyResult<()> {
+        let logs: Vec<serde_json::Value> = serde_json::from_str(result.get())?;
+
+        self.num_results += logs.len() as u64;
+        self.num_bytes += result.get().len() as u64;
+
+        if (limits.max_paginated_results > 0 && self.num_results > limits.max_paginated_results)
+            || (limits.max_paginated_bytes > 0 && self.num_bytes > limits.max_paginated_bytes)
+        {
+            return Err(Web3ProxyError::PaginatedLogsTooLarge {
+                from,
+                to,
+                results: self.num_results,
+                bytes: self.num_bytes,
+                max_results: limits.max_paginated_results,
+                max_bytes: limits.max_paginated_bytes,
+            });
+        }
+
+        self.pages.insert(from, logs);
+
+        Ok(())
+    }
+
+    /// every log, ordered by (blockNumber, logIndex)
+    pub fn into_sorted(self) -> Vec<serde_json::Value> {
+        let mut x: Vec<_> = self.pages.into_values().flatten().collect();
+
+        // the pages are already in order and backends usually sort their own results, so this is cheap
+        x.sort_by_key(log_position);
+
+        x
+    }
+}
+
+impl GetLogsLimits {
+    pub fn max_range(&self, premium: bool) -> u64 {
+        if premium {
+            self.max_range_premium
+        } else {
+            self.max_range_public
+        }
+    }
+
+    /// The most blocks that one page of an auto paginated query can cover. 0 if there is no limit
+    pub fn page_size(&self, filter: &serde_json::Value, premium: bool) -> u64 {
+        let mut x = self.max_range(premium);
+
+        if self.max_unfiltered_range > 0 && !has_address_or_topic(filter) {
+            x = if x == 0 {
+                self.max_unfiltered_range
+            } else {
+                x.min(self.max_unfiltered_range)
+            };
+        }
+
+        // validate allows `to - from` to equal the limit, so a page can hold one more block than the limit
+        if x == 0 {
+            0
+        } else {
+            x + 1
+        }
+    }
+
+    /// `filter` is the first param of the request, after its block numbers were normalized
+    pub fn validate(
+        &self,
+        filter: &serde_json::Value,
+        from_block: &BlockNumOrHash,
+        to_block: &BlockNumOrHash,
+        head_block_num: U64,
+        premium: bool,
+    ) -> Web3ProxyResult<()> {
+        let range = to_block.num().saturating_sub(from_block.num());
+
+        if self.max_blocks_past_head > 0
+            && to_block.num() > head_block_num + self.max_blocks_past_head
+        {
+            return Err(Web3ProxyError::RangeInFuture {
+                to: to_block.clone(),
+                head: head_block_num,
+            });
+        }
+
+        let max_range = self.max_range(premium);
+
+        if max_range > 0 && range.as_u64() > max_range {
+            return Err(Web3ProxyError::RangeTooLarge {
+                from: from_block.clone(),
+                to: to_block.clone(),
+                requested: range,
+                allowed: max_range.into(),
+            });
+        }
+
+        if self.max_unfiltered_range > 0
+            && range.as_u64() > self.max_unfiltered_range
+            && !has_address_or_topic(filter)
+        {
+            return Err(Web3ProxyError::UnfilteredRangeTooLarge {
+                requested: range,
+                allowed: self.max_unfiltered_range.into(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(x: OneOrMany<T>) -> Self {
+        match x {
+            OneOrMany::One(x) => vec![x],
+            OneOrMany::Many(x) => x,
+        }
+    }
+}
+
+/// the address and topics of an eth_getLogs filter. anything else in the filter is ignored
+#[derive(Deserialize)]
+struct RawLogFilter {
+    #[serde(default)]
+    address: Option<OneOrMany<Address>>,
+    #[serde(default)]
+    topics: Option<Vec<Option<OneOrMany<H256>>>>,
+}
+
+/// Which logs a filter matches. Block ranges are not part of this.
+///
+/// An empty list of addresses matches any address. Each position in `topics` matches that position in the log. An empty
+/// position (`null` in the filter) matches any topic. Otherwise, the log's topic must be one of the alternatives.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(from = "RawLogFilter")]
+pub struct LogFilter {
+    pub addresses: Vec<Address>,
+    pub topics: Vec<Vec<H256>>,
+}
+
+impl From<RawLogFilter> for LogFilter {
+    fn from(x: RawLogFilter) -> Self {
+        Self {
+            addresses: x.address.map(Into::into).unwrap_or_default(),
+            topics: x
+                .topics
+                .unwrap_or_default()
+                .into_iter()
+                .map(|x| x.map(Into::into).unwrap_or_default())
+                .collect(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// `None` matches every log
+    pub fn from_param(param: Option<&serde_json::Value>) -> Web3ProxyResult<Self> {
+        let Some(param) = param else {
+            return Ok(Default::default());
+        };
+
+        Self::deserialize(param).map_err(|err| {
+            Web3ProxyError::InvalidParams(format!("invalid log filter: {}", err).into())
+        })
+    }
+
+    pub fn matches(&self, log: &Log) -> bool {
+        if !self.addresses.is_empty() && !self.addresses.contains(&log.address) {
+            return false;
+        }
+
+        // like geth, a filter with more topics than the log never matches. even if the extra topics are null
+        if self.topics.len() > log.topics.len() {
+            return false;
+        }
+
+        self.topics
+            .iter()
+            .zip(log.topics.iter())
+            .all(|(alternatives, topic)| alternatives.is_empty() || alternatives.contains(topic))
+    }
+}
+
+/// The logs that a subscription sent for each of its recent heads.
+/// When the chain reorgs, the logs from blocks that are no longer on it are sent again with `removed: true`.
+#[derive(Debug)]
+pub struct RecentLogs {
+    max_blocks: usize,
+    /// oldest first
+    blocks: VecDeque<(U64, H256, Vec<Log>)>,
+}
+
+impl RecentLogs {
+    pub fn new(max_blocks: usize) -> Self {
+        Self {
+            max_blocks: max_blocks.max(1),
+            blocks: VecDeque::with_capacity(max_blocks),
+        }
+    }
+
+    pub fn contains(&self, num: U64, hash: &H256) -> bool {
+        self.blocks.iter().any(|(n, h, _)| *n == num && h == hash)
+    }
+
+    pub fn oldest(&self) -> Option<U64> {
+        self.blocks.front().map(|(n, _, _)| *n)
+    }
+
+    /// Forget the blocks that are no longer on the chain and return their logs, newest first, marked as removed.
+    /// `canonical` has the new chain's hashes by number, from the new head back as far as they are known.
+    /// Blocks older than that are assumed to still be on the chain.
+    pub fn rewind(&mut self, canonical: &BTreeMap<U64, H256>) -> Vec<Log> {
+        let Some(new_head_num) = canonical.keys().next_back().copied() else {
+            return vec![];
+        };
+
+        let mut removed = vec![];
+
+        while let Some((num, hash, _)) = self.blocks.back() {
+            let still_canonical = match canonical.get(num) {
+                Some(x) => x == hash,
+                None => *num < new_head_num,
+            };
+
+            if still_canonical {
+                break;
+            }
+
+            let (_, _, logs) = self.blocks.pop_back().expect("back was just checked");
+
+            removed.extend(logs.into_iter().rev().map(|mut x| {
+                x.removed = Some(true);
+                x
+            }));
+        }
+
+        removed
+    }
+
+    pub fn push(&mut self, num: U64, hash: H256, logs: Vec<Log>) {
+        if self.blocks.len() >= self.max_blocks {
+            self.blocks.pop_front();
+        }
+
+        self.blocks.push_back((num, hash, logs));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn num(x: u64) -> BlockNumOrHash {
+        BlockNumOrHash::Num(x.into())
+    }
+
+    fn range(from: u64, to: u64) -> (U64, U64) {
+        (from.into(), to.into())
+    }
+
+    #[test]
+    fn get_logs_limits() {
+        let limits = GetLogsLimits {
+            max_range_public: 1_000,
+            max_range_premium: 10_000,
+            max_unfiltered_range: 100,
+            max_blocks_past_head: 2,
+            ..Default::default()
+        };
+
+        let head = U64::from(1_000_000);
+
+        let address = json!({"address": "0x0000000000000000000000000000000000000001"});
+        let topic = json!({"topics": [null, ["0x01"]]});
+        let unfiltered = json!({"address": [], "topics": [null, []]});
+
+        // small ranges are always fine
+        for filter in [&address, &topic, &unfiltered] {
+            limits
+                .validate(filter, &num(999_950), &num(1_000_000), head, false)
+                .unwrap();
+        }
+
+        // unfiltered queries can only be small
+        assert!(matches!(
+            limits.validate(&unfiltered, &num(999_000), &num(1_000_000), head, false),
+            Err(Web3ProxyError::UnfilteredRangeTooLarge { .. })
+        ));
+        limits
+            .validate(&topic, &num(999_000), &num(1_000_000), head, false)
+            .unwrap();
+
+        // premium users get a larger range
+        assert!(matches!(
+            limits.validate(&address, &num(990_000), &num(1_000_000), head, false),
+            Err(Web3ProxyError::RangeTooLarge { .. })
+        ));
+        limits
+            .validate(&address, &num(990_000), &num(1_000_000), head, true)
+            .unwrap();
+
+        // a little past the head is okay. far past is not
+        limits
+            .validate(&address, &num(1_000_000), &num(1_000_002), head, false)
+            .unwrap();
+        assert!(matches!(
+            limits.validate(&address, &num(1_000_000), &num(2_000_000), head, false),
+            Err(Web3ProxyError::RangeInFuture { .. })
+        ));
+
+        // 0 disables the limits
+        let limits = GetLogsLimits {
+            max_range_public: 0,
+            max_range_premium: 0,
+            max_unfiltered_range: 0,
+            max_blocks_past_head: 0,
+            ..Default::default()
+        };
+
+        limits
+            .validate(&unfiltered, &num(0), &num(2_000_000), head, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn pages() {
+        let limits = GetLogsLimits {
+            max_range_public: 1_000,
+            max_range_premium: 10_000,
+            max_unfiltered_range: 100,
+            ..Default::default()
+        };
+
+        let address = json!({"address": "0x0000000000000000000000000000000000000001"});
+        let unfiltered = json!({});
+
+        assert_eq!(limits.page_size(&address, false), 1_001);
+        assert_eq!(limits.page_size(&address, true), 10_001);
+        assert_eq!(limits.page_size(&unfiltered, true), 101);
+
+        let ranges = page_ranges(0.into(), 2_500.into(), limits.page_size(&address, false));
+
+        assert_eq!(
+            ranges,
+            [range(0, 1_000), range(1_001, 2_001), range(2_002, 2_500)]
+        );
+
+        // every page passes validation
+        for (from, to) in ranges {
+            limits
+                .validate(
+                    &address,
+                    &num(from.as_u64()),
+                    &num(to.as_u64()),
+                    2_500.into(),
+                    false,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(page_ranges(5.into(), 5.into(), 10), [range(5, 5)]);
+        assert_eq!(page_ranges(0.into(), 9.into(), 0), [range(0, 9)]);
+    }
+
+    #[test]
+    fn web3_proxy_options() {
+        let mut params = json!([{"address": [], "web3ProxyOptions": {"autoPaginate": true}}]);
+
+        assert!(Web3ProxyOptions::take(&mut params).unwrap().auto_paginate);
+        assert_eq!(params, json!([{"address": []}]));
+
+        // the header doesn't override the body
+        let mut params = json!([{"web3ProxyOptions": {"autoPaginate": false}}]);
+        Web3ProxyOptions::default_auto_paginate(&mut params);
+        assert!(!Web3ProxyOptions::take(&mut params).unwrap().auto_paginate);
+
+        let mut params = json!([{}]);
+        Web3ProxyOptions::default_auto_paginate(&mut params);
+        assert!(Web3ProxyOptions::take(&mut params).unwrap().auto_paginate);
+
+        let mut params = json!([{"web3ProxyOptions": "yes"}]);
+        assert!(Web3ProxyOptions::take(&mut params).is_err());
+    }
+
+    #[test]
+    fn paginated_logs() {
+        let limits = GetLogsLimits {
+            max_paginated_results: 4,
+            ..Default::default()
+        };
+
+        let log = |block: u64, index: u64| json!({"blockNumber": U64::from(block), "logIndex": U64::from(index)});
+        let page = |logs: Vec<serde_json::Value>| serde_json::value::to_raw_value(&logs).unwrap();
+
+        let mut x = PaginatedLogs::default();
+
+        // pages finish out of order
+        x.add_page(
+            &limits,
+            10.into(),
+            19.into(),
+            &page(vec![log(12, 1), log(12, 0)]),
+        )
+        .unwrap();
+        x.add_page(&limits, 0.into(), 9.into(), &page(vec![log(3, 7)]))
+            .unwrap();
+
+        assert_eq!(x.into_sorted(), [log(3, 7), log(12, 0), log(12, 1)]);
+
+        // too many results
+        let mut x = PaginatedLogs::default();
+
+        x.add_page(
+            &limits,
+            0.into(),
+            9.into(),
+            &page(vec![log(1, 0), log(2, 0)]),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            x.add_page(
+                &limits,
+                10.into(),
+                19.into(),
+                &page(vec![log(11, 0), log(12, 0), log(13, 0)])
+            ),
+            Err(Web3ProxyError::PaginatedLogsTooLarge { results: 5, .. })
+        ));
+    }
+
+    fn log(address: u64, topics: &[u64]) -> Log {
+        Log {
+            address: Address::from_low_u64_be(address),
+            topics: topics.iter().map(|x| H256::from_low_u64_be(*x)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn log_filter() {
+        let a = Address::from_low_u64_be(1);
+        let t = |x: u64| H256::from_low_u64_be(x);
+
+        let x = LogFilter::from_param(Some(&json!({
+            "address": a,
+            "topics": [null, [t(2), t(3)]],
+            "fromBlock": "latest",
+        })))
+        .unwrap();
+
+        assert_eq!(
+            x,
+            LogFilter {
+                addresses: vec![a],
+                topics: vec![vec![], vec![t(2), t(3)]],
+            }
+        );
+
+        assert!(x.matches(&log(1, &[9, 2])));
+        assert!(x.matches(&log(1, &[9, 3, 4])));
+        assert!(!x.matches(&log(1, &[9, 4])));
+        assert!(!x.matches(&log(2, &[9, 2])));
+        // more topics in the filter than in the log
+        assert!(!x.matches(&log(1, &[9])));
+
+        // a list of addresses and a single topic
+        let x = LogFilter::from_param(Some(&json!({
+            "address": [a, Address::from_low_u64_be(2)],
+            "topics": [t(5)],
+        })))
+        .unwrap();
+
+        assert!(x.matches(&log(2, &[5])));
+        assert!(!x.matches(&log(3, &[5])));
+        assert!(!x.matches(&log(2, &[])));
+
+        // no filter matches everything
+        let x = LogFilter::from_param(None).unwrap();
+        assert!(x.matches(&log(7, &[])));
+        assert_eq!(x, LogFilter::from_param(Some(&json!({}))).unwrap());
+
+        assert!(matches!(
+            LogFilter::from_param(Some(&json!({"address": "not an address"}))),
+            Err(Web3ProxyError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn recent_logs_rewind() {
+        let h = |x: u64| H256::from_low_u64_be(x);
+
+        let mut x = RecentLogs::new(3);
+
+        x.push(1.into(), h(1), vec![log(1, &[1])]);
+        x.push(2.into(), h(2), vec![log(1, &[2])]);
+        x.push(3.into(), h(3), vec![log(1, &[3]), log(1, &[4])]);
+        x.push(4.into(), h(4), vec![]);
+
+        // the oldest block fell off
+        assert_eq!(x.oldest(), Some(2.into()));
+        assert!(x.contains(3.into(), &h(3)));
+
+        // the next head builds on ours
+        let canonical = BTreeMap::from([(4.into(), h(4)), (5.into(), h(5))]);
+        assert!(x.rewind(&canonical).is_empty());
+
+        // a new 4 whose parent is a new 3. the 2 is still ours
+        let canonical = BTreeMap::from([(2.into(), h(2)), (3.into(), h(30)), (4.into(), h(40))]);
+
+        let removed = x.rewind(&canonical);
+
+        let removed: Vec<_> = removed.iter().map(|x| (x.topics[0], x.removed)).collect();
+
+        assert_eq!(removed, vec![(h(4), Some(true)), (h(3), Some(true))]);
+
+        assert_eq!(x.oldest(), Some(2.into()));
+        assert!(!x.contains(3.into(), &h(3)));
+
+        // a shorter chain replaces blocks past its head
+        x.push(3.into(), h(30), vec![log(1, &[30])]);
+        x.push(4.into(), h(40), vec![log(1, &[40])]);
+
+        let canonical = BTreeMap::from([(3.into(), h(30))]);
+
+        let removed = x.rewind(&canonical);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].topics[0], h(40));
+    }
+}
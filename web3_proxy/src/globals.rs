@@ -2,14 +2,16 @@
 
 use crate::{app::App, errors::Web3ProxyError, relational_db::DatabaseReplica};
 use derivative::Derivative;
+use hashbrown::HashMap;
 use migration::{
     sea_orm::{DatabaseConnection, DatabaseTransaction, TransactionTrait},
     DbErr,
 };
 use parking_lot::RwLock;
-use std::sync::{Arc, LazyLock, OnceLock};
+use std::sync::{Arc, LazyLock};
 
-pub static APP: OnceLock<Arc<App>> = OnceLock::new();
+/// The App for each chain that this process serves, keyed by chain id
+static APPS: LazyLock<RwLock<HashMap<u64, Arc<App>>>> = LazyLock::new(Default::default);
 
 pub static DB_CONN: LazyLock<RwLock<Result<DatabaseConnection, DatabaseError>>> =
     LazyLock::new(|| RwLock::new(Err(DatabaseError::NotConfigured)));
@@ -17,6 +19,29 @@ pub static DB_CONN: LazyLock<RwLock<Result<DatabaseConnection, DatabaseError>>>
 pub static DB_REPLICA: LazyLock<RwLock<Result<DatabaseReplica, DatabaseError>>> =
     LazyLock::new(|| RwLock::new(Err(DatabaseError::NotConfigured)));
 
+/// The first App spawned for a chain is kept. Returns the app back if its chain already has one
+pub fn set_global_app(app: Arc<App>) -> Result<(), Arc<App>> {
+    let mut apps = APPS.write();
+
+    if apps.contains_key(&app.config.chain_id) {
+        return Err(app);
+    }
+
+    apps.insert(app.config.chain_id, app);
+
+    Ok(())
+}
+
+#[inline]
+pub fn global_app(chain_id: u64) -> Option<Arc<App>> {
+    APPS.read().get(&chain_id).cloned()
+}
+
+/// every chain's App
+pub fn global_apps() -> Vec<Arc<App>> {
+    APPS.read().values().cloned().collect()
+}
+
 #[derive(Clone, Debug, Derivative)]
 pub enum DatabaseError {
     /// no database configured. depending on what you need, this may or may not be a problem
@@ -0,0 +1,216 @@
+//! The last few consensus heads, so that newHeads subscribers can catch up after a brief disconnect.
+//!
+//! A client that reconnects sends `web3proxy_resubscribe` with the last block it saw. The heads that it missed come
+//! back with the new subscription's id. If the oldest head here is still newer than what the client missed, the
+//! response also has a `gap` so the client knows to backfill some other way. Heads are the same for everyone, so there
+//! is one small buffer for the whole app.
+
+use crate::config::AppConfig;
+use crate::rpcs::blockchain::BlockHeader;
+use ethers::types::U64;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::info;
+
+/// the client's last block is older than anything in the buffer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ReplayGap {
+    pub last_block: U64,
+    /// None if no heads have been seen yet
+    pub oldest_block: Option<U64>,
+}
+
+#[derive(Debug)]
+pub struct MissedHeads {
+    /// oldest first
+    pub heads: Vec<BlockHeader>,
+    pub gap: Option<ReplayGap>,
+}
+
+#[derive(Debug)]
+pub struct HeadReplay {
+    heads: RwLock<VecDeque<BlockHeader>>,
+    max_heads: usize,
+}
+
+impl HeadReplay {
+    pub fn new(max_heads: usize) -> Self {
+        Self {
+            heads: RwLock::new(VecDeque::with_capacity(max_heads)),
+            max_heads,
+        }
+    }
+
+    /// None if `head_replay_blocks` is 0
+    pub fn spawn(
+        config: &AppConfig,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+    ) -> Option<Arc<Self>> {
+        if config.head_replay_blocks == 0 {
+            return None;
+        }
+
+        let x = Arc::new(Self::new(config.head_replay_blocks));
+
+        tokio::spawn(x.clone().follow(head_block_receiver));
+
+        info!(
+            head_replay_blocks = config.head_replay_blocks,
+            "keeping recent heads for resubscribes"
+        );
+
+        Some(x)
+    }
+
+    async fn follow(
+        self: Arc<Self>,
+        mut head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+    ) {
+        while head_block_receiver.changed().await.is_ok() {
+            let head = head_block_receiver.borrow_and_update().clone();
+
+            if let Some(head) = head {
+                self.push(head);
+            }
+        }
+    }
+
+    pub fn push(&self, head: BlockHeader) {
+        let mut heads = self.heads.write();
+
+        // on a reorg, the new head replaces everything at or above its height
+        while heads.back().map_or(false, |x| x.number() >= head.number()) {
+            heads.pop_back();
+        }
+
+        heads.push_back(head);
+
+        while heads.len() > self.max_heads {
+            heads.pop_front();
+        }
+    }
+
+    /// Every head after `last_block`
+    pub fn since(&self, last_block: U64) -> MissedHeads {
+        let heads = self.heads.read();
+
+        let oldest_block = heads.front().map(|x| x.number());
+
+        let gap = match oldest_block {
+            Some(oldest) if oldest <= last_block + 1 => None,
+            oldest_block => Some(ReplayGap {
+                last_block,
+                oldest_block,
+            }),
+        };
+
+        let heads = heads
+            .iter()
+            .filter(|x| x.number() > last_block)
+            .cloned()
+            .collect();
+
+        MissedHeads { heads, gap }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heads.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heads.read().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Block, H256};
+
+    fn head(num: u64, hash: u64) -> BlockHeader {
+        let block = Block {
+            number: Some(num.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            ..Default::default()
+        };
+
+        BlockHeader::try_new(Arc::new(block)).unwrap()
+    }
+
+    fn nums(x: &MissedHeads) -> Vec<u64> {
+        x.heads.iter().map(|x| x.number().as_u64()).collect()
+    }
+
+    #[test]
+    fn replays_missed_heads() {
+        let replay = HeadReplay::new(4);
+
+        for i in 1..=6 {
+            replay.push(head(i, i));
+        }
+
+        // only the last 4 are kept
+        assert_eq!(replay.len(), 4);
+
+        let missed = replay.since(4.into());
+        assert_eq!(nums(&missed), vec![5, 6]);
+        assert_eq!(missed.gap, None);
+
+        // the oldest kept head is right after the client's last block
+        let missed = replay.since(2.into());
+        assert_eq!(nums(&missed), vec![3, 4, 5, 6]);
+        assert_eq!(missed.gap, None);
+
+        // block 2 is gone. the client gets what we have and a gap
+        let missed = replay.since(1.into());
+        assert_eq!(nums(&missed), vec![3, 4, 5, 6]);
+        assert_eq!(
+            missed.gap,
+            Some(ReplayGap {
+                last_block: 1.into(),
+                oldest_block: Some(3.into()),
+            })
+        );
+
+        // already caught up
+        let missed = replay.since(6.into());
+        assert!(missed.heads.is_empty());
+        assert_eq!(missed.gap, None);
+    }
+
+    #[test]
+    fn reorg_replaces_heads() {
+        let replay = HeadReplay::new(8);
+
+        for i in 1..=5 {
+            replay.push(head(i, i));
+        }
+
+        // a new block 4 orphans the old 4 and 5
+        replay.push(head(4, 44));
+
+        let missed = replay.since(3.into());
+        assert_eq!(nums(&missed), vec![4]);
+        assert_eq!(*missed.heads[0].hash(), H256::from_low_u64_be(44));
+    }
+
+    #[test]
+    fn empty_is_a_gap() {
+        let replay = HeadReplay::new(8);
+
+        assert!(replay.is_empty());
+
+        let missed = replay.since(10.into());
+        assert!(missed.heads.is_empty());
+        assert_eq!(
+            missed.gap,
+            Some(ReplayGap {
+                last_block: 10.into(),
+                oldest_block: None,
+            })
+        );
+    }
+}
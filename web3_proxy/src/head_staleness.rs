@@ -0,0 +1,156 @@
+//! How long since the consensus head last changed.
+//!
+//! If every backend stops producing heads (a chain halt, or all of our subscriptions died), "latest" keeps resolving to
+//! the same old block. The block's own timestamp can't be trusted to notice this, so this tracks when we *saw* the head
+//! change. Past `stale_head_ms`, nothing new is saved in the response cache for the head block, `/health` fails, and
+//! responses get an `X-W3P-STALE-HEAD` header. With `stale_head_reject_latest`, requests for the head block are rejected.
+//! The next new head clears all of this.
+
+use crate::config::AppConfig;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::ValidatedRequest;
+use crate::rpcs::blockchain::BlockHeader;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// without `stale_head_ms`, the head is stale after this many average block intervals
+pub const DEFAULT_STALE_HEAD_BLOCKS: u32 = 5;
+
+#[derive(Debug)]
+pub struct HeadStaleness {
+    /// when the consensus head last changed. None until the first head
+    changed_at: Mutex<Option<Instant>>,
+    max_age: Duration,
+    reject_latest: bool,
+    /// so that going stale is only logged once
+    warned: AtomicBool,
+}
+
+impl HeadStaleness {
+    pub fn new(max_age: Duration, reject_latest: bool) -> Self {
+        Self {
+            changed_at: Mutex::new(None),
+            max_age,
+            reject_latest,
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn spawn(
+        config: &AppConfig,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+    ) -> Arc<Self> {
+        let max_age = config
+            .stale_head_ms
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| config.block_interval() * DEFAULT_STALE_HEAD_BLOCKS);
+
+        let x = Arc::new(Self::new(max_age, config.stale_head_reject_latest));
+
+        tokio::spawn(x.clone().follow(head_block_receiver));
+
+        x
+    }
+
+    async fn follow(
+        self: Arc<Self>,
+        mut head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+    ) {
+        let mut last_hash = None;
+
+        while head_block_receiver.changed().await.is_ok() {
+            let hash = head_block_receiver
+                .borrow_and_update()
+                .as_ref()
+                .map(|x| *x.hash());
+
+            // the sender sometimes sends the same head again. that isn't progress
+            if hash.is_some() && hash != last_hash {
+                last_hash = hash;
+
+                self.head_changed(Instant::now());
+            }
+        }
+    }
+
+    pub fn head_changed(&self, now: Instant) {
+        *self.changed_at.lock() = Some(now);
+
+        if self.warned.swap(false, Ordering::Relaxed) {
+            info!("consensus head is fresh again");
+        }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Some if the head is older than `max_age`. Before the first head, there is nothing to be stale
+    pub fn stale_age(&self) -> Option<Duration> {
+        let age = self.changed_at.lock().map(|x| x.elapsed())?;
+
+        if age <= self.max_age {
+            return None;
+        }
+
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(?age, max_age=?self.max_age, "consensus head is stale. not caching the latest block");
+        }
+
+        Some(age)
+    }
+
+    #[inline]
+    pub fn is_stale(&self) -> bool {
+        self.stale_age().is_some()
+    }
+
+    /// true if a response for this request shouldn't go into the response cache
+    pub fn skip_cache(&self, web3_request: &ValidatedRequest) -> bool {
+        web3_request.uses_head_block() && self.is_stale()
+    }
+
+    /// errors if `stale_head_reject_latest` is set and this request needs the stale head
+    pub fn check_request(&self, web3_request: &ValidatedRequest) -> Web3ProxyResult<()> {
+        if !self.reject_latest || !web3_request.uses_head_block() {
+            return Ok(());
+        }
+
+        match self.stale_age() {
+            Some(age) => Err(Web3ProxyError::StaleHead(age)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_and_recover() {
+        let x = HeadStaleness::new(Duration::from_secs(10), false);
+
+        // no head yet
+        assert_eq!(x.stale_age(), None);
+
+        x.head_changed(Instant::now());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        assert!(!x.is_stale());
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        assert_eq!(x.stale_age(), Some(Duration::from_secs(15)));
+
+        // a new head clears it
+        x.head_changed(Instant::now());
+
+        assert!(!x.is_stale());
+    }
+}
@@ -0,0 +1,187 @@
+//! Keep the chain from going backwards for a client.
+//!
+//! Backends at different heights can answer the same client. Without this, a wallet could see block N from one request
+//! and block N-1 from the next. The highest head served to each key (or ip) is remembered for a while. When the
+//! consensus head is below that, a backend that already has the client's block is preferred. If none do, the request
+//! waits briefly for the consensus head to catch up. If every backend went backwards, the older head is used and the
+//! response gets an `X-W3P-OLDER-HEAD` header.
+
+use crate::bans::BanKey;
+use crate::config::AppConfig;
+use crate::frontend::authorization::Authorization;
+use crate::rpcs::blockchain::BlockHeader;
+use crate::rpcs::many::Web3Rpcs;
+use ethers::types::U64;
+use moka::future::{Cache, CacheBuilder};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// the head used for a request was older than one this client already saw
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OlderHead {
+    pub served: U64,
+    pub seen: U64,
+}
+
+/// used for the header. `served/seen`
+impl fmt::Display for OlderHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.served, self.seen)
+    }
+}
+
+pub struct HeadWatermarks {
+    pub(crate) heads: Cache<BanKey, Arc<AtomicU64>>,
+    wait: Duration,
+}
+
+impl HeadWatermarks {
+    pub fn new(max_clients: u64, ttl: Duration, wait: Duration) -> Self {
+        let heads = CacheBuilder::new(max_clients)
+            .name("head_watermarks")
+            .time_to_idle(ttl)
+            .build();
+
+        Self { heads, wait }
+    }
+
+    /// None if `head_watermark_ttl_secs` is 0
+    pub fn try_new(config: &AppConfig) -> Option<Self> {
+        if config.head_watermark_ttl_secs == 0 {
+            return None;
+        }
+
+        Some(Self::new(
+            100_000,
+            Duration::from_secs(config.head_watermark_ttl_secs),
+            Duration::from_millis(config.head_watermark_wait_ms),
+        ))
+    }
+
+    /// The highest head served to this client. 0 if it hasn't been seen recently
+    pub async fn seen(&self, key: BanKey) -> U64 {
+        match self.heads.get(&key).await {
+            Some(x) => x.load(Ordering::Relaxed).into(),
+            None => U64::zero(),
+        }
+    }
+
+    /// Remember that this client was sent `num`. Lower numbers are ignored
+    pub async fn served(&self, key: BanKey, num: U64) {
+        self.heads
+            .get_with(key, async { Arc::new(AtomicU64::new(0)) })
+            .await
+            .fetch_max(num.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Pick the head for a request so that this client never sees an older one than it already has.
+    /// The second value is set if that wasn't possible.
+    pub async fn guard(
+        &self,
+        authorization: &Authorization,
+        head_block: Option<BlockHeader>,
+        rpcs: &Web3Rpcs,
+    ) -> (Option<BlockHeader>, Option<OlderHead>) {
+        // internal requests don't have a client to protect
+        let Some(key) = BanKey::from_authorization(authorization) else {
+            return (head_block, None);
+        };
+
+        let Some(head_block) = head_block else {
+            return (None, None);
+        };
+
+        let seen = self.seen(key).await;
+
+        let head_block = if head_block.number() >= seen {
+            head_block
+        } else if let Some(x) = block_on_any_rpc(rpcs, seen).await {
+            // some rpcs are behind, but not all of them. the request will prefer the ones that have this block
+            x
+        } else if let Some(x) = self.wait_for_head(rpcs, seen).await {
+            x
+        } else {
+            // every rpc went backwards. this is the best we can do
+            let older = OlderHead {
+                served: head_block.number(),
+                seen,
+            };
+
+            return (Some(head_block), Some(older));
+        };
+
+        self.served(key, head_block.number()).await;
+
+        (Some(head_block), None)
+    }
+
+    /// Wait a short time for the consensus head to reach `num`
+    async fn wait_for_head(&self, rpcs: &Web3Rpcs, num: U64) -> Option<BlockHeader> {
+        let mut ranked_rpcs_recv = rpcs.watch_ranked_rpcs.subscribe();
+
+        let f = async {
+            loop {
+                let head_block = ranked_rpcs_recv
+                    .borrow_and_update()
+                    .as_ref()
+                    .and_then(|x| x.head_block.clone());
+
+                if let Some(x) = head_block.filter(|x| x.number() >= num) {
+                    return Some(x);
+                }
+
+                ranked_rpcs_recv.changed().await.ok()?;
+            }
+        };
+
+        timeout(self.wait, f).await.ok().flatten()
+    }
+}
+
+/// The block at `num` if we know its header and at least one ranked rpc has it
+async fn block_on_any_rpc(rpcs: &Web3Rpcs, num: U64) -> Option<BlockHeader> {
+    let hash = rpcs.blocks_by_number.get(&num).await?;
+
+    let block = rpcs.blocks_by_hash.get(&hash).await?;
+
+    let ranked_rpcs = rpcs.watch_ranked_rpcs.borrow().clone()?;
+
+    ranked_rpcs
+        .inner
+        .iter()
+        .any(|x| x.has_block_data(num))
+        .then_some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[tokio::test]
+    async fn watermark_only_goes_up() {
+        let watermarks =
+            HeadWatermarks::new(100, Duration::from_secs(60), Duration::from_millis(10));
+
+        let a = BanKey::Ip("203.0.113.7".parse::<IpAddr>().unwrap());
+        let b = BanKey::Ip("203.0.113.8".parse::<IpAddr>().unwrap());
+
+        assert_eq!(watermarks.seen(a).await, U64::zero());
+
+        // one backend is at 10, another at 9
+        watermarks.served(a, 10.into()).await;
+        watermarks.served(a, 9.into()).await;
+        assert_eq!(watermarks.seen(a).await, 10.into());
+
+        // other clients are tracked separately
+        watermarks.served(b, 9.into()).await;
+        assert_eq!(watermarks.seen(b).await, 9.into());
+        assert_eq!(watermarks.seen(a).await, 10.into());
+
+        watermarks.served(a, 11.into()).await;
+        assert_eq!(watermarks.seen(a).await, 11.into());
+    }
+}
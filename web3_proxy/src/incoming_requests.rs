@@ -0,0 +1,130 @@
+//! Counts for the coalescing of identical cacheable requests.
+//!
+//! The coalescing itself is done by the response cache. The first request for a cache key runs the cache's loader and
+//! is sent to a backend. Identical requests that arrive while it is in flight wait for the loader and get its response.
+//! If the first request failed or its response was not cacheable, the waiters send their own requests.
+//!
+//! The cache keys that are in flight are kept in a map so that we can see how many there are. A burst of unique
+//! requests grows the map, so it is shrunk again once it empties.
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// an empty map keeps room for this many cache keys
+pub const MIN_IN_FLIGHT_CAPACITY: usize = 64;
+
+#[derive(Default, Serialize)]
+pub struct IncomingRequestCounts {
+    /// requests that waited for an identical request instead of going to a backend
+    pub coalesced: u64,
+    /// requests from keys that skip coalescing
+    pub skipped: u64,
+}
+
+#[derive(Default)]
+pub struct IncomingRequests {
+    coalesced: AtomicU64,
+    skipped: AtomicU64,
+    /// cache key -> requests for it that are in flight
+    in_flight: Mutex<HashMap<u64, usize>>,
+}
+
+/// Removes the request from the in-flight map when dropped
+pub struct InFlightGuard<'a> {
+    incoming_requests: &'a IncomingRequests,
+    cache_key: u64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.incoming_requests.in_flight.lock();
+
+        if let Some(x) = in_flight.get_mut(&self.cache_key) {
+            *x -= 1;
+
+            if *x == 0 {
+                in_flight.remove(&self.cache_key);
+            }
+        }
+
+        // the map never gives memory back on its own
+        if in_flight.is_empty() && in_flight.capacity() > MIN_IN_FLIGHT_CAPACITY * 4 {
+            in_flight.shrink_to(MIN_IN_FLIGHT_CAPACITY);
+        }
+    }
+}
+
+impl IncomingRequests {
+    /// track a request for this cache key until the guard is dropped
+    pub fn start(&self, cache_key: u64) -> InFlightGuard<'_> {
+        *self.in_flight.lock().entry(cache_key).or_default() += 1;
+
+        InFlightGuard {
+            incoming_requests: self,
+            cache_key,
+        }
+    }
+
+    /// cache keys with at least one request in flight
+    pub fn num_in_flight(&self) -> usize {
+        self.in_flight.lock().len()
+    }
+
+    /// how many cache keys the in-flight map can hold without growing
+    pub fn in_flight_capacity(&self) -> usize {
+        self.in_flight.lock().capacity()
+    }
+
+    /// a request got the response of an identical request that was already in flight
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a key that skips coalescing sent its request straight to a backend
+    pub fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> IncomingRequestCounts {
+        IncomingRequestCounts {
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_flight_map_shrinks() {
+        let x = IncomingRequests::default();
+
+        let a = x.start(1);
+        let b = x.start(1);
+
+        assert_eq!(x.num_in_flight(), 1);
+
+        drop(a);
+
+        // the identical request is still in flight
+        assert_eq!(x.num_in_flight(), 1);
+
+        drop(b);
+
+        assert_eq!(x.num_in_flight(), 0);
+
+        let burst: Vec<_> = (0..10_000).map(|i| x.start(i)).collect();
+
+        assert_eq!(x.num_in_flight(), 10_000);
+        assert!(x.in_flight_capacity() >= 10_000);
+
+        drop(burst);
+
+        assert_eq!(x.num_in_flight(), 0);
+        assert!(x.in_flight_capacity() <= MIN_IN_FLIGHT_CAPACITY * 4);
+    }
+}
@@ -0,0 +1,215 @@
+//! Answer node introspection methods from the proxy's own state.
+//!
+//! `eth_syncing`, `net_peerCount`, `net_listening`, and `web3_clientVersion` describe a single node. Forwarding them
+//! leaks whichever backend happened to answer, so monitoring pointed at the proxy sees the peer count bounce around
+//! as requests are balanced. Each method can still be forwarded for operators that prefer passthrough.
+
+use crate::rpcs::blockchain::BlockHeader;
+use ethers::types::U64;
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Who answers an introspection method
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntrospectionPolicy {
+    /// the proxy answers from its own state
+    #[default]
+    Local,
+    /// send it to a backend like any other request
+    Forward,
+}
+
+impl IntrospectionPolicy {
+    #[inline]
+    pub fn is_local(&self) -> bool {
+        matches!(self, Self::Local)
+    }
+}
+
+/// Settings for the node introspection methods
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeIntrospection {
+    #[serde(default = "Default::default")]
+    pub eth_syncing: IntrospectionPolicy,
+
+    #[serde(default = "Default::default")]
+    pub net_listening: IntrospectionPolicy,
+
+    #[serde(default = "Default::default")]
+    pub net_peer_count: IntrospectionPolicy,
+
+    #[serde(default = "Default::default")]
+    pub web3_client_version: IntrospectionPolicy,
+
+    /// `net_peerCount` answers with this instead of the number of healthy backends
+    #[serde_inline_default(None)]
+    pub peer_count: Option<u64>,
+
+    /// `web3_clientVersion` answers with this instead of the proxy's user agent
+    #[serde_inline_default(None)]
+    pub client_version: Option<String>,
+
+    /// `eth_syncing` answers false while the consensus head is at most this old (in seconds).
+    /// None uses the balanced rpcs' `max_head_block_age`
+    #[serde_inline_default(None)]
+    pub syncing_max_head_age_secs: Option<u64>,
+}
+
+impl Default for NodeIntrospection {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+impl NodeIntrospection {
+    /// true if the proxy should answer this method itself. false for methods that aren't introspection at all
+    pub fn is_local(&self, method: &str) -> bool {
+        match method {
+            "eth_syncing" => self.eth_syncing.is_local(),
+            "net_listening" => self.net_listening.is_local(),
+            "net_peerCount" => self.net_peer_count.is_local(),
+            "web3_clientVersion" => self.web3_client_version.is_local(),
+            _ => false,
+        }
+    }
+
+    pub fn net_peer_count(&self, num_synced_rpcs: usize) -> U64 {
+        self.peer_count.unwrap_or(num_synced_rpcs as u64).into()
+    }
+
+    pub fn syncing_max_head_age(&self, max_head_block_age: Duration) -> Duration {
+        self.syncing_max_head_age_secs
+            .map_or(max_head_block_age, Duration::from_secs)
+    }
+
+    pub fn web3_client_version<'a>(&'a self, default: &'a str) -> &'a str {
+        self.client_version.as_deref().unwrap_or(default)
+    }
+}
+
+/// The `eth_syncing` response for the proxy.
+///
+/// `false` while the consensus head is fresh. Once it is missing or older than `max_head_block_age`, every backend is
+/// behind, so this answers like a syncing node with the consensus head as the current block and the highest head any
+/// backend has as the highest block.
+pub fn eth_syncing(
+    head_block: Option<&BlockHeader>,
+    max_head_block_age: Duration,
+    highest_block: Option<U64>,
+) -> Value {
+    if let Some(head_block) = head_block {
+        if head_block.age() <= max_head_block_age {
+            return Value::Bool(false);
+        }
+    }
+
+    let current_block = head_block.map(|x| x.number()).unwrap_or_default();
+
+    let highest_block = highest_block.unwrap_or_default().max(current_block);
+
+    json!({
+        "startingBlock": current_block,
+        "currentBlock": current_block,
+        "highestBlock": highest_block,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Block, H256};
+    use std::sync::Arc;
+
+    fn block(num: u64, age: Duration) -> BlockHeader {
+        let timestamp = chrono::Utc::now().timestamp() as u64 - age.as_secs();
+
+        let block = Block {
+            hash: Some(H256::from_low_u64_be(num)),
+            number: Some(num.into()),
+            timestamp: timestamp.into(),
+            ..Default::default()
+        };
+
+        BlockHeader::try_new(Arc::new(block)).unwrap()
+    }
+
+    #[test]
+    fn syncing() {
+        let max_age = Duration::from_secs(60);
+
+        let fresh = block(100, Duration::ZERO);
+
+        assert_eq!(
+            eth_syncing(Some(&fresh), max_age, Some(100.into())),
+            json!(false)
+        );
+
+        let stale = block(100, Duration::from_secs(600));
+
+        assert_eq!(
+            eth_syncing(Some(&stale), max_age, Some(105.into())),
+            json!({
+                "startingBlock": "0x64",
+                "currentBlock": "0x64",
+                "highestBlock": "0x69",
+            })
+        );
+
+        // no consensus at all
+        assert_eq!(
+            eth_syncing(None, max_age, Some(7.into())),
+            json!({
+                "startingBlock": "0x0",
+                "currentBlock": "0x0",
+                "highestBlock": "0x7",
+            })
+        );
+    }
+
+    #[test]
+    fn config() {
+        let a = NodeIntrospection::default();
+
+        assert!(a.is_local("eth_syncing"));
+        assert!(a.is_local("net_listening"));
+        assert!(a.is_local("net_peerCount"));
+        assert!(a.is_local("web3_clientVersion"));
+        assert!(!a.is_local("eth_chainId"));
+
+        assert_eq!(a.net_peer_count(3), 3.into());
+        assert_eq!(a.web3_client_version("web3_proxy/1.0"), "web3_proxy/1.0");
+        assert_eq!(
+            a.syncing_max_head_age(Duration::from_secs(60)),
+            Duration::from_secs(60)
+        );
+
+        let b: NodeIntrospection = serde_json::from_value(json!({
+            "net_peer_count": "forward",
+            "web3_client_version": "forward",
+            "peer_count": 25,
+            "client_version": "example/v1",
+            "syncing_max_head_age_secs": 5,
+        }))
+        .unwrap();
+
+        assert!(b.is_local("eth_syncing"));
+        assert!(!b.is_local("net_peerCount"));
+        assert!(!b.is_local("web3_clientVersion"));
+
+        assert_eq!(b.net_peer_count(3), 25.into());
+        assert_eq!(b.web3_client_version("web3_proxy/1.0"), "example/v1");
+        assert_eq!(
+            b.syncing_max_head_age(Duration::from_secs(60)),
+            Duration::from_secs(5)
+        );
+
+        assert!(
+            serde_json::from_value::<NodeIntrospection>(json!({"eth_syncing": "maybe"})).is_err()
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Limits on how deeply JSON may nest.
+//!
+//! serde_json recurses once for every array or object when it parses into a `serde_json::Value`. A small body made of
+//! thousands of `[` would use a lot of stack. Request params and backend responses are scanned here first. The scan
+//! doesn't recurse, so it is safe on any input.
+
+use serde::de;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// serde_json refuses anything deeper than this on its own, so a higher limit has no effect
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 128;
+
+/// Set from `max_json_depth` in the config. 0 disables the check.
+static MAX_JSON_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_JSON_DEPTH);
+
+pub fn max_json_depth() -> usize {
+    MAX_JSON_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn set_max_json_depth(x: usize) {
+    MAX_JSON_DEPTH.store(x, Ordering::Relaxed);
+}
+
+/// How deeply arrays and objects nest. Brackets inside of strings are ignored.
+/// This does not check that the json is valid. Counting stops once it is deeper than `limit`.
+pub fn json_depth(json: &[u8], limit: usize) -> usize {
+    let mut depth = 0usize;
+    let mut deepest = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for x in json {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if *x == b'\\' {
+                escaped = true;
+            } else if *x == b'"' {
+                in_string = false;
+            }
+
+            continue;
+        }
+
+        match x {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+
+                if depth > deepest {
+                    deepest = depth;
+
+                    if deepest > limit {
+                        break;
+                    }
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    deepest
+}
+
+/// Error if arrays and objects in `json` nest deeper than `max_json_depth`.
+/// The error is a serde error so that it can be returned from inside of a `Deserialize`.
+pub fn check_json_depth<E: de::Error>(json: &[u8]) -> Result<(), E> {
+    let max = max_json_depth();
+
+    if max == 0 {
+        return Ok(());
+    }
+
+    if json_depth(json, max) > max {
+        return Err(E::custom(format!(
+            "json is nested deeper than max_json_depth ({})",
+            max
+        )));
+    }
+
+    Ok(())
+}
+
+/// Deserialize into a `serde_json::Value`, but only after checking the depth
+pub fn deserialize_limited_value<'de, D>(deserializer: D) -> Result<serde_json::Value, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    // serde_json collects a RawValue without recursing
+    let raw: Box<serde_json::value::RawValue> = de::Deserialize::deserialize(deserializer)?;
+
+    check_json_depth::<D::Error>(raw.get().as_bytes())?;
+
+    serde_json::from_str(raw.get()).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nanorand::Rng;
+
+    fn nested(depth: usize) -> String {
+        format!("{}1{}", "[".repeat(depth), "]".repeat(depth))
+    }
+
+    #[test]
+    fn depths() {
+        assert_eq!(json_depth(b"1", 128), 0);
+        assert_eq!(json_depth(b"[]", 128), 1);
+        assert_eq!(json_depth(br#"[{"a": [1]}, []]"#, 128), 3);
+        assert_eq!(json_depth(nested(100).as_bytes(), 128), 100);
+
+        // brackets in strings don't count
+        assert_eq!(json_depth(br#"["[[[", "\"[[[", "\\", []]"#, 128), 2);
+
+        // counting stops past the limit
+        assert_eq!(json_depth(nested(1_000_000).as_bytes(), 10), 11);
+    }
+
+    #[test]
+    fn errors_name_the_limit() {
+        assert!(
+            check_json_depth::<serde_json::Error>(nested(DEFAULT_MAX_JSON_DEPTH).as_bytes())
+                .is_ok()
+        );
+
+        let err =
+            check_json_depth::<serde_json::Error>(nested(DEFAULT_MAX_JSON_DEPTH + 1).as_bytes())
+                .unwrap_err();
+
+        assert!(err.to_string().contains("max_json_depth (128)"), "{}", err);
+    }
+
+    /// random brackets, quotes, and escapes must never panic
+    #[test]
+    fn fuzz_json_depth() {
+        let mut rng = nanorand::tls_rng();
+
+        let alphabet = b"[]{}\"\\,:1 ";
+
+        for _ in 0..1_000 {
+            let len = rng.generate_range(0usize..2_000);
+
+            let x: Vec<u8> = (0..len)
+                .map(|_| alphabet[rng.generate_range(0..alphabet.len())])
+                .collect();
+
+            let depth = json_depth(&x, 128);
+
+            assert!(depth <= 129);
+
+            // anything that passes the check is safe for serde_json to try
+            if check_json_depth::<serde_json::Error>(&x).is_ok() {
+                let _ = serde_json::from_slice::<serde_json::Value>(&x);
+            }
+        }
+    }
+}
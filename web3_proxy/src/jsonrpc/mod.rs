@@ -1,3 +1,4 @@
+pub mod depth;
 pub mod error;
 pub mod id;
 pub mod request;
@@ -12,7 +13,7 @@ pub use self::request::{JsonRpcRequestEnum, SingleRequest};
 pub use self::response::{
     ParsedResponse, Response, ResponsePayload, SingleResponse, StreamResponse,
 };
-pub use request_builder::ValidatedRequest;
+pub use request_builder::{ErrorClass, ValidatedRequest};
 
 pub trait JsonRpcParams = fmt::Debug + serde::Serialize + Send + Sync + 'static;
 pub trait JsonRpcResultData = serde::Serialize + serde::de::DeserializeOwned + fmt::Debug + Send;
@@ -83,4 +84,44 @@ mod tests {
 
         assert!(matches!(output, JsonRpcRequestEnum::Batch(_)));
     }
+
+    #[test]
+    fn deeply_nested_params() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+
+        // params are one level below the request object
+        let ok = format!(
+            r#"{{"jsonrpc":"2.0","method":"eth_call","params":{},"id":1}}"#,
+            nested(100)
+        );
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(&ok).unwrap();
+        assert!(matches!(output, JsonRpcRequestEnum::Single(_)));
+
+        // far too deep for a recursive parser. this must error instead of overflowing the stack
+        for depth in [129, 10_000, 1_000_000] {
+            let bad = format!(
+                r#"{{"jsonrpc":"2.0","method":"eth_call","params":{},"id":1}}"#,
+                nested(depth)
+            );
+
+            let err = serde_json::from_str::<JsonRpcRequestEnum>(&bad).unwrap_err();
+            assert!(err.to_string().contains("max_json_depth"), "{}", err);
+
+            let err = serde_json::from_str::<SingleRequest>(&bad).unwrap_err();
+            assert!(err.to_string().contains("max_json_depth"), "{}", err);
+
+            let err =
+                serde_json::from_str::<Vec<SingleRequest>>(&format!("[{}]", bad)).unwrap_err();
+            assert!(err.to_string().contains("max_json_depth"), "{}", err);
+        }
+
+        // unbalanced input is an error too
+        let bad = format!(
+            r#"{{"jsonrpc":"2.0","method":"eth_call","params":{},"id":1}}"#,
+            "[".repeat(1_000_000)
+        );
+
+        assert!(serde_json::from_str::<JsonRpcRequestEnum>(&bad).is_err());
+    }
 }
@@ -1,8 +1,11 @@
 use super::LooseId;
 use crate::app::App;
+use crate::bans::Violation;
 use crate::errors::{RequestForError, Web3ProxyError};
 use crate::frontend::authorization::{Authorization, RequestOrMethod};
-use crate::jsonrpc::ValidatedRequest;
+use crate::get_logs::Web3ProxyOptions;
+use crate::jsonrpc::depth::deserialize_limited_value;
+use crate::jsonrpc::{ErrorClass, ValidatedRequest};
 use axum::response::Response as AxumResponse;
 use derive_more::From;
 use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
@@ -26,6 +29,7 @@ pub struct SingleRequest {
     pub id: Box<RawValue>,
     pub method: Cow<'static, str>,
     #[serde_inline_default(serde_json::Value::Null)]
+    #[serde(deserialize_with = "deserialize_limited_value")]
     pub params: serde_json::Value,
 }
 
@@ -87,6 +91,18 @@ impl JsonRpcRequestEnum {
         }
     }
 
+    /// the client sent the auto pagination header. turn it on for every eth_getLogs that doesn't say otherwise
+    pub fn default_auto_paginate(&mut self) {
+        let requests = match self {
+            Self::Batch(x) => x.as_mut_slice(),
+            Self::Single(x) => std::slice::from_mut(x),
+        };
+
+        for x in requests.iter_mut().filter(|x| x.method == "eth_getLogs") {
+            Web3ProxyOptions::default_auto_paginate(&mut x.params);
+        }
+    }
+
     /// returns the id of the first invalid result (if any). None is good
     pub fn validate(&self) -> Option<Box<RawValue>> {
         match self {
@@ -119,7 +135,10 @@ impl JsonRpcRequestEnum {
             .expect("JsonRpcRequestEnum should always serialize")
             .len();
 
-        // TODO: create a stat so we can penalize
+        app.bans
+            .record_authorization(authorization, Violation::InvalidRequest)
+            .await;
+
         // TODO: what request size
         // TODO: this probably needs a permit
         let request = ValidatedRequest::new_with_app(
@@ -134,11 +153,7 @@ impl JsonRpcRequestEnum {
         .await
         .unwrap();
 
-        {
-            let mut response_lock = request.response.lock();
-
-            response_lock.user_error_response = true;
-        }
+        request.response.lock().set_error_class(ErrorClass::User);
 
         let response = Web3ProxyError::BadRequest("request failed validation".into());
 
@@ -155,6 +170,18 @@ impl JsonRpcRequestEnum {
     }
 }
 
+/// a `serde_json::Value` that is checked against `max_json_depth` before it is parsed
+struct LimitedValue(serde_json::Value);
+
+impl<'de> Deserialize<'de> for LimitedValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize_limited_value(deserializer).map(Self)
+    }
+}
+
 impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -227,7 +254,7 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                             if params.is_some() {
                                 return Err(de::Error::duplicate_field("params"));
                             }
-                            params = Some(map.next_value()?);
+                            params = Some(map.next_value::<LimitedValue>()?.0);
                         }
                     }
                 }
@@ -1,23 +1,30 @@
 use super::{JsonRpcParams, LooseId, SingleRequest};
 use crate::{
+    access_log,
     app::App,
-    block_number::CacheMode,
+    block_number::{rewrite_pending_to_latest, uses_pending_block, CacheMode},
+    call_cache::{call_cache_target, CallCacheTarget},
+    compute_units::ComputeUnit,
+    config::PendingBlockPolicy,
     errors::{Web3ProxyError, Web3ProxyResult},
     frontend::{
         authorization::{key_is_authorized, Authorization, RequestOrMethod, ResponseOrBytes},
         rpc_proxy_ws::ProxyMode,
     },
-    globals::APP,
+    get_logs::Web3ProxyOptions,
+    globals::global_app,
     response_cache::JsonRpcQueryCacheKey,
     rpcs::{blockchain::BlockHeader, one::Web3Rpc},
     secrets::RpcSecretKey,
     stats::AppStat,
+    vendor_methods::VendorMethodConfig,
 };
 use anyhow::Context;
 use axum::headers::{Origin, Referer, UserAgent};
 use chrono::Utc;
 use derivative::Derivative;
 use ethers::types::U64;
+use http::StatusCode;
 use parking_lot::Mutex;
 use rust_decimal::Decimal;
 use serde::{ser::SerializeStruct, Serialize};
@@ -32,7 +39,7 @@ use tokio::{
     sync::{mpsc, OwnedSemaphorePermit},
     time::Instant,
 };
-use tracing::{error, trace};
+use tracing::{debug, error, trace};
 
 #[cfg(feature = "rdkafka")]
 use {
@@ -41,6 +48,9 @@ use {
     ulid::Ulid,
 };
 
+/// how long premium users wait for a response. this is the longest of the default timeouts
+pub const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(295);
+
 #[derive(Derivative)]
 #[derivative(Default)]
 pub struct RequestBuilder {
@@ -87,8 +97,10 @@ impl RequestBuilder {
             .context("app is required for public requests")?;
 
         // TODO: we can check authorization now, but the semaphore needs to wait!
-        let authorization =
-            key_is_authorized(app, rpc_key, ip, origin, proxy_mode, referer, user_agent).await?;
+        let authorization = key_is_authorized(
+            app, rpc_key, ip, origin, proxy_mode, referer, user_agent, true,
+        )
+        .await?;
 
         Ok(Self {
             authorization: Some(Arc::new(authorization)),
@@ -190,14 +202,67 @@ impl RequestBuilder {
     }
 }
 
+/// Why a request failed. Requests that failed because of the proxy or its backends are not charged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    #[default]
+    None,
+    /// the request was invalid or a backend returned a jsonrpc error for it (like a revert)
+    User,
+    /// no backend was able to answer
+    Backend,
+    /// the proxy itself failed
+    Proxy,
+}
+
+impl ErrorClass {
+    /// classify an error that is about to be sent to the user with `status`
+    pub fn new(err: &Web3ProxyError, status: StatusCode) -> Self {
+        match err {
+            // these happen while talking to a backend. jsonrpc errors inside of them are sent with a 200
+            Web3ProxyError::EthersHttpClient(_)
+            | Web3ProxyError::EthersProvider(_)
+            | Web3ProxyError::EthersWsClient(_)
+            | Web3ProxyError::Reqwest(_)
+                if !status.is_success() =>
+            {
+                Self::Backend
+            }
+            Web3ProxyError::Timeout(_) => Self::Backend,
+            _ => Self::from_status(status),
+        }
+    }
+
+    /// classify an error by the status code that the user will receive
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            // jsonrpc errors are sent with a 200
+            x if x.is_success() || x.is_client_error() => Self::User,
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => Self::Backend,
+            _ => Self::Proxy,
+        }
+    }
+
+    /// true if this was a fault of the proxy or its backends
+    pub fn is_app_error(self) -> bool {
+        matches!(self, Self::Backend | Self::Proxy)
+    }
+}
+
 #[derive(Debug, Default)]
 /// todo: better name.
 /// the inside bits for ValidatedRequest. It's usually in an Arc, so it's not mutable
 pub struct ValidatedResponse {
-    /// TODO: set archive_request during the new instead of after
+    /// Set when the request needs blocks older than `archive_depth`. The RequestBuilder can also force it.
     /// TODO: this is more complex than "requires a block older than X height". different types of data can be pruned differently
     pub archive_request: bool,
 
+    /// Set for requests in a pinned batch. This rpc is tried first so the whole batch is answered by the same backend
+    pub batch_rpc: Option<String>,
+
     /// if this is empty, there was a cache_hit
     /// otherwise, it is populated with any rpc servers that were used by this request
     pub backend_rpcs: Vec<Arc<Web3Rpc>>,
@@ -210,6 +275,9 @@ pub struct ValidatedResponse {
     /// TODO: this will need more thought once we support other ProxyMode
     pub error_response: bool,
 
+    /// Why the request failed. `error_response` and `user_error_response` are kept in sync with this by `set_error_class`
+    pub error_class: ErrorClass,
+
     /// Size in bytes of the JSON response. Does not include headers or things like that.
     pub response_bytes: u64,
 
@@ -222,6 +290,9 @@ pub struct ValidatedResponse {
 
     /// If the request is invalid or received a jsonrpc error response (excluding reverts)
     pub user_error_response: bool,
+
+    /// How far apart the backends' eth_estimateGas results were, in basis points. Only set when more than one answered
+    pub estimate_gas_dispersion_bps: Option<u64>,
 }
 
 impl ValidatedResponse {
@@ -230,6 +301,12 @@ impl ValidatedResponse {
     pub fn response_from_backup_rpc(&self) -> bool {
         self.backend_rpcs.last().map(|x| x.backup).unwrap_or(false)
     }
+
+    pub fn set_error_class(&mut self, error_class: ErrorClass) {
+        self.error_class = error_class;
+        self.error_response = error_class.is_app_error();
+        self.user_error_response = error_class == ErrorClass::User;
+    }
 }
 
 /// TODO:
@@ -239,16 +316,33 @@ impl ValidatedResponse {
 pub struct ValidatedRequest {
     pub authorization: Arc<Authorization>,
 
+    /// this eth_getLogs is too large for one backend request and the client asked for it to be split into pages
+    pub auto_paginate: bool,
+
     pub cache_mode: CacheMode,
 
+    /// set for `eth_call`s that are cached by their contract's ttl instead of by block
+    pub call_cache: Option<CallCacheTarget>,
+
     /// TODO: this should probably be in a global config. although maybe if we run multiple chains in one process this will be useful
     pub chain_id: u64,
 
     pub head_block: Option<BlockHeader>,
 
+    /// set for requests with the "pending" block tag when `pending_block_policy` is "route_to_designated".
+    /// only this balanced rpc will be used
+    pub pending_rpc: Option<String>,
+
+    /// set for requests with the "pending" block tag when `pending_block_policy` is "pending_state".
+    /// the rpcs with `pending_state` are used first
+    pub pending_state: bool,
+
     /// TODO: this should be in a global config. not copied to every single request
     pub usd_per_cu: Decimal,
 
+    /// set for methods in `vendor_methods`. only the rpcs it names will be used
+    pub vendor_method: Option<VendorMethodConfig>,
+
     pub response: Mutex<ValidatedResponse>,
 
     pub inner: RequestOrMethod,
@@ -350,7 +444,11 @@ impl ValidatedRequest {
     ) -> Web3ProxyResult<Arc<Self>> {
         let start_instant = Instant::now();
 
-        let stat_sender = app.and_then(|x| x.stat_sender.clone());
+        let stat_sender = if authorization.checks.skip_stats {
+            None
+        } else {
+            app.and_then(|x| x.stat_sender.clone())
+        };
 
         let started_active_premium = authorization.active_premium().await;
 
@@ -366,10 +464,66 @@ impl ValidatedRequest {
         #[cfg(not(feature = "rdkafka"))]
         let kafka_debug_logger = None;
 
+        // our options are not part of the filter. remove them before they are used for the cache key or sent to a backend
+        let wants_auto_paginate = match &mut request {
+            RequestOrMethod::Request(x) if x.method == "eth_getLogs" => {
+                Web3ProxyOptions::take(&mut x.params)?.auto_paginate
+            }
+            _ => false,
+        };
+
+        // backends disagree about what "pending" means. this has to happen before the cache mode replaces "latest"
+        let mut pending_rpc = None;
+        let mut pending_state = false;
+        let mut pending_uncacheable = false;
+
+        if let (Some(app), RequestOrMethod::Request(x)) = (app, &mut request) {
+            if uses_pending_block(x) {
+                match app.config.pending_block_policy {
+                    PendingBlockPolicy::PendingState => {
+                        pending_uncacheable = true;
+
+                        if app.balanced_rpcs.has_pending_state() {
+                            pending_state = true;
+                        } else {
+                            debug!(method=%x.method, "no rpcs have pending_state. downgrading pending to latest");
+                            rewrite_pending_to_latest(x);
+                        }
+                    }
+                    PendingBlockPolicy::RewriteToLatest => {
+                        rewrite_pending_to_latest(x);
+                    }
+                    PendingBlockPolicy::RouteToDesignated => {
+                        pending_rpc = app.config.pending_block_rpc.clone();
+                        pending_uncacheable = true;
+                    }
+                    PendingBlockPolicy::ForwardAsIs => {
+                        pending_uncacheable = true;
+                    }
+                }
+            }
+        }
+
+        let vendor_method =
+            app.and_then(|x| x.config.vendor_methods.get(request.method()).cloned());
+
+        // this needs the user's block param, so it has to be checked before the cache mode replaces "latest"
+        let call_cache = match (app, &request) {
+            (Some(app), RequestOrMethod::Request(x))
+                if x.method == "eth_call" && head_block.is_some() =>
+            {
+                call_cache_target(&x.params, &app.config.call_cache)
+            }
+            _ => None,
+        };
+
         // now that kafka has logged the user's original params, we can calculate the cache key
         // calculating the CacheMode might alter the params
-        let cache_mode = if head_block.is_none() {
+        let cache_mode = if head_block.is_none() || pending_uncacheable {
             CacheMode::Never
+        } else if let Some(x) = vendor_method.as_ref() {
+            // we don't know where a vendor method's block param is (if it has one)
+            x.cache_mode(head_block.as_ref())
         } else {
             // TODO: modify CacheMode::new to wait for a future block if one is requested! be sure to update head_block too!
             match &mut request {
@@ -378,34 +532,111 @@ impl ValidatedRequest {
             }
         };
 
+        // validate after the cache mode is known so that the block numbers have already been normalized
+        let mut auto_paginate = false;
+
+        if let (
+            Some(app),
+            RequestOrMethod::Request(x),
+            CacheMode::Range {
+                from_block,
+                to_block,
+                ..
+            },
+            Some(head_block),
+        ) = (app, &request, &cache_mode, head_block.as_ref())
+        {
+            if x.method == "eth_getLogs" {
+                match app.get_logs_limits.load().validate(
+                    x.params.get(0).unwrap_or(&serde_json::Value::Null),
+                    from_block,
+                    to_block,
+                    head_block.number(),
+                    started_active_premium,
+                ) {
+                    Ok(()) => {}
+                    Err(
+                        Web3ProxyError::RangeTooLarge { .. }
+                        | Web3ProxyError::UnfilteredRangeTooLarge { .. },
+                    ) if wants_auto_paginate => {
+                        auto_paginate = true;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        // blocks older than the archive depth are pruned from most nodes. these requests cost more
+        let oldest_block = cache_mode.from_block().or_else(|| cache_mode.to_block());
+
+        let archive_request = match (app, oldest_block, head_block.as_ref()) {
+            (Some(app), Some(oldest_block), Some(head_block)) => {
+                oldest_block
+                    .num()
+                    .saturating_add(U64::from(app.config.archive_depth))
+                    < head_block.number()
+            }
+            _ => false,
+        };
+
+        // simulations can replay many blocks of calls. only send them to servers that have all the data
+        let archive_request = archive_request || request.method() == "eth_simulateV1";
+
+        // the key's owner can keep it away from some methods
+        authorization.checks.methods.check(request.method())?;
+
+        // some tiers are not allowed to use archive and trace servers at all
+        if let Err(err) = authorization.checks.entitlements.check(
+            request.method(),
+            archive_request,
+            authorization.checks.user_tier_title.as_deref(),
+        ) {
+            if let Some(app) = app {
+                app.recent_requests.record_denied_by_tier(request.method());
+            }
+
+            return Err(err);
+        }
+
         // TODO: what should we do if we want a really short max_wait?
-        let connect_timeout = Duration::from_secs(10);
+        let mut connect_timeout = Duration::from_secs(10);
 
         let expire_timeout = if let Some(max_wait) = max_wait {
             max_wait
+        } else if let Some(x) = vendor_method.as_ref().and_then(|x| x.timeout()) {
+            connect_timeout = connect_timeout.min(x);
+            x
         } else if authorization.active_premium().await {
-            Duration::from_secs(295)
+            MAX_REQUEST_TIMEOUT
         } else {
             Duration::from_secs(60)
         }
         .max(connect_timeout);
 
         let x = Self {
-            response: Mutex::new(Default::default()),
+            response: Mutex::new(ValidatedResponse {
+                archive_request,
+                ..Default::default()
+            }),
             authorization,
+            auto_paginate,
             cache_mode,
+            call_cache,
             chain_id,
             connect_timeout,
             expire_timeout,
             head_block: head_block.clone(),
             kafka_debug_logger,
             inner: request,
+            pending_rpc,
+            pending_state,
             permit,
             start_instant,
             started_active_premium,
             stat_sender,
             usd_per_cu,
             request_id,
+            vendor_method,
         };
 
         Ok(Arc::new(x))
@@ -455,6 +686,7 @@ impl ValidatedRequest {
     }
 
     pub async fn new_internal<P: JsonRpcParams>(
+        chain_id: u64,
         method: Cow<'static, str>,
         params: &P,
         head_block: Option<BlockHeader>,
@@ -462,15 +694,15 @@ impl ValidatedRequest {
     ) -> Web3ProxyResult<Arc<Self>> {
         let authorization = Arc::new(Authorization::internal().unwrap());
 
-        // todo!(we need a real id! increment a counter on the app or websocket-only providers are going to have a problem)
+        // TODO: a real id? websocket providers assign their own ids, so this is only seen by http and ipc
         let id = LooseId::Number(1);
 
         // TODO: this seems inefficient
         let request = SingleRequest::new(id, method, json!(params)).unwrap();
 
-        if let Some(app) = APP.get() {
+        if let Some(app) = global_app(chain_id) {
             Self::new_with_app(
-                app,
+                &app,
                 authorization,
                 max_wait,
                 None,
@@ -483,7 +715,7 @@ impl ValidatedRequest {
             Self::new_with_options(
                 None,
                 authorization,
-                0,
+                chain_id,
                 head_block,
                 #[cfg(feature = "rdkafka")]
                 None,
@@ -515,6 +747,25 @@ impl ValidatedRequest {
         }
     }
 
+    /// true if the response depends on the consensus head. "latest" becomes the head's number before this is checked
+    pub fn uses_head_block(&self) -> bool {
+        match (self.cache_mode.cache_block(), self.head_block.as_ref()) {
+            (Some(cache_block), Some(head_block)) => cache_block.num() >= head_block.number(),
+            _ => false,
+        }
+    }
+
+    /// true if every block the response depends on is at least `confirmations` behind the head.
+    /// "latest" is cached under the head, so it is never past the horizon
+    pub fn past_reorg_horizon(&self, confirmations: u64) -> bool {
+        match (self.cache_mode.cache_block(), self.head_block.as_ref()) {
+            (Some(cache_block), Some(head_block)) => {
+                cache_block.num().saturating_add(U64::from(confirmations)) <= head_block.number()
+            }
+            _ => false,
+        }
+    }
+
     #[inline]
     pub fn cache_jsonrpc_errors(&self) -> bool {
         self.cache_mode.cache_jsonrpc_errors()
@@ -594,13 +845,14 @@ impl ValidatedRequest {
         Ok(())
     }
 
-    pub fn set_error_response(&self, _err: &Web3ProxyError) {
-        {
-            let mut response_lock = self.response.lock();
+    pub fn set_error_response(&self, err: &Web3ProxyError) {
+        // this is only used for errors from backend requests
+        let error_class = match err {
+            Web3ProxyError::JsonRpcErrorData(_) => ErrorClass::User,
+            _ => ErrorClass::Backend,
+        };
 
-            response_lock.error_response = true;
-            response_lock.user_error_response = false;
-        }
+        self.response.lock().set_error_class(error_class);
 
         // TODO: add the actual response size
         self.set_response(0);
@@ -643,6 +895,28 @@ impl ValidatedRequest {
         }
     }
 
+    /// The price of this request before any discounts. Vendor methods can have their own price
+    pub fn compute_unit(&self, response_bytes: u64) -> ComputeUnit {
+        ComputeUnit::for_request(
+            self.inner.method(),
+            self.chain_id,
+            self.vendor_method.as_ref().and_then(|x| x.compute_units),
+            response_bytes,
+        )
+    }
+
+    /// What this request is charged, as it stands now. The accounting stat uses the same prices
+    pub fn compute_unit_cost(&self) -> Decimal {
+        let response_lock = self.response.lock();
+
+        self.compute_unit(response_lock.response_bytes).cost(
+            response_lock.archive_request,
+            response_lock.backend_rpcs.is_empty(),
+            response_lock.error_class,
+            &self.usd_per_cu,
+        )
+    }
+
     pub fn try_send_arc_stat(self: Arc<Self>) -> Web3ProxyResult<()> {
         match Arc::into_inner(self) {
             Some(x) => x.try_send_stat(),
@@ -669,6 +943,9 @@ impl Drop for ValidatedRequest {
 
             // trace!(?x, "request metadata dropped without stat send");
             let _ = x.try_send_stat();
+        } else {
+            // the stat (if any) has been sent and this is the last copy. log it once here
+            access_log::record(self);
         }
     }
 }
@@ -1,19 +1,29 @@
+use super::depth::check_json_depth;
 use super::JsonRpcErrorData;
+use crate::app::App;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::globals::global_app;
 use crate::jsonrpc::ValidatedRequest;
+use crate::metrics::TooLarge;
+use crate::response_budget::read_body;
 use crate::response_cache::ForwardedResponse;
 use axum::body::StreamBody;
 use axum::response::IntoResponse;
 use axum::Json;
 use bytes::{Bytes, BytesMut};
+use futures_util::future;
 use futures_util::stream::{self, StreamExt};
 use futures_util::TryStreamExt;
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
 use serde::{de, Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::fmt;
+use std::io;
 use std::marker::PhantomData;
 use std::sync::Arc;
+use tracing::warn;
 
 pub trait JsonRpcParams = fmt::Debug + serde::Serialize + Send + Sync + 'static;
 pub trait JsonRpcResultData = serde::Serialize + serde::de::DeserializeOwned + fmt::Debug + Send;
@@ -39,6 +49,17 @@ impl ParsedResponse {
 }
 
 impl ParsedResponse<Arc<RawValue>> {
+    /// About how many bytes this serializes to. Cheap because the result is already json.
+    pub fn approx_num_bytes(&self) -> usize {
+        let payload = match &self.payload {
+            ResponsePayload::Success { result } => result.get().len(),
+            // errors are small
+            ResponsePayload::Error { .. } => 0,
+        };
+
+        payload + self.id.get().len()
+    }
+
     #[inline]
     pub fn from_response_data(data: ForwardedResponse<Arc<RawValue>>, id: Box<RawValue>) -> Self {
         match data {
@@ -82,6 +103,14 @@ impl<T> ParsedResponse<T> {
             ResponsePayload::Error { error } => Err(Web3ProxyError::JsonRpcErrorData(error)),
         }
     }
+
+    /// the `retry_after` seconds from an error's data. used for the Retry-After header
+    pub fn retry_after(&self) -> Option<u64> {
+        match &self.payload {
+            ResponsePayload::Success { .. } => None,
+            ResponsePayload::Error { error } => error.data.as_ref()?.get("retry_after")?.as_u64(),
+        }
+    }
 }
 
 impl<'de, T> Deserialize<'de> for ParsedResponse<T>
@@ -207,19 +236,33 @@ pub enum ResponsePayload<T> {
 pub struct StreamResponse<T> {
     _t: PhantomData<T>,
     buffer: Bytes,
+    /// the body is cut off past this many bytes
+    max_bytes: u64,
     num_bytes: Option<u64>,
     response: reqwest::Response,
     web3_request: Arc<ValidatedRequest>,
 }
 
 impl<T> StreamResponse<T> {
+    /// Read the whole body into memory. This counts against the app's `response_budget` until it is parsed.
     pub async fn read(self) -> Web3ProxyResult<ParsedResponse<T>>
     where
         T: de::DeserializeOwned,
     {
-        let mut buffer = BytesMut::with_capacity(self.buffer.len());
-        buffer.extend(self.buffer);
-        buffer.extend(self.response.bytes().await?);
+        let app = global_app(self.web3_request.chain_id);
+
+        let budget = app.as_ref().map(|x| &x.response_budget);
+
+        let (buffer, _permit) = read_body(
+            budget,
+            self.buffer,
+            self.num_bytes,
+            self.response,
+            self.max_bytes,
+        )
+        .await?;
+
+        check_json_depth::<serde_json::Error>(&buffer)?;
         let parsed = serde_json::from_slice(&buffer)?;
         Ok(parsed)
     }
@@ -227,14 +270,41 @@ impl<T> StreamResponse<T> {
 
 impl<T> IntoResponse for StreamResponse<T> {
     fn into_response(self) -> axum::response::Response {
+        let max_bytes = self.max_bytes;
+        let mut sent = 0u64;
+
+        // the status and headers are already sent. past the limit, all we can do is cut the body off
         let stream = stream::once(async { Ok::<_, reqwest::Error>(self.buffer) })
             .chain(self.response.bytes_stream())
-            .map_ok(move |x| {
+            .map_err(io::Error::other)
+            .and_then(move |x| {
                 let len = x.len() as u64;
 
-                self.web3_request.set_response(len);
+                sent += len;
+
+                let x = if sent > max_bytes {
+                    warn!(
+                        request=%self.web3_request,
+                        sent,
+                        max_bytes,
+                        "ResponseTooLarge. cutting off the stream"
+                    );
+
+                    if let Some(app) = global_app(self.web3_request.chain_id) {
+                        app.request_metrics.record_too_large(TooLarge::Response);
+                    }
 
-                x
+                    Err(io::Error::other(format!(
+                        "response is larger than {} bytes",
+                        max_bytes
+                    )))
+                } else {
+                    self.web3_request.set_response(len);
+
+                    Ok(x)
+                };
+
+                future::ready(x)
             });
         let body = StreamBody::new(stream);
         body.into_response()
@@ -264,18 +334,27 @@ where
     // TODO: threshold from configs
     // TODO: error handling
     // TODO: if a large stream's response's initial chunk "error" then we should buffer it
+    /// Responses bigger than `max_bytes` are an error. Without a Content-Length, that might not be known until the
+    /// response is streamed to the client
     pub async fn read_if_short(
         mut response: reqwest::Response,
         nbytes: u64,
+        max_bytes: u64,
         web3_request: &Arc<ValidatedRequest>,
     ) -> Web3ProxyResult<SingleResponse<T>> {
         match response.content_length() {
+            // too long. don't read any of it
+            Some(len) if len > max_bytes => Err(Web3ProxyError::ResponseTooLarge {
+                bytes: len,
+                max: max_bytes,
+            }),
             // short
             Some(len) if len <= nbytes => Ok(Self::from_bytes(response.bytes().await?)?),
             // long
             Some(len) => Ok(Self::Stream(StreamResponse {
                 _t: PhantomData::<T>,
                 buffer: Bytes::new(),
+                max_bytes,
                 num_bytes: Some(len),
                 response,
                 web3_request: web3_request.clone(),
@@ -288,6 +367,13 @@ where
                     match response.chunk().await? {
                         Some(chunk) => {
                             buffer.extend(chunk);
+
+                            if buffer.len() as u64 > max_bytes {
+                                return Err(Web3ProxyError::ResponseTooLarge {
+                                    bytes: buffer.len() as u64,
+                                    max: max_bytes,
+                                });
+                            }
                         }
                         None => {
                             // it was short
@@ -301,6 +387,7 @@ where
                 Ok(Self::Stream(StreamResponse {
                     _t: PhantomData::<T>,
                     buffer,
+                    max_bytes,
                     num_bytes: None,
                     response,
                     web3_request: web3_request.clone(),
@@ -310,6 +397,8 @@ where
     }
 
     fn from_bytes(buf: Bytes) -> Result<Self, serde_json::Error> {
+        // a backend could be compromised. don't trust it to nest sanely either
+        check_json_depth::<serde_json::Error>(&buf)?;
         let val = serde_json::from_slice(&buf)?;
         Ok(Self::Parsed(val))
     }
@@ -367,22 +456,91 @@ pub enum Response<T = Arc<RawValue>> {
     Batch(Vec<ParsedResponse<T>>),
 }
 
+/// Responses larger than this are serialized on a blocking thread instead of on the request's tokio worker.
+fn serialize_blocking_bytes(app: &App) -> usize {
+    app.config.response_serialize_blocking_bytes as usize
+}
+
 impl Response<Arc<RawValue>> {
-    pub async fn to_json_string(self) -> Web3ProxyResult<String> {
+    /// About how many bytes this serializes to. Streams are not counted since they are never serialized.
+    pub fn approx_num_bytes(&self) -> usize {
+        match self {
+            Self::Single(SingleResponse::Parsed(x)) => x.approx_num_bytes(),
+            Self::Single(SingleResponse::Stream(..)) => 0,
+            Self::Batch(x) => x.iter().map(|x| x.approx_num_bytes()).sum(),
+        }
+    }
+
+    pub async fn to_json_string(self, app: &App) -> Web3ProxyResult<String> {
+        self.to_json_string_with_threshold(serialize_blocking_bytes(app))
+            .await
+    }
+
+    async fn to_json_string_with_threshold(self, threshold: usize) -> Web3ProxyResult<String> {
         let x = match self {
             Self::Single(resp) => {
                 // TODO: handle streaming differently?
-                let parsed = resp.parsed().await?;
-
-                serde_json::to_string(&parsed)
+                Self::Single(SingleResponse::Parsed(resp.parsed().await?))
             }
-            Self::Batch(resps) => serde_json::to_string(&resps),
+            x => x,
         };
 
-        let x = x.expect("to_string should always work");
+        let x = if x.approx_num_bytes() > threshold {
+            tokio::task::spawn_blocking(move || x.serialize_to_string()).await?
+        } else {
+            x.serialize_to_string()
+        };
 
         Ok(x)
     }
+
+    fn serialize_to_string(&self) -> String {
+        let x = match self {
+            Self::Single(SingleResponse::Parsed(resp)) => serde_json::to_string(resp),
+            Self::Single(SingleResponse::Stream(..)) => unreachable!("streams are read first"),
+            Self::Batch(resps) => serde_json::to_string(resps),
+        };
+
+        x.expect("to_string should always work")
+    }
+
+    /// Like `into_response`, but multi-megabyte bodies don't pin the worker on serde for tens of milliseconds.
+    /// Streams are passed through as-is.
+    pub async fn into_http_response(
+        self,
+        status_code: StatusCode,
+        app: &App,
+    ) -> Web3ProxyResult<axum::response::Response> {
+        self.into_http_response_with_threshold(status_code, serialize_blocking_bytes(app))
+            .await
+    }
+
+    async fn into_http_response_with_threshold(
+        self,
+        status_code: StatusCode,
+        threshold: usize,
+    ) -> Web3ProxyResult<axum::response::Response> {
+        if matches!(self, Self::Single(SingleResponse::Stream(..)))
+            || self.approx_num_bytes() <= threshold
+        {
+            return Ok((status_code, self).into_response());
+        }
+
+        let body = tokio::task::spawn_blocking(move || self.serialize_to_string()).await?;
+
+        Ok((status_code, [(CONTENT_TYPE, "application/json")], body).into_response())
+    }
+}
+
+impl<T> Response<T> {
+    /// the longest `retry_after` of any error in this response
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::Single(SingleResponse::Parsed(x)) => x.retry_after(),
+            Self::Single(SingleResponse::Stream(..)) => None,
+            Self::Batch(x) => x.iter().filter_map(|x| x.retry_after()).max(),
+        }
+    }
 }
 
 impl<T> From<ParsedResponse<T>> for Response<T> {
@@ -402,3 +560,102 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn huge_response() -> Response {
+        // about the size of a big eth_getLogs response
+        let log = serde_json::json!({
+            "address": "0x4200000000000000000000000000000000000006",
+            "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+            "data": "0x0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+            "blockNumber": "0x10",
+            "transactionHash": "0x5e1ec7ed5e1ec7ed5e1ec7ed5e1ec7ed5e1ec7ed5e1ec7ed5e1ec7ed5e1ec7ed",
+        });
+        let logs = serde_json::Value::Array(vec![log; 100_000]);
+
+        ParsedResponse::from_value(logs, Default::default()).into()
+    }
+
+    fn small_response() -> Response {
+        ParsedResponse::from_value(serde_json::json!("0x10"), Default::default()).into()
+    }
+
+    #[tokio::test]
+    async fn offloaded_body_matches() {
+        let expected = huge_response()
+            .to_json_string_with_threshold(usize::MAX)
+            .await
+            .unwrap();
+
+        let response = huge_response()
+            .into_http_response_with_threshold(StatusCode::OK, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body, expected.as_bytes());
+    }
+
+    /// p99 of small responses while huge responses are going out on the same workers
+    async fn small_p99(threshold: usize) -> Duration {
+        let huge = tokio::spawn(async move {
+            let mut handles = vec![];
+            for _ in 0..32 {
+                handles.push(tokio::spawn(async move {
+                    huge_response()
+                        .into_http_response_with_threshold(StatusCode::OK, threshold)
+                        .await
+                        .unwrap()
+                }));
+            }
+            for x in handles {
+                x.await.unwrap();
+            }
+        });
+
+        let mut latencies = vec![];
+        while !huge.is_finished() {
+            let start = Instant::now();
+
+            tokio::spawn(async move {
+                small_response()
+                    .into_http_response_with_threshold(StatusCode::OK, threshold)
+                    .await
+                    .unwrap()
+            })
+            .await
+            .unwrap();
+
+            latencies.push(start.elapsed());
+
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        latencies.sort();
+
+        latencies[latencies.len() * 99 / 100]
+    }
+
+    /// Benchmark. `cargo test -p web3_proxy --release -- --ignored --nocapture small_requests_p99`
+    #[ignore]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn small_requests_p99() {
+        let inline = small_p99(usize::MAX).await;
+        let offloaded = small_p99(1_048_576).await;
+
+        println!(
+            "small request p99: inline={:?} offloaded={:?}",
+            inline, offloaded
+        );
+
+        assert!(offloaded <= inline);
+    }
+}
@@ -59,7 +59,7 @@ impl KafkaDebugLogger {
         // TODO: would be nice to have the block hash too
 
         // another item is added with the response, so initial_capacity is +1 what is needed here
-        let kafka_headers = KafkaOwnedHeaders::new_with_capacity(6)
+        let kafka_headers = KafkaOwnedHeaders::new_with_capacity(8)
             .insert(KafkaHeader {
                 key: "rpc_secret_key_id",
                 value: authorization
@@ -68,6 +68,14 @@ impl KafkaDebugLogger {
                     .map(|x| x.to_string())
                     .as_ref(),
             })
+            .insert(KafkaHeader {
+                key: "rpc_key_label",
+                value: authorization.checks.label.as_ref(),
+            })
+            .insert(KafkaHeader {
+                key: "rpc_key_internal_tags",
+                value: authorization.checks.internal_tags.as_ref(),
+            })
             .insert(KafkaHeader {
                 key: "ip",
                 value: Some(&authorization.ip.to_string()),
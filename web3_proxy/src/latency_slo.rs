@@ -0,0 +1,699 @@
+//! Latency objectives per method class and user tier.
+//!
+//! We promise customers a p95 for cached reads and another for uncached reads. Every request's latency goes into a small
+//! sketch for its class and tier. There is one sketch per one-minute bucket, and the last few buckets are merged to check
+//! the objectives once a minute. A breach is logged, counted for prometheus, and optionally POSTed to a webhook along
+//! with the backends that were slowest for that class.
+//!
+//! The sketches are DDSketches. Quantiles are within 1% of the real value, and merging two sketches is adding counts.
+//! Everything here is in memory and only covers this server.
+
+use crate::config::AppConfig;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, trace, warn};
+
+pub use web3_proxy_client::types::{LatencyClass, LatencyRow, LatencySummary};
+
+pub const BUCKET_SECS: u64 = 60;
+pub const NUM_BUCKETS: usize = 5;
+
+/// a bucket stops tracking new tiers or backends after this many
+pub const MAX_KEYS_PER_BUCKET: usize = 200;
+
+pub const NUM_TOP_BACKENDS: usize = 3;
+
+/// quantiles from the sketch are within this fraction of the real value
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// anything faster than a microsecond is counted as 0
+const MIN_MS: f64 = 0.001;
+
+/// tier name for requests without a user tier
+pub const ANONYMOUS_TIER: &str = "anonymous";
+
+/// A mergeable quantile sketch (DDSketch) of latencies in milliseconds
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencySketch {
+    bins: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+fn bin_index(ms: f64) -> i32 {
+    (ms.ln() / gamma().ln()).ceil() as i32
+}
+
+impl LatencySketch {
+    pub fn add(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+
+        if ms <= MIN_MS {
+            self.zeros += 1;
+        } else {
+            *self.bins.entry(bin_index(ms)).or_default() += 1;
+        }
+
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (i, count) in other.bins.iter() {
+            *self.bins.entry(*i).or_default() += count;
+        }
+
+        self.zeros += other.zeros;
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// `q` is between 0 and 1. None if the sketch is empty
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+
+        if rank < self.zeros {
+            return Some(Duration::ZERO);
+        }
+
+        let mut seen = self.zeros;
+        for (i, count) in self.bins.iter() {
+            seen += count;
+
+            if seen > rank {
+                let gamma = gamma();
+
+                let ms = 2.0 * gamma.powi(*i) / (gamma + 1.0);
+
+                return Some(Duration::from_secs_f64(ms / 1000.0));
+            }
+        }
+
+        None
+    }
+
+    /// roughly how many latencies were slower than `threshold`
+    pub fn count_above(&self, threshold: Duration) -> u64 {
+        let ms = threshold.as_secs_f64() * 1000.0;
+
+        if ms <= MIN_MS {
+            return self.count - self.zeros;
+        }
+
+        let threshold_index = bin_index(ms);
+
+        self.bins
+            .range(threshold_index + 1..)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// unix time divided by BUCKET_SECS
+    id: u64,
+    tiers: HashMap<(LatencyClass, String), LatencySketch>,
+    backends: HashMap<(LatencyClass, String), LatencySketch>,
+}
+
+/// merged buckets
+#[derive(Debug, Default)]
+struct Window {
+    tiers: BTreeMap<(LatencyClass, String), LatencySketch>,
+    backends: HashMap<(LatencyClass, String), LatencySketch>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BackendLatency {
+    pub name: String,
+    pub latency_ms: u64,
+    /// requests slower than the objective
+    pub slow_requests: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SloEventKind {
+    Breach,
+    Recovered,
+}
+
+/// what gets logged and sent to the webhook
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SloEvent {
+    pub kind: SloEventKind,
+    pub class: LatencyClass,
+    pub tier: String,
+    pub percentile: u8,
+    pub latency_ms: u64,
+    pub objective_ms: u64,
+    pub requests: u64,
+    pub window_secs: u64,
+    /// slowest first. empty for cached reads
+    pub top_backends: Vec<BackendLatency>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+fn as_ms(x: Duration) -> u64 {
+    x.as_millis() as u64
+}
+
+pub struct LatencySlo {
+    buckets: Mutex<[Bucket; NUM_BUCKETS]>,
+    cached: Option<Duration>,
+    uncached: Option<Duration>,
+    percentile: u8,
+    min_requests: u64,
+    webhook: Option<(reqwest::Client, String)>,
+    /// classes and tiers that were over their objective the last time they were checked
+    breached: Mutex<HashSet<(LatencyClass, String)>>,
+    /// total breach events. recoveries are not counted
+    breaches: AtomicU64,
+}
+
+impl LatencySlo {
+    pub fn new(config: &AppConfig, http_client: Option<reqwest::Client>) -> Self {
+        let objective = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+
+        let webhook = config
+            .latency_slo_webhook_url
+            .clone()
+            .map(|url| (http_client.unwrap_or_default(), url));
+
+        Self {
+            buckets: Default::default(),
+            cached: objective(config.latency_slo_cached_ms),
+            uncached: objective(config.latency_slo_uncached_ms),
+            percentile: config.latency_slo_percentile.clamp(1, 100),
+            min_requests: config.latency_slo_min_requests,
+            webhook,
+            breached: Default::default(),
+            breaches: AtomicU64::new(0),
+        }
+    }
+
+    /// Also starts checking the objectives once a minute if any are set
+    pub fn spawn(config: &AppConfig, http_client: Option<reqwest::Client>) -> Arc<Self> {
+        let x = Arc::new(Self::new(config, http_client));
+
+        if x.cached.is_some() || x.uncached.is_some() {
+            tokio::spawn(x.clone().evaluate_loop());
+        }
+
+        x
+    }
+
+    pub fn objective(&self, class: LatencyClass) -> Option<Duration> {
+        match class {
+            LatencyClass::CachedRead => self.cached,
+            LatencyClass::UncachedRead => self.uncached,
+            LatencyClass::Write => None,
+        }
+    }
+
+    pub fn record(
+        &self,
+        method: &str,
+        cache_hit: bool,
+        tier: Option<&str>,
+        backends: &[&str],
+        latency: Duration,
+    ) {
+        let class = LatencyClass::new(method, cache_hit);
+
+        self.record_at(now_secs(), class, tier, backends, latency)
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        self.summary_at(now_secs())
+    }
+
+    fn record_at(
+        &self,
+        now: u64,
+        class: LatencyClass,
+        tier: Option<&str>,
+        backends: &[&str],
+        latency: Duration,
+    ) {
+        let id = now / BUCKET_SECS;
+
+        let tier = tier.unwrap_or(ANONYMOUS_TIER);
+
+        let mut buckets = self.buckets.lock();
+
+        let bucket = &mut buckets[id as usize % NUM_BUCKETS];
+
+        if bucket.id != id {
+            // this slot is from an older window. reuse it
+            *bucket = Bucket {
+                id,
+                ..Default::default()
+            };
+        }
+
+        let key = (class, tier.to_string());
+        if let Some(x) = bucket.tiers.get_mut(&key) {
+            x.add(latency);
+        } else if bucket.tiers.len() < MAX_KEYS_PER_BUCKET {
+            bucket.tiers.entry(key).or_default().add(latency);
+        }
+
+        for backend in backends {
+            let key = (class, backend.to_string());
+            if let Some(x) = bucket.backends.get_mut(&key) {
+                x.add(latency);
+            } else if bucket.backends.len() < MAX_KEYS_PER_BUCKET {
+                bucket.backends.entry(key).or_default().add(latency);
+            }
+        }
+    }
+
+    /// the merged buckets and how many seconds they cover
+    fn window_at(&self, now: u64) -> (Window, u64) {
+        let id = now / BUCKET_SECS;
+        let oldest_id = (id + 1).saturating_sub(NUM_BUCKETS as u64);
+
+        let mut window = Window::default();
+
+        let buckets = self.buckets.lock();
+
+        for bucket in buckets.iter().filter(|x| x.id >= oldest_id && x.id <= id) {
+            for (key, sketch) in bucket.tiers.iter() {
+                window.tiers.entry(key.clone()).or_default().merge(sketch);
+            }
+            for (key, sketch) in bucket.backends.iter() {
+                window
+                    .backends
+                    .entry(key.clone())
+                    .or_default()
+                    .merge(sketch);
+            }
+        }
+
+        drop(buckets);
+
+        // the current bucket is only partly done
+        let window_secs = (id - oldest_id) * BUCKET_SECS + now % BUCKET_SECS + 1;
+
+        (window, window_secs)
+    }
+
+    fn quantile(&self) -> f64 {
+        self.percentile as f64 / 100.0
+    }
+
+    fn top_backends(
+        &self,
+        window: &Window,
+        class: LatencyClass,
+        objective: Duration,
+    ) -> Vec<BackendLatency> {
+        let mut backends: Vec<_> = window
+            .backends
+            .iter()
+            .filter(|((x, _), _)| *x == class)
+            .filter_map(|((_, name), sketch)| {
+                Some(BackendLatency {
+                    name: name.clone(),
+                    latency_ms: as_ms(sketch.quantile(self.quantile())?),
+                    slow_requests: sketch.count_above(objective),
+                })
+            })
+            .collect();
+
+        // ties are sorted by name so the output is stable
+        backends.sort_unstable_by(|a, b| {
+            b.slow_requests
+                .cmp(&a.slow_requests)
+                .then(b.latency_ms.cmp(&a.latency_ms))
+                .then(a.name.cmp(&b.name))
+        });
+        backends.truncate(NUM_TOP_BACKENDS);
+
+        backends
+    }
+
+    /// Check every class and tier against its objective. Only changes are returned
+    fn evaluate_at(&self, now: u64) -> Vec<SloEvent> {
+        let (window, window_secs) = self.window_at(now);
+
+        let mut breached = self.breached.lock();
+
+        let mut events = vec![];
+
+        for ((class, tier), sketch) in window.tiers.iter() {
+            let Some(objective) = self.objective(*class) else {
+                continue;
+            };
+
+            // too few requests to say anything
+            if sketch.count() < self.min_requests.max(1) {
+                continue;
+            }
+
+            let Some(latency) = sketch.quantile(self.quantile()) else {
+                continue;
+            };
+
+            let key = (*class, tier.clone());
+
+            let kind = if latency > objective {
+                if !breached.insert(key) {
+                    continue;
+                }
+                SloEventKind::Breach
+            } else {
+                if !breached.remove(&key) {
+                    continue;
+                }
+                SloEventKind::Recovered
+            };
+
+            events.push(SloEvent {
+                kind,
+                class: *class,
+                tier: tier.clone(),
+                percentile: self.percentile,
+                latency_ms: as_ms(latency),
+                objective_ms: as_ms(objective),
+                requests: sketch.count(),
+                window_secs,
+                top_backends: self.top_backends(&window, *class, objective),
+            });
+        }
+
+        events
+    }
+
+    fn summary_at(&self, now: u64) -> LatencySummary {
+        let (window, window_secs) = self.window_at(now);
+
+        let breached = self.breached.lock();
+
+        let rows = window
+            .tiers
+            .iter()
+            .map(|((class, tier), sketch)| LatencyRow {
+                class: *class,
+                tier: tier.clone(),
+                requests: sketch.count(),
+                p50_ms: sketch.quantile(0.5).map(as_ms),
+                objective_latency_ms: sketch.quantile(self.quantile()).map(as_ms),
+                p99_ms: sketch.quantile(0.99).map(as_ms),
+                objective_ms: self.objective(*class).map(as_ms),
+                breached: breached.contains(&(*class, tier.clone())),
+            })
+            .collect();
+
+        LatencySummary {
+            window_secs,
+            percentile: self.percentile,
+            rows,
+        }
+    }
+
+    fn emit(&self, event: SloEvent) {
+        match event.kind {
+            SloEventKind::Breach => {
+                self.breaches.fetch_add(1, Ordering::Relaxed);
+
+                warn!(
+                    class = ?event.class,
+                    tier = %event.tier,
+                    percentile = event.percentile,
+                    latency_ms = event.latency_ms,
+                    objective_ms = event.objective_ms,
+                    requests = event.requests,
+                    top_backends = ?event.top_backends,
+                    "latency objective breached"
+                );
+            }
+            SloEventKind::Recovered => {
+                info!(
+                    class = ?event.class,
+                    tier = %event.tier,
+                    latency_ms = event.latency_ms,
+                    objective_ms = event.objective_ms,
+                    "latency objective recovered"
+                );
+            }
+        }
+
+        if let Some((client, url)) = self.webhook.as_ref() {
+            let request = client.post(url).json(&event);
+
+            // never slow down the checks for a webhook
+            tokio::spawn(async move {
+                match request.send().await.and_then(|x| x.error_for_status()) {
+                    Ok(_) => trace!("sent latency objective webhook"),
+                    Err(err) => warn!(?err, "unable to send latency objective webhook"),
+                }
+            });
+        }
+    }
+
+    async fn evaluate_loop(self: Arc<Self>) {
+        let mut check_interval = interval(Duration::from_secs(BUCKET_SECS));
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            check_interval.tick().await;
+
+            for event in self.evaluate_at(now_secs()) {
+                self.emit(event);
+            }
+        }
+    }
+}
+
+impl Serialize for LatencySlo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("LatencySlo", 2)?;
+
+        state.serialize_field("breaches", &self.breaches.load(Ordering::Relaxed))?;
+        state.serialize_field("breached", &self.breached.lock().len())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn slo() -> LatencySlo {
+        let config: AppConfig = serde_json::from_value(json!({
+            "chain_id": 1,
+            "latency_slo_min_requests": 10,
+        }))
+        .unwrap();
+
+        LatencySlo::new(&config, None)
+    }
+
+    #[test]
+    fn sketch_quantiles() {
+        let mut a = LatencySketch::default();
+        let mut b = LatencySketch::default();
+
+        // 1..=1000ms, split across two sketches
+        for ms in 1..=1000u64 {
+            let x = if ms % 2 == 0 { &mut a } else { &mut b };
+            x.add(Duration::from_millis(ms));
+        }
+
+        a.merge(&b);
+        assert_eq!(a.count(), 1000);
+
+        for (q, expected) in [(0.5, 500.0), (0.95, 950.0), (0.99, 990.0)] {
+            let x = a.quantile(q).unwrap().as_secs_f64() * 1000.0;
+
+            assert!(
+                (x - expected).abs() <= expected * RELATIVE_ACCURACY * 1.5,
+                "p{} = {}",
+                q,
+                x
+            );
+        }
+
+        // the bin that holds the threshold is not counted
+        let above = a.count_above(Duration::from_millis(900));
+        assert!((80..=100).contains(&above), "{}", above);
+
+        a.add(Duration::ZERO);
+        assert_eq!(a.quantile(0.0), Some(Duration::ZERO));
+
+        assert_eq!(LatencySketch::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn breach_detection() {
+        let x = slo();
+
+        assert_eq!(
+            x.objective(LatencyClass::CachedRead),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(
+            x.objective(LatencyClass::UncachedRead),
+            Some(Duration::from_millis(1_500))
+        );
+        assert_eq!(x.objective(LatencyClass::Write), None);
+
+        let start = 1_000 * BUCKET_SECS;
+
+        // cached reads are fast. one slow request isn't enough to move the p95
+        for i in 0..99 {
+            x.record_at(
+                start,
+                LatencyClass::CachedRead,
+                None,
+                &[],
+                Duration::from_millis(i),
+            );
+        }
+        x.record_at(
+            start,
+            LatencyClass::CachedRead,
+            None,
+            &[],
+            Duration::from_secs(5),
+        );
+
+        // uncached reads for one tier are just under the objective on "fast", but "slow" is over it
+        for _ in 0..90 {
+            x.record_at(
+                start,
+                LatencyClass::UncachedRead,
+                Some("Premium"),
+                &["fast"],
+                Duration::from_millis(1_400),
+            );
+        }
+        for _ in 0..10 {
+            x.record_at(
+                start,
+                LatencyClass::UncachedRead,
+                Some("Premium"),
+                &["slow"],
+                Duration::from_millis(1_600),
+            );
+        }
+
+        // writes never breach
+        for _ in 0..100 {
+            x.record_at(
+                start,
+                LatencyClass::Write,
+                None,
+                &["fast"],
+                Duration::from_secs(30),
+            );
+        }
+
+        // too few requests to check
+        for _ in 0..5 {
+            x.record_at(
+                start,
+                LatencyClass::UncachedRead,
+                None,
+                &["slow"],
+                Duration::from_secs(10),
+            );
+        }
+
+        let events = x.evaluate_at(start);
+        assert_eq!(events.len(), 1, "{:#?}", events);
+
+        let event = &events[0];
+        assert_eq!(event.kind, SloEventKind::Breach);
+        assert_eq!(event.class, LatencyClass::UncachedRead);
+        assert_eq!(event.tier, "Premium");
+        assert_eq!(event.objective_ms, 1_500);
+        assert_eq!(event.requests, 100);
+        assert!(event.latency_ms > 1_500);
+        assert_eq!(event.top_backends[0].name, "slow");
+        assert_eq!(event.top_backends[0].slow_requests, 15);
+        assert_eq!(event.top_backends[1].name, "fast");
+        assert_eq!(event.top_backends[1].slow_requests, 0);
+
+        // a breach is only reported once
+        assert!(x.evaluate_at(start).is_empty());
+
+        let summary = x.summary_at(start);
+        let premium = summary.rows.iter().find(|x| x.tier == "Premium").unwrap();
+        assert!(premium.breached);
+        assert_eq!(premium.requests, 100);
+
+        let cached = summary
+            .rows
+            .iter()
+            .find(|x| x.class == LatencyClass::CachedRead)
+            .unwrap();
+        assert_eq!(cached.tier, ANONYMOUS_TIER);
+        assert!(!cached.breached);
+        assert!(cached.objective_latency_ms.unwrap() < 300);
+
+        // once the slow minute leaves the window, the tier recovers
+        let later = start + NUM_BUCKETS as u64 * BUCKET_SECS;
+        for _ in 0..100 {
+            x.record_at(
+                later,
+                LatencyClass::UncachedRead,
+                Some("Premium"),
+                &["fast"],
+                Duration::from_millis(200),
+            );
+        }
+
+        let events = x.evaluate_at(later);
+        assert_eq!(events.len(), 1, "{:#?}", events);
+        assert_eq!(events[0].kind, SloEventKind::Recovered);
+        assert_eq!(events[0].tier, "Premium");
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(
+            LatencyClass::new("eth_call", true),
+            LatencyClass::CachedRead
+        );
+        assert_eq!(
+            LatencyClass::new("eth_call", false),
+            LatencyClass::UncachedRead
+        );
+        assert_eq!(
+            LatencyClass::new("eth_sendRawTransaction", false),
+            LatencyClass::Write
+        );
+    }
+}
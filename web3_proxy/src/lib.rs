@@ -4,30 +4,68 @@
 #![feature(result_flattening)]
 #![forbid(unsafe_code)]
 
+pub mod access_log;
 pub mod admin_queries;
 pub mod app;
+pub mod backend_scores;
 pub mod balance;
+pub mod bans;
 pub mod block_number;
+pub mod block_tags;
+pub mod cache_invalidation;
+pub mod cache_revalidation;
 pub mod caches;
+pub mod call_cache;
+pub mod canary;
 pub mod compute_units;
 pub mod config;
+pub mod config_reload;
+pub mod degraded;
+pub mod disk_cache;
+pub mod embed;
 pub mod errors;
+pub mod estimate_gas;
+pub mod exempt_traffic;
 pub mod frontend;
+pub mod get_logs;
 pub mod globals;
+pub mod head_replay;
+pub mod head_staleness;
+pub mod head_watermark;
 pub mod http_params;
+pub mod incoming_requests;
+pub mod introspection;
 pub mod jsonrpc;
+pub mod latency_slo;
+pub mod log_filter;
+pub mod memory;
+pub mod metrics;
 pub mod pagerduty;
+pub mod param_chain_id;
 pub mod prelude;
 pub mod premium;
 pub mod prometheus;
+pub mod quotas;
+pub mod rate_limit_weights;
+pub mod raw_transaction;
+pub mod recent_errors;
+pub mod recent_requests;
 pub mod referral_code;
 pub mod relational_db;
+pub mod response_budget;
 pub mod response_cache;
+pub mod response_rewrite;
+pub mod response_signing;
+pub mod retry_policy;
 pub mod rpcs;
 pub mod secrets;
+pub mod standby;
 pub mod stats;
 pub mod test_utils;
+pub mod tx_origin;
+pub mod tx_tracker;
 pub mod user_token;
+pub mod vendor_methods;
 
 #[cfg(feature = "rdkafka")]
 pub mod kafka;
@@ -0,0 +1,262 @@
+//! Change the log filter without restarting.
+//!
+//! During an incident, restarting with `RUST_LOG=web3_proxy::rpcs=trace` loses the state we want to look at. Every layer
+//! that logs gets its filter from `reloadable_filter`, and `PUT /admin/log_filter` changes all of them at once.
+//! An optional revert puts the startup filter back so that a forgotten trace filter doesn't fill the disks.
+
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter};
+
+pub use web3_proxy_client::types::{AdminLogFilterPut, LogFilterStatus};
+
+pub static LOG_FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+/// Replaces one layer's filter. The filter string has already been validated
+pub type ReloadLogFilter = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// A filter for one layer and a way to change it later. Give the reloaders to `LogFilter::install`
+pub fn reloadable_filter<S>(
+    filter: &str,
+) -> anyhow::Result<(reload::Layer<EnvFilter, S>, ReloadLogFilter)>
+where
+    S: 'static,
+{
+    let (layer, handle) = reload::Layer::new(EnvFilter::builder().parse(filter)?);
+
+    let reload: ReloadLogFilter = Box::new(move |x| {
+        let filter = EnvFilter::builder().parse(x).map_err(|x| x.to_string())?;
+
+        handle.reload(filter).map_err(|x| x.to_string())
+    });
+
+    Ok((layer, reload))
+}
+
+#[derive(Debug)]
+struct Current {
+    filter: String,
+    revert_at: Option<DateTime<Utc>>,
+    /// so that an old revert doesn't undo a newer change
+    generation: u64,
+}
+
+pub struct LogFilter {
+    default_filter: String,
+    current: Mutex<Current>,
+    reloaders: Vec<ReloadLogFilter>,
+}
+
+impl LogFilter {
+    pub fn new(default_filter: String, reloaders: Vec<ReloadLogFilter>) -> Self {
+        Self {
+            current: Mutex::new(Current {
+                filter: default_filter.clone(),
+                revert_at: None,
+                generation: 0,
+            }),
+            default_filter,
+            reloaders,
+        }
+    }
+
+    /// Make the filter adjustable through the admin api. Only the first call does anything
+    pub fn install(default_filter: String, reloaders: Vec<ReloadLogFilter>) {
+        if LOG_FILTER
+            .set(Self::new(default_filter, reloaders))
+            .is_err()
+        {
+            warn!("log filter already installed");
+        }
+    }
+
+    pub fn global() -> Web3ProxyResult<&'static Self> {
+        LOG_FILTER.get().ok_or_else(|| {
+            Web3ProxyError::StatusCode(
+                http::StatusCode::NOT_IMPLEMENTED,
+                "the log filter can't be changed in this process".into(),
+                None,
+            )
+        })
+    }
+
+    /// errors if the filter can't be parsed. nothing is changed then
+    pub fn validate(filter: &str) -> Web3ProxyResult<()> {
+        EnvFilter::builder().parse(filter).map_err(|err| {
+            Web3ProxyError::BadRequest(format!("invalid log filter: {}", err).into())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> LogFilterStatus {
+        let current = self.current.lock();
+
+        LogFilterStatus {
+            filter: current.filter.clone(),
+            default_filter: self.default_filter.clone(),
+            revert_at: current.revert_at,
+        }
+    }
+
+    /// With `revert_after`, the startup filter comes back unless something else changes the filter first
+    pub fn set(
+        &'static self,
+        filter: &str,
+        revert_after: Option<Duration>,
+    ) -> Web3ProxyResult<LogFilterStatus> {
+        Self::validate(filter)?;
+
+        let revert_at = revert_after
+            .map(|x| chrono::Duration::from_std(x).map(|x| Utc::now() + x))
+            .transpose()
+            .map_err(|_| Web3ProxyError::BadRequest("revert_after_secs is too large".into()))?;
+
+        let generation = {
+            let mut current = self.current.lock();
+
+            self.reload(filter)?;
+
+            current.filter = filter.to_string();
+            current.revert_at = revert_at;
+            current.generation += 1;
+
+            current.generation
+        };
+
+        if let Some(revert_after) = revert_after {
+            tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+
+                self.revert(generation);
+            });
+        }
+
+        Ok(self.status())
+    }
+
+    fn revert(&self, generation: u64) {
+        let mut current = self.current.lock();
+
+        if current.generation != generation {
+            // the filter was changed again since this revert was scheduled
+            return;
+        }
+
+        if let Err(err) = self.reload(&self.default_filter) {
+            warn!(?err, "failed reverting the log filter");
+            return;
+        }
+
+        info!(filter=%current.filter, default=%self.default_filter, "reverted the log filter");
+
+        current.filter = self.default_filter.clone();
+        current.revert_at = None;
+        current.generation += 1;
+    }
+
+    fn reload(&self, filter: &str) -> Web3ProxyResult<()> {
+        for reload in self.reloaders.iter() {
+            reload(filter).map_err(|err| {
+                Web3ProxyError::StatusCode(
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("failed reloading the log filter: {}", err).into(),
+                    None,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn recording_reloader(seen: Arc<Mutex<Vec<String>>>) -> ReloadLogFilter {
+        Box::new(move |x| {
+            seen.lock().push(x.to_string());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        LogFilter::validate("info,web3_proxy::rpcs=trace").unwrap();
+
+        assert!(matches!(
+            LogFilter::validate("web3_proxy=loud"),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_and_revert() {
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        // every layer gets every change
+        let x: &'static LogFilter = Box::leak(Box::new(LogFilter::new(
+            "info".to_string(),
+            vec![
+                recording_reloader(seen.clone()),
+                recording_reloader(seen.clone()),
+            ],
+        )));
+
+        assert!(x.set("web3_proxy=loud", None).is_err());
+        assert!(seen.lock().is_empty());
+
+        let status = x
+            .set("info,web3_proxy=debug", Some(Duration::from_secs(60)))
+            .unwrap();
+
+        assert_eq!(status.filter, "info,web3_proxy=debug");
+        assert_eq!(status.default_filter, "info");
+        assert!(status.revert_at.is_some());
+        assert_eq!(seen.lock().len(), 2);
+
+        // a newer change without a revert cancels the old revert
+        x.set("info,web3_proxy=trace", None).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        assert_eq!(x.status().filter, "info,web3_proxy=trace");
+        assert_eq!(seen.lock().len(), 4);
+
+        x.set("debug", Some(Duration::from_secs(5))).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        let status = x.status();
+        assert_eq!(status.filter, "info");
+        assert_eq!(status.revert_at, None);
+        assert_eq!(seen.lock().last().map(|x| x.as_str()), Some("info"));
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_old_filter() {
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let failing: ReloadLogFilter = {
+            let calls = calls.clone();
+            Box::new(move |_| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err("subscriber is gone".to_string())
+            })
+        };
+
+        let x: &'static LogFilter =
+            Box::leak(Box::new(LogFilter::new("info".to_string(), vec![failing])));
+
+        assert!(x.set("debug", None).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(x.status().filter, "info");
+    }
+}
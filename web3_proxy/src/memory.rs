@@ -0,0 +1,332 @@
+//! A cheap report of what is using memory.
+//!
+//! Everything here comes from counters that are already maintained (moka's entry counts and weighted sizes, channel
+//! lengths, and a few atomics). Nothing is traversed, so monitoring can poll it as often as it wants.
+//! Byte counts are estimates. Only the response cache has a weigher that measures actual bytes.
+
+use crate::app::App;
+use crate::caches::RegisteredUserRateLimitKey;
+use crate::frontend::authorization::AuthorizationChecks;
+use crate::secrets::RpcSecretKey;
+use crate::tx_tracker::TrackedTx;
+use chrono::{DateTime, Utc};
+use entities::tx_origin;
+use ethers::types::TxHash;
+use moka::future::Cache;
+use serde_json::json;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// rough bookkeeping cost of one moka entry (node, deque pointers, timestamps, frequency sketch)
+pub const MOKA_ENTRY_OVERHEAD: usize = 128;
+
+/// an `Arc` around the deferred rate limiter's local counts
+const LOCAL_COUNT_BYTES: usize = 48;
+
+/// Counters for things that don't live in a cache
+#[derive(Debug, Default)]
+pub struct MemoryCounters {
+    pub websockets: AtomicUsize,
+    /// messages waiting to be written to every open websocket
+    pub websocket_queued_messages: AtomicUsize,
+    /// bytes waiting to be written to every open websocket
+    pub websocket_queued_bytes: AtomicUsize,
+    /// pending transaction notifications dropped because a client was reading too slowly
+    pub websocket_dropped_messages: AtomicU64,
+    /// websockets closed because a client was reading too slowly to keep up with messages that can't be dropped
+    pub websocket_slow_disconnects: AtomicU64,
+}
+
+impl MemoryCounters {
+    /// keep the returned guard alive for as long as the websocket is open
+    pub fn websocket_opened(self: &Arc<Self>) -> WebsocketMemoryGuard {
+        self.websockets.fetch_add(1, Ordering::Relaxed);
+
+        WebsocketMemoryGuard {
+            counters: self.clone(),
+        }
+    }
+}
+
+pub struct WebsocketMemoryGuard {
+    counters: Arc<MemoryCounters>,
+}
+
+impl Drop for WebsocketMemoryGuard {
+    fn drop(&mut self) {
+        self.counters.websockets.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// entries and estimated bytes for a moka cache. if `weighted`, the cache's weigher already counts bytes
+fn cache_json<K, V>(cache: &Cache<K, V>, weighted: bool) -> serde_json::Value
+where
+    K: std::hash::Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    let entries = cache.entry_count();
+
+    let per_entry = (size_of::<K>() + size_of::<V>() + MOKA_ENTRY_OVERHEAD) as u64;
+
+    let estimated_bytes = if weighted {
+        cache.weighted_size() + entries * per_entry
+    } else {
+        entries * per_entry
+    };
+
+    json!({
+        "entries": entries,
+        "estimated_bytes": estimated_bytes,
+        "max_capacity": cache.policy().max_capacity(),
+        "weighted_size": weighted.then(|| cache.weighted_size()),
+    })
+}
+
+fn total_estimated_bytes(x: &serde_json::Map<String, serde_json::Value>) -> u64 {
+    x.values()
+        .filter_map(|x| x.get("estimated_bytes").and_then(|x| x.as_u64()))
+        .sum()
+}
+
+/// resident set size of this process. only available on linux
+pub fn process_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    let line = status.lines().find(|x| x.starts_with("VmRSS:"))?;
+
+    // "VmRSS:     123456 kB"
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kb * 1024)
+}
+
+/// the `memory` section of `/status`. `detailed` adds a breakdown of every structure for the admin endpoint
+pub fn memory_report(app: &App, detailed: bool) -> serde_json::Value {
+    let mut structures = serde_json::Map::new();
+
+    structures.insert(
+        "jsonrpc_response_cache".into(),
+        cache_json(&app.jsonrpc_response_cache, true),
+    );
+    if let Some(x) = app.archive_response_cache.as_ref() {
+        structures.insert("archive_response_cache".into(), cache_json(x, true));
+    }
+    structures.insert(
+        "call_cache".into(),
+        cache_json(app.call_cache.cache(), true),
+    );
+    structures.insert(
+        "rpc_secret_key_cache".into(),
+        cache_json(&app.rpc_secret_key_cache, false),
+    );
+    structures.insert(
+        "user_balance_cache".into(),
+        cache_json(&app.user_balance_cache.0, false),
+    );
+
+    structures.insert(
+        "jsonrpc_response_failed_cache_keys".into(),
+        cache_json(&app.jsonrpc_response_failed_cache_keys, false),
+    );
+    structures.insert(
+        "jsonrpc_response_cache_sources".into(),
+        cache_json(&app.jsonrpc_response_cache_sources, false),
+    );
+    structures.insert(
+        "jsonrpc_response_cache_blocks".into(),
+        cache_json(&app.jsonrpc_response_cache_blocks, false),
+    );
+
+    {
+        let in_flight = app.incoming_requests.num_in_flight();
+        let capacity = app.incoming_requests.in_flight_capacity();
+
+        // hashbrown keeps one control byte per slot
+        structures.insert(
+            "incoming_requests".into(),
+            json!({
+                "capacity": capacity,
+                "estimated_bytes": capacity * (size_of::<(u64, usize)>() + 1),
+                "in_flight": in_flight,
+            }),
+        );
+    }
+
+    structures.insert(
+        "ip_semaphores".into(),
+        cache_json(&app.ip_semaphores, false),
+    );
+    structures.insert(
+        "user_semaphores".into(),
+        cache_json(&app.user_semaphores, false),
+    );
+
+    {
+        let firehose = &app.pending_txid_firehose;
+
+        let dedupe_entries = firehose.dedupe_entries();
+        let queued = firehose.queued();
+
+        let estimated_bytes =
+            dedupe_entries * (32 + MOKA_ENTRY_OVERHEAD) as u64 + queued as u64 * 32;
+
+        structures.insert(
+            "pending_txid_firehose".into(),
+            json!({
+                "dedupe_entries": dedupe_entries,
+                "estimated_bytes": estimated_bytes,
+                "queued": queued,
+            }),
+        );
+    }
+
+    if let Some(x) = app.tx_tracker.as_ref() {
+        let entries = x.len();
+
+        // the map entry, its place in the age order, and its place in a recent block
+        let per_entry = size_of::<(TxHash, TrackedTx)>()
+            + size_of::<(DateTime<Utc>, TxHash)>()
+            + size_of::<TxHash>();
+
+        structures.insert(
+            "tx_tracker".into(),
+            json!({
+                "entries": entries,
+                "estimated_bytes": entries * per_entry,
+            }),
+        );
+    }
+
+    if let Some(x) = app.tx_origins.as_ref() {
+        let queued = x.queued();
+
+        structures.insert(
+            "tx_origins".into(),
+            json!({
+                "estimated_bytes": queued * size_of::<tx_origin::ActiveModel>(),
+                "queued": queued,
+            }),
+        );
+    }
+
+    {
+        let websockets = app.memory.websockets.load(Ordering::Relaxed);
+        let queued_messages = app.memory.websocket_queued_messages.load(Ordering::Relaxed);
+        let queued_bytes = app.memory.websocket_queued_bytes.load(Ordering::Relaxed);
+
+        // the queued bytes plus a guess at the socket's own buffers
+        let estimated_bytes = queued_bytes as u64 + websockets as u64 * 16 * 1024;
+
+        structures.insert(
+            "websockets".into(),
+            json!({
+                "estimated_bytes": estimated_bytes,
+                "open": websockets,
+                "queued_bytes": queued_bytes,
+                "queued_messages": queued_messages,
+            }),
+        );
+    }
+
+    // long backend responses that are being read into memory right now
+    structures.insert(
+        "buffered_responses".into(),
+        json!({
+            "estimated_bytes": app.response_budget.buffered_bytes(),
+            "budget": &*app.response_budget,
+        }),
+    );
+
+    if let Some(x) = app.frontend_public_rate_limiter.as_ref() {
+        let entries = x.num_local_keys();
+
+        structures.insert(
+            "frontend_public_rate_limiter".into(),
+            json!({
+                "entries": entries,
+                "estimated_bytes": entries * (size_of::<IpAddr>() + LOCAL_COUNT_BYTES + MOKA_ENTRY_OVERHEAD) as u64,
+            }),
+        );
+    }
+
+    if let Some(x) = app.frontend_premium_rate_limiter.as_ref() {
+        let entries = x.num_local_keys();
+
+        structures.insert(
+            "frontend_premium_rate_limiter".into(),
+            json!({
+                "entries": entries,
+                "estimated_bytes": entries * (size_of::<RegisteredUserRateLimitKey>() + LOCAL_COUNT_BYTES + MOKA_ENTRY_OVERHEAD) as u64,
+            }),
+        );
+    }
+
+    let estimated_bytes = total_estimated_bytes(&structures);
+
+    let mut report = json!({
+        "estimated_bytes": estimated_bytes,
+        "process_rss_bytes": process_rss_bytes(),
+    });
+
+    if detailed {
+        report["structures"] = structures.into();
+        report["sizes"] = json!({
+            "authorization_checks": size_of::<AuthorizationChecks>(),
+            "moka_entry_overhead": MOKA_ENTRY_OVERHEAD,
+            "rpc_secret_key": size_of::<RpcSecretKey>(),
+            "semaphore": size_of::<Arc<Semaphore>>() + size_of::<Semaphore>(),
+            "user_semaphore_key": size_of::<(NonZeroU64, IpAddr)>(),
+        });
+    } else {
+        // the summary only has the big ones
+        for key in [
+            "jsonrpc_response_cache",
+            "archive_response_cache",
+            "rpc_secret_key_cache",
+            "user_balance_cache",
+            "pending_txid_firehose",
+            "websockets",
+            "buffered_responses",
+        ] {
+            if let Some(x) = structures.get(key) {
+                report[key] = x["estimated_bytes"].clone();
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_guard() {
+        let counters = Arc::new(MemoryCounters::default());
+
+        let a = counters.websocket_opened();
+        let b = counters.websocket_opened();
+
+        assert_eq!(counters.websockets.load(Ordering::Relaxed), 2);
+
+        drop(a);
+
+        assert_eq!(counters.websockets.load(Ordering::Relaxed), 1);
+
+        drop(b);
+
+        assert_eq!(counters.websockets.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn rss() {
+        if cfg!(target_os = "linux") {
+            assert!(process_rss_bytes().unwrap() > 0);
+        }
+    }
+}
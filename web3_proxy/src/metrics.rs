@@ -0,0 +1,559 @@
+//! Labeled metrics for prometheus.
+//!
+//! serde_prometheus can't add labels or HELP and TYPE lines. These metrics are written in the text format by hand and
+//! served after its output. Backends are labeled with their name from the config so that providers can be compared.
+
+use crate::app::App;
+use crate::rpcs::one::Web3Rpc;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// upper bounds of the latency histogram buckets
+pub const LATENCY_BUCKETS_SECS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// methods past this many are counted as "other". this keeps junk method names from making new time series
+pub const MAX_METHODS: usize = 256;
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// not cumulative. they are summed when written
+    buckets: [AtomicU64; LATENCY_BUCKETS_SECS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+
+        if let Some(i) = LATENCY_BUCKETS_SECS.iter().position(|x| secs <= *x) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, w: &mut MetricsWriter, name: &str, labels: &[(&str, &str)]) {
+        let mut cumulative = 0;
+
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS_SECS) {
+            cumulative += bucket.load(Ordering::Relaxed);
+
+            let le = le.to_string();
+
+            w.sample_with(
+                &format!("{}_bucket", name),
+                labels,
+                ("le", le.as_str()),
+                cumulative,
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+
+        w.sample_with(&format!("{}_bucket", name), labels, ("le", "+Inf"), count);
+        w.sample(
+            &format!("{}_sum", name),
+            labels,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        );
+        w.sample(&format!("{}_count", name), labels, count);
+    }
+}
+
+/// How the proxy answered a request
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RequestOutcome {
+    CacheHit,
+    CacheMiss,
+    BackendError,
+    RateLimited,
+}
+
+impl RequestOutcome {
+    pub const ALL: [Self; 4] = [
+        Self::CacheHit,
+        Self::CacheMiss,
+        Self::BackendError,
+        Self::RateLimited,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CacheHit => "cache_hit",
+            Self::CacheMiss => "cache_miss",
+            Self::BackendError => "backend_error",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// What was bigger than its limit. These are counted apart from backend errors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TooLarge {
+    /// `max_request_bytes`
+    Request,
+    /// `max_batch_size`
+    Batch,
+    /// `max_response_bytes`
+    Response,
+}
+
+impl TooLarge {
+    pub const ALL: [Self; 3] = [Self::Request, Self::Batch, Self::Response];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Request => "request",
+            Self::Batch => "batch",
+            Self::Response => "response",
+        }
+    }
+}
+
+/// Counters for requests from users. Internal requests are not counted
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    by_method: Mutex<HashMap<String, u64>>,
+    by_outcome: [AtomicU64; RequestOutcome::ALL.len()],
+    too_large: [AtomicU64; TooLarge::ALL.len()],
+    /// from the request arriving until the response is ready
+    latency: Histogram,
+}
+
+impl RequestMetrics {
+    pub fn record(&self, method: &str, outcome: RequestOutcome, latency: Duration) {
+        {
+            let mut by_method = self.by_method.lock();
+
+            if let Some(x) = by_method.get_mut(method) {
+                *x += 1;
+            } else if by_method.len() < MAX_METHODS {
+                by_method.insert(method.to_string(), 1);
+            } else if let Some(x) = by_method.get_mut("other") {
+                *x += 1;
+            } else {
+                by_method.insert("other".to_string(), 1);
+            }
+        }
+
+        self.by_outcome[outcome as usize].fetch_add(1, Ordering::Relaxed);
+
+        self.latency.observe(latency);
+    }
+
+    /// rate limited requests are turned away before their method is known
+    pub fn record_rate_limited(&self) {
+        self.by_outcome[RequestOutcome::RateLimited as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_too_large(&self, kind: TooLarge) {
+        self.too_large[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn write(&self, w: &mut MetricsWriter) {
+        w.header(
+            "web3_proxy_requests_by_method_total",
+            "counter",
+            "requests from users by jsonrpc method",
+        );
+        {
+            let by_method = self.by_method.lock();
+
+            let mut methods: Vec<_> = by_method.iter().collect();
+            methods.sort();
+
+            for (method, count) in methods {
+                w.sample(
+                    "web3_proxy_requests_by_method_total",
+                    &[("method", method.as_str())],
+                    *count,
+                );
+            }
+        }
+
+        w.header(
+            "web3_proxy_requests_by_outcome_total",
+            "counter",
+            "requests from users by how they were answered",
+        );
+        for outcome in RequestOutcome::ALL {
+            w.sample(
+                "web3_proxy_requests_by_outcome_total",
+                &[("outcome", outcome.as_str())],
+                self.by_outcome[outcome as usize].load(Ordering::Relaxed),
+            );
+        }
+
+        w.header(
+            "web3_proxy_too_large_total",
+            "counter",
+            "requests, batches, and backend responses that were bigger than their limit",
+        );
+        for kind in TooLarge::ALL {
+            w.sample(
+                "web3_proxy_too_large_total",
+                &[("kind", kind.as_str())],
+                self.too_large[kind as usize].load(Ordering::Relaxed),
+            );
+        }
+
+        w.header(
+            "web3_proxy_request_latency_seconds",
+            "histogram",
+            "time from a request arriving until its response was ready",
+        );
+        self.latency
+            .write(w, "web3_proxy_request_latency_seconds", &[]);
+    }
+}
+
+/// The prometheus text format
+#[derive(Default)]
+pub struct MetricsWriter(String);
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl ToString) {
+        self.write_sample(name, labels.iter().copied(), value)
+    }
+
+    fn sample_with(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        extra: (&str, &str),
+        value: impl ToString,
+    ) {
+        self.write_sample(name, labels.iter().copied().chain([extra]), value)
+    }
+
+    fn write_sample<'a>(
+        &mut self,
+        name: &str,
+        labels: impl Iterator<Item = (&'a str, &'a str)>,
+        value: impl ToString,
+    ) {
+        self.0.push_str(name);
+
+        let mut first = true;
+        for (k, v) in labels {
+            self.0.push(if first { '{' } else { ',' });
+            first = false;
+
+            let _ = write!(self.0, "{}=\"{}\"", k, escape_label(v));
+        }
+        if !first {
+            self.0.push('}');
+        }
+
+        let _ = writeln!(self.0, " {}", value.to_string());
+    }
+
+    /// One gauge per backend
+    fn backend_gauge<T: ToString>(&mut self, name: &str, help: &str, backends: &[(String, T)]) {
+        self.header(name, "gauge", help);
+
+        for (rpc, value) in backends {
+            self.sample(name, &[("rpc", rpc.as_str())], value.to_string());
+        }
+    }
+}
+
+fn escape_label(x: &str) -> String {
+    x.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Everything that needs labels, in the prometheus text format
+pub fn labeled_metrics(app: &App) -> String {
+    let mut w = MetricsWriter::default();
+
+    app.request_metrics.write(&mut w);
+
+    w.header(
+        "web3_proxy_head_block_number",
+        "gauge",
+        "the consensus head block",
+    );
+    let head_block_num = app
+        .watch_consensus_head_receiver
+        .borrow()
+        .as_ref()
+        .map(|x| x.number().as_u64());
+    w.sample(
+        "web3_proxy_head_block_number",
+        &[],
+        head_block_num.unwrap_or_default(),
+    );
+
+    w.header(
+        "web3_proxy_synced_backends",
+        "gauge",
+        "balanced rpcs on the consensus head",
+    );
+    w.sample(
+        "web3_proxy_synced_backends",
+        &[],
+        app.balanced_rpcs.num_synced_rpcs(),
+    );
+
+    w.header(
+        "web3_proxy_response_cache_entries",
+        "gauge",
+        "responses in the response cache",
+    );
+    w.sample(
+        "web3_proxy_response_cache_entries",
+        &[],
+        app.jsonrpc_response_cache.entry_count(),
+    );
+
+    w.header(
+        "web3_proxy_response_cache_bytes",
+        "gauge",
+        "size of the responses in the response cache",
+    );
+    w.sample(
+        "web3_proxy_response_cache_bytes",
+        &[],
+        app.jsonrpc_response_cache.weighted_size(),
+    );
+
+    w.header(
+        "web3_proxy_requests_in_flight",
+        "gauge",
+        "requests from users that have not been answered yet",
+    );
+    w.sample(
+        "web3_proxy_requests_in_flight",
+        &[],
+        app.recent_requests.in_flight(),
+    );
+
+    w.header(
+        "web3_proxy_incoming_requests_in_flight",
+        "gauge",
+        "cache keys with a request to a backend in flight",
+    );
+    w.sample(
+        "web3_proxy_incoming_requests_in_flight",
+        &[],
+        app.incoming_requests.num_in_flight(),
+    );
+
+    w.header(
+        "web3_proxy_tracked_transactions",
+        "gauge",
+        "relayed transactions whose status is being followed",
+    );
+    w.sample(
+        "web3_proxy_tracked_transactions",
+        &[],
+        app.tx_tracker.as_ref().map(|x| x.len()).unwrap_or_default(),
+    );
+
+    let rpcs: Vec<_> = {
+        let mut x: Vec<_> = app.balanced_rpcs.by_name.read().values().cloned().collect();
+        x.sort_by(|a, b| a.name.cmp(&b.name));
+        x
+    };
+
+    let per_rpc = |f: &dyn Fn(&Web3Rpc) -> u64| -> Vec<(String, u64)> {
+        rpcs.iter().map(|x| (x.name.clone(), f(x))).collect()
+    };
+
+    w.backend_gauge(
+        "web3_proxy_backend_head_block_number",
+        "each backend's head block. 0 if it has none",
+        &per_rpc(&|x| x.head_block_num().map(|x| x.as_u64()).unwrap_or_default()),
+    );
+    w.backend_gauge(
+        "web3_proxy_backend_healthy",
+        "1 if the backend's health checks pass",
+        &per_rpc(&|x| x.is_healthy() as u64),
+    );
+    w.backend_gauge(
+        "web3_proxy_backend_quarantined",
+        "1 if the backend is quarantined for failing too many requests",
+        &per_rpc(&|x| x.is_quarantined() as u64),
+    );
+
+    w.header(
+        "web3_proxy_backend_requests_total",
+        "counter",
+        "requests sent to each backend",
+    );
+    for rpc in rpcs.iter() {
+        let (external, internal) = rpc.request_counts();
+
+        w.sample(
+            "web3_proxy_backend_requests_total",
+            &[("rpc", rpc.name.as_str()), ("kind", "external")],
+            external,
+        );
+        w.sample(
+            "web3_proxy_backend_requests_total",
+            &[("rpc", rpc.name.as_str()), ("kind", "internal")],
+            internal,
+        );
+    }
+
+    w.header(
+        "web3_proxy_backend_errors_total",
+        "counter",
+        "failed requests that were the backend's fault",
+    );
+    for rpc in rpcs.iter() {
+        w.sample(
+            "web3_proxy_backend_errors_total",
+            &[("rpc", rpc.name.as_str())],
+            rpc.backend_errors.load(Ordering::Relaxed),
+        );
+    }
+
+    w.header(
+        "web3_proxy_backend_latency_seconds",
+        "histogram",
+        "how long each backend took to answer",
+    );
+    for rpc in rpcs.iter() {
+        rpc.request_latency.write(
+            &mut w,
+            "web3_proxy_backend_latency_seconds",
+            &[("rpc", rpc.name.as_str())],
+        );
+    }
+
+    w.header(
+        "web3_proxy_backend_headers_latency_seconds",
+        "histogram",
+        "how long each backend took to send response headers. connecting plus time to first byte",
+    );
+    for rpc in rpcs.iter() {
+        rpc.headers_latency.write(
+            &mut w,
+            "web3_proxy_backend_headers_latency_seconds",
+            &[("rpc", rpc.name.as_str())],
+        );
+    }
+
+    w.backend_gauge(
+        "web3_proxy_backend_peak_latency_seconds",
+        "moving peak of each backend's request latency. used to pick between backends",
+        &rpcs
+            .iter()
+            .map(|x| (x.name.clone(), x.peak_request_latency().as_secs_f64()))
+            .collect::<Vec<_>>(),
+    );
+    w.backend_gauge(
+        "web3_proxy_backend_latency_tier",
+        "lower tiers have lower median latency and are tried first",
+        &per_rpc(&|x| x.tier() as u64),
+    );
+
+    w.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let x = Histogram::default();
+
+        x.observe(Duration::from_millis(3));
+        x.observe(Duration::from_millis(200));
+        x.observe(Duration::from_secs(60));
+
+        let mut w = MetricsWriter::default();
+        x.write(&mut w, "latency", &[("rpc", "a")]);
+
+        let lines: Vec<_> = w.0.lines().collect();
+
+        assert_eq!(lines[0], "latency_bucket{rpc=\"a\",le=\"0.005\"} 1");
+        assert_eq!(lines[5], "latency_bucket{rpc=\"a\",le=\"0.25\"} 2");
+        assert_eq!(lines[11], "latency_bucket{rpc=\"a\",le=\"30\"} 2");
+        assert_eq!(lines[12], "latency_bucket{rpc=\"a\",le=\"+Inf\"} 3");
+        assert_eq!(lines[13], "latency_sum{rpc=\"a\"} 60.203");
+        assert_eq!(lines[14], "latency_count{rpc=\"a\"} 3");
+    }
+
+    #[test]
+    fn requests() {
+        let x = RequestMetrics::default();
+
+        x.record(
+            "eth_call",
+            RequestOutcome::CacheMiss,
+            Duration::from_millis(10),
+        );
+        x.record(
+            "eth_call",
+            RequestOutcome::CacheHit,
+            Duration::from_millis(1),
+        );
+        x.record(
+            "eth_chainId",
+            RequestOutcome::CacheHit,
+            Duration::from_millis(1),
+        );
+        x.record_rate_limited();
+        x.record_too_large(TooLarge::Response);
+        x.record_too_large(TooLarge::Response);
+
+        let mut w = MetricsWriter::default();
+        x.write(&mut w);
+
+        let text = w.0;
+
+        assert!(text.contains("# TYPE web3_proxy_requests_by_method_total counter\n"));
+        assert!(text.contains("web3_proxy_requests_by_method_total{method=\"eth_call\"} 2\n"));
+        assert!(text.contains("web3_proxy_requests_by_method_total{method=\"eth_chainId\"} 1\n"));
+        assert!(text.contains("web3_proxy_requests_by_outcome_total{outcome=\"cache_hit\"} 2\n"));
+        assert!(text.contains("web3_proxy_requests_by_outcome_total{outcome=\"rate_limited\"} 1\n"));
+        assert!(text.contains("web3_proxy_request_latency_seconds_count 3\n"));
+        assert!(text.contains("web3_proxy_too_large_total{kind=\"batch\"} 0\n"));
+        assert!(text.contains("web3_proxy_too_large_total{kind=\"response\"} 2\n"));
+    }
+
+    #[test]
+    fn junk_methods() {
+        let x = RequestMetrics::default();
+
+        for i in 0..MAX_METHODS + 10 {
+            x.record(
+                &format!("junk_{}", i),
+                RequestOutcome::CacheMiss,
+                Duration::ZERO,
+            );
+        }
+
+        let by_method = x.by_method.lock();
+
+        assert_eq!(by_method.len(), MAX_METHODS + 1);
+        assert_eq!(by_method["other"], 10);
+    }
+
+    #[test]
+    fn escaping() {
+        let mut w = MetricsWriter::default();
+
+        w.sample("x", &[("method", "a\"b\\c\nd")], 1);
+
+        assert_eq!(w.0, "x{method=\"a\\\"b\\\\c\\nd\"} 1\n");
+    }
+}
@@ -0,0 +1,226 @@
+//! Find the chain id that a request's params commit to.
+//!
+//! Only a handful of methods have a well defined place for one. Forwarding a mismatched request just gets a confusing
+//! error from the backend (or worse, a signature that is valid somewhere else), so these are rejected up front.
+//!
+//! `eth_sendUserOperation` is not here. The user operation hash commits to the chain id, but the params do not carry it.
+//! The ERC-7677 paymaster methods that sign for an entry point do.
+
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::raw_transaction::RawTransaction;
+use ethers::types::{Bytes, U64};
+use serde_json::Value;
+use std::str::FromStr;
+
+/// The chain id in `params`, if `method` has one and the client included it.
+pub fn param_chain_id(method: &str, params: &Value) -> Web3ProxyResult<Option<U64>> {
+    match method {
+        "eth_sendRawTransaction" | "eth_sendRawTransactionConditional" => {
+            raw_tx_chain_id(params.get(0))
+        }
+        // flashbots style. `[{"tx": "0x...", ...}]`
+        "eth_sendPrivateTransaction" => raw_tx_chain_id(params.get(0).and_then(|x| x.get("tx"))),
+        // `[address, typedData]`. wallets send the typed data as an object or as a json string
+        "eth_signTypedData_v3" | "eth_signTypedData_v4" => {
+            let Some(typed_data) = params.get(1) else {
+                return Ok(None);
+            };
+
+            let parsed;
+            let typed_data = if let Some(x) = typed_data.as_str() {
+                parsed = serde_json::from_str::<Value>(x).map_err(|_| {
+                    Web3ProxyError::BadRequest("typed data is not valid json".into())
+                })?;
+                &parsed
+            } else {
+                typed_data
+            };
+
+            typed_data
+                .get("domain")
+                .and_then(|x| x.get("chainId"))
+                .map(parse_chain_id)
+                .transpose()
+        }
+        // ERC-7677. `[userOp, entryPoint, chainId, context]`
+        "pm_getPaymasterData" | "pm_getPaymasterStubData" => {
+            params.get(2).map(parse_chain_id).transpose()
+        }
+        _ => Ok(None),
+    }
+}
+
+/// legacy transactions from before EIP-155 don't have a chain id
+fn raw_tx_chain_id(raw: Option<&Value>) -> Web3ProxyResult<Option<U64>> {
+    let raw = raw
+        .and_then(|x| x.as_str())
+        .ok_or_else(|| Web3ProxyError::BadRequest("raw transaction must be a string".into()))?;
+
+    let bytes = Bytes::from_str(raw)
+        .map_err(|_| Web3ProxyError::BadRequest("Unable to parse params as bytes".into()))?;
+
+    let tx = RawTransaction::decode(bytes.as_ref())?;
+
+    Ok(tx.chain_id)
+}
+
+/// chain ids show up as numbers, hex strings, and decimal strings
+fn parse_chain_id(x: &Value) -> Web3ProxyResult<U64> {
+    let parsed = match x {
+        Value::Number(x) => x.as_u64().map(U64::from),
+        Value::String(x) => match x.strip_prefix("0x") {
+            Some(hex) => U64::from_str_radix(hex, 16).ok(),
+            None => x.parse::<u64>().ok().map(U64::from),
+        },
+        _ => None,
+    };
+
+    parsed.ok_or_else(|| Web3ProxyError::BadRequest(format!("invalid chainId: {}", x).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use ethers::types::{Address, Eip1559TransactionRequest, TransactionRequest};
+    use ethers::{prelude::LocalWallet, signers::Signer};
+    use serde_json::json;
+
+    fn wallet() -> LocalWallet {
+        "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse()
+            .unwrap()
+    }
+
+    fn signed(tx: TypedTransaction) -> Bytes {
+        let signature = wallet().sign_transaction_sync(&tx).unwrap();
+
+        tx.rlp_signed(&signature)
+    }
+
+    #[test]
+    fn raw_transactions() {
+        let eip1559 = signed(
+            Eip1559TransactionRequest::new()
+                .to(Address::repeat_byte(0x42))
+                .nonce(0)
+                .gas(21_000)
+                .max_fee_per_gas(30_000_000_000u64)
+                .chain_id(10)
+                .into(),
+        );
+
+        assert_eq!(
+            param_chain_id("eth_sendRawTransaction", &json!([eip1559])).unwrap(),
+            Some(10.into())
+        );
+        assert_eq!(
+            param_chain_id("eth_sendRawTransactionConditional", &json!([eip1559, {}])).unwrap(),
+            Some(10.into())
+        );
+        assert_eq!(
+            param_chain_id("eth_sendPrivateTransaction", &json!([{"tx": eip1559}])).unwrap(),
+            Some(10.into())
+        );
+
+        // EIP-155 puts the chain id in `v`
+        let legacy = signed(
+            TransactionRequest::new()
+                .to(Address::repeat_byte(0x42))
+                .nonce(0)
+                .gas(21_000)
+                .gas_price(30_000_000_000u64)
+                .chain_id(137)
+                .into(),
+        );
+
+        assert_eq!(
+            param_chain_id("eth_sendRawTransaction", &json!([legacy])).unwrap(),
+            Some(137.into())
+        );
+
+        assert!(param_chain_id("eth_sendRawTransaction", &json!(["0xdeadbeef"])).is_err());
+        assert!(param_chain_id("eth_sendRawTransaction", &json!([])).is_err());
+    }
+
+    #[test]
+    fn typed_data() {
+        let address = Address::repeat_byte(0x42);
+
+        let typed_data = json!({
+            "types": {},
+            "primaryType": "Mail",
+            "domain": {"name": "Ether Mail", "version": "1", "chainId": 1},
+            "message": {},
+        });
+
+        assert_eq!(
+            param_chain_id("eth_signTypedData_v4", &json!([address, typed_data])).unwrap(),
+            Some(1.into())
+        );
+
+        // as a string, with a hex chain id
+        let typed_data = json!({"domain": {"chainId": "0x89"}}).to_string();
+
+        assert_eq!(
+            param_chain_id("eth_signTypedData_v3", &json!([address, typed_data])).unwrap(),
+            Some(137.into())
+        );
+
+        // decimal string
+        let typed_data = json!({"domain": {"chainId": "42161"}});
+
+        assert_eq!(
+            param_chain_id("eth_signTypedData_v4", &json!([address, typed_data])).unwrap(),
+            Some(42161.into())
+        );
+
+        // the domain doesn't have to bind a chain
+        let typed_data = json!({"domain": {"name": "Ether Mail"}});
+
+        assert_eq!(
+            param_chain_id("eth_signTypedData_v4", &json!([address, typed_data])).unwrap(),
+            None
+        );
+
+        let typed_data = json!({"domain": {"chainId": "mainnet"}});
+
+        assert!(param_chain_id("eth_signTypedData_v4", &json!([address, typed_data])).is_err());
+    }
+
+    #[test]
+    fn paymaster() {
+        let entry_point = Address::repeat_byte(0x55);
+
+        assert_eq!(
+            param_chain_id(
+                "pm_getPaymasterStubData",
+                &json!([{}, entry_point, "0x2105", {}])
+            )
+            .unwrap(),
+            Some(8453.into())
+        );
+        assert_eq!(
+            param_chain_id(
+                "pm_getPaymasterData",
+                &json!([{}, entry_point, "0x1", null])
+            )
+            .unwrap(),
+            Some(1.into())
+        );
+    }
+
+    #[test]
+    fn other_methods() {
+        let user_op = json!([{"sender": Address::repeat_byte(0x42)}, Address::repeat_byte(0x55)]);
+
+        assert_eq!(
+            param_chain_id("eth_sendUserOperation", &user_op).unwrap(),
+            None
+        );
+        assert_eq!(
+            param_chain_id("eth_call", &json!([{"chainId": "0x2"}, "latest"])).unwrap(),
+            None
+        );
+    }
+}
@@ -30,6 +30,7 @@ pub use tracing;
 pub use ulid;
 pub use url;
 pub use uuid;
+pub use web3_proxy_client;
 
 #[cfg(feature = "rdkafka")]
 pub use rdkafka;
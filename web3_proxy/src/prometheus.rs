@@ -11,14 +11,32 @@ use tracing::info;
 
 use crate::app::App;
 use crate::errors::Web3ProxyResult;
+use crate::frontend::ServedChain;
+use crate::metrics::labeled_metrics;
 
-/// Run a prometheus metrics server on the given port.
+/// Run a prometheus metrics server on the given port. It is separate from the frontend so that it isn't rate limited.
+/// The metrics are at `/` and `/metrics`. With several chains, each chain's metrics are at `/<name>` and `/<name>/metrics`
 pub async fn serve(
-    app: Arc<App>,
+    chains: Vec<ServedChain>,
     mut shutdown_receiver: broadcast::Receiver<()>,
 ) -> Web3ProxyResult<()> {
-    // routes should be ordered most to least common
-    let router = Router::new().route("/", get(root)).with_state(app.clone());
+    let mut router = Router::new();
+
+    for chain in chains.iter() {
+        // routes should be ordered most to least common
+        let chain_router = Router::new()
+            .route("/", get(root))
+            .route("/metrics", get(root))
+            .with_state(chain.app.clone());
+
+        router = match chain.name.as_ref() {
+            None => router.merge(chain_router),
+            Some(name) => router.nest(&format!("/{}", name), chain_router),
+        };
+    }
+
+    // every chain shares the port
+    let app = chains[0].app.clone();
 
     // note: the port here might be 0
     let port = app.prometheus_port.load(Ordering::SeqCst);
@@ -44,14 +62,21 @@ pub async fn serve(
 }
 
 async fn root(State(app): State<Arc<App>>) -> Response {
-    let serialized = app.prometheus_metrics().await;
+    let mut serialized = app.prometheus_metrics().await;
+
+    if !serialized.is_empty() && !serialized.ends_with('\n') {
+        serialized.push('\n');
+    }
+
+    serialized.push_str(&labeled_metrics(&app));
 
     let mut r = serialized.into_response();
 
     // // TODO: is there an easier way to do this?
     r.headers_mut().insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("application/openmetrics-text; version=1.0.0; charset=utf-8"),
+        // this is the older text format. openmetrics needs a "# EOF" line and other things that we don't send
+        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
     );
 
     r
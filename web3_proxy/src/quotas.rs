@@ -0,0 +1,501 @@
+//! Daily and monthly request quotas for rpc keys.
+//!
+//! `max_requests_per_period` stops bursts. Quotas are for plans that are sold by the day or by the month. Days and
+//! months are in utc.
+//!
+//! A redis round trip for every request would be too slow, so each server counts locally and adds its counts to redis
+//! every `quota_sync_ms`, or sooner once a key makes `quota_max_local_burst` requests. A server can let a key go over its
+//! quota by at most `quota_max_local_burst` before it sees what the other servers counted. Without `volatile_redis_url`,
+//! the counts are only for this server and start over when it restarts.
+
+use crate::config::AppConfig;
+use crate::errors::Web3ProxyResult;
+use crate::frontend::authorization::AuthorizationChecks;
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeZone, Utc};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use redis_rate_limiter::redis::{self, AsyncCommands};
+use redis_rate_limiter::RedisPool;
+use serde::Serialize;
+use std::fmt;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+use tracing::{trace, warn};
+
+/// counts in redis are kept this long after their period ends. servers with slow clocks still find them
+const REDIS_GRACE_SECS: i64 = 3_600;
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl fmt::Display for QuotaPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Day => write!(f, "day"),
+            Self::Month => write!(f, "month"),
+        }
+    }
+}
+
+impl QuotaPeriod {
+    pub const ALL: [Self; 2] = [Self::Day, Self::Month];
+
+    /// the key's quota for this period. None if it doesn't have one
+    pub fn limit(&self, checks: &AuthorizationChecks) -> Option<u64> {
+        match self {
+            Self::Day => checks.max_requests_per_day,
+            Self::Month => checks.max_requests_per_month,
+        }
+    }
+
+    /// when the period that includes `now` started
+    pub fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let date = now.date_naive();
+
+        let date = match self {
+            Self::Day => date,
+            Self::Month => date.with_day(1).expect("every month has a first day"),
+        };
+
+        midnight(date)
+    }
+
+    /// when the period that includes `now` ends and the next one starts
+    pub fn reset(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let start = self.start(now).date_naive();
+
+        let next = match self {
+            Self::Day => start.succ_opt(),
+            Self::Month => start.checked_add_months(Months::new(1)),
+        };
+
+        midnight(next.expect("dates this far in the future are not a concern"))
+    }
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("midnight always exists"))
+}
+
+/// the key's quotas that are set
+fn limits(checks: &AuthorizationChecks) -> impl Iterator<Item = (QuotaPeriod, u64)> + '_ {
+    QuotaPeriod::ALL
+        .into_iter()
+        .filter_map(|period| Some((period, period.limit(checks)?)))
+}
+
+/// Why a request was refused
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct QuotaExceeded {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    /// when the next period starts and requests are allowed again
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub reset: DateTime<Utc>,
+}
+
+/// How much of one of a key's quotas has been used
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct QuotaUsage {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub reset: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+struct CounterKey {
+    rpc_key_id: NonZeroU64,
+    period: QuotaPeriod,
+    start: DateTime<Utc>,
+}
+
+impl CounterKey {
+    fn new(rpc_key_id: NonZeroU64, period: QuotaPeriod, now: DateTime<Utc>) -> Self {
+        Self {
+            rpc_key_id,
+            period,
+            start: period.start(now),
+        }
+    }
+
+    /// not per-chain. a quota covers every chain that shares this redis
+    fn redis_key(&self) -> String {
+        format!(
+            "web3_proxy:quota:{}:{}:{}",
+            self.rpc_key_id,
+            self.period,
+            self.start.timestamp()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    /// the total in redis at the last sync. includes what this server sent
+    synced: u64,
+    /// counted here, but not sent to redis yet
+    pending: u64,
+}
+
+pub struct Quotas {
+    counters: Mutex<HashMap<CounterKey, Counter>>,
+    max_local_burst: u64,
+    redis_pool: Option<RedisPool>,
+    sync_now: Arc<Notify>,
+    /// requests refused because a quota was used up
+    pub exceeded: AtomicU64,
+    /// syncs that failed. their counts are sent with the next one
+    pub sync_errors: AtomicU64,
+}
+
+impl Quotas {
+    fn new(max_local_burst: u64, redis_pool: Option<RedisPool>) -> Self {
+        Self {
+            counters: Default::default(),
+            max_local_burst: max_local_burst.max(1),
+            redis_pool,
+            sync_now: Default::default(),
+            exceeded: AtomicU64::new(0),
+            sync_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts are synced in a background task until this is dropped
+    pub fn spawn(config: &AppConfig, redis_pool: Option<&RedisPool>) -> Arc<Self> {
+        let x = Arc::new(Self::new(config.quota_max_local_burst, redis_pool.cloned()));
+
+        tokio::spawn(sync_loop(
+            Arc::downgrade(&x),
+            x.sync_now.clone(),
+            Duration::from_millis(config.quota_sync_ms.max(1)),
+        ));
+
+        x
+    }
+
+    /// Count one request against each of the key's quotas. If any of them is used up, nothing is counted
+    pub fn count(
+        &self,
+        checks: &AuthorizationChecks,
+        now: DateTime<Utc>,
+    ) -> Result<(), QuotaExceeded> {
+        let Some(rpc_key_id) = checks.rpc_secret_key_id else {
+            return Ok(());
+        };
+
+        let mut sync_now = false;
+
+        {
+            let mut counters = self.counters.lock();
+
+            for (period, limit) in limits(checks) {
+                let key = CounterKey::new(rpc_key_id, period, now);
+
+                let used = counters.get(&key).map_or(0, |x| x.synced + x.pending);
+
+                if used >= limit {
+                    self.exceeded.fetch_add(1, Ordering::Relaxed);
+
+                    return Err(QuotaExceeded {
+                        period,
+                        limit,
+                        reset: period.reset(now),
+                    });
+                }
+            }
+
+            for (period, _) in limits(checks) {
+                let counter = counters
+                    .entry(CounterKey::new(rpc_key_id, period, now))
+                    .or_insert_with(|| {
+                        // the other servers might have used most of this already
+                        sync_now = true;
+                        Counter::default()
+                    });
+
+                counter.pending += 1;
+
+                if counter.pending >= self.max_local_burst {
+                    sync_now = true;
+                }
+            }
+        }
+
+        if sync_now {
+            self.sync_now.notify_one();
+        }
+
+        Ok(())
+    }
+
+    /// How much of each of the key's quotas has been used in the current periods. Includes other servers' counts
+    pub async fn usage(&self, checks: &AuthorizationChecks) -> Web3ProxyResult<Vec<QuotaUsage>> {
+        let Some(rpc_key_id) = checks.rpc_secret_key_id else {
+            return Ok(vec![]);
+        };
+
+        let now = Utc::now();
+
+        let mut x = vec![];
+
+        for (period, limit) in limits(checks) {
+            let key = CounterKey::new(rpc_key_id, period, now);
+
+            let (synced, pending) = self
+                .counters
+                .lock()
+                .get(&key)
+                .map_or((0, 0), |x| (x.synced, x.pending));
+
+            let synced = if let Some(redis_pool) = self.redis_pool.as_ref() {
+                let mut conn = redis_pool.get().await?;
+
+                let in_redis: Option<u64> = conn.get(key.redis_key()).await?;
+
+                in_redis.unwrap_or_default().max(synced)
+            } else {
+                synced
+            };
+
+            let used = synced + pending;
+
+            x.push(QuotaUsage {
+                period,
+                limit,
+                used,
+                remaining: limit.saturating_sub(used),
+                reset: period.reset(now),
+            });
+        }
+
+        Ok(x)
+    }
+
+    /// Send pending counts to redis and learn what the other servers counted.
+    /// Counters for periods that are over are dropped
+    pub async fn sync(&self) {
+        let now = Utc::now();
+
+        let batch: Vec<(CounterKey, u64)> = {
+            let mut counters = self.counters.lock();
+
+            counters.retain(|k, _| k.start == k.period.start(now));
+
+            counters
+                .iter_mut()
+                .map(|(k, v)| (*k, std::mem::take(&mut v.pending)))
+                .collect()
+        };
+
+        if batch.is_empty() {
+            return;
+        }
+
+        let Some(redis_pool) = self.redis_pool.as_ref() else {
+            // nobody else is counting
+            let mut counters = self.counters.lock();
+
+            for (k, pending) in batch {
+                if let Some(x) = counters.get_mut(&k) {
+                    x.synced += pending;
+                }
+            }
+
+            return;
+        };
+
+        match sync_redis(redis_pool, &batch).await {
+            Ok(totals) => {
+                trace!(counters = batch.len(), "synced quotas");
+
+                let mut counters = self.counters.lock();
+
+                for ((k, _), total) in batch.into_iter().zip(totals) {
+                    if let Some(x) = counters.get_mut(&k) {
+                        x.synced = total;
+                    }
+                }
+            }
+            Err(err) => {
+                self.sync_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(?err, "unable to sync quotas. will try again");
+
+                let mut counters = self.counters.lock();
+
+                for (k, pending) in batch {
+                    if let Some(x) = counters.get_mut(&k) {
+                        x.pending += pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Add each pending count to redis. Returns the new totals in the same order.
+/// Counters without anything pending still get their total from the other servers
+async fn sync_redis(
+    redis_pool: &RedisPool,
+    batch: &[(CounterKey, u64)],
+) -> anyhow::Result<Vec<u64>> {
+    let mut pipe = redis::pipe();
+
+    for (key, pending) in batch.iter() {
+        let redis_key = key.redis_key();
+
+        let expire_at = key.period.reset(key.start).timestamp() + REDIS_GRACE_SECS;
+
+        pipe.incr(&redis_key, *pending)
+            .expire_at(&redis_key, expire_at as usize)
+            .ignore();
+    }
+
+    let mut conn = redis_pool
+        .get()
+        .await
+        .context("get redis connection for quotas")?;
+
+    let totals: Vec<u64> = pipe
+        .query_async(&mut *conn)
+        .await
+        .context("cannot increment quotas")?;
+
+    Ok(totals)
+}
+
+async fn sync_loop(quotas: Weak<Quotas>, sync_now: Arc<Notify>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        // a key that is new or has counted a lot locally doesn't wait for the next tick
+        select! {
+            _ = interval.tick() => {}
+            _ = sync_now.notified() => {}
+        }
+
+        let Some(quotas) = quotas.upgrade() else {
+            break;
+        };
+
+        quotas.sync().await;
+    }
+
+    trace!("quota sync loop exited");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(per_day: Option<u64>, per_month: Option<u64>) -> AuthorizationChecks {
+        AuthorizationChecks {
+            rpc_secret_key_id: NonZeroU64::new(1),
+            max_requests_per_day: per_day,
+            max_requests_per_month: per_month,
+            ..Default::default()
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn periods() {
+        let now = utc("2023-12-31T15:04:05Z");
+
+        assert_eq!(QuotaPeriod::Day.start(now), utc("2023-12-31T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Day.reset(now), utc("2024-01-01T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.start(now), utc("2023-12-01T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.reset(now), utc("2024-01-01T00:00:00Z"));
+
+        let now = utc("2024-02-29T23:59:59Z");
+
+        assert_eq!(QuotaPeriod::Day.reset(now), utc("2024-03-01T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.start(now), utc("2024-02-01T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.reset(now), utc("2024-03-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn daily_quota() {
+        let quotas = Quotas::new(100, None);
+        let checks = checks(Some(3), None);
+
+        let now = utc("2023-12-08T12:00:00Z");
+
+        for _ in 0..3 {
+            quotas.count(&checks, now).unwrap();
+        }
+
+        assert_eq!(
+            quotas.count(&checks, now),
+            Err(QuotaExceeded {
+                period: QuotaPeriod::Day,
+                limit: 3,
+                reset: utc("2023-12-09T00:00:00Z"),
+            })
+        );
+        assert_eq!(quotas.exceeded.load(Ordering::Relaxed), 1);
+
+        // a new day
+        quotas.count(&checks, utc("2023-12-09T00:00:00Z")).unwrap();
+    }
+
+    #[test]
+    fn used_up_quota_counts_nothing() {
+        let quotas = Quotas::new(100, None);
+
+        let now = utc("2023-12-08T12:00:00Z");
+
+        quotas.count(&checks(Some(1), Some(2)), now).unwrap();
+
+        // the daily quota is used up. the monthly quota shouldn't lose anything for it
+        assert!(quotas.count(&checks(Some(1), Some(2)), now).is_err());
+        quotas.count(&checks(None, Some(2)), now).unwrap();
+
+        let err = quotas.count(&checks(None, Some(2)), now).unwrap_err();
+        assert_eq!(err.period, QuotaPeriod::Month);
+        assert_eq!(err.reset, utc("2024-01-01T00:00:00Z"));
+
+        // keys without quotas and anonymous users are never counted
+        quotas.count(&checks(None, None), now).unwrap();
+        quotas.count(&AuthorizationChecks::default(), now).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_sync() {
+        let quotas = Quotas::new(100, None);
+        let checks = checks(Some(10), Some(100));
+
+        quotas.count(&checks, Utc::now()).unwrap();
+        quotas.count(&checks, Utc::now()).unwrap();
+
+        quotas.sync().await;
+
+        let usage = quotas.usage(&checks).await.unwrap();
+
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].period, QuotaPeriod::Day);
+        assert_eq!(usage[0].used, 2);
+        assert_eq!(usage[0].remaining, 8);
+        assert_eq!(usage[1].period, QuotaPeriod::Month);
+        assert_eq!(usage[1].remaining, 98);
+
+        let counters = quotas.counters.lock();
+        assert!(counters.values().all(|x| x.synced == 2 && x.pending == 0));
+    }
+}
@@ -0,0 +1,89 @@
+//! How much of a user's `max_requests_per_period` each method uses.
+//!
+//! Without any weights, every request counts once. Authorizing a request counts that first one before the body is read.
+//! Once the methods are known, anything heavier counts the rest against the same limit.
+
+use crate::jsonrpc::JsonRpcRequestEnum;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+/// Method names to weights. A key ending in `*` matches every method that starts with the rest of it, and the longest
+/// match wins. `"*"` alone sets the weight for methods that match nothing else. Unlisted methods weigh 1.
+/// These can be changed without restarting.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct RateLimitWeights(HashMap<String, u64>);
+
+impl RateLimitWeights {
+    pub fn weight(&self, method: &str) -> u64 {
+        if let Some(x) = self.0.get(method) {
+            return *x;
+        }
+
+        self.0
+            .iter()
+            .filter_map(|(k, v)| {
+                let prefix = k.strip_suffix('*')?;
+
+                method.starts_with(prefix).then_some((prefix.len(), *v))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, v)| v)
+            .unwrap_or(1)
+    }
+
+    /// A batch is the sum of its requests
+    pub fn request_weight(&self, request: &JsonRpcRequestEnum) -> u64 {
+        match request {
+            JsonRpcRequestEnum::Single(x) => self.weight(&x.method),
+            JsonRpcRequestEnum::Batch(x) => x
+                .iter()
+                .fold(0u64, |acc, x| acc.saturating_add(self.weight(&x.method))),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopConfig;
+    use serde_json::json;
+
+    #[test]
+    fn weights() {
+        let x = TopConfig::from_toml(
+            r#"
+            [app]
+            chain_id = 1
+
+            [app.rate_limit_weights]
+            eth_getLogs = 20
+            "debug_*" = 10
+            "debug_trace*" = 50
+            "*" = 2
+            "#,
+        )
+        .unwrap();
+
+        let weights = &x.app.rate_limit_weights;
+
+        assert_eq!(weights.weight("eth_getLogs"), 20);
+        assert_eq!(weights.weight("debug_getBadBlocks"), 10);
+        assert_eq!(weights.weight("debug_traceTransaction"), 50);
+        assert_eq!(weights.weight("eth_blockNumber"), 2);
+
+        assert_eq!(RateLimitWeights::default().weight("eth_getLogs"), 1);
+
+        let batch: JsonRpcRequestEnum = serde_json::from_value(json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{}]},
+            {"jsonrpc": "2.0", "id": 2, "method": "eth_blockNumber", "params": []},
+        ]))
+        .unwrap();
+
+        assert_eq!(weights.request_weight(&batch), 22);
+    }
+}
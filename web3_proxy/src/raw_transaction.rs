@@ -0,0 +1,430 @@
+//! Decoding raw transactions from `eth_sendRawTransaction`.
+//!
+//! ethers can decode legacy, EIP-2930, and EIP-1559 transactions, but not EIP-4844 blob transactions.
+//! Blob transactions are sent in their "network" form, which wraps the signed transaction with the blobs, commitments, and proofs.
+//! We only need the hash and a few fields, so we validate the wrapper and hash the (small) canonical transaction.
+//! The blobs are never copied out of the request.
+
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::JsonRpcErrorData;
+use crate::response_cache::ForwardedResponse;
+use ethers::types::{Transaction, TxHash, H256, U256, U64};
+use ethers::utils::keccak256;
+use ethers::utils::rlp::{Decodable, Rlp};
+use serde_json::value::RawValue;
+use std::sync::Arc;
+
+/// EIP-4844 transaction type
+pub const BLOB_TX_TYPE: u8 = 0x03;
+
+/// every blob is exactly this many bytes
+pub const BYTES_PER_BLOB: usize = 4096 * 32;
+
+/// kzg commitments and proofs are compressed BLS12-381 points
+pub const BYTES_PER_COMMITMENT: usize = 48;
+
+/// blob versioned hashes start with this version byte
+pub const VERSIONED_HASH_VERSION_KZG: u8 = 0x01;
+
+/// number of fields in the signed blob transaction payload
+const BLOB_TX_FIELDS: usize = 14;
+
+/// every client has its own way of saying that it already has a transaction. it was still sent
+const ALREADY_KNOWN: [&str; 5] = [
+    "already known",
+    "already imported",
+    "known transaction",
+    "existing tx with same hash",
+    "already exists",
+];
+
+/// rejections that tell the sender what to fix
+const SPECIFIC_REJECTIONS: [&str; 10] = [
+    "nonce too low",
+    "nonce too high",
+    "replacement transaction underpriced",
+    "transaction underpriced",
+    "insufficient funds",
+    "intrinsic gas too low",
+    "exceeds block gas limit",
+    "less than block base fee",
+    "exceeds the configured cap",
+    "invalid sender",
+];
+
+/// The parts of a raw transaction that we care about
+#[derive(Clone, Debug)]
+pub struct RawTransaction {
+    pub tx_type: u8,
+    pub hash: TxHash,
+    pub chain_id: Option<U64>,
+    pub nonce: U256,
+    /// only set for blob transactions
+    pub blob_versioned_hashes: Vec<H256>,
+    /// true if the request included the blobs, commitments, and proofs
+    pub has_sidecar: bool,
+}
+
+impl RawTransaction {
+    pub fn decode(bytes: &[u8]) -> Web3ProxyResult<Self> {
+        match bytes.first() {
+            None => Err(Web3ProxyError::BadRequest("empty bytes".into())),
+            Some(&BLOB_TX_TYPE) => Self::decode_blob(&bytes[1..]),
+            Some(&tx_type) => {
+                let rlp = Rlp::new(bytes);
+
+                let tx = Transaction::decode(&rlp).map_err(|_| {
+                    Web3ProxyError::BadRequest("failed to parse rlp into transaction".into())
+                })?;
+
+                Ok(Self {
+                    // legacy transactions are an rlp list and have no type byte
+                    tx_type: if tx_type >= 0xc0 { 0 } else { tx_type },
+                    hash: tx.hash(),
+                    chain_id: tx.chain_id.map(|x| x.as_u64().into()),
+                    nonce: tx.nonce,
+                    blob_versioned_hashes: vec![],
+                    has_sidecar: false,
+                })
+            }
+        }
+    }
+
+    /// `payload` is everything after the type byte. It is either:
+    /// - canonical: `rlp([chain_id, nonce, ..., blob_versioned_hashes, y_parity, r, s])`
+    /// - network: `rlp([canonical, blobs, commitments, proofs])`
+    fn decode_blob(payload: &[u8]) -> Web3ProxyResult<Self> {
+        let bad = |msg: &'static str| Web3ProxyError::BadRequest(msg.into());
+
+        let rlp = Rlp::new(payload);
+
+        if !rlp.is_list() {
+            return Err(bad("blob transaction is not an rlp list"));
+        }
+
+        let item_count = rlp
+            .item_count()
+            .map_err(|_| bad("invalid blob transaction rlp"))?;
+
+        let (tx, sidecar) = match item_count {
+            BLOB_TX_FIELDS => (rlp, None),
+            4 => {
+                let tx = rlp.at(0).map_err(|_| bad("invalid blob transaction rlp"))?;
+
+                (tx, Some(rlp))
+            }
+            _ => return Err(bad("unexpected number of fields in blob transaction")),
+        };
+
+        if tx.item_count().ok() != Some(BLOB_TX_FIELDS) {
+            return Err(bad("unexpected number of fields in blob transaction"));
+        }
+
+        let chain_id: U64 = tx
+            .val_at(0)
+            .map_err(|_| bad("invalid blob transaction chain_id"))?;
+
+        let nonce: U256 = tx
+            .val_at(1)
+            .map_err(|_| bad("invalid blob transaction nonce"))?;
+
+        // blob transactions cannot create contracts
+        let to = tx
+            .at(5)
+            .and_then(|x| x.data().map(|x| x.len()))
+            .map_err(|_| bad("invalid blob transaction to"))?;
+
+        if to != 20 {
+            return Err(bad("blob transactions must have a to address"));
+        }
+
+        let blob_versioned_hashes: Vec<H256> = tx
+            .list_at(10)
+            .map_err(|_| bad("invalid blob versioned hashes"))?;
+
+        if blob_versioned_hashes.is_empty() {
+            return Err(bad("blob transactions must have at least one blob"));
+        }
+
+        if blob_versioned_hashes
+            .iter()
+            .any(|x| x.as_bytes()[0] != VERSIONED_HASH_VERSION_KZG)
+        {
+            return Err(bad("unsupported blob versioned hash version"));
+        }
+
+        if let Some(sidecar) = sidecar.as_ref() {
+            Self::validate_sidecar(sidecar, blob_versioned_hashes.len())?;
+        }
+
+        // the hash never includes the sidecar
+        let mut canonical = Vec::with_capacity(1 + tx.as_raw().len());
+        canonical.push(BLOB_TX_TYPE);
+        canonical.extend_from_slice(tx.as_raw());
+
+        let hash = keccak256(&canonical).into();
+
+        Ok(Self {
+            tx_type: BLOB_TX_TYPE,
+            hash,
+            chain_id: Some(chain_id),
+            nonce,
+            blob_versioned_hashes,
+            has_sidecar: sidecar.is_some(),
+        })
+    }
+
+    /// check the shape of the blobs, commitments, and proofs without copying them
+    fn validate_sidecar(sidecar: &Rlp, num_blobs: usize) -> Web3ProxyResult<()> {
+        let bad = |msg: &'static str| Web3ProxyError::BadRequest(msg.into());
+
+        for (i, expected_len) in [
+            (1, BYTES_PER_BLOB),
+            (2, BYTES_PER_COMMITMENT),
+            (3, BYTES_PER_COMMITMENT),
+        ] {
+            let items = sidecar.at(i).map_err(|_| bad("invalid blob sidecar rlp"))?;
+
+            if items.item_count().ok() != Some(num_blobs) {
+                return Err(bad("blob sidecar does not match blob versioned hashes"));
+            }
+
+            for item in items.iter() {
+                if item.data().map(|x| x.len()).ok() != Some(expected_len) {
+                    return Err(bad("blob sidecar item has the wrong length"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_blob(&self) -> bool {
+        self.tx_type == BLOB_TX_TYPE
+    }
+}
+
+/// true if the backend says it already has the transaction
+pub fn is_already_known(error: &JsonRpcErrorData) -> bool {
+    // some backends answer a duplicate with an empty error
+    if error.message.is_empty() {
+        return true;
+    }
+
+    let message = error.message.to_lowercase();
+
+    ALREADY_KNOWN.iter().any(|x| message.contains(x))
+}
+
+/// None if the backend accepted the transaction (or already had it).
+/// Otherwise, how useful its rejection is to the sender. Higher is better.
+/// When every backend rejects a transaction, the highest ranked rejection is returned.
+pub fn rejection_rank(response: &Web3ProxyResult<ForwardedResponse<Arc<RawValue>>>) -> Option<u8> {
+    let error_data = match response {
+        Ok(ForwardedResponse::Result { .. }) => return None,
+        Ok(ForwardedResponse::RpcError { error_data, .. }) => error_data,
+        // timeouts and connection errors say nothing about the transaction
+        Err(_) => return Some(0),
+    };
+
+    if is_already_known(error_data) {
+        return None;
+    }
+
+    let message = error_data.message.to_lowercase();
+
+    if SPECIFIC_REJECTIONS.iter().any(|x| message.contains(x)) {
+        Some(3)
+    } else if error_data.code == -32603 || message.contains("internal") {
+        Some(1)
+    } else {
+        Some(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use ethers::types::{Address, Eip1559TransactionRequest};
+    use ethers::utils::rlp::RlpStream;
+    use ethers::{prelude::LocalWallet, signers::Signer};
+
+    /// rlp of an unsigned-looking but well formed blob transaction payload
+    fn blob_tx_body(num_blobs: usize) -> Vec<u8> {
+        let mut s = RlpStream::new_list(BLOB_TX_FIELDS);
+
+        s.append(&U64::from(1)); // chain_id
+        s.append(&U256::from(7)); // nonce
+        s.append(&U256::from(1_000_000_000u64)); // max_priority_fee_per_gas
+        s.append(&U256::from(30_000_000_000u64)); // max_fee_per_gas
+        s.append(&U256::from(21_000)); // gas_limit
+        s.append(&Address::repeat_byte(0x42)); // to
+        s.append(&U256::zero()); // value
+        s.append_empty_data(); // data
+        s.begin_list(0); // access_list
+        s.append(&U256::from(1_000_000_000u64)); // max_fee_per_blob_gas
+
+        let mut versioned_hash = H256::repeat_byte(0x11);
+        versioned_hash.0[0] = VERSIONED_HASH_VERSION_KZG;
+        s.append_list::<H256, H256>(&vec![versioned_hash; num_blobs]);
+
+        s.append(&0u8); // y_parity
+        s.append(&U256::from(1)); // r
+        s.append(&U256::from(2)); // s
+
+        s.out().to_vec()
+    }
+
+    fn network_form(body: &[u8], num_blobs: usize) -> Vec<u8> {
+        let mut s = RlpStream::new_list(4);
+
+        s.append_raw(body, 1);
+
+        s.begin_list(num_blobs);
+        for _ in 0..num_blobs {
+            s.append(&vec![0u8; BYTES_PER_BLOB]);
+        }
+
+        for _ in 0..2 {
+            s.begin_list(num_blobs);
+            for _ in 0..num_blobs {
+                s.append(&vec![0u8; BYTES_PER_COMMITMENT]);
+            }
+        }
+
+        let mut out = vec![BLOB_TX_TYPE];
+        out.extend_from_slice(&s.out());
+        out
+    }
+
+    #[test]
+    fn blob_tx_network_and_canonical_forms() {
+        let body = blob_tx_body(2);
+
+        let mut canonical = vec![BLOB_TX_TYPE];
+        canonical.extend_from_slice(&body);
+
+        let network = network_form(&body, 2);
+
+        // the network form is megabytes. the canonical form is tiny
+        assert!(network.len() > 2 * BYTES_PER_BLOB);
+
+        let a = RawTransaction::decode(&canonical).unwrap();
+        let b = RawTransaction::decode(&network).unwrap();
+
+        // both forms dedupe to the same hash
+        assert_eq!(a.hash, b.hash);
+        assert_eq!(a.hash, H256::from(keccak256(&canonical)));
+
+        assert!(!a.has_sidecar);
+        assert!(b.has_sidecar);
+
+        assert!(b.is_blob());
+        assert_eq!(b.chain_id, Some(1.into()));
+        assert_eq!(b.nonce, 7.into());
+        assert_eq!(b.blob_versioned_hashes.len(), 2);
+    }
+
+    #[test]
+    fn blob_tx_mismatched_sidecar() {
+        let body = blob_tx_body(2);
+
+        let network = network_form(&body, 1);
+
+        assert!(RawTransaction::decode(&network).is_err());
+
+        // truncated payloads error instead of panicking
+        for i in [1, 10, network.len() / 2] {
+            assert!(RawTransaction::decode(&network[..i]).is_err());
+        }
+    }
+
+    #[test]
+    fn eip1559_tx() {
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse::<LocalWallet>()
+                .unwrap()
+                .with_chain_id(1u64);
+
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(Address::repeat_byte(0x42))
+            .nonce(3)
+            .gas(21_000)
+            .max_fee_per_gas(30_000_000_000u64)
+            .max_priority_fee_per_gas(1_000_000_000u64)
+            .chain_id(1)
+            .into();
+
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+        let raw = tx.rlp_signed(&signature);
+
+        let x = RawTransaction::decode(&raw).unwrap();
+
+        assert_eq!(x.tx_type, 2);
+        assert_eq!(x.hash, tx.hash(&signature));
+        assert_eq!(x.chain_id, Some(1.into()));
+        assert_eq!(x.nonce, 3.into());
+        assert!(!x.is_blob());
+    }
+
+    fn rejected(
+        code: i64,
+        message: &'static str,
+    ) -> Web3ProxyResult<ForwardedResponse<Arc<RawValue>>> {
+        Ok(JsonRpcErrorData {
+            code,
+            message: message.into(),
+            data: None,
+        }
+        .into())
+    }
+
+    #[test]
+    fn send_raw_transaction_rejections() {
+        let accepted: Web3ProxyResult<ForwardedResponse<Arc<RawValue>>> =
+            Ok(serde_json::json!(TxHash::zero()).into());
+        assert_eq!(rejection_rank(&accepted), None);
+
+        // duplicates were still sent
+        for message in [
+            "already known",
+            "ALREADY_EXISTS: already known",
+            "INTERNAL_ERROR: existing tx with same hash",
+            "Transaction with the same hash was already imported.",
+            "known transaction: 0x1234",
+            "",
+        ] {
+            assert_eq!(
+                rejection_rank(&rejected(-32000, message)),
+                None,
+                "{}",
+                message
+            );
+        }
+
+        let nonce = rejection_rank(&rejected(-32000, "nonce too low")).unwrap();
+        let generic = rejection_rank(&rejected(-32000, "something went wrong")).unwrap();
+        let internal = rejection_rank(&rejected(-32603, "internal error")).unwrap();
+        let transport = rejection_rank(&Err(Web3ProxyError::NoServersSynced)).unwrap();
+
+        assert!(nonce > generic);
+        assert!(generic > internal);
+        assert!(internal > transport);
+
+        assert_eq!(
+            rejection_rank(&rejected(-32000, "replacement transaction underpriced")),
+            Some(nonce)
+        );
+        assert_eq!(
+            rejection_rank(&rejected(
+                -32000,
+                "insufficient funds for gas * price + value"
+            )),
+            Some(nonce)
+        );
+    }
+}
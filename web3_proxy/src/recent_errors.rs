@@ -0,0 +1,147 @@
+//! The last few errors that each rpc key received.
+//!
+//! Most "my requests are failing" tickets can be answered by the user if they can see the errors themselves.
+//! These are only kept in memory. Request params might be private, so only the method and the error are stored.
+
+use crate::jsonrpc::{self, ResponsePayload, ValidatedRequest};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use moka::future::{Cache, CacheBuilder};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// longer messages are cut off
+pub const MAX_MESSAGE_CHARS: usize = 200;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ErrorSample {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    /// the http status code
+    pub status: u16,
+    /// the jsonrpc error code
+    pub code: i64,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+/// A small ring buffer of errors for every recently active rpc key
+pub struct RecentErrors {
+    by_key: Cache<u64, Arc<Mutex<VecDeque<ErrorSample>>>>,
+    per_key: usize,
+}
+
+impl RecentErrors {
+    /// None if `per_key` is 0
+    pub fn new(max_keys: u64, per_key: usize) -> Option<Self> {
+        if per_key == 0 {
+            return None;
+        }
+
+        let by_key = CacheBuilder::new(max_keys).name("recent_errors").build();
+
+        Some(Self { by_key, per_key })
+    }
+
+    pub fn per_key(&self) -> usize {
+        self.per_key
+    }
+
+    /// save an error that is about to be sent to the user. requests without an rpc key are ignored
+    pub async fn record(
+        &self,
+        web3_request: &ValidatedRequest,
+        status: StatusCode,
+        response: &jsonrpc::SingleResponse,
+    ) {
+        let Some(rpc_key_id) = web3_request.authorization.checks.rpc_secret_key_id else {
+            return;
+        };
+
+        let jsonrpc::SingleResponse::Parsed(response) = response else {
+            return;
+        };
+
+        let ResponsePayload::Error { error } = &response.payload else {
+            return;
+        };
+
+        let sample = ErrorSample {
+            timestamp: Utc::now(),
+            method: web3_request.inner.method().to_string(),
+            status: status.as_u16(),
+            code: error.code,
+            message: error.message.chars().take(MAX_MESSAGE_CHARS).collect(),
+            request_id: web3_request.request_id.clone(),
+        };
+
+        self.push(rpc_key_id.get(), sample).await;
+    }
+
+    async fn push(&self, rpc_key_id: u64, sample: ErrorSample) {
+        let per_key = self.per_key;
+
+        let samples = self
+            .by_key
+            .get_with(rpc_key_id, async move {
+                Arc::new(Mutex::new(VecDeque::with_capacity(per_key)))
+            })
+            .await;
+
+        let mut samples = samples.lock();
+
+        if samples.len() >= per_key {
+            samples.pop_front();
+        }
+
+        samples.push_back(sample);
+    }
+
+    /// newest first
+    pub async fn get(&self, rpc_key_id: u64) -> Vec<ErrorSample> {
+        match self.by_key.get(&rpc_key_id).await {
+            Some(x) => x.lock().iter().rev().cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(method: &str, message: String) -> ErrorSample {
+        ErrorSample {
+            timestamp: Utc::now(),
+            method: method.to_string(),
+            status: 502,
+            code: 502,
+            message,
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn ring_buffer() {
+        assert!(RecentErrors::new(10, 0).is_none());
+
+        let x = RecentErrors::new(10, 3).unwrap();
+
+        for i in 0..5 {
+            x.push(1, sample(&format!("method_{}", i), "oops".to_string()))
+                .await;
+        }
+
+        x.push(2, sample("other_key", "oops".to_string())).await;
+
+        let methods: Vec<_> = x.get(1).await.into_iter().map(|x| x.method).collect();
+
+        // only the newest are kept and they come back newest first
+        assert_eq!(methods, ["method_4", "method_3", "method_2"]);
+
+        assert_eq!(x.get(2).await.len(), 1);
+        assert!(x.get(3).await.is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! Counters for the last few minutes of requests.
+//!
+//! `/admin/summary` needs hit rates and top methods without asking influx. Every minute gets a bucket and only the
+//! last few buckets are kept. Everything here is in memory and only covers this server.
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use web3_proxy_client::types::{MethodCount, RecentRequestsSummary};
+
+pub const BUCKET_SECS: u64 = 60;
+pub const NUM_BUCKETS: usize = 5;
+
+/// a bucket stops counting new methods after this many. this keeps junk method names from using a lot of memory
+pub const MAX_METHODS_PER_BUCKET: usize = 1_000;
+
+pub const NUM_TOP_METHODS: usize = 10;
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// unix time divided by BUCKET_SECS
+    id: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    rate_limited: u64,
+    methods: HashMap<String, u64>,
+    /// requests that the user's tier was not entitled to
+    denied_by_tier: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct RecentRequests {
+    buckets: Mutex<[Bucket; NUM_BUCKETS]>,
+    in_flight: AtomicUsize,
+}
+
+/// Decrements the in-flight count when dropped
+pub struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn increment(methods: &mut HashMap<String, u64>, method: &str) {
+    if let Some(x) = methods.get_mut(method) {
+        *x += 1;
+    } else if methods.len() < MAX_METHODS_PER_BUCKET {
+        methods.insert(method.to_string(), 1);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+impl RecentRequests {
+    /// count a request until the guard is dropped
+    pub fn start(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        InFlightGuard(&self.in_flight)
+    }
+
+    /// requests that have started and not finished
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// a request with no backend rpcs was served from the cache
+    pub fn record_request(&self, method: &str, cache_hit: bool) {
+        self.record_request_at(now_secs(), method, cache_hit)
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.record_rate_limited_at(now_secs())
+    }
+
+    /// the user's tier doesn't include archive or trace requests
+    pub fn record_denied_by_tier(&self, method: &str) {
+        self.record_denied_by_tier_at(now_secs(), method)
+    }
+
+    pub fn summary(&self) -> RecentRequestsSummary {
+        self.summary_at(now_secs())
+    }
+
+    fn with_bucket<F: FnOnce(&mut Bucket)>(&self, now: u64, f: F) {
+        let id = now / BUCKET_SECS;
+
+        let mut buckets = self.buckets.lock();
+
+        let bucket = &mut buckets[id as usize % NUM_BUCKETS];
+
+        if bucket.id != id {
+            // this slot is from an older window. reuse it
+            *bucket = Bucket {
+                id,
+                ..Default::default()
+            };
+        }
+
+        f(bucket)
+    }
+
+    fn record_request_at(&self, now: u64, method: &str, cache_hit: bool) {
+        self.with_bucket(now, |bucket| {
+            if cache_hit {
+                bucket.cache_hits += 1;
+            } else {
+                bucket.cache_misses += 1;
+            }
+
+            increment(&mut bucket.methods, method);
+        })
+    }
+
+    fn record_denied_by_tier_at(&self, now: u64, method: &str) {
+        self.with_bucket(now, |bucket| increment(&mut bucket.denied_by_tier, method))
+    }
+
+    fn record_rate_limited_at(&self, now: u64) {
+        self.with_bucket(now, |bucket| bucket.rate_limited += 1)
+    }
+
+    fn summary_at(&self, now: u64) -> RecentRequestsSummary {
+        let id = now / BUCKET_SECS;
+        let oldest_id = (id + 1).saturating_sub(NUM_BUCKETS as u64);
+
+        let mut cache_hits = 0;
+        let mut cache_misses = 0;
+        let mut rate_limited = 0;
+        let mut methods = HashMap::<&str, u64>::new();
+        let mut denied_by_tier = HashMap::<&str, u64>::new();
+
+        let buckets = self.buckets.lock();
+
+        for bucket in buckets.iter().filter(|x| x.id >= oldest_id && x.id <= id) {
+            cache_hits += bucket.cache_hits;
+            cache_misses += bucket.cache_misses;
+            rate_limited += bucket.rate_limited;
+
+            for (method, count) in bucket.methods.iter() {
+                *methods.entry(method.as_str()).or_default() += count;
+            }
+
+            for (method, count) in bucket.denied_by_tier.iter() {
+                *denied_by_tier.entry(method.as_str()).or_default() += count;
+            }
+        }
+
+        let mut top_methods = sorted_counts(methods);
+        top_methods.truncate(NUM_TOP_METHODS);
+
+        let denied_by_tier = sorted_counts(denied_by_tier);
+
+        drop(buckets);
+
+        let requests = cache_hits + cache_misses;
+
+        let cache_hit_rate = if requests == 0 {
+            None
+        } else {
+            Some(cache_hits as f64 / requests as f64)
+        };
+
+        // the current bucket is only partly done
+        let window_secs = (id - oldest_id) * BUCKET_SECS + now % BUCKET_SECS + 1;
+
+        RecentRequestsSummary {
+            window_secs,
+            requests,
+            cache_hits,
+            cache_misses,
+            cache_hit_rate,
+            rate_limited,
+            rate_limited_per_second: rate_limited as f64 / window_secs as f64,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            top_methods,
+            denied_by_tier,
+        }
+    }
+}
+
+/// most requested first. ties are sorted by name so the output is stable
+fn sorted_counts(methods: HashMap<&str, u64>) -> Vec<MethodCount> {
+    let mut x: Vec<_> = methods
+        .into_iter()
+        .map(|(method, count)| MethodCount {
+            method: method.to_string(),
+            count,
+        })
+        .collect();
+
+    x.sort_unstable_by(|a, b| b.count.cmp(&a.count).then(a.method.cmp(&b.method)));
+
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_window() {
+        let x = RecentRequests::default();
+
+        let start = 1_000 * BUCKET_SECS;
+
+        // too old to be in the window once we are NUM_BUCKETS minutes later
+        x.record_request_at(start, "eth_call", false);
+
+        let now = start + NUM_BUCKETS as u64 * BUCKET_SECS;
+
+        for i in 0..12 {
+            x.record_request_at(now - BUCKET_SECS, &format!("method_{:02}", i), true);
+        }
+
+        x.record_request_at(now, "eth_blockNumber", true);
+        x.record_request_at(now, "eth_blockNumber", true);
+        x.record_request_at(now, "eth_getLogs", false);
+        x.record_rate_limited_at(now);
+        x.record_denied_by_tier_at(now, "trace_block");
+        x.record_denied_by_tier_at(now, "eth_call");
+        x.record_denied_by_tier_at(now, "eth_call");
+
+        let in_flight = x.start();
+
+        let summary = x.summary_at(now);
+
+        assert_eq!(summary.requests, 15);
+        assert_eq!(summary.cache_hits, 14);
+        assert_eq!(summary.cache_misses, 1);
+        assert_eq!(summary.rate_limited, 1);
+        assert_eq!(summary.in_flight, 1);
+        assert_eq!(
+            summary.window_secs,
+            (NUM_BUCKETS as u64 - 1) * BUCKET_SECS + 1
+        );
+
+        assert_eq!(summary.top_methods.len(), NUM_TOP_METHODS);
+        assert_eq!(
+            summary.top_methods[0],
+            MethodCount {
+                method: "eth_blockNumber".to_string(),
+                count: 2
+            }
+        );
+        assert!(summary.top_methods.iter().all(|x| x.method != "eth_call"));
+
+        // denied requests are not counted as requests
+        assert_eq!(
+            summary.denied_by_tier,
+            vec![
+                MethodCount {
+                    method: "eth_call".to_string(),
+                    count: 2
+                },
+                MethodCount {
+                    method: "trace_block".to_string(),
+                    count: 1
+                },
+            ]
+        );
+
+        drop(in_flight);
+
+        assert_eq!(x.summary_at(now).in_flight, 0);
+
+        // nothing is left once the window has moved on
+        let later = x.summary_at(now + NUM_BUCKETS as u64 * BUCKET_SECS);
+        assert_eq!(later.requests, 0);
+        assert_eq!(later.cache_hit_rate, None);
+        assert!(later.top_methods.is_empty());
+        assert!(later.denied_by_tier.is_empty());
+    }
+}
@@ -0,0 +1,446 @@
+//! A global limit on how many bytes of backend responses are buffered at the same time.
+//!
+//! Short responses are always read into memory. Long responses that are streamed straight through to the client don't
+//! buffer and skip the budget. Everything else that has to read a long response (caching, batches, signing, ...) must
+//! fit inside `response_buffer_max_bytes` along with every other body that is being read at the same time. When the
+//! budget is used up, the request waits up to `response_buffer_wait_ms` for room and then fails with an "overloaded"
+//! error instead of letting a burst of large responses run the process out of memory.
+
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use bytes::{Bytes, BytesMut};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+/// the semaphore counts KiB so that large budgets fit in its u32 permits
+const UNIT: u64 = 1024;
+
+fn units(bytes: u64) -> u32 {
+    bytes.div_ceil(UNIT).try_into().unwrap_or(u32::MAX)
+}
+
+#[derive(Debug)]
+pub struct ResponseBudget {
+    semaphore: Arc<Semaphore>,
+    max_bytes: u64,
+    wait: Duration,
+    /// bytes that are buffered right now
+    buffered: AtomicU64,
+    /// the most bytes that have been buffered at once
+    peak: AtomicU64,
+    /// requests that failed because the budget stayed full
+    rejected: AtomicU64,
+}
+
+/// Budget for one response body. Ask for more with `ensure` as the body grows. Everything is returned on drop.
+#[derive(Debug)]
+pub struct ResponseBudgetPermit {
+    budget: Arc<ResponseBudget>,
+    permit: Option<OwnedSemaphorePermit>,
+    units: u32,
+    bytes: u64,
+}
+
+impl ResponseBudget {
+    pub fn new(max_bytes: u64, wait: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(units(max_bytes) as usize)),
+            max_bytes,
+            wait,
+            buffered: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for room to buffer `bytes`
+    pub async fn acquire(self: &Arc<Self>, bytes: u64) -> Web3ProxyResult<ResponseBudgetPermit> {
+        let mut permit = ResponseBudgetPermit {
+            budget: self.clone(),
+            permit: None,
+            units: 0,
+            bytes: 0,
+        };
+
+        permit.ensure(bytes).await?;
+
+        Ok(permit)
+    }
+
+    pub fn buffered_bytes(&self) -> u64 {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_buffered_bytes(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    fn overloaded(&self, needed: u64) -> Web3ProxyError {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+
+        Web3ProxyError::ResponseBufferFull {
+            needed,
+            buffered: self.buffered_bytes(),
+            max: self.max_bytes,
+        }
+    }
+}
+
+impl ResponseBudgetPermit {
+    /// Make sure this permit covers at least `total_bytes`
+    pub async fn ensure(&mut self, total_bytes: u64) -> Web3ProxyResult<()> {
+        if total_bytes <= self.bytes {
+            return Ok(());
+        }
+
+        let budget = &self.budget;
+
+        let needed_units = units(total_bytes).saturating_sub(self.units);
+
+        if needed_units > 0 {
+            // this can never fit. don't make it wait
+            if total_bytes > budget.max_bytes {
+                return Err(budget.overloaded(total_bytes));
+            }
+
+            let more = match timeout(
+                budget.wait,
+                budget.semaphore.clone().acquire_many_owned(needed_units),
+            )
+            .await
+            {
+                Ok(x) => x?,
+                Err(_) => return Err(budget.overloaded(total_bytes)),
+            };
+
+            match self.permit.as_mut() {
+                Some(x) => x.merge(more),
+                None => self.permit = Some(more),
+            }
+
+            self.units += needed_units;
+        }
+
+        let buffered = budget
+            .buffered
+            .fetch_add(total_bytes - self.bytes, Ordering::Relaxed)
+            + total_bytes
+            - self.bytes;
+
+        budget.peak.fetch_max(buffered, Ordering::Relaxed);
+
+        self.bytes = total_bytes;
+
+        Ok(())
+    }
+}
+
+impl Drop for ResponseBudgetPermit {
+    fn drop(&mut self) {
+        self.budget
+            .buffered
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Read the rest of a long response into memory. `prefix` is whatever was already read to decide that the response
+/// is long. Keep the returned permit until the body is no longer needed.
+/// Reading stops with `ResponseTooLarge` as soon as the body is bigger than `max_bytes`.
+pub async fn read_body(
+    budget: Option<&Arc<ResponseBudget>>,
+    prefix: Bytes,
+    content_length: Option<u64>,
+    mut response: reqwest::Response,
+    max_bytes: u64,
+) -> Web3ProxyResult<(Bytes, Option<ResponseBudgetPermit>)> {
+    // with a Content-Length we can ask for everything at once. otherwise, ask as the chunks arrive
+    let expected = content_length.unwrap_or(prefix.len() as u64);
+
+    if expected > max_bytes {
+        return Err(Web3ProxyError::ResponseTooLarge {
+            bytes: expected,
+            max: max_bytes,
+        });
+    }
+
+    let mut permit = match budget {
+        Some(x) => Some(x.acquire(expected).await?),
+        None => None,
+    };
+
+    let mut buffer = BytesMut::with_capacity(expected as usize);
+    buffer.extend(prefix);
+
+    while let Some(chunk) = response.chunk().await? {
+        let needed = (buffer.len() + chunk.len()) as u64;
+
+        // the rest of the body is dropped unread
+        if needed > max_bytes {
+            return Err(Web3ProxyError::ResponseTooLarge {
+                bytes: needed,
+                max: max_bytes,
+            });
+        }
+
+        if let Some(permit) = permit.as_mut() {
+            permit.ensure(needed).await?;
+        }
+
+        buffer.extend(chunk);
+    }
+
+    Ok((buffer.freeze(), permit))
+}
+
+impl Serialize for ResponseBudget {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ResponseBudget", 4)?;
+
+        state.serialize_field("max_bytes", &self.max_bytes)?;
+        state.serialize_field("buffered_bytes", &self.buffered_bytes())?;
+        state.serialize_field("peak_buffered_bytes", &self.peak_buffered_bytes())?;
+        state.serialize_field("rejected", &self.rejected())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::process_rss_bytes;
+    use futures::future::join_all;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn synthetic_response(len: u64) -> reqwest::Response {
+        // a json string so that the body is valid if anyone parses it
+        let mut body = vec![b'a'; len as usize];
+        body[0] = b'"';
+        body[len as usize - 1] = b'"';
+
+        http::Response::new(body).into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_then_rejects() {
+        let budget = Arc::new(ResponseBudget::new(10 * UNIT, Duration::from_secs(1)));
+
+        let a = budget.acquire(8 * UNIT).await.unwrap();
+        assert_eq!(budget.buffered_bytes(), 8 * UNIT);
+
+        // no room. this waits and then gives up
+        let err = budget.acquire(4 * UNIT).await.unwrap_err();
+        assert!(matches!(err, Web3ProxyError::ResponseBufferFull { .. }));
+        assert_eq!(budget.rejected(), 1);
+
+        // something that could never fit fails without waiting
+        let err = budget.acquire(11 * UNIT).await.unwrap_err();
+        assert!(matches!(err, Web3ProxyError::ResponseBufferFull { .. }));
+
+        // a body that is already being read can grow into the free space
+        let mut b = budget.acquire(UNIT).await.unwrap();
+        b.ensure(2 * UNIT).await.unwrap();
+        assert_eq!(budget.buffered_bytes(), 10 * UNIT);
+
+        drop(a);
+        assert_eq!(budget.buffered_bytes(), 2 * UNIT);
+
+        let c = budget.acquire(8 * UNIT).await.unwrap();
+
+        drop(b);
+        drop(c);
+
+        assert_eq!(budget.buffered_bytes(), 0);
+        assert_eq!(budget.peak_buffered_bytes(), 10 * UNIT);
+    }
+
+    #[tokio::test]
+    async fn max_bytes() {
+        let budget = Arc::new(ResponseBudget::new(8 * MB, Duration::from_millis(100)));
+
+        // the Content-Length is enough to know
+        let err = read_body(
+            Some(&budget),
+            Bytes::new(),
+            Some(2 * MB),
+            synthetic_response(2 * MB),
+            MB,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Web3ProxyError::ResponseTooLarge { bytes, max: MB } if bytes == 2 * MB
+        ));
+
+        // without one, the body is counted as it is read
+        let err = read_body(
+            Some(&budget),
+            Bytes::new(),
+            None,
+            synthetic_response(2 * MB),
+            MB,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Web3ProxyError::ResponseTooLarge { max: MB, .. }
+        ));
+
+        // nothing is left buffered
+        assert_eq!(budget.buffered_bytes(), 0);
+
+        let (body, _permit) = read_body(
+            Some(&budget),
+            Bytes::new(),
+            None,
+            synthetic_response(MB),
+            MB,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body.len() as u64, MB);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flood_stays_bounded() {
+        let budget = Arc::new(ResponseBudget::new(8 * MB, Duration::from_millis(100)));
+
+        // far more than the budget if they were all buffered at once
+        let reads = (0..50).map(|_| {
+            let budget = budget.clone();
+
+            async move {
+                let (body, permit) = read_body(
+                    Some(&budget),
+                    Bytes::new(),
+                    Some(MB),
+                    synthetic_response(MB),
+                    u64::MAX,
+                )
+                .await?;
+
+                // pretend to parse it
+                tokio::time::sleep(Duration::from_millis(10)).await;
+
+                drop(permit);
+
+                Ok::<_, Web3ProxyError>(body.len() as u64)
+            }
+        });
+
+        let results = join_all(reads).await;
+
+        let succeeded = results.iter().filter(|x| x.is_ok()).count();
+
+        assert!(succeeded >= 8, "{}", succeeded);
+        assert!(budget.peak_buffered_bytes() <= 8 * MB);
+        assert_eq!(budget.buffered_bytes(), 0);
+        assert_eq!(succeeded as u64 + budget.rejected(), 50);
+    }
+
+    /// `cargo test -p web3_proxy --release -- --ignored response_flood_rss --nocapture`
+    ///
+    /// RSS belongs to the whole test process and depends on the allocator, so this is only run by hand.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn response_flood_rss() {
+        let max_bytes = 64 * MB;
+        let num = 200;
+        let len = 20 * MB;
+
+        // a backend that always answers with the same large body. it is only allocated once
+        let body = Bytes::from(vec![b'a'; len as usize]);
+
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || {
+                let body = body.clone();
+                async move { body }
+            }),
+        );
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+
+        let url = format!("http://{}/", server.local_addr());
+
+        tokio::spawn(server);
+
+        let budget = Arc::new(ResponseBudget::new(max_bytes, Duration::from_secs(1)));
+
+        let client = reqwest::Client::new();
+
+        let before = process_rss_bytes().unwrap();
+
+        let reads = (0..num).map(|_| {
+            let budget = budget.clone();
+            let client = client.clone();
+            let url = url.clone();
+
+            tokio::spawn(async move {
+                let response = client.get(url).send().await?;
+
+                let content_length = response.content_length();
+
+                let (body, permit) = read_body(
+                    Some(&budget),
+                    Bytes::new(),
+                    content_length,
+                    response,
+                    u64::MAX,
+                )
+                .await?;
+
+                tokio::time::sleep(Duration::from_millis(50)).await;
+
+                drop(body);
+                drop(permit);
+
+                Ok::<_, Web3ProxyError>(())
+            })
+        });
+
+        let mut peak_rss = before;
+
+        let mut reads = tokio::spawn(join_all(reads));
+
+        loop {
+            tokio::select! {
+                _ = &mut reads => break,
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    peak_rss = peak_rss.max(process_rss_bytes().unwrap());
+                }
+            }
+        }
+
+        let growth = peak_rss.saturating_sub(before);
+
+        println!(
+            "{} x {} MB responses. budget {} MB. rss grew {} MB. {} rejected",
+            num,
+            len / MB,
+            max_bytes / MB,
+            growth / MB,
+            budget.rejected(),
+        );
+
+        assert!(budget.peak_buffered_bytes() <= max_bytes);
+
+        // without the budget this would be num * len (4 GB). allow plenty for socket buffers and allocator slack
+        assert!(growth < 4 * max_bytes, "rss grew {} MB", growth / MB);
+    }
+}
@@ -11,10 +11,12 @@ use ethers::{
 };
 use hashbrown::hash_map::DefaultHashBuilder;
 use moka::future::Cache;
+use moka::Expiry;
 use serde_json::value::{to_raw_value, RawValue};
 use std::{
     hash::{BuildHasher, Hash, Hasher},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 #[derive(Clone, Debug, Eq, From)]
@@ -92,7 +94,39 @@ impl<'a> JsonRpcQueryCacheKey<'a> {
     }
 }
 
-pub type JsonRpcResponseCache = Cache<u64, ForwardedResponse<Arc<RawValue>>>;
+pub type JsonRpcResponseCache = Cache<u64, CachedResponse>;
+
+/// A response in the response cache
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub response: ForwardedResponse<Arc<RawValue>>,
+    /// from `response_cache_ttls`. None leaves it to the cache's idle timeout
+    pub ttl: Option<Duration>,
+}
+
+/// Responses for methods in `response_cache_ttls` expire after their method's ttl
+pub struct CachedResponseExpiry;
+
+impl Expiry<u64, CachedResponse> for CachedResponseExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &u64,
+        value: &CachedResponse,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        value.ttl
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &u64,
+        value: &CachedResponse,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.ttl
+    }
+}
 
 /// TODO: think about this more. there is a lot of overlap with ParsedResponse
 #[derive(Clone, Debug)]
@@ -143,6 +177,31 @@ impl ForwardedResponse<Arc<RawValue>> {
     }
 }
 
+impl ForwardedResponse<Arc<RawValue>> {
+    /// compare the parsed json instead of the raw bytes. whitespace and key order do not matter
+    pub fn canonical_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Result { value: a, .. }, Self::Result { value: b, .. }) => {
+                if a.get() == b.get() {
+                    return true;
+                }
+
+                match (
+                    serde_json::from_str::<serde_json::Value>(a.get()),
+                    serde_json::from_str::<serde_json::Value>(b.get()),
+                ) {
+                    (Ok(a), Ok(b)) => a == b,
+                    _ => false,
+                }
+            }
+            (Self::RpcError { error_data: a, .. }, Self::RpcError { error_data: b, .. }) => {
+                a.code == b.code && a.message == b.message && a.data == b.data
+            }
+            _ => false,
+        }
+    }
+}
+
 impl From<ResponsePayload<Arc<RawValue>>> for ForwardedResponse<Arc<RawValue>> {
     fn from(value: ResponsePayload<Arc<RawValue>>) -> Self {
         match value {
@@ -335,7 +394,7 @@ impl JsonRpcResponseWeigher {
 
 #[cfg(test)]
 mod tests {
-    use super::ForwardedResponse;
+    use super::{CachedResponse, CachedResponseExpiry, ForwardedResponse, JsonRpcResponseCache};
     use crate::response_cache::JsonRpcResponseWeigher;
     use moka::future::{Cache, CacheBuilder};
     use serde_json::value::RawValue;
@@ -398,4 +457,61 @@ mod tests {
         // now it should be empty
         assert!(test_cache.get(&2).await.is_none());
     }
+
+    #[tokio::test]
+    async fn test_response_bigger_than_the_cache() {
+        let weight_capacity = 1_000;
+
+        let weigher = JsonRpcResponseWeigher(weight_capacity as u32);
+
+        let test_cache: JsonRpcResponseCache = CacheBuilder::new(weight_capacity)
+            .weigher(move |k, v: &CachedResponse| weigher.weigh(k, &v.response))
+            .expire_after(CachedResponseExpiry)
+            .build();
+
+        let response = |num_bytes| CachedResponse {
+            response: ForwardedResponse::Result {
+                value: Box::<RawValue>::default().into(),
+                num_bytes,
+            },
+            ttl: None,
+        };
+
+        test_cache.insert(0, response(600)).await;
+        test_cache.insert(1, response(weight_capacity * 10)).await;
+
+        test_cache.run_pending_tasks().await;
+
+        // the huge response is gone and didn't push out the one that fit
+        assert!(test_cache.get(&1).await.is_none());
+        assert!(test_cache.get(&0).await.is_some());
+        assert!(test_cache.weighted_size() <= weight_capacity);
+    }
+
+    #[tokio::test]
+    async fn test_method_ttls() {
+        let test_cache: JsonRpcResponseCache = CacheBuilder::new(1_000)
+            .expire_after(CachedResponseExpiry)
+            .build();
+
+        let response = |ttl| CachedResponse {
+            response: ForwardedResponse::Result {
+                value: Box::<RawValue>::default().into(),
+                num_bytes: 1,
+            },
+            ttl,
+        };
+
+        test_cache
+            .insert(0, response(Some(Duration::from_millis(50))))
+            .await;
+        test_cache.insert(1, response(None)).await;
+
+        assert!(test_cache.get(&0).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(test_cache.get(&0).await.is_none());
+        assert!(test_cache.get(&1).await.is_some());
+    }
 }
@@ -0,0 +1,463 @@
+//! Operator-defined fixes for backends that answer in non-standard ways.
+//!
+//! Some vendors return `"0x"` where everyone else returns `null`, or word their revert errors differently. Depending
+//! on which backend served a request, users would see different answers for the same thing. A rule matches one method
+//! (and optionally only some backends), looks at one value in the response with a JSON pointer, and replaces it if it
+//! matches. Rewrites happen as soon as the backend answers, so the response cache only ever sees the canonical form.
+//!
+//! Responses that are too large to be read into memory are streamed to the client as-is.
+
+use crate::config::TopConfig;
+use crate::jsonrpc::{self, ResponsePayload};
+use hashbrown::{HashMap, HashSet};
+use regex::Regex;
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{trace, warn};
+
+/// One rule in `[[app.response_rewrites]]`. Exactly one of `equals` and `regex` must be set.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseRewriteConfig {
+    /// Unique. Used in logs and in the metrics
+    pub name: String,
+    /// The jsonrpc method this applies to
+    pub method: String,
+    /// Only apply to responses from these backends. Empty applies to every backend
+    #[serde(default)]
+    pub rpcs: Vec<String>,
+    /// Where the value is in the response. Starts with "/result" or "/error". "/error/message" is the error's message
+    pub pointer: String,
+    /// The value matches if it is exactly this
+    pub equals: Option<Value>,
+    /// The value matches if it is a string (or a number or bool) that matches this regex
+    pub regex: Option<String>,
+    /// What to put in place of the value. For regex rules, a string can use the captures, like "execution reverted: $1".
+    /// Leave it out to replace with null (toml can't write null)
+    #[serde(default)]
+    pub replace: Value,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Equals(Value),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct ResponseRewrite {
+    name: String,
+    rpcs: HashSet<String>,
+    pointer: String,
+    matcher: Matcher,
+    replace: Value,
+    applied: Arc<AtomicU64>,
+}
+
+impl ResponseRewrite {
+    fn applies_to(&self, rpc_name: &str) -> bool {
+        self.rpcs.is_empty() || self.rpcs.contains(rpc_name)
+    }
+
+    /// the value that should replace `x`. None if this rule doesn't match
+    fn replacement(&self, x: &Value) -> Option<Value> {
+        match &self.matcher {
+            Matcher::Equals(y) => (x == y).then(|| self.replace.clone()),
+            Matcher::Regex(re) => {
+                let haystack = match x {
+                    Value::String(x) => x.clone(),
+                    Value::Number(x) => x.to_string(),
+                    Value::Bool(x) => x.to_string(),
+                    _ => return None,
+                };
+
+                let captures = re.captures(&haystack)?;
+
+                match &self.replace {
+                    Value::String(template) => {
+                        let mut expanded = String::new();
+
+                        captures.expand(template, &mut expanded);
+
+                        Some(Value::String(expanded))
+                    }
+                    other => Some(other.clone()),
+                }
+            }
+        }
+    }
+}
+
+/// The compiled rules, grouped by method
+#[derive(Debug, Default)]
+pub struct ResponseRewrites {
+    by_method: HashMap<String, Vec<ResponseRewrite>>,
+    config: Vec<ResponseRewriteConfig>,
+}
+
+impl ResponseRewrites {
+    /// Check and compile the rules. Any mistake is an error so that a typo can't quietly turn a rule off.
+    /// Rules with the same name as one in `previous` keep counting from where it was.
+    pub fn try_new(
+        top_config: &TopConfig,
+        previous: Option<&ResponseRewrites>,
+    ) -> anyhow::Result<Self> {
+        let mut counters: HashMap<&str, Arc<AtomicU64>> = previous
+            .into_iter()
+            .flat_map(|x| x.by_method.values().flatten())
+            .map(|x| (x.name.as_str(), x.applied.clone()))
+            .collect();
+
+        let mut names = HashSet::new();
+        let mut by_method: HashMap<String, Vec<ResponseRewrite>> = HashMap::new();
+
+        for x in top_config.app.response_rewrites.iter() {
+            if !names.insert(x.name.as_str()) {
+                anyhow::bail!("response rewrite {:?} is defined more than once", x.name);
+            }
+
+            if !(x.pointer.starts_with("/result") || x.pointer.starts_with("/error")) {
+                anyhow::bail!(
+                    "response rewrite {:?} has pointer {:?}. it must start with \"/result\" or \"/error\"",
+                    x.name,
+                    x.pointer
+                );
+            }
+
+            let matcher = match (&x.equals, &x.regex) {
+                (Some(y), None) => Matcher::Equals(y.clone()),
+                (None, Some(y)) => Matcher::Regex(Regex::new(y).map_err(|err| {
+                    anyhow::anyhow!(
+                        "response rewrite {:?} has an invalid regex: {}",
+                        x.name,
+                        err
+                    )
+                })?),
+                _ => anyhow::bail!(
+                    "response rewrite {:?} needs exactly one of \"equals\" or \"regex\"",
+                    x.name
+                ),
+            };
+
+            for rpc in x.rpcs.iter() {
+                if !(top_config.balanced_rpcs.contains_key(rpc)
+                    || top_config.private_rpcs.contains_key(rpc)
+                    || top_config.bundler_4337_rpcs.contains_key(rpc))
+                {
+                    anyhow::bail!("response rewrite {:?} is for unknown rpc {:?}", x.name, rpc);
+                }
+            }
+
+            let applied = counters.remove(x.name.as_str()).unwrap_or_default();
+
+            by_method
+                .entry(x.method.clone())
+                .or_default()
+                .push(ResponseRewrite {
+                    name: x.name.clone(),
+                    rpcs: x.rpcs.iter().cloned().collect(),
+                    pointer: x.pointer.clone(),
+                    matcher,
+                    replace: x.replace.clone(),
+                    applied,
+                });
+        }
+
+        Ok(Self {
+            by_method,
+            config: top_config.app.response_rewrites.clone(),
+        })
+    }
+
+    /// the rules as they were configured
+    pub fn config(&self) -> &[ResponseRewriteConfig] {
+        &self.config
+    }
+
+    /// Rewrite a backend's response in place. Returns the names of the rules that changed it
+    pub fn apply<R: jsonrpc::JsonRpcResultData>(
+        &self,
+        rpc_name: &str,
+        method: &str,
+        response: &mut jsonrpc::SingleResponse<R>,
+    ) -> Vec<&str> {
+        let rules = match self.by_method.get(method) {
+            Some(x) => x,
+            None => return vec![],
+        };
+
+        if !rules.iter().any(|x| x.applies_to(rpc_name)) {
+            return vec![];
+        }
+
+        let response = match response {
+            jsonrpc::SingleResponse::Parsed(x) => x,
+            jsonrpc::SingleResponse::Stream(..) => return vec![],
+        };
+
+        let mut payload = match serde_json::to_value(&response.payload) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, "unable to check response for rewrites");
+                return vec![];
+            }
+        };
+
+        let mut applied = vec![];
+
+        for rule in rules.iter().filter(|x| x.applies_to(rpc_name)) {
+            if let Some(x) = payload.pointer_mut(&rule.pointer) {
+                if let Some(new) = rule.replacement(x) {
+                    trace!(rule=%rule.name, old=%x, %new, "rewriting response");
+
+                    *x = new;
+
+                    applied.push(rule);
+                }
+            }
+        }
+
+        if applied.is_empty() {
+            return vec![];
+        }
+
+        // a round trip through a string because `R` is usually a RawValue
+        match serde_json::to_string(&payload)
+            .and_then(|x| serde_json::from_str::<ResponsePayload<R>>(&x))
+        {
+            Ok(x) => response.payload = x,
+            Err(err) => {
+                let applied: Vec<_> = applied.iter().map(|x| x.name.as_str()).collect();
+
+                warn!(
+                    ?err,
+                    ?applied,
+                    "rewritten response is invalid. sending the original"
+                );
+
+                return vec![];
+            }
+        }
+
+        applied
+            .into_iter()
+            .map(|x| {
+                x.applied.fetch_add(1, Ordering::Relaxed);
+
+                x.name.as_str()
+            })
+            .collect()
+    }
+}
+
+/// how many times each rule has been applied. rules at 0 might not be needed anymore
+impl Serialize for ResponseRewrites {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let rules: Vec<_> = self.by_method.values().flatten().collect();
+
+        let mut map = serializer.serialize_map(Some(rules.len()))?;
+
+        for x in rules {
+            map.serialize_entry(&x.name, &x.applied.load(Ordering::Relaxed))?;
+        }
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn top_config(rules: Value) -> TopConfig {
+        serde_json::from_value(json!({
+            "app": {
+                "chain_id": 1,
+                "response_rewrites": rules,
+            },
+            "balanced_rpcs": {
+                "vendor_x": {},
+                "vendor_y": {},
+            },
+        }))
+        .unwrap()
+    }
+
+    fn response(payload: Value) -> jsonrpc::SingleResponse {
+        let mut x = payload.as_object().unwrap().clone();
+
+        x.insert("jsonrpc".into(), "2.0".into());
+        x.insert("id".into(), 1.into());
+
+        let x: jsonrpc::ParsedResponse = serde_json::from_value(Value::Object(x)).unwrap();
+
+        x.into()
+    }
+
+    fn payload(x: jsonrpc::SingleResponse) -> Value {
+        match x {
+            jsonrpc::SingleResponse::Parsed(x) => serde_json::to_value(x.payload).unwrap(),
+            jsonrpc::SingleResponse::Stream(..) => unimplemented!(),
+        }
+    }
+
+    #[test]
+    fn empty_hex_is_null() {
+        let rules = ResponseRewrites::try_new(
+            &top_config(json!([{
+                "name": "vendor_x_empty_receipt",
+                "method": "eth_getTransactionReceipt",
+                "rpcs": ["vendor_x"],
+                "pointer": "/result",
+                "equals": "0x",
+                "replace": null,
+            }])),
+            None,
+        )
+        .unwrap();
+
+        let mut x = response(json!({"result": "0x"}));
+        assert_eq!(
+            rules.apply("vendor_x", "eth_getTransactionReceipt", &mut x),
+            vec!["vendor_x_empty_receipt"]
+        );
+        assert_eq!(payload(x), json!({"result": null}));
+
+        // other backends, other methods, and other values are left alone
+        let mut x = response(json!({"result": "0x"}));
+        assert!(rules
+            .apply("vendor_y", "eth_getTransactionReceipt", &mut x)
+            .is_empty());
+        assert_eq!(payload(x), json!({"result": "0x"}));
+
+        let mut x = response(json!({"result": "0x"}));
+        assert!(rules
+            .apply("vendor_x", "eth_getStorageAt", &mut x)
+            .is_empty());
+
+        let mut x = response(json!({"result": {"status": "0x1"}}));
+        assert!(rules
+            .apply("vendor_x", "eth_getTransactionReceipt", &mut x)
+            .is_empty());
+
+        assert_eq!(
+            serde_json::to_value(&rules).unwrap(),
+            json!({"vendor_x_empty_receipt": 1})
+        );
+    }
+
+    #[test]
+    fn revert_message_regex() {
+        let rules = ResponseRewrites::try_new(
+            &top_config(json!([{
+                "name": "vendor_y_revert",
+                "method": "eth_call",
+                "pointer": "/error/message",
+                "regex": "^VM Exception while processing transaction: revert ?(.*)$",
+                "replace": "execution reverted: $1",
+            }])),
+            None,
+        )
+        .unwrap();
+
+        let mut x = response(json!({"error": {
+            "code": -32000,
+            "message": "VM Exception while processing transaction: revert not enough balance",
+            "data": "0x08c379a0",
+        }}));
+
+        assert_eq!(
+            rules.apply("vendor_y", "eth_call", &mut x),
+            vec!["vendor_y_revert"]
+        );
+
+        match &x {
+            jsonrpc::SingleResponse::Parsed(x) => match &x.payload {
+                ResponsePayload::Error { error } => {
+                    assert_eq!(error.code, -32000);
+                    assert_eq!(error.message, "execution reverted: not enough balance");
+                    assert_eq!(error.data, Some(json!("0x08c379a0")));
+                }
+                _ => panic!("should still be an error"),
+            },
+            _ => unimplemented!(),
+        }
+
+        // counts survive a reload
+        let reloaded = ResponseRewrites::try_new(
+            &top_config(json!([{
+                "name": "vendor_y_revert",
+                "method": "eth_call",
+                "pointer": "/error/message",
+                "regex": "^VM Exception while processing transaction: revert ?(.*)$",
+                "replace": "execution reverted: $1",
+            }])),
+            Some(&rules),
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&reloaded).unwrap(),
+            json!({"vendor_y_revert": 1})
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let rule = json!({
+            "name": "a",
+            "method": "eth_call",
+            "pointer": "/result",
+            "equals": "0x",
+            "replace": null,
+        });
+
+        assert!(ResponseRewrites::try_new(&top_config(json!([rule])), None).is_ok());
+
+        let mut invalid = vec![];
+
+        // duplicate name
+        invalid.push(json!([rule, rule]));
+
+        // pointer outside the payload
+        let mut x = rule.clone();
+        x["pointer"] = json!("/id");
+        invalid.push(json!([x]));
+
+        // both matchers
+        let mut x = rule.clone();
+        x["regex"] = json!("^0x$");
+        invalid.push(json!([x]));
+
+        // no matcher
+        let mut x = rule.clone();
+        x.as_object_mut().unwrap().remove("equals");
+        invalid.push(json!([x]));
+
+        // bad regex
+        let mut x = rule.clone();
+        x.as_object_mut().unwrap().remove("equals");
+        x["regex"] = json!("(");
+        invalid.push(json!([x]));
+
+        // unknown rpc
+        let mut x = rule.clone();
+        x["rpcs"] = json!(["vendor_z"]);
+        invalid.push(json!([x]));
+
+        for x in invalid {
+            assert!(
+                ResponseRewrites::try_new(&top_config(x.clone()), None).is_err(),
+                "{}",
+                x
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Optional signatures on responses so that downstream services can check that a response came from this proxy.
+//!
+//! Responses for keys with `sign_responses` get an HMAC-SHA256 over `{timestamp}.{request_id}.{body}`. The body is the
+//! canonical json (sorted keys and no whitespace) of the uncompressed response, so a cache that re-serializes the json
+//! doesn't break the signature, but changing any value does. The key comes from `response_signing_key` (usually set
+//! with `response_signing_key_file`). Consumers share that key and check responses with `verify` or the cli's
+//! `verify_response`.
+
+use ethers::utils::hex;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+
+/// hex of the HMAC-SHA256
+pub const SIGNATURE_HEADER: &str = "X-W3P-SIGNATURE";
+/// unix seconds when the response was signed
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "X-W3P-SIGNATURE-TIMESTAMP";
+/// the request id that is part of the signature
+pub const SIGNATURE_REQUEST_ID_HEADER: &str = "X-W3P-REQUEST-ID";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct ResponseSigner {
+    key: Arc<[u8]>,
+}
+
+impl fmt::Debug for ResponseSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseSigner(<redacted>)")
+    }
+}
+
+/// Sorted keys and no whitespace. serde_json's `Map` is a `BTreeMap` because we don't enable `preserve_order`.
+pub fn canonical_body(body: &[u8]) -> serde_json::Result<Vec<u8>> {
+    let x: Value = serde_json::from_slice(body)?;
+
+    serde_json::to_vec(&x)
+}
+
+impl ResponseSigner {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().into(),
+        }
+    }
+
+    fn mac(&self, timestamp: u64, request_id: &str, canonical_body: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("hmac accepts keys of any length");
+
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(request_id.as_bytes());
+        mac.update(b".");
+        mac.update(canonical_body);
+
+        mac
+    }
+
+    /// Sign a json response body. Returns the hex signature.
+    pub fn sign(
+        &self,
+        body: &[u8],
+        timestamp: u64,
+        request_id: &str,
+    ) -> serde_json::Result<String> {
+        let body = canonical_body(body)?;
+
+        let x = self.mac(timestamp, request_id, &body).finalize();
+
+        Ok(hex::encode(x.into_bytes()))
+    }
+
+    /// Check a signature from `SIGNATURE_HEADER`. The comparison is constant time.
+    pub fn verify(&self, body: &[u8], timestamp: u64, request_id: &str, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature.trim_start_matches("0x")) else {
+            return false;
+        };
+
+        let Ok(body) = canonical_body(body) else {
+            return false;
+        };
+
+        self.mac(timestamp, request_id, &body)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Instant;
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = ResponseSigner::new("hunter2");
+
+        let body = br#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#;
+
+        let sig = signer.sign(body, 1_700_000_000, "req-1").unwrap();
+
+        assert!(signer.verify(body, 1_700_000_000, "req-1", &sig));
+
+        // re-serializing the json with different key order and whitespace doesn't matter
+        let reordered = br#"{ "result": "0x10", "id": 1, "jsonrpc": "2.0" }"#;
+        assert!(signer.verify(reordered, 1_700_000_000, "req-1", &sig));
+
+        // but everything else does
+        let tampered = br#"{"jsonrpc":"2.0","id":1,"result":"0x11"}"#;
+        assert!(!signer.verify(tampered, 1_700_000_000, "req-1", &sig));
+        assert!(!signer.verify(body, 1_700_000_001, "req-1", &sig));
+        assert!(!signer.verify(body, 1_700_000_000, "req-2", &sig));
+        assert!(!ResponseSigner::new("hunter3").verify(body, 1_700_000_000, "req-1", &sig));
+        assert!(!signer.verify(body, 1_700_000_000, "req-1", "not hex"));
+    }
+
+    /// `cargo test -p web3_proxy --release -- --ignored sign_get_block_timing --nocapture`
+    #[test]
+    #[ignore]
+    fn sign_get_block_timing() {
+        // a typical eth_getBlockByNumber response without full transactions
+        let transactions: Vec<_> = (0..200u64).map(|x| format!("0x{:064x}", x)).collect();
+
+        let body = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "baseFeePerGas": "0x3b9aca00",
+                "difficulty": "0x0",
+                "extraData": "0x",
+                "gasLimit": "0x1c9c380",
+                "gasUsed": "0xe4e1c0",
+                "hash": format!("0x{:064x}", 1),
+                "logsBloom": format!("0x{}", "0".repeat(512)),
+                "miner": format!("0x{:040x}", 2),
+                "mixHash": format!("0x{:064x}", 3),
+                "nonce": "0x0000000000000000",
+                "number": "0x112a880",
+                "parentHash": format!("0x{:064x}", 4),
+                "receiptsRoot": format!("0x{:064x}", 5),
+                "sha3Uncles": format!("0x{:064x}", 6),
+                "size": "0x1d3a4",
+                "stateRoot": format!("0x{:064x}", 7),
+                "timestamp": "0x655f2b17",
+                "totalDifficulty": "0xc70d815d562d3cfa955",
+                "transactions": transactions,
+                "transactionsRoot": format!("0x{:064x}", 8),
+                "uncles": [],
+                "withdrawals": [],
+            },
+        }))
+        .unwrap();
+
+        let signer = ResponseSigner::new("hunter2");
+
+        let n = 1_000u32;
+
+        let start = Instant::now();
+        for _ in 0..n {
+            signer.sign(&body, 1_700_000_000, "req-1").unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "signed a {} byte block in {:?} on average",
+            body.len(),
+            elapsed / n
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! When a backend fails a request, the next best backend gets a chance.
+//!
+//! Connection errors, timeouts, and 5xx responses come back as `Err` and are always worth another backend.
+//! Some jsonrpc errors are the backend's fault too ("internal error", rate limits). Those are retried, but if every
+//! attempt fails, the client still sees the backend's error instead of one of ours.
+
+use crate::config::AppConfig;
+use crate::jsonrpc::JsonRpcErrorData;
+use tokio::time::{Duration, Instant};
+
+/// Methods that change something. A retry could do it twice
+const SIDE_EFFECTS: [&str; 9] = [
+    "eth_sendRawTransaction",
+    "eth_sendTransaction",
+    "eth_sendBundle",
+    "eth_sendPrivateTransaction",
+    "eth_sendPrivateRawTransaction",
+    "eth_cancelPrivateTransaction",
+    "eth_sendUserOperation",
+    "eth_submitWork",
+    "eth_submitHashrate",
+];
+
+/// jsonrpc error messages that another backend probably won't give
+const SERVER_ERRORS: [&str; 8] = [
+    "internal error",
+    "timeout",
+    "timed out",
+    "rate limit",
+    "too many requests",
+    "busy",
+    "unavailable",
+    "try again",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts after the first one
+    pub max_retries: usize,
+    /// no attempts start this long after the request started
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            deadline: Duration::from_secs(3),
+        }
+    }
+}
+
+impl From<&AppConfig> for RetryPolicy {
+    fn from(config: &AppConfig) -> Self {
+        Self {
+            max_retries: config.backend_max_retries,
+            deadline: Duration::from_millis(config.backend_retry_deadline_ms),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn has_side_effects(method: &str) -> bool {
+        SIDE_EFFECTS.contains(&method)
+    }
+
+    /// true if another backend should get the request after `attempts` backends already failed it
+    pub fn should_retry(&self, method: &str, attempts: usize, started: Instant) -> bool {
+        attempts <= self.max_retries
+            && started.elapsed() < self.deadline
+            && !Self::has_side_effects(method)
+    }
+}
+
+/// true if this error is the backend's problem and not the request's
+pub fn is_server_error(error: &JsonRpcErrorData) -> bool {
+    match error.code {
+        // internal error
+        -32603 => true,
+        // rate limits
+        429 => true,
+        // anything else in the server error range needs a closer look. reverts and bad nonces are -32000 too
+        -32099..=-32000 => {
+            let message = error.message.to_lowercase();
+
+            !message.contains("revert") && SERVER_ERRORS.iter().any(|x| message.contains(x))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(code: i64, message: &'static str) -> JsonRpcErrorData {
+        JsonRpcErrorData {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn server_errors() {
+        assert!(is_server_error(&error(-32603, "internal error")));
+        assert!(is_server_error(&error(429, "Too Many Requests")));
+        assert!(is_server_error(&error(-32005, "rate limit exceeded")));
+        assert!(is_server_error(&error(-32000, "request timed out")));
+        assert!(is_server_error(&error(-32000, "upstream busy. try again")));
+
+        // these would be the same on every backend
+        assert!(!is_server_error(&error(-32000, "execution reverted")));
+        assert!(!is_server_error(&error(-32000, "nonce too low")));
+        assert!(!is_server_error(&error(-32602, "invalid params")));
+        assert!(!is_server_error(&error(3, "execution reverted: timeout")));
+    }
+
+    #[test]
+    fn retry_budget() {
+        let x = RetryPolicy {
+            max_retries: 2,
+            deadline: Duration::from_secs(60),
+        };
+
+        let started = Instant::now();
+
+        assert!(x.should_retry("eth_call", 1, started));
+        assert!(x.should_retry("eth_call", 2, started));
+        assert!(!x.should_retry("eth_call", 3, started));
+
+        // never sent twice
+        assert!(!x.should_retry("eth_sendRawTransaction", 1, started));
+
+        let expired = RetryPolicy {
+            deadline: Duration::ZERO,
+            ..x
+        };
+
+        assert!(!expired.should_retry("eth_call", 1, started));
+    }
+}
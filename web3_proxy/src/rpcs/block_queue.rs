@@ -0,0 +1,190 @@
+//! The queue between every rpc's head block subscription and the consensus finder.
+//!
+//! Heads are rare, so when the queue is full the sending rpc waits a little for room. If the consensus finder is stuck
+//! for longer than that, the head is dropped and counted instead of letting the queue grow without limit. The rpc's
+//! next head supersedes the dropped one anyways.
+//!
+//! Pending transactions don't go through here. They are sent straight to a broadcast channel that already drops the
+//! oldest items for subscribers that fall behind.
+
+use crate::config::BlockAndRpc;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::SendTimeoutError};
+use tracing::warn;
+
+/// there is usually only a handful of rpcs each sending a head every few seconds. this is plenty of slack
+pub const BLOCK_QUEUE_CAPACITY: usize = 1_000;
+
+/// how long an rpc waits for room in a full queue before its head is dropped
+pub const BLOCK_QUEUE_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// processing a single head slower than this is logged
+pub const SLOW_BLOCK_PROCESSING: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default)]
+pub struct BlockQueueStats {
+    /// heads that made it into the queue
+    pub sent: AtomicU64,
+    /// heads that were dropped because the queue stayed full
+    pub dropped: AtomicU64,
+    /// heads that the consensus finder finished with
+    pub processed: AtomicU64,
+    /// heads that took longer than `SLOW_BLOCK_PROCESSING`
+    pub slow: AtomicU64,
+    /// the slowest that any single head has been processed
+    pub max_processing_ms: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockQueueSender {
+    sender: mpsc::Sender<BlockAndRpc>,
+    send_timeout: Duration,
+    stats: Arc<BlockQueueStats>,
+}
+
+/// the queue is closed. the consensus finder exited
+#[derive(Debug)]
+pub struct BlockQueueClosed;
+
+impl std::fmt::Display for BlockQueueClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "block queue closed")
+    }
+}
+
+impl std::error::Error for BlockQueueClosed {}
+
+impl BlockQueueSender {
+    pub fn channel(capacity: usize, send_timeout: Duration) -> (Self, mpsc::Receiver<BlockAndRpc>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+
+        let x = Self {
+            sender,
+            send_timeout,
+            stats: Default::default(),
+        };
+
+        (x, receiver)
+    }
+
+    /// Queue a head for the consensus finder. Returns false if it was dropped because the queue stayed full.
+    pub async fn send(&self, block_and_rpc: BlockAndRpc) -> Result<bool, BlockQueueClosed> {
+        match self
+            .sender
+            .send_timeout(block_and_rpc, self.send_timeout)
+            .await
+        {
+            Ok(()) => {
+                self.stats.sent.fetch_add(1, Ordering::Relaxed);
+                Ok(true)
+            }
+            Err(SendTimeoutError::Timeout((_, rpc))) => {
+                let dropped = self.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+                warn!(rpc=%rpc, depth=self.depth(), dropped, "block queue is full. dropped a head block");
+
+                Ok(false)
+            }
+            Err(SendTimeoutError::Closed(_)) => Err(BlockQueueClosed),
+        }
+    }
+
+    /// Record how long the consensus finder took with one head
+    pub fn processed(&self, rpc_name: &str, elapsed: Duration) {
+        self.stats.processed.fetch_add(1, Ordering::Relaxed);
+
+        let ms = elapsed.as_millis() as u64;
+
+        self.stats
+            .max_processing_ms
+            .fetch_max(ms, Ordering::Relaxed);
+
+        if elapsed > SLOW_BLOCK_PROCESSING {
+            self.stats.slow.fetch_add(1, Ordering::Relaxed);
+
+            warn!(rpc=%rpc_name, ms, depth=self.depth(), "slow head block processing");
+        }
+    }
+
+    /// how many heads are waiting for the consensus finder
+    pub fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn stats(&self) -> &BlockQueueStats {
+        &self.stats
+    }
+}
+
+impl Serialize for BlockQueueSender {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("BlockQueue", 7)?;
+
+        state.serialize_field("capacity", &self.sender.max_capacity())?;
+        state.serialize_field("depth", &self.depth())?;
+        state.serialize_field("sent", &self.stats.sent.load(Ordering::Relaxed))?;
+        state.serialize_field("dropped", &self.stats.dropped.load(Ordering::Relaxed))?;
+        state.serialize_field("processed", &self.stats.processed.load(Ordering::Relaxed))?;
+        state.serialize_field("slow", &self.stats.slow.load(Ordering::Relaxed))?;
+        state.serialize_field(
+            "max_processing_ms",
+            &self.stats.max_processing_ms.load(Ordering::Relaxed),
+        )?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpcs::one::Web3Rpc;
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_consensus_finder_drops_heads() {
+        let (queue, mut receiver) = BlockQueueSender::channel(4, BLOCK_QUEUE_SEND_TIMEOUT);
+
+        let rpc = Arc::new(Web3Rpc {
+            name: "stuck".to_string(),
+            ..Default::default()
+        });
+
+        // nothing is reading from the queue
+        let mut sent = 0;
+        for _ in 0..20 {
+            if queue.send((None, rpc.clone())).await.unwrap() {
+                sent += 1;
+            }
+        }
+
+        // the queue never grew past its capacity. everything else was dropped
+        assert_eq!(sent, 4);
+        assert_eq!(queue.depth(), 4);
+        assert_eq!(queue.stats().sent.load(Ordering::Relaxed), 4);
+        assert_eq!(queue.stats().dropped.load(Ordering::Relaxed), 16);
+
+        // once the consensus finder catches up, heads flow again
+        for _ in 0..4 {
+            receiver.recv().await.unwrap();
+            queue.processed("stuck", Duration::from_secs(2));
+        }
+
+        assert!(queue.send((None, rpc.clone())).await.unwrap());
+        assert_eq!(queue.depth(), 1);
+
+        let status = serde_json::to_value(&queue).unwrap();
+        assert_eq!(status["dropped"], 16);
+        assert_eq!(status["slow"], 4);
+        assert_eq!(status["max_processing_ms"], 2_000);
+
+        drop(receiver);
+        assert!(queue.send((None, rpc)).await.is_err());
+    }
+}
@@ -10,7 +10,7 @@ use serde::Serialize;
 use serde_json::json;
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{fmt::Display, sync::Arc};
 use tokio::select;
 use tokio::sync::mpsc;
@@ -53,6 +53,9 @@ impl Serialize for BlockHeader {
             "parent_hash": self.0.parent_hash,
             "number": self.0.number,
             "timestamp": self.0.timestamp,
+            "base_fee_per_gas": self.0.base_fee_per_gas,
+            "blob_gas_used": self.0.blob_gas_used,
+            "excess_blob_gas": self.0.excess_blob_gas,
         });
 
         state.serialize_field("block", &block)?;
@@ -198,9 +201,26 @@ impl Web3Rpcs {
         Ok(block)
     }
 
+    /// The chain reorganized at this height. Forget which hashes we had for it and everything above it.
+    /// They are looked up again the next time they are needed
+    pub async fn forget_blocks_from(&self, number: U64) {
+        let stale: Vec<U64> = self
+            .blocks_by_number
+            .iter()
+            .filter(|(x, _)| **x >= number)
+            .map(|(x, _)| *x)
+            .collect();
+
+        debug!(%number, count=stale.len(), "forgetting blocks");
+
+        for x in stale {
+            self.blocks_by_number.invalidate(&x).await;
+        }
+    }
+
     pub(super) async fn process_incoming_blocks(
         &self,
-        mut block_and_rpc_receiver: mpsc::UnboundedReceiver<BlockAndRpc>,
+        mut block_and_rpc_receiver: mpsc::Receiver<BlockAndRpc>,
     ) -> Web3ProxyResult<()> {
         if self.watch_head_block.is_none() {
             return Ok(());
@@ -220,6 +240,8 @@ impl Web3Rpcs {
                         Some((new_block, rpc)) => {
                             let rpc_name = rpc.name.clone();
 
+                            let start = Instant::now();
+
                             // TODO: we used to have a timeout on this, but i think it was obscuring a bug
                             match consensus_finder
                                 .process_block_from_rpc(self, new_block, rpc)
@@ -233,6 +255,9 @@ impl Web3Rpcs {
                                     );
                                 }
                             }
+
+                            // every rpc waits on this loop. make slow heads visible
+                            self.block_and_rpc_sender.processed(&rpc_name, start.elapsed());
                         }
                         None => {
                             // TODO: panic is probably too much, but getting here is definitely not good
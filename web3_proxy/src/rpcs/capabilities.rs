@@ -0,0 +1,114 @@
+//! Learned gaps in what methods a backend rpc supports.
+//!
+//! Not every backend supports every method (`trace_*` is only on some clients). When a backend says "method not
+//! found", we remember that for a while and send that method to other backends instead.
+
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+/// forget that a backend is missing a method after this long. it might have been upgraded or reconfigured
+pub const MISSING_METHOD_TTL: Duration = Duration::from_secs(3600);
+
+/// the most missing methods to remember per backend. a client spamming made up methods shouldn't use unbounded memory
+pub const MAX_MISSING_METHODS: usize = 256;
+
+/// Methods that a backend has told us it doesn't support
+#[derive(Debug, Default)]
+pub struct MissingMethods(RwLock<HashMap<String, Instant>>);
+
+impl MissingMethods {
+    pub fn insert(&self, method: &str) {
+        let now = Instant::now();
+
+        let mut x = self.0.write();
+
+        if x.len() >= MAX_MISSING_METHODS && !x.contains_key(method) {
+            x.retain(|_, learned_at| now.duration_since(*learned_at) < MISSING_METHOD_TTL);
+
+            if x.len() >= MAX_MISSING_METHODS {
+                // still full. forget the oldest
+                if let Some(oldest) = x
+                    .iter()
+                    .min_by_key(|(_, learned_at)| **learned_at)
+                    .map(|(k, _)| k.clone())
+                {
+                    x.remove(&oldest);
+                }
+            }
+        }
+
+        x.insert(method.to_string(), now);
+    }
+
+    /// true if this backend recently said it doesn't support `method`
+    pub fn contains(&self, method: &str) -> bool {
+        self.0.read().get(method).map_or(false, |learned_at| {
+            learned_at.elapsed() < MISSING_METHOD_TTL
+        })
+    }
+
+    /// the methods that are currently known to be missing, sorted
+    pub fn list(&self) -> Vec<String> {
+        let mut x: Vec<_> = self
+            .0
+            .read()
+            .iter()
+            .filter(|(_, learned_at)| learned_at.elapsed() < MISSING_METHOD_TTL)
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        x.sort();
+
+        x
+    }
+}
+
+impl Serialize for MissingMethods {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.list().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_methods_expire() {
+        let x = MissingMethods::default();
+
+        x.insert("trace_block");
+
+        assert!(x.contains("trace_block"));
+        assert!(!x.contains("eth_call"));
+        assert_eq!(x.list(), ["trace_block"]);
+
+        tokio::time::advance(MISSING_METHOD_TTL).await;
+
+        assert!(!x.contains("trace_block"));
+        assert!(x.list().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_methods_are_bounded() {
+        let x = MissingMethods::default();
+
+        for i in 0..MAX_MISSING_METHODS {
+            x.insert(&format!("made_up_{}", i));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+
+        x.insert("trace_block");
+
+        assert_eq!(x.list().len(), MAX_MISSING_METHODS);
+        assert!(x.contains("trace_block"));
+        // the oldest was forgotten to make room
+        assert!(!x.contains("made_up_0"));
+        assert!(x.contains("made_up_1"));
+    }
+}
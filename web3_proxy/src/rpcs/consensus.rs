@@ -1,8 +1,12 @@
 use super::blockchain::BlockHeader;
+use super::maintenance::MaintenanceWindow;
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
+use super::reorg::{HeadAncestry, Reorg};
 use super::request::OpenRequestHandle;
+use crate::cache_invalidation::Invalidation;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use crate::globals::global_app;
 use crate::jsonrpc::ValidatedRequest;
 use crate::rpcs::request::OpenRequestResult;
 use async_stream::stream;
@@ -285,6 +289,14 @@ impl RankedRpcs {
             }
         }
 
+        // the rest of the batch went here. keep this request with them if it can be served there
+        if let Some(name) = web3_request.response.lock().batch_rpc.as_deref() {
+            if let Some(i) = inner_for_request.iter().position(|x| x.name == name) {
+                let rpc = inner_for_request.remove(i);
+                inner_for_request.insert(0, rpc);
+            }
+        }
+
         if inner_for_request.is_empty() {
             warn!(?inner_for_request, ?outer_for_request, %web3_request, head_block=%MaybeBlockNum(&head_block_num), "no rpcs for request");
             None
@@ -298,6 +310,19 @@ impl RankedRpcs {
         }
     }
 
+    /// The rpc that a whole batch should prefer. None if none of them have the batch's `head_block`
+    pub fn batch_rpc(&self, head_block: &BlockHeader) -> Option<Arc<Web3Rpc>> {
+        let now = Instant::now();
+
+        self.inner
+            .iter()
+            .filter(|x| !x.backup || self.backups_needed)
+            .filter(|x| x.has_block_data(head_block.number()))
+            .filter(|x| !x.is_quarantined())
+            .min_by_key(|x| x.sort_for_load_balancing_on(Some(head_block.number()), now))
+            .cloned()
+    }
+
     pub fn all(&self) -> hashbrown::hash_set::Iter<Arc<Web3Rpc>> {
         self.inner.iter()
     }
@@ -364,6 +389,8 @@ pub struct ConsensusFinder {
     max_head_block_lag: U64,
     /// Block Hash -> First Seen Instant. used to track rpc.head_delay. The same cache should be shared between all ConnectionsGroups
     first_seen: FirstSeenCache,
+    /// recent consensus heads. used to notice when a new head is on another branch
+    ancestry: HeadAncestry,
 }
 
 impl ConsensusFinder {
@@ -378,6 +405,7 @@ impl ConsensusFinder {
             max_head_block_age,
             max_head_block_lag,
             first_seen,
+            ancestry: Default::default(),
         }
     }
 
@@ -432,6 +460,20 @@ impl ConsensusFinder {
 
         let new_ranked_rpcs = Arc::new(new_ranked_rpcs);
 
+        // caches are cleared before anything sees the new head
+        if let Some(head) = consensus_head_block.as_ref() {
+            let reorg = self
+                .ancestry
+                .push(head, |hash| async move {
+                    web3_rpcs.blocks_by_hash.get(&hash).await
+                })
+                .await;
+
+            if let Some(reorg) = reorg {
+                reorged(web3_rpcs, reorg).await;
+            }
+        }
+
         if let Some(rpc_block_sender) = rpc_block_sender {
             rpc_block_sender.send_replace(new_block.clone());
         }
@@ -519,8 +561,7 @@ impl ConsensusFinder {
                                 rpc_head_str,
                             )
                         } else {
-                            // hash changed
-
+                            // hash changed. the reorg was already handled
                             debug!(
                                 "unc {}/{} {}{}/{}/{} con={} old={} rpc={}",
                                 best_tier,
@@ -574,7 +615,6 @@ impl ConsensusFinder {
                             warn!("Backup RPCs are in use!");
                         }
 
-                        // TODO: tell save_block to remove any higher block numbers from the cache. not needed because we have other checks on requested blocks being > head, but still seems like a good idea
                         let consensus_head_block =
                             if let Some(consensus_head_block) = consensus_head_block {
                                 let consensus_head_block = web3_rpcs
@@ -780,19 +820,16 @@ impl ConsensusFinder {
 
                 trace!("min_median_latency_sec: {}", min_median_latency_sec);
 
-                // TODO: get someone who is better at math to do something smarter. maybe involving stddev? maybe involving cutting the histogram at the troughs?
-                // bucket sizes of the larger of 20ms or 1/2 the lowest latency
-                // TODO: is 20ms an okay default? make it configurable?
-                // TODO: does keeping the buckets the same size make sense?
-                let tier_sec_size = 0.020f32.max(min_median_latency_sec / 2.0);
-
-                trace!("tier_sec_size: {}", tier_sec_size);
+                let latency_weight = self
+                    .rpc_heads
+                    .keys()
+                    .next()
+                    .and_then(|x| global_app(x.chain_id))
+                    .map_or(100, |x| x.config.backend_latency_weight);
 
                 for (rpc, median_latency_sec) in median_latencies_sec.into_iter() {
-                    let tier = (median_latency_sec - min_median_latency_sec) / tier_sec_size;
-
-                    // start tiers at 1
-                    let tier = (tier.floor() as u32).saturating_add(1);
+                    let tier =
+                        latency_tier(median_latency_sec, min_median_latency_sec, latency_weight);
 
                     trace!("{} - p50_sec: {}, tier {}", rpc, median_latency_sec, tier);
 
@@ -930,6 +967,43 @@ impl ConsensusFinder {
     }
 }
 
+/// Tiers start at 1. `latency_weight` is a percent. 0 puts everything in tier 1
+fn latency_tier(median_latency_sec: f32, min_median_latency_sec: f32, latency_weight: u32) -> u32 {
+    if latency_weight == 0 {
+        return 1;
+    }
+
+    // TODO: get someone who is better at math to do something smarter. maybe involving stddev? maybe involving cutting the histogram at the troughs?
+    // bucket sizes of the larger of 20ms or 1/2 the lowest latency
+    // TODO: does keeping the buckets the same size make sense?
+    let tier_sec_size = 0.020f32.max(min_median_latency_sec / 2.0) * 100.0 / latency_weight as f32;
+
+    let tier = (median_latency_sec - min_median_latency_sec) / tier_sec_size;
+
+    (tier.floor() as u32).saturating_add(1)
+}
+
+/// The new consensus head replaced blocks we already had. Forget them here and tell the other servers to do the same
+async fn reorged(web3_rpcs: &Web3Rpcs, reorg: Reorg) {
+    warn!(
+        number = %reorg.number,
+        depth = reorg.depth,
+        orphaned = ?reorg.orphaned,
+        "reorg"
+    );
+
+    web3_rpcs.forget_blocks_from(reorg.number).await;
+
+    if let Some(app) = global_app(web3_rpcs.chain_id) {
+        app.purge_orphaned_responses(&reorg.orphaned).await;
+
+        app.publish_invalidation(Invalidation::Reorg {
+            number: reorg.number,
+            orphaned: reorg.orphaned,
+        });
+    }
+}
+
 /*
 fn best_rpc<'a>(rpc_a: &'a Arc<Web3Rpc>, rpc_b: &'a Arc<Web3Rpc>) -> &'a Arc<Web3Rpc> {
     let now = Instant::now();
@@ -945,6 +1019,81 @@ fn best_rpc<'a>(rpc_a: &'a Arc<Web3Rpc>, rpc_b: &'a Arc<Web3Rpc>) -> &'a Arc<Web
 */
 
 impl RpcsForRequest {
+    /// Only ever use `rpc`. There is no fallback if it is rate limited or down.
+    pub fn designated(rpc: Arc<Web3Rpc>, request: Arc<ValidatedRequest>) -> Self {
+        Self {
+            inner: vec![rpc],
+            outer: vec![],
+            request,
+        }
+    }
+
+    /// Only use these rpcs, in this order. None if there aren't any
+    pub fn pinned(rpcs: Vec<Arc<Web3Rpc>>, request: Arc<ValidatedRequest>) -> Option<Self> {
+        if rpcs.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            inner: rpcs,
+            outer: vec![],
+            request,
+        })
+    }
+
+    /// Skip the backends that are in maintenance. None if none of the preferred rpcs are left.
+    pub fn without_maintenance(mut self, window: &MaintenanceWindow) -> Option<Self> {
+        self.inner.retain(|x| !window.covers(&x.name));
+        self.outer.retain(|x| !window.covers(&x.name));
+
+        if self.inner.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    /// true if every rpc here recently said it does not support the request's method
+    pub fn all_lack_method(&self) -> bool {
+        let method = self.request.inner.method();
+
+        self.inner
+            .iter()
+            .chain(self.outer.iter())
+            .all(|x| x.lacks_method(method))
+    }
+
+    /// Open handles on up to `max` different rpcs without waiting on any that are rate limited or lagged.
+    /// Empty if none of them are ready right now.
+    pub async fn open_handles(&self, max: usize) -> Vec<OpenRequestHandle> {
+        let method = self.request.inner.method();
+
+        let mut handles = Vec::with_capacity(max);
+
+        for best_rpc in self.inner.iter().chain(self.outer.iter()) {
+            if handles.len() >= max {
+                break;
+            }
+
+            if best_rpc.lacks_method(method) {
+                continue;
+            }
+
+            match best_rpc
+                .try_request_handle(&self.request, None, false)
+                .await
+            {
+                Ok(OpenRequestResult::Handle(handle)) => handles.push(handle),
+                Ok(_) => {}
+                Err(err) => {
+                    trace!("No request handle for {}. err={:?}", best_rpc, err);
+                }
+            }
+        }
+
+        handles
+    }
+
     pub fn to_stream(self) -> impl Stream<Item = OpenRequestHandle> {
         stream! {
             trace!("entered stream");
@@ -959,9 +1108,17 @@ impl RpcsForRequest {
                 let mut tried = 0;
                 let mut wait_for_sync = Vec::new();
 
+                let method = self.request.inner.method();
+
                 // TODO: we used to do a neat power of 2 random choices here, but it had bugs. bring that back
                 for rpcs in [self.inner.iter(), self.outer.iter()] {
                     for best_rpc in rpcs {
+                        if best_rpc.lacks_method(method) {
+                            // don't bother. we already know this rpc will say "method not found"
+                            trace!("{} lacks {}", best_rpc, method);
+                            continue;
+                        }
+
                         tried += 1;
 
                         match best_rpc
@@ -1071,3 +1228,29 @@ impl std::fmt::Display for MaybeBlockNum<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::latency_tier;
+
+    #[test]
+    fn latency_tiers() {
+        // 100ms is the fastest. tiers are 50ms wide
+        assert_eq!(latency_tier(0.100, 0.100, 100), 1);
+        assert_eq!(latency_tier(0.149, 0.100, 100), 1);
+        assert_eq!(latency_tier(0.160, 0.100, 100), 2);
+        assert_eq!(latency_tier(3.010, 0.100, 100), 59);
+
+        // tiers are never less than 20ms wide
+        assert_eq!(latency_tier(0.015, 0.001, 100), 1);
+        assert_eq!(latency_tier(0.025, 0.001, 100), 2);
+
+        // a heavier weight splits the same latencies into more tiers
+        assert_eq!(latency_tier(0.149, 0.100, 200), 2);
+        assert_eq!(latency_tier(0.149, 0.100, 50), 1);
+        assert_eq!(latency_tier(0.250, 0.100, 50), 2);
+
+        // no weight ignores latency
+        assert_eq!(latency_tier(3.000, 0.100, 0), 1);
+    }
+}
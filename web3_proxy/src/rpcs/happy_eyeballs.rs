@@ -0,0 +1,340 @@
+//! Happy eyeballs (RFC 8305) for backends that publish both A and AAAA records.
+//!
+//! When one family's path is broken, a connect to it hangs until it times out. Both families are resolved and the one
+//! that won last time goes first, so a broken family only costs the race delay once.
+//!
+//! Http connects go through reqwest, which uses `HappyEyeballs` as its resolver. hyper races the families itself and
+//! starts the second one after 300ms. Websockets are raced here with `backend_connect_race_ms` before ethers connects.
+//! ethers resolves the host again on its own, so that race can't pick the address. It does record which family works
+//! and it fails in seconds when neither does.
+
+use crate::config::AppConfig;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Serialize, Serializer};
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::trace;
+use url::Url;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Self::Ipv4,
+            SocketAddr::V6(_) => Self::Ipv6,
+        }
+    }
+}
+
+/// The family that served the most recent connection. Shown in `/status` to spot broken ipv6 paths
+#[derive(Debug, Default)]
+pub struct LastFamily(AtomicU8);
+
+impl LastFamily {
+    pub fn get(&self) -> Option<AddressFamily> {
+        match self.0.load(Ordering::Relaxed) {
+            4 => Some(AddressFamily::Ipv4),
+            6 => Some(AddressFamily::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, family: AddressFamily) {
+        let x = match family {
+            AddressFamily::Ipv4 => 4,
+            AddressFamily::Ipv6 => 6,
+        };
+
+        self.0.store(x, Ordering::Relaxed);
+    }
+}
+
+impl Serialize for LastFamily {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.get().serialize(serializer)
+    }
+}
+
+pub struct HappyEyeballs {
+    race_delay: Duration,
+    connect_timeout: Duration,
+    /// the family that won the most recent connect to each host
+    winners: RwLock<HashMap<String, AddressFamily>>,
+}
+
+impl HappyEyeballs {
+    pub fn new(config: &AppConfig, connect_timeout: Duration) -> Arc<Self> {
+        let x = Self {
+            race_delay: Duration::from_millis(config.backend_connect_race_ms),
+            connect_timeout,
+            winners: Default::default(),
+        };
+
+        Arc::new(x)
+    }
+
+    /// Returns the family of `addr`. The next connect to `host` tries that family first
+    pub fn remember(&self, host: &str, addr: &SocketAddr) -> AddressFamily {
+        let family = AddressFamily::of(addr);
+
+        // this is called for every http response. only take the write lock when the winner changes
+        if self.winners.read().get(host) != Some(&family) {
+            self.winners.write().insert(host.to_string(), family);
+        }
+
+        family
+    }
+
+    pub fn winner(&self, host: &str) -> Option<AddressFamily> {
+        self.winners.read().get(host).copied()
+    }
+
+    /// Connect to the first of `host`'s addresses that answers. Each address gets a head start of the race delay on the
+    /// next one. A failed address starts the next one immediately
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<(TcpStream, SocketAddr)> {
+        let addrs = lookup_host((host, port)).await?;
+
+        let addrs = sort_addrs(addrs, self.winner(host));
+
+        let (stream, addr) = race(addrs, self.race_delay, self.connect_timeout).await?;
+
+        self.remember(host, &addr);
+
+        Ok((stream, addr))
+    }
+
+    /// Race a connect to the url's host and drop it. Returns the family that won
+    pub async fn check_url(&self, url: &Url) -> io::Result<AddressFamily> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "url has no host"))?;
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "url has no port"))?;
+
+        let (_, addr) = self.connect(host, port).await?;
+
+        Ok(AddressFamily::of(&addr))
+    }
+}
+
+impl Resolve for HappyEyeballs {
+    fn resolve(&self, name: Name) -> Resolving {
+        let first = self.winner(name.as_str());
+
+        Box::pin(async move {
+            // hyper sets the port
+            let addrs = lookup_host((name.as_str(), 0)).await?;
+
+            let addrs = sort_addrs(addrs, first);
+
+            trace!(host=%name.as_str(), ?addrs, "resolved");
+
+            Ok::<_, Box<dyn Error + Send + Sync>>(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// RFC 8305 section 4. Alternate the families, starting with `first` or else with the resolver's first address
+pub fn sort_addrs(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    first: Option<AddressFamily>,
+) -> Vec<SocketAddr> {
+    let addrs: Vec<_> = addrs.into_iter().collect();
+
+    let Some(first) = first.or_else(|| addrs.first().map(AddressFamily::of)) else {
+        return addrs;
+    };
+
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|x| AddressFamily::of(x) == first);
+
+    let mut x = Vec::with_capacity(preferred.len() + other.len());
+
+    preferred.reverse();
+    other.reverse();
+
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => x.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    x
+}
+
+/// Connect to the first address that answers. Every `race_delay`, or as soon as an attempt fails, the next address
+/// starts. Attempts that are still connecting are kept. Gives up after `connect_timeout`
+pub async fn race(
+    addrs: Vec<SocketAddr>,
+    race_delay: Duration,
+    connect_timeout: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let f = async {
+        let mut addrs = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+            }
+
+            if attempts.is_empty() {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                }));
+            }
+
+            tokio::select! {
+                Some((addr, x)) = attempts.next() => match x {
+                    Ok(stream) => return Ok((stream, addr)),
+                    Err(err) => {
+                        trace!(%addr, ?err, "connect failed");
+                        last_err = Some(err);
+                    }
+                },
+                _ = sleep(race_delay), if !addrs.as_slice().is_empty() => {}
+            }
+        }
+    };
+
+    timeout(connect_timeout, f).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no address connected before the timeout",
+        )
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    fn v4(x: u8) -> SocketAddr {
+        (Ipv4Addr::new(10, 0, 0, x), 8545).into()
+    }
+
+    fn v6(x: u16) -> SocketAddr {
+        (Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, x), 8545).into()
+    }
+
+    #[test]
+    fn interleave() {
+        let addrs = [v6(1), v6(2), v6(3), v4(1)];
+
+        // the resolver's order picks the first family
+        assert_eq!(sort_addrs(addrs, None), vec![v6(1), v4(1), v6(2), v6(3)]);
+
+        // the last winner goes first
+        assert_eq!(
+            sort_addrs(addrs, Some(AddressFamily::Ipv4)),
+            vec![v4(1), v6(1), v6(2), v6(3)]
+        );
+
+        // a winner without addresses doesn't matter
+        assert_eq!(
+            sort_addrs([v6(1), v6(2)], Some(AddressFamily::Ipv4)),
+            vec![v6(1), v6(2)]
+        );
+
+        assert!(sort_addrs([], None).is_empty());
+    }
+
+    #[test]
+    fn last_family() {
+        let x = LastFamily::default();
+        assert_eq!(serde_json::to_value(&x).unwrap(), serde_json::Value::Null);
+
+        x.set(AddressFamily::Ipv6);
+        assert_eq!(x.get(), Some(AddressFamily::Ipv6));
+        assert_eq!(serde_json::to_value(&x).unwrap(), "ipv6");
+    }
+
+    /// 100::/64 is discard-only (RFC 6666). depending on the network it times out or fails right away. either way the
+    /// working address should connect in about the race delay instead of the connect timeout
+    #[tokio::test]
+    async fn race_skips_a_blackholed_family() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let blackholed: SocketAddr = (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1), port).into();
+        let working: SocketAddr = (Ipv4Addr::LOCALHOST, port).into();
+
+        let race_delay = Duration::from_millis(250);
+        let connect_timeout = Duration::from_secs(10);
+
+        let start = Instant::now();
+
+        let (_, addr) = race(vec![blackholed, working], race_delay, connect_timeout)
+            .await
+            .unwrap();
+
+        let elapsed = start.elapsed();
+
+        assert_eq!(addr, working);
+        assert!(
+            elapsed < race_delay + Duration::from_millis(500),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn race_times_out() {
+        let blackholed: SocketAddr = (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1), 8545).into();
+
+        let start = Instant::now();
+
+        let x = race(
+            vec![blackholed],
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+        )
+        .await;
+
+        // an unroutable network fails right away. a blackhole waits for the timeout
+        assert!(x.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn connect_remembers_the_winner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let x = HappyEyeballs::new(&AppConfig::default(), Duration::from_secs(5));
+
+        assert_eq!(x.winner("127.0.0.1"), None);
+
+        let url: Url = format!("http://127.0.0.1:{}", port).parse().unwrap();
+
+        assert_eq!(x.check_url(&url).await.unwrap(), AddressFamily::Ipv4);
+        assert_eq!(x.winner("127.0.0.1"), Some(AddressFamily::Ipv4));
+    }
+}
@@ -0,0 +1,544 @@
+//! Planned maintenance for a group of rpcs.
+//!
+//! While a whole group is in maintenance, requests that would have gone to it get a "temporarily unavailable" error
+//! with a retry hint instead of timing out or falling back to another group. Maintenance on only some of a group's
+//! backends takes just those backends out of rotation.
+//!
+//! Backends with known weekly maintenance can also list `maintenance_windows` in their config. They are drained a little
+//! before each window starts, so clients never see the node going down. After the window, they wait for a health check
+//! to pass before they are used again.
+
+use crate::config::Web3RpcConfig;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::de::{self, Deserializer};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// tell clients to retry after this many seconds if the maintenance has no end time
+pub const DEFAULT_MAINTENANCE_RETRY_AFTER: u64 = 60;
+
+/// The groups of rpcs that can be put into maintenance
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcGroup {
+    Balanced,
+    Private,
+    #[serde(rename = "bundler_4337")]
+    Bundler4337,
+}
+
+impl fmt::Display for RpcGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Balanced => f.write_str("balanced"),
+            Self::Private => f.write_str("private"),
+            Self::Bundler4337 => f.write_str("bundler_4337"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceWindow {
+    /// shown to users in the error
+    pub message: Option<String>,
+    /// only these backends are in maintenance. if empty, the whole group is
+    pub backends: Vec<String>,
+    pub since: Instant,
+    /// None if the maintenance lasts until an admin ends it
+    pub until: Option<Instant>,
+}
+
+impl MaintenanceWindow {
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.until.map_or(false, |x| x <= now)
+    }
+
+    /// true if every backend in the group is in maintenance
+    pub fn is_whole_group(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    pub fn covers(&self, rpc_name: &str) -> bool {
+        self.is_whole_group() || self.backends.iter().any(|x| x == rpc_name)
+    }
+
+    /// seconds until the maintenance is scheduled to end
+    pub fn retry_after(&self, now: Instant) -> u64 {
+        self.until
+            .map(|x| x.saturating_duration_since(now).as_secs().max(1))
+            .unwrap_or(DEFAULT_MAINTENANCE_RETRY_AFTER)
+    }
+
+    /// Make sure the backends exist. If `keep_one`, at least one of `all_backends` must stay out of maintenance.
+    pub fn check_backends<'a>(
+        &self,
+        all_backends: impl IntoIterator<Item = &'a str>,
+        keep_one: bool,
+    ) -> Result<(), Cow<'static, str>> {
+        let all_backends: Vec<&str> = all_backends.into_iter().collect();
+
+        for x in self.backends.iter() {
+            if !all_backends.contains(&x.as_str()) {
+                return Err(format!("unknown backend: {}", x).into());
+            }
+        }
+
+        if keep_one && !all_backends.iter().any(|x| !self.covers(x)) {
+            return Err("at least one backend must stay out of maintenance".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for MaintenanceWindow {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let now = Instant::now();
+
+        let mut state = serializer.serialize_struct("MaintenanceWindow", 4)?;
+
+        state.serialize_field("message", &self.message)?;
+        state.serialize_field("backends", &self.backends)?;
+        state.serialize_field("since_secs", &now.duration_since(self.since).as_secs())?;
+        state.serialize_field(
+            "remaining_secs",
+            &self
+                .until
+                .map(|x| x.saturating_duration_since(now).as_secs()),
+        )?;
+
+        state.end()
+    }
+}
+
+/// Longer lead times are cut to this
+pub const MAX_MAINTENANCE_LEAD: Duration = Duration::from_secs(86_400);
+
+/// A weekly maintenance window for one backend. `start` and `end` are "HH:MM" in `timezone`.
+/// A window that ends at or before its start runs past midnight into the next day.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledWindow {
+    /// The days that the window starts on, like "mon" or "Tuesday". Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "deserialize_hh_mm")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_hh_mm")]
+    pub end: NaiveTime,
+    /// An IANA name like "America/New_York". Daylight saving time is followed.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn deserialize_hh_mm<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let x = String::deserialize(deserializer)?;
+
+    NaiveTime::parse_from_str(&x, "%H:%M")
+        .map_err(|err| de::Error::custom(format!("{:?} is not HH:MM: {}", x, err)))
+}
+
+impl ScheduledWindow {
+    /// The window that starts on this local date. None if it doesn't start that day
+    fn occurrence(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.days.is_empty() && !self.days.contains(&date.weekday()) {
+            return None;
+        }
+
+        let end_date = if self.end <= self.start {
+            date.succ_opt()?
+        } else {
+            date
+        };
+
+        let start = self.to_utc(date.and_time(self.start))?;
+        let end = self.to_utc(end_date.and_time(self.end))?;
+
+        Some((start, end))
+    }
+
+    /// Local times that a daylight saving change skips are moved an hour later
+    fn to_utc(&self, x: NaiveDateTime) -> Option<DateTime<Utc>> {
+        self.timezone
+            .from_local_datetime(&x)
+            .earliest()
+            .or_else(|| {
+                self.timezone
+                    .from_local_datetime(&(x + chrono::Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|x| x.with_timezone(&Utc))
+    }
+
+    /// When the backend is out of rotation for the windows that start from `first` through `last` (local dates).
+    /// Each drain begins `lead` before its window
+    fn drains(
+        &self,
+        lead: Duration,
+        first: NaiveDate,
+        last: NaiveDate,
+    ) -> impl Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + '_ {
+        let lead = chrono::Duration::from_std(lead.min(MAX_MAINTENANCE_LEAD))
+            .expect("the max lead always fits");
+
+        first
+            .iter_days()
+            .take_while(move |x| *x <= last)
+            .filter_map(|x| self.occurrence(x))
+            .map(move |(start, end)| (start - lead, end))
+    }
+
+    /// When the drain that covers `now` ends. None if `now` is outside this window and its lead time
+    pub fn drain_until(&self, now: DateTime<Utc>, lead: Duration) -> Option<DateTime<Utc>> {
+        let today = now.with_timezone(&self.timezone).date_naive();
+
+        // yesterday's window can run past midnight. tomorrow's window can start draining today
+        self.drains(lead, today.pred_opt()?, today.succ_opt()?)
+            .filter(|(from, until)| *from <= now && now < *until)
+            .map(|(_, until)| until)
+            .max()
+    }
+}
+
+/// Where a backend is in its scheduled maintenance
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum ScheduledMaintenanceState {
+    InService,
+    /// out of rotation until the window ends
+    Draining {
+        until: DateTime<Utc>,
+    },
+    /// the window ended. the backend is used again once a health check passes
+    AwaitingHealthCheck,
+}
+
+impl ScheduledMaintenanceState {
+    pub fn is_draining(&self) -> bool {
+        matches!(self, Self::Draining { .. })
+    }
+}
+
+/// The scheduled windows for one backend and where it is in them
+pub struct ScheduledMaintenance {
+    rpc_name: String,
+    windows: Vec<ScheduledWindow>,
+    lead: Duration,
+    state: Mutex<ScheduledMaintenanceState>,
+}
+
+impl ScheduledMaintenance {
+    /// None if the backend has no windows
+    pub fn new(rpc_name: String, windows: Vec<ScheduledWindow>, lead: Duration) -> Option<Self> {
+        if windows.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            rpc_name,
+            windows,
+            lead,
+            state: Mutex::new(ScheduledMaintenanceState::InService),
+        })
+    }
+
+    /// Call this before each health check. True while the backend should stay out of rotation. Don't check it then
+    pub fn draining(&self, now: DateTime<Utc>) -> bool {
+        let until = self
+            .windows
+            .iter()
+            .filter_map(|x| x.drain_until(now, self.lead))
+            .max();
+
+        let mut state = self.state.lock();
+
+        match until {
+            Some(until) => {
+                if !state.is_draining() {
+                    info!(rpc=%self.rpc_name, %until, "draining for scheduled maintenance");
+                }
+
+                *state = ScheduledMaintenanceState::Draining { until };
+
+                true
+            }
+            None => {
+                if state.is_draining() {
+                    info!(rpc=%self.rpc_name, "scheduled maintenance is over. waiting for a health check");
+
+                    *state = ScheduledMaintenanceState::AwaitingHealthCheck;
+                }
+
+                false
+            }
+        }
+    }
+
+    /// A backend that was waiting on a health check after its window is back in rotation
+    pub fn health_check_passed(&self) {
+        let mut state = self.state.lock();
+
+        if *state == ScheduledMaintenanceState::AwaitingHealthCheck {
+            info!(rpc=%self.rpc_name, "back in rotation after scheduled maintenance");
+
+            *state = ScheduledMaintenanceState::InService;
+        }
+    }
+
+    pub fn state(&self) -> ScheduledMaintenanceState {
+        *self.state.lock()
+    }
+}
+
+/// A time when scheduled maintenance leaves fewer than `min_synced_rpcs` balanced rpcs in rotation
+#[derive(Debug, PartialEq, Eq)]
+pub struct MaintenanceConflict {
+    pub at: DateTime<Utc>,
+    pub draining: Vec<String>,
+    pub remaining: usize,
+    pub min_synced_rpcs: usize,
+}
+
+impl fmt::Display for MaintenanceConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scheduled maintenance at {} drains {}. {} rpcs would be left, but min_synced_rpcs is {}",
+            self.at,
+            self.draining.join(", "),
+            self.remaining,
+            self.min_synced_rpcs
+        )
+    }
+}
+
+/// The first time in the next week that too many of these rpcs are drained at once. Lead times count.
+/// Disabled rpcs are never in rotation, so they don't count either way
+pub fn maintenance_conflict(
+    rpcs: &HashMap<String, Web3RpcConfig>,
+    min_synced_rpcs: usize,
+    now: DateTime<Utc>,
+) -> Option<MaintenanceConflict> {
+    let enabled: Vec<_> = rpcs.iter().filter(|(_, x)| !x.disabled).collect();
+
+    let mut drains = vec![];
+
+    for (name, config) in enabled.iter() {
+        for window in config.maintenance_windows.iter() {
+            let today = now.with_timezone(&window.timezone).date_naive();
+
+            let (Some(first), Some(last)) = (
+                today.pred_opt(),
+                today.checked_add_days(chrono::Days::new(8)),
+            ) else {
+                continue;
+            };
+
+            drains.extend(
+                window
+                    .drains(config.maintenance_lead(), first, last)
+                    .map(|(from, until)| (name.as_str(), from, until)),
+            );
+        }
+    }
+
+    // the number of drained rpcs only goes up when a drain starts. those are the only times to check
+    drains
+        .iter()
+        .filter_map(|(_, at, _)| {
+            let mut draining: Vec<_> = drains
+                .iter()
+                .filter(|(_, from, until)| from <= at && at < until)
+                .map(|(name, ..)| name.to_string())
+                .collect();
+
+            draining.sort();
+            draining.dedup();
+
+            let remaining = enabled.len().saturating_sub(draining.len());
+
+            (remaining < min_synced_rpcs).then(|| MaintenanceConflict {
+                at: *at,
+                draining,
+                remaining,
+                min_synced_rpcs,
+            })
+        })
+        .min_by_key(|x| x.at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn window_expires() {
+        let now = Instant::now();
+
+        let x = MaintenanceWindow {
+            message: Some("upgrading".into()),
+            backends: vec![],
+            since: now,
+            until: Some(now + Duration::from_secs(90)),
+        };
+
+        assert!(x.is_whole_group());
+        assert!(x.covers("anything"));
+        assert_eq!(x.retry_after(now), 90);
+        assert!(!x.is_expired(now));
+
+        tokio::time::advance(Duration::from_secs(90)).await;
+
+        assert!(x.is_expired(Instant::now()));
+
+        let forever = MaintenanceWindow { until: None, ..x };
+
+        assert!(!forever.is_expired(Instant::now()));
+        assert_eq!(
+            forever.retry_after(Instant::now()),
+            DEFAULT_MAINTENANCE_RETRY_AFTER
+        );
+    }
+
+    #[test]
+    fn balanced_keeps_one_backend() {
+        let all = ["a", "b"];
+
+        let x = MaintenanceWindow {
+            message: None,
+            backends: vec!["a".into()],
+            since: Instant::now(),
+            until: None,
+        };
+
+        assert!(x.covers("a"));
+        assert!(!x.covers("b"));
+        assert!(x.check_backends(all, true).is_ok());
+
+        let both = MaintenanceWindow {
+            backends: vec!["a".into(), "b".into()],
+            ..x.clone()
+        };
+        assert!(both.check_backends(all, true).is_err());
+        assert!(both.check_backends(all, false).is_ok());
+
+        let whole = MaintenanceWindow {
+            backends: vec![],
+            ..x.clone()
+        };
+        assert!(whole.check_backends(all, true).is_err());
+        assert!(whole.check_backends(all, false).is_ok());
+
+        let unknown = MaintenanceWindow {
+            backends: vec!["c".into()],
+            ..x
+        };
+        assert!(unknown.check_backends(all, false).is_err());
+    }
+
+    fn utc(x: &str) -> DateTime<Utc> {
+        x.parse().unwrap()
+    }
+
+    #[test]
+    fn scheduled_window_drains() {
+        // tuesday nights in new york. this runs past midnight
+        let x: ScheduledWindow = serde_json::from_value(serde_json::json!({
+            "days": ["tue"],
+            "start": "23:00",
+            "end": "01:00",
+            "timezone": "America/New_York",
+        }))
+        .unwrap();
+
+        let lead = Duration::from_secs(600);
+
+        // 2023-12-12 is a tuesday. 23:00 EST is 04:00 UTC
+        assert_eq!(x.drain_until(utc("2023-12-13T03:49:59Z"), lead), None);
+        assert_eq!(
+            x.drain_until(utc("2023-12-13T03:50:00Z"), lead),
+            Some(utc("2023-12-13T06:00:00Z"))
+        );
+        assert_eq!(
+            x.drain_until(utc("2023-12-13T05:59:59Z"), lead),
+            Some(utc("2023-12-13T06:00:00Z"))
+        );
+        assert_eq!(x.drain_until(utc("2023-12-13T06:00:00Z"), lead), None);
+
+        // not on wednesdays
+        assert_eq!(x.drain_until(utc("2023-12-14T04:30:00Z"), lead), None);
+
+        // daylight saving time moves the window an hour earlier in UTC
+        assert_eq!(
+            x.drain_until(utc("2024-07-03T02:55:00Z"), lead),
+            Some(utc("2024-07-03T05:00:00Z"))
+        );
+        assert_eq!(x.drain_until(utc("2024-07-03T05:00:00Z"), lead), None);
+
+        assert!(
+            serde_json::from_value::<ScheduledWindow>(serde_json::json!({
+                "start": "23:00",
+                "end": "01:00",
+                "timezone": "Mars/Olympus_Mons",
+            }))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn scheduled_maintenance_readmits_after_health_check() {
+        let window: ScheduledWindow = serde_json::from_value(serde_json::json!({
+            "start": "02:00",
+            "end": "03:00",
+        }))
+        .unwrap();
+
+        assert!(ScheduledMaintenance::new("a".into(), vec![], Duration::from_secs(300)).is_none());
+
+        let x =
+            ScheduledMaintenance::new("a".into(), vec![window], Duration::from_secs(300)).unwrap();
+
+        assert!(!x.draining(utc("2023-12-12T01:54:59Z")));
+        assert_eq!(x.state(), ScheduledMaintenanceState::InService);
+
+        assert!(x.draining(utc("2023-12-12T01:55:00Z")));
+        assert_eq!(
+            x.state(),
+            ScheduledMaintenanceState::Draining {
+                until: utc("2023-12-12T03:00:00Z")
+            }
+        );
+
+        // health checks during the window don't end it early
+        x.health_check_passed();
+        assert!(x.draining(utc("2023-12-12T02:30:00Z")));
+
+        // the window is over, but the backend stays out until it is checked
+        assert!(!x.draining(utc("2023-12-12T03:00:00Z")));
+        assert_eq!(x.state(), ScheduledMaintenanceState::AwaitingHealthCheck);
+        assert!(!x.draining(utc("2023-12-12T03:00:30Z")));
+        assert_eq!(x.state(), ScheduledMaintenanceState::AwaitingHealthCheck);
+
+        x.health_check_passed();
+        assert_eq!(x.state(), ScheduledMaintenanceState::InService);
+    }
+}
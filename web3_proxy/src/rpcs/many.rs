@@ -1,31 +1,38 @@
 //! Load balanced communication with a group of web3 rpc providers
+use super::block_queue::{BlockQueueSender, BLOCK_QUEUE_CAPACITY, BLOCK_QUEUE_SEND_TIMEOUT};
 use super::blockchain::{BlockHeader, BlocksByHashCache, BlocksByNumberCache};
 use super::consensus::{RankedRpcs, RpcsForRequest};
-use super::one::Web3Rpc;
+use super::maintenance::MaintenanceWindow;
+use super::one::{BackendStatus, Web3Rpc, Web3RpcSummary};
 use crate::app::{App, Web3ProxyJoinHandle};
-use crate::config::{average_block_interval, BlockAndRpc, Web3RpcConfig};
+use crate::backend_scores::BackendCounters;
+use crate::config::{average_block_interval, Web3RpcConfig};
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::frontend::status::MokaCacheSerializer;
+use crate::globals::global_app;
 use crate::jsonrpc::ValidatedRequest;
 use crate::jsonrpc::{self, JsonRpcErrorData, JsonRpcParams, JsonRpcResultData};
+use crate::raw_transaction::rejection_rank;
+use crate::response_cache::ForwardedResponse;
+use crate::retry_policy::{is_server_error, RetryPolicy};
 use deduped_broadcast::DedupedBroadcaster;
 use derive_more::From;
 use ethers::prelude::{TxHash, U64};
-use futures::stream::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures_util::future::join_all;
 use hashbrown::HashMap;
-use http::StatusCode;
 use moka::future::CacheBuilder;
 use parking_lot::RwLock;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::fmt::{self, Display};
 use std::sync::Arc;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{sleep_until, Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
 use tokio::{pin, select};
 use tracing::{debug, error, info, trace, warn};
 
@@ -35,10 +42,12 @@ pub struct Web3Rpcs {
     pub(crate) name: Cow<'static, str>,
     pub(crate) chain_id: u64,
     /// if watch_head_block is some, Web3Rpc inside self will send blocks here when they get them
-    pub(crate) block_and_rpc_sender: mpsc::UnboundedSender<(Option<BlockHeader>, Arc<Web3Rpc>)>,
+    pub(crate) block_and_rpc_sender: BlockQueueSender,
     /// any requests will be forwarded to one (or more) of these connections
     /// TODO: hopefully this not being an async lock will be okay. if you need it across awaits, clone the arc
     pub(crate) by_name: RwLock<HashMap<String, Arc<Web3Rpc>>>,
+    /// planned maintenance set by an admin. expired windows are cleared when they are next read
+    pub(crate) maintenance: RwLock<Option<MaintenanceWindow>>,
     /// all providers with the same consensus head block. won't update if there is no `self.watch_head_block`
     /// TODO: why is watch_head_block in an Option, but this one isn't?
     /// TODO: document that this is a watch sender and not a broadcast! if things get busy, blocks might get missed
@@ -110,7 +119,7 @@ impl Web3Rpcs {
         watch::Receiver<Option<Arc<RankedRpcs>>>,
     )> {
         let (block_and_rpc_sender, block_and_rpc_receiver) =
-            mpsc::unbounded_channel::<BlockAndRpc>();
+            BlockQueueSender::channel(BLOCK_QUEUE_CAPACITY, BLOCK_QUEUE_SEND_TIMEOUT);
 
         // these blocks don't have full transactions, but they do have rather variable amounts of transaction hashes
         // TODO: actual weighter on this
@@ -150,6 +159,7 @@ impl Web3Rpcs {
             blocks_by_number,
             by_name,
             chain_id,
+            maintenance: Default::default(),
             max_head_block_age,
             max_head_block_lag,
             min_synced_rpcs: min_head_rpcs,
@@ -207,7 +217,12 @@ impl Web3Rpcs {
 
         let chain_id = app.config.chain_id;
 
-        let block_interval = average_block_interval(chain_id);
+        let block_interval = app.config.block_interval();
+
+        let silent_head_timeout = match app.config.silent_head_subscription_blocks {
+            0 => None,
+            x => Some(block_interval * x),
+        };
 
         let mut names_to_keep = vec![];
 
@@ -223,6 +238,7 @@ impl Web3Rpcs {
                 }
 
                 let http_client = app.http_client.clone();
+                let happy_eyeballs = Some(app.happy_eyeballs.clone());
                 let vredis_pool = app.vredis_pool.clone();
 
                 let block_and_rpc_sender = if self.watch_head_block.is_some() {
@@ -244,16 +260,26 @@ impl Web3Rpcs {
                     chain_id,
                     block_interval,
                     http_client,
+                    happy_eyeballs,
                     blocks_by_hash_cache,
                     block_and_rpc_sender,
                     self.pending_txid_firehose.clone(),
                     self.max_head_block_age,
+                    silent_head_timeout,
                 );
 
                 Some(handle)
             })
             .collect();
 
+        let grace = Duration::from_secs(app.config.config_reload_grace_secs);
+
+        // while we are serving requests, new rpcs need to sync before we count them as working
+        let currently_synced = self.synced();
+
+        let mut num_failed = 0;
+        let mut waits = vec![];
+
         for x in join_all(spawn_handles).await {
             match x {
                 Ok((new_rpc, _handle)) => {
@@ -261,49 +287,97 @@ impl Web3Rpcs {
 
                     let old_rpc = self.by_name.read().get(&new_rpc.name).map(Arc::clone);
 
-                    // clean up the old rpc if it exists
-                    if let Some(old_rpc) = old_rpc {
-                        trace!("old_rpc: {}", old_rpc);
+                    // an rpc that an admin disabled stays disabled when its config is reloaded
+                    if let Some(old_rpc) = old_rpc.as_ref() {
+                        new_rpc.set_admin_disabled(old_rpc.is_admin_disabled());
+                    }
 
-                        // if the old rpc was synced, wait for the new one to sync
-                        if old_rpc
+                    // if the old rpc was synced, wait for the new one to sync
+                    let wait_for_sync = match old_rpc.as_ref() {
+                        Some(old_rpc) => old_rpc
                             .head_block_sender
                             .as_ref()
                             .unwrap()
                             .borrow()
-                            .is_some()
-                        {
-                            let mut new_head_receiver =
-                                new_rpc.head_block_sender.as_ref().unwrap().subscribe();
-                            trace!("waiting for new {} connection to sync", new_rpc);
-
-                            // TODO: maximum wait time
-                            while new_head_receiver.borrow_and_update().is_none() {
-                                if new_head_receiver.changed().await.is_err() {
-                                    break;
-                                };
-                            }
-                        }
-
-                        // new rpc is synced (or old one was not synced). update the local map
-                        // make sure that any new requests use the new connection
-                        self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
-
-                        // tell the old rpc to disconnect
-                        if let Some(ref disconnect_sender) = old_rpc.disconnect_watch {
-                            debug!("telling old {} to disconnect", old_rpc);
-                            disconnect_sender.send_replace(true);
-                        }
-                    } else {
-                        self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
-                    }
+                            .is_some(),
+                        None => currently_synced,
+                    };
+
+                    waits.push(async move {
+                        let synced = !wait_for_sync
+                            || timeout(grace, new_rpc.wait_for_head_block())
+                                .await
+                                .unwrap_or(false);
+
+                        (new_rpc, old_rpc, synced)
+                    });
                 }
                 Err(err) => {
                     // if we got an error here, the app can continue on
                     // TODO: include context about which connection failed
                     // TODO: retry automatically
                     error!("Unable to create connection. err={:?}", err);
+                    num_failed += 1;
+                }
+            }
+        }
+
+        let num_spawned = num_failed + waits.len();
+
+        let ready = join_all(waits).await;
+
+        num_failed += ready.iter().filter(|(_, _, synced)| !synced).count();
+
+        if num_spawned > 0 && num_failed == num_spawned {
+            // nothing has been swapped yet. leave the old rpcs in place
+            for (new_rpc, _, _) in ready {
+                if let Some(ref disconnect_sender) = new_rpc.disconnect_watch {
+                    disconnect_sender.send_replace(true);
+                }
+            }
+
+            return Err(anyhow::anyhow!(
+                "none of the {} rpcs connected within {} seconds",
+                num_failed,
+                grace.as_secs()
+            )
+            .into());
+        }
+
+        for (new_rpc, old_rpc, synced) in ready {
+            // clean up the old rpc if it exists
+            if let Some(old_rpc) = old_rpc {
+                trace!("old_rpc: {}", old_rpc);
+
+                if !synced {
+                    warn!(
+                        "new {} connection did not sync within {} seconds. keeping the old one",
+                        new_rpc,
+                        grace.as_secs()
+                    );
+
+                    if let Some(ref disconnect_sender) = new_rpc.disconnect_watch {
+                        disconnect_sender.send_replace(true);
+                    }
+
+                    continue;
+                }
+
+                // new rpc is synced (or old one was not synced). update the local map
+                // an admin might have toggled the old rpc while the new one was syncing
+                new_rpc.set_admin_disabled(old_rpc.is_admin_disabled());
+
+                // make sure that any new requests use the new connection
+                self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
+
+                // tell the old rpc to disconnect
+                if let Some(ref disconnect_sender) = old_rpc.disconnect_watch {
+                    debug!("telling old {} to disconnect", old_rpc);
+                    disconnect_sender.send_replace(true);
                 }
+            } else {
+                // a brand new rpc keeps trying to connect in the background even if it isn't synced yet
+                self.by_name.write().insert(new_rpc.name.clone(), new_rpc);
             }
         }
 
@@ -333,6 +407,16 @@ impl Web3Rpcs {
         Ok(())
     }
 
+    /// tell every rpc to stop. used once the frontend has shut down
+    pub fn disconnect_all(&self) {
+        for rpc in self.by_name.read().values() {
+            if let Some(ref disconnect_sender) = rpc.disconnect_watch {
+                debug!("telling {} to disconnect. shutting down", rpc);
+                disconnect_sender.send_replace(true);
+            }
+        }
+    }
+
     pub fn get(&self, conn_name: &str) -> Option<Arc<Web3Rpc>> {
         self.by_name.read().get(conn_name).cloned()
     }
@@ -341,10 +425,141 @@ impl Web3Rpcs {
         self.by_name.read().len()
     }
 
+    /// how old the consensus head can be before we stop serving requests
+    pub fn max_head_block_age(&self) -> Duration {
+        self.max_head_block_age
+    }
+
+    /// sorted by name
+    pub fn summaries(&self, consensus_head_num: Option<U64>) -> Vec<Web3RpcSummary> {
+        let mut x: Vec<_> = self
+            .by_name
+            .read()
+            .values()
+            .map(|x| x.summary(consensus_head_num))
+            .collect();
+
+        x.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        x
+    }
+
+    pub fn backend_statuses(&self) -> Vec<BackendStatus> {
+        let mut x: Vec<_> = self
+            .by_name
+            .read()
+            .values()
+            .map(|x| x.backend_status())
+            .collect();
+
+        x.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        x
+    }
+
+    /// Take one rpc out of rotation (or put it back) until the server restarts. Returns the previous value.
+    /// None if this group has no rpc with that name.
+    pub fn set_admin_disabled(
+        &self,
+        name: &str,
+        disabled: bool,
+        keep_one: bool,
+    ) -> Web3ProxyResult<Option<bool>> {
+        let by_name = self.by_name.read();
+
+        let Some(rpc) = by_name.get(name) else {
+            return Ok(None);
+        };
+
+        if disabled
+            && keep_one
+            && by_name
+                .values()
+                .all(|x| x.name == name || x.is_admin_disabled())
+        {
+            return Err(Web3ProxyError::BadRequest(
+                "at least one backend must stay enabled".into(),
+            ));
+        }
+
+        Ok(Some(rpc.set_admin_disabled(disabled)))
+    }
+
+    /// Running totals for the backend scorer, by name
+    pub fn score_counters(
+        &self,
+        consensus_head_num: Option<U64>,
+    ) -> HashMap<String, BackendCounters> {
+        self.by_name
+            .read()
+            .iter()
+            .map(|(name, x)| (name.clone(), x.score_counters(consensus_head_num)))
+            .collect()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.by_name.read().is_empty()
     }
 
+    /// true if any of these rpcs are trusted for the "pending" block tag
+    pub fn has_pending_state(&self) -> bool {
+        self.by_name.read().values().any(|x| x.pending_state)
+    }
+
+    /// The current maintenance window. Expired windows are cleared here.
+    pub fn maintenance(&self) -> Option<MaintenanceWindow> {
+        let now = Instant::now();
+
+        {
+            let x = self.maintenance.read();
+
+            match x.as_ref() {
+                None => return None,
+                Some(x) if !x.is_expired(now) => return Some(x.clone()),
+                Some(_) => {}
+            }
+        }
+
+        let mut x = self.maintenance.write();
+
+        if x.as_ref().map_or(false, |x| x.is_expired(now)) {
+            if let Some(expired) = x.take() {
+                info!(rpcs=%self, backends=?expired.backends, "maintenance expired");
+            }
+        }
+
+        x.clone()
+    }
+
+    /// Start (or replace) maintenance on this group. Returns the previous window.
+    /// If `keep_one`, at least one backend must stay out of maintenance.
+    pub fn start_maintenance(
+        &self,
+        window: MaintenanceWindow,
+        keep_one: bool,
+    ) -> Web3ProxyResult<Option<MaintenanceWindow>> {
+        {
+            let by_name = self.by_name.read();
+
+            window
+                .check_backends(by_name.keys().map(|x| x.as_str()), keep_one)
+                .map_err(Web3ProxyError::BadRequest)?;
+        }
+
+        let previous = self.maintenance.write().replace(window);
+
+        Ok(previous)
+    }
+
+    /// End maintenance on this group. Returns the window that was active.
+    pub fn end_maintenance(&self) -> Option<MaintenanceWindow> {
+        let previous = self.maintenance();
+
+        *self.maintenance.write() = None;
+
+        previous
+    }
+
     /// TODO: rename to be consistent between "head" and "synced"
     pub fn min_head_rpcs(&self) -> usize {
         self.min_synced_rpcs
@@ -361,19 +576,13 @@ impl Web3Rpcs {
             // other places check web3_request ttl. i don't think we need a check here too
             let next_try = match self.try_rpcs_for_request(web3_request).await {
                 Ok(x) => return Ok(x),
-                Err(Web3ProxyError::RateLimited(_, Some(retry_at))) => retry_at,
+                Err(Web3ProxyError::RateLimited(_, Some(retry_at), _)) => retry_at,
                 Err(x) => return Err(x),
             };
 
             if next_try > web3_request.connect_timeout_at() {
-                let retry_in = Instant::now().duration_since(next_try).as_secs().min(1);
-
                 // we don't use Web3ProxyError::RateLimited because that is for the user being rate limited
-                return Err(Web3ProxyError::StatusCode(
-                    StatusCode::TOO_MANY_REQUESTS,
-                    "backend rpcs are all rate limited!".into(),
-                    Some(json!({"retry_in": retry_in})),
-                ));
+                return Err(Web3ProxyError::BackendSaturated(next_try));
             }
 
             trace!(?next_try, "retry needed");
@@ -403,6 +612,58 @@ impl Web3Rpcs {
         &self,
         web3_request: &Arc<ValidatedRequest>,
     ) -> Web3ProxyResult<RpcsForRequest> {
+        // planned maintenance on the whole group is an error. we don't fall back to any other group
+        let maintenance = self.maintenance();
+
+        if let Some(x) = maintenance.as_ref() {
+            if x.is_whole_group() {
+                return Err(Web3ProxyError::Maintenance(self.name.clone(), x.clone()));
+            }
+        }
+
+        // "pending" requests can be pinned to one rpc so that a sequence of them sees the same mempool
+        if let Some(name) = web3_request.pending_rpc.as_deref() {
+            if let Some(rpc) = self.get(name) {
+                if let Some(x) = maintenance.as_ref().filter(|x| x.covers(name)) {
+                    return Err(Web3ProxyError::Maintenance(self.name.clone(), x.clone()));
+                }
+
+                return Ok(RpcsForRequest::designated(rpc, web3_request.clone()));
+            }
+        }
+
+        // "pending" requests go to the rpcs that we trust for pending state. if they are all in maintenance, any rpc will do
+        if web3_request.pending_state {
+            let mut rpcs: Vec<_> = self
+                .by_name
+                .read()
+                .values()
+                .filter(|x| x.pending_state)
+                .filter(|x| !maintenance.as_ref().is_some_and(|m| m.covers(&x.name)))
+                .cloned()
+                .collect();
+
+            // the same order every time so that a sequence of requests sees the same mempool
+            rpcs.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+            if let Some(x) = RpcsForRequest::pinned(rpcs, web3_request.clone()) {
+                return Ok(x);
+            }
+        }
+
+        // vendor methods only go to the rpcs that are configured to support them. there is no fallback
+        if let Some(vendor_method) = web3_request.vendor_method.as_ref() {
+            let rpcs = vendor_method
+                .rpcs
+                .iter()
+                .filter(|name| !maintenance.as_ref().is_some_and(|x| x.covers(name)))
+                .filter_map(|name| self.get(name))
+                .collect();
+
+            return RpcsForRequest::pinned(rpcs, web3_request.clone())
+                .ok_or(Web3ProxyError::NoServersSynced);
+        }
+
         // TODO: by_name might include things that are on a forked
         let ranked_rpcs: Arc<RankedRpcs> =
             if let Some(ranked_rpcs) = self.watch_ranked_rpcs.borrow().clone() {
@@ -426,10 +687,15 @@ impl Web3Rpcs {
                 Arc::new(x)
             };
 
-        match ranked_rpcs.for_request(web3_request) {
-            None => Err(Web3ProxyError::NoServersSynced),
-            Some(x) => Ok(x),
-        }
+        let rpcs = ranked_rpcs.for_request(web3_request);
+
+        // backends in maintenance are skipped
+        let rpcs = match maintenance {
+            None => rpcs,
+            Some(x) => rpcs.and_then(|rpcs| rpcs.without_maintenance(&x)),
+        };
+
+        rpcs.ok_or(Web3ProxyError::NoServersSynced)
     }
 
     pub async fn internal_request<P: JsonRpcParams, R: JsonRpcResultData>(
@@ -441,7 +707,8 @@ impl Web3Rpcs {
         let head_block = self.head_block();
 
         let web3_request =
-            ValidatedRequest::new_internal(method, params, head_block, max_wait).await?;
+            ValidatedRequest::new_internal(self.chain_id, method, params, head_block, max_wait)
+                .await?;
 
         let response = self.request_with_metadata::<R>(&web3_request).await?;
 
@@ -476,9 +743,25 @@ impl Web3Rpcs {
         // TODO: collect the most common error. Web3ProxyError isn't Hash + Eq though. And making it so would be a pain
         let mut errors = vec![];
 
-        // TODO: limit number of tries
         let rpcs = self.try_rpcs_for_request(web3_request).await?;
 
+        if rpcs.all_lack_method() {
+            return Err(self.missing_method_error(web3_request.inner.method()));
+        }
+
+        let retry_policy = global_app(self.chain_id)
+            .map(|x| RetryPolicy::from(&x.config))
+            .unwrap_or_default();
+
+        let method = web3_request.inner.method();
+
+        // backends that failed this request. rpcs that lack the method don't count
+        let mut failed = 0;
+        let mut tried: Vec<Arc<Web3Rpc>> = vec![];
+
+        // a jsonrpc error that is the backend's fault. the client gets it if no other backend does better
+        let mut server_error_response = None;
+
         let stream = rpcs.to_stream();
 
         pin!(stream);
@@ -487,29 +770,103 @@ impl Web3Rpcs {
             // TODO: i'd like to get rid of this clone
             let rpc = active_request_handle.clone_connection();
 
+            // the stream starts over if no rpcs were ready. don't send to the same rpc twice
+            if tried.iter().any(|x| Arc::ptr_eq(x, &rpc)) {
+                continue;
+            }
+
+            tried.push(rpc.clone());
+
             {
                 let mut response_lock = web3_request.response.lock();
 
-                response_lock.backend_rpcs.push(rpc);
+                response_lock.backend_rpcs.push(rpc.clone());
             }
 
             match active_request_handle.request::<R>().await {
                 Ok(response) => {
-                    // TODO: some jsonrpc errors should probably be retried. maybe save in errors
+                    let server_error = match &response {
+                        jsonrpc::SingleResponse::Parsed(x) => matches!(
+                            &x.payload,
+                            jsonrpc::ResponsePayload::Error { error } if is_server_error(error)
+                        ),
+                        jsonrpc::SingleResponse::Stream(_) => false,
+                    };
+
+                    if server_error {
+                        failed += 1;
+
+                        if retry_policy.should_retry(method, failed, web3_request.start_instant) {
+                            debug!(%rpc, %method, failed, "server error. trying the next best server");
+
+                            server_error_response = Some(response);
+
+                            continue;
+                        }
+                    }
+
+                    if failed > 0 {
+                        debug!(%method, failed, tried=?names(&tried), "answered after retries");
+                    }
+
                     return Ok(response);
                 }
+                // every backend would send the same huge response
+                Err(error @ Web3ProxyError::ResponseTooLarge { .. }) => {
+                    return Err(error);
+                }
+                // another rpc might have the method. nothing was sent, so this is always safe to retry
+                Err(error @ Web3ProxyError::MethodNotFound(_)) => {
+                    errors.push(error);
+                }
                 Err(error) => {
-                    // TODO: if this is an error, do NOT return. continue to try on another server
+                    failed += 1;
 
                     // TODO: track the most common errors
+                    if !retry_policy.should_retry(method, failed, web3_request.start_instant) {
+                        debug!(%rpc, %method, failed, ?error, "not retrying");
+
+                        errors.push(error);
+
+                        break;
+                    }
+
+                    debug!(%rpc, %method, failed, ?error, "trying the next best server");
+
                     errors.push(error);
                 }
             }
         }
 
-        // TODO: find the most common error
-        if let Some(err) = errors.into_iter().next() {
-            return Err(err);
+        if failed > 0 {
+            debug!(%method, failed, tried=?names(&tried), "every attempt failed");
+        }
+
+        // the backend's own error says more than any of ours
+        if let Some(response) = server_error_response {
+            return Ok(response);
+        }
+
+        // "method not found" from some rpcs doesn't mean the others can't answer. prefer any other error
+        if let Some(i) = errors
+            .iter()
+            .position(|x| !matches!(x, Web3ProxyError::MethodNotFound(_)))
+        {
+            // TODO: find the most common error
+            return Err(errors.swap_remove(i));
+        }
+
+        if !errors.is_empty() {
+            return Err(self.missing_method_error(web3_request.inner.method()));
+        }
+
+        // every rpc has pruned the block. that's clearer than "not available"
+        if let (Some(requested), Some(oldest)) =
+            (web3_request.min_block_needed(), self.oldest_block())
+        {
+            if requested < oldest {
+                return Err(Web3ProxyError::BeyondPruningHorizon { requested, oldest });
+            }
         }
 
         // let min_block_needed = web3_request.min_block_needed();
@@ -575,7 +932,132 @@ impl Web3Rpcs {
         .into())
     }
 
+    /// The oldest block that any rpc still has state for
+    pub fn oldest_block(&self) -> Option<U64> {
+        self.by_name
+            .read()
+            .values()
+            .filter_map(|x| x.oldest_block())
+            .min()
+    }
+
+    /// Every rpc that we tried lacks `method`. If any other rpc might support it, it is only temporarily unavailable
+    pub fn missing_method_error(&self, method: &str) -> Web3ProxyError {
+        missing_method_error(method, self.by_name.read().values())
+    }
+
     #[allow(clippy::too_many_arguments)]
+    /// Send the request to up to `max_rpcs` servers at the same time and return every result.
+    /// If no servers are ready right now, this falls back to waiting for one the same way `request_with_metadata` does.
+    pub async fn request_with_metadata_from_many<R: JsonRpcResultData>(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        max_rpcs: usize,
+    ) -> Web3ProxyResult<Vec<Web3ProxyResult<jsonrpc::SingleResponse<R>>>> {
+        let rpcs = self.try_rpcs_for_request(web3_request).await?;
+
+        if rpcs.all_lack_method() {
+            return Err(self.missing_method_error(web3_request.inner.method()));
+        }
+
+        let handles = rpcs.open_handles(max_rpcs).await;
+
+        if handles.is_empty() {
+            return Ok(vec![self.request_with_metadata(web3_request).await]);
+        }
+
+        {
+            let mut response_lock = web3_request.response.lock();
+
+            for handle in handles.iter() {
+                response_lock.backend_rpcs.push(handle.clone_connection());
+            }
+        }
+
+        let responses = join_all(handles.into_iter().map(|x| x.request::<R>())).await;
+
+        Ok(responses)
+    }
+
+    /// Send a raw transaction to every ready server at the same time.
+    /// The first server that accepts it (or already has it) answers with the locally computed `txid`.
+    /// The rest keep sending in the background. If every server rejects it, the most specific rejection is returned.
+    pub async fn broadcast_raw_transaction(
+        &self,
+        web3_request: &Arc<ValidatedRequest>,
+        txid: TxHash,
+    ) -> Web3ProxyResult<ForwardedResponse<Arc<RawValue>>> {
+        let rpcs = self.try_rpcs_for_request(web3_request).await?;
+
+        if rpcs.all_lack_method() {
+            return Err(self.missing_method_error(web3_request.inner.method()));
+        }
+
+        let handles = rpcs.open_handles(usize::MAX).await;
+
+        if handles.is_empty() {
+            let response = self
+                .request_with_metadata::<Arc<RawValue>>(web3_request)
+                .await;
+
+            let response = match response {
+                Ok(x) => x.parsed().await.map(jsonrpc::SingleResponse::Parsed),
+                Err(err) => Err(err),
+            };
+
+            let response = ForwardedResponse::try_from(response);
+
+            if rejection_rank(&response).is_none() {
+                return Ok(json!(txid).into());
+            }
+
+            return response;
+        }
+
+        {
+            let mut response_lock = web3_request.response.lock();
+
+            for handle in handles.iter() {
+                response_lock.backend_rpcs.push(handle.clone_connection());
+            }
+        }
+
+        // spawned so that returning early doesn't cancel the slower sends
+        let mut sends = handles
+            .into_iter()
+            .map(|handle| {
+                tokio::spawn(async move {
+                    let response = match handle.request::<Arc<RawValue>>().await {
+                        Ok(x) => x.parsed().await.map(jsonrpc::SingleResponse::Parsed),
+                        Err(err) => Err(err),
+                    };
+
+                    ForwardedResponse::try_from(response)
+                })
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut best: Option<(u8, Web3ProxyResult<ForwardedResponse<Arc<RawValue>>>)> = None;
+
+        while let Some(response) = sends.next().await {
+            let response = response.map_err(Web3ProxyError::from).and_then(|x| x);
+
+            let Some(rank) = rejection_rank(&response) else {
+                return Ok(json!(txid).into());
+            };
+
+            trace!(%txid, ?response, rank, "raw transaction rejected");
+
+            match &best {
+                Some((x, _)) if *x >= rank => {}
+                _ => best = Some((rank, response)),
+            }
+        }
+
+        best.map(|(_, x)| x)
+            .unwrap_or(Err(Web3ProxyError::NoServersSynced))
+    }
+
     pub async fn try_proxy_connection<R: JsonRpcResultData>(
         &self,
         web3_request: &Arc<ValidatedRequest>,
@@ -591,6 +1073,21 @@ impl Web3Rpcs {
     }
 }
 
+fn names(rpcs: &[Arc<Web3Rpc>]) -> Vec<&str> {
+    rpcs.iter().map(|x| x.name.as_str()).collect()
+}
+
+fn missing_method_error<'a>(
+    method: &str,
+    rpcs: impl IntoIterator<Item = &'a Arc<Web3Rpc>>,
+) -> Web3ProxyError {
+    if rpcs.into_iter().any(|x| !x.lacks_method(method)) {
+        Web3ProxyError::MethodTemporarilyUnavailable(method.to_string().into())
+    } else {
+        Web3ProxyError::MethodNotFound(method.to_string().into())
+    }
+}
+
 impl Display for Web3Rpcs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&self.name)
@@ -621,7 +1118,7 @@ impl Serialize for Web3Rpcs {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpcs", 7)?;
+        let mut state = serializer.serialize_struct("Web3Rpcs", 9)?;
 
         {
             let by_name = self.by_name.read();
@@ -637,6 +1134,8 @@ impl Serialize for Web3Rpcs {
 
         state.serialize_field("max_head_block_lag", &self.max_head_block_lag)?;
 
+        state.serialize_field("maintenance", &self.maintenance())?;
+
         {
             let consensus_rpcs = self.watch_ranked_rpcs.borrow().clone();
             // TODO: rename synced_connections to consensus_rpcs
@@ -668,6 +1167,8 @@ impl Serialize for Web3Rpcs {
             ),
         )?;
 
+        state.serialize_field("block_queue", &self.block_and_rpc_sender)?;
+
         state.serialize_field(
             "watch_consensus_rpcs_receivers",
             &self.watch_ranked_rpcs.receiver_count(),
@@ -795,6 +1296,50 @@ mod tests {
         assert_eq!(names_in_sort_order, ["c", "f", "b", "e", "a", "d"]);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_missing_methods_on_disjoint_rpcs() {
+        // erigon has trace_*. geth has debug_*
+        let erigon = Arc::new(Web3Rpc {
+            name: "erigon".to_string(),
+            ..Default::default()
+        });
+        let geth = Arc::new(Web3Rpc {
+            name: "geth".to_string(),
+            ..Default::default()
+        });
+
+        erigon.missing_methods.insert("debug_traceCall");
+        geth.missing_methods.insert("trace_block");
+
+        assert!(geth.lacks_method("trace_block"));
+        assert!(!erigon.lacks_method("trace_block"));
+
+        let rpcs = [erigon.clone(), geth.clone()];
+
+        // geth said "method not found", but erigon might still answer
+        assert!(matches!(
+            missing_method_error("trace_block", &rpcs),
+            Web3ProxyError::MethodTemporarilyUnavailable(_)
+        ));
+        assert!(matches!(
+            missing_method_error("debug_traceCall", &rpcs),
+            Web3ProxyError::MethodTemporarilyUnavailable(_)
+        ));
+
+        // once both say it, the method really isn't supported
+        erigon.missing_methods.insert("trace_block");
+
+        assert!(matches!(
+            missing_method_error("trace_block", &rpcs),
+            Web3ProxyError::MethodNotFound(_)
+        ));
+
+        assert_eq!(
+            erigon.missing_methods.list(),
+            ["debug_traceCall", "trace_block"]
+        );
+    }
+
     // #[test_log::test(tokio::test)]
     // async fn test_server_selection_by_height() {
     //     let now = chrono::Utc::now().timestamp().into();
@@ -1,7 +1,13 @@
 // TODO: all pub, or export useful things here instead?
+pub mod block_queue;
 pub mod blockchain;
+pub mod capabilities;
 pub mod consensus;
+pub mod happy_eyeballs;
+pub mod maintenance;
 pub mod many;
 pub mod one;
 pub mod provider;
+pub mod quarantine;
+pub mod reorg;
 pub mod request;
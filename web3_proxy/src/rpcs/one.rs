@@ -1,13 +1,20 @@
 //! Rate-limited communication with a web3 provider.
+use super::block_queue::BlockQueueSender;
 use super::blockchain::{ArcBlock, BlockHeader, BlocksByHashCache};
+use super::capabilities::MissingMethods;
+use super::happy_eyeballs::{HappyEyeballs, LastFamily};
+use super::maintenance::ScheduledMaintenance;
 use super::provider::{connect_ws, EthersWsProvider};
+use super::quarantine::{Outcome, Quarantine};
 use super::request::{OpenRequestHandle, OpenRequestResult};
 use crate::app::Web3ProxyJoinHandle;
-use crate::config::{BlockAndRpc, Web3RpcConfig};
+use crate::backend_scores::BackendCounters;
+use crate::config::{redacted_url, Web3RpcConfig};
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::globals;
 use crate::jsonrpc::ValidatedRequest;
 use crate::jsonrpc::{self, JsonRpcParams, JsonRpcResultData};
+use crate::metrics::Histogram;
 use crate::rpcs::request::RequestErrorHandler;
 use anyhow::{anyhow, Context};
 use arc_swap::ArcSwapOption;
@@ -20,7 +27,10 @@ use migration::sea_orm::DatabaseConnection;
 use nanorand::tls::TlsWyRand;
 use nanorand::Rng;
 use parking_lot::RwLock;
-use redis_rate_limiter::{RedisPool, RedisRateLimitResult, RedisRateLimiter};
+use redis_rate_limiter::{
+    FallbackStore, MemoryStore, RateLimitStore, RedisPool, RedisRateLimitResult, RedisRateLimiter,
+    RedisStore,
+};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
@@ -32,11 +42,13 @@ use std::path::PathBuf;
 use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
 use tokio::select;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
+use tokio::sync::{watch, Semaphore};
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration, Instant, MissedTickBehavior};
 use tracing::{debug, error, info, trace, warn, Level};
 use url::Url;
 
+pub use web3_proxy_client::types::{BackendStatus, Web3RpcSummary};
+
 /// An active connection to a Web3 RPC server like geth or erigon.
 /// TODO: smarter Default derive or move the channels around so they aren't part of this at all
 #[derive(Default)]
@@ -57,10 +69,24 @@ pub struct Web3Rpc {
     /// if no ipc_stream, most all requests prefer to use the http_provider
     pub(super) http_client: Option<reqwest::Client>,
     pub(super) http_url: Option<Url>,
-    /// the websocket url is only used for subscriptions
+    /// the address family that served the last http response
+    pub(super) http_family: LastFamily,
+    /// races the address families before the websocket connects and keeps the winner for http connects
+    pub(super) happy_eyeballs: Option<Arc<HappyEyeballs>>,
+    /// the websocket url is used for subscriptions. without an http_url or ipc_path, it is used for all requests
     pub(super) ws_url: Option<Url>,
-    /// the websocket provider is only used for subscriptions
+    /// the address family that won the race before the websocket last connected
+    pub(super) ws_family: LastFamily,
+    /// the websocket provider. this is None while reconnecting
     pub(super) ws_provider: ArcSwapOption<EthersWsProvider>,
+    /// incremented every time the websocket provider is dropped. requests still waiting on the old socket then fail
+    /// ws_generation is only inside an Option so that the "Default" derive works. it will always be set.
+    pub(super) ws_generation: Option<watch::Sender<u64>>,
+    /// limits how many requests can wait on the websocket at once
+    /// ws_semaphore is only inside an Option so that the "Default" derive works. it will always be set.
+    pub(super) ws_semaphore: Option<Semaphore>,
+    /// requests sent over the websocket give up after this long
+    pub(super) ws_request_timeout: Duration,
     /// most all requests prefer the ipc provider.
     /// TODO: ArcSwapOption?
     pub(super) ipc_path: Option<PathBuf>,
@@ -76,8 +102,12 @@ pub struct Web3Rpc {
     pub(super) automatic_block_limit: bool,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     pub backup: bool,
+    /// if false, responses from this rpc are never saved in the response cache
+    pub cacheable: bool,
+    /// if true, requests for the "pending" block prefer this rpc
+    pub pending_state: bool,
     /// if subscribed to new heads, blocks are sent through this channel to update a parent Web3Rpcs
-    pub(super) block_and_rpc_sender: Option<mpsc::UnboundedSender<BlockAndRpc>>,
+    pub(super) block_and_rpc_sender: Option<BlockQueueSender>,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
     /// head_block is only inside an Option so that the "Default" derive works. it will always be set.
@@ -87,6 +117,12 @@ pub struct Web3Rpc {
     pub(super) head_delay: RwLock<EwmaLatency>,
     /// false if a health check has failed
     pub(super) healthy: AtomicBool,
+    /// set by an admin. a disabled rpc keeps following the chain, but it gets no requests until it is enabled again
+    pub(crate) admin_disabled: AtomicBool,
+    /// weekly windows from the config. None if there aren't any
+    pub(crate) scheduled_maintenance: Option<ScheduledMaintenance>,
+    /// benches this rpc when too many recent requests failed. None if turned off
+    pub(crate) quarantine: Option<Quarantine>,
     /// Track peak request latency
     /// peak_latency is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) peak_latency: Option<PeakEwmaLatency>,
@@ -98,6 +134,24 @@ pub struct Web3Rpc {
     pub(super) external_requests: AtomicUsize,
     /// If the head block is too old, it is ignored.
     pub(super) max_head_block_age: Duration,
+    /// methods that this rpc recently said it does not support
+    pub(super) missing_methods: MissingMethods,
+    /// if the head subscription is quiet for this long, check that the node hasn't moved on without it
+    pub(super) silent_head_timeout: Option<Duration>,
+    /// how many times the head subscription went quiet while the node kept advancing
+    pub(crate) silent_subscription_deaths: AtomicU64,
+    /// how long this rpc took to answer. successes and errors
+    pub(crate) request_latency: Histogram,
+    /// how long http requests waited for the response headers. connecting plus the rpc's time to first byte
+    pub(crate) headers_latency: Histogram,
+    /// moving average of `headers_latency`
+    pub(super) headers_delay: RwLock<EwmaLatency>,
+    /// failed responses that were the rpc's fault. connection errors, rate limits, and crashes. not bad requests
+    pub(crate) backend_errors: AtomicU64,
+    /// pending transactions this rpc told us about
+    pub(crate) pending_txs: AtomicU64,
+    /// pending transactions that this rpc told us about before any other rpc did
+    pub(crate) unique_pending_txs: AtomicU64,
     /// Track time used by external requests served
     /// request_ms_histogram is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) median_latency: Option<RollingQuantileLatency>,
@@ -118,43 +172,41 @@ impl Web3Rpc {
         chain_id: u64,
         // optional because this is only used for http providers. websocket-only providers don't use it
         http_client: Option<reqwest::Client>,
+        happy_eyeballs: Option<Arc<HappyEyeballs>>,
         redis_pool: Option<RedisPool>,
         server_id: i64,
         block_interval: Duration,
         block_map: BlocksByHashCache,
-        block_and_rpc_sender: Option<mpsc::UnboundedSender<BlockAndRpc>>,
+        block_and_rpc_sender: Option<BlockQueueSender>,
         pending_txid_firehose: Option<Arc<DedupedBroadcaster<TxHash>>>,
         max_head_block_age: Duration,
+        silent_head_timeout: Option<Duration>,
     ) -> anyhow::Result<(Arc<Web3Rpc>, Web3ProxyJoinHandle<()>)> {
         let created_at = Instant::now();
 
-        let hard_limit = match (config.hard_limit, redis_pool) {
-            (None, None) => None,
-            (Some(hard_limit), Some(redis_pool)) => {
-                let label = if config.hard_limit_per_endpoint {
-                    format!("{}:{}:{}", chain_id, "endpoint", name)
-                } else {
-                    format!("{}:{}:{}", chain_id, server_id, name)
-                };
+        let hard_limit = config.hard_limit.map(|hard_limit| {
+            let label = if config.hard_limit_per_endpoint {
+                format!("{}:{}:{}", chain_id, "endpoint", name)
+            } else {
+                format!("{}:{}:{}", chain_id, server_id, name)
+            };
 
-                // TODO: in process rate limiter instead? or maybe deferred? or is this good enough?
-                let rrl = RedisRateLimiter::new(
-                    "web3_proxy",
-                    &label,
-                    hard_limit,
-                    config.hard_limit_period as f32,
-                    redis_pool,
-                );
+            // without redis (or while it is down), the limit is only for this server. the app warns about that once
+            let store: Arc<dyn RateLimitStore> = match redis_pool {
+                Some(redis_pool) => {
+                    Arc::new(FallbackStore::new(Arc::new(RedisStore::new(redis_pool))))
+                }
+                None => Arc::new(MemoryStore::default()),
+            };
 
-                Some(rrl)
-            }
-            (None, Some(_)) => None,
-            (Some(_hard_limit), None) => {
-                return Err(anyhow::anyhow!(
-                    "no redis client pool! needed for hard limit"
-                ))
-            }
-        };
+            RedisRateLimiter::with_store(
+                "web3_proxy",
+                &label,
+                hard_limit,
+                config.hard_limit_period as f32,
+                store,
+            )
+        });
 
         let backup = config.backup;
 
@@ -207,6 +259,8 @@ impl Web3Rpc {
 
         let (disconnect_watch, _) = watch::channel(false);
 
+        let (ws_generation, _) = watch::channel(0);
+
         // TODO: start optimistically?
         let healthy = false.into();
 
@@ -217,10 +271,20 @@ impl Web3Rpc {
             None
         };
 
+        let scheduled_maintenance = ScheduledMaintenance::new(
+            name.clone(),
+            config.maintenance_windows.clone(),
+            config.maintenance_lead(),
+        );
+
+        let quarantine = Quarantine::new(name.clone(), &config);
+
         let new_rpc = Self {
             automatic_block_limit,
             backup,
             block_data_limit,
+            cacheable: config.cacheable,
+            pending_state: config.pending_state,
             block_interval,
             block_map: Some(block_map),
             chain_id,
@@ -231,6 +295,7 @@ impl Web3Rpc {
             head_block_sender: Some(head_block),
             http_url,
             http_client,
+            happy_eyeballs,
             ipc_path: config.ipc_path,
             max_head_block_age,
             name,
@@ -239,9 +304,15 @@ impl Web3Rpc {
             soft_limit: config.soft_limit,
             pending_txid_firehose,
             block_and_rpc_sender,
+            silent_head_timeout,
             ws_url,
+            ws_generation: Some(ws_generation),
+            ws_semaphore: Some(Semaphore::new(config.ws_max_concurrent_requests)),
+            ws_request_timeout: Duration::from_millis(config.ws_request_timeout_ms),
             disconnect_watch: Some(disconnect_watch),
             healthy,
+            scheduled_maintenance,
+            quarantine,
             ..Default::default()
         };
 
@@ -257,6 +328,25 @@ impl Web3Rpc {
         Ok((new_connection, handle))
     }
 
+    /// Wait until this rpc has a head block. False if it shut down first.
+    pub async fn wait_for_head_block(&self) -> bool {
+        let Some(head_block_sender) = self.head_block_sender.as_ref() else {
+            return false;
+        };
+
+        let mut head_block_receiver = head_block_sender.subscribe();
+
+        trace!("waiting for new {} connection to sync", self);
+
+        while head_block_receiver.borrow_and_update().is_none() {
+            if head_block_receiver.changed().await.is_err() {
+                return false;
+            }
+        }
+
+        true
+    }
+
     pub fn next_available(&self, now: Instant) -> Instant {
         if let Some(hard_limit_until) = self.hard_limit_until.as_ref() {
             let hard_limit_until = *hard_limit_until.borrow();
@@ -347,12 +437,21 @@ impl Web3Rpc {
         (sort_on, r)
     }
 
-    pub fn weighted_peak_latency(&self) -> Duration {
-        let peak_latency = if let Some(peak_latency) = self.peak_latency.as_ref() {
+    /// Tiers start at 1. Lower tiers have lower median latency and are tried first
+    pub fn tier(&self) -> u32 {
+        self.tier.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn peak_request_latency(&self) -> Duration {
+        if let Some(peak_latency) = self.peak_latency.as_ref() {
             peak_latency.latency()
         } else {
             Duration::from_secs(1)
-        };
+        }
+    }
+
+    pub fn weighted_peak_latency(&self) -> Duration {
+        let peak_latency = self.peak_request_latency();
 
         // TODO: what scaling?
         // TODO: figure out how many requests add what level of latency
@@ -453,11 +552,147 @@ impl Web3Rpc {
         Ok(limit)
     }
 
+    pub fn summary(&self, consensus_head_num: Option<U64>) -> Web3RpcSummary {
+        let head_block_num = self
+            .head_block_sender
+            .as_ref()
+            .and_then(|x| x.borrow().as_ref().map(|x| x.number()));
+
+        let lag = match (consensus_head_num, head_block_num) {
+            (Some(consensus), Some(ours)) => Some(consensus.saturating_sub(ours).as_u64()),
+            _ => None,
+        };
+
+        let peak_latency_ms = self
+            .peak_latency
+            .as_ref()
+            .map(|x| x.latency().as_secs_f32() * 1000.0)
+            .unwrap_or_default();
+
+        Web3RpcSummary {
+            name: self.name.clone(),
+            backup: self.backup,
+            healthy: self.healthy.load(atomic::Ordering::SeqCst),
+            head_block_num,
+            lag,
+            active_requests: self.active_requests.load(atomic::Ordering::SeqCst),
+            peak_latency_ms,
+        }
+    }
+
+    /// For `GET /admin/backends`
+    pub fn backend_status(&self) -> BackendStatus {
+        let url = self
+            .http_url
+            .as_ref()
+            .or(self.ws_url.as_ref())
+            .map(|x| redacted_url(x.as_str()));
+
+        BackendStatus {
+            name: self.name.clone(),
+            url,
+            backup: self.backup,
+            head_block_num: self.head_block_num(),
+            soft_limit: self.soft_limit,
+            hard_limit: self.hard_limit.as_ref().map(|x| x.max_requests_per_period),
+            active_requests: self.active_requests.load(atomic::Ordering::SeqCst),
+            healthy: self.is_healthy(),
+            quarantined: self.is_quarantined(),
+            disabled: self.is_admin_disabled(),
+        }
+    }
+
+    pub fn head_block_num(&self) -> Option<U64> {
+        self.head_block_sender
+            .as_ref()
+            .and_then(|x| x.borrow().as_ref().map(|x| x.number()))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn is_admin_disabled(&self) -> bool {
+        self.admin_disabled.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Take this rpc out of rotation (or put it back). Returns the previous value
+    pub fn set_admin_disabled(&self, disabled: bool) -> bool {
+        self.admin_disabled.swap(disabled, atomic::Ordering::SeqCst)
+    }
+
+    /// external and internal requests sent to this rpc
+    pub fn request_counts(&self) -> (usize, usize) {
+        (
+            self.external_requests.load(atomic::Ordering::SeqCst),
+            self.internal_requests.load(atomic::Ordering::SeqCst),
+        )
+    }
+
+    /// Running totals for the backend scorer. It compares these to the last ones it saw
+    pub fn score_counters(&self, consensus_head_num: Option<U64>) -> BackendCounters {
+        let head_block_num = self
+            .head_block_sender
+            .as_ref()
+            .and_then(|x| x.borrow().as_ref().map(|x| x.number()));
+
+        let lag = match (consensus_head_num, head_block_num) {
+            (Some(consensus), Some(ours)) => Some(consensus.saturating_sub(ours).as_u64()),
+            _ => None,
+        };
+
+        let requests = (self.external_requests.load(atomic::Ordering::SeqCst)
+            + self.internal_requests.load(atomic::Ordering::SeqCst)) as u64;
+
+        // the median starts out at 0 and would look perfect
+        let latency_ms = if requests > 0 {
+            self.median_latency
+                .as_ref()
+                .map(|x| x.latency().as_secs_f64() * 1000.0)
+        } else {
+            None
+        };
+
+        // only rpcs that are subscribed to heads have a head delay
+        let head_delay_ms = self
+            .block_and_rpc_sender
+            .is_some()
+            .then(|| self.head_delay.read().latency().as_secs_f64() * 1000.0);
+
+        BackendCounters {
+            backup: self.backup,
+            soft_limit: self.soft_limit,
+            latency_ms,
+            head_delay_ms,
+            lag,
+            requests,
+            errors: self.backend_errors.load(atomic::Ordering::Relaxed),
+            silent_deaths: self
+                .silent_subscription_deaths
+                .load(atomic::Ordering::Relaxed),
+            pending_txs: self.pending_txs.load(atomic::Ordering::Relaxed),
+            unique_pending_txs: self.unique_pending_txs.load(atomic::Ordering::Relaxed),
+        }
+    }
+
     /// TODO: this might be too simple. different nodes can prune differently. its possible we will have a block range
     pub fn block_data_limit(&self) -> U64 {
         self.block_data_limit.load(atomic::Ordering::SeqCst).into()
     }
 
+    /// the oldest block that this rpc still has state for. None until it has a head block
+    pub fn oldest_block(&self) -> Option<U64> {
+        let head_block_num = self.head_block_sender.as_ref()?.borrow().as_ref()?.number();
+
+        Some(head_block_num.saturating_sub(self.block_data_limit()))
+    }
+
+    /// true if this rpc recently responded to `method` with "method not found"
+    #[inline]
+    pub fn lacks_method(&self, method: &str) -> bool {
+        self.missing_methods.contains(method)
+    }
+
     /// TODO: get rid of this now that consensus rpcs does it
     pub fn has_block_data(&self, needed_block_num: U64) -> bool {
         if let Some(head_block_sender) = self.head_block_sender.as_ref() {
@@ -627,8 +862,10 @@ impl Web3Rpc {
         if let Some(block_and_rpc_sender) = &self.block_and_rpc_sender {
             // tell web3rpcs about this rpc having this block
             // web3rpcs will do `self.head_block_sender.send_replace(new_head_block)`
+            // if the queue stays full, this head is dropped. our next head will replace it
             block_and_rpc_sender
                 .send((new_head_block, self.clone()))
+                .await
                 .context("block_and_rpc_sender failed sending")?;
         } else {
             head_block_sender.send_replace(new_head_block);
@@ -642,6 +879,89 @@ impl Web3Rpc {
         *self.disconnect_watch.as_ref().unwrap().borrow()
     }
 
+    /// True while a scheduled maintenance window has this rpc out of rotation. It is marked unhealthy then
+    fn draining_for_maintenance(&self) -> bool {
+        self.draining_for_maintenance_at(chrono::Utc::now())
+    }
+
+    pub(crate) fn draining_for_maintenance_at(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        let draining = self
+            .scheduled_maintenance
+            .as_ref()
+            .is_some_and(|x| x.draining(now));
+
+        if draining {
+            self.healthy.store(false, atomic::Ordering::SeqCst);
+        }
+
+        draining
+    }
+
+    /// Back in rotation. This includes rpcs that were waiting on a health check after a maintenance window
+    pub(crate) fn health_check_passed(&self) {
+        self.healthy.store(true, atomic::Ordering::SeqCst);
+
+        if let Some(x) = self.scheduled_maintenance.as_ref() {
+            x.health_check_passed();
+        }
+    }
+
+    /// true while too many recent requests failed. quarantined rpcs are skipped until a probe recovers them
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantine.as_ref().is_some_and(|x| x.is_quarantined())
+    }
+
+    /// Remember how a request went. A newly quarantined rpc is probed in the background until it recovers
+    pub(crate) fn record_outcome(self: &Arc<Self>, outcome: Outcome) {
+        let Some(quarantine) = self.quarantine.as_ref() else {
+            return;
+        };
+
+        if quarantine.record(outcome) {
+            let rpc = self.clone();
+
+            tokio::spawn(async move { rpc.probe_quarantine().await });
+        }
+    }
+
+    /// The probes' outcomes are recorded like any other request. That is what ends the quarantine
+    async fn probe_quarantine(self: Arc<Self>) {
+        let Some(quarantine) = self.quarantine.as_ref() else {
+            return;
+        };
+
+        while quarantine.is_quarantined() && !self.should_disconnect() {
+            sleep(quarantine.probe_interval).await;
+
+            if let Err(err) = self
+                .internal_request::<_, U64>(
+                    "eth_blockNumber".into(),
+                    &[(); 0],
+                    Some(Level::TRACE.into()),
+                    Some(Duration::from_secs(5)),
+                )
+                .await
+            {
+                debug!(?err, "quarantine probe on {} failed", self);
+            }
+        }
+    }
+
+    /// true if there is no http or ipc, so requests are sent over the websocket
+    pub fn ws_only(&self) -> bool {
+        self.ws_url.is_some() && self.http_url.is_none() && self.ipc_path.is_none()
+    }
+
+    /// drop the websocket provider. requests still waiting on it fail with a retryable error
+    fn disconnect_ws(&self) {
+        if self.ws_provider.swap(None).is_some() {
+            self.ws_generation
+                .as_ref()
+                .expect("ws_generation is always set")
+                .send_modify(|x| *x += 1);
+        }
+    }
+
     async fn check_health(
         self: &Arc<Self>,
         detailed_healthcheck: bool,
@@ -700,7 +1020,12 @@ impl Web3Rpc {
     /// TODO: this needs to be a subscribe_with_reconnect that does a retry with jitter and exponential backoff
     async fn subscribe_with_reconnect(self: Arc<Self>) -> Web3ProxyResult<()> {
         loop {
-            if let Err(err) = self.clone().subscribe().await {
+            let result = self.clone().subscribe().await;
+
+            // subscribe might have exited early. make sure nothing waits on the old websocket
+            self.disconnect_ws();
+
+            if let Err(err) = result {
                 if self.should_disconnect() {
                     break;
                 }
@@ -740,7 +1065,18 @@ impl Web3Rpc {
         if let Some(url) = self.ws_url.clone() {
             trace!("starting websocket provider on {}", self);
 
-            let x = connect_ws(url, usize::MAX).await?;
+            // websocket-only rpcs reconnect here instead of inside ethers. that way requests waiting on a dead socket fail
+            // and are tried on another rpc instead of waiting for the reconnect
+            let reconnects = if self.ws_only() { 0 } else { usize::MAX };
+
+            // ethers resolves the host again, so this can't pick the address. it does fail fast if nothing answers
+            if let Some(happy_eyeballs) = self.happy_eyeballs.as_ref() {
+                let family = happy_eyeballs.check_url(&url).await?;
+
+                self.ws_family.set(family);
+            }
+
+            let x = connect_ws(url, reconnects).await?;
 
             let x = Arc::new(x);
 
@@ -794,8 +1130,12 @@ impl Web3Rpc {
                     // TODO: i think there is an erigon bug when fetching transactions from a fresh block. disable detailed health checks for now
                     let detailed_healthcheck = false;
 
-                    // TODO: if this fails too many times, reset the connection
-                    if let Err(err) = rpc.check_health(detailed_healthcheck, error_handler).await {
+                    if rpc.draining_for_maintenance() {
+                        // the node is expected to be down. don't log errors about it
+                    } else if let Err(err) =
+                        rpc.check_health(detailed_healthcheck, error_handler).await
+                    {
+                        // TODO: if this fails too many times, reset the connection
                         rpc.healthy.store(false, atomic::Ordering::SeqCst);
 
                         // TODO: different level depending on the error handler
@@ -806,7 +1146,7 @@ impl Web3Rpc {
                             error!(?err, "health check on {} failed", rpc);
                         }
                     } else {
-                        rpc.healthy.store(true, atomic::Ordering::SeqCst);
+                        rpc.health_check_passed();
                     }
 
                     // TODO: should we count the requests done inside this health check
@@ -819,19 +1159,19 @@ impl Web3Rpc {
             };
 
             // TODO: log quick_check lik
-            let initial_check = if let Err(err) = self.check_health(false, error_handler).await {
+            if self.draining_for_maintenance() {
+                // the health check loop checks again once the window is over
+            } else if let Err(err) = self.check_health(false, error_handler).await {
                 if self.backup {
                     warn!(?err, "initial health check on {} failed", self);
                 } else {
                     error!(?err, "initial health check on {} failed", self);
                 }
 
-                false
+                self.healthy.store(false, atomic::Ordering::SeqCst);
             } else {
-                true
-            };
-
-            self.healthy.store(initial_check, atomic::Ordering::SeqCst);
+                self.health_check_passed();
+            }
 
             tokio::spawn(f)
         } else {
@@ -845,8 +1185,10 @@ impl Web3Rpc {
                         break;
                     }
 
-                    // TODO: if this fails too many times, reset the connection
-                    if let Err(err) = rpc.check_provider().await {
+                    if rpc.draining_for_maintenance() {
+                        // the node is expected to be down. don't log errors about it
+                    } else if let Err(err) = rpc.check_provider().await {
+                        // TODO: if this fails too many times, reset the connection
                         rpc.healthy.store(false, atomic::Ordering::SeqCst);
 
                         // TODO: if rate limit error, set "retry_at"
@@ -855,8 +1197,13 @@ impl Web3Rpc {
                         } else {
                             error!(?err, "provider check on {} failed", rpc);
                         }
+
+                        // without a head block subscription, nothing else notices a dead websocket
+                        if rpc.ws_only() {
+                            return Err(err);
+                        }
                     } else {
-                        rpc.healthy.store(true, atomic::Ordering::SeqCst);
+                        rpc.health_check_passed();
                     }
 
                     sleep(Duration::from_secs(health_sleep_seconds)).await;
@@ -889,7 +1236,7 @@ impl Web3Rpc {
             let clone = self.clone();
 
             let f = async move {
-                let app = globals::APP.get().unwrap();
+                let app = globals::global_app(clone.chain_id).unwrap();
                 let permit = app.tx_subscriptions.acquire().await?;
 
                 let result = clone.subscribe_new_transactions().await;
@@ -924,7 +1271,7 @@ impl Web3Rpc {
         }
 
         // TODO: tell ethers to disconnect? i think dropping will do that
-        self.ws_provider.store(None);
+        self.disconnect_ws();
 
         Ok(())
     }
@@ -943,7 +1290,12 @@ impl Web3Rpc {
             let mut pending_txs_sub = ws_provider.subscribe_pending_txs().await?;
 
             while let Some(x) = pending_txs_sub.next().await {
-                pending_txid_firehose.send(x).await;
+                self.pending_txs.fetch_add(1, atomic::Ordering::Relaxed);
+
+                if pending_txid_firehose.send(x).await {
+                    self.unique_pending_txs
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                }
             }
         } else {
             // only websockets subscribe to pending transactions
@@ -969,26 +1321,58 @@ impl Web3Rpc {
             self.wait_for_throttle(Instant::now() + Duration::from_secs(5))
                 .await?;
 
-            let mut blocks = ws_provider.subscribe_blocks().await?;
+            'subscribe: loop {
+                let mut blocks = ws_provider.subscribe_blocks().await?;
 
-            // query the block once since the subscription doesn't send the current block
-            // there is a very small race condition here where the stream could send us a new block right now
-            // but sending the same block twice won't break anything
-            let latest_block: Result<Option<ArcBlock>, _> = self
-                .internal_request(
-                    "eth_getBlockByNumber".into(),
-                    &("latest", false),
-                    error_handler,
-                    Some(Duration::from_secs(5)),
-                )
-                .await;
+                // query the block once since the subscription doesn't send the current block
+                // there is a very small race condition here where the stream could send us a new block right now
+                // but sending the same block twice won't break anything
+                let latest_block: Result<Option<ArcBlock>, _> = self
+                    .internal_request(
+                        "eth_getBlockByNumber".into(),
+                        &("latest", false),
+                        error_handler,
+                        Some(Duration::from_secs(5)),
+                    )
+                    .await;
 
-            self.send_head_block_result(latest_block).await?;
+                let mut last_head_num = latest_block
+                    .as_ref()
+                    .ok()
+                    .and_then(|x| x.as_ref())
+                    .and_then(|x| x.number);
 
-            while let Some(block) = blocks.next().await {
-                let block = Ok(Some(Arc::new(block)));
+                self.send_head_block_result(latest_block).await?;
 
-                self.send_head_block_result(block).await?;
+                loop {
+                    let block = match self.silent_head_timeout {
+                        None => blocks.next().await,
+                        Some(silent_head_timeout) => {
+                            match timeout(silent_head_timeout, blocks.next()).await {
+                                Ok(x) => x,
+                                Err(_) => {
+                                    if self.head_subscription_is_silent(last_head_num).await {
+                                        // dropping the stream unsubscribes
+                                        drop(blocks);
+                                        continue 'subscribe;
+                                    }
+
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+
+                    let Some(block) = block else {
+                        break 'subscribe;
+                    };
+
+                    last_head_num = block.number;
+
+                    let block = Ok(Some(Arc::new(block)));
+
+                    self.send_head_block_result(block).await?;
+                }
             }
         } else if self.http_client.is_some() {
             // there is a "watch_blocks" function, but a lot of public nodes (including llamanodes) do not support the necessary rpc endpoints
@@ -1026,6 +1410,46 @@ impl Web3Rpc {
         }
     }
 
+    /// The head subscription hasn't sent anything in `silent_head_timeout`. Ask the node for its head some other way.
+    /// True if the node has moved past `last_head_num`, which means the subscription died without telling us.
+    /// False if the node really is stuck (that is for the consensus and staleness checks to handle) or the probe failed.
+    async fn head_subscription_is_silent(self: &Arc<Self>, last_head_num: Option<U64>) -> bool {
+        // this prefers http, so it works even if the websocket is what is broken
+        let head_num = match self
+            .internal_request::<_, U64>(
+                "eth_blockNumber".into(),
+                &[(); 0],
+                Some(Level::DEBUG.into()),
+                Some(Duration::from_secs(5)),
+            )
+            .await
+        {
+            Ok(x) => x,
+            Err(err) => {
+                debug!(?err, "head probe on {} failed", self);
+                return false;
+            }
+        };
+
+        if last_head_num.map_or(true, |x| head_num > x) {
+            self.silent_subscription_deaths
+                .fetch_add(1, atomic::Ordering::Relaxed);
+
+            warn!(
+                ?last_head_num,
+                %head_num,
+                "head subscription on {} went silent while the node advanced. resubscribing",
+                self
+            );
+
+            true
+        } else {
+            trace!(%head_num, "{} has not advanced", self);
+
+            false
+        }
+    }
+
     pub async fn wait_for_request_handle(
         self: &Arc<Self>,
         web3_request: &Arc<ValidatedRequest>,
@@ -1167,13 +1591,26 @@ impl Web3Rpc {
         error_handler: Option<RequestErrorHandler>,
         allow_unhealthy: bool,
     ) -> Web3ProxyResult<OpenRequestResult> {
-        // TODO: if websocket is reconnecting, return an error?
+        if self.ws_only() && self.ws_provider.load().is_none() {
+            // the websocket is reconnecting
+            return Ok(OpenRequestResult::Failed);
+        }
 
         if !allow_unhealthy {
             if !(self.healthy.load(atomic::Ordering::SeqCst)) {
                 return Ok(OpenRequestResult::Failed);
             }
 
+            if self.is_quarantined() {
+                trace!("{} is quarantined", self);
+                return Ok(OpenRequestResult::Failed);
+            }
+
+            if self.is_admin_disabled() {
+                trace!("{} is disabled", self);
+                return Ok(OpenRequestResult::Failed);
+            }
+
             if self.block_and_rpc_sender.is_some() {
                 // make sure this rpc has the oldest block that this request needs
                 if let Some(block_needed) = web3_request.min_block_needed() {
@@ -1369,7 +1806,7 @@ impl Serialize for Web3Rpc {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpc", 16)?;
+        let mut state = serializer.serialize_struct("Web3Rpc", 27)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1378,6 +1815,10 @@ impl Serialize for Web3Rpc {
 
         state.serialize_field("backup", &self.backup)?;
 
+        state.serialize_field("cacheable", &self.cacheable)?;
+
+        state.serialize_field("pending_state", &self.pending_state)?;
+
         state.serialize_field("web3_clientVersion", &self.client_version.read().as_ref())?;
 
         match self.block_data_limit.load(atomic::Ordering::SeqCst) {
@@ -1416,6 +1857,15 @@ impl Serialize for Web3Rpc {
             &self.active_requests.load(atomic::Ordering::SeqCst),
         )?;
 
+        state.serialize_field("missing_methods", &self.missing_methods)?;
+
+        state.serialize_field(
+            "silent_subscription_deaths",
+            &self
+                .silent_subscription_deaths
+                .load(atomic::Ordering::Relaxed),
+        )?;
+
         {
             let head_delay_ms = self.head_delay.read().latency().as_secs_f32() * 1000.0;
             state.serialize_field("head_delay_ms", &(head_delay_ms))?;
@@ -1437,6 +1887,10 @@ impl Serialize for Web3Rpc {
                 self.peak_latency.as_ref().unwrap().latency().as_secs_f32() * 1000.0;
             state.serialize_field("peak_latency_ms", &peak_latency_ms)?;
         }
+        {
+            let headers_latency_ms = self.headers_delay.read().latency().as_secs_f32() * 1000.0;
+            state.serialize_field("headers_latency_ms", &headers_latency_ms)?;
+        }
         {
             let weighted_latency_ms = self.weighted_peak_latency().as_secs_f32() * 1000.0;
             state.serialize_field("weighted_latency_ms", &weighted_latency_ms)?;
@@ -1445,6 +1899,16 @@ impl Serialize for Web3Rpc {
             let healthy = self.healthy.load(atomic::Ordering::SeqCst);
             state.serialize_field("healthy", &healthy)?;
         }
+        state.serialize_field("admin_disabled", &self.is_admin_disabled())?;
+        {
+            let maintenance = self.scheduled_maintenance.as_ref().map(|x| x.state());
+            state.serialize_field("scheduled_maintenance", &maintenance)?;
+        }
+        state.serialize_field("quarantine", &self.quarantine)?;
+
+        // a backend that should be on ipv6 but keeps ending up on ipv4 probably has a broken ipv6 path
+        state.serialize_field("http_family", &self.http_family)?;
+        state.serialize_field("ws_family", &self.ws_family)?;
 
         state.end()
     }
@@ -1469,6 +1933,10 @@ impl fmt::Debug for Web3Rpc {
 
         f.field("weighted_ms", &self.weighted_peak_latency().as_millis());
 
+        if let Some(quarantine) = self.quarantine.as_ref() {
+            f.field("quarantine", quarantine);
+        }
+
         if let Some(head_block_watch) = self.head_block_sender.as_ref() {
             if let Some(head_block) = head_block_watch.borrow().as_ref() {
                 f.field("head_num", &head_block.number());
@@ -1492,7 +1960,39 @@ impl fmt::Display for Web3Rpc {
 mod tests {
     #![allow(unused_imports)]
     use super::*;
+    use crate::test_utils::TestAnvil;
     use ethers::types::{Block, H256, U256};
+    use moka::future::CacheBuilder;
+
+    #[test]
+    fn test_scheduled_maintenance_takes_rpc_out_of_rotation() {
+        let window = serde_json::from_value(json!({"start": "02:00", "end": "03:00"})).unwrap();
+
+        let x = Web3Rpc {
+            name: "a".to_string(),
+            scheduled_maintenance: ScheduledMaintenance::new(
+                "a".to_string(),
+                vec![window],
+                Duration::from_secs(300),
+            ),
+            ..Default::default()
+        };
+
+        x.health_check_passed();
+        assert!(x.healthy.load(atomic::Ordering::SeqCst));
+
+        let now: chrono::DateTime<chrono::Utc> = "2023-12-12T01:56:00Z".parse().unwrap();
+        assert!(x.draining_for_maintenance_at(now));
+        assert!(!x.healthy.load(atomic::Ordering::SeqCst));
+
+        // out until a health check passes
+        let now: chrono::DateTime<chrono::Utc> = "2023-12-12T03:00:00Z".parse().unwrap();
+        assert!(!x.draining_for_maintenance_at(now));
+        assert!(!x.healthy.load(atomic::Ordering::SeqCst));
+
+        x.health_check_passed();
+        assert!(x.healthy.load(atomic::Ordering::SeqCst));
+    }
 
     #[test]
     fn test_archive_node_has_block_data() {
@@ -1565,6 +2065,87 @@ mod tests {
         assert!(!x.has_block_data(head_block.number() + 1000));
     }
 
+    #[tokio::test]
+    async fn ws_only_requests() {
+        let a = TestAnvil::spawn(31337).await;
+
+        let config = Web3RpcConfig {
+            ws_url: Some(a.instance.ws_endpoint()),
+            ws_max_concurrent_requests: 1,
+            ws_request_timeout_ms: 1_000,
+            ..Default::default()
+        };
+
+        let (rpc, handle) = config
+            .spawn(
+                "anvil_ws".to_string(),
+                None,
+                0,
+                31337,
+                Duration::from_secs(1),
+                None,
+                None,
+                CacheBuilder::new(100).build(),
+                None,
+                None,
+                Duration::from_secs(60),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(rpc.ws_only());
+
+        // wait for the websocket to connect
+        let chain_id = loop {
+            match rpc
+                .internal_request::<_, U64>("eth_chainId".into(), &[(); 0], None, None)
+                .await
+            {
+                Ok(x) => break x,
+                Err(_) => sleep(Duration::from_millis(100)).await,
+            }
+        };
+
+        assert_eq!(chain_id, 31337.into());
+
+        // with the only permit taken, requests wait until they time out
+        let permit = rpc.ws_semaphore.as_ref().unwrap().acquire().await.unwrap();
+
+        let err = rpc
+            .internal_request::<_, U64>("eth_chainId".into(), &[(); 0], None, None)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Web3ProxyError::Timeout(_)), "{:?}", err);
+
+        // requests that are waiting when the websocket is dropped fail instead of waiting for the reconnect
+        let waiting = {
+            let rpc = rpc.clone();
+
+            tokio::spawn(async move {
+                rpc.internal_request::<_, U64>("eth_chainId".into(), &[(); 0], None, None)
+                    .await
+            })
+        };
+
+        sleep(Duration::from_millis(100)).await;
+
+        rpc.disconnect_ws();
+
+        let err = waiting.await.unwrap().unwrap_err();
+
+        assert!(
+            matches!(err, Web3ProxyError::WebsocketDisconnected(_)),
+            "{:?}",
+            err
+        );
+
+        drop(permit);
+
+        handle.abort();
+    }
+
     /*
     // TODO: think about how to bring the concept of a "lagged" node back
     #[test]
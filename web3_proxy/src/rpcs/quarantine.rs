@@ -0,0 +1,272 @@
+//! Benching backends that keep failing.
+//!
+//! A backend can keep its websocket up while it answers everything with rate limits or garbage. Each backend remembers
+//! how its most recent requests went. Once too many of them failed, it is quarantined and skipped when picking servers.
+//! A probe in the background checks on it, and it goes back in rotation after enough probes in a row succeed.
+
+use crate::config::Web3RpcConfig;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// How a request to a backend went. Bad requests are the user's fault, so an error response to one is a success here
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Error,
+    Timeout,
+}
+
+#[derive(Debug, Default)]
+struct QuarantineState {
+    /// the most recent outcomes. oldest first
+    outcomes: VecDeque<Outcome>,
+    errors: usize,
+    timeouts: usize,
+    /// set while quarantined
+    since: Option<Instant>,
+    /// what the window looked like when the backend was quarantined
+    reason: Option<String>,
+    /// successful probes since the backend was quarantined. any failure starts this over
+    successes_in_a_row: u32,
+    times_quarantined: u64,
+}
+
+impl QuarantineState {
+    fn push(&mut self, outcome: Outcome, window: usize) {
+        self.outcomes.push_back(outcome);
+        self.count(outcome, true);
+
+        while self.outcomes.len() > window {
+            if let Some(old) = self.outcomes.pop_front() {
+                self.count(old, false);
+            }
+        }
+    }
+
+    fn count(&mut self, outcome: Outcome, add: bool) {
+        let x = match outcome {
+            Outcome::Success => return,
+            Outcome::Error => &mut self.errors,
+            Outcome::Timeout => &mut self.timeouts,
+        };
+
+        if add {
+            *x += 1;
+        } else {
+            *x -= 1;
+        }
+    }
+}
+
+/// The rolling window of outcomes for one backend and whether it is benched
+pub struct Quarantine {
+    rpc_name: String,
+    /// quarantine once at least this percent of the window failed
+    error_percent: usize,
+    /// how many outcomes are judged. nothing is judged until the window is full
+    window: usize,
+    /// how often a quarantined backend is checked
+    pub probe_interval: Duration,
+    /// successful probes in a row that put the backend back in rotation
+    recover_after: u32,
+    state: Mutex<QuarantineState>,
+}
+
+impl Quarantine {
+    /// None if quarantine is turned off for the backend
+    pub fn new(rpc_name: String, config: &Web3RpcConfig) -> Option<Self> {
+        if config.quarantine_error_percent == 0 || config.quarantine_window == 0 {
+            return None;
+        }
+
+        Some(Self {
+            rpc_name,
+            error_percent: config.quarantine_error_percent.min(100) as usize,
+            window: config.quarantine_window,
+            probe_interval: Duration::from_secs(config.quarantine_probe_secs.max(1)),
+            recover_after: config.quarantine_recover_after.max(1),
+            state: Default::default(),
+        })
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.state.lock().since.is_some()
+    }
+
+    /// Remember how a request went. True if this put the backend in quarantine. Start probing it then
+    pub fn record(&self, outcome: Outcome) -> bool {
+        let mut state = self.state.lock();
+
+        if state.since.is_some() {
+            // only probes and health checks get sent to a quarantined backend
+            if outcome == Outcome::Success {
+                state.successes_in_a_row += 1;
+
+                if state.successes_in_a_row >= self.recover_after {
+                    info!(rpc=%self.rpc_name, successes=%state.successes_in_a_row, "back in rotation after quarantine");
+
+                    // it needs a whole new window of failures before it is quarantined again
+                    state.since = None;
+                    state.reason = None;
+                    state.successes_in_a_row = 0;
+                    state.outcomes.clear();
+                    state.errors = 0;
+                    state.timeouts = 0;
+                }
+            } else {
+                state.successes_in_a_row = 0;
+            }
+
+            return false;
+        }
+
+        state.push(outcome, self.window);
+
+        if state.outcomes.len() < self.window {
+            return false;
+        }
+
+        let failed = state.errors + state.timeouts;
+
+        if failed * 100 < self.error_percent * self.window {
+            return false;
+        }
+
+        let reason = format!(
+            "{} errors and {} timeouts in the last {} requests",
+            state.errors, state.timeouts, self.window
+        );
+
+        warn!(rpc=%self.rpc_name, %reason, "quarantined");
+
+        state.since = Some(Instant::now());
+        state.reason = Some(reason);
+        state.successes_in_a_row = 0;
+        state.times_quarantined += 1;
+
+        true
+    }
+}
+
+impl Serialize for Quarantine {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let state = self.state.lock();
+
+        let mut x = serializer.serialize_struct("Quarantine", 7)?;
+
+        x.serialize_field("quarantined", &state.since.is_some())?;
+        x.serialize_field("since_secs", &state.since.map(|x| x.elapsed().as_secs()))?;
+        x.serialize_field("reason", &state.reason)?;
+        x.serialize_field("successes_in_a_row", &state.successes_in_a_row)?;
+        x.serialize_field("times_quarantined", &state.times_quarantined)?;
+        x.serialize_field("window_errors", &state.errors)?;
+        x.serialize_field("window_timeouts", &state.timeouts)?;
+
+        x.end()
+    }
+}
+
+impl std::fmt::Debug for Quarantine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock();
+
+        f.debug_struct("Quarantine")
+            .field("quarantined", &state.since.is_some())
+            .field("reason", &state.reason)
+            .field("errors", &state.errors)
+            .field("timeouts", &state.timeouts)
+            .field("requests", &state.outcomes.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn quarantine(error_percent: u8, window: usize, recover_after: u32) -> Quarantine {
+        let config = Web3RpcConfig {
+            quarantine_error_percent: error_percent,
+            quarantine_window: window,
+            quarantine_recover_after: recover_after,
+            ..Default::default()
+        };
+
+        Quarantine::new("a".to_string(), &config).unwrap()
+    }
+
+    #[test]
+    fn off() {
+        let config = Web3RpcConfig {
+            quarantine_error_percent: 0,
+            ..Default::default()
+        };
+
+        assert!(Quarantine::new("a".to_string(), &config).is_none());
+    }
+
+    #[test]
+    fn quarantine_and_recover() {
+        let x = quarantine(50, 4, 2);
+
+        // nothing is judged until the window is full
+        assert!(!x.record(Outcome::Error));
+        assert!(!x.record(Outcome::Timeout));
+        assert!(!x.record(Outcome::Error));
+        assert!(!x.is_quarantined());
+
+        // 3 of 4
+        assert!(x.record(Outcome::Success));
+        assert!(x.is_quarantined());
+
+        let status = serde_json::to_value(&x).unwrap();
+        assert_eq!(status["quarantined"], true);
+        assert_eq!(
+            status["reason"],
+            json!("2 errors and 1 timeouts in the last 4 requests")
+        );
+        assert_eq!(status["times_quarantined"], 1);
+
+        // a failed probe starts over
+        assert!(!x.record(Outcome::Success));
+        assert!(!x.record(Outcome::Error));
+        assert!(!x.record(Outcome::Success));
+        assert!(x.is_quarantined());
+
+        assert!(!x.record(Outcome::Success));
+        assert!(!x.is_quarantined());
+
+        // the old failures are forgotten
+        assert!(!x.record(Outcome::Error));
+        assert!(!x.record(Outcome::Success));
+        assert!(!x.record(Outcome::Success));
+        assert!(!x.record(Outcome::Success));
+        assert!(!x.is_quarantined());
+    }
+
+    #[test]
+    fn rolling_window() {
+        let x = quarantine(50, 4, 1);
+
+        for _ in 0..4 {
+            assert!(!x.record(Outcome::Success));
+        }
+
+        // the window slides. the old successes fall out
+        assert!(!x.record(Outcome::Error));
+        assert!(x.record(Outcome::Error));
+
+        let status = serde_json::to_value(&x).unwrap();
+        assert_eq!(status["window_errors"], 2);
+        assert_eq!(status["window_timeouts"], 0);
+    }
+}
@@ -0,0 +1,244 @@
+//! Notice when the consensus head moves to another branch.
+//!
+//! A new head at the same height as the old one (or lower) is easy to spot. A higher head can be on another branch too,
+//! so the recent consensus heads are remembered by height and the new head's parents are followed back until they meet
+//! one of them. Every remembered head above that point was orphaned.
+
+use super::blockchain::BlockHeader;
+use ethers::types::{H256, U64};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// how many recent consensus heads are remembered. reorgs deeper than this are only noticed at their tip
+pub const RECENT_HEADS: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reorg {
+    /// the lowest height that was replaced
+    pub number: U64,
+    /// how many heights were replaced
+    pub depth: u64,
+    /// the heads that are no longer on the chain. anything cached for them is wrong now
+    pub orphaned: Vec<H256>,
+}
+
+/// The consensus chain as of the latest head
+#[derive(Debug, Default)]
+pub struct HeadAncestry {
+    heads: BTreeMap<U64, H256>,
+}
+
+impl HeadAncestry {
+    /// Remember a new consensus head. Returns the reorg if it isn't a descendant of the heads we had.
+    /// `block_by_hash` should only check caches. If a parent isn't found, only heights at or above the new head count
+    /// as replaced
+    pub async fn push<F, Fut>(&mut self, head: &BlockHeader, block_by_hash: F) -> Option<Reorg>
+    where
+        F: Fn(H256) -> Fut,
+        Fut: Future<Output = Option<BlockHeader>>,
+    {
+        let num = head.number();
+
+        if self.heads.get(&num) == Some(head.hash()) {
+            // we already have this one. the chain went back to it, but nothing was replaced
+            return None;
+        }
+
+        let lowest = self.heads.keys().next().copied();
+
+        // the new head's branch below it. remembered once we know where it meets ours
+        let mut branch = vec![];
+
+        let mut fork = None;
+
+        let mut block = head.clone();
+
+        while let Some(parent_num) = block.number().checked_sub(U64::one()) {
+            if lowest.map_or(true, |x| parent_num < x) {
+                // deeper than we remember
+                break;
+            }
+
+            if self.heads.get(&parent_num) == Some(block.parent_hash()) {
+                fork = Some(parent_num);
+                break;
+            }
+
+            let Some(parent) = block_by_hash(*block.parent_hash()).await else {
+                break;
+            };
+
+            // a parent with an unexpected number would loop forever
+            if parent.number() != parent_num {
+                break;
+            }
+
+            branch.push((parent_num, *parent.hash()));
+
+            block = parent;
+        }
+
+        let replaced_from = match fork {
+            Some(x) => x + 1,
+            None => num,
+        };
+
+        let replaced = self.heads.split_off(&replaced_from);
+
+        if fork.is_some() {
+            self.heads.extend(branch);
+        }
+
+        self.heads.insert(num, *head.hash());
+
+        while self.heads.len() > RECENT_HEADS {
+            self.heads.pop_first();
+        }
+
+        let (first, last) = (replaced.keys().next()?, replaced.keys().next_back()?);
+
+        Some(Reorg {
+            number: *first,
+            depth: (*last - *first).as_u64() + 1,
+            orphaned: replaced.into_values().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Block;
+    use hashbrown::HashMap;
+    use std::sync::Arc;
+
+    /// `branch` keeps forks apart. 0 is the main chain
+    fn hash(num: u64, branch: u64) -> H256 {
+        H256::from_low_u64_be(num << 8 | branch)
+    }
+
+    fn block(num: u64, branch: u64, parent_branch: u64) -> BlockHeader {
+        let block = Block {
+            hash: Some(hash(num, branch)),
+            parent_hash: hash(num.saturating_sub(1), parent_branch),
+            number: Some(num.into()),
+            ..Default::default()
+        };
+
+        BlockHeader::try_new(Arc::new(block)).unwrap()
+    }
+
+    struct Chain(HashMap<H256, BlockHeader>);
+
+    impl Chain {
+        fn new(blocks: &[BlockHeader]) -> Self {
+            Self(blocks.iter().map(|x| (*x.hash(), x.clone())).collect())
+        }
+
+        async fn push(&self, ancestry: &mut HeadAncestry, head: &BlockHeader) -> Option<Reorg> {
+            ancestry
+                .push(head, |x| async move { self.0.get(&x).cloned() })
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn descendants_are_not_reorgs() {
+        let mut ancestry = HeadAncestry::default();
+
+        let chain = Chain::new(&[]);
+
+        for num in 1..=5 {
+            assert_eq!(chain.push(&mut ancestry, &block(num, 0, 0)).await, None);
+        }
+
+        // going back to a head we already had replaces nothing
+        assert_eq!(chain.push(&mut ancestry, &block(4, 0, 0)).await, None);
+    }
+
+    #[tokio::test]
+    async fn same_height() {
+        let mut ancestry = HeadAncestry::default();
+
+        let chain = Chain::new(&[]);
+
+        for num in 1..=5 {
+            chain.push(&mut ancestry, &block(num, 0, 0)).await;
+        }
+
+        let reorg = chain.push(&mut ancestry, &block(5, 1, 0)).await.unwrap();
+
+        assert_eq!(
+            reorg,
+            Reorg {
+                number: 5.into(),
+                depth: 1,
+                orphaned: vec![hash(5, 0)],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn higher_head_on_another_branch() {
+        let mut ancestry = HeadAncestry::default();
+
+        // 1-5 on the main chain. the fork leaves it after 3
+        let fork = [block(4, 1, 0), block(5, 1, 1)];
+        let chain = Chain::new(&fork);
+
+        for num in 1..=5 {
+            chain.push(&mut ancestry, &block(num, 0, 0)).await;
+        }
+
+        let reorg = chain.push(&mut ancestry, &block(6, 1, 1)).await.unwrap();
+
+        assert_eq!(
+            reorg,
+            Reorg {
+                number: 4.into(),
+                depth: 2,
+                orphaned: vec![hash(4, 0), hash(5, 0)],
+            }
+        );
+
+        // the fork's blocks are remembered now. its next head is a descendant
+        assert_eq!(chain.push(&mut ancestry, &block(7, 1, 1)).await, None);
+        assert_eq!(ancestry.heads.get(&4.into()), Some(&hash(4, 1)));
+    }
+
+    #[tokio::test]
+    async fn unknown_parents() {
+        let mut ancestry = HeadAncestry::default();
+
+        let chain = Chain::new(&[]);
+
+        for num in 1..=5 {
+            chain.push(&mut ancestry, &block(num, 0, 0)).await;
+        }
+
+        // without the fork's blocks, only what is at or above the new head is known to be replaced
+        assert_eq!(chain.push(&mut ancestry, &block(6, 1, 1)).await, None);
+
+        let reorg = chain.push(&mut ancestry, &block(4, 2, 2)).await.unwrap();
+        assert_eq!(reorg.number, 4.into());
+        assert_eq!(reorg.depth, 3);
+        assert_eq!(reorg.orphaned, vec![hash(4, 0), hash(5, 0), hash(6, 1)]);
+    }
+
+    #[tokio::test]
+    async fn only_recent_heads_are_kept() {
+        let mut ancestry = HeadAncestry::default();
+
+        let chain = Chain::new(&[]);
+
+        for num in 1..=(RECENT_HEADS as u64 * 2) {
+            chain.push(&mut ancestry, &block(num, 0, 0)).await;
+        }
+
+        assert_eq!(ancestry.heads.len(), RECENT_HEADS);
+        assert_eq!(
+            ancestry.heads.keys().next(),
+            Some(&(RECENT_HEADS as u64 + 1).into())
+        );
+    }
+}
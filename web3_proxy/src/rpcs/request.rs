@@ -1,10 +1,14 @@
+use super::happy_eyeballs::AddressFamily;
 use super::one::Web3Rpc;
+use super::quarantine::Outcome;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::authorization::{Authorization, AuthorizationType};
-use crate::globals::{global_db_conn, DB_CONN};
+use crate::frontend::client_ip::X_FORWARDED_FOR;
+use crate::globals::{global_app, global_db_conn, DB_CONN};
 use crate::jsonrpc::{
     self, JsonRpcErrorData, JsonRpcResultData, ParsedResponse, ResponsePayload, ValidatedRequest,
 };
+use crate::metrics::TooLarge;
 use anyhow::Context;
 use chrono::Utc;
 use derive_more::From;
@@ -22,8 +26,9 @@ use std::sync::atomic;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::net::UnixStream;
-use tokio::time::{Duration, Instant};
-use tracing::{debug, error, info, trace, warn, Level};
+use tokio::select;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, error, error_span, info, trace, warn, Instrument, Level};
 
 #[derive(From)]
 pub enum OpenRequestResult {
@@ -238,6 +243,17 @@ impl OpenRequestHandle {
                 .context("there should always be a request here")?;
 
             let mut request_builder = client.post(url).json(request);
+
+            // backends that do their own abuse detection should see the client. not us
+            let authorization = &self.web3_request.authorization;
+            if !matches!(
+                authorization.authorization_type,
+                AuthorizationType::Internal
+            ) {
+                request_builder =
+                    request_builder.header(X_FORWARDED_FOR, authorization.ip.to_string());
+            }
+
             if request.method == "eth_sendRawTransaction" {
                 if let Some(ref request_id) = self.web3_request.request_id {
                     let mut headers = reqwest::header::HeaderMap::with_capacity(1);
@@ -250,8 +266,25 @@ impl OpenRequestHandle {
                     request_builder = request_builder.headers(headers);
                 }
             }
+            let start = Instant::now();
+
             let response = request_builder.send().await?;
 
+            let headers_latency = start.elapsed();
+            self.rpc.headers_latency.observe(headers_latency);
+            self.rpc.headers_delay.write().record(headers_latency);
+
+            if let Some(addr) = response.remote_addr() {
+                let host = self.rpc.http_url.as_ref().and_then(|x| x.host_str());
+
+                let family = match (self.rpc.happy_eyeballs.as_ref(), host) {
+                    (Some(happy_eyeballs), Some(host)) => happy_eyeballs.remember(host, &addr),
+                    _ => AddressFamily::of(&addr),
+                };
+
+                self.rpc.http_family.set(family);
+            }
+
             if response.status() == StatusCode::TOO_MANY_REQUESTS {
                 // TODO: how much should we actually rate limit?
                 self.rate_limit_for(Duration::from_secs(1));
@@ -259,15 +292,21 @@ impl OpenRequestHandle {
 
             let response = response.error_for_status()?;
 
+            let max_response_bytes =
+                global_app(self.rpc.chain_id).map_or(u64::MAX, |x| x.config.max_response_bytes);
+
             // cache 128kb responses
-            jsonrpc::SingleResponse::read_if_short(response, 131_072, &self.web3_request).await
-        } else if let Some(p) = self.rpc.ws_provider.load().as_ref() {
+            jsonrpc::SingleResponse::read_if_short(
+                response,
+                131_072,
+                max_response_bytes,
+                &self.web3_request,
+            )
+            .await
+        } else if self.rpc.ws_url.is_some() {
             // use the websocket provider if no other provider is available
-            let method = self.web3_request.inner.method();
-            let params = self.web3_request.inner.params();
-
             // some ethers::ProviderError need to be converted to JsonRpcErrorData. the rest to Web3ProxyError
-            let response = match p.request::<_, R>(method, params).await {
+            let response = match self.ws_request::<R>().await? {
                 Ok(x) => jsonrpc::ParsedResponse::from_result(x, self.web3_request.id()),
                 Err(provider_error) => match JsonRpcErrorData::try_from(&provider_error) {
                     Ok(x) => jsonrpc::ParsedResponse::from_error(x, self.web3_request.id()),
@@ -295,6 +334,53 @@ impl OpenRequestHandle {
         }
     }
 
+    /// Send the request on the websocket that the subscriptions use. ethers matches the response to the request by its id.
+    /// This gives up if it waits too long or if the websocket is dropped. Both errors can be retried on another rpc.
+    async fn ws_request<R: JsonRpcResultData>(&self) -> Web3ProxyResult<Result<R, ProviderError>> {
+        let timeout = self.rpc.ws_request_timeout;
+        // a huge ws_request_timeout_ms would overflow the Instant. the request's own expiry still applies
+        let deadline = Instant::now()
+            .checked_add(timeout)
+            .map_or(self.web3_request.expire_at(), |x| {
+                x.min(self.web3_request.expire_at())
+            });
+
+        // subscribe before loading the provider so that a disconnect in between is not missed
+        let mut ws_generation = self
+            .rpc
+            .ws_generation
+            .as_ref()
+            .expect("ws_generation is always set")
+            .subscribe();
+
+        let p = self
+            .rpc
+            .ws_provider
+            .load_full()
+            .ok_or_else(|| Web3ProxyError::WebsocketDisconnected(self.rpc.name.clone()))?;
+
+        let method = self.web3_request.inner.method();
+        let params = self.web3_request.inner.params();
+
+        let f = async {
+            let _permit = self
+                .rpc
+                .ws_semaphore
+                .as_ref()
+                .expect("ws_semaphore is always set")
+                .acquire()
+                .await?;
+
+            Ok::<_, Web3ProxyError>(p.request::<_, R>(method, params).await)
+        };
+
+        select! {
+            x = f => x,
+            _ = ws_generation.changed() => Err(Web3ProxyError::WebsocketDisconnected(self.rpc.name.clone())),
+            _ = sleep_until(deadline) => Err(Web3ProxyError::Timeout(Some(timeout))),
+        }
+    }
+
     pub fn error_handler(&self) -> RequestErrorHandler {
         if let RequestErrorHandler::Save = self.error_handler {
             let method = self.web3_request.inner.method();
@@ -338,7 +424,20 @@ impl OpenRequestHandle {
     pub async fn request<R: JsonRpcResultData + serde::Serialize>(
         self,
     ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
-        // TODO: use tracing spans
+        // the frontend's span is lost when requests are spawned. the id ties this back to it and to the access log
+        // internal requests don't have an id
+        let span = error_span!(
+            "backend",
+            rpc = %self.rpc.name,
+            id = self.web3_request.request_id.as_deref(),
+        );
+
+        self._request_with_handling().instrument(span).await
+    }
+
+    async fn _request_with_handling<R: JsonRpcResultData + serde::Serialize>(
+        self,
+    ) -> Web3ProxyResult<jsonrpc::SingleResponse<R>> {
         // TODO: including params in this log is way too verbose
         // trace!(rpc=%self.rpc, %method, "request");
         trace!("requesting from {}", self.rpc);
@@ -371,6 +470,19 @@ impl OpenRequestHandle {
         // originally i thought we wouldn't want errors, but I think it's a more accurate number including all requests
         let latency = start.elapsed();
 
+        self.rpc.request_latency.observe(latency);
+
+        // fix known quirks before anything else looks at the response. this is before caching
+        if let (Ok(x), Some(app)) = (response.as_mut(), global_app(self.rpc.chain_id)) {
+            let rewrites = app.response_rewrites.load();
+
+            let applied = rewrites.apply(&self.rpc.name, self.web3_request.inner.method(), x);
+
+            if !applied.is_empty() {
+                trace!(rpc=%self.rpc, ?applied, "rewrote response");
+            }
+        }
+
         // we used to fetch_sub the active_request count here, but sometimes the handle is dropped without request being called!
 
         trace!(
@@ -392,6 +504,8 @@ impl OpenRequestHandle {
         };
 
         if response_is_success {
+            self.rpc.record_outcome(Outcome::Success);
+
             // only track latency for successful requests
             tokio::spawn(async move {
                 self.rpc.peak_latency.as_ref().unwrap().report(latency);
@@ -411,6 +525,9 @@ impl OpenRequestHandle {
                 RateLimited,
             }
 
+            // connection errors and timeouts. jsonrpc errors are checked below
+            let transport_error = response.is_err();
+
             let response_type: ResponseType = match &response {
                 Ok(jsonrpc::SingleResponse::Parsed(x, ..)) => match &x.payload {
                     ResponsePayload::Success { .. } => unreachable!(),
@@ -484,6 +601,9 @@ impl OpenRequestHandle {
                                     {
                                         let method = self.web3_request.inner.method().to_string();
 
+                                        // remember this so that we send this method to other rpcs until it expires
+                                        self.rpc.missing_methods.insert(&method);
+
                                         response =
                                             Err(Web3ProxyError::MethodNotFound(method.into()))
                                     }
@@ -504,6 +624,33 @@ impl OpenRequestHandle {
                 self.rate_limit_for(Duration::from_secs(1));
             }
 
+            // bad requests are the user's fault. these are the rpc's
+            let outcome = match &response {
+                // the backend did what it was asked. the query was too broad
+                Err(Web3ProxyError::ResponseTooLarge { .. }) => {
+                    if let Some(app) = global_app(self.rpc.chain_id) {
+                        app.request_metrics.record_too_large(TooLarge::Response);
+                    }
+
+                    Outcome::Success
+                }
+                Err(Web3ProxyError::Timeout(_)) => Outcome::Timeout,
+                Err(Web3ProxyError::Reqwest(err)) if err.is_timeout() => Outcome::Timeout,
+                Err(Web3ProxyError::MdbxPanic(..)) => Outcome::Error,
+                _ if transport_error || matches!(response_type, ResponseType::RateLimited) => {
+                    Outcome::Error
+                }
+                _ => Outcome::Success,
+            };
+
+            if outcome != Outcome::Success {
+                self.rpc
+                    .backend_errors
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+
+            self.rpc.record_outcome(outcome);
+
             match error_handler {
                 RequestErrorHandler::DebugLevel => {
                     // TODO: think about this revert check more. sometimes we might want reverts logged so this needs a flag
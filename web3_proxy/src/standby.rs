@@ -0,0 +1,196 @@
+//! Warm standby for fast failover.
+//!
+//! A server started with `standby = true` connects to its backends and follows the head like any other, but it answers
+//! jsonrpc with a 503. Admin, health, status, and metrics keep working. Every time the head changes, the configured
+//! `standby_warm_requests` are sent through the proxy so that the response cache is full when traffic arrives.
+//!
+//! Promoting is only a flag. Nothing is reconnected, so a promoted server starts serving right away.
+
+use crate::app::App;
+use crate::config::AppConfig;
+use crate::frontend::authorization::Authorization;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tracing::{debug, trace, warn};
+
+pub use web3_proxy_client::types::StandbyStatus;
+
+/// A request that a standby sends on every new head to keep the response cache warm
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmRequest {
+    pub method: String,
+    /// Leave it out for methods without params
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl WarmRequest {
+    fn params(&self) -> Value {
+        match &self.params {
+            Value::Null => json!([]),
+            x => x.clone(),
+        }
+    }
+}
+
+pub struct Standby {
+    on: AtomicBool,
+    warm_requests: Vec<WarmRequest>,
+    warmed: AtomicU64,
+    warm_errors: AtomicU64,
+}
+
+impl Standby {
+    pub fn new(config: &AppConfig) -> Arc<Self> {
+        let x = Self {
+            on: AtomicBool::new(config.standby),
+            warm_requests: config.standby_warm_requests.clone(),
+            warmed: Default::default(),
+            warm_errors: Default::default(),
+        };
+
+        Arc::new(x)
+    }
+
+    /// true if jsonrpc requests should get a 503
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Returns the previous value. `false` promotes this server. `true` demotes it
+    pub fn set(&self, on: bool) -> bool {
+        self.on.swap(on, Ordering::Relaxed)
+    }
+
+    pub fn status(&self, previous: Option<bool>) -> StandbyStatus {
+        StandbyStatus {
+            previous,
+            standby: self.is_on(),
+            warmed: self.warmed.load(Ordering::Relaxed),
+            warm_errors: self.warm_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Warm the caches until the app is dropped. Nothing is sent while this server is serving
+    pub fn start(self: &Arc<Self>, app: Weak<App>) {
+        if self.warm_requests.is_empty() {
+            return;
+        }
+
+        tokio::spawn(warm_loop(self.clone(), app));
+    }
+
+    async fn warm(&self, app: &Arc<App>, authorization: &Arc<Authorization>) {
+        for x in self.warm_requests.iter() {
+            match app
+                .authorized_request::<_, Arc<RawValue>>(
+                    &x.method,
+                    x.params(),
+                    authorization.clone(),
+                    None,
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.warmed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    self.warm_errors.fetch_add(1, Ordering::Relaxed);
+
+                    debug!(?err, method=%x.method, "unable to warm the cache");
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for Standby {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.status(None).serialize(serializer)
+    }
+}
+
+async fn warm_loop(standby: Arc<Standby>, app: Weak<App>) {
+    let authorization = match Authorization::internal_without_stats() {
+        Ok(x) => Arc::new(x),
+        Err(err) => {
+            warn!(?err, "standby could not authorize itself");
+            return;
+        }
+    };
+
+    let mut head_block_receiver = match app.upgrade() {
+        Some(app) => app.head_block_receiver(),
+        None => return,
+    };
+
+    loop {
+        let has_head = head_block_receiver.borrow_and_update().is_some();
+
+        if has_head && standby.is_on() {
+            let Some(app) = app.upgrade() else {
+                break;
+            };
+
+            standby.warm(&app, &authorization).await;
+        }
+
+        if head_block_receiver.changed().await.is_err() {
+            break;
+        }
+    }
+
+    trace!("standby warming exited");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopConfig;
+
+    #[test]
+    fn promote_and_demote() {
+        let x = TopConfig::from_toml(
+            r#"
+            [app]
+            chain_id = 1
+            standby = true
+
+            [[app.standby_warm_requests]]
+            method = "eth_getBlockByNumber"
+            params = ["latest", false]
+
+            [[app.standby_warm_requests]]
+            method = "eth_gasPrice"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            x.app.standby_warm_requests[0].params(),
+            json!(["latest", false])
+        );
+        assert_eq!(x.app.standby_warm_requests[1].params(), json!([]));
+
+        let standby = Standby::new(&x.app);
+        assert!(standby.is_on());
+
+        assert!(standby.set(false));
+        assert!(!standby.is_on());
+
+        assert!(!standby.set(true));
+        assert!(standby.is_on());
+
+        assert_eq!(
+            serde_json::to_value(&*standby).unwrap(),
+            json!({"standby": true, "warmed": 0, "warm_errors": 0})
+        );
+    }
+}
@@ -207,6 +207,18 @@ pub async fn query_user_stats<'a>(
 
         response_body.insert("rpc_key_id", serde_json::Value::Number(rpc_key_id.into()));
 
+        // the label is the owner's. internal tags are never included here
+        let mut label_query = rpc_key::Entity::find_by_id(rpc_key_id);
+        if user_id != 0 {
+            label_query = label_query.filter(rpc_key::Column::UserId.eq(user_id));
+        }
+        let label = label_query
+            .one(db_replica.as_ref())
+            .await?
+            .and_then(|x| x.label);
+
+        response_body.insert("rpc_key_label", json!(label));
+
         condition = condition.add(rpc_accounting::Column::RpcKeyId.eq(rpc_key_id));
 
         q = q.group_by(rpc_accounting::Column::RpcKeyId);
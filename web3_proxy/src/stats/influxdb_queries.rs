@@ -111,7 +111,7 @@ pub async fn query_user_influx_stats<'a>(
         "opt_in_proxy"
     };
 
-    // Include a hashmap to go from rpc_secret_key_id to the rpc_secret_key
+    // Include a hashmap to go from rpc_secret_key_id to the rpc_secret_key and its label
     let mut rpc_key_id_to_key = HashMap::new();
 
     let rpc_key_filter = if user_id == 0 {
@@ -126,7 +126,7 @@ pub async fn query_user_influx_stats<'a>(
             .into_iter()
             .map(|x| {
                 let key = x.id.to_string();
-                let val = Ulid::from(x.secret_key);
+                let val = (Ulid::from(x.secret_key), x.label);
                 rpc_key_id_to_key.insert(key.clone(), val);
                 key
             })
@@ -146,7 +146,7 @@ pub async fn query_user_influx_stats<'a>(
                     Some(shared_rpc_key) => {
                         if subuser.role == Role::Admin || subuser.role == Role::Owner {
                             let key = shared_rpc_key.id.to_string();
-                            let val = Ulid::from(shared_rpc_key.secret_key);
+                            let val = (Ulid::from(shared_rpc_key.secret_key), shared_rpc_key.label);
                             rpc_key_id_to_key.insert(key.clone(), val);
                             Some(key)
                         } else {
@@ -471,8 +471,9 @@ pub async fn query_user_influx_stats<'a>(
                     match value {
                         influxdb2_structmap::value::Value::String(inner) => {
                             match rpc_key_id_to_key.get(&inner) {
-                                Some(x) => {
+                                Some((x, label)) => {
                                     out.insert("rpc_key", serde_json::Value::String(x.to_string()));
+                                    out.insert("rpc_key_label", json!(label));
                                 }
                                 None => {
                                     trace!("rpc_secret_key_id is not included in this query")
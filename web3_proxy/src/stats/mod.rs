@@ -1,5 +1,6 @@
 //! Store "stats" in a database for billing and a different database for graphing
 //! TODO: move some of these structs/functions into their own file?
+mod retry_queue;
 mod stat_buffer;
 
 pub mod db_queries;
@@ -7,14 +8,13 @@ pub mod influxdb_queries;
 
 use self::stat_buffer::BufferedRpcQueryStats;
 use crate::caches::{RpcSecretKeyCache, UserBalanceCache};
-use crate::compute_units::ComputeUnit;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::authorization::{Authorization, AuthorizationType};
-use crate::jsonrpc::ValidatedRequest;
+use crate::jsonrpc::{ErrorClass, ValidatedRequest};
 use crate::rpcs::one::Web3Rpc;
 use anyhow::{anyhow, Context};
 use chrono::{DateTime, Months, TimeZone, Utc};
-use derive_more::{AddAssign, From};
+use derive_more::From;
 use entities::{referee, referrer, rpc_accounting_v2};
 use influxdb2::models::DataPoint;
 use migration::sea_orm::prelude::Decimal;
@@ -25,10 +25,11 @@ use migration::sea_orm::{
 use migration::{Expr, LockType, OnConflict};
 use num_traits::ToPrimitive;
 use std::borrow::Cow;
+use std::ops::AddAssign;
 use std::sync::Arc;
 use tracing::{error, instrument, trace, warn};
 
-pub use stat_buffer::{SpawnedStatBuffer, StatBuffer};
+pub use stat_buffer::{SpawnedStatBuffer, StatBuffer, StatBufferStatus};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum StatType {
@@ -36,13 +37,19 @@ pub enum StatType {
     Detailed,
 }
 
-#[derive(AddAssign, Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct FlushedStats {
     /// the number of rows saved to the relational database.
     /// rows can contain multiple requests
     pub relational: usize,
     pub relational_frontend_requests: u64,
     pub relational_internal_requests: u64,
+    /// rows that failed to save and are waiting to be retried
+    pub relational_retry_queue: usize,
+    /// how long ago the oldest row in the retry queue first failed. 0 if the queue is empty
+    pub relational_retry_oldest_ms: u64,
+    /// rows that were given up on because the retry queue was full. a running total
+    pub relational_dropped: u64,
     /// the number of data points saved to the timeseries database.
     /// data points can contain multiple requests
     pub timeseries: usize,
@@ -51,6 +58,29 @@ pub struct FlushedStats {
     pub timeseries_internal_requests: u64,
 }
 
+impl AddAssign for FlushedStats {
+    /// counts are summed. the retry queue fields describe the buffer right now, so the newer ones are kept
+    fn add_assign(&mut self, rhs: Self) {
+        self.relational += rhs.relational;
+        self.relational_frontend_requests += rhs.relational_frontend_requests;
+        self.relational_internal_requests += rhs.relational_internal_requests;
+        self.relational_retry_queue = rhs.relational_retry_queue;
+        self.relational_retry_oldest_ms = rhs.relational_retry_oldest_ms;
+        self.relational_dropped = rhs.relational_dropped;
+        self.timeseries += rhs.timeseries;
+        self.timeseries_frontend_requests += rhs.timeseries_frontend_requests;
+        self.timeseries_internal_requests += rhs.timeseries_internal_requests;
+    }
+}
+
+/// The parts of saving a stat to the relational database.
+/// The accounting upsert adds to the existing row, so a retry must not repeat it once it has worked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbSaveStage {
+    Accounting,
+    Referral,
+}
+
 /// TODO: better name? RpcQueryStatBuilder?
 #[derive(Clone, Debug)]
 pub struct RpcQueryStats {
@@ -59,6 +89,8 @@ pub struct RpcQueryStats {
     pub method: Cow<'static, str>,
     pub archive_request: bool,
     pub error_response: bool,
+    /// Why the request failed. Only the user's own errors are charged
+    pub error_class: ErrorClass,
     pub request_bytes: u64,
     /// if backend_requests is 0, there was a cache_hit
     /// no need to track frontend_request on this. a RpcQueryStats always represents one frontend request
@@ -71,6 +103,8 @@ pub struct RpcQueryStats {
     pub compute_unit_cost: Decimal,
     /// If the request is invalid or received a jsonrpc error response (excluding reverts)
     pub user_error_response: bool,
+    /// How far apart the backends' eth_estimateGas results were, in basis points
+    pub estimate_gas_dispersion_bps: Option<u64>,
 }
 
 #[derive(Clone, Debug, From, Hash, PartialEq, Eq)]
@@ -88,6 +122,8 @@ pub struct RpcQueryKey {
     user_error_response: bool,
     /// the rpc method used.
     method: Cow<'static, str>,
+    /// true if the key skips request coalescing. these cost us more backend requests
+    skip_request_coalescing: bool,
     /// 0 if the public url was used.
     rpc_secret_key_id: u64,
     /// 0 if the public url was used.
@@ -129,6 +165,9 @@ impl RpcQueryStats {
         // user_error_response is always set to false because we don't bother tracking this in the database
         let user_error_response = false;
 
+        // the relational database doesn't split on this either. the time series has it
+        let skip_request_coalescing = false;
+
         // Depending on method, add some arithmetic around calculating credits_used
         // I think balance should not go here, this looks more like a key thingy
         RpcQueryKey {
@@ -139,6 +178,7 @@ impl RpcQueryStats {
             method,
             rpc_secret_key_id,
             rpc_key_user_id: self.authorization.checks.user_id,
+            skip_request_coalescing,
             user_error_response,
         }
     }
@@ -161,6 +201,7 @@ impl RpcQueryStats {
             method,
             rpc_secret_key_id,
             rpc_key_user_id,
+            skip_request_coalescing: self.authorization.checks.skip_request_coalescing,
             user_error_response: self.user_error_response,
         }
     }
@@ -188,6 +229,7 @@ impl RpcQueryStats {
             method,
             rpc_secret_key_id,
             rpc_key_user_id: self.authorization.checks.user_id,
+            skip_request_coalescing: self.authorization.checks.skip_request_coalescing,
             user_error_response: self.user_error_response,
         };
 
@@ -232,6 +274,12 @@ impl BufferedRpcQueryStats {
             self.paid_credits_used += stat.compute_unit_cost;
         }
 
+        if let Some(x) = stat.estimate_gas_dispersion_bps {
+            self.estimate_gas_samples += 1;
+            self.sum_estimate_gas_dispersion_bps =
+                self.sum_estimate_gas_dispersion_bps.saturating_add(x);
+        }
+
         if approximate_balance_remaining.is_some() {
             // notice that we overwrite. we intentionally do not increment!
             self.approximate_balance_remaining = approximate_balance_remaining;
@@ -338,30 +386,51 @@ impl BufferedRpcQueryStats {
         Ok(())
     }
 
+    /// Save the stat starting at `stage`. On error, returns the stage that still needs to be retried
     // TODO: take a db transaction instead so that we can batch?
     async fn save_db(
-        self,
+        &self,
         chain_id: u64,
         db_conn: &DatabaseConnection,
-        key: RpcQueryKey,
+        key: &RpcQueryKey,
+        stage: DbSaveStage,
         user_balance_cache: &UserBalanceCache,
         rpc_secret_key_cache: &RpcSecretKeyCache,
-    ) -> Web3ProxyResult<()> {
-        // Sanity check, if we need to save stats
-        if key.response_timestamp == 0 {
-            return Err(Web3ProxyError::Anyhow(anyhow!(
-                "no response_timestamp! This is a bug! {:?} {:?}",
-                key,
-                self
-            )));
+    ) -> Result<(), (DbSaveStage, Web3ProxyError)> {
+        if stage == DbSaveStage::Accounting {
+            // Sanity check, if we need to save stats
+            if key.response_timestamp == 0 {
+                return Err((
+                    DbSaveStage::Accounting,
+                    Web3ProxyError::Anyhow(anyhow!(
+                        "no response_timestamp! This is a bug! {:?} {:?}",
+                        key,
+                        self
+                    )),
+                ));
+            }
+
+            // save the statistics to the database:
+            self._save_db_stats(chain_id, db_conn, key)
+                .await
+                .map_err(|err| (DbSaveStage::Accounting, err))?;
         }
 
+        self._save_db_referral(db_conn, key, user_balance_cache, rpc_secret_key_cache)
+            .await
+            .map_err(|err| (DbSaveStage::Referral, err))
+    }
+
+    async fn _save_db_referral(
+        &self,
+        db_conn: &DatabaseConnection,
+        key: &RpcQueryKey,
+        user_balance_cache: &UserBalanceCache,
+        rpc_secret_key_cache: &RpcSecretKeyCache,
+    ) -> Web3ProxyResult<()> {
         // TODO: rename to owner_id?
         let sender_user_id = key.rpc_key_user_id;
 
-        // save the statistics to the database:
-        self._save_db_stats(chain_id, db_conn, &key).await?;
-
         // Apply all the referral logic; let's keep it simple and flat for now
         if self.paid_credits_used > 0.into() {
             let mut invalidate_caches = false;
@@ -513,6 +582,16 @@ impl BufferedRpcQueryStats {
                     .context("sum_credits_used is really (too) large")?,
             );
 
+        // only estimateGas fan out has these. leave them off everything else
+        if self.estimate_gas_samples > 0 {
+            builder = builder
+                .field("estimate_gas_samples", self.estimate_gas_samples as i64)
+                .field(
+                    "sum_estimate_gas_dispersion_bps",
+                    self.sum_estimate_gas_dispersion_bps as i64,
+                );
+        }
+
         if let Some(balance) = self.approximate_balance_remaining {
             builder = builder.field(
                 "balance",
@@ -525,6 +604,11 @@ impl BufferedRpcQueryStats {
             builder = builder.tag("rpc_secret_key_id", key.rpc_secret_key_id.to_string());
         }
 
+        // only set on the keys that skip it so that every other series stays the same
+        if key.skip_request_coalescing {
+            builder = builder.tag("skip_request_coalescing", "true");
+        }
+
         // [add "uniq" to the timestamp](https://docs.influxdata.com/influxdb/v2.0/write-data/best-practices/duplicate-points/#increment-the-timestamp)
         // i64 timestamps get us to Friday, April 11, 2262
         assert!(uniq < 1_000_000_000, "uniq is way too big");
@@ -558,11 +642,14 @@ impl RpcQueryStats {
 
         let response_bytes = response_lock.response_bytes;
 
+        let mut error_class = response_lock.error_class;
         let mut error_response = response_lock.error_response;
         let mut response_millis = response_lock.response_millis;
 
         let user_error_response = response_lock.user_error_response;
 
+        let estimate_gas_dispersion_bps = response_lock.estimate_gas_dispersion_bps;
+
         let response_timestamp = match response_lock.response_timestamp {
             0 => {
                 // no response timestamp!
@@ -575,6 +662,7 @@ impl RpcQueryStats {
                         "no response known, but no errors logged. investigate",
                     );
                     error_response = true;
+                    error_class = ErrorClass::Proxy;
                 }
 
                 if response_millis == 0 {
@@ -588,14 +676,14 @@ impl RpcQueryStats {
             x => x,
         };
 
-        let cu = ComputeUnit::new(metadata.inner.method(), metadata.chain_id, response_bytes);
+        let cu = metadata.compute_unit(response_bytes);
 
         let cache_hit = backend_rpcs_used.is_empty();
 
         let compute_unit_cost = cu.cost(
             archive_request,
             cache_hit,
-            error_response,
+            error_class,
             &metadata.usd_per_cu,
         );
 
@@ -607,7 +695,9 @@ impl RpcQueryStats {
             backend_rpcs_used,
             chain_id: metadata.chain_id,
             compute_unit_cost,
+            error_class,
             error_response,
+            estimate_gas_dispersion_bps,
             method,
             request_bytes,
             response_bytes,
@@ -625,6 +715,7 @@ mod tests {
     use crate::test_utils::TestInflux;
     use crate::{caches::UserBalanceCache, stats::StatBuffer};
     use moka::future::Cache;
+    use std::time::Duration;
     use tokio::sync::{broadcast, mpsc};
 
     #[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
@@ -660,6 +751,8 @@ mod tests {
             flush_sender_1,
             flush_receiver_1,
             1,
+            100,
+            Duration::from_secs(60),
         )
         .unwrap()
         .unwrap();
@@ -677,6 +770,8 @@ mod tests {
             flush_sender_2,
             flush_receiver_2,
             2,
+            100,
+            Duration::from_secs(60),
         )
         .unwrap()
         .unwrap();
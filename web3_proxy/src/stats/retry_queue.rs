@@ -0,0 +1,161 @@
+//! Accounting saves that failed and are waiting to be tried again.
+//!
+//! Everything is kept in memory. A restart during an outage loses whatever is still queued (the final flush on
+//! shutdown tries one last time and logs what it couldn't save).
+
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+/// the first retry after a failure waits this long. each failure after that doubles it
+pub const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct RetryQueue<T> {
+    /// oldest first. the Instant is when the item first failed to save
+    items: VecDeque<(Instant, T)>,
+    max_len: usize,
+    max_backoff: Duration,
+    backoff: Duration,
+    /// None if the last attempt worked (or there hasn't been one)
+    next_retry_at: Option<Instant>,
+    /// items that were pushed out because the queue was full
+    dropped: u64,
+}
+
+impl<T> RetryQueue<T> {
+    pub fn new(max_len: usize, max_backoff: Duration) -> Self {
+        Self {
+            items: VecDeque::new(),
+            max_len,
+            max_backoff: max_backoff.max(MIN_RETRY_BACKOFF),
+            backoff: MIN_RETRY_BACKOFF,
+            next_retry_at: None,
+            dropped: 0,
+        }
+    }
+
+    /// Queue an item that failed to save. `failed_at` is when it *first* failed.
+    /// If the queue is full, the oldest item is removed and returned so the caller can account for it.
+    pub fn push(&mut self, failed_at: Instant, item: T) -> Option<(Instant, T)> {
+        self.items.push_back((failed_at, item));
+
+        if self.items.len() > self.max_len {
+            self.dropped += 1;
+
+            self.items.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// take everything so that it can be tried again. anything that fails again should be pushed back
+    pub fn take(&mut self) -> VecDeque<(Instant, T)> {
+        std::mem::take(&mut self.items)
+    }
+
+    /// a save failed. wait longer before the next retry
+    pub fn failed(&mut self, now: Instant) {
+        if self.next_retry_at.is_some() {
+            self.backoff = (self.backoff * 2).min(self.max_backoff);
+        } else {
+            self.backoff = MIN_RETRY_BACKOFF;
+        }
+
+        self.next_retry_at = Some(now + self.backoff);
+    }
+
+    /// a save worked. the database is back
+    pub fn succeeded(&mut self) {
+        self.backoff = MIN_RETRY_BACKOFF;
+        self.next_retry_at = None;
+    }
+
+    /// when the queue should be tried again. None if there is nothing to retry
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(self.next_retry_at.unwrap_or_else(Instant::now))
+        }
+    }
+
+    /// true while waiting out a backoff. saves shouldn't be attempted
+    pub fn backing_off(&self, now: Instant) -> bool {
+        self.next_retry_at.map(|x| x > now).unwrap_or(false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// when the oldest queued item first failed
+    pub fn oldest(&self) -> Option<Instant> {
+        self.items.iter().map(|(x, _)| *x).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn cap_and_backoff() {
+        let mut q = RetryQueue::new(2, Duration::from_secs(4));
+
+        let start = Instant::now();
+
+        assert_eq!(q.next_retry_at(), None);
+        assert!(!q.backing_off(start));
+
+        q.failed(start);
+        assert!(q.push(start, 1).is_none());
+        assert_eq!(q.next_retry_at(), Some(start + Duration::from_secs(1)));
+        assert!(q.backing_off(start));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let now = Instant::now();
+
+        assert!(q.push(now, 2).is_none());
+
+        // full. the oldest is pushed out
+        assert_eq!(q.push(now, 3), Some((start, 1)));
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.dropped(), 1);
+        assert_eq!(q.oldest(), Some(now));
+
+        // backoff doubles up to the max
+        q.failed(now);
+        assert_eq!(q.next_retry_at(), Some(now + Duration::from_secs(2)));
+        q.failed(now);
+        assert_eq!(q.next_retry_at(), Some(now + Duration::from_secs(4)));
+        q.failed(now);
+        assert_eq!(q.next_retry_at(), Some(now + Duration::from_secs(4)));
+
+        // a retry takes everything. one fails again and keeps its original time
+        let mut retrying = q.take();
+        assert!(q.is_empty());
+
+        let (failed_at, x) = retrying.pop_front().unwrap();
+        assert_eq!(x, 2);
+        q.push(failed_at, x);
+
+        q.succeeded();
+        assert!(!q.backing_off(now));
+        assert_eq!(q.oldest(), Some(now));
+
+        // the next failure starts the backoff over
+        q.failed(now);
+        assert_eq!(q.next_retry_at(), Some(now + Duration::from_secs(1)));
+
+        // drops are a running total
+        assert_eq!(q.dropped(), 1);
+    }
+}
@@ -1,4 +1,5 @@
-use super::{AppStat, FlushedStats, RpcQueryKey};
+use super::retry_queue::RetryQueue;
+use super::{AppStat, DbSaveStage, FlushedStats, RpcQueryKey};
 use crate::app::Web3ProxyJoinHandle;
 use crate::caches::{RpcSecretKeyCache, UserBalanceCache};
 use crate::errors::Web3ProxyResult;
@@ -10,11 +11,15 @@ use derive_more::From;
 use futures::stream;
 use hashbrown::HashMap;
 use migration::sea_orm::prelude::Decimal;
-use std::time::Duration;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::select;
 use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio::time::{interval, sleep};
+use tokio::time::{interval, sleep, sleep_until, Instant};
 use tracing::{debug, error, info, trace, warn, Instrument};
+use web3_proxy_client::types::StatBufferSummary;
 
 #[derive(Debug, Default)]
 pub struct BufferedRpcQueryStats {
@@ -30,17 +35,98 @@ pub struct BufferedRpcQueryStats {
     pub sum_credits_used: Decimal,
     pub sum_cu_used: Decimal,
     pub paid_credits_used: Decimal,
+    /// eth_estimateGas requests that got estimates from more than one backend
+    pub estimate_gas_samples: u64,
+    /// sum of the spread between backends' estimates, in basis points
+    pub sum_estimate_gas_dispersion_bps: u64,
     /// The user's balance at this point in time.
     /// Multiple queries might be modifying it at once, so this is a copy of it when received
     /// None if this is an unauthenticated request
     pub approximate_balance_remaining: Option<Decimal>,
 }
 
+/// What the stat buffer is doing. Shared with the app so that it can be read without messaging the buffer
+#[derive(Debug)]
+pub struct StatBufferStatus {
+    /// aggregated stats that are waiting to be saved
+    pub buffered: AtomicUsize,
+    /// false if the last save to the relational database failed
+    pub relational_ok: AtomicBool,
+    /// accounting rows that failed to save and are waiting to be retried
+    pub relational_retry_queue: AtomicUsize,
+    /// unix epoch ms of when the oldest row in the retry queue first failed. 0 if the queue is empty
+    pub relational_retry_oldest_at_ms: AtomicU64,
+    /// accounting rows that were given up on because the retry queue was full
+    pub relational_dropped: AtomicU64,
+    /// false if the last write to the timeseries database failed
+    pub timeseries_ok: AtomicBool,
+}
+
+impl Default for StatBufferStatus {
+    fn default() -> Self {
+        Self {
+            buffered: AtomicUsize::new(0),
+            relational_ok: AtomicBool::new(true),
+            relational_retry_queue: AtomicUsize::new(0),
+            relational_retry_oldest_at_ms: AtomicU64::new(0),
+            relational_dropped: AtomicU64::new(0),
+            timeseries_ok: AtomicBool::new(true),
+        }
+    }
+}
+
+impl StatBufferStatus {
+    /// how long ago the oldest row in the retry queue first failed
+    pub fn relational_retry_oldest_age(&self) -> Option<Duration> {
+        let oldest_at_ms = self.relational_retry_oldest_at_ms.load(Ordering::Relaxed);
+
+        if oldest_at_ms == 0 {
+            return None;
+        }
+
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Some(Duration::from_millis(now_ms.saturating_sub(oldest_at_ms)))
+    }
+
+    pub fn summary(&self) -> StatBufferSummary {
+        StatBufferSummary {
+            buffered: self.buffered.load(Ordering::Relaxed),
+            relational_ok: self.relational_ok.load(Ordering::Relaxed),
+            relational_retry_queue: self.relational_retry_queue.load(Ordering::Relaxed),
+            relational_retry_oldest_secs: self.relational_retry_oldest_age().map(|x| x.as_secs()),
+            relational_dropped: self.relational_dropped.load(Ordering::Relaxed),
+            timeseries_ok: self.timeseries_ok.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Serialize for StatBufferStatus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.summary().serialize(serializer)
+    }
+}
+
+/// an accounting row that failed to save
+#[derive(Debug)]
+struct PendingDbSave {
+    key: RpcQueryKey,
+    stat: BufferedRpcQueryStats,
+    stage: DbSaveStage,
+}
+
 #[derive(From)]
 pub struct SpawnedStatBuffer {
     pub stat_sender: mpsc::UnboundedSender<AppStat>,
     /// these handles are important and must be allowed to finish
     pub background_handle: Web3ProxyJoinHandle<()>,
+    pub status: Arc<StatBufferStatus>,
 }
 
 pub struct StatBuffer {
@@ -56,7 +142,9 @@ pub struct StatBuffer {
     /// this will be combined with tsdb_window to create a number with a max of 1e9-1
     uniq_id: i64,
     opt_in_timeseries_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
+    relational_retry_queue: RetryQueue<PendingDbSave>,
     rpc_secret_key_cache: RpcSecretKeyCache,
+    status: Arc<StatBufferStatus>,
     tsdb_save_interval_seconds: u32,
     /// a wrapping counter to keep stats from old times that got delayed from being seen as a duplicate
     tsdb_window: i64,
@@ -81,6 +169,8 @@ impl StatBuffer {
         flush_sender: mpsc::Sender<oneshot::Sender<FlushedStats>>,
         flush_receiver: mpsc::Receiver<oneshot::Sender<FlushedStats>>,
         uniq_id: i64,
+        retry_max_entries: usize,
+        retry_max_backoff: Duration,
     ) -> anyhow::Result<Option<SpawnedStatBuffer>> {
         if influxdb_bucket.is_none() {
             influxdb_client = None;
@@ -97,6 +187,8 @@ impl StatBuffer {
 
         assert!(uniq_id < 1_000_000_000, "uniq_id too large!");
 
+        let status = Arc::new(StatBufferStatus::default());
+
         let mut new = Self {
             accounting_db_buffer: Default::default(),
             billing_period_seconds,
@@ -108,7 +200,9 @@ impl StatBuffer {
             uniq_id,
             num_tsdb_windows,
             opt_in_timeseries_buffer: Default::default(),
+            relational_retry_queue: RetryQueue::new(retry_max_entries, retry_max_backoff),
             rpc_secret_key_cache,
+            status: status.clone(),
             tsdb_save_interval_seconds,
             tsdb_window,
             user_balance_cache,
@@ -123,7 +217,7 @@ impl StatBuffer {
                 .await
         });
 
-        Ok(Some((stat_sender, handle).into()))
+        Ok(Some((stat_sender, handle, status).into()))
     }
 
     async fn aggregate_and_save_loop(
@@ -149,6 +243,8 @@ impl StatBuffer {
         let mut db_internal_requests = 0;
 
         loop {
+            let relational_retry_at = self.relational_retry_queue.next_retry_at();
+
             select! {
                 stat = stat_receiver.recv() => {
                     if let Some(stat) = stat {
@@ -162,13 +258,22 @@ impl StatBuffer {
                 _ = db_save_interval.tick() => {
                     // TODO: tokio spawn this! (but with a semaphore on db_save_interval)
                     trace!("DB save internal tick");
-                    let (count, new_frontend_requests, new_internal_requests) = self.save_relational_stats().await;
+                    let (count, new_frontend_requests, new_internal_requests) = self.save_relational_stats(false).await;
                     if count > 0 {
                         db_frontend_requests += new_frontend_requests;
                         db_internal_requests += new_internal_requests;
                         debug!("Saved {} stats for {}+{} requests to the relational db", count, new_frontend_requests, new_internal_requests);
                     }
                 }
+                _ = sleep_until(relational_retry_at.unwrap_or_else(Instant::now)), if relational_retry_at.is_some() => {
+                    trace!("DB retry tick");
+                    let (count, new_frontend_requests, new_internal_requests) = self.retry_relational_stats(false).await;
+                    if count > 0 {
+                        db_frontend_requests += new_frontend_requests;
+                        db_internal_requests += new_internal_requests;
+                        info!("Saved {} previously failed stats for {}+{} requests to the relational db", count, new_frontend_requests, new_internal_requests);
+                    }
+                }
                 _ = tsdb_save_interval.tick() => {
                     trace!("TSDB save internal tick");
                     let (count, new_frontend_requests, new_internal_requests) = self.save_tsdb_stats().await;
@@ -236,6 +341,11 @@ impl StatBuffer {
         db_frontend_requests += flushed_stats.relational_frontend_requests;
         db_internal_requests += flushed_stats.relational_internal_requests;
 
+        if !self.relational_retry_queue.is_empty() {
+            // TODO: write these somewhere so they can be saved by hand?
+            error!(pending=%self.relational_retry_queue.len(), "exiting with accounting rows that could not be saved!");
+        }
+
         // TODO: if these totals don't match, something is wrong! log something or maybe even return an error
         info!(%total_requests, %tsdb_frontend_requests, %tsdb_internal_requests, %db_frontend_requests, %db_internal_requests, "accounting and stat save loop complete");
 
@@ -243,9 +353,21 @@ impl StatBuffer {
     }
 
     async fn _buffer_app_stat(&mut self, stat: AppStat) -> Web3ProxyResult<u64> {
-        match stat {
+        let x = match stat {
             AppStat::RpcQuery(web3_request) => self._buffer_web3_request(web3_request).await,
-        }
+        };
+
+        self.update_buffered();
+
+        x
+    }
+
+    fn update_buffered(&self) {
+        let buffered = self.accounting_db_buffer.len()
+            + self.global_timeseries_buffer.len()
+            + self.opt_in_timeseries_buffer.len();
+
+        self.status.buffered.store(buffered, Ordering::Relaxed);
     }
 
     async fn _buffer_web3_request(
@@ -376,7 +498,7 @@ impl StatBuffer {
         let (timeseries_count, timeseries_frontend_requests, timeseries_internal_requests) =
             self.save_tsdb_stats().await;
         let (relational_count, relational_frontend_requests, relational_internal_requests) =
-            self.save_relational_stats().await;
+            self.save_relational_stats(true).await;
 
         // notify
         let flushed_stats = FlushedStats {
@@ -386,6 +508,9 @@ impl StatBuffer {
             relational: relational_count,
             relational_frontend_requests,
             relational_internal_requests,
+            relational_retry_queue: self.relational_retry_queue.len(),
+            relational_retry_oldest_ms: self.relational_retry_oldest_ms(),
+            relational_dropped: self.relational_retry_queue.dropped(),
         };
 
         trace!(?flushed_stats);
@@ -393,42 +518,205 @@ impl StatBuffer {
         Ok(flushed_stats)
     }
 
-    async fn save_relational_stats(&mut self) -> (usize, u64, u64) {
+    /// Save the buffered accounting rows. Anything that fails is queued to be retried.
+    /// Unless `force`, nothing is attempted while backing off after a failure. The rows go straight into the queue.
+    async fn save_relational_stats(&mut self, force: bool) -> (usize, u64, u64) {
         let mut count = 0;
         let mut frontend_requests = 0;
         let mut internal_requests = 0;
 
-        if let Ok(db_conn) = global_db_conn() {
-            count = self.accounting_db_buffer.len();
-            for (key, stat) in self.accounting_db_buffer.drain() {
-                let new_frontend_requests = stat.frontend_requests;
-                let is_internal = matches!(key.authorization_type, AuthorizationType::Internal);
+        if global_db_conn().is_ok() {
+            let (retried, retried_frontend, retried_internal) =
+                self.retry_relational_stats(force).await;
 
-                // TODO: batch saves
-                // TODO: i don't like passing key (which came from the stat) to the function on the stat. but it works for now
-                if let Err(err) = stat
-                    .save_db(
-                        self.chain_id,
-                        &db_conn,
-                        key,
-                        &self.user_balance_cache,
-                        &self.rpc_secret_key_cache,
-                    )
-                    .await
-                {
-                    // TODO: save the stat and retry later!
-                    error!(?err, %count, %new_frontend_requests, %is_internal, "unable to save accounting entry!");
-                } else if is_internal {
-                    internal_requests += new_frontend_requests;
-                } else {
-                    frontend_requests += new_frontend_requests;
-                };
+            count += retried;
+            frontend_requests += retried_frontend;
+            internal_requests += retried_internal;
+
+            let pending: Vec<_> = self
+                .accounting_db_buffer
+                .drain()
+                .map(|(key, stat)| PendingDbSave {
+                    key,
+                    stat,
+                    stage: DbSaveStage::Accounting,
+                })
+                .collect();
+
+            let now = Instant::now();
+
+            // if the retries just failed, the database is still down
+            let try_save = !self.relational_retry_queue.backing_off(now);
+
+            let (saved, new_frontend, new_internal) = self
+                ._save_relational_stats(pending.into_iter().map(|x| (now, x)), try_save)
+                .await;
+
+            count += saved;
+            frontend_requests += new_frontend;
+            internal_requests += new_internal;
+
+            self.update_buffered();
+        }
+
+        (count, frontend_requests, internal_requests)
+    }
+
+    /// try the rows that failed before. unless `force`, this does nothing while backing off
+    async fn retry_relational_stats(&mut self, force: bool) -> (usize, u64, u64) {
+        if self.relational_retry_queue.is_empty()
+            || (!force && self.relational_retry_queue.backing_off(Instant::now()))
+        {
+            return (0, 0, 0);
+        }
+
+        let pending = self.relational_retry_queue.take();
+
+        let num_pending = pending.len();
+
+        let x = self._save_relational_stats(pending.into_iter(), true).await;
+
+        if self.relational_retry_queue.is_empty() {
+            info!(%num_pending, "relational retry queue is empty");
+        }
+
+        x
+    }
+
+    /// if `try_save` is false, everything goes straight into the retry queue
+    async fn _save_relational_stats(
+        &mut self,
+        pending: impl Iterator<Item = (Instant, PendingDbSave)>,
+        try_save: bool,
+    ) -> (usize, u64, u64) {
+        let mut count = 0;
+        let mut frontend_requests = 0;
+        let mut internal_requests = 0;
+
+        let db_conn = match global_db_conn() {
+            Ok(x) => x,
+            Err(err) => {
+                // this shouldn't happen. the database was configured when these were buffered
+                error!(?err, "no database for saving accounting entries");
+                self.relational_retry_queue.failed(Instant::now());
+                for (failed_at, x) in pending {
+                    self.queue_relational_retry(failed_at, x);
+                }
+                return (count, frontend_requests, internal_requests);
+            }
+        };
+
+        let mut attempted = 0;
+        let mut relational_ok = true;
+
+        for (failed_at, mut x) in pending {
+            // once one save fails, the database is probably down. don't wait on a timeout for every row
+            if !try_save || !relational_ok {
+                self.queue_relational_retry(failed_at, x);
+                continue;
+            }
+
+            attempted += 1;
+
+            let new_frontend_requests = x.stat.frontend_requests;
+            let is_internal = matches!(x.key.authorization_type, AuthorizationType::Internal);
+
+            // TODO: batch saves
+            // TODO: i don't like passing key (which came from the stat) to the function on the stat. but it works for now
+            match x
+                .stat
+                .save_db(
+                    self.chain_id,
+                    &db_conn,
+                    &x.key,
+                    x.stage,
+                    &self.user_balance_cache,
+                    &self.rpc_secret_key_cache,
+                )
+                .await
+            {
+                Ok(()) => {
+                    self.relational_retry_queue.succeeded();
+
+                    count += 1;
+
+                    if is_internal {
+                        internal_requests += new_frontend_requests;
+                    } else {
+                        frontend_requests += new_frontend_requests;
+                    }
+                }
+                Err((stage, err)) => {
+                    error!(?err, ?stage, %new_frontend_requests, %is_internal, "unable to save accounting entry! queued for retry");
+
+                    relational_ok = false;
+
+                    self.relational_retry_queue.failed(Instant::now());
+
+                    x.stage = stage;
+
+                    self.queue_relational_retry(failed_at, x);
+                }
             }
         }
 
+        if attempted > 0 {
+            self.status
+                .relational_ok
+                .store(relational_ok, Ordering::Relaxed);
+        }
+
+        self.update_relational_retry_status();
+
         (count, frontend_requests, internal_requests)
     }
 
+    fn queue_relational_retry(&mut self, failed_at: Instant, x: PendingDbSave) {
+        if let Some((first_failed_at, dropped)) = self.relational_retry_queue.push(failed_at, x) {
+            // TODO: if these are ever needed, we could write them to a file instead
+            error!(
+                key=?dropped.key,
+                stat=?dropped.stat,
+                stage=?dropped.stage,
+                age=?first_failed_at.elapsed(),
+                total_dropped=%self.relational_retry_queue.dropped(),
+                "relational retry queue is full! dropped an accounting entry"
+            );
+        }
+    }
+
+    fn relational_retry_oldest_ms(&self) -> u64 {
+        self.relational_retry_queue
+            .oldest()
+            .map(|x| x.elapsed().as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn update_relational_retry_status(&self) {
+        self.status
+            .relational_retry_queue
+            .store(self.relational_retry_queue.len(), Ordering::Relaxed);
+        let oldest_at_ms = self
+            .relational_retry_queue
+            .oldest()
+            .map(|x| {
+                let failed_at = SystemTime::now() - x.elapsed();
+
+                failed_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            })
+            .unwrap_or(0);
+
+        self.status
+            .relational_retry_oldest_at_ms
+            .store(oldest_at_ms, Ordering::Relaxed);
+        self.status
+            .relational_dropped
+            .store(self.relational_retry_queue.dropped(), Ordering::Relaxed);
+    }
+
     // TODO: bucket should be an enum so that we don't risk typos
     // TODO: return type should be a struct so we dont mix up the values
     async fn save_tsdb_stats(&mut self) -> (usize, u64, u64) {
@@ -498,7 +786,11 @@ impl StatBuffer {
 
             count = points.len();
 
+            self.update_buffered();
+
             if count > 0 {
+                let mut timeseries_ok = true;
+
                 // TODO: put max_batch_size in config?
                 // TODO: i think the real limit is the byte size of the http request. so, a simple line count won't work very well
                 let max_batch_size = 1000;
@@ -519,11 +811,16 @@ impl StatBuffer {
                     {
                         // TODO: if this errors, we throw away some of the pending stats! retry any failures! (but not successes. it can have partial successes!)
                         error!(?err, batch_size, "unable to save tsdb stats!");
+                        timeseries_ok = false;
                         // TODO: we should probably wait a second to give errors a chance to settle
                     }
 
                     points = p;
                 }
+
+                self.status
+                    .timeseries_ok
+                    .store(timeseries_ok, Ordering::Relaxed);
             }
         }
 
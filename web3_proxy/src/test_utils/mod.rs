@@ -2,7 +2,9 @@ pub mod anvil;
 pub mod create_provider_with_rpc_key;
 pub mod influx;
 pub mod mysql;
+pub mod redis;
 
 pub use self::anvil::TestAnvil;
 pub use self::influx::TestInflux;
 pub use self::mysql::TestMysql;
+pub use self::redis::TestRedis;
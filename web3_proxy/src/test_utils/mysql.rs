@@ -10,7 +10,7 @@ use tokio::{
 };
 use tracing::{info, trace, warn};
 
-/// on drop, the mysql docker container will be shut down
+/// on drop, the mysql docker container will be shut down and removed
 pub struct TestMysql {
     pub url: Option<String>,
     pub container_name: String,
@@ -34,6 +34,13 @@ impl TestMysql {
 
         info!(%db_container_name);
 
+        // pick the port ourselves. docker would pick a new one every time the container is started
+        let host_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
         // create the db_data as soon as the url is known
         // when this is dropped, the db will be stopped
         let mut test_mysql = Self {
@@ -46,14 +53,13 @@ impl TestMysql {
                 "run",
                 "--name",
                 &db_container_name,
-                "--rm",
                 "-d",
                 "-e",
                 &format!("MYSQL_ROOT_PASSWORD={}", password),
                 "-e",
                 "MYSQL_DATABASE=web3_proxy_test",
                 "-p",
-                "0:3306",
+                &format!("127.0.0.1:{}:3306", host_port),
                 "mysql",
             ])
             .output()
@@ -165,14 +171,57 @@ impl TestMysql {
     pub async fn conn(&self) -> DatabaseConnection {
         connect_db(self.url.clone().unwrap(), 1, 99).await.unwrap()
     }
+
+    /// kill the db without removing its data. `start` brings it back on the same port
+    pub async fn kill(&self) {
+        info!(%self.container_name, "killing db");
+
+        let _ = AsyncCommand::new("docker")
+            .args(["kill", "-s", "9", &self.container_name])
+            .output()
+            .await
+            .expect("failed to kill db");
+    }
+
+    /// start the db after `kill` and wait until it accepts queries
+    pub async fn start(&self) {
+        info!(%self.container_name, "starting db");
+
+        let _ = AsyncCommand::new("docker")
+            .args(["start", &self.container_name])
+            .output()
+            .await
+            .expect("failed to start db");
+
+        let db_url = self.url.clone().unwrap();
+
+        let start = Instant::now();
+        let max_wait = Duration::from_secs(60);
+        loop {
+            if start.elapsed() > max_wait {
+                panic!("db took too long to restart");
+            }
+
+            if let Ok(conn) = connect_db(db_url.clone(), 1, 1).await {
+                if conn.ping().await.is_ok() {
+                    break;
+                }
+            }
+
+            // not ready yet. sleep and then try again
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        info!(%self.container_name, elapsed=%start.elapsed().as_secs_f32(), "db is back");
+    }
 }
 
 impl Drop for TestMysql {
     fn drop(&mut self) {
-        info!(%self.container_name, "killing db");
+        info!(%self.container_name, "removing db");
 
         let _ = SyncCommand::new("docker")
-            .args(["kill", "-s", "9", &self.container_name])
+            .args(["rm", "-f", "-v", &self.container_name])
             .output();
     }
 }
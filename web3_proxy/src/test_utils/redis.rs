@@ -0,0 +1,93 @@
+use ethers::prelude::rand::{self, distributions::Alphanumeric, Rng};
+use std::process::Command as SyncCommand;
+use std::time::Duration;
+use tokio::{
+    net::TcpStream,
+    process::Command as AsyncCommand,
+    time::{sleep, Instant},
+};
+use tracing::info;
+
+/// on drop, the redis docker container will be shut down and removed
+pub struct TestRedis {
+    pub url: String,
+    pub container_name: String,
+}
+
+impl TestRedis {
+    pub async fn spawn() -> Self {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let container_name = format!("web3-proxy-test-redis-{}", random);
+
+        info!(%container_name);
+
+        // pick the port ourselves. docker would pick a new one every time the container is started
+        let host_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // create this as soon as the name is known so that the container is removed even if we panic below
+        let test_redis = Self {
+            url: format!("redis://127.0.0.1:{}", host_port),
+            container_name: container_name.clone(),
+        };
+
+        let _ = AsyncCommand::new("docker")
+            .args([
+                "run",
+                "--name",
+                &container_name,
+                "-d",
+                "-p",
+                &format!("127.0.0.1:{}:6379", host_port),
+                "redis",
+            ])
+            .output()
+            .await
+            .expect("failed to start redis");
+
+        info!(url=%test_redis.url, "waiting for start");
+
+        let start = Instant::now();
+        let max_wait = Duration::from_secs(60);
+        loop {
+            if start.elapsed() > max_wait {
+                panic!("redis container took too long to start");
+            }
+
+            if TcpStream::connect(format!("127.0.0.1:{}", host_port))
+                .await
+                .is_ok()
+            {
+                break;
+            };
+
+            // not open yet. sleep and then try again
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        // TODO: make sure redis is actually ready for connections
+        sleep(Duration::from_secs(1)).await;
+
+        info!(url=%test_redis.url, elapsed=%start.elapsed().as_secs_f32(), "redis is open");
+
+        test_redis
+    }
+}
+
+impl Drop for TestRedis {
+    fn drop(&mut self) {
+        info!(%self.container_name, "removing redis");
+
+        let _ = SyncCommand::new("docker")
+            .args(["rm", "-f", "-v", &self.container_name])
+            .output();
+    }
+}
@@ -0,0 +1,274 @@
+//! Which rpc key sent a transaction.
+//!
+//! Compliance sometimes needs to know which of our customers submitted a transaction. Transactions relayed by
+//! `eth_sendRawTransaction` are recorded (tx hash, rpc key, and a salted hash of the ip) and kept for a limited time.
+//! Rows are written in batches by a background task so that sends never wait on the database. During a flood of sends,
+//! anything past the per-second cap is sampled instead of being written to the database.
+
+use crate::config::AppConfig;
+use crate::errors::Web3ProxyResult;
+use crate::frontend::authorization::Authorization;
+use crate::globals::global_db_conn;
+use chrono::Utc;
+use entities::tx_origin;
+use ethers::core::utils::keccak256;
+use ethers::types::TxHash;
+use migration::sea_orm::{self, ColumnTrait, EntityTrait, QueryFilter};
+use nanorand::Rng;
+use parking_lot::Mutex;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Instant};
+use tracing::{info, trace, warn};
+
+/// write this many rows at a time
+pub const BATCH_SIZE: usize = 100;
+
+/// write rows at least this often, even if a batch isn't full
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how often rows past the retention period are deleted
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// rows waiting to be written. if the database is slow enough for this to fill, new rows are dropped
+pub const MAX_PENDING: usize = 10_000;
+
+/// Records the origin of relayed transactions
+pub struct TxOriginRecorder {
+    chain_id: u64,
+    ip_salt: String,
+    max_per_second: u64,
+    sample_chance: u16,
+    /// when the current second started and how many sends have been seen in it
+    window: Mutex<(Instant, u64)>,
+    sender: mpsc::Sender<tx_origin::ActiveModel>,
+    /// sends past the per-second cap that lost the dice roll
+    pub sampled_out: AtomicU64,
+    /// rows dropped because the writer fell behind
+    pub dropped: AtomicU64,
+}
+
+/// a salted hash of an ip. an admin with the salt can check an ip against it, but the table alone doesn't reveal ips
+pub fn hash_ip(salt: &str, ip: &IpAddr) -> [u8; 32] {
+    keccak256(format!("{}:{}", salt, ip).as_bytes())
+}
+
+impl TxOriginRecorder {
+    /// None unless `tx_origin_retention_days` is set. The writer and retention sweeper run in a background task
+    pub fn spawn(config: &AppConfig) -> Option<Arc<Self>> {
+        let retention_days = config.tx_origin_retention_days?;
+
+        if config.public_recent_ips_salt.is_none() {
+            warn!("public_recent_ips_salt is not set. tx origin ip hashes will be easy to reverse");
+        }
+
+        let (sender, receiver) = mpsc::channel(MAX_PENDING);
+
+        let x = Self::new(config, sender);
+
+        let retention = chrono::Duration::days(retention_days as i64);
+
+        tokio::spawn(write_loop(receiver, retention));
+
+        info!(retention_days, "recording tx origins");
+
+        Some(Arc::new(x))
+    }
+
+    fn new(config: &AppConfig, sender: mpsc::Sender<tx_origin::ActiveModel>) -> Self {
+        Self {
+            chain_id: config.chain_id,
+            ip_salt: config.public_recent_ips_salt.clone().unwrap_or_default(),
+            max_per_second: config.tx_origin_max_per_second,
+            sample_chance: config.tx_origin_sample_chance,
+            window: Mutex::new((Instant::now(), 0)),
+            sender,
+            sampled_out: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// true if this send should be recorded. everything under the per-second cap is. past that, sends are sampled
+    fn should_record(&self) -> bool {
+        if self.max_per_second == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+
+        let under_cap = {
+            let mut window = self.window.lock();
+
+            if now.duration_since(window.0) >= Duration::from_secs(1) {
+                *window = (now, 0);
+            }
+
+            window.1 += 1;
+
+            window.1 <= self.max_per_second
+        };
+
+        under_cap
+            || self.sample_chance == u16::MAX
+            || (self.sample_chance > 0
+                && nanorand::tls_rng().generate_range(0u16..u16::MAX) < self.sample_chance)
+    }
+
+    /// rows waiting for the writer
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// queue a row for a transaction that was just relayed. this never waits on the database
+    pub fn record(&self, tx_hash: TxHash, authorization: &Authorization) {
+        if !self.should_record() {
+            self.sampled_out.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let row = tx_origin::ActiveModel {
+            id: sea_orm::NotSet,
+            tx_hash: sea_orm::Set(tx_hash.as_bytes().to_vec()),
+            rpc_key_id: sea_orm::Set(authorization.checks.rpc_secret_key_id.map(|x| x.get())),
+            ip_hash: sea_orm::Set(hash_ip(&self.ip_salt, &authorization.ip).to_vec()),
+            chain_id: sea_orm::Set(self.chain_id),
+            timestamp: sea_orm::Set(Utc::now()),
+        };
+
+        if self.sender.try_send(row).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// hash an ip the same way that recorded rows were hashed
+    pub fn hash_ip(&self, ip: &IpAddr) -> [u8; 32] {
+        hash_ip(&self.ip_salt, ip)
+    }
+}
+
+async fn write_loop(
+    mut receiver: mpsc::Receiver<tx_origin::ActiveModel>,
+    retention: chrono::Duration,
+) {
+    let mut flush_interval = interval(FLUSH_INTERVAL);
+    let mut sweep_interval = interval(SWEEP_INTERVAL);
+
+    let mut pending = Vec::with_capacity(BATCH_SIZE);
+
+    loop {
+        select! {
+            x = receiver.recv() => {
+                match x {
+                    Some(x) => {
+                        pending.push(x);
+
+                        if pending.len() >= BATCH_SIZE {
+                            flush(&mut pending).await;
+                        }
+                    }
+                    None => {
+                        flush(&mut pending).await;
+                        break;
+                    }
+                }
+            }
+            _ = flush_interval.tick() => {
+                flush(&mut pending).await;
+            }
+            _ = sweep_interval.tick() => {
+                match sweep(retention).await {
+                    Ok(deleted) => trace!(deleted, "swept old tx origins"),
+                    Err(err) => warn!(?err, "unable to delete old tx origins"),
+                }
+            }
+        }
+    }
+}
+
+async fn flush(pending: &mut Vec<tx_origin::ActiveModel>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let rows = mem::take(pending);
+    let num_rows = rows.len();
+
+    match global_db_conn() {
+        Ok(db_conn) => {
+            if let Err(err) = tx_origin::Entity::insert_many(rows).exec(&db_conn).await {
+                warn!(?err, num_rows, "unable to save tx origins");
+            }
+        }
+        Err(err) => {
+            trace!(?err, num_rows, "no database for tx origins");
+        }
+    }
+}
+
+/// delete rows older than the retention period. returns how many were deleted
+pub async fn sweep(retention: chrono::Duration) -> Web3ProxyResult<u64> {
+    let db_conn = global_db_conn()?;
+
+    let cutoff = Utc::now() - retention;
+
+    let x = tx_origin::Entity::delete_many()
+        .filter(tx_origin::Column::Timestamp.lt(cutoff))
+        .exec(&db_conn)
+        .await?;
+
+    Ok(x.rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder(max_per_second: u64, sample_chance: u16) -> TxOriginRecorder {
+        let config = AppConfig {
+            tx_origin_max_per_second: max_per_second,
+            tx_origin_sample_chance: sample_chance,
+            ..Default::default()
+        };
+
+        let (sender, _) = mpsc::channel(1);
+
+        TxOriginRecorder::new(&config, sender)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sampled_past_the_cap() {
+        let x = recorder(3, 0);
+
+        let recorded = (0..10).filter(|_| x.should_record()).count();
+
+        assert_eq!(recorded, 3);
+
+        // the next second gets a fresh cap
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert!(x.should_record());
+
+        // a chance of u16::MAX records everything
+        let x = recorder(3, u16::MAX);
+
+        assert!((0..10).all(|_| x.should_record()));
+
+        // no cap
+        let x = recorder(0, 0);
+
+        assert!((0..10).all(|_| x.should_record()));
+    }
+
+    #[test]
+    fn ip_hashes_are_salted() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(hash_ip("a", &ip), hash_ip("a", &ip));
+        assert_ne!(hash_ip("a", &ip), hash_ip("b", &ip));
+    }
+}
@@ -0,0 +1,590 @@
+//! Where each relayed transaction is in its lifecycle.
+//!
+//! Transactions relayed by `eth_sendRawTransaction` start out pending. They are confirmed once a new consensus head
+//! includes them and are orphaned if that block is reorged away. An orphaned transaction is confirmed again if a later
+//! head includes it.
+//!
+//! Confirmed transactions are forgotten once their block is too deep for a reorg to be noticed. Transactions that stay
+//! pending too long are forgotten too, and anything else is forgotten once it hasn't changed state for the retention
+//! period. When too many are tracked, the oldest are forgotten to make room.
+//!
+//! Sends are queued for a background task that also watches the consensus head. Status lookups only take a short lock.
+
+use crate::app::Web3ProxyJoinHandle;
+use crate::config::AppConfig;
+use crate::errors::Web3ProxyResult;
+use crate::globals::global_app;
+use crate::rpcs::blockchain::BlockHeader;
+use chrono::{DateTime, Utc};
+use ethers::types::{TxHash, H256, U64};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::interval;
+use tracing::{debug, info, trace};
+
+pub use web3_proxy_client::types::{TrackedTx, TxState};
+
+/// sends waiting to be tracked. if the task falls this far behind, new sends are dropped
+pub const MAX_QUEUED: usize = 10_000;
+
+/// how many recent heads are remembered. reorgs deeper than this are not noticed
+pub const RECENT_BLOCKS: usize = 128;
+
+/// how often old and long pending transactions are forgotten
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct RecentBlock {
+    hash: H256,
+    txs: Vec<TxHash>,
+}
+
+/// The state machine. Kept separate from the task so that it is easy to test
+#[derive(Debug, Default)]
+pub struct TxTrackerState {
+    txs: HashMap<TxHash, TrackedTx>,
+    /// oldest first. entries for transactions that were already forgotten are skipped
+    order: VecDeque<(DateTime<Utc>, TxHash)>,
+    /// the canonical chain as of the latest head
+    recent_blocks: BTreeMap<U64, RecentBlock>,
+    pub confirmed: u64,
+    pub orphaned: u64,
+    /// forgotten after not changing state for the retention period
+    pub pruned: u64,
+    /// forgotten after staying pending for too long
+    pub expired: u64,
+    /// confirmed transactions forgotten once their block was too deep to be reorged
+    pub settled: u64,
+    /// forgotten to make room for newer transactions
+    pub evicted: u64,
+}
+
+impl TxTrackerState {
+    pub fn get(&self, txid: &TxHash) -> Option<TrackedTx> {
+        self.txs.get(txid).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// A transaction was relayed. If `max_tracked` is hit, the oldest transaction is forgotten to make room
+    pub fn sent(&mut self, txid: TxHash, now: DateTime<Utc>, max_tracked: usize) {
+        if self.txs.contains_key(&txid) {
+            return;
+        }
+
+        while self.txs.len() >= max_tracked.max(1) {
+            let Some((first_seen, oldest)) = self.order.pop_front() else {
+                break;
+            };
+
+            // the same txid might have been forgotten and sent again since this entry was queued
+            if self.txs.get(&oldest).map(|x| x.first_seen) == Some(first_seen) {
+                self.txs.remove(&oldest);
+                self.evicted += 1;
+            }
+        }
+
+        let mut tx = TrackedTx {
+            state: TxState::Pending,
+            first_seen: now,
+            updated: now,
+        };
+
+        // on fast chains the head that includes the transaction can arrive before the send is tracked
+        if let Some((num, block)) = self
+            .recent_blocks
+            .iter()
+            .find(|(_, block)| block.txs.contains(&txid))
+        {
+            tx.state = TxState::Confirmed {
+                block_num: *num,
+                block_hash: block.hash,
+            };
+            self.confirmed += 1;
+        }
+
+        self.txs.insert(txid, tx);
+        self.order.push_back((now, txid));
+    }
+
+    /// A new consensus head. Blocks that it replaces orphan their transactions and its own transactions are confirmed.
+    /// Returns the transactions that were orphaned and not picked back up by this head
+    pub fn new_head(
+        &mut self,
+        num: U64,
+        hash: H256,
+        parent_hash: H256,
+        txs: &[TxHash],
+        now: DateTime<Utc>,
+    ) -> Vec<TxHash> {
+        if self.recent_blocks.get(&num).map(|x| x.hash) == Some(hash) {
+            // we already have this one
+            return vec![];
+        }
+
+        // everything at or past this height was on another branch
+        let mut replaced: Vec<(U64, RecentBlock)> =
+            self.recent_blocks.split_off(&num).into_iter().collect();
+
+        // the parent might have changed too. anything deeper than that is not noticed
+        if num > U64::zero() {
+            let parent_num = num - 1;
+
+            if let Some(parent) = self.recent_blocks.get(&parent_num) {
+                if parent.hash != parent_hash {
+                    let parent = self.recent_blocks.remove(&parent_num).unwrap();
+                    replaced.push((parent_num, parent));
+                }
+            }
+        }
+
+        let mut orphaned = vec![];
+
+        for (_, block) in replaced {
+            for txid in block.txs.iter() {
+                if let Some(tx) = self.txs.get_mut(txid) {
+                    if let TxState::Confirmed {
+                        block_num,
+                        block_hash,
+                    } = tx.state
+                    {
+                        if block_hash == block.hash {
+                            tx.state = TxState::Orphaned {
+                                block_num,
+                                block_hash,
+                            };
+                            tx.updated = now;
+                            self.orphaned += 1;
+                            orphaned.push(*txid);
+                        }
+                    }
+                }
+            }
+        }
+
+        for txid in txs {
+            if let Some(tx) = self.txs.get_mut(txid) {
+                if !matches!(tx.state, TxState::Confirmed { block_hash, .. } if block_hash == hash)
+                {
+                    tx.state = TxState::Confirmed {
+                        block_num: num,
+                        block_hash: hash,
+                    };
+                    tx.updated = now;
+                    self.confirmed += 1;
+                }
+            }
+        }
+
+        self.recent_blocks.insert(
+            num,
+            RecentBlock {
+                hash,
+                txs: txs.to_vec(),
+            },
+        );
+
+        while self.recent_blocks.len() > RECENT_BLOCKS {
+            let Some((_, block)) = self.recent_blocks.pop_first() else {
+                break;
+            };
+
+            // reorgs this deep are not noticed. there is nothing left to track
+            for txid in block.txs.iter() {
+                if matches!(self.txs.get(txid), Some(TrackedTx { state: TxState::Confirmed { block_hash, .. }, .. }) if *block_hash == block.hash)
+                {
+                    self.txs.remove(txid);
+                    self.settled += 1;
+                }
+            }
+        }
+
+        // this head might have picked some of them back up
+        orphaned.retain(|txid| {
+            matches!(
+                self.txs.get(txid),
+                Some(TrackedTx {
+                    state: TxState::Orphaned { .. },
+                    ..
+                })
+            )
+        });
+
+        orphaned
+    }
+
+    /// Forget transactions that have been pending longer than `pending_max_age` or haven't changed in `retention`
+    pub fn prune(
+        &mut self,
+        now: DateTime<Utc>,
+        retention: chrono::Duration,
+        pending_max_age: chrono::Duration,
+    ) {
+        let cutoff = now - retention;
+        let pending_cutoff = now - pending_max_age;
+
+        let mut expired = 0;
+        let mut pruned = 0;
+
+        self.txs.retain(|_, tx| {
+            if tx.state == TxState::Pending && tx.first_seen < pending_cutoff {
+                expired += 1;
+                false
+            } else if tx.updated < cutoff {
+                pruned += 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        self.expired += expired;
+        self.pruned += pruned;
+
+        let txs = &self.txs;
+        self.order
+            .retain(|(first_seen, txid)| txs.get(txid).map(|x| x.first_seen) == Some(*first_seen));
+    }
+}
+
+/// Tracks relayed transactions until they are confirmed
+pub struct TxTracker {
+    /// the app for this chain re-broadcasts orphaned transactions
+    chain_id: u64,
+    max_tracked: usize,
+    sender: mpsc::Sender<(TxHash, DateTime<Utc>)>,
+    state: Mutex<TxTrackerState>,
+    /// sends waiting for the task
+    queued: AtomicUsize,
+    /// sends that the task has handled
+    processed_txs: AtomicU64,
+    /// heads that the task has handled
+    processed_heads: AtomicU64,
+    /// sends dropped because the queue was full
+    dropped: AtomicU64,
+}
+
+impl TxTracker {
+    /// None if `tx_tracker_retention_secs` is 0. The handle exits when the app shuts down
+    pub fn spawn(
+        config: &AppConfig,
+        head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Option<(Arc<Self>, Web3ProxyJoinHandle<()>)> {
+        if config.tx_tracker_retention_secs == 0 {
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED);
+
+        let x = Arc::new(Self {
+            chain_id: config.chain_id,
+            max_tracked: config.tx_tracker_max_tracked,
+            sender,
+            state: Default::default(),
+            queued: AtomicUsize::new(0),
+            processed_txs: AtomicU64::new(0),
+            processed_heads: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+
+        let retention = chrono::Duration::seconds(config.tx_tracker_retention_secs as i64);
+        let pending_max_age =
+            chrono::Duration::seconds(config.tx_tracker_pending_max_age_secs as i64);
+
+        let handle = tokio::spawn(x.clone().track_loop(
+            receiver,
+            head_block_receiver,
+            shutdown_receiver,
+            retention,
+            pending_max_age,
+        ));
+
+        info!(
+            retention_secs = config.tx_tracker_retention_secs,
+            pending_max_age_secs = config.tx_tracker_pending_max_age_secs,
+            max_tracked = config.tx_tracker_max_tracked,
+            "tracking relayed transactions"
+        );
+
+        Some((x, handle))
+    }
+
+    /// Queue a relayed transaction. This never waits
+    pub fn sent(&self, txid: TxHash) {
+        if self.sender.try_send((txid, Utc::now())).is_ok() {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, txid: &TxHash) -> Option<TrackedTx> {
+        self.state.lock().get(txid)
+    }
+
+    /// transactions that are being tracked
+    pub fn len(&self) -> usize {
+        self.state.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().is_empty()
+    }
+
+    async fn track_loop(
+        self: Arc<Self>,
+        mut receiver: mpsc::Receiver<(TxHash, DateTime<Utc>)>,
+        mut head_block_receiver: watch::Receiver<Option<BlockHeader>>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+        retention: chrono::Duration,
+        pending_max_age: chrono::Duration,
+    ) -> Web3ProxyResult<()> {
+        let mut prune_interval = interval(PRUNE_INTERVAL);
+
+        loop {
+            select! {
+                x = receiver.recv() => {
+                    let Some((txid, first_seen)) = x else {
+                        break;
+                    };
+
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+
+                    self.state.lock().sent(txid, first_seen, self.max_tracked);
+
+                    self.processed_txs.fetch_add(1, Ordering::Relaxed);
+                }
+                x = head_block_receiver.changed() => {
+                    if x.is_err() {
+                        break;
+                    }
+
+                    let head = head_block_receiver.borrow_and_update().clone();
+
+                    if let Some(head) = head {
+                        let orphaned = self.state.lock().new_head(
+                            head.number(),
+                            *head.hash(),
+                            *head.parent_hash(),
+                            head.transactions(),
+                            Utc::now(),
+                        );
+
+                        self.processed_heads.fetch_add(1, Ordering::Relaxed);
+
+                        // they are pending again. newPendingTransactions subscribers already saw them once, so skip the dedupe
+                        if !orphaned.is_empty() {
+                            if let Some(app) = global_app(self.chain_id) {
+                                debug!(count = orphaned.len(), "re-broadcasting orphaned transactions");
+
+                                for txid in orphaned {
+                                    app.pending_txid_firehose.resend(txid).await;
+                                }
+                            }
+                        }
+                    }
+                }
+                _ = prune_interval.tick() => {
+                    self.state.lock().prune(Utc::now(), retention, pending_max_age);
+                }
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+            }
+        }
+
+        trace!("tx tracker exited");
+
+        Ok(())
+    }
+}
+
+impl Serialize for TxTracker {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let state = self.state.lock();
+
+        let mut s = serializer.serialize_struct("TxTracker", 12)?;
+
+        s.serialize_field("queued", &self.queued.load(Ordering::Relaxed))?;
+        s.serialize_field("processed_txs", &self.processed_txs.load(Ordering::Relaxed))?;
+        s.serialize_field(
+            "processed_heads",
+            &self.processed_heads.load(Ordering::Relaxed),
+        )?;
+        s.serialize_field("dropped", &self.dropped.load(Ordering::Relaxed))?;
+        s.serialize_field("tracked", &state.len())?;
+        s.serialize_field("confirmed", &state.confirmed)?;
+        s.serialize_field("orphaned", &state.orphaned)?;
+        s.serialize_field("pruned", &state.pruned)?;
+        s.serialize_field("expired", &state.expired)?;
+        s.serialize_field("settled", &state.settled)?;
+        s.serialize_field("evicted", &state.evicted)?;
+        s.serialize_field("max_tracked", &self.max_tracked)?;
+
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(x: u64) -> H256 {
+        H256::from_low_u64_be(x)
+    }
+
+    #[test]
+    fn pending_confirmed_orphaned() {
+        let mut x = TxTrackerState::default();
+        let now = Utc::now();
+
+        let tx_a = hash(100);
+        let tx_b = hash(101);
+
+        x.new_head(1.into(), hash(1), hash(0), &[], now);
+
+        x.sent(tx_a, now, 10);
+        x.sent(tx_b, now, 10);
+        assert_eq!(x.get(&tx_a).unwrap().state, TxState::Pending);
+
+        x.new_head(2.into(), hash(2), hash(1), &[tx_a], now);
+        assert_eq!(
+            x.get(&tx_a).unwrap().state,
+            TxState::Confirmed {
+                block_num: 2.into(),
+                block_hash: hash(2)
+            }
+        );
+        assert_eq!(x.get(&tx_b).unwrap().state, TxState::Pending);
+
+        // block 2 is replaced by a sibling that doesn't include tx_a
+        assert_eq!(
+            x.new_head(2.into(), hash(22), hash(1), &[tx_b], now),
+            vec![tx_a]
+        );
+        assert_eq!(
+            x.get(&tx_a).unwrap().state,
+            TxState::Orphaned {
+                block_num: 2.into(),
+                block_hash: hash(2)
+            }
+        );
+        assert!(matches!(
+            x.get(&tx_b).unwrap().state,
+            TxState::Confirmed { .. }
+        ));
+
+        // a later block picks tx_a back up
+        x.new_head(3.into(), hash(3), hash(22), &[tx_a], now);
+        assert!(matches!(
+            x.get(&tx_a).unwrap().state,
+            TxState::Confirmed { block_num, .. } if block_num == 3.into()
+        ));
+
+        // a new head whose parent changed orphans the parent's transactions too
+        x.new_head(4.into(), hash(44), hash(33), &[], now);
+        assert!(matches!(
+            x.get(&tx_a).unwrap().state,
+            TxState::Orphaned { .. }
+        ));
+
+        assert_eq!(x.orphaned, 2);
+    }
+
+    #[test]
+    fn head_before_send() {
+        let mut x = TxTrackerState::default();
+        let now = Utc::now();
+
+        let txid = hash(100);
+
+        x.new_head(1.into(), hash(1), hash(0), &[txid], now);
+
+        x.sent(txid, now, 10);
+        assert!(matches!(
+            x.get(&txid).unwrap().state,
+            TxState::Confirmed { .. }
+        ));
+    }
+
+    #[test]
+    fn limits_and_pruning() {
+        let mut x = TxTrackerState::default();
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+
+        x.sent(hash(1), now - hour * 2, 2);
+        x.sent(hash(2), now - hour * 2, 2);
+        x.new_head(1.into(), hash(1001), hash(1000), &[hash(2)], now - hour * 2);
+        x.sent(hash(3), now, 2);
+        x.sent(hash(4), now, 2);
+
+        // the oldest were forgotten to make room
+        assert_eq!(x.len(), 2);
+        assert_eq!(x.evicted, 2);
+        assert!(x.get(&hash(1)).is_none());
+        assert!(x.get(&hash(2)).is_none());
+
+        // pending too long
+        x.sent(hash(7), now - hour, 10);
+        // orphaned and unchanged for longer than the retention period
+        x.new_head(2.into(), hash(1002), hash(1001), &[hash(8)], now - hour * 3);
+        x.sent(hash(8), now - hour * 3, 10);
+        x.new_head(2.into(), hash(2002), hash(1001), &[], now - hour * 3);
+        assert!(matches!(
+            x.get(&hash(8)).unwrap().state,
+            TxState::Orphaned { .. }
+        ));
+
+        x.prune(now, hour * 2, chrono::Duration::minutes(15));
+
+        assert!(x.get(&hash(7)).is_none());
+        assert!(x.get(&hash(8)).is_none());
+        assert_eq!(x.expired, 1);
+        assert_eq!(x.pruned, 1);
+        assert_eq!(x.order.len(), x.len());
+    }
+
+    #[test]
+    fn confirmed_txs_settle() {
+        let mut x = TxTrackerState::default();
+        let now = Utc::now();
+
+        let txid = hash(1);
+
+        x.sent(txid, now, 10);
+        x.new_head(1.into(), hash(1001), hash(1000), &[txid], now);
+
+        // still remembered while a reorg could orphan it
+        for i in 2..=(RECENT_BLOCKS as u64) {
+            x.new_head(i.into(), hash(i + 1000), hash(i + 999), &[], now);
+        }
+        assert!(x.get(&txid).is_some());
+
+        // only RECENT_BLOCKS are remembered. the block that confirmed it fell off
+        for i in (RECENT_BLOCKS as u64 + 1)..(RECENT_BLOCKS as u64 * 2) {
+            x.new_head(i.into(), hash(i + 1000), hash(i + 999), &[], now);
+        }
+        assert_eq!(x.recent_blocks.len(), RECENT_BLOCKS);
+        assert!(x.get(&txid).is_none());
+        assert_eq!(x.settled, 1);
+    }
+}
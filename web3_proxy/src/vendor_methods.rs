@@ -0,0 +1,225 @@
+//! Non-standard methods that only some backends support, like `ots_searchTransactionsBefore` or
+//! `alchemy_getAssetTransfers`.
+//!
+//! Without any config, these get the `unknown_methods` policy: they are either blocked, or sent to whichever balanced
+//! rpc is best right now and priced and cached like any other unknown method. Most of our backends would then answer
+//! "method not found". A method listed in `[app.vendor_methods]` is only sent to the rpcs named for it, in that order,
+//! and gets its own price, timeout, and caching. Methods that aren't listed are handled the same as before.
+
+use crate::block_number::CacheMode;
+use crate::config::TopConfig;
+use crate::jsonrpc::request_builder::MAX_REQUEST_TIMEOUT;
+use crate::rpcs::blockchain::BlockHeader;
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use std::time::Duration;
+
+/// One entry in `[app.vendor_methods]`. The key is the method's name
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VendorMethodConfig {
+    /// The balanced rpcs that support this method, most preferred first. Requests for it never go anywhere else
+    pub rpcs: Vec<String>,
+
+    /// What each request is charged. Without this, it is priced like any other unknown method
+    pub compute_units: Option<u64>,
+
+    /// How long to wait for a response. Without this, the usual request timeout is used.
+    /// Longer timeouts are cut to the max request timeout
+    pub timeout_secs: Option<u64>,
+
+    /// Cache successful responses until the next block. We don't know which param (if any) is a block number, so
+    /// only turn this on for methods that answer the same way for the same params during a block
+    #[serde_inline_default(false)]
+    pub cache: bool,
+}
+
+impl VendorMethodConfig {
+    fn check(&self, method: &str, top_config: &TopConfig) -> anyhow::Result<()> {
+        if method.starts_with("admin_") {
+            anyhow::bail!(
+                "vendor method {:?} can not be allowed. admin methods are always blocked",
+                method
+            );
+        }
+
+        if self.rpcs.is_empty() {
+            anyhow::bail!("vendor method {:?} needs at least one rpc", method);
+        }
+
+        let mut enabled = 0;
+
+        for rpc in self.rpcs.iter() {
+            match top_config.balanced_rpcs.get(rpc) {
+                None => anyhow::bail!(
+                    "vendor method {:?} is for unknown balanced rpc {:?}",
+                    method,
+                    rpc
+                ),
+                Some(x) if !x.disabled => enabled += 1,
+                Some(_) => {}
+            }
+        }
+
+        if enabled == 0 {
+            anyhow::bail!("vendor method {:?} is only for disabled rpcs", method);
+        }
+
+        if self.timeout_secs == Some(0) {
+            anyhow::bail!("vendor method {:?} has a timeout of 0 seconds", method);
+        }
+
+        Ok(())
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs
+            .map(|x| Duration::from_secs(x).min(MAX_REQUEST_TIMEOUT))
+    }
+
+    /// Cached responses are kept with the head block. A backend's error is never cached since another backend might not fail
+    pub fn cache_mode(&self, head_block: Option<&BlockHeader>) -> CacheMode {
+        match head_block {
+            Some(head_block) if self.cache => CacheMode::Standard {
+                block_needed: head_block.into(),
+                cache_block: head_block.into(),
+                cache_errors: false,
+            },
+            _ => CacheMode::Never,
+        }
+    }
+}
+
+impl TopConfig {
+    /// Every rpc named in `vendor_methods` must be a balanced rpc. Configs with a mistake are rejected so that a typo
+    /// can't send a method to a backend that doesn't support it
+    pub fn check_vendor_methods(&self) -> anyhow::Result<()> {
+        let mut methods: Vec<_> = self.app.vendor_methods.iter().collect();
+        methods.sort_by_key(|(method, _)| *method);
+
+        for (method, x) in methods {
+            x.check(method, self)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Block, H256};
+    use std::sync::Arc;
+
+    fn top_config(vendor_methods: &str) -> TopConfig {
+        TopConfig::from_toml(&format!(
+            r#"
+            [app]
+            chain_id = 1
+
+            [app.vendor_methods]
+            {}
+
+            [balanced_rpcs.erigon]
+            http_url = "https://erigon.example.com"
+
+            [balanced_rpcs.old_erigon]
+            http_url = "https://old-erigon.example.com"
+            disabled = true
+            "#,
+            vendor_methods
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn check_vendor_methods() {
+        let x = top_config(
+            r#"
+            ots_searchTransactionsBefore = { rpcs = ["erigon", "old_erigon"], compute_units = 100, timeout_secs = 30 }
+            "#,
+        );
+        x.check_vendor_methods().unwrap();
+
+        let method = &x.app.vendor_methods["ots_searchTransactionsBefore"];
+        assert_eq!(method.compute_units, Some(100));
+        assert_eq!(method.timeout(), Some(Duration::from_secs(30)));
+        assert!(!method.cache);
+
+        for (bad, expected) in [
+            (
+                r#"ots_getApiLevel = { rpcs = ["erign"] }"#,
+                "unknown balanced rpc",
+            ),
+            (r#"ots_getApiLevel = { rpcs = [] }"#, "at least one rpc"),
+            (
+                r#"ots_getApiLevel = { rpcs = ["old_erigon"] }"#,
+                "only for disabled rpcs",
+            ),
+            (
+                r#"ots_getApiLevel = { rpcs = ["erigon"], timeout_secs = 0 }"#,
+                "timeout of 0",
+            ),
+            (r#"admin_peers = { rpcs = ["erigon"] }"#, "admin methods"),
+        ] {
+            let err = top_config(bad)
+                .check_vendor_methods()
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(expected), "{} -> {}", bad, err);
+        }
+
+        assert!(TopConfig::from_toml(
+            r#"
+            [app]
+            chain_id = 1
+
+            [app.vendor_methods]
+            ots_getApiLevel = { rpcs = ["erigon"], cahce = true }
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn timeouts_are_capped() {
+        let x = top_config(r#"erigon_watch = { rpcs = ["erigon"], timeout_secs = 3600 }"#);
+
+        assert_eq!(
+            x.app.vendor_methods["erigon_watch"].timeout(),
+            Some(MAX_REQUEST_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn vendor_cache_mode() {
+        let head_block = BlockHeader::try_new(Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(100.into()),
+            ..Default::default()
+        }))
+        .unwrap();
+
+        let x = top_config(
+            r#"
+            erigon_getHeaderByNumber = { rpcs = ["erigon"], cache = true }
+            ots_searchTransactionsBefore = { rpcs = ["erigon"] }
+            "#,
+        );
+
+        let cached = &x.app.vendor_methods["erigon_getHeaderByNumber"];
+        let uncached = &x.app.vendor_methods["ots_searchTransactionsBefore"];
+
+        assert_eq!(
+            cached.cache_mode(Some(&head_block)),
+            CacheMode::Standard {
+                block_needed: (&head_block).into(),
+                cache_block: (&head_block).into(),
+                cache_errors: false,
+            }
+        );
+        assert_eq!(cached.cache_mode(None), CacheMode::Never);
+        assert_eq!(uncached.cache_mode(Some(&head_block)), CacheMode::Never);
+    }
+}
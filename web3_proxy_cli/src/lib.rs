@@ -1,2 +1,3 @@
+pub mod output;
 pub mod sub_commands;
 pub mod test_utils;
@@ -2,25 +2,32 @@ use web3_proxy::prelude::*;
 
 use anyhow::Context;
 use argh::FromArgs;
-use ethers::types::U256;
 use pagerduty_rs::eventsv2async::EventsV2 as PagerdutyAsyncEventsV2;
 use pagerduty_rs::eventsv2sync::EventsV2 as PagerdutySyncEventsV2;
 use sentry::types::Dsn;
 use std::{
     borrow::Cow,
-    fs, panic,
+    env,
+    fs::OpenOptions,
+    panic,
     path::Path,
+    process::ExitCode,
     sync::atomic::{self, AtomicUsize},
+    sync::Mutex,
 };
 use tokio::runtime;
-use tracing::{info, warn};
-use tracing_subscriber::{prelude::*, EnvFilter};
+use tracing::{info, warn, Level};
+use tracing_subscriber::filter::{filter_fn, Targets};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*};
+use web3_proxy::access_log::ACCESS_LOG_TARGET;
+use web3_proxy::log_filter::{reloadable_filter, LogFilter};
 use web3_proxy::pagerduty::panic_handler;
 use web3_proxy::{
     app::APP_USER_AGENT,
     config::TopConfig,
     relational_db::{connect_db, get_migrated_db},
 };
+use web3_proxy_cli::output::{ExitStatus, ExitStatusContext, Output};
 use web3_proxy_cli::sub_commands;
 
 #[cfg(feature = "mimalloc")]
@@ -52,6 +59,10 @@ pub struct Web3ProxyCli {
     #[argh(option)]
     pub sentry_url: Option<Dsn>,
 
+    /// print results as json instead of as tables. logs go to stderr
+    #[argh(switch)]
+    pub json: bool,
+
     /// this one cli can do multiple things
     #[argh(subcommand)]
     sub_command: SubCommand,
@@ -60,6 +71,7 @@ pub struct Web3ProxyCli {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
+    BackendScores(sub_commands::BackendScoresSubCommand),
     ChangeAdminStatus(sub_commands::ChangeAdminStatusSubCommand),
     ChangeUserAddress(sub_commands::ChangeUserAddressSubCommand),
     ChangeUserTier(sub_commands::ChangeUserTierSubCommand),
@@ -84,11 +96,66 @@ enum SubCommand {
     TransferKey(sub_commands::TransferKeySubCommand),
     UserExport(sub_commands::UserExportSubCommand),
     UserImport(sub_commands::UserImportSubCommand),
+    VerifyResponse(sub_commands::VerifyResponseSubCommand),
     // TODO: sub command to downgrade migrations? sea-orm has this but doing downgrades here would be easier+safer
     // TODO: sub command to add new api keys to an existing user?
 }
 
-fn main() -> anyhow::Result<()> {
+/// like `argh::from_env`, but bad arguments exit with `ExitStatus::Validation`
+fn parse_args() -> Result<Web3ProxyCli, ExitCode> {
+    let args: Vec<String> = env::args().collect();
+
+    let cmd = args
+        .first()
+        .and_then(|x| Path::new(x).file_name())
+        .and_then(|x| x.to_str())
+        .unwrap_or("web3_proxy_cli");
+
+    let args: Vec<&str> = args.iter().skip(1).map(|x| x.as_str()).collect();
+
+    Web3ProxyCli::from_args(&[cmd], &args).map_err(|early_exit| match early_exit.status {
+        Ok(()) => {
+            // --help
+            println!("{}", early_exit.output);
+            ExitStatus::Ok.into()
+        }
+        Err(()) => {
+            eprintln!(
+                "{}\nRun {} --help for more information.",
+                early_exit.output, cmd
+            );
+            ExitStatus::Validation.into()
+        }
+    })
+}
+
+/// the database url is required for most subcommands
+fn require_db_url(db_url: Option<String>, sub_command: &str) -> anyhow::Result<String> {
+    db_url
+        .with_context(|| {
+            format!(
+                "'--config' (with a db) or '--db-url' is required to run {}",
+                sub_command
+            )
+        })
+        .validation()
+}
+
+fn main() -> ExitCode {
+    let cli_config = match parse_args() {
+        Ok(x) => x,
+        Err(x) => return x,
+    };
+
+    let output = Output::new(cli_config.json);
+
+    match run(cli_config, &output) {
+        Ok(()) => ExitStatus::Ok.into(),
+        Err(err) => output.fail(&err).into(),
+    }
+}
+
+fn run(mut cli_config: Web3ProxyCli, output: &Output) -> anyhow::Result<()> {
     // this probably won't matter for us in docker, but better safe than sorry
     fdlimit::raise_fd_limit()?;
 
@@ -158,8 +225,6 @@ fn main() -> anyhow::Result<()> {
         rust_log.push_str(&extra_rust_log);
     }
 
-    let mut cli_config: Web3ProxyCli = argh::from_env();
-
     if cli_config.config.is_none() && cli_config.db_url.is_none() && cli_config.sentry_url.is_none()
     {
         // TODO: default to example.toml if development.toml doesn't exist
@@ -170,11 +235,10 @@ fn main() -> anyhow::Result<()> {
     let (top_config, top_config_path) = if let Some(top_config_path) = cli_config.config.clone() {
         let top_config_path = Path::new(&top_config_path)
             .canonicalize()
-            .context(format!("checking for config at {}", top_config_path))?;
-
-        let top_config: String = fs::read_to_string(top_config_path.clone())?;
+            .context(format!("checking for config at {}", top_config_path))
+            .validation()?;
 
-        let mut top_config: TopConfig = toml::from_str(&top_config)?;
+        let mut top_config = TopConfig::load(&top_config_path).validation()?;
 
         if cli_config.db_url.is_none() {
             cli_config.db_url = top_config.app.db_url.clone();
@@ -184,17 +248,6 @@ fn main() -> anyhow::Result<()> {
             cli_config.sentry_url = Some(sentry_url);
         }
 
-        if top_config.app.chain_id == 137 {
-            // TODO: these numbers are arbitrary. i think the maticnetwork/erigon fork has a bug
-            if top_config.app.gas_increase_min.is_none() {
-                top_config.app.gas_increase_min = Some(U256::from(40_000));
-            }
-
-            if top_config.app.gas_increase_percent.is_none() {
-                top_config.app.gas_increase_percent = Some(U256::from(40));
-            }
-        }
-
         top_config.clean();
 
         (Some(top_config), Some(top_config_path))
@@ -229,23 +282,62 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    let env_filter = EnvFilter::builder().parse(&rust_log)?;
+    // json output owns stdout
+    let log_writer = if output.is_json() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
+    // the access log can have a file of its own. it is left out of the other logs then
+    let access_log_layer = match top_config
+        .as_ref()
+        .and_then(|x| x.app.access_log_path.as_ref())
+    {
+        None => None,
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context(format!("opening access log at {}", path))?;
+
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(Mutex::new(file))
+                    .with_filter(Targets::new().with_target(ACCESS_LOG_TARGET, Level::INFO)),
+            )
+        }
+    };
+    let separate_access_log = access_log_layer.is_some();
+
+    // every layer's filter can be changed later with `PUT /admin/log_filter`
+    let (env_filter, fmt_reload) = reloadable_filter(&rust_log)?;
     let fmt_layer = tracing_subscriber::fmt::layer()
         .pretty()
-        .with_filter(env_filter);
+        .with_writer(log_writer)
+        .with_filter(env_filter)
+        .with_filter(filter_fn(move |x| {
+            !separate_access_log || x.target() != ACCESS_LOG_TARGET
+        }));
 
-    let env_filter = EnvFilter::builder().parse(rust_log)?;
+    let (env_filter, sentry_reload) = reloadable_filter(&rust_log)?;
     let sentry_layer = sentry_tracing::layer().with_filter(env_filter);
 
+    LogFilter::install(rust_log, vec![fmt_reload, sentry_reload]);
+
     // build a `Subscriber` by combining layers
     let tracing_registry = tracing_subscriber::registry()
         .with(fmt_layer)
+        .with(access_log_layer)
         .with(sentry_layer);
 
     #[cfg(feature = "tokio-console")]
     let tracing_registry = {
         // TODO: i'm not sure if this env_filter is needed, but it seems like a good idea
-        let env_filter = EnvFilter::builder().parse("tokio=trace,runtime=trace")?;
+        let env_filter =
+            tracing_subscriber::EnvFilter::builder().parse("tokio=trace,runtime=trace")?;
         let console_layer = console_subscriber::spawn().with_filter(env_filter);
 
         tracing_registry.with(console_layer)
@@ -316,134 +408,119 @@ fn main() -> anyhow::Result<()> {
 
     rt.block_on(async {
         match cli_config.sub_command {
+            SubCommand::BackendScores(x) => {
+                let top_config = top_config
+                    .context("--config is required to run backend_scores")
+                    .validation()?;
+
+                x.main(top_config, output).await
+            }
             SubCommand::ChangeAdminStatus(x) => {
-                let db_url = cli_config.db_url.expect(
-                    "'--config' (with a db) or '--db-url' is required to run change_admin_status",
-                );
+                let db_url = require_db_url(cli_config.db_url, "change_admin_status")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::ChangeUserAddress(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run change_user_addres");
+                let db_url = require_db_url(cli_config.db_url, "change_user_address")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::ChangeUserTier(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run change_user_tier");
+                let db_url = require_db_url(cli_config.db_url, "change_user_tier")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::ChangeUserTierByAddress(x) => {
-                let db_url = cli_config.db_url.expect(
-                    "'--config' (with a db) or '--db-url' is required to run change_user_tier_by_address",
-                );
+                let db_url = require_db_url(cli_config.db_url, "change_user_tier_by_address")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::ChangeUserTierByKey(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run change_user_tier_by_key");
+                let db_url = require_db_url(cli_config.db_url, "change_user_tier_by_key")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::CheckBalance(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run change_user_addres");
+                let db_url = require_db_url(cli_config.db_url, "check_balance")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
-            SubCommand::CheckConfig(x) => x.main().await,
+            SubCommand::CheckConfig(x) => x.main(output).await,
             SubCommand::CreateKey(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run create a key");
+                let db_url = require_db_url(cli_config.db_url, "create_key")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::CreateUser(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run create_user");
+                let db_url = require_db_url(cli_config.db_url, "create_user")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::CountUsers(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run count_users");
+                let db_url = require_db_url(cli_config.db_url, "count_users")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::GrantCreditsToAddress(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run create_user");
+                let db_url = require_db_url(cli_config.db_url, "grant_credits_to_address")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::Proxyd(x) => {
-                let top_config = top_config.expect("--config is required to run proxyd");
+                let top_config = top_config
+                    .context("--config is required to run proxyd")
+                    .validation()?;
                 let top_config_path =
                     top_config_path.expect("path must be set if top_config exists");
 
                 x.main(top_config, top_config_path, num_workers).await
             }
             SubCommand::DropMigrationLock(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run drop_migration_lock");
+                let db_url = require_db_url(cli_config.db_url, "drop_migration_lock")?;
 
                 // very intentionally, do NOT run migrations here. that would wait forever if the migration lock is abandoned
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::MassGrantCredits(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run mass_grant_credits");
+                let db_url = require_db_url(cli_config.db_url, "mass_grant_credits")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::MigrateStatsToV2(x) => {
 
-                let top_config = top_config.expect("--config is required to run the migration from stats-mysql to stats-influx");
+                let top_config = top_config
+                    .context("--config is required to run the migration from stats-mysql to stats-influx")
+                    .validation()?;
                 // let top_config_path =
                 //     top_config_path.expect("path must be set if top_config exists");
 
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run the migration from stats-mysql to stats-influx");
+                let db_url = require_db_url(cli_config.db_url, "the migration from stats-mysql to stats-influx")?;
 
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
                 x.main(top_config, &db_conn).await
             }
             SubCommand::Pagerduty(x) => {
@@ -453,9 +530,15 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(pagerduty_async, top_config).await
             }
-            SubCommand::PopularityContest(x) => x.main().await,
+            SubCommand::PopularityContest(x) => x.main(output).await,
             #[cfg(feature = "rdkafka")]
-            SubCommand::SearchKafka(x) => x.main(top_config.unwrap()).await,
+            SubCommand::SearchKafka(x) => {
+                let top_config = top_config
+                    .context("--config is required to run search_kafka")
+                    .validation()?;
+
+                x.main(top_config).await
+            }
             SubCommand::Sentryd(x) => {
                 if cli_config.sentry_url.is_none() {
                     warn!("sentry_url is not set! Logs will only show in this console");
@@ -464,39 +547,36 @@ fn main() -> anyhow::Result<()> {
                 x.main(pagerduty_async, top_config).await
             }
             SubCommand::RpcAccounting(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run rpc_accounting");
+                let db_url = require_db_url(cli_config.db_url, "rpc_accounting")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::TransferKey(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run transfer_key");
-                let db_conn = connect_db(db_url, 1, 1).await?;
+                let db_url = require_db_url(cli_config.db_url, "transfer_key")?;
+                let db_conn = connect_db(db_url, 1, 1).await.connectivity()?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::UserExport(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run user_export");
+                let db_url = require_db_url(cli_config.db_url, "user_export")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
             }
             SubCommand::UserImport(x) => {
-                let db_url = cli_config
-                    .db_url
-                    .expect("'--config' (with a db) or '--db-url' is required to run user_import");
+                let db_url = require_db_url(cli_config.db_url, "user_import")?;
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, output).await
+            }
+            SubCommand::VerifyResponse(x) => {
+                let config_key = top_config.and_then(|x| x.app.response_signing_key);
+
+                x.main(config_key, output).await
             }
         }
     })
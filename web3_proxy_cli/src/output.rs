@@ -0,0 +1,305 @@
+//! Output and exit codes that every subcommand shares.
+//!
+//! Automation runs these commands, too. With `--json`, stdout gets exactly one json document and logs go to stderr.
+//! If a command fails before it prints anything, that document is the error.
+//!
+//! Exit codes:
+//!
+//! | code | meaning                                                          |
+//! |------|------------------------------------------------------------------|
+//! | 0    | success                                                          |
+//! | 1    | any other failure                                                |
+//! | 2    | validation failure. bad arguments or a bad config                |
+//! | 3    | connectivity failure. a database or rpc could not be reached     |
+//! | 4    | not found. the user, key, or tier does not exist                 |
+//!
+//! These are part of the cli's interface. Add new codes to the end. Never renumber them.
+
+use prettytable::{Cell, Row, Table};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use web3_proxy::prelude::anyhow::{self, Context};
+use web3_proxy::prelude::migration::sea_orm::DbErr;
+use web3_proxy::prelude::toml;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ExitStatus {
+    Ok = 0,
+    Failure = 1,
+    Validation = 2,
+    Connectivity = 3,
+    NotFound = 4,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// This is also the context message that is added to the error
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            Self::Ok => "ok",
+            Self::Failure => "failure",
+            Self::Validation => "validation failure",
+            Self::Connectivity => "connectivity failure",
+            Self::NotFound => "not found",
+        };
+
+        f.write_str(x)
+    }
+}
+
+impl From<ExitStatus> for process::ExitCode {
+    fn from(x: ExitStatus) -> Self {
+        x.code().into()
+    }
+}
+
+/// Pick the exit code for an error.
+/// An `ExitStatus` anywhere in the context wins. Some common errors are known without one.
+pub fn exit_status(err: &anyhow::Error) -> ExitStatus {
+    if let Some(x) = err.downcast_ref::<ExitStatus>() {
+        return *x;
+    }
+
+    for cause in err.chain() {
+        if let Some(x) = cause.downcast_ref::<DbErr>() {
+            if matches!(x, DbErr::Conn(_) | DbErr::ConnectionAcquire(_)) {
+                return ExitStatus::Connectivity;
+            }
+        }
+
+        if cause.is::<toml::de::Error>() {
+            return ExitStatus::Validation;
+        }
+    }
+
+    ExitStatus::Failure
+}
+
+/// Attach an `ExitStatus` to an error. Works like `anyhow::Context`.
+pub trait ExitStatusContext<T, E> {
+    fn validation(self) -> anyhow::Result<T>;
+    fn connectivity(self) -> anyhow::Result<T>;
+    fn not_found(self) -> anyhow::Result<T>;
+}
+
+impl<T, E, R> ExitStatusContext<T, E> for R
+where
+    R: Context<T, E>,
+{
+    fn validation(self) -> anyhow::Result<T> {
+        self.context(ExitStatus::Validation)
+    }
+
+    fn connectivity(self) -> anyhow::Result<T> {
+        self.context(ExitStatus::Connectivity)
+    }
+
+    fn not_found(self) -> anyhow::Result<T> {
+        self.context(ExitStatus::NotFound)
+    }
+}
+
+/// Where subcommands print their results. Humans get tables. `--json` gets json.
+#[derive(Debug, Default)]
+pub struct Output {
+    json: bool,
+    printed: AtomicBool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self {
+            json,
+            printed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Print one result. Humans get a table with a row for each field.
+    pub fn print<T: Serialize>(&self, x: &T) -> anyhow::Result<()> {
+        let x = self.render(x)?;
+
+        self.printed.store(true, Ordering::Relaxed);
+
+        println!("{}", x);
+
+        Ok(())
+    }
+
+    /// Print a list of results. Humans get a table with `columns` in that order. json gets every field.
+    pub fn print_rows<T: Serialize>(&self, columns: &[&str], rows: &[T]) -> anyhow::Result<()> {
+        let x = self.render_rows(columns, rows)?;
+
+        self.printed.store(true, Ordering::Relaxed);
+
+        println!("{}", x);
+
+        Ok(())
+    }
+
+    /// Print the error that ended the command and return the status to exit with.
+    /// In json mode, the error is only sent to stdout if the command hasn't printed its own document already.
+    pub fn fail(&self, err: &anyhow::Error) -> ExitStatus {
+        let status = exit_status(err);
+
+        if self.json {
+            let x = self.render_error(err, status);
+
+            if self.printed.swap(true, Ordering::Relaxed) {
+                eprintln!("{}", x);
+            } else {
+                println!("{}", x);
+            }
+        } else {
+            eprintln!("Error: {:?}", err);
+        }
+
+        status
+    }
+
+    pub fn render<T: Serialize>(&self, x: &T) -> anyhow::Result<String> {
+        let x = serde_json::to_value(x)?;
+
+        if self.json {
+            return Ok(x.to_string());
+        }
+
+        let Value::Object(x) = x else {
+            return Ok(human_cell(&x));
+        };
+
+        let mut table = Table::new();
+
+        for (k, v) in x.iter() {
+            table.add_row(Row::new(vec![Cell::new(k), Cell::new(&human_cell(v))]));
+        }
+
+        Ok(table.to_string())
+    }
+
+    pub fn render_rows<T: Serialize>(
+        &self,
+        columns: &[&str],
+        rows: &[T],
+    ) -> anyhow::Result<String> {
+        let rows = rows
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if self.json {
+            return Ok(Value::Array(rows).to_string());
+        }
+
+        let mut table = Table::new();
+
+        table.set_titles(Row::new(columns.iter().map(|x| Cell::new(x)).collect()));
+
+        for row in rows.iter() {
+            let cells = match row {
+                Value::Object(x) => columns
+                    .iter()
+                    .map(|k| Cell::new(&x.get(*k).map(human_cell).unwrap_or_default()))
+                    .collect(),
+                x => vec![Cell::new(&human_cell(x))],
+            };
+
+            table.add_row(Row::new(cells));
+        }
+
+        Ok(table.to_string())
+    }
+
+    fn render_error(&self, err: &anyhow::Error, status: ExitStatus) -> String {
+        json!({
+            "error": format!("{:#}", err),
+            "exit_code": status.code(),
+            "status": status,
+        })
+        .to_string()
+    }
+}
+
+/// strings without their quotes and null as an empty cell. everything else is json
+fn human_cell(x: &Value) -> String {
+    match x {
+        Value::Null => "".to_string(),
+        Value::String(x) => x.clone(),
+        Value::Array(x) if x.iter().all(|x| x.is_string()) => x
+            .iter()
+            .filter_map(|x| x.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        x => x.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3_proxy::prelude::migration::sea_orm::RuntimeErr;
+
+    #[derive(Serialize)]
+    struct Example {
+        name: &'static str,
+        count: u64,
+        note: Option<&'static str>,
+    }
+
+    #[test]
+    fn render_formats() {
+        let x = Example {
+            name: "llama",
+            count: 3,
+            note: None,
+        };
+
+        let json = Output::new(true).render(&x).unwrap();
+        assert_eq!(json, r#"{"count":3,"name":"llama","note":null}"#);
+
+        let human = Output::new(false).render(&x).unwrap();
+        assert!(human.contains("| name  | llama |"), "{}", human);
+        assert!(!human.contains("null"), "{}", human);
+
+        let human = Output::new(false)
+            .render_rows(&["name", "count"], &[&x])
+            .unwrap();
+        assert!(human.contains("| name  | count |"), "{}", human);
+        assert!(!human.contains("note"), "{}", human);
+
+        let json = Output::new(true).render_rows(&["name"], &[x]).unwrap();
+        assert_eq!(json, r#"[{"count":3,"name":"llama","note":null}]"#);
+    }
+
+    #[test]
+    fn exit_statuses() {
+        let err = anyhow::anyhow!("boom");
+        assert_eq!(exit_status(&err), ExitStatus::Failure);
+
+        let err: anyhow::Result<()> = Err(anyhow::anyhow!("bad config"));
+        let err = err.validation().context("checking config").unwrap_err();
+        assert_eq!(exit_status(&err), ExitStatus::Validation);
+        assert!(format!("{:#}", err).contains("validation failure: bad config"));
+
+        let err = None::<()>.context("No user found").not_found().unwrap_err();
+        assert_eq!(exit_status(&err), ExitStatus::NotFound);
+
+        let err = anyhow::Error::new(DbErr::Conn(RuntimeErr::Internal("refused".into())));
+        assert_eq!(exit_status(&err), ExitStatus::Connectivity);
+
+        assert_eq!(ExitStatus::Connectivity.code(), 3);
+    }
+}
@@ -0,0 +1,162 @@
+//! score the balanced rpcs from the inputs that the proxies saved in influx
+use crate::output::{ExitStatusContext, Output};
+use serde::Serialize;
+use std::time::Duration;
+use web3_proxy::backend_scores::{combine, query_inputs, RpcConfigSuggestion};
+use web3_proxy::config::TopConfig;
+use web3_proxy::prelude::anyhow::{self, Context};
+use web3_proxy::prelude::argh::{self, FromArgs};
+use web3_proxy::prelude::chrono::Utc;
+use web3_proxy::prelude::influxdb2;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Score the balanced rpcs from the last few hours of saved scoring inputs and suggest config changes.
+/// Nothing is changed.
+#[argh(subcommand, name = "backend_scores")]
+pub struct BackendScoresSubCommand {
+    #[argh(option, default = "3600")]
+    /// how many seconds of history to score
+    window_secs: u64,
+
+    #[argh(switch)]
+    /// only print the suggested config changes
+    suggestions: bool,
+}
+
+#[derive(Serialize)]
+struct ScoreRow<'a> {
+    name: &'a str,
+    score: Option<f64>,
+    latency_ms: Option<f64>,
+    head_delay_ms: Option<f64>,
+    lag: Option<u64>,
+    requests: u64,
+    errors: u64,
+    silent_deaths: u64,
+    unique_pending_txs: u64,
+    soft_limit: u32,
+    backup: bool,
+    suggestion: String,
+}
+
+fn round1(x: f64) -> f64 {
+    (x * 10.0).round() / 10.0
+}
+
+fn describe(x: Option<&RpcConfigSuggestion>) -> String {
+    let Some(x) = x else {
+        return String::new();
+    };
+
+    let mut out = vec![];
+
+    if let Some(soft_limit) = x.soft_limit.as_ref() {
+        out.push(format!(
+            "soft_limit {} -> {}",
+            soft_limit.from, soft_limit.to
+        ));
+    }
+
+    if let Some(backup) = x.backup.as_ref() {
+        out.push(format!("backup {} -> {}", backup.from, backup.to));
+    }
+
+    out.join(", ")
+}
+
+impl BackendScoresSubCommand {
+    pub async fn main(self, top_config: TopConfig, out: &Output) -> anyhow::Result<()> {
+        let app = &top_config.app;
+
+        let influxdb_host = app
+            .influxdb_host
+            .as_ref()
+            .context("influxdb_host is required to score backends offline")
+            .validation()?;
+        let influxdb_org = app
+            .influxdb_org
+            .clone()
+            .context("influxdb_org needed when influxdb_host is set")
+            .validation()?;
+        let influxdb_token = app
+            .influxdb_token
+            .clone()
+            .context("influxdb_token needed when influxdb_host is set")
+            .validation()?;
+        let influxdb_bucket = app
+            .influxdb_bucket
+            .as_ref()
+            .context("influxdb_bucket needed when influxdb_host is set")
+            .validation()?;
+
+        let influxdb_client = influxdb2::Client::new(influxdb_host, influxdb_org, influxdb_token);
+
+        let rows = query_inputs(
+            &influxdb_client,
+            influxdb_bucket,
+            app.chain_id,
+            Duration::from_secs(self.window_secs),
+        )
+        .await
+        .connectivity()?;
+
+        let mut inputs = combine(rows);
+
+        // suggestions should be relative to the config as it is now, not as it was when the inputs were saved
+        for x in inputs.iter_mut() {
+            if let Some(rpc_config) = top_config.balanced_rpcs.get(&x.name) {
+                x.soft_limit = rpc_config.soft_limit;
+                x.backup = rpc_config.backup;
+            }
+        }
+
+        let report = app
+            .backend_scoring
+            .report(inputs, self.window_secs, Utc::now());
+
+        if self.suggestions {
+            return out.print(&report.suggestions);
+        }
+
+        if out.is_json() {
+            return out.print(&report);
+        }
+
+        let rows: Vec<_> = report
+            .backends
+            .iter()
+            .map(|x| ScoreRow {
+                name: &x.name,
+                score: x.score.map(round1),
+                latency_ms: x.inputs.latency_ms.map(round1),
+                head_delay_ms: x.inputs.head_delay_ms.map(round1),
+                lag: x.inputs.lag,
+                requests: x.inputs.requests,
+                errors: x.inputs.errors,
+                silent_deaths: x.inputs.silent_deaths,
+                unique_pending_txs: x.inputs.unique_pending_txs,
+                soft_limit: x.inputs.soft_limit,
+                backup: x.inputs.backup,
+                suggestion: describe(report.suggestions.balanced_rpcs.get(&x.name)),
+            })
+            .collect();
+
+        out.print_rows(
+            &[
+                "name",
+                "score",
+                "latency_ms",
+                "head_delay_ms",
+                "lag",
+                "requests",
+                "errors",
+                "silent_deaths",
+                "unique_pending_txs",
+                "soft_limit",
+                "backup",
+                "suggestion",
+            ],
+            &rows,
+        )
+    }
+}
@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{admin, login, user};
@@ -22,8 +23,8 @@ pub struct ChangeAdminStatusSubCommand {
 }
 
 impl ChangeAdminStatusSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
-        let address: Address = self.address.parse()?;
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
+        let address: Address = self.address.parse().validation()?;
         let should_be_admin: bool = self.should_be_admin;
 
         // Find user in database
@@ -31,7 +32,8 @@ impl ChangeAdminStatusSubCommand {
             .filter(user::Column::Address.eq(address.as_bytes()))
             .one(db_conn)
             .await?
-            .context(format!("No user with this address found {:?}", address))?;
+            .context(format!("No user with this address found {:?}", address))
+            .not_found()?;
 
         debug!("user: {:#}", json!(&user));
 
@@ -58,7 +60,12 @@ impl ChangeAdminStatusSubCommand {
             _ => {
                 info!("no change needed for: {:#}", json!(user));
                 // Since no change happened, we do not want to delete active logins. Return now.
-                return Ok(());
+                return out.print(&json!({
+                    "address": address,
+                    "admin": should_be_admin,
+                    "changed": false,
+                    "user_id": user.id,
+                }));
             }
         }
 
@@ -70,6 +77,11 @@ impl ChangeAdminStatusSubCommand {
 
         debug!("cleared modified logins: {:?}", delete_result);
 
-        Ok(())
+        out.print(&json!({
+            "address": address,
+            "admin": should_be_admin,
+            "changed": true,
+            "user_id": user.id,
+        }))
     }
 }
@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::user;
@@ -23,19 +24,24 @@ pub struct ChangeUserAddressSubCommand {
 }
 
 impl ChangeUserAddressSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
-        let old_address: Address = self.old_address.parse()?;
-        let new_address: Address = self.new_address.parse()?;
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
+        let old_address: Address = self.old_address.parse().validation()?;
+        let new_address: Address = self.new_address.parse().validation()?;
 
         let u = user::Entity::find()
             .filter(user::Column::Address.eq(old_address.as_bytes()))
             .one(db_conn)
             .await?
-            .context("No user found with that address")?;
+            .context("No user found with that address")
+            .not_found()?;
 
         debug!("initial user: {:#}", json!(&u));
 
-        if u.address == new_address.as_bytes() {
+        let user_id = u.id;
+
+        let changed = u.address != new_address.as_bytes();
+
+        if !changed {
             info!("user already has this address");
         } else {
             let mut u = u.into_active_model();
@@ -47,6 +53,10 @@ impl ChangeUserAddressSubCommand {
             info!("updated user: {:#?}", u);
         }
 
-        Ok(())
+        out.print(&json!({
+            "address": new_address,
+            "changed": changed,
+            "user_id": user_id,
+        }))
     }
 }
@@ -1,9 +1,10 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::user_tier;
 use web3_proxy::prelude::migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
-    QueryFilter,
+    QueryFilter, TryIntoModel,
 };
 use web3_proxy::prelude::serde_json::json;
 use web3_proxy::prelude::tracing::{debug, info};
@@ -28,12 +29,13 @@ pub struct ChangeUserTierSubCommand {
 impl ChangeUserTierSubCommand {
     // TODO: don't expose the RpcSecretKeys at all. Better to take a user/key id. this is definitely most convenient
 
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.eq(self.user_tier_title))
             .one(db_conn)
             .await?
-            .context("No user tier found with that name")?;
+            .context("No user tier found with that name")
+            .not_found()?;
 
         debug!("initial user_tier: {:#}", json!(&user_tier));
 
@@ -59,10 +61,10 @@ impl ChangeUserTierSubCommand {
             }
         }
 
-        let user_tier = user_tier.save(db_conn).await?;
+        let user_tier = user_tier.save(db_conn).await?.try_into_model()?;
 
         debug!("new user_tier: {:#?}", user_tier);
 
-        Ok(())
+        out.print(&user_tier)
     }
 }
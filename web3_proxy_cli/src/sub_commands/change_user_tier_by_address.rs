@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{user, user_tier};
@@ -23,13 +24,14 @@ pub struct ChangeUserTierByAddressSubCommand {
 }
 
 impl ChangeUserTierByAddressSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         // use the address to get the user
         let user = user::Entity::find()
             .filter(user::Column::Address.eq(self.user_address.as_bytes()))
             .one(db_conn)
             .await?
-            .context("No user found with that key")?;
+            .context("No user found with that key")
+            .not_found()?;
 
         // TODO: don't serialize the rpc key
         debug!("user: {:#}", json!(&user));
@@ -39,11 +41,16 @@ impl ChangeUserTierByAddressSubCommand {
             .filter(user_tier::Column::Title.eq(self.user_tier_title))
             .one(db_conn)
             .await?
-            .context("No user tier found with that name")?;
+            .context("No user tier found with that name")
+            .not_found()?;
 
         debug!("user_tier: {:#}", json!(&user_tier));
 
-        if user.user_tier_id == user_tier.id {
+        let user_id = user.id;
+
+        let changed = user.user_tier_id != user_tier.id;
+
+        if !changed {
             info!("user already has that tier");
         } else {
             let mut user = user.into_active_model();
@@ -55,6 +62,10 @@ impl ChangeUserTierByAddressSubCommand {
             info!("user's tier changed");
         }
 
-        Ok(())
+        out.print(&json!({
+            "changed": changed,
+            "user_id": user_id,
+            "user_tier": user_tier.title,
+        }))
     }
 }
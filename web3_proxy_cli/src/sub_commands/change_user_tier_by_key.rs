@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{rpc_key, user, user_tier};
@@ -26,14 +27,15 @@ pub struct ChangeUserTierByKeySubCommand {
 impl ChangeUserTierByKeySubCommand {
     // TODO: don't expose the RpcSecretKeys at all. Better to take a user/key id. this is definitely most convenient
 
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let rpc_secret_key: Uuid = self.rpc_secret_key.into();
 
         let user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.eq(self.user_tier_title))
             .one(db_conn)
             .await?
-            .context("No user tier found with that name")?;
+            .context("No user tier found with that name")
+            .not_found()?;
 
         debug!("user_tier: {:#}", json!(&user_tier));
 
@@ -43,11 +45,16 @@ impl ChangeUserTierByKeySubCommand {
             .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
             .one(db_conn)
             .await?
-            .context("No user found with that key")?;
+            .context("No user found with that key")
+            .not_found()?;
 
         debug!("user: {:#}", json!(&user));
 
-        if user.user_tier_id == user_tier.id {
+        let user_id = user.id;
+
+        let changed = user.user_tier_id != user_tier.id;
+
+        if !changed {
             info!("user already has that tier");
         } else {
             let mut user = user.into_active_model();
@@ -59,6 +66,10 @@ impl ChangeUserTierByKeySubCommand {
             info!("user's tier changed");
         }
 
-        Ok(())
+        out.print(&json!({
+            "changed": changed,
+            "user_id": user_id,
+            "user_tier": user_tier.title,
+        }))
     }
 }
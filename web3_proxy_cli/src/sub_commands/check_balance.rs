@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::balance::Balance;
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -19,13 +20,14 @@ pub struct CheckBalanceSubCommand {
 }
 
 impl CheckBalanceSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         // use the address to get the user
         let user = user::Entity::find()
             .filter(user::Column::Address.eq(self.user_address.as_bytes()))
             .one(db_conn)
             .await?
-            .context("No user found with that key")?;
+            .context("No user found with that address")
+            .not_found()?;
 
         // TODO: don't serialize the rpc key
         debug!("user: {:#}", json!(&user));
@@ -33,6 +35,9 @@ impl CheckBalanceSubCommand {
         let balance = Balance::try_from_db(db_conn, user.id).await?;
         debug!("balance: {:#}", json!(&balance));
 
-        Ok(())
+        out.print(&json!({
+            "balance": balance,
+            "user_id": user.id,
+        }))
     }
 }
@@ -1,9 +1,17 @@
+use crate::output::{ExitStatusContext, Output};
+use serde::Serialize;
 use std::fs;
-use web3_proxy::config::TopConfig;
-use web3_proxy::prelude::anyhow;
+use std::path::Path;
+use std::time::Duration;
+use web3_proxy::config::{TopConfig, SECRET_FILE_SUFFIX, SECRET_KEYS};
+use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
+use web3_proxy::prelude::chrono::Utc;
+use web3_proxy::prelude::ethers::types::U64;
+use web3_proxy::prelude::tokio::time::timeout;
 use web3_proxy::prelude::toml;
 use web3_proxy::prelude::tracing::{error, info, warn};
+use web3_proxy::rpcs::provider::connect_ws;
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Check the config for any problems.
@@ -14,20 +22,99 @@ pub struct CheckConfigSubCommand {
     path: String,
 }
 
-impl CheckConfigSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        let mut num_errors = 0;
+/// Everything that check_config found. Problems are logged as they are found, too.
+#[derive(Debug, Default, Serialize)]
+struct CheckConfigReport {
+    path: String,
+    chain_id: u64,
+    /// the config is wrong
+    errors: Vec<String>,
+    /// the config looks right, but a backend could not be reached
+    connectivity_errors: Vec<String>,
+    warnings: Vec<String>,
+    unknown_keys: Vec<String>,
+}
+
+impl CheckConfigReport {
+    fn error(&mut self, msg: String) {
+        error!("{}", msg);
+        self.errors.push(msg);
+    }
+
+    fn warn(&mut self, msg: String) {
+        warn!("{}", msg);
+        self.warnings.push(msg);
+    }
+}
 
+impl CheckConfigSubCommand {
+    pub async fn main(self, out: &Output) -> anyhow::Result<()> {
         info!("Loading config @ {}", self.path);
-        let top_config: String = fs::read_to_string(self.path)?;
-        let mut top_config: TopConfig = toml::from_str(&top_config)?;
+        let raw_config = fs::read_to_string(&self.path)
+            .with_context(|| format!("reading {}", self.path))
+            .validation()?;
+
+        let raw_config: toml::Table = toml::from_str(&raw_config).validation()?;
+
+        // this also reads any secret files. a missing or unreadable file is an error here
+        let mut top_config = match TopConfig::load(Path::new(&self.path)) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("unable to load config: {:#}", err);
+                return Err(err).validation();
+            }
+        };
+
+        let mut report = CheckConfigReport {
+            path: self.path.clone(),
+            chain_id: top_config.app.chain_id,
+            ..Default::default()
+        };
+
+        // secrets in the toml end up in git and in backups. suggest files instead
+        if let Some(toml::Value::Table(app)) = raw_config.get("app") {
+            for key in SECRET_KEYS {
+                if app.contains_key(key) {
+                    report.warn(format!(
+                        "app.{} is set directly. consider app.{}{} with the path to a secret file",
+                        key, key, SECRET_FILE_SUFFIX
+                    ));
+                }
+            }
+        }
+
+        for (prefix, top_config) in chain_sections(&top_config) {
+            // the proxy would merge these. check before cleaning so that the config gets fixed instead
+            for duplicate in top_config.duplicate_rpcs() {
+                report.error(format!("{}{}", prefix, duplicate));
+            }
+
+            // the proxy would refuse to start
+            if let Some(conflict) = top_config.maintenance_conflict(Utc::now()) {
+                report.error(format!("{}{}", prefix, conflict));
+            }
+
+            if let Err(err) = top_config.check_vendor_methods() {
+                report.error(format!("{}{}", prefix, err));
+            }
+        }
 
+        // this warns about each unknown key
         top_config.clean();
 
+        report.unknown_keys = top_config.unknown_keys();
+        if !report.unknown_keys.is_empty() {
+            warn!(
+                "{} unknown keys will be ignored: {}",
+                report.unknown_keys.len(),
+                report.unknown_keys.join(", ")
+            );
+        }
+
         info!("config: {:#?}", top_config);
 
         if top_config.app.db_url.is_none() {
-            warn!("app.db_url is not set! Some features disabled")
+            report.warn("app.db_url is not set! Some features disabled".into())
         }
 
         match top_config.app.public_requests_per_period {
@@ -44,7 +131,7 @@ impl CheckConfigSubCommand {
             None => {
                 info!("app.default_user_requests_per_period is None. Fully open to registered requests!")
             }
-            Some(0) => warn!("app.default_user_requests_per_period is 0. Registered user's requests are blocked! Are you sure you want that?"),
+            Some(0) => report.warn("app.default_user_requests_per_period is 0. Registered user's requests are blocked! Are you sure you want that?".into()),
             Some(_) => {
                 // TODO: make sure this isn't < anonymous requests per period
             }
@@ -61,38 +148,119 @@ impl CheckConfigSubCommand {
         // TODO: check login_rate_limit_per_period is a reasonable amount. requires redis
 
         if top_config.app.volatile_redis_url.is_none() {
-            warn!("app.volatile_redis_url is not set! Some features disabled")
+            report.warn("app.volatile_redis_url is not set! Some features disabled".into())
         }
 
         // TODO: check response_cache_max_bytes is a reasonable amount
 
         if top_config.app.redirect_public_url.is_none() {
-            warn!("app.redirect_public_url is None. Anonyoumous users will get an error page instead of a redirect")
+            report.warn("app.redirect_public_url is None. Anonyoumous users will get an error page instead of a redirect".into())
         }
 
         // TODO: also check that it contains rpc_key_id!
         match top_config.app.redirect_rpc_key_url {
             None => {
-                warn!("app.redirect_rpc_key_url is None. Registered users will get an error page instead of a redirect")
+                report.warn("app.redirect_rpc_key_url is None. Registered users will get an error page instead of a redirect".into())
             }
             Some(x) => {
                 if !x.contains("{{rpc_key_id}}") {
-                    num_errors += 1;
-                    error!("redirect_rpc_key_url user url must contain \"{{rpc_key_id}}\"")
+                    report.error("redirect_rpc_key_url user url must contain \"{{rpc_key_id}}\"".into())
                 }
             }
         }
 
-        // TODO: print num warnings and have a flag to fail even on warnings
+        // websocket-only rpcs send every request over their websocket. make sure that works
+        for (prefix, top_config) in chain_sections(&top_config) {
+            for (group, rpcs) in [
+                ("balanced_rpcs", &top_config.balanced_rpcs),
+                ("private_rpcs", &top_config.private_rpcs),
+                ("bundler_4337_rpcs", &top_config.bundler_4337_rpcs),
+            ] {
+                for (name, rpc_config) in rpcs.iter() {
+                    let Some(ws_url) = rpc_config.ws_url.as_ref() else {
+                        continue;
+                    };
 
-        if num_errors == 0 {
-            Ok(())
+                    if rpc_config.disabled
+                        || rpc_config.http_url.is_some()
+                        || rpc_config.ipc_path.is_some()
+                    {
+                        continue;
+                    }
+
+                    match check_ws_rpc(ws_url, top_config.app.chain_id).await {
+                        Ok(()) => info!("{}{}.{} answered over its websocket", prefix, group, name),
+                        Err(err) => {
+                            let msg = format!(
+                                "{}{}.{} only has a ws_url, but a request over it failed: {:#}",
+                                prefix, group, name, err
+                            );
+
+                            error!("{}", msg);
+                            report.connectivity_errors.push(msg);
+                        }
+                    }
+                }
+            }
+        }
+
+        // TODO: have a flag to fail even on warnings
+
+        out.print(&report)?;
+
+        if !report.errors.is_empty() {
+            Err(anyhow::anyhow!(
+                "there were {} errors!",
+                report.errors.len()
+            ))
+            .validation()
+        } else if !report.connectivity_errors.is_empty() {
+            Err(anyhow::anyhow!(
+                "there were {} connectivity errors!",
+                report.connectivity_errors.len()
+            ))
+            .connectivity()
         } else {
-            Err(anyhow::anyhow!("there were {} errors!", num_errors))
+            Ok(())
         }
     }
 }
 
+/// The config of each chain that would be served and the prefix of its keys. Without `[chains]`, that is the whole config
+fn chain_sections(top_config: &TopConfig) -> Vec<(String, &TopConfig)> {
+    if top_config.chains.is_empty() {
+        return vec![(String::new(), top_config)];
+    }
+
+    top_config
+        .chains
+        .iter()
+        .map(|(name, x)| (format!("chains.{}.", name), &x.top_config))
+        .collect()
+}
+
+/// connect to a websocket and make sure that it answers requests for the expected chain
+async fn check_ws_rpc(ws_url: &str, chain_id: u64) -> anyhow::Result<()> {
+    let ws_url = ws_url.parse()?;
+
+    let provider = timeout(Duration::from_secs(10), connect_ws(ws_url, 0))
+        .await?
+        .map_err(|err| anyhow::anyhow!("unable to connect: {}", err))?;
+
+    let found_chain_id: U64 =
+        timeout(Duration::from_secs(10), provider.request("eth_chainId", ())).await??;
+
+    if found_chain_id.as_u64() != chain_id {
+        anyhow::bail!(
+            "incorrect chain id! Config has {}, but RPC has {}",
+            chain_id,
+            found_chain_id
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
@@ -114,7 +282,7 @@ mod tests {
             CheckConfigSubCommand::from_args(&["check_config"], &[config_path_str])
                 .expect("the command should have run");
 
-        let check_config_result = check_config_command.main().await;
+        let check_config_result = check_config_command.main(&Output::default()).await;
 
         println!("{:?}", check_config_result);
 
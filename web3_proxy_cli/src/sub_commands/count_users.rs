@@ -1,8 +1,9 @@
-use tracing::info;
+use crate::output::Output;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::user;
 use web3_proxy::prelude::migration::sea_orm::{self, EntityTrait, PaginatorTrait};
+use web3_proxy::prelude::serde_json::json;
 
 #[derive(FromArgs, PartialEq, Debug, Eq)]
 /// Create a new user and api key
@@ -10,11 +11,9 @@ use web3_proxy::prelude::migration::sea_orm::{self, EntityTrait, PaginatorTrait}
 pub struct CountUsersSubCommand {}
 
 impl CountUsersSubCommand {
-    pub async fn main(self, db: &sea_orm::DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db: &sea_orm::DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let count = user::Entity::find().count(db).await?;
 
-        info!("user count: {}", count);
-
-        Ok(())
+        out.print(&json!({ "user_count": count }))
     }
 }
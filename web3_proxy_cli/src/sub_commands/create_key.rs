@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{rpc_key, user};
@@ -5,7 +6,7 @@ use web3_proxy::prelude::ethers::prelude::Address;
 use web3_proxy::prelude::migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter,
 };
-use web3_proxy::prelude::tracing::info;
+use web3_proxy::prelude::serde_json::json;
 use web3_proxy::prelude::ulid::Ulid;
 use web3_proxy::prelude::uuid::Uuid;
 use web3_proxy::secrets::RpcSecretKey;
@@ -31,15 +32,14 @@ pub struct CreateKeySubCommand {
 }
 
 impl CreateKeySubCommand {
-    pub async fn main(self, db: &sea_orm::DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db: &sea_orm::DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         // TODO: get existing or create a new one
         let u = user::Entity::find()
             .filter(user::Column::Address.eq(self.address.as_bytes()))
             .one(db)
             .await?
-            .context("No user found with that address")?;
-
-        info!("user #{}", u.id);
+            .context("No user found with that address")
+            .not_found()?;
 
         let rpc_secret_key = self.rpc_secret_key.unwrap_or_default();
 
@@ -51,11 +51,13 @@ impl CreateKeySubCommand {
             ..Default::default()
         };
 
-        let _uk = uk.save(db).await.context("Failed saving new user key")?;
-
-        info!("user key as ULID: {}", Ulid::from(rpc_secret_key));
-        info!("user key as UUID: {}", Uuid::from(rpc_secret_key));
+        let uk = uk.save(db).await.context("Failed saving new user key")?;
 
-        Ok(())
+        out.print(&json!({
+            "rpc_key_id": uk.id.as_ref(),
+            "rpc_secret_key_ulid": Ulid::from(rpc_secret_key).to_string(),
+            "rpc_secret_key_uuid": Uuid::from(rpc_secret_key).to_string(),
+            "user_id": u.id,
+        }))
     }
 }
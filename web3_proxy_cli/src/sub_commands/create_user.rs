@@ -1,9 +1,10 @@
-use tracing::info;
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{rpc_key, user};
 use web3_proxy::prelude::ethers::prelude::Address;
 use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait, TransactionTrait};
+use web3_proxy::prelude::serde_json::json;
 use web3_proxy::prelude::ulid::Ulid;
 use web3_proxy::prelude::uuid::Uuid;
 use web3_proxy::secrets::RpcSecretKey;
@@ -33,13 +34,13 @@ pub struct CreateUserSubCommand {
 }
 
 impl CreateUserSubCommand {
-    pub async fn main(self, db: &sea_orm::DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db: &sea_orm::DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let txn = db.begin().await?;
 
         // TODO: would be nice to use the fixed array instead of a Vec in the entities
         // take a simple String. If it starts with 0x, parse as address. otherwise convert ascii to hex
         let address = if self.address.starts_with("0x") {
-            let address = self.address.parse::<Address>()?;
+            let address = self.address.parse::<Address>().validation()?;
 
             address.to_fixed_bytes().into()
         } else {
@@ -62,11 +63,8 @@ impl CreateUserSubCommand {
 
         let u = u.save(&txn).await.context("Failed saving new user")?;
 
-        info!(
-            "user #{}: {:?}",
-            u.id.as_ref(),
-            Address::from_slice(u.address.as_ref())
-        );
+        let user_id = *u.id.as_ref();
+        let user_address = Address::from_slice(u.address.as_ref());
 
         let rpc_secret_key = self.rpc_secret_key.unwrap_or_default();
 
@@ -79,13 +77,16 @@ impl CreateUserSubCommand {
         };
 
         // TODO: if this fails, rever adding the user, too
-        let _uk = uk.save(&txn).await.context("Failed saving new user key")?;
+        let uk = uk.save(&txn).await.context("Failed saving new user key")?;
 
         txn.commit().await?;
 
-        info!("user key as ULID: {}", Ulid::from(rpc_secret_key));
-        info!("user key as UUID: {}", Uuid::from(rpc_secret_key));
-
-        Ok(())
+        out.print(&json!({
+            "address": user_address,
+            "rpc_key_id": uk.id.as_ref(),
+            "rpc_secret_key_ulid": Ulid::from(rpc_secret_key).to_string(),
+            "rpc_secret_key_uuid": Uuid::from(rpc_secret_key).to_string(),
+            "user_id": user_id,
+        }))
     }
 }
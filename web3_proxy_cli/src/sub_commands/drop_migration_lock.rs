@@ -1,6 +1,8 @@
+use crate::output::Output;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::migration::sea_orm::DatabaseConnection;
+use web3_proxy::prelude::serde_json::json;
 use web3_proxy::relational_db::{drop_migration_lock, migrate_db};
 
 #[derive(FromArgs, PartialEq, Debug, Eq)]
@@ -13,7 +15,7 @@ pub struct DropMigrationLockSubCommand {
 }
 
 impl DropMigrationLockSubCommand {
-    pub async fn main(&self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(&self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         if self.and_migrate {
             migrate_db(db_conn, true).await?;
         } else {
@@ -21,6 +23,9 @@ impl DropMigrationLockSubCommand {
             drop_migration_lock(db_conn).await?;
         }
 
-        Ok(())
+        out.print(&json!({
+            "dropped_lock": true,
+            "migrated": self.and_migrate,
+        }))
     }
 }
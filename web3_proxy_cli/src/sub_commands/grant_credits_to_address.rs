@@ -2,6 +2,7 @@
 // that's easier than refactoring right now.
 // it could be cleaned up, but this is a script that runs once so isn't worth spending tons of time on.
 
+use crate::output::{ExitStatusContext, Output};
 use web3_proxy::balance::Balance;
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -13,7 +14,6 @@ use web3_proxy::prelude::migration::sea_orm::{
 };
 use web3_proxy::prelude::rust_decimal::Decimal;
 use web3_proxy::prelude::serde_json::json;
-use web3_proxy::prelude::tracing::info;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Grant credits to all the users in a tier (and change their tier to premium).
@@ -33,18 +33,20 @@ pub struct GrantCreditsToAddress {
 }
 
 impl GrantCreditsToAddress {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let premium_user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.like("Premium"))
             .one(db_conn)
             .await?
-            .context("no Premium user tier found")?;
+            .context("no Premium user tier found")
+            .not_found()?;
 
         let user = user::Entity::find()
             .filter(user::Column::Address.eq(self.user_address.as_bytes()))
             .one(db_conn)
             .await?
-            .context("no user")?;
+            .context("no user")
+            .not_found()?;
 
         let user_id = user.id;
 
@@ -79,8 +81,10 @@ impl GrantCreditsToAddress {
             .await?
             .context("no balance")?;
 
-        info!("{:?} balance: {:#}", self.user_address, json!(balance));
-
-        Ok(())
+        out.print(&json!({
+            "address": self.user_address,
+            "balance": balance,
+            "user_id": user_id,
+        }))
     }
 }
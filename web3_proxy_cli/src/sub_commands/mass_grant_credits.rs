@@ -2,6 +2,7 @@
 // that's easier than refactoring right now.
 // it could be cleaned up, but this is a script that runs once so isn't worth spending tons of time on.
 
+use crate::output::{ExitStatusContext, Output};
 use tracing::info;
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -12,6 +13,7 @@ use web3_proxy::prelude::migration::sea_orm::{
     PaginatorTrait, QueryFilter, QueryOrder, TransactionTrait,
 };
 use web3_proxy::prelude::rust_decimal::Decimal;
+use web3_proxy::prelude::serde_json::json;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Grant credits to all the users in a tier (and change their tier to premium).
@@ -27,18 +29,20 @@ pub struct MassGrantCredits {
 }
 
 impl MassGrantCredits {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let old_user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.like(&self.tier_to_upgrade))
             .one(db_conn)
             .await?
-            .context("no user tier found with that name")?;
+            .context("no user tier found with that name")
+            .not_found()?;
 
         let new_user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.like("Premium"))
             .one(db_conn)
             .await?
-            .context("no Premium user tier found")?;
+            .context("no Premium user tier found")
+            .not_found()?;
 
         let mut user_stream = user::Entity::find()
             .filter(user::Column::UserTierId.eq(old_user_tier.id))
@@ -46,10 +50,14 @@ impl MassGrantCredits {
             .paginate(db_conn, 50)
             .into_stream();
 
+        let mut num_upgraded = 0;
+
         while let Some(users_to_upgrade) = user_stream.try_next().await? {
             let txn = db_conn.begin().await?;
 
-            info!("Upgrading {} users", users_to_upgrade.len());
+            let users_to_upgrade_len = users_to_upgrade.len();
+
+            info!("Upgrading {} users", users_to_upgrade_len);
 
             for user_to_upgrade in users_to_upgrade {
                 if self.credits > 0.into() {
@@ -73,11 +81,14 @@ impl MassGrantCredits {
 
             txn.commit().await?;
 
+            num_upgraded += users_to_upgrade_len;
+
             // we can't invalidate balance caches because they are in another process. they do have short ttls though
         }
 
-        info!("success");
-
-        Ok(())
+        out.print(&json!({
+            "credits_per_user": self.credits,
+            "upgraded_users": num_upgraded,
+        }))
     }
 }
@@ -1,3 +1,4 @@
+mod backend_scores;
 mod change_admin_status;
 mod change_user_address;
 mod change_user_tier;
@@ -20,10 +21,12 @@ mod sentryd;
 mod transfer_key;
 mod user_export;
 mod user_import;
+mod verify_response;
 
 #[cfg(feature = "rdkafka")]
 mod search_kafka;
 
+pub use self::backend_scores::BackendScoresSubCommand;
 pub use self::change_admin_status::ChangeAdminStatusSubCommand;
 pub use self::change_user_address::ChangeUserAddressSubCommand;
 pub use self::change_user_tier::ChangeUserTierSubCommand;
@@ -46,6 +49,7 @@ pub use self::sentryd::SentrydSubCommand;
 pub use self::transfer_key::TransferKeySubCommand;
 pub use self::user_export::UserExportSubCommand;
 pub use self::user_import::UserImportSubCommand;
+pub use self::verify_response::VerifyResponseSubCommand;
 
 #[cfg(feature = "rdkafka")]
 pub use self::search_kafka::SearchKafkaSubCommand;
@@ -1,4 +1,5 @@
-use prettytable::{row, Table};
+use crate::output::{ExitStatusContext, Output};
+use serde::Serialize;
 use std::{cmp::Reverse, str::FromStr};
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -33,10 +34,31 @@ struct BackendRpcData<'a> {
     weighted_latency_ms: f64,
 }
 
+#[derive(Serialize)]
+struct PopularityRow<'a> {
+    name: &'a str,
+    external_pct: f64,
+    external: u64,
+    internal: u64,
+    active: u64,
+    lag: u64,
+    block_data_limit: String,
+    head_ms: f64,
+    median_ms: f64,
+    peak_ms: f64,
+    weighted_ms: f64,
+    tier: String,
+}
+
+fn round3(x: f64) -> f64 {
+    (x * 1000.0).round() / 1000.0
+}
+
 impl PopularityContestSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
+    pub async fn main(self, out: &Output) -> anyhow::Result<()> {
         let x: serde_json::Value = reqwest::get(format!("{}/status", self.rpc))
-            .await?
+            .await
+            .connectivity()?
             .json()
             .await?;
 
@@ -147,28 +169,13 @@ impl PopularityContestSubCommand {
             )
         });
 
-        let mut table = Table::new();
-
-        table.add_row(row![
-            "name",
-            "external %",
-            "external",
-            "internal",
-            "active",
-            "lag",
-            "block_data_limit",
-            "head_ms",
-            "median_ms",
-            "peak_ms",
-            "weighted_ms",
-            "tier",
-        ]);
+        let mut rows = Vec::with_capacity(rpc_data.len());
 
         for rpc in rpc_data.into_iter() {
             let external_request_pct = if total_external_requests == 0 {
                 0.0
             } else {
-                (rpc.external_requests as f32) / (total_external_requests as f32) * 100.0
+                (rpc.external_requests as f64) / (total_external_requests as f64) * 100.0
             };
 
             let block_data_limit = if rpc.block_data_limit == u64::MAX {
@@ -185,24 +192,38 @@ impl PopularityContestSubCommand {
 
             let lag = highest_block - rpc.head_block;
 
-            table.add_row(row![
-                rpc.name,
-                format!("{:.3}", external_request_pct),
-                rpc.external_requests,
-                rpc.internal_requests,
-                rpc.active_requests,
+            rows.push(PopularityRow {
+                name: rpc.name,
+                external_pct: round3(external_request_pct),
+                external: rpc.external_requests,
+                internal: rpc.internal_requests,
+                active: rpc.active_requests,
                 lag,
                 block_data_limit,
-                format!("{:.3}", rpc.head_delay_ms),
-                rpc.median_latency_ms,
-                rpc.peak_latency_ms,
-                format!("{:.3}", rpc.weighted_latency_ms),
+                head_ms: round3(rpc.head_delay_ms),
+                median_ms: rpc.median_latency_ms,
+                peak_ms: rpc.peak_latency_ms,
+                weighted_ms: round3(rpc.weighted_latency_ms),
                 tier,
-            ]);
+            });
         }
 
-        table.printstd();
-
-        Ok(())
+        out.print_rows(
+            &[
+                "name",
+                "external_pct",
+                "external",
+                "internal",
+                "active",
+                "lag",
+                "block_data_limit",
+                "head_ms",
+                "median_ms",
+                "peak_ms",
+                "weighted_ms",
+                "tier",
+            ],
+            &rows,
+        )
     }
 }
@@ -1,7 +1,7 @@
 // select all requests for a timeline. sum bandwidth and request count. give `cost / byte` and `cost / request`.
+use crate::output::{ExitStatusContext, Output};
 use serde::Serialize;
 use serde_json::json;
-use tracing::info;
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
 use web3_proxy::prelude::entities::{rpc_accounting, rpc_key, user};
@@ -38,7 +38,7 @@ pub struct RpcAccountingSubCommand {
 }
 
 impl RpcAccountingSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         #[derive(Serialize, FromQueryResult)]
         struct SelectResult {
             total_frontend_requests: Decimal,
@@ -93,14 +93,15 @@ impl RpcAccountingSubCommand {
 
         // TODO: do this with add_option? try operator is harder to use then
         if let Some(address) = self.address {
-            let address = address.parse::<Address>()?;
+            let address = address.parse::<Address>().validation()?;
 
             // TODO: find_with_related
             let u = user::Entity::find()
                 .filter(user::Column::Address.eq(address.as_bytes()))
                 .one(db_conn)
                 .await?
-                .context("no user found")?;
+                .context("no user found")
+                .not_found()?;
 
             // TODO: select_only
             let u_keys = rpc_key::Entity::find()
@@ -108,7 +109,9 @@ impl RpcAccountingSubCommand {
                 .all(db_conn)
                 .await?;
 
-            anyhow::ensure!(!u_keys.is_empty(), "no user keys");
+            if u_keys.is_empty() {
+                return Err(anyhow::anyhow!("no user keys")).not_found();
+            }
 
             let u_key_ids: Vec<_> = u_keys.into_iter().map(|x| x.id).collect();
 
@@ -135,37 +138,33 @@ impl RpcAccountingSubCommand {
             .await?
             .context("no query result")?;
 
-        if let Some(chain_id) = self.chain_id {
-            info!("stats for chain {}", chain_id);
-        } else {
-            info!("stats for all chains");
-        }
-
-        info!("stats: {:#}", json!(&stats));
-
         let query_seconds: Decimal = stats
             .last_period_datetime
             .signed_duration_since(stats.first_period_datetime)
             .num_seconds()
             .into();
-        info!(%query_seconds);
 
         let avg_request_per_second = (stats.total_frontend_requests / query_seconds).round_dp(2);
-        info!(%avg_request_per_second);
 
         let cache_hit_rate = (stats.total_cache_hits / stats.total_frontend_requests
             * Decimal::from(100))
         .round_dp(2);
-        info!(%cache_hit_rate);
 
         let avg_response_millis =
             (stats.total_response_millis / stats.total_frontend_requests).round_dp(3);
-        info!(%avg_response_millis);
 
         let avg_response_bytes =
             (stats.total_response_bytes / stats.total_frontend_requests).round();
-        info!(%avg_response_bytes);
 
-        Ok(())
+        // chain_id is null for all chains
+        out.print(&json!({
+            "avg_request_per_second": avg_request_per_second,
+            "avg_response_bytes": avg_response_bytes,
+            "avg_response_millis": avg_response_millis,
+            "cache_hit_rate": cache_hit_rate,
+            "chain_id": self.chain_id,
+            "query_seconds": query_seconds,
+            "stats": stats,
+        }))
     }
 }
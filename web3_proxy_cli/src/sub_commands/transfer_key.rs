@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use tracing::{debug, info};
 use web3_proxy::prelude::anyhow::{self, Context};
 use web3_proxy::prelude::argh::{self, FromArgs};
@@ -7,6 +8,7 @@ use web3_proxy::prelude::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
     QueryFilter,
 };
+use web3_proxy::prelude::serde_json::json;
 use web3_proxy::prelude::uuid::Uuid;
 use web3_proxy::secrets::RpcSecretKey;
 
@@ -24,16 +26,17 @@ pub struct TransferKeySubCommand {
 }
 
 impl TransferKeySubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let rpc_secret_key: Uuid = self.rpc_secret_key.into();
 
-        let new_address: Address = self.new_address.parse()?;
+        let new_address: Address = self.new_address.parse().validation()?;
 
         let uk = rpc_key::Entity::find()
             .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
             .one(db_conn)
             .await?
-            .context("No key found")?;
+            .context("No key found")
+            .not_found()?;
 
         debug!("user key: {}", serde_json::to_string(&uk)?);
 
@@ -41,11 +44,16 @@ impl TransferKeySubCommand {
             .filter(user::Column::Address.eq(new_address.as_bytes()))
             .one(db_conn)
             .await?
-            .context("No user found with that key")?;
+            .context("No user found with that address")
+            .not_found()?;
 
         debug!("new user: {}", serde_json::to_string(&new_u)?);
 
-        if new_u.id == uk.user_id {
+        let rpc_key_id = uk.id;
+
+        let changed = new_u.id != uk.user_id;
+
+        if !changed {
             info!("user already owns that key");
         } else {
             let mut uk = uk.into_active_model();
@@ -57,6 +65,10 @@ impl TransferKeySubCommand {
             info!("changed the key's owner");
         }
 
-        Ok(())
+        out.print(&json!({
+            "changed": changed,
+            "rpc_key_id": rpc_key_id,
+            "user_id": new_u.id,
+        }))
     }
 }
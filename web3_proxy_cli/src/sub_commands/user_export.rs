@@ -1,6 +1,8 @@
+use crate::output::Output;
 use argh::FromArgs;
 use entities::{rpc_key, user};
 use migration::sea_orm::{DatabaseConnection, EntityTrait, PaginatorTrait};
+use serde_json::json;
 use std::fs::{self, create_dir_all};
 use std::path::Path;
 use tracing::info;
@@ -18,7 +20,7 @@ pub struct UserExportSubCommand {
 }
 
 impl UserExportSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         // create the output dir if it does not exist
         create_dir_all(&self.output_dir)?;
 
@@ -72,6 +74,11 @@ impl UserExportSubCommand {
             export_dir.to_string_lossy()
         );
 
-        Ok(())
+        out.print(&json!({
+            "export_dir": export_dir,
+            "export_timestamp": now,
+            "rpc_key_files": rpc_key_file_count,
+            "user_files": user_file_count,
+        }))
     }
 }
@@ -1,3 +1,4 @@
+use crate::output::{ExitStatusContext, Output};
 use std::path::{Path, PathBuf};
 use std::{fs::File, io::BufReader};
 use tracing::info;
@@ -11,6 +12,7 @@ use web3_proxy::prelude::migration::sea_orm::{
     ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
     Set,
 };
+use web3_proxy::prelude::serde_json::json;
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Import users from another database.
@@ -29,14 +31,16 @@ pub struct UserImportSubCommand {
 type UserMap = HashMap<u64, u64>;
 
 impl UserImportSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(self, db_conn: &DatabaseConnection, out: &Output) -> anyhow::Result<()> {
         let import_dir = Path::new(&self.input_dir);
 
-        anyhow::ensure!(
-            import_dir.exists(),
-            "import dir ({}) does not exist!",
-            import_dir.to_string_lossy()
-        );
+        if !import_dir.exists() {
+            return Err(anyhow::anyhow!(
+                "import dir ({}) does not exist!",
+                import_dir.to_string_lossy()
+            ))
+            .validation();
+        }
 
         let user_glob_path = import_dir.join(format!("{}-users-*.json", self.export_timestamp));
 
@@ -102,7 +106,13 @@ impl UserImportSubCommand {
             imported_rpc_key_count, rpc_key_file_count
         );
 
-        Ok(())
+        out.print(&json!({
+            "imported_rpc_keys": imported_rpc_key_count,
+            "imported_users": imported_user_count,
+            "mapped_users": user_map.len(),
+            "rpc_key_files": rpc_key_file_count,
+            "user_files": user_file_count,
+        }))
     }
 
     pub async fn import_user_file(
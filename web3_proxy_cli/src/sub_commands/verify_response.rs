@@ -0,0 +1,73 @@
+use crate::output::{ExitStatusContext, Output};
+use std::fs;
+use std::io::{self, Read};
+use web3_proxy::prelude::anyhow::{self, Context};
+use web3_proxy::prelude::argh::{self, FromArgs};
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::response_signing::ResponseSigner;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Check the signature headers on a response from a key with `sign_responses`.
+#[argh(subcommand, name = "verify_response")]
+pub struct VerifyResponseSubCommand {
+    #[argh(option)]
+    /// file with the signing key. defaults to the config's `response_signing_key`
+    key_file: Option<String>,
+
+    #[argh(option)]
+    /// file with the response body. "-" reads stdin
+    body: String,
+
+    #[argh(option)]
+    /// the X-W3P-SIGNATURE header
+    signature: String,
+
+    #[argh(option)]
+    /// the X-W3P-SIGNATURE-TIMESTAMP header
+    timestamp: u64,
+
+    #[argh(option)]
+    /// the X-W3P-REQUEST-ID header
+    request_id: String,
+}
+
+impl VerifyResponseSubCommand {
+    pub async fn main(self, config_key: Option<String>, out: &Output) -> anyhow::Result<()> {
+        let key = match self.key_file.as_ref() {
+            Some(x) => fs::read_to_string(x)
+                .with_context(|| format!("reading {}", x))
+                .validation()?
+                .trim()
+                .to_string(),
+            None => config_key
+                .context("'--key-file' or a config with response_signing_key is required")
+                .validation()?,
+        };
+
+        let body = if self.body == "-" {
+            let mut x = vec![];
+            io::stdin().read_to_end(&mut x).context("reading stdin")?;
+            x
+        } else {
+            fs::read(&self.body)
+                .with_context(|| format!("reading {}", self.body))
+                .validation()?
+        };
+
+        let signer = ResponseSigner::new(&key);
+
+        let valid = signer.verify(&body, self.timestamp, &self.request_id, &self.signature);
+
+        out.print(&json!({
+            "request_id": self.request_id,
+            "timestamp": self.timestamp,
+            "valid": valid,
+        }))?;
+
+        if !valid {
+            return Err(anyhow::anyhow!("signature does not match")).validation();
+        }
+
+        Ok(())
+    }
+}
@@ -1,18 +1,14 @@
-use crate::sub_commands::ProxydSubCommand;
-use std::{
-    env,
-    str::FromStr,
-    sync::atomic::{AtomicU16, Ordering},
-    thread,
-};
-use std::{sync::Arc, time::Duration};
+use super::top_config::{anvil_rpc_config, TopConfigBuilder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, str::FromStr, thread};
 use tracing::info;
 use web3_proxy::prelude::anyhow;
 use web3_proxy::prelude::ethers::{
     prelude::{Http, Provider},
     types::Address,
 };
-use web3_proxy::prelude::hashbrown::HashMap;
 use web3_proxy::prelude::serde_json::json;
 use web3_proxy::prelude::tokio::{
     runtime::Builder,
@@ -20,13 +16,10 @@ use web3_proxy::prelude::tokio::{
         broadcast::{self, error::SendError},
         mpsc, oneshot,
     },
-    time::{sleep, Instant},
+    time::{sleep, timeout},
 };
 use web3_proxy::test_utils::{TestAnvil, TestInflux, TestMysql};
-use web3_proxy::{
-    config::{AppConfig, TopConfig, Web3RpcConfig},
-    stats::FlushedStats,
-};
+use web3_proxy::{app::App, config::TopConfig, embed::Web3ProxyBuilder, stats::FlushedStats};
 
 pub struct TestApp {
     /// **THREAD** (not async) handle for the proxy.
@@ -36,6 +29,12 @@ pub struct TestApp {
     /// connection to the proxy that is connected to anil.
     pub proxy_provider: Provider<Http>,
 
+    /// where prometheus metrics are served
+    pub prometheus_addr: SocketAddr,
+
+    /// the app behind the proxy. for checking its internal state
+    pub app: Arc<App>,
+
     /// tell the app to flush stats to the database
     flush_stat_buffer_sender: mpsc::Sender<oneshot::Sender<FlushedStats>>,
 
@@ -50,8 +49,19 @@ impl TestApp {
         influx: Option<&TestInflux>,
         unique_id: Option<u64>,
     ) -> Self {
+        let top_config = Self::top_config(anvil, db, influx, unique_id);
+
+        Self::spawn_with_top_config(top_config).await
+    }
+
+    /// The config that `spawn` uses. Tests that need one more setting can change this and use `spawn_with_top_config`
+    pub fn top_config(
+        anvil: &TestAnvil,
+        db: Option<&TestMysql>,
+        influx: Option<&TestInflux>,
+        unique_id: Option<u64>,
+    ) -> TopConfig {
         let chain_id = anvil.instance.chain_id();
-        let num_workers = 4;
 
         // TODO: move basic setup into a test fixture
         let path = env::var("PATH").unwrap();
@@ -73,106 +83,88 @@ impl TestApp {
         // make a test TopConfig
         // TODO: test influx
         // TODO: test redis
-        let app_config: AppConfig = serde_json::from_value(json!({
-            "chain_id": chain_id,
-            "db_url": db_url,
-            "influxdb_host": influx_host,
-            "influxdb_org": influx_org,
-            "influxdb_token": influx_token,
-            "influxdb_bucket": influx_bucket,
-            "unique_id": unique_id.unwrap_or_default(),
-            "default_user_max_requests_per_period": Some(6_000_000),
-            "deposit_factory_contract": Address::from_str(
-                "4e3BC2054788De923A04936C6ADdB99A05B0Ea36",
-            )
-            .ok(),
-            "min_sum_soft_limit": 1,
-            "min_synced_rpcs": 1,
-            "public_requests_per_period": Some(1_000_000),
-            "response_cache_max_bytes": 10_u64.pow(7),
-        }))
-        .unwrap();
-
-        info!("App Config is: {:?}", app_config);
-
-        let top_config = TopConfig {
-            app: app_config,
-            balanced_rpcs: HashMap::from([(
-                "anvil".to_string(),
-                Web3RpcConfig {
-                    http_url: Some(anvil.instance.endpoint()),
-                    ws_url: Some(anvil.instance.ws_endpoint()),
-                    ..Default::default()
-                },
-            )]),
-            // influxdb_client: influx.map(|x| x.client),
-            private_rpcs: HashMap::from([(
-                "anvil_private".to_string(),
-                Web3RpcConfig {
-                    http_url: Some(anvil.instance.endpoint()),
-                    ws_url: Some(anvil.instance.ws_endpoint()),
-                    ..Default::default()
-                },
-            )]),
-            bundler_4337_rpcs: Default::default(),
-            extra: Default::default(),
-        };
+        let top_config = TopConfigBuilder::new(chain_id)
+            .app(json!({
+                "db_url": db_url,
+                "influxdb_host": influx_host,
+                "influxdb_org": influx_org,
+                "influxdb_token": influx_token,
+                "influxdb_bucket": influx_bucket,
+                "unique_id": unique_id.unwrap_or_default(),
+                "default_user_max_requests_per_period": Some(6_000_000),
+                "deposit_factory_contract": Address::from_str(
+                    "4e3BC2054788De923A04936C6ADdB99A05B0Ea36",
+                )
+                .ok(),
+                // anvil starts at block 0. keep this small so tests can make archive requests
+                "archive_depth": 2,
+            }))
+            .anvil_rpc("anvil", anvil)
+            .private_rpc("anvil_private", anvil_rpc_config(anvil))
+            .build();
+
+        info!("App Config is: {:?}", top_config.app);
+
+        top_config
+    }
 
-        let (shutdown_sender, _shutdown_receiver) = broadcast::channel(1);
+    /// Spawn the app with a config that the test built itself
+    pub async fn spawn_with_top_config(top_config: TopConfig) -> Self {
+        Self::spawn_with_top_config_and_port(top_config, 0).await
+    }
 
-        let frontend_port_arc = Arc::new(AtomicU16::new(0));
-        let prometheus_port_arc = Arc::new(AtomicU16::new(0));
+    /// Spawn the app on a specific port. Useful when the config needs to know the port. 0 lets the os choose
+    pub async fn spawn_with_top_config_and_port(top_config: TopConfig, port: u16) -> Self {
+        let num_workers = 4;
 
-        let (flush_stat_buffer_sender, flush_stat_buffer_receiver) = mpsc::channel(1);
+        let (started_sender, started_receiver) = oneshot::channel();
 
         // spawn the app
-        // TODO: spawn in a thread so we can run from non-async tests and so the Drop impl can wait for it to stop
-        let handle = {
-            let frontend_port_arc = frontend_port_arc.clone();
-            let prometheus_port_arc = prometheus_port_arc.clone();
-            let flush_stat_buffer_sender = flush_stat_buffer_sender.clone();
-            let shutdown_sender = shutdown_sender.clone();
-
-            // TODO: thread isn't enough! this needs its own process for the globals to be isolated!
-            thread::spawn(move || {
-                let runtime = Builder::new_multi_thread()
-                    .enable_all()
-                    .worker_threads(num_workers)
-                    .build()
-                    .unwrap();
-
-                runtime.block_on(ProxydSubCommand::_main(
-                    top_config,
-                    None,
-                    frontend_port_arc,
-                    prometheus_port_arc,
-                    num_workers,
-                    shutdown_sender,
-                    flush_stat_buffer_sender,
-                    flush_stat_buffer_receiver,
-                ))
+        // TODO: thread isn't enough! this needs its own process for the globals to be isolated!
+        let handle = thread::spawn(move || {
+            let runtime = Builder::new_multi_thread()
+                .enable_all()
+                .worker_threads(num_workers)
+                .build()
+                .unwrap();
+
+            runtime.block_on(async move {
+                let mut proxy = Web3ProxyBuilder::new(top_config)
+                    .port(port)
+                    .num_workers(num_workers)
+                    .spawn()
+                    .await
+                    .map_err(|err| anyhow::anyhow!("unable to start: {}", err))?;
+
+                let _ = started_sender.send((
+                    proxy.app().clone(),
+                    proxy.local_addr(),
+                    proxy.prometheus_addr(),
+                    proxy.flush_stat_buffer_sender().clone(),
+                    proxy.shutdown_sender().clone(),
+                ));
+
+                proxy.join().await.map_err(|err| anyhow::anyhow!("{}", err))
             })
-        };
+        });
 
-        let mut frontend_port = frontend_port_arc.load(Ordering::SeqCst);
-        let start = Instant::now();
-        while frontend_port == 0 {
-            // we have to give it some time because it might have to do migrations
-            if start.elapsed() > Duration::from_secs(30) {
-                panic!("took too long to start!");
-            }
-
-            sleep(Duration::from_millis(10)).await;
-            frontend_port = frontend_port_arc.load(Ordering::SeqCst);
-        }
+        // we have to give it some time because it might have to do migrations
+        let (app, local_addr, prometheus_addr, flush_stat_buffer_sender, shutdown_sender) =
+            match timeout(Duration::from_secs(90), started_receiver).await {
+                Ok(Ok(x)) => x,
+                Ok(Err(_)) => panic!("app exited while starting! {:?}", handle.join()),
+                Err(_) => panic!("took too long to start!"),
+            };
 
-        let proxy_endpoint = format!("http://127.0.0.1:{}", frontend_port);
+        let proxy_endpoint = format!("http://{}", local_addr);
 
         let proxy_provider = Provider::<Http>::try_from(proxy_endpoint).unwrap();
 
         Self {
             proxy_handle: Some(handle),
             proxy_provider,
+            prometheus_addr,
+            app,
             flush_stat_buffer_sender,
             shutdown_sender,
         }
@@ -1,4 +1,5 @@
 use super::{TestApp, TestMysql};
+use crate::output::Output;
 use crate::sub_commands::ChangeAdminStatusSubCommand;
 use tracing::info;
 use web3_proxy::frontend::users::authentication::{LoginPostResponse, PostLogin};
@@ -76,7 +77,10 @@ pub async fn create_user_as_admin(
 
     info!("Changing the status of the admin_wallet to be an admin");
     // Pass on the database into it ...
-    admin_status_changer.main(&db_conn).await.unwrap();
+    admin_status_changer
+        .main(&db_conn, &Output::default())
+        .await
+        .unwrap();
 
     // Now log him in again, because he was just signed out
     // Login the admin again, because he was just signed out
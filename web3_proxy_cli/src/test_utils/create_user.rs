@@ -20,6 +20,21 @@ pub async fn create_user(
     user_wallet: &LocalWallet,
     referral_code: Option<String>,
 ) -> (LoginPostResponse) {
+    let (status, user_login_response) = login(x, r, user_wallet, referral_code).await;
+
+    assert_eq!(status, StatusCode::CREATED);
+
+    user_login_response
+}
+
+/// Log in with a wallet. Creates the user if it doesn't exist yet (`StatusCode::CREATED`)
+#[allow(unused)]
+pub async fn login(
+    x: &TestApp,
+    r: &reqwest::Client,
+    user_wallet: &LocalWallet,
+    referral_code: Option<String>,
+) -> (StatusCode, LoginPostResponse) {
     let login_post_url = format!("{}user/login", x.proxy_provider.url());
     let user_login_get_url = format!(
         "{}user/login/{:?}",
@@ -54,7 +69,7 @@ pub async fn create_user(
         .unwrap();
     trace!(?user_login_response);
 
-    assert_eq!(user_login_response.status(), StatusCode::CREATED);
+    let status = user_login_response.status();
 
     let user_login_text = user_login_response.text().await.unwrap();
     trace!("user_login_text: {:#}", user_login_text);
@@ -62,7 +77,7 @@ pub async fn create_user(
     let user_login_response: LoginPostResponse = serde_json::from_str(&user_login_text).unwrap();
     info!(?user_login_response);
 
-    user_login_response
+    (status, user_login_response)
 }
 
 /// TODO: use an admin user to do this instead
@@ -0,0 +1,214 @@
+//! Backends for tests that need a node to misbehave.
+//! Anything the test's handler doesn't answer itself is forwarded to anvil.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use web3_proxy::prelude::ethers::providers::{Provider, Ws};
+use web3_proxy::prelude::futures::{SinkExt, StreamExt};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{self, sync::mpsc};
+use web3_proxy::test_utils::TestAnvil;
+
+/// Sends jsonrpc requests to anvil's http endpoint
+#[derive(Clone)]
+pub struct AnvilHttp {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl AnvilHttp {
+    pub fn new(anvil: &TestAnvil) -> Self {
+        Self {
+            url: anvil.instance.endpoint(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn request(&self, request: &Value) -> Value {
+        self.client
+            .post(&self.url)
+            .json(request)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
+
+/// One request to a mock backend
+pub struct MockRequest {
+    pub headers: HeaderMap,
+    pub body: Value,
+    pub anvil: AnvilHttp,
+}
+
+impl MockRequest {
+    pub fn method(&self) -> &str {
+        self.body["method"].as_str().unwrap_or_default()
+    }
+
+    /// what anvil says. handlers that change anvil's answer start here
+    pub async fn anvil_response(&self) -> Value {
+        self.anvil.request(&self.body).await
+    }
+
+    /// answer exactly like anvil would
+    pub async fn forward(&self) -> Response {
+        Json(self.anvil_response().await).into_response()
+    }
+
+    /// a successful response with this request's id
+    pub fn result(&self, result: Value) -> Response {
+        Json(json!({
+            "jsonrpc": "2.0",
+            "id": self.body["id"],
+            "result": result,
+        }))
+        .into_response()
+    }
+
+    /// an error response with this request's id
+    pub fn error(&self, error: Value) -> Response {
+        Json(json!({
+            "jsonrpc": "2.0",
+            "id": self.body["id"],
+            "error": error,
+        }))
+        .into_response()
+    }
+}
+
+/// Serve the router on a random port on localhost
+pub fn serve(router: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service()),
+    );
+
+    addr
+}
+
+/// Relay one of anvil's subscriptions to a mock backend's websocket with our own subscription id
+async fn relay_subscription(
+    anvil_ws_url: String,
+    params: Value,
+    id: String,
+    sender: mpsc::UnboundedSender<Value>,
+) {
+    let provider = Provider::<Ws>::connect(anvil_ws_url).await.unwrap();
+
+    let mut subscription = provider.subscribe::<_, Value>(params).await.unwrap();
+
+    while let Some(result) = subscription.next().await {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": id,
+                "result": result,
+            },
+        });
+
+        if sender.send(notification).is_err() {
+            break;
+        }
+    }
+}
+
+/// Websocket requests are forwarded to anvil without going through the handler
+async fn relay_websocket(socket: WebSocket, anvil: AnvilHttp, anvil_ws_url: String) {
+    let (mut socket_sender, mut socket_receiver) = socket.split();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+
+    tokio::spawn(async move {
+        while let Some(x) = receiver.recv().await {
+            if socket_sender
+                .send(Message::Text(x.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut num_subscriptions = 0u64;
+
+    while let Some(Ok(message)) = socket_receiver.next().await {
+        let Message::Text(message) = message else {
+            continue;
+        };
+
+        let request: Value = serde_json::from_str(&message).unwrap();
+
+        if request["method"] == "eth_subscribe" {
+            num_subscriptions += 1;
+
+            let id = format!("0x{:x}", num_subscriptions);
+
+            let _ = sender.send(json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": id,
+            }));
+
+            tokio::spawn(relay_subscription(
+                anvil_ws_url.clone(),
+                request["params"].clone(),
+                id,
+                sender.clone(),
+            ));
+        } else {
+            let _ = sender.send(anvil.request(&request).await);
+        }
+    }
+}
+
+/// A backend that sends every http request through `handler`. Returns the backend's url.
+/// `state` is shared with the test so that it can count requests or change how the backend behaves.
+/// The same address also takes websockets. Those are relayed to anvil, so subscriptions work like they do on anvil
+pub fn spawn_mock_backend<S, F, Fut>(anvil: &TestAnvil, state: Arc<S>, handler: F) -> String
+where
+    S: Send + Sync + 'static,
+    F: Fn(Arc<S>, MockRequest) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let anvil_ws_url = anvil.instance.ws_endpoint();
+
+    let anvil = AnvilHttp::new(anvil);
+
+    let ws_anvil = anvil.clone();
+
+    let router = Router::new().route(
+        "/",
+        post(move |headers: HeaderMap, Json(body): Json<Value>| {
+            let request = MockRequest {
+                headers,
+                body,
+                anvil: anvil.clone(),
+            };
+
+            handler(state.clone(), request)
+        })
+        .get(move |ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |socket| relay_websocket(socket, ws_anvil, anvil_ws_url))
+        }),
+    );
+
+    format!("http://{}", serve(router))
+}
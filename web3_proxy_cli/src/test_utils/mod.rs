@@ -4,12 +4,17 @@ pub mod app;
 pub mod create_admin;
 pub mod create_provider_with_rpc_key;
 pub mod create_user;
+pub mod mock_backend;
 pub mod referral;
 pub mod rpc_key;
 pub mod stats_accounting;
+pub mod top_config;
 pub mod user_balance;
 
 pub use self::app::TestApp;
+pub use self::mock_backend::{spawn_mock_backend, MockRequest};
+pub use self::top_config::TopConfigBuilder;
 pub use web3_proxy::test_utils::anvil::TestAnvil;
 pub use web3_proxy::test_utils::influx::TestInflux;
 pub use web3_proxy::test_utils::mysql::TestMysql;
+pub use web3_proxy::test_utils::redis::TestRedis;
@@ -19,16 +19,27 @@ pub struct RpcKeyResponse {
 #[derive(Debug, Deserialize)]
 pub struct RpcKey {
     pub active: bool,
+    pub allow_batches: bool,
+    pub allow_websocket: bool,
+    pub allowed_methods: Option<Vec<String>>,
     pub allowed_ips: Option<serde_json::Value>,
     pub allowed_origins: Option<serde_json::Value>,
     pub allowed_referers: Option<serde_json::Value>,
     pub allowed_user_agents: Option<serde_json::Value>,
+    pub denied_methods: Option<Vec<String>>,
     pub description: Option<serde_json::Value>,
     pub id: u64,
+    pub label: Option<String>,
     pub log_revert_chance: f64,
+    pub max_requests_per_day: Option<u64>,
+    pub max_requests_per_month: Option<u64>,
     pub private_txs: bool,
+    pub require_jsonrpc_2: bool,
     pub role: String,
     pub secret_key: Ulid,
+    pub sign_responses: bool,
+    pub skip_chain_id_check: bool,
+    pub skip_request_coalescing: bool,
     pub user_id: u64,
 }
 
@@ -0,0 +1,92 @@
+use web3_proxy::config::{AppConfig, TopConfig, Web3RpcConfig};
+use web3_proxy::prelude::hashbrown::HashMap;
+use web3_proxy::prelude::serde_json::{self, json, Map, Value};
+use web3_proxy::test_utils::TestAnvil;
+
+/// anvil's http and websocket
+pub fn anvil_rpc_config(anvil: &TestAnvil) -> Web3RpcConfig {
+    Web3RpcConfig {
+        http_url: Some(anvil.instance.endpoint()),
+        ws_url: Some(anvil.instance.ws_endpoint()),
+        ..Default::default()
+    }
+}
+
+/// only http. most mock backends don't have websockets
+pub fn http_rpc_config(http_url: String) -> Web3RpcConfig {
+    Web3RpcConfig {
+        http_url: Some(http_url),
+        ..Default::default()
+    }
+}
+
+/// A `TopConfig` for tests. One synced rpc is enough, public requests are (almost) never limited, and responses are
+/// cached. Tests only need to add their rpcs and whatever app settings they are testing
+pub struct TopConfigBuilder {
+    app: Map<String, Value>,
+    balanced_rpcs: HashMap<String, Web3RpcConfig>,
+    private_rpcs: HashMap<String, Web3RpcConfig>,
+}
+
+impl TopConfigBuilder {
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            app: Default::default(),
+            balanced_rpcs: Default::default(),
+            private_rpcs: Default::default(),
+        }
+        .app(json!({
+            "chain_id": chain_id,
+            "min_sum_soft_limit": 1,
+            "min_synced_rpcs": 1,
+            "public_requests_per_period": 1_000_000,
+            "response_cache_max_bytes": 10_u64.pow(7),
+        }))
+    }
+
+    /// Set some of the `[app]` keys. These replace the defaults and anything set earlier
+    pub fn app(mut self, app: Value) -> Self {
+        let Value::Object(app) = app else {
+            panic!("app config must be an object. got {}", app);
+        };
+
+        self.app.extend(app);
+
+        self
+    }
+
+    pub fn balanced_rpc(mut self, name: &str, rpc: Web3RpcConfig) -> Self {
+        self.balanced_rpcs.insert(name.to_string(), rpc);
+
+        self
+    }
+
+    /// `balanced_rpc` with only an http url
+    pub fn http_rpc(self, name: &str, http_url: String) -> Self {
+        self.balanced_rpc(name, http_rpc_config(http_url))
+    }
+
+    /// `balanced_rpc` with anvil's http and websocket
+    pub fn anvil_rpc(self, name: &str, anvil: &TestAnvil) -> Self {
+        self.balanced_rpc(name, anvil_rpc_config(anvil))
+    }
+
+    pub fn private_rpc(mut self, name: &str, rpc: Web3RpcConfig) -> Self {
+        self.private_rpcs.insert(name.to_string(), rpc);
+
+        self
+    }
+
+    pub fn build(self) -> TopConfig {
+        let app: AppConfig = serde_json::from_value(Value::Object(self.app)).unwrap();
+
+        TopConfig {
+            app,
+            balanced_rpcs: self.balanced_rpcs,
+            private_rpcs: self.private_rpcs,
+            bundler_4337_rpcs: Default::default(),
+            chains: Default::default(),
+            extra: Default::default(),
+        }
+    }
+}
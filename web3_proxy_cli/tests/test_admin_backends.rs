@@ -0,0 +1,129 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::hashbrown::HashMap;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// the names of the backends that served this request
+async fn backend_rpcs(r: &reqwest::Client, x: &TestApp) -> String {
+    let response = r
+        .post(x.proxy_provider.url().as_str())
+        // a random address so that nothing is served from the cache
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string())
+        .unwrap_or_default();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%body, %backend_rpcs);
+
+    assert_eq!(body["result"], "0x0", "{}", body);
+
+    backend_rpcs
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_disable_and_enable_backend() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.balanced_rpcs = ["anvil_a", "anvil_b"]
+        .into_iter()
+        .map(|name| {
+            (
+                name.to_string(),
+                Web3RpcConfig {
+                    http_url: Some(a.instance.endpoint()),
+                    ws_url: Some(a.instance.ws_endpoint()),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &a.wallet(1)).await;
+
+    let client = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(admin_login_response.bearer_token.to_string());
+
+    let backends = client.admin_backends().await.unwrap();
+    info!(?backends);
+
+    assert_eq!(
+        backends
+            .balanced
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>(),
+        ["anvil_a", "anvil_b"]
+    );
+    assert!(backends.balanced.iter().all(|x| !x.disabled));
+    assert!(backends.private.is_empty());
+
+    let disabled = client.admin_disable_backend("anvil_a").await.unwrap();
+    assert_eq!(disabled.group, "balanced");
+    assert!(disabled.disabled);
+    assert!(!disabled.previous);
+
+    for _ in 0..10 {
+        assert_eq!(backend_rpcs(&r, &x).await, "anvil_b");
+    }
+
+    let backends = client.admin_backends().await.unwrap();
+    assert!(backends.balanced[0].disabled);
+    assert!(!backends.balanced[1].disabled);
+
+    // the last enabled backend can't be disabled
+    let err = client.admin_disable_backend("anvil_b").await.unwrap_err();
+    assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+
+    let err = client.admin_disable_backend("unknown").await.unwrap_err();
+    assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+
+    // back in rotation. anvil_b can be disabled now
+    let enabled = client.admin_enable_backend("anvil_a").await.unwrap();
+    assert!(!enabled.disabled);
+    assert!(enabled.previous);
+
+    client.admin_disable_backend("anvil_b").await.unwrap();
+
+    for _ in 0..10 {
+        assert_eq!(backend_rpcs(&r, &x).await, "anvil_a");
+    }
+
+    client.admin_enable_backend("anvil_b").await.unwrap();
+
+    let backends = client.admin_backends().await.unwrap();
+    assert!(backends.balanced.iter().all(|x| !x.disabled));
+
+    let cleared = client.admin_clear_cache().await.unwrap();
+    info!(?cleared);
+    assert_eq!(cleared.backend, None);
+
+    // drop the app first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
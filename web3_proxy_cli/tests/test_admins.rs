@@ -1,9 +1,12 @@
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
 use web3_proxy::prelude::migration::sea_orm::prelude::Decimal;
 use web3_proxy::prelude::reqwest;
 use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::web3_proxy_client::types::LatencyClass;
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
 use web3_proxy::test_utils::mysql::TestMysql;
 use web3_proxy::test_utils::TestAnvil;
 use web3_proxy_cli::test_utils::admin_increases_balance::admin_increase_balance;
@@ -70,3 +73,91 @@ async fn test_admin_grant_credits() {
 async fn test_admin_change_user_tier() {
     todo!();
 }
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_admin_summary() {
+    let a: TestAnvil = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(0);
+    let admin_wallet = a.wallet(1);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+
+    // some traffic for the counters
+    for _ in 0..3 {
+        x.proxy_provider
+            .request::<_, U64>("eth_blockNumber", ())
+            .await
+            .unwrap();
+    }
+
+    let client = Web3ProxyClient::new(x.proxy_provider.url().clone()).with_http_client(r.clone());
+
+    // only admins can see the summary
+    let err = client
+        .clone()
+        .with_bearer_token(user_login_response.bearer_token.to_string())
+        .admin_summary()
+        .await
+        .unwrap_err();
+    assert!(err.status().unwrap().is_client_error(), "{:?}", err);
+
+    let client = client.with_bearer_token(admin_login_response.bearer_token.to_string());
+
+    let start = Instant::now();
+
+    let summary = client.admin_summary().await.unwrap();
+
+    let elapsed = start.elapsed();
+
+    info!(?elapsed, ?summary);
+
+    // the summary itself only reads memory. most of this is the bearer check and the local http round trip
+    assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+
+    let head_block_num = summary.head_block_num.unwrap();
+
+    let balanced = &summary.backends.balanced;
+    assert_eq!(balanced.len(), 1);
+    assert_eq!(balanced[0].name, "anvil");
+    assert_eq!(balanced[0].head_block_num, Some(head_block_num));
+    assert_eq!(balanced[0].lag, Some(0));
+
+    assert!(summary.synced);
+
+    let requests = &summary.requests;
+    assert!(requests.requests >= 3, "{:?}", requests);
+    assert_eq!(requests.top_methods[0].method, "eth_blockNumber");
+
+    // eth_blockNumber is answered from the head block. the anonymous tier's cached reads are well under the objective
+    let latency = &summary.latency;
+    assert_eq!(latency.percentile, 95);
+    let cached = latency
+        .rows
+        .iter()
+        .find(|x| x.class == LatencyClass::CachedRead && x.tier == "anonymous")
+        .unwrap();
+    assert!(cached.requests >= 3, "{:?}", cached);
+    assert_eq!(cached.objective_ms, Some(300));
+    assert!(!cached.breached);
+
+    // stats are collected because there is a database
+    assert!(summary.stat_buffer.is_some());
+
+    assert_eq!(summary.connectivity.db, Some(true));
+    assert_eq!(summary.connectivity.influx, None);
+    assert_eq!(summary.connectivity.redis, None);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+/// the names of the backends that served this request. empty if it came from the cache
+async fn backend_rpcs(r: &reqwest::Client, x: &TestApp, request: &Value) -> String {
+    let response = r
+        .post(x.proxy_provider.url().as_str())
+        .json(request)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%request, %body, %backend_rpcs);
+
+    assert!(body["result"].is_object(), "{}", body);
+
+    backend_rpcs
+}
+
+/// blocks past the reorg horizon stay cached when the head changes
+#[test_log::test(tokio::test)]
+async fn test_archive_cache() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // move the first block past the horizon
+    let _: Value = a.provider.request("anvil_mine", (100,)).await.unwrap();
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.archive_cache_confirmations = 64;
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let historical = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x1", false]});
+
+    assert_ne!(backend_rpcs(&r, &x, &historical).await, "");
+    assert_eq!(backend_rpcs(&r, &x, &historical).await, "");
+
+    // the proxy never saw block 1, so only its number is known. a new head doesn't change its key
+    let head_block: U64 = x
+        .proxy_provider
+        .request("eth_blockNumber", ())
+        .await
+        .unwrap();
+
+    let _: Value = a.provider.request("evm_mine", ()).await.unwrap();
+
+    while x
+        .proxy_provider
+        .request::<_, U64>("eth_blockNumber", ())
+        .await
+        .unwrap()
+        == head_block
+    {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_eq!(backend_rpcs(&r, &x, &historical).await, "");
+
+    x.wait_for_stop();
+}
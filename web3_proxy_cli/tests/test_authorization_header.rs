@@ -0,0 +1,43 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+/// Gateways in front of the public routes can add basic auth of their own. That is not an rpc key, so the request is
+/// public and nothing is held against the ip
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_public_request_with_basic_auth() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // more than enough invalid keys to cross the default ban threshold
+    for _ in 0..20 {
+        let response = r
+            .post(x.proxy_provider.url().clone())
+            .basic_auth("user", Some("hunter2"))
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.status().is_success(), "{:?}", response);
+
+        let response: Value = response.json().await.unwrap();
+
+        assert_eq!(response["result"], json!("0x7a69"), "{}", response);
+    }
+
+    assert_eq!(x.app.bans.total_violations.load(Ordering::Relaxed), 0);
+    assert_eq!(x.app.bans.num_active(), 0);
+
+    x.wait_for_stop();
+}
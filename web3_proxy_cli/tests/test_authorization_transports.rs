@@ -0,0 +1,161 @@
+use axum::extract::connect_info::MockConnectInfo;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Router};
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use web3_proxy::app::App;
+use web3_proxy::frontend::authorization::{Authorization, AuthorizationRequest};
+use web3_proxy::prelude::ethers::providers::{Provider, Ws};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::mock_backend::serve;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+type Seen = Arc<Mutex<Vec<Authorization>>>;
+
+async fn authorize(app: &App, seen: &Seen, request: AuthorizationRequest) {
+    let authorization = request.authorize(app).await.unwrap();
+
+    seen.lock().push(authorization);
+}
+
+async fn record_http(
+    State(app): State<Arc<App>>,
+    Extension(seen): Extension<Seen>,
+    request: AuthorizationRequest,
+) {
+    authorize(&app, &seen, request).await
+}
+
+async fn record_websocket(
+    State(app): State<Arc<App>>,
+    Extension(seen): Extension<Seen>,
+    request: AuthorizationRequest,
+    ws: WebSocketUpgrade,
+) -> Response {
+    authorize(&app, &seen, request).await;
+
+    ws.on_upgrade(|_| async {}).into_response()
+}
+
+/// The same key over http and over a websocket gets the same checks, tier, and limits
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_http_and_websocket_authorize_the_same() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+
+    let seen = Seen::default();
+
+    // the proxy's own routes don't hand out their Authorization, so authorize with the proxy's app on routes of our own
+    let router = Router::new()
+        .route("/rpc/:rpc_key", post(record_http).get(record_websocket))
+        .layer(Extension(seen.clone()))
+        .layer(MockConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4567))))
+        .with_state(x.app.clone());
+
+    let addr = serve(router);
+
+    let key = Ulid::from(rpc_key.secret_key);
+
+    let response = r
+        .post(format!("http://{}/rpc/{}", addr, key))
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success(), "{:?}", response);
+
+    let ws = Provider::<Ws>::connect(format!("ws://{}/rpc/{}", addr, key))
+        .await
+        .unwrap();
+
+    drop(ws);
+
+    let seen = seen.lock();
+
+    assert_eq!(seen.len(), 2);
+
+    let (http, websocket) = (&seen[0], &seen[1]);
+
+    // a real key, not the anonymous default
+    assert_eq!(http.checks.rpc_secret_key, Some(key.into()));
+    assert_eq!(
+        http.checks.rpc_secret_key_id.map(|x| x.get()),
+        Some(rpc_key.id)
+    );
+    assert_eq!(http.checks.user_id, rpc_key.user_id);
+    assert!(http.checks.user_tier_title.is_some());
+
+    assert_eq!(http.ip, websocket.ip);
+    assert_eq!(http.authorization_type, websocket.authorization_type);
+
+    // checks
+    assert_eq!(http.checks.user_id, websocket.checks.user_id);
+    assert_eq!(http.checks.rpc_secret_key, websocket.checks.rpc_secret_key);
+    assert_eq!(
+        http.checks.rpc_secret_key_id,
+        websocket.checks.rpc_secret_key_id
+    );
+    assert_eq!(http.checks.proxy_mode, websocket.checks.proxy_mode);
+    assert_eq!(http.checks.protocol, websocket.checks.protocol);
+    assert_eq!(http.checks.methods, websocket.checks.methods);
+    assert_eq!(http.checks.private_txs, websocket.checks.private_txs);
+    assert_eq!(
+        http.checks.paid_credits_used,
+        websocket.checks.paid_credits_used
+    );
+
+    // tier
+    assert_eq!(
+        http.checks.user_tier_title,
+        websocket.checks.user_tier_title
+    );
+    assert_eq!(http.checks.entitlements, websocket.checks.entitlements);
+
+    // limits
+    assert_eq!(
+        http.checks.max_requests_per_period,
+        websocket.checks.max_requests_per_period
+    );
+    assert_eq!(
+        http.checks.max_requests_per_day,
+        websocket.checks.max_requests_per_day
+    );
+    assert_eq!(
+        http.checks.max_requests_per_month,
+        websocket.checks.max_requests_per_month
+    );
+    assert_eq!(
+        http.checks.max_concurrent_requests,
+        websocket.checks.max_concurrent_requests
+    );
+    assert_eq!(
+        http.rate_limit.map(|x| x.limit),
+        websocket.rate_limit.map(|x| x.limit)
+    );
+
+    drop(seen);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,155 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::U256,
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::parking_lot::Mutex;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+#[derive(Clone, Copy)]
+enum Failure {
+    Http500,
+    InternalError,
+}
+
+#[derive(Default)]
+struct Vendor {
+    /// the next request for this method fails. only once, no matter which backend gets it
+    fail_next: Mutex<Option<(&'static str, Failure)>>,
+    /// every request for the method that is set to fail
+    attempts: Mutex<usize>,
+}
+
+async fn flaky_vendor(vendor: Arc<Vendor>, request: MockRequest) -> Response {
+    let failure = {
+        let mut fail_next = vendor.fail_next.lock();
+
+        match *fail_next {
+            Some((method, failure)) if request.method() == method => {
+                *vendor.attempts.lock() += 1;
+
+                fail_next.take();
+
+                Some(failure)
+            }
+            _ => None,
+        }
+    };
+
+    match failure {
+        Some(Failure::Http500) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "oops").into_response();
+        }
+        Some(Failure::InternalError) => {
+            return request.error(json!({"code": -32603, "message": "internal error"}));
+        }
+        None => {}
+    }
+
+    if request.method() == "eth_getBalance" || request.method() == "eth_sendRawTransaction" {
+        *vendor.attempts.lock() += 1;
+    }
+
+    request.forward().await
+}
+
+/// two backends that share one `Vendor`. whichever is tried first fails
+async fn spawn_app(a: &TestAnvil) -> (Arc<Vendor>, TestApp) {
+    let vendor = Arc::new(Vendor::default());
+
+    let top_config = TopConfigBuilder::new(31337)
+        .http_rpc(
+            "flaky_a",
+            spawn_mock_backend(a, vendor.clone(), flaky_vendor),
+        )
+        .http_rpc(
+            "flaky_b",
+            spawn_mock_backend(a, vendor.clone(), flaky_vendor),
+        )
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    (vendor, x)
+}
+
+async fn post(x: &TestApp, method: &str, params: Value) -> Value {
+    let body: Value = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    info!(%body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_transient_errors_are_retried() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (vendor, x) = spawn_app(&a).await;
+
+    for failure in [Failure::Http500, Failure::InternalError] {
+        *vendor.attempts.lock() = 0;
+        *vendor.fail_next.lock() = Some(("eth_getBalance", failure));
+
+        // a random address so that nothing is served from the cache
+        let body = post(&x, "eth_getBalance", json!([Address::random(), "latest"])).await;
+
+        // the client never sees the first backend's error
+        assert_eq!(body["result"], "0x0", "{}", body);
+
+        assert_eq!(*vendor.attempts.lock(), 2);
+    }
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_side_effects_are_not_retried() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (vendor, x) = spawn_app(&a).await;
+
+    let wallet = a.wallet(0);
+
+    let gas_price: U256 = x.proxy_provider.request("eth_gasPrice", ()).await.unwrap();
+
+    let tx = TypedTransaction::Eip1559(Eip1559TransactionRequest {
+        chain_id: Some(31337.into()),
+        to: Some(Address::repeat_byte(0x42).into()),
+        gas: Some(21000.into()),
+        value: Some(1.into()),
+        max_fee_per_gas: Some(gas_price * U256::from(2)),
+        nonce: Some(0.into()),
+        ..Default::default()
+    });
+
+    let sig = wallet.sign_transaction_sync(&tx).unwrap();
+
+    *vendor.fail_next.lock() = Some(("eth_sendRawTransaction", Failure::Http500));
+
+    let body = post(&x, "eth_sendRawTransaction", json!([tx.rlp_signed(&sig)])).await;
+
+    // the backend might have sent it before it failed. sending it somewhere else could send it twice
+    assert!(body["error"].is_object(), "{}", body);
+    assert_eq!(*vendor.attempts.lock(), 1);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// the block numbers that each response in the batch is about, and the backends that answered them
+async fn batch(r: &reqwest::Client, x: &TestApp, unpinned: bool) -> (Vec<U64>, String) {
+    let mut request = r.post(x.proxy_provider.url().clone()).json(&json!([
+        {"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"},
+        {"jsonrpc": "2.0", "id": 2, "method": "eth_getBlockByNumber", "params": ["latest", false]},
+        {"jsonrpc": "2.0", "id": 3, "method": "eth_getBlockTransactionCountByNumber", "params": ["latest"]},
+        {"jsonrpc": "2.0", "id": 4, "method": "eth_getBlockByNumber", "params": ["latest", false]},
+    ]));
+
+    if unpinned {
+        request = request.header("x-web3-proxy-unpinned-batch", "true");
+    }
+
+    let response = request.send().await.unwrap();
+
+    let backend_rpcs = response.headers()["X-W3P-BACKEND-RPCS"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Vec<Value> = response.json().await.unwrap();
+    info!(?body, %backend_rpcs);
+
+    assert_eq!(body.len(), 4);
+
+    let block_number = serde_json::from_value(body[0]["result"].clone()).unwrap();
+    let first_block = serde_json::from_value(body[1]["result"]["number"].clone()).unwrap();
+    let second_block = serde_json::from_value(body[3]["result"]["number"].clone()).unwrap();
+    assert!(body[2]["result"].is_string(), "{:?}", body[2]);
+
+    (vec![block_number, first_block, second_block], backend_rpcs)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_batch_pinned_to_one_head() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // two backends for the same chain so that a batch has somewhere else to go
+    let top_config = TopConfigBuilder::new(31337)
+        .anvil_rpc("anvil_a", &a)
+        .anvil_rpc("anvil_b", &a)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    // keep the head moving while the batches are in flight
+    let mining = Arc::new(AtomicBool::new(true));
+
+    let miner = {
+        let mining = mining.clone();
+        let provider = a.provider.clone();
+
+        tokio::spawn(async move {
+            while mining.load(Ordering::SeqCst) {
+                provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+    };
+
+    let mut seen_heads = vec![];
+
+    for _ in 0..50 {
+        let (block_numbers, backend_rpcs) = batch(&r, &x, false).await;
+
+        assert!(
+            block_numbers.iter().all(|x| *x == block_numbers[0]),
+            "batch saw more than one head: {:?}",
+            block_numbers
+        );
+
+        // every request that wasn't answered from the cache went to the same backend
+        assert!(!backend_rpcs.contains(','), "{}", backend_rpcs);
+
+        seen_heads.push(block_numbers[0]);
+    }
+
+    mining.store(false, Ordering::SeqCst);
+    miner.await.unwrap();
+
+    // otherwise nothing raced
+    seen_heads.dedup();
+    assert!(seen_heads.len() > 1, "the head never moved");
+
+    // opting out still answers from one head, but the backends can differ
+    let (block_numbers, _) = batch(&r, &x, true).await;
+    assert!(block_numbers.iter().all(|x| *x == block_numbers[0]));
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,192 @@
+use axum::response::Response;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::{
+    prelude::{Address, Bytes, H256, U256, U64},
+    providers::{Middleware, Provider, Ws},
+    utils::{keccak256, rlp::RlpStream},
+};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{self, time::timeout};
+use web3_proxy::raw_transaction::{
+    BLOB_TX_TYPE, BYTES_PER_BLOB, BYTES_PER_COMMITMENT, VERSIONED_HASH_VERSION_KZG,
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// Accepts any raw transaction and answers with the hash a node would give it
+struct BlobBackend {
+    hash: H256,
+    /// length of the last raw transaction that was sent here
+    received: AtomicUsize,
+}
+
+async fn accept_raw_transactions(state: Arc<BlobBackend>, request: MockRequest) -> Response {
+    if request.method() != "eth_sendRawTransaction" {
+        return request.forward().await;
+    }
+
+    let raw = request.body["params"][0].as_str().unwrap_or_default();
+
+    state.received.store(raw.len(), Ordering::SeqCst);
+
+    request.result(json!(state.hash))
+}
+
+/// rlp of a well formed (but not validly signed) blob transaction without the type byte
+fn blob_tx_body(chain_id: u64, num_blobs: usize) -> Vec<u8> {
+    let mut s = RlpStream::new_list(14);
+
+    s.append(&U64::from(chain_id)); // chain_id
+    s.append(&U256::zero()); // nonce
+    s.append(&U256::from(1_000_000_000u64)); // max_priority_fee_per_gas
+    s.append(&U256::from(30_000_000_000u64)); // max_fee_per_gas
+    s.append(&U256::from(21_000)); // gas_limit
+    s.append(&Address::repeat_byte(0x42)); // to
+    s.append(&U256::zero()); // value
+    s.append_empty_data(); // data
+    s.begin_list(0); // access_list
+    s.append(&U256::from(1_000_000_000u64)); // max_fee_per_blob_gas
+
+    let mut versioned_hash = H256::repeat_byte(0x11);
+    versioned_hash.0[0] = VERSIONED_HASH_VERSION_KZG;
+    s.append_list::<H256, H256>(&vec![versioned_hash; num_blobs]);
+
+    s.append(&0u8); // y_parity
+    s.append(&U256::from(1)); // r
+    s.append(&U256::from(2)); // s
+
+    s.out().to_vec()
+}
+
+/// the transaction wrapped with its blobs, commitments, and proofs. this is what eth_sendRawTransaction takes
+fn network_form(body: &[u8], num_blobs: usize) -> Vec<u8> {
+    let mut s = RlpStream::new_list(4);
+
+    s.append_raw(body, 1);
+
+    s.begin_list(num_blobs);
+    for _ in 0..num_blobs {
+        s.append(&vec![0u8; BYTES_PER_BLOB]);
+    }
+
+    for _ in 0..2 {
+        s.begin_list(num_blobs);
+        for _ in 0..num_blobs {
+            s.append(&vec![0u8; BYTES_PER_COMMITMENT]);
+        }
+    }
+
+    let mut out = vec![BLOB_TX_TYPE];
+    out.extend_from_slice(&s.out());
+    out
+}
+
+/// A type-3 transaction is forwarded with its blobs and only its hash goes to pending transaction subscribers
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_blob_transaction() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let body = blob_tx_body(31337, 1);
+
+    let mut canonical = vec![BLOB_TX_TYPE];
+    canonical.extend_from_slice(&body);
+
+    let network = network_form(&body, 1);
+
+    let tx_hash = H256::from(keccak256(&canonical));
+
+    let state = Arc::new(BlobBackend {
+        hash: tx_hash,
+        received: AtomicUsize::new(0),
+    });
+
+    let url = spawn_mock_backend(&a, state.clone(), accept_raw_transactions);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "free_subscriptions": true,
+        }))
+        .balanced_rpc(
+            "blobs",
+            Web3RpcConfig {
+                http_url: Some(url),
+                ..Default::default()
+            },
+        )
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let mut pending = ws.subscribe_pending_txs().await.unwrap();
+
+    let response: H256 = x
+        .proxy_provider
+        .request("eth_sendRawTransaction", [Bytes::from(network.clone())])
+        .await
+        .unwrap();
+
+    assert_eq!(response, tx_hash);
+
+    // the backend got the blobs. "0x" and two hex characters per byte
+    assert_eq!(state.received.load(Ordering::SeqCst), 2 + 2 * network.len());
+
+    // subscribers only get the hash
+    let notified = timeout(Duration::from_secs(10), pending.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(notified, tx_hash);
+
+    // without the blobs, no node would accept it
+    state.received.store(0, Ordering::SeqCst);
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let response = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendRawTransaction",
+            "params": [Bytes::from(canonical)],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let response: Value = response.json().await.unwrap();
+
+    assert!(
+        response["error"]["data"]["err"]
+            .as_str()
+            .unwrap()
+            .contains("blobs"),
+        "{}",
+        response
+    );
+
+    assert_eq!(state.received.load(Ordering::SeqCst), 0);
+
+    // and nothing more was announced
+    assert!(timeout(Duration::from_millis(500), pending.next())
+        .await
+        .is_err());
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::signers::Signer;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Instant},
+};
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql, TestRedis};
+
+/// the names of the backends that served this request. empty if it came from the cache
+async fn backend_rpcs(r: &reqwest::Client, x: &TestApp, request: &Value) -> String {
+    let response = r
+        .post(x.proxy_provider.url().as_str())
+        .json(request)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%request, %body, %backend_rpcs);
+
+    backend_rpcs
+}
+
+/// clearing the response cache on one server clears it on the others
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_cache_invalidation() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let redis = TestRedis::spawn().await;
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.volatile_redis_url = Some(redis.url.clone());
+
+    let x = TestApp::spawn_with_top_config(top_config.clone()).await;
+    let y = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let admin_wallet = a.wallet(1);
+
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [a.wallet(0).address(), "latest"]});
+
+    // fill y's cache
+    assert_ne!(backend_rpcs(&r, &y, &request).await, "");
+    assert_eq!(backend_rpcs(&r, &y, &request).await, "");
+
+    // clear the cache on x
+    let cleared = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(admin_login_response.bearer_token.to_string())
+        .admin_delete_response_cache(&Default::default())
+        .await
+        .unwrap();
+    info!(?cleared);
+
+    // y hears about it over redis
+    let start = Instant::now();
+    loop {
+        if backend_rpcs(&r, &y, &request).await != "" {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("y never invalidated its cache");
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // drop the apps first to avoid spurious warnings about mysql and redis shutting down before the apps
+    drop(x);
+    drop(y);
+}
@@ -0,0 +1,73 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::canary::CanaryConfig;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp};
+
+/// Forwards to anvil, but thinks it is on a different chain
+async fn wrong_chain_reference(_: Arc<()>, request: MockRequest) -> Response {
+    if request.method() == "eth_chainId" {
+        return request.result(json!("0x1"));
+    }
+
+    request.forward().await
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_canary_finds_mismatches() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let reference_url = spawn_mock_backend(&a, Arc::new(()), wrong_chain_reference);
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.canary = CanaryConfig {
+        reference_url: Some(reference_url),
+        interval_secs: 1,
+        ..Default::default()
+    };
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let status_url = format!("{}status", x.proxy_provider.url());
+
+    let mut canary = Value::Null;
+
+    for _ in 0..30 {
+        let status: Value = reqwest::get(&status_url)
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        canary = status["canary"].clone();
+
+        if canary["counts"]["runs"].as_u64().unwrap_or_default() >= 2 {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    info!(%canary);
+
+    assert!(canary["counts"]["runs"].as_u64().unwrap() >= 2);
+    assert_eq!(canary["counts"]["errors"], 0);
+
+    // the block numbers are close enough. only the chain id is wrong
+    let mismatches = canary["counts"]["mismatches"].as_u64().unwrap();
+    assert!(mismatches >= 1);
+    assert!(mismatches <= canary["counts"]["runs"].as_u64().unwrap() + 1);
+
+    assert_eq!(canary["last_mismatch"]["method"], "eth_chainId");
+    assert_eq!(canary["last_mismatch"]["proxy"], r#""0x7a69""#);
+    assert_eq!(canary["last_mismatch"]["reference"], r#""0x1""#);
+
+    x.wait_for_stop();
+}
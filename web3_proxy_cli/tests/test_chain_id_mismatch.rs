@@ -0,0 +1,111 @@
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+async fn send_raw(r: &reqwest::Client, rpc_url: &str, raw: &Value) -> Value {
+    let body: Value = r
+        .post(rpc_url)
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendRawTransaction",
+            "params": [raw],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_chain_id_mismatch() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+
+    assert!(!rpc_key.skip_chain_id_check);
+
+    let rpc_url = format!(
+        "{}rpc/{}",
+        x.proxy_provider.url(),
+        Ulid::from(rpc_key.secret_key)
+    );
+
+    // signed for mainnet, sent to a proxy for chain 31337
+    let tx: TypedTransaction = Eip1559TransactionRequest::new()
+        .to(Address::repeat_byte(0x42))
+        .nonce(0)
+        .gas(21_000)
+        .max_fee_per_gas(30_000_000_000u64)
+        .chain_id(1)
+        .into();
+
+    let signature = user_wallet
+        .clone()
+        .with_chain_id(1u64)
+        .sign_transaction_sync(&tx)
+        .unwrap();
+
+    let raw = json!(tx.rlp_signed(&signature));
+
+    let rejected = send_raw(&r, &rpc_url, &raw).await;
+    assert_eq!(rejected["error"]["code"], json!(-32602));
+    assert_eq!(rejected["error"]["data"]["expected"], json!(31337));
+    assert_eq!(rejected["error"]["data"]["found"], json!("0x1"));
+
+    let message = rejected["error"]["message"].as_str().unwrap();
+    assert!(message.contains("31337"), "{}", message);
+    assert!(message.contains(" 1"), "{}", message);
+
+    // cross-chain tooling can opt out
+    let updated: Value = r
+        .put(format!("{}user/keys", x.proxy_provider.url()))
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "skip_chain_id_check": true,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?updated);
+
+    assert_eq!(updated["skip_chain_id_check"], json!(true));
+
+    // the proxy forwards it. anvil is the one that rejects it now
+    let forwarded = send_raw(&r, &rpc_url, &raw).await;
+    assert!(forwarded.get("error").is_some());
+    assert!(forwarded["error"]["data"].get("expected").is_none());
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+async fn block_number(
+    r: &reqwest::Client,
+    x: &TestApp,
+    origin: Option<&str>,
+) -> (StatusCode, Value) {
+    let mut request = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}));
+
+    if let Some(origin) = origin {
+        request = request.header("Origin", origin);
+    }
+
+    let response = request.send().await.unwrap();
+
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+
+    info!(?origin, %status, %body);
+
+    (status, body)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_cors_allowed_origins() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.cors.allowed_origins = vec![
+        "https://app.example.com".to_string(),
+        "https://*.example.org".to_string(),
+    ];
+    top_config.app.cors.max_age_secs = 600;
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // preflights from allowed origins get the origin echoed back
+    for origin in ["https://app.example.com", "https://docs.example.org"] {
+        let preflight = r
+            .request(reqwest::Method::OPTIONS, x.proxy_provider.url().clone())
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "content-type")
+            .send()
+            .await
+            .unwrap();
+
+        let headers = preflight.headers();
+        info!(?headers);
+
+        assert_eq!(headers["access-control-allow-origin"], origin);
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(headers["access-control-max-age"], "600");
+    }
+
+    // preflights from other origins don't get an allow header
+    let preflight = r
+        .request(reqwest::Method::OPTIONS, x.proxy_provider.url().clone())
+        .header("Origin", "https://evil.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .unwrap();
+    assert!(preflight
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    let (status, body) = block_number(&r, &x, Some("https://app.example.com")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["result"].is_string(), "{}", body);
+
+    // a spoofed origin doesn't get past the server side check
+    let (status, body) = block_number(&r, &x, Some("https://evil.example.com")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"]["message"], "Origin is not allowed!");
+    assert_eq!(body["error"]["data"], "https://evil.example.com");
+
+    // requests without an origin aren't from browsers
+    let (status, body) = block_number(&r, &x, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["result"].is_string(), "{}", body);
+
+    x.wait_for_stop();
+}
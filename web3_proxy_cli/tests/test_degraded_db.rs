@@ -0,0 +1,145 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::degraded::DegradedAuth;
+use web3_proxy::prelude::entities::{rpc_key, user};
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Instant},
+};
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy::secrets::RpcSecretKey;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+async fn status(r: &reqwest::Client, x: &TestApp) -> Value {
+    r.get(format!("{}status", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+/// the status code and body of an eth_blockNumber sent with the key
+async fn keyed_request(r: &reqwest::Client, x: &TestApp, key: &Ulid) -> (StatusCode, Value) {
+    let response = r
+        .post(format!("{}rpc/{}", x.proxy_provider.url(), key))
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+    info!(%status, %body);
+
+    (status, body)
+}
+
+/// the app starts without its database and recovers when the database comes back
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_degraded_db() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    // make the user and key directly. the app can't make them without its database
+    let rpc_secret_key = RpcSecretKey::new();
+    {
+        let db_conn = db.conn().await;
+
+        let u = user::ActiveModel {
+            address: sea_orm::Set(a.wallet(0).address().to_fixed_bytes().into()),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+
+        rpc_key::ActiveModel {
+            user_id: u.id,
+            secret_key: sea_orm::Set(rpc_secret_key.into()),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+    }
+    let key = Ulid::from(rpc_secret_key);
+
+    // the port is the same when the db is started again
+    db.kill().await;
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.db_reconnect_secs = 1;
+    top_config.app.degraded_auth = DegradedAuth::Reject;
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // anonymous requests don't need the database
+    let anon_block: U64 = x
+        .proxy_provider
+        .request("eth_blockNumber", ())
+        .await
+        .unwrap();
+    info!(?anon_block);
+
+    let health = r
+        .get(format!("{}health", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(health.status(), StatusCode::OK);
+    assert_eq!(health.text().await.unwrap(), "OK\ndatabase down\n");
+
+    let degraded = &status(&r, &x).await["degraded"];
+    info!(%degraded);
+    assert_eq!(degraded["db_down"], 1);
+    assert_eq!(degraded["policy"], "reject");
+
+    // keys can't be checked
+    let (code, body) = keyed_request(&r, &x, &key).await;
+    assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["error"]["message"], "auth unavailable");
+
+    db.start().await;
+
+    // the reconnect loop notices without a restart
+    let start = Instant::now();
+    loop {
+        let (code, body) = keyed_request(&r, &x, &key).await;
+
+        if code == StatusCode::OK {
+            assert!(body["result"].is_string(), "{}", body);
+            break;
+        }
+
+        assert_eq!(body["error"]["message"], "auth unavailable");
+
+        if start.elapsed() > Duration::from_secs(30) {
+            panic!("keyed auth never recovered");
+        }
+
+        sleep(Duration::from_millis(500)).await;
+    }
+
+    let degraded = &status(&r, &x).await["degraded"];
+    info!(%degraded);
+    assert_eq!(degraded["db_down"], 0);
+    assert_eq!(degraded["reconnects"], 1);
+    assert!(degraded["rejected_keys"].as_u64().unwrap() > 0);
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::{Block, TxHash, H256};
+use web3_proxy::prelude::ethers::signers::Signer;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+/// returns the backends that answered (empty for a cache hit) and the result
+async fn send(r: &reqwest::Client, x: &TestApp, request: &Value) -> (String, Value) {
+    let response = r
+        .post(x.proxy_provider.url().clone())
+        .json(request)
+        .send()
+        .await
+        .unwrap();
+
+    let rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string())
+        .unwrap_or_default();
+
+    let body: Value = response.json().await.unwrap();
+
+    (rpcs, body["result"].clone())
+}
+
+async fn get_block(r: &reqwest::Client, x: &TestApp, hash: &str) -> (String, Value) {
+    send(
+        r,
+        x,
+        &json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByHash", "params": [hash, false]}),
+    )
+    .await
+}
+
+async fn disk_cache_status(r: &reqwest::Client, x: &TestApp) -> Value {
+    let status: Value = r
+        .get(format!("{}status", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    status["disk_cache"].clone()
+}
+
+/// the write happens in the background
+async fn wait_for_writes(r: &reqwest::Client, x: &TestApp, writes: u64) {
+    for _ in 0..50 {
+        if disk_cache_status(r, x).await["writes"] == json!(writes) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("the disk cache never wrote {} responses", writes);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_disk_cache_survives_restart() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let dir = env::temp_dir().join(format!("web3_proxy_disk_cache_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let genesis: Block<TxHash> = a
+        .provider
+        .request("eth_getBlockByNumber", ("0x0", false))
+        .await
+        .unwrap();
+    let hash = format!("{:?}", genesis.hash.unwrap());
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.disk_cache_path = Some(dir.clone());
+
+    let x = TestApp::spawn_with_top_config(top_config.clone()).await;
+
+    let (rpcs, first) = get_block(&r, &x, &hash).await;
+    assert!(!rpcs.is_empty());
+    assert_eq!(first["hash"], json!(hash));
+
+    wait_for_writes(&r, &x, 1).await;
+
+    x.wait_for_stop();
+
+    // a new proxy starts with an empty response cache, but the disk still has the block
+    let x = TestApp::spawn_with_top_config(top_config.clone()).await;
+
+    let (rpcs, second) = get_block(&r, &x, &hash).await;
+    info!(%rpcs, %second);
+
+    assert_eq!(rpcs, "");
+    assert_eq!(first, second);
+
+    let status = disk_cache_status(&r, &x).await;
+    info!(%status);
+    assert_eq!(status["hits"], json!(1));
+    assert_eq!(status["responses"], json!(1));
+
+    x.wait_for_stop();
+
+    // another chain must not use these responses
+    top_config.app.chain_id = 1;
+    assert!(web3_proxy::disk_cache::DiskCache::spawn(
+        &top_config.app,
+        tokio::sync::broadcast::channel(1).1
+    )
+    .is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// transactions, receipts, and code at a fixed block are saved once they are past the reorg horizon
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_disk_cache_confirmed_transactions() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let dir = env::temp_dir().join(format!("web3_proxy_disk_cache_txs_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // anvil mines it right away
+    let tx_hash: H256 = a
+        .provider
+        .request(
+            "eth_sendTransaction",
+            [json!({"from": a.wallet(0).address(), "to": a.wallet(1).address(), "value": "0x1"})],
+        )
+        .await
+        .unwrap();
+
+    // move the transaction's block past the horizon
+    let _: Value = a.provider.request("anvil_mine", (100,)).await.unwrap();
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.archive_cache_confirmations = 64;
+    top_config.app.disk_cache_path = Some(dir.clone());
+
+    let requests = [
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionByHash", "params": [tx_hash]}),
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionReceipt", "params": [tx_hash]}),
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getCode", "params": [a.wallet(0).address(), "0x1"]}),
+    ];
+
+    let x = TestApp::spawn_with_top_config(top_config.clone()).await;
+
+    let mut first = vec![];
+    for request in requests.iter() {
+        let (rpcs, result) = send(&r, &x, request).await;
+        info!(%request, %rpcs, %result);
+
+        assert!(!rpcs.is_empty());
+        assert!(!result.is_null(), "{}", request);
+
+        first.push(result);
+    }
+
+    wait_for_writes(&r, &x, requests.len() as u64).await;
+
+    x.wait_for_stop();
+
+    // none of them go to a backend after a restart
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    for (request, first) in requests.iter().zip(first) {
+        let (rpcs, result) = send(&r, &x, request).await;
+
+        assert_eq!(rpcs, "", "{}", request);
+        assert_eq!(result, first);
+    }
+
+    let status = disk_cache_status(&r, &x).await;
+    info!(%status);
+    assert_eq!(status["hits"], json!(requests.len()));
+
+    x.wait_for_stop();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
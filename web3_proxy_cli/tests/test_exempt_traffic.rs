@@ -0,0 +1,130 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::RateLimitStoreKind;
+use web3_proxy::exempt_traffic::ExemptTrafficConfig;
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestInflux, TestMysql};
+
+const PUBLIC_REQUESTS_PER_PERIOD: u64 = 3;
+
+/// an uptime bot can check far more often than the public rate limit allows, and none of its checks are saved as stats
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_exempt_traffic() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+    let i = TestInflux::spawn().await;
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), Some(&i), None);
+    top_config.app.public_requests_per_period = Some(PUBLIC_REQUESTS_PER_PERIOD);
+    top_config.app.rate_limit_store = RateLimitStoreKind::Memory;
+    top_config.app.exempt_traffic = ExemptTrafficConfig {
+        user_agent_prefixes: vec!["test-uptime-bot".to_string()],
+        ..Default::default()
+    };
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let bot = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .user_agent("test-uptime-bot/1.0")
+        .build()
+        .unwrap();
+
+    let admin_wallet = a.wallet(0);
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+
+    // start from empty stats
+    x.flush_stats_and_wait().await.unwrap();
+
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+
+    let num_checks = PUBLIC_REQUESTS_PER_PERIOD * 4;
+
+    for _ in 0..num_checks {
+        let response = bot
+            .post(x.proxy_provider.url().as_str())
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Value = response.json().await.unwrap();
+        assert!(body["result"].is_string(), "{}", body);
+    }
+
+    // methods that need a backend are refused instead of being proxied for free
+    let body: Value = bot
+        .post(x.proxy_provider.url().as_str())
+        .json(&json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "latest"]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(%body);
+    assert_eq!(body["error"]["code"], -32601);
+
+    // load balancers don't need a user agent for HEAD
+    let response = r
+        .head(x.proxy_provider.url().as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+    assert_eq!(flushed.timeseries_frontend_requests, 0);
+    assert_eq!(flushed.relational_frontend_requests, 0);
+
+    // everyone else is still limited. the limit is per ip, and the bot was on the same ip
+    let mut statuses = vec![];
+    for _ in 0..num_checks {
+        let response = r
+            .post(x.proxy_provider.url().as_str())
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+
+        statuses.push(response.status());
+    }
+    info!(?statuses);
+
+    assert_eq!(statuses[0], StatusCode::OK);
+    assert!(statuses.contains(&StatusCode::TOO_MANY_REQUESTS));
+
+    // and they show up in the stats
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+    assert!(flushed.timeseries_frontend_requests > 0);
+
+    let summary = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(admin_login_response.bearer_token.to_string())
+        .admin_summary()
+        .await
+        .unwrap();
+    info!(exempt_traffic=?summary.exempt_traffic);
+
+    assert_eq!(summary.exempt_traffic.user_agent, num_checks + 1);
+    assert_eq!(summary.exempt_traffic.head_or_options, 1);
+    assert_eq!(summary.exempt_traffic.cidr, 0);
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
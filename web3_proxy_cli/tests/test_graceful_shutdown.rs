@@ -0,0 +1,77 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::{Address, Middleware, Provider, Ws};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, timeout},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// eth_getBalance takes a while. everything else is passed straight through
+async fn slow_vendor(_: Arc<()>, request: MockRequest) -> Response {
+    if request.method() == "eth_getBalance" {
+        sleep(Duration::from_secs(2)).await;
+    }
+
+    request.forward().await
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_in_flight_requests_finish_on_shutdown() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let vendor_url = spawn_mock_backend(&a, Arc::new(()), slow_vendor);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({"shutdown_grace_secs": 10}))
+        .http_rpc("slow_vendor", vendor_url)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+    let mut heads = ws.subscribe_blocks().await.unwrap();
+
+    let slow_request = {
+        let url = x.proxy_provider.url().clone();
+
+        tokio::spawn(async move {
+            reqwest::Client::new()
+                .post(url)
+                .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+                .send()
+                .await
+                .unwrap()
+                .json::<Value>()
+                .await
+                .unwrap()
+        })
+    };
+
+    // let the request reach the backend
+    sleep(Duration::from_millis(500)).await;
+
+    x.stop().unwrap();
+
+    // websockets are told to close right away
+    let closed = timeout(Duration::from_secs(5), async {
+        while heads.next().await.is_some() {}
+    })
+    .await;
+    assert!(closed.is_ok(), "the websocket was not closed");
+
+    // the slow request still gets its answer
+    let body = slow_request.await.unwrap();
+    info!(%body);
+    assert!(body["result"].is_string(), "{}", body);
+
+    x.wait_for_stop();
+}
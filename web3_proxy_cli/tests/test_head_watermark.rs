@@ -0,0 +1,123 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+async fn mine(anvil: &TestAnvil, blocks: u64) {
+    for _ in 0..blocks {
+        anvil
+            .provider
+            .request::<_, U64>("evm_mine", ())
+            .await
+            .unwrap();
+    }
+}
+
+/// eth_blockNumber and the X-W3P-OLDER-HEAD header
+async fn block_number(r: &reqwest::Client, x: &TestApp) -> (U64, Option<String>) {
+    let response = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber"}))
+        .send()
+        .await
+        .unwrap();
+
+    let older_head = response
+        .headers()
+        .get("X-W3P-OLDER-HEAD")
+        .map(|x| x.to_str().unwrap().to_string());
+
+    let body: Value = response.json().await.unwrap();
+
+    let num = serde_json::from_value(body["result"].clone()).unwrap();
+
+    (num, older_head)
+}
+
+/// wait for the consensus head without being a client that the watermark applies to
+async fn wait_for_consensus_head(r: &reqwest::Client, x: &TestApp, num: u64) {
+    for _ in 0..300 {
+        let status: Value = r
+            .get(format!("{}status", x.proxy_provider.url()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        if status["head_block_num"] == json!(U64::from(num)) {
+            return;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("consensus head never became {}", num);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_heads_never_go_backwards() {
+    let ahead = TestAnvil::spawn(31337).await;
+    let behind = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({"head_watermark_wait_ms": 2_000}))
+        .anvil_rpc("ahead", &ahead)
+        .anvil_rpc("behind", &behind)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::new();
+
+    mine(&behind, 2).await;
+    mine(&ahead, 5).await;
+
+    wait_for_consensus_head(&r, &x, 5).await;
+
+    let (seen, older_head) = block_number(&r, &x).await;
+    assert_eq!(seen, 5.into());
+    assert_eq!(older_head, None);
+
+    // the backend that was ahead goes away. the consensus head drops to 2
+    drop(ahead);
+
+    wait_for_consensus_head(&r, &x, 2).await;
+
+    // nothing can catch up in time. the older head is used, but the response says so
+    let (num, older_head) = block_number(&r, &x).await;
+    info!(%num, ?older_head);
+    assert_eq!(num, 2.into());
+    assert_eq!(older_head.as_deref(), Some("2/5"));
+
+    // the remaining backend catches up while the request waits
+    let mining = tokio::spawn(async move {
+        sleep(Duration::from_millis(200)).await;
+        mine(&behind, 4).await;
+        behind
+    });
+
+    let (num, older_head) = block_number(&r, &x).await;
+    info!(%num, ?older_head);
+    assert!(num >= 5.into(), "{}", num);
+    assert_eq!(older_head, None);
+
+    let _behind = mining.await.unwrap();
+
+    // and from now on, heads only go up
+    let mut last = num;
+    for _ in 0..10 {
+        let (num, older_head) = block_number(&r, &x).await;
+        assert!(num >= last, "{} < {}", num, last);
+        assert_eq!(older_head, None);
+        last = num;
+    }
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,154 @@
+use tracing::info;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+async fn send(r: &reqwest::Client, rpc_url: &str, request: Value) -> Value {
+    let body: Value = r
+        .post(rpc_url)
+        .json(&request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_key_method_lists() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+
+    // new keys can call anything
+    assert_eq!(rpc_key.allowed_methods, None);
+    assert_eq!(rpc_key.denied_methods, None);
+
+    let rpc_url = format!(
+        "{}rpc/{}",
+        x.proxy_provider.url(),
+        Ulid::from(rpc_key.secret_key)
+    );
+
+    let keys_url = format!("{}user/keys", x.proxy_provider.url());
+
+    // patterns are checked before they are saved
+    let bad = r
+        .put(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "denied_methods": ["*_traceTransaction"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+
+    r.put(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "denied_methods": ["eth_getLogs", "debug_*"],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+    assert_eq!(
+        rpc_key.denied_methods,
+        Some(vec!["eth_getLogs".to_string(), "debug_*".to_string()])
+    );
+
+    let single = send(
+        &r,
+        &rpc_url,
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{}]}),
+    )
+    .await;
+    assert_eq!(single["error"]["code"], json!(-32601));
+    assert_eq!(single["error"]["data"]["method"], json!("eth_getLogs"));
+
+    // only the blocked entries of a batch get errors
+    let batch = send(
+        &r,
+        &rpc_url,
+        json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "debug_traceTransaction", "params": ["0x0000000000000000000000000000000000000000000000000000000000000000"]},
+            {"jsonrpc": "2.0", "id": 3, "method": "eth_getLogs", "params": [{}]},
+        ]),
+    )
+    .await;
+
+    let batch = batch.as_array().unwrap();
+    assert_eq!(batch.len(), 3);
+
+    let by_id = |id: u64| batch.iter().find(|x| x["id"] == json!(id)).unwrap();
+
+    assert_eq!(by_id(1)["result"], json!("0x7a69"));
+    assert_eq!(by_id(2)["error"]["code"], json!(-32601));
+    assert!(by_id(2)["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("debug_traceTransaction"));
+    assert_eq!(by_id(3)["error"]["code"], json!(-32601));
+
+    // an allowlist blocks everything else
+    r.put(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "allowed_methods": ["eth_chainId", "net_*"],
+            "denied_methods": [],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let batch = send(
+        &r,
+        &rpc_url,
+        json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "net_version", "params": []},
+            {"jsonrpc": "2.0", "id": 3, "method": "eth_blockNumber", "params": []},
+        ]),
+    )
+    .await;
+
+    let batch = batch.as_array().unwrap();
+
+    let by_id = |id: u64| batch.iter().find(|x| x["id"] == json!(id)).unwrap();
+
+    assert_eq!(by_id(1)["result"], json!("0x7a69"));
+    assert!(by_id(2)["result"].is_string());
+    assert_eq!(by_id(3)["error"]["code"], json!(-32601));
+
+    x.wait_for_stop();
+}
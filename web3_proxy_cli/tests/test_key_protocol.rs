@@ -0,0 +1,113 @@
+use tracing::info;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// an old client that still frames its requests as json-rpc 1.0
+async fn send_jsonrpc_1(r: &reqwest::Client, rpc_url: &str) -> Value {
+    let response = r
+        .post(rpc_url)
+        .json(&json!({
+            "jsonrpc": "1.0",
+            "id": 1,
+            "method": "eth_chainId",
+            "params": [],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let body: Value = response.json().await.unwrap();
+    info!(?body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_key_protocol_requirements() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+
+    // new keys keep the old permissive behavior
+    assert!(!rpc_key.require_jsonrpc_2);
+    assert!(rpc_key.allow_batches);
+    assert!(rpc_key.allow_websocket);
+
+    let rpc_url = format!(
+        "{}rpc/{}",
+        x.proxy_provider.url(),
+        Ulid::from(rpc_key.secret_key)
+    );
+
+    let permissive = send_jsonrpc_1(&r, &rpc_url).await;
+    assert_eq!(permissive["result"], json!("0x7a69"));
+
+    // make the key strict
+    let updated: Value = r
+        .put(format!("{}user/keys", x.proxy_provider.url()))
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "require_jsonrpc_2": true,
+            "allow_batches": false,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?updated);
+
+    assert_eq!(updated["require_jsonrpc_2"], json!(true));
+    assert_eq!(updated["allow_batches"], json!(false));
+
+    let strict = send_jsonrpc_1(&r, &rpc_url).await;
+    assert_eq!(strict["error"]["code"], json!(-32600));
+    assert!(strict["error"]["message"].as_str().unwrap().contains("2.0"));
+
+    // conforming requests still work
+    let response: Value = r
+        .post(&rpc_url)
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["result"], json!("0x7a69"));
+
+    // but not as a batch
+    let response: Value = r
+        .post(&rpc_url)
+        .json(&json!([{"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"}]))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?response);
+    assert_eq!(response["error"]["code"], json!(-32600));
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,131 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::hashbrown::HashMap;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::top_config::http_rpc_config;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp};
+
+/// Forwards to anvil after a delay
+async fn delayed_vendor(delay: Arc<Duration>, request: MockRequest) -> Response {
+    tokio::time::sleep(*delay).await;
+
+    request.forward().await
+}
+
+fn spawn_vendor(a: &TestAnvil, delay: Duration) -> String {
+    spawn_mock_backend(a, Arc::new(delay), delayed_vendor)
+}
+
+/// the names of the backends that served this request
+async fn backend_rpcs(r: &reqwest::Client, x: &TestApp) -> String {
+    let response = r
+        .post(x.proxy_provider.url().as_str())
+        // a random address so that nothing is served from the cache
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap();
+
+    response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_fast_backend_gets_more_traffic() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    // these backends don't have websockets. poll them often so that new heads are seen quickly
+    top_config.app.block_interval_ms = Some(500);
+    top_config.balanced_rpcs = HashMap::from([
+        (
+            "fast".to_string(),
+            http_rpc_config(spawn_vendor(&a, Duration::ZERO)),
+        ),
+        (
+            "slow".to_string(),
+            http_rpc_config(spawn_vendor(&a, Duration::from_millis(300))),
+        ),
+    ]);
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+
+    // both backends start out looking the same. give them a chance to show their latency
+    for _ in 0..10 {
+        backend_rpcs(&r, &x).await;
+    }
+
+    // a new head recalculates the latency tiers
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut counts = HashMap::<String, usize>::new();
+    for _ in 0..20 {
+        *counts.entry(backend_rpcs(&r, &x).await).or_default() += 1;
+    }
+
+    info!(?counts);
+
+    let fast = counts.get("fast").copied().unwrap_or_default();
+    let slow = counts.get("slow").copied().unwrap_or_default();
+
+    assert!(fast > slow, "{:?}", counts);
+
+    // the latencies are visible in the status
+    let status: Value = r
+        .get(format!("{}status", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let conns = status["balanced_rpcs"]["conns"].as_array().unwrap();
+
+    let rpc = |name: &str| {
+        conns
+            .iter()
+            .find(|x| x["name"] == name)
+            .unwrap_or_else(|| panic!("{} missing from {:?}", name, conns))
+    };
+
+    info!(fast=%rpc("fast"), slow=%rpc("slow"));
+
+    assert!(
+        rpc("slow")["headers_latency_ms"].as_f64().unwrap()
+            > rpc("fast")["headers_latency_ms"].as_f64().unwrap()
+    );
+    assert!(rpc("slow")["tier"].as_u64().unwrap() > rpc("fast")["tier"].as_u64().unwrap());
+
+    // and in the metrics
+    let metrics = r
+        .get(format!("http://{}/metrics", x.prometheus_addr))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(metrics.contains("web3_proxy_backend_headers_latency_seconds_count{rpc=\"slow\"}"));
+    assert!(metrics.contains("web3_proxy_backend_latency_tier{rpc=\"fast\"} 1\n"));
+}
@@ -0,0 +1,110 @@
+use parking_lot::Mutex;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+use tracing_subscriber::prelude::*;
+use web3_proxy::log_filter::{reloadable_filter, LogFilter};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn contains(&self, x: &str) -> bool {
+        String::from_utf8_lossy(&self.0.lock()).contains(x)
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// an admin turns on debug logs for one target without restarting. they go away again after the revert
+/// this doesn't use test_log because it needs its own subscriber
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[tokio::test]
+async fn test_log_filter() {
+    let captured = CapturedLogs::default();
+
+    let (env_filter, reload) = reloadable_filter("info").unwrap();
+
+    let writer = captured.clone();
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .with_filter(env_filter);
+
+    tracing_subscriber::registry().with(fmt_layer).init();
+
+    LogFilter::install("info".to_string(), vec![reload]);
+
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let admin_login = create_user_as_admin(&x, &db, &r, &a.wallet(0)).await;
+
+    let client = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(admin_login.bearer_token.to_string());
+
+    debug!("suppressed marker");
+    info!("info marker");
+
+    assert!(captured.contains("info marker"));
+    assert!(!captured.contains("suppressed marker"));
+
+    // typos are rejected and change nothing
+    assert!(client
+        .admin_set_log_filter("info,test_log_filter=loud", None)
+        .await
+        .is_err());
+
+    let status = client
+        .admin_set_log_filter("info,test_log_filter=debug", Some(Duration::from_secs(2)))
+        .await
+        .unwrap();
+    info!(?status);
+
+    assert_eq!(status.filter, "info,test_log_filter=debug");
+    assert_eq!(status.default_filter, "info");
+    assert!(status.revert_at.is_some());
+
+    debug!("first debug marker");
+
+    assert!(captured.contains("first debug marker"));
+
+    // wait for the revert
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let status = client.admin_log_filter().await.unwrap();
+
+    assert_eq!(status.filter, "info");
+    assert_eq!(status.revert_at, None);
+
+    debug!("second debug marker");
+
+    assert!(!captured.contains("second debug marker"));
+
+    // drop the app first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,182 @@
+use axum::response::Response;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::{Address, Filter, Log, TransactionReceipt, H256},
+    providers::{Middleware, Provider, Ws},
+    signers::Signer,
+};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::serde_json::{self, json};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, timeout},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// init code for a contract that emits LOG1 with topic 1 and no data whenever it is called
+const EMIT_LOG_INIT_CODE: &str = "0x6008600c60003960086000f3600160006000a100";
+
+/// send a transaction from one of anvil's unlocked accounts and wait for its receipt. anvil mines it right away
+async fn send(a: &TestAnvil, tx: serde_json::Value) -> TransactionReceipt {
+    let tx_hash: H256 = a
+        .provider
+        .request("eth_sendTransaction", [tx])
+        .await
+        .unwrap();
+
+    a.provider
+        .get_transaction_receipt(tx_hash)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_logs_subscription() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.free_subscriptions = true;
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let from = a.wallet(0).address();
+
+    let receipt = send(&a, json!({"from": from, "data": EMIT_LOG_INIT_CODE})).await;
+    let contract = receipt.contract_address.unwrap();
+    info!(?contract);
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let topic = H256::from_low_u64_be(1);
+
+    let mut matching = ws
+        .subscribe_logs(&Filter::new().address(contract).topic0(topic))
+        .await
+        .unwrap();
+
+    // a filter for some other contract
+    let mut other = ws
+        .subscribe_logs(&Filter::new().address(Address::repeat_byte(0x42)))
+        .await
+        .unwrap();
+
+    let receipt = send(&a, json!({"from": from, "to": contract})).await;
+    info!(block=?receipt.block_number, logs=?receipt.logs);
+    assert_eq!(receipt.logs.len(), 1);
+
+    let log: Log = timeout(Duration::from_secs(10), matching.next())
+        .await
+        .unwrap()
+        .unwrap();
+    info!(?log);
+
+    assert_eq!(log.address, contract);
+    assert_eq!(log.topics, vec![topic]);
+    assert_eq!(log.block_hash, receipt.block_hash);
+    assert_ne!(log.removed, Some(true));
+
+    assert!(timeout(Duration::from_millis(500), other.next())
+        .await
+        .is_err());
+
+    x.wait_for_stop();
+}
+
+/// eth_getLogs fails while `failing` is set
+#[derive(Default)]
+struct FlakyLogs {
+    failing: AtomicBool,
+    failed: AtomicUsize,
+}
+
+async fn flaky_logs(state: Arc<FlakyLogs>, request: MockRequest) -> Response {
+    if request.method() == "eth_getLogs" && state.failing.load(Ordering::SeqCst) {
+        state.failed.fetch_add(1, Ordering::SeqCst);
+
+        return request.error(json!({"code": -32603, "message": "internal error"}));
+    }
+
+    request.forward().await
+}
+
+/// A block whose logs couldn't be fetched is not skipped. Its logs are sent with the next head
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_logs_subscription_retries_failed_blocks() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let flaky = Arc::new(FlakyLogs::default());
+
+    let backend_url = spawn_mock_backend(&a, flaky.clone(), flaky_logs);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({"free_subscriptions": true}))
+        .http_rpc("flaky_logs", backend_url)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let from = a.wallet(0).address();
+
+    let receipt = send(&a, json!({"from": from, "data": EMIT_LOG_INIT_CODE})).await;
+    let contract = receipt.contract_address.unwrap();
+
+    // the subscription's first head must be the deploy. logs are only sent for blocks after it
+    timeout(Duration::from_secs(10), async {
+        while x.proxy_provider.get_block_number().await.unwrap() < receipt.block_number.unwrap() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let mut matching = ws
+        .subscribe_logs(&Filter::new().address(contract))
+        .await
+        .unwrap();
+
+    flaky.failing.store(true, Ordering::SeqCst);
+
+    let failed_receipt = send(&a, json!({"from": from, "to": contract})).await;
+    info!(block=?failed_receipt.block_number);
+
+    timeout(Duration::from_secs(10), async {
+        while flaky.failed.load(Ordering::SeqCst) == 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    flaky.failing.store(false, Ordering::SeqCst);
+
+    assert!(timeout(Duration::from_millis(500), matching.next())
+        .await
+        .is_err());
+
+    // a block without any logs for the filter
+    send(&a, json!({"from": from, "to": from})).await;
+
+    let log: Log = timeout(Duration::from_secs(10), matching.next())
+        .await
+        .unwrap()
+        .unwrap();
+    info!(?log);
+
+    assert_eq!(log.block_hash, failed_receipt.block_hash);
+    assert_ne!(log.removed, Some(true));
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+/// the value of the sample with exactly this name and labels
+fn sample(metrics: &str, name_and_labels: &str) -> Option<f64> {
+    metrics.lines().find_map(|x| {
+        let (k, v) = x.rsplit_once(' ')?;
+
+        (k == name_and_labels).then(|| v.parse().unwrap())
+    })
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_metrics() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    for _ in 0..2 {
+        let _: U64 = x.proxy_provider.request("eth_chainId", ()).await.unwrap();
+    }
+
+    let response = r
+        .get(format!("http://{}/metrics", x.prometheus_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+
+    let metrics = response.text().await.unwrap();
+    info!(%metrics);
+
+    assert!(metrics.contains("# TYPE web3_proxy_requests_by_method_total counter\n"));
+    assert_eq!(
+        sample(
+            &metrics,
+            "web3_proxy_requests_by_method_total{method=\"eth_chainId\"}"
+        ),
+        Some(2.0)
+    );
+    assert!(
+        sample(
+            &metrics,
+            "web3_proxy_requests_by_outcome_total{outcome=\"cache_hit\"}"
+        )
+        .unwrap()
+            >= 1.0
+    );
+    assert!(sample(&metrics, "web3_proxy_request_latency_seconds_count").unwrap() >= 2.0);
+
+    assert!(sample(&metrics, "web3_proxy_head_block_number").is_some());
+    assert_eq!(sample(&metrics, "web3_proxy_synced_backends"), Some(1.0));
+
+    // backends are labeled with their names from the config
+    assert_eq!(
+        sample(&metrics, "web3_proxy_backend_healthy{rpc=\"anvil\"}"),
+        Some(1.0)
+    );
+    assert!(
+        sample(
+            &metrics,
+            "web3_proxy_backend_latency_seconds_count{rpc=\"anvil\"}"
+        )
+        .unwrap()
+            > 0.0
+    );
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use tracing::info;
+use web3_proxy::config::{ChainConfig, TopConfig};
+use web3_proxy::embed::Web3ProxyBuilder;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::top_config::TopConfigBuilder;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+/// a config that serves each anvil as its own chain
+fn chains_config(chains: &[(&str, &str, &TestAnvil)]) -> TopConfig {
+    let mut top_config = TopConfigBuilder::new(1).build();
+
+    top_config.chains = chains
+        .iter()
+        .map(|(name, host, anvil)| {
+            let chain = ChainConfig {
+                hosts: vec![host.to_string()],
+                top_config: TopConfigBuilder::new(anvil.instance.chain_id())
+                    .anvil_rpc("anvil", anvil)
+                    .build(),
+            };
+
+            (name.to_string(), chain)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    top_config
+}
+
+async fn chain_id(r: &reqwest::Client, url: &str, host: Option<&str>) -> Value {
+    let mut request = r.post(url).json(&json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_chainId",
+        "params": [],
+    }));
+
+    if let Some(host) = host {
+        request = request.header("host", host);
+    }
+
+    let body: Value = request.send().await.unwrap().json().await.unwrap();
+    info!(%url, ?host, %body);
+
+    body["result"].clone()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_multiple_chains() {
+    let a = TestAnvil::spawn(31337).await;
+    let b = TestAnvil::spawn(999_001).await;
+
+    // two chains can't claim the same host. this fails before anything is started
+    let err = Web3ProxyBuilder::new(chains_config(&[
+        ("a", "rpc.example.test", &a),
+        ("b", "RPC.example.test", &b),
+    ]))
+    .spawn()
+    .await
+    .err()
+    .expect("conflicting hosts should not start");
+    assert!(err.to_string().contains("both claim the host"), "{}", err);
+
+    let x = TestApp::spawn_with_top_config(chains_config(&[
+        ("a", "a.example.test", &a),
+        ("b", "b.example.test", &b),
+    ]))
+    .await;
+
+    let base = x.proxy_provider.url().to_string();
+
+    let r = reqwest::Client::new();
+
+    // by path
+    assert_eq!(
+        chain_id(&r, &format!("{}a", base), None).await,
+        json!("0x7a69")
+    );
+    assert_eq!(
+        chain_id(&r, &format!("{}b/", base), None).await,
+        json!("0xf3e59")
+    );
+
+    // by host. the port and case don't matter
+    assert_eq!(
+        chain_id(&r, &base, Some("A.example.test:8544")).await,
+        json!("0x7a69")
+    );
+    assert_eq!(
+        chain_id(&r, &base, Some("b.example.test")).await,
+        json!("0xf3e59")
+    );
+
+    // an unknown host is routed by path. there is no chain at `/`
+    let response = r
+        .post(&base)
+        .header("host", "c.example.test")
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    // every chain has its own health and status
+    for (name, chain_id) in [("a", 31337), ("b", 999_001)] {
+        let health = r
+            .get(format!("{}{}/health", base, name))
+            .send()
+            .await
+            .unwrap();
+        assert!(health.status().is_success(), "{}", name);
+
+        let status: Value = r
+            .get(format!("{}{}/status", base, name))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(status["chain_id"], json!(chain_id), "{}", name);
+    }
+
+    // the process is only healthy if every chain is
+    let health = r.get(format!("{}health", base)).send().await.unwrap();
+    assert!(health.status().is_success());
+
+    // each chain's metrics are under its name
+    let metrics = reqwest::get(format!("http://{}/b/metrics", x.prometheus_addr))
+        .await
+        .unwrap();
+    assert!(metrics.status().is_success());
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,127 @@
+use tracing::info;
+use web3_proxy::app::APP_USER_AGENT;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Duration},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// monitoring pointed at the proxy should see the proxy, not whichever backend was picked
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_node_introspection() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "node_introspection": {
+                "peer_count": 25,
+                "web3_client_version": "forward",
+            },
+        }))
+        .anvil_rpc("anvil", &a)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // a fresh head so the consensus isn't stale
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+    for _ in 0..50 {
+        let head: U64 = x
+            .proxy_provider
+            .request("eth_blockNumber", ())
+            .await
+            .unwrap();
+
+        if head >= 1.into() {
+            break;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let syncing: Value = x.proxy_provider.request("eth_syncing", ()).await.unwrap();
+    assert_eq!(syncing, json!(false));
+
+    let listening: bool = x.proxy_provider.request("net_listening", ()).await.unwrap();
+    assert!(listening);
+
+    // the configured constant, not anvil's peer count
+    let peer_count: U64 = x.proxy_provider.request("net_peerCount", ()).await.unwrap();
+    assert_eq!(peer_count, 25.into());
+
+    // forwarded to anvil
+    let client_version: String = x
+        .proxy_provider
+        .request("web3_clientVersion", ())
+        .await
+        .unwrap();
+    info!(%client_version);
+
+    let anvil_version: String = a.provider.request("web3_clientVersion", ()).await.unwrap();
+    assert_eq!(client_version, anvil_version);
+    assert!(!client_version.starts_with("llamanodes_"));
+
+    x.wait_for_stop();
+}
+
+/// with no backends synced, the proxy says it is syncing instead of guessing
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_node_introspection_without_consensus() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // there is only one backend, so there is never a consensus head
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({"min_synced_rpcs": 2}))
+        .anvil_rpc("anvil", &a)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    // the backend's own head is still the highest block
+    let mut syncing = Value::Null;
+    for _ in 0..50 {
+        syncing = x.proxy_provider.request("eth_syncing", ()).await.unwrap();
+
+        if syncing["highestBlock"] == json!(U64::one()) {
+            break;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+    info!(%syncing);
+
+    assert_eq!(
+        syncing,
+        json!({
+            "startingBlock": "0x0",
+            "currentBlock": "0x0",
+            "highestBlock": "0x1",
+        })
+    );
+
+    let peer_count: U64 = x.proxy_provider.request("net_peerCount", ()).await.unwrap();
+    assert_eq!(peer_count, U64::zero());
+
+    // answered by the proxy even though no backend could answer it
+    let client_version: String = x
+        .proxy_provider
+        .request("web3_clientVersion", ())
+        .await
+        .unwrap();
+    assert_eq!(client_version, APP_USER_AGENT);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,196 @@
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::{
+    prelude::{H256, U256},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::top_config::anvil_rpc_config;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// wallets compute their next nonce with the "pending" tag. the answer has to come from a backend that saw their
+/// transactions, not whichever rpc the balancer picked
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_pending_nonce_uses_designated_rpc() {
+    // this one has the transaction in its mempool
+    let mempool = TestAnvil::spawn(31337).await;
+    // this one never hears about it
+    let other = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "pending_block_policy": "route_to_designated",
+            "pending_block_rpc": "mempool",
+        }))
+        .anvil_rpc("mempool", &mempool)
+        .anvil_rpc("other", &other)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // keep the transaction pending
+    mempool
+        .provider
+        .request::<_, ()>("evm_setAutomine", [false])
+        .await
+        .unwrap();
+
+    let wallet = mempool.wallet(0);
+
+    let gas_price: U256 = mempool.provider.request("eth_gasPrice", ()).await.unwrap();
+
+    let tx = TypedTransaction::Eip1559(Eip1559TransactionRequest {
+        chain_id: Some(31337.into()),
+        to: Some(Address::repeat_byte(0x42).into()),
+        gas: Some(21000.into()),
+        value: Some(1.into()),
+        max_fee_per_gas: Some(gas_price * U256::from(2)),
+        nonce: Some(0.into()),
+        ..Default::default()
+    });
+
+    let sig = wallet.sign_transaction_sync(&tx).unwrap();
+
+    // send it straight to the one backend. the proxy would relay it everywhere
+    let tx_hash: H256 = mempool
+        .provider
+        .request("eth_sendRawTransaction", [tx.rlp_signed(&sig)])
+        .await
+        .unwrap();
+    info!(?tx_hash);
+
+    let from = wallet.address();
+
+    let r = reqwest::Client::new();
+
+    let proxy_url = x.proxy_provider.url().clone();
+
+    // every request goes to the same backend, so the wallet always sees its pending transaction
+    for id in 0..10 {
+        let response = r
+            .post(proxy_url.clone())
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "eth_getTransactionCount",
+                "params": [from, "pending"],
+            }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()["X-W3P-BACKEND-RPCS"], "mempool");
+
+        let body: Value = response.json().await.unwrap();
+
+        assert_eq!(body["result"], json!(U256::one()), "{:?}", body);
+    }
+
+    // "latest" is balanced like normal and doesn't include the pending transaction
+    let latest: U256 = x
+        .proxy_provider
+        .request("eth_getTransactionCount", (from, "latest"))
+        .await
+        .unwrap();
+    assert_eq!(latest, U256::zero());
+
+    // the other backend really doesn't know about it
+    let other_pending: U256 = other
+        .provider
+        .request("eth_getTransactionCount", (from, "pending"))
+        .await
+        .unwrap();
+    assert_eq!(other_pending, U256::zero());
+
+    x.wait_for_stop();
+}
+
+/// the names of the backends that served this request and its body. the names are empty if it came from the cache
+async fn get_transaction_count(x: &TestApp, from: Address, block: &str) -> (String, Value) {
+    let response = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getTransactionCount",
+            "params": [from, block],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let backend_rpcs = response.headers()["X-W3P-BACKEND-RPCS"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%block, %backend_rpcs, %body);
+
+    (backend_rpcs, body)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_pending_uses_pending_state_rpcs() {
+    let mempool = TestAnvil::spawn(31337).await;
+    let other = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .balanced_rpc(
+            "mempool",
+            Web3RpcConfig {
+                pending_state: true,
+                ..anvil_rpc_config(&mempool)
+            },
+        )
+        .anvil_rpc("other", &other)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let from = Address::random();
+
+    // never served from the cache, and always from the rpc that we trust for pending state
+    for _ in 0..10 {
+        let (backend_rpcs, body) = get_transaction_count(&x, from, "pending").await;
+
+        assert_eq!(backend_rpcs, "mempool");
+        assert_eq!(body["result"], "0x0", "{}", body);
+    }
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_pending_without_pending_state_rpcs_is_not_cached() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let from = Address::random();
+
+    // downgraded to "latest", but still never served from the cache
+    for _ in 0..5 {
+        let (backend_rpcs, body) = get_transaction_count(&x, from, "pending").await;
+
+        assert!(!backend_rpcs.is_empty());
+        assert_eq!(body["result"], "0x0", "{}", body);
+    }
+
+    // the pending requests did not fill the cache for "latest"
+    let (backend_rpcs, _) = get_transaction_count(&x, from, "latest").await;
+    assert!(!backend_rpcs.is_empty());
+
+    // "latest" is cached like normal
+    let (backend_rpcs, _) = get_transaction_count(&x, from, "latest").await;
+    assert_eq!(backend_rpcs, "");
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,101 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::{
+    prelude::H256,
+    providers::{Middleware, Provider, Ws},
+    signers::Signer,
+};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, timeout},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+async fn passthrough(_: Arc<()>, request: MockRequest) -> Response {
+    request.forward().await
+}
+
+/// the mock backend's http and websocket. its websocket relays anvil's pending transactions
+fn subscribe_txs_config(url: String) -> Web3RpcConfig {
+    Web3RpcConfig {
+        ws_url: Some(url.replacen("http", "ws", 1)),
+        http_url: Some(url),
+        subscribe_txs: true,
+        ..Default::default()
+    }
+}
+
+/// Two backends announce the same pending transaction. Subscribers only hear about it once
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_pending_tx_dedupe() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let first_url = spawn_mock_backend(&a, Arc::new(()), passthrough);
+    let second_url = spawn_mock_backend(&a, Arc::new(()), passthrough);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "free_subscriptions": true,
+            "pending_txid_dedupe_window_secs": 60,
+        }))
+        .balanced_rpc("first", subscribe_txs_config(first_url))
+        .balanced_rpc("second", subscribe_txs_config(second_url))
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // keep the transaction in the mempool
+    a.provider
+        .request::<_, ()>("evm_setAutomine", [false])
+        .await
+        .unwrap();
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let mut pending = ws.subscribe_pending_txs().await.unwrap();
+
+    // give both backends time to subscribe to anvil's pending transactions
+    sleep(Duration::from_secs(2)).await;
+
+    let from = a.wallet(0).address();
+
+    let tx_hash: H256 = a
+        .provider
+        .request("eth_sendTransaction", [json!({"from": from, "to": from})])
+        .await
+        .unwrap();
+    info!(?tx_hash);
+
+    let first = timeout(Duration::from_secs(10), pending.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(first, tx_hash);
+
+    // the other backend's announcement arrives and is dropped
+    timeout(Duration::from_secs(10), async {
+        while x.app.pending_txid_firehose.suppressed() == 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(x.app.pending_txid_firehose.suppressed(), 1);
+
+    assert!(timeout(Duration::from_millis(500), pending.next())
+        .await
+        .is_err());
+
+    x.wait_for_stop();
+}
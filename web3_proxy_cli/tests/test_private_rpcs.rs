@@ -0,0 +1,130 @@
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::{H256, U256},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// send a transaction and then look up its receipt. returns the backends that handled each request
+async fn send_and_lookup(anvil: &TestAnvil, x: &TestApp) -> (String, String) {
+    let wallet = anvil.wallet(0);
+
+    let gas_price: U256 = x.proxy_provider.request("eth_gasPrice", ()).await.unwrap();
+
+    let tx = TypedTransaction::Eip1559(Eip1559TransactionRequest {
+        chain_id: Some(31337.into()),
+        to: Some(Address::repeat_byte(0x42).into()),
+        gas: Some(21000.into()),
+        value: Some(1.into()),
+        max_fee_per_gas: Some(gas_price * U256::from(2)),
+        nonce: Some(0.into()),
+        ..Default::default()
+    });
+
+    let sig = wallet.sign_transaction_sync(&tx).unwrap();
+
+    let r = reqwest::Client::new();
+
+    let proxy_url = x.proxy_provider.url().clone();
+
+    let response = r
+        .post(proxy_url.clone())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendRawTransaction",
+            "params": [tx.rlp_signed(&sig)],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let send_rpcs = response.headers()["X-W3P-BACKEND-RPCS"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Value = response.json().await.unwrap();
+    info!(?body);
+
+    let tx_hash: H256 = serde_json::from_value(body["result"].clone()).unwrap();
+
+    let response = r
+        .post(proxy_url)
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "eth_getTransactionReceipt",
+            "params": [tx_hash],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let receipt_rpcs = response.headers()["X-W3P-BACKEND-RPCS"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let body: Value = response.json().await.unwrap();
+    info!(?body);
+
+    assert_eq!(body["result"]["transactionHash"], json!(tx_hash));
+
+    (send_rpcs, receipt_rpcs)
+}
+
+async fn private_rpcs_mode(x: &TestApp) -> Value {
+    let status: Value = reqwest::get(format!("{}status", x.proxy_provider.url()))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    status["private_rpcs_mode"].clone()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_dedicated_private_rpcs() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // the default test app has one balanced and one private rpc
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    assert_eq!(private_rpcs_mode(&x).await, "dedicated");
+
+    let (send_rpcs, receipt_rpcs) = send_and_lookup(&a, &x).await;
+
+    // transactions only go to the private rpcs. receipts come from the balanced rpcs
+    assert_eq!(send_rpcs, "anvil_private");
+    assert_eq!(receipt_rpcs, "anvil");
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_no_private_rpcs() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337).anvil_rpc("anvil", &a).build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    assert_eq!(private_rpcs_mode(&x).await, "same_as_balanced");
+
+    let (send_rpcs, receipt_rpcs) = send_and_lookup(&a, &x).await;
+
+    // a single best-server send. not a relay to every balanced rpc
+    assert_eq!(send_rpcs, "anvil");
+    assert_eq!(receipt_rpcs, "anvil");
+
+    x.wait_for_stop();
+}
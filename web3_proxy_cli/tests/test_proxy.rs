@@ -42,6 +42,22 @@ async fn it_starts_and_stops() {
     dbg!(&health_response);
     assert_eq!(health_response.unwrap().status(), StatusCode::OK);
 
+    // load balancers that accept json get the sync details
+    let health_json: Value = reqwest::Client::new()
+        .get(format!("{}health", proxy_url))
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(%health_json);
+    assert_eq!(health_json["status"], "ok");
+    assert_eq!(health_json["chain_id"], 31337);
+    assert_eq!(health_json["synced_rpcs"], health_json["total_rpcs"]);
+    assert!(health_json["head_block_hash"].is_string());
+
     // check the /status page
     let status_response = reqwest::get(format!("{}status", proxy_url)).await;
     dbg!(&status_response);
@@ -107,6 +123,26 @@ async fn it_starts_and_stops() {
 
     assert_eq!(Some(anvil_result), proxy_result);
 
+    // an unsupported method in a batch only errors its own entry
+    let batch: Value = reqwest::Client::new()
+        .post(proxy_url.as_str())
+        .json(&json!([
+            {"jsonrpc": "2.0", "id": "a", "method": "eth_newFilter", "params": [{}]},
+            {"jsonrpc": "2.0", "id": "b", "method": "eth_blockNumber", "params": []},
+        ]))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(%batch);
+    assert_eq!(batch[0]["jsonrpc"], "2.0");
+    assert_eq!(batch[0]["id"], "a");
+    assert_eq!(batch[0]["error"]["code"], -32601);
+    assert_eq!(batch[1]["id"], "b");
+    assert!(batch[1]["result"].is_string(), "{}", batch);
+
     // this won't do anything since stats aren't tracked when there isn't a db
     let flushed = x.flush_stats_and_wait().await.unwrap();
     assert_eq!(flushed.relational, 0);
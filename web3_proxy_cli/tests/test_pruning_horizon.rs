@@ -0,0 +1,98 @@
+use tracing::info;
+use web3_proxy::config::{BlockDataLimit, Web3RpcConfig};
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::top_config::anvil_rpc_config;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+async fn spawn_app(a: &TestAnvil, rpcs: &[(&str, BlockDataLimit)]) -> TestApp {
+    // move the first blocks past the pruned node's horizon
+    let _: Value = a.provider.request("anvil_mine", (100,)).await.unwrap();
+
+    let mut top_config = TopConfigBuilder::new(31337);
+
+    for (name, block_data_limit) in rpcs {
+        top_config = top_config.balanced_rpc(
+            name,
+            Web3RpcConfig {
+                block_data_limit: block_data_limit.clone(),
+                ..anvil_rpc_config(a)
+            },
+        );
+    }
+
+    TestApp::spawn_with_top_config(top_config.build()).await
+}
+
+async fn get_balance(x: &TestApp, block: &str) -> (String, Value) {
+    let response = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        // a random address so that nothing is served from the cache
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), block]}))
+        .send()
+        .await
+        .unwrap();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string())
+        .unwrap_or_default();
+
+    let body: Value = response.json().await.unwrap();
+
+    info!(%block, %body, %backend_rpcs);
+
+    (backend_rpcs, body)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_pruned_block_is_a_clear_error() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = spawn_app(&a, &[("pruned", BlockDataLimit::Set(64))]).await;
+
+    let (_, body) = get_balance(&x, "latest").await;
+    assert_eq!(body["result"], "0x0", "{}", body);
+
+    let (_, body) = get_balance(&x, "0x1").await;
+    assert_eq!(body["error"]["code"], -32002, "{}", body);
+    assert!(
+        body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("pruning horizon"),
+        "{}",
+        body
+    );
+    assert_eq!(body["error"]["data"]["requested_block"], "0x1");
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_old_blocks_go_to_archive_nodes() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = spawn_app(
+        &a,
+        &[
+            ("pruned", BlockDataLimit::Set(64)),
+            ("archive", BlockDataLimit::Archive),
+        ],
+    )
+    .await;
+
+    for _ in 0..5 {
+        let (backend_rpcs, body) = get_balance(&x, "0x1").await;
+        assert_eq!(body["result"], "0x0", "{}", body);
+        assert_eq!(backend_rpcs, "archive");
+    }
+
+    x.wait_for_stop();
+}
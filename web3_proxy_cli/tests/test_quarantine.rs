@@ -0,0 +1,125 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Instant},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// eth_getBalance fails while this is set. everything else keeps working, so the head block keeps moving
+async fn flaky_vendor(failing: Arc<AtomicBool>, request: MockRequest) -> Response {
+    if request.method() == "eth_getBalance" && failing.load(Ordering::SeqCst) {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    request.forward().await
+}
+
+async fn quarantine_status(r: &reqwest::Client, x: &TestApp) -> Value {
+    let status: Value = r
+        .get(format!("{}status", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let quarantine = status["balanced_rpcs"]["conns"][0]["quarantine"].clone();
+    info!(%quarantine);
+
+    quarantine
+}
+
+/// a random address so that nothing is served from the cache
+async fn get_balance(r: &reqwest::Client, x: &TestApp) -> Value {
+    let body: Value = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    info!(%body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_quarantine() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let failing = Arc::new(AtomicBool::new(false));
+
+    let vendor_url = spawn_mock_backend(&a, failing.clone(), flaky_vendor);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .balanced_rpc(
+            "flaky_vendor",
+            Web3RpcConfig {
+                http_url: Some(vendor_url),
+                quarantine_probe_secs: 1,
+                quarantine_recover_after: 2,
+                quarantine_window: 4,
+                ..Default::default()
+            },
+        )
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    assert!(get_balance(&r, &x).await["result"].is_string());
+    assert_eq!(quarantine_status(&r, &x).await["quarantined"], false);
+
+    failing.store(true, Ordering::SeqCst);
+
+    let mut quarantined = false;
+    for _ in 0..10 {
+        let body = get_balance(&r, &x).await;
+        assert!(body["error"].is_object(), "{}", body);
+
+        let quarantine = quarantine_status(&r, &x).await;
+
+        if quarantine["quarantined"] == true {
+            assert!(quarantine["reason"].is_string());
+            assert_eq!(quarantine["times_quarantined"], 1);
+            quarantined = true;
+            break;
+        }
+    }
+    assert!(quarantined, "the failing rpc was never quarantined");
+
+    // the probes bring it back once the vendor is fixed
+    failing.store(false, Ordering::SeqCst);
+
+    let start = Instant::now();
+    while quarantine_status(&r, &x).await["quarantined"] == true {
+        if start.elapsed() > Duration::from_secs(15) {
+            panic!("the rpc never left quarantine");
+        }
+
+        sleep(Duration::from_millis(500)).await;
+    }
+
+    assert!(get_balance(&r, &x).await["result"].is_string());
+
+    x.wait_for_stop();
+}
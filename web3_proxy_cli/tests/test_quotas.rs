@@ -0,0 +1,118 @@
+use std::net::TcpListener;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::http::header::RETRY_AFTER;
+use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait, IntoActiveModel};
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy_cli::test_utils::create_user::login;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+async fn block_number(r: &reqwest::Client, url: &str) -> (StatusCode, Option<String>, Value) {
+    let response = r
+        .post(url)
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .map(|x| x.to_str().unwrap().to_string());
+    let body = response.json().await.unwrap();
+
+    info!(%status, ?retry_after, %body);
+
+    (status, retry_after, body)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_daily_quota() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    // the base url needs the port before the app starts
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.public_base_url = Some(format!("http://127.0.0.1:{}/", port));
+
+    let x = TestApp::spawn_with_top_config_and_port(top_config, port).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let (_, user_login) = login(&x, &r, &a.wallet(0), None).await;
+
+    let rpc_key = user_login.rpc_keys.values().next().unwrap().clone();
+    let secret_key = Ulid::from(rpc_key.secret_key);
+
+    // quotas are set by us, not by the key's owner
+    {
+        let db_conn = db.conn().await;
+
+        let mut rpc_key = rpc_key.into_active_model();
+        rpc_key.max_requests_per_day = sea_orm::Set(Some(3));
+        rpc_key.max_requests_per_month = sea_orm::Set(Some(1_000));
+        rpc_key.update(&db_conn).await.unwrap();
+    }
+
+    let url = format!("{}rpc/{}", x.proxy_provider.url(), secret_key);
+
+    for _ in 0..3 {
+        let (status, _, body) = block_number(&r, &url).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    let (status, retry_after, body) = block_number(&r, &url).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"]["message"], "daily request quota exceeded");
+    assert_eq!(body["error"]["data"]["period"], "day");
+    assert_eq!(body["error"]["data"]["limit"], 3);
+
+    // the reset is the next utc midnight
+    let retry_after: i64 = retry_after.unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 86_400);
+    assert_eq!(body["error"]["data"]["reset"].as_i64().unwrap() % 86_400, 0);
+
+    // the per-minute rate limit has a different message
+    assert_ne!(body["error"]["message"], "too many requests");
+
+    let connect: Value = r
+        .get(format!("{}user/connect", x.proxy_provider.url()))
+        .bearer_auth(user_login.bearer_token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(%connect);
+
+    let quotas = &connect["rpc_keys"][0]["limits"]["quotas"];
+
+    assert_eq!(quotas[0]["period"], "day");
+    assert_eq!(quotas[0]["used"], 3);
+    assert_eq!(quotas[0]["remaining"], 0);
+    // the refused request didn't use up any of the month
+    assert_eq!(quotas[1]["period"], "month");
+    assert_eq!(quotas[1]["used"], 3);
+    assert_eq!(quotas[1]["remaining"], 997);
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::RateLimitStoreKind;
+use web3_proxy::prelude::entities::{rpc_key, user, user_tier};
+use web3_proxy::prelude::http::header::RETRY_AFTER;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy::secrets::RpcSecretKey;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// the status code and the limit, remaining, and reset headers
+async fn limited_request(
+    r: &reqwest::RequestBuilder,
+) -> (StatusCode, Option<u64>, Option<u64>, Option<u64>) {
+    let response = r
+        .try_clone()
+        .unwrap()
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let headers = response.headers();
+
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .map(|x| x.to_str().unwrap().parse::<u64>().unwrap())
+    };
+
+    let x = (
+        response.status(),
+        header("X-RateLimit-Limit"),
+        header("X-RateLimit-Remaining"),
+        header("X-RateLimit-Reset"),
+    );
+
+    info!(?x, retry_after=?headers.get(RETRY_AFTER));
+
+    if x.0 == StatusCode::TOO_MANY_REQUESTS {
+        // both round up, but they are measured a moment apart
+        let retry_after = header(RETRY_AFTER.as_str()).unwrap();
+        let reset = x.3.unwrap();
+
+        assert!(retry_after == reset || retry_after == reset + 1);
+    }
+
+    x
+}
+
+/// count down to a 429 and check the headers along the way
+async fn count_down(r: reqwest::RequestBuilder, limit: u64) {
+    for remaining in (0..limit).rev() {
+        let (status, x_limit, x_remaining, x_reset) = limited_request(&r).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(x_limit, Some(limit));
+        assert_eq!(x_remaining, Some(remaining));
+        assert!(x_reset.unwrap() <= 60);
+    }
+
+    let (status, x_limit, x_remaining, x_reset) = limited_request(&r).await;
+
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(x_limit, Some(limit));
+    assert_eq!(x_remaining, Some(0));
+    assert!(x_reset.unwrap() <= 60);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_public_rate_limit_headers() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    top_config.app.public_requests_per_period = Some(3);
+    top_config.app.rate_limit_store = RateLimitStoreKind::Memory;
+    top_config.app.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // localhost is never limited. pretend to be a load balancer forwarding someone else
+    let request = r
+        .post(x.proxy_provider.url().as_str())
+        .header("X-Forwarded-For", "192.0.2.1");
+
+    count_down(request, 3).await;
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_key_rate_limit_headers() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let rpc_secret_key = RpcSecretKey::new();
+    {
+        let db_conn = db.conn().await;
+
+        let tier = user_tier::ActiveModel {
+            title: sea_orm::Set("Tiny".to_string()),
+            max_requests_per_period: sea_orm::Set(Some(4)),
+            max_concurrent_requests: sea_orm::Set(None),
+            downgrade_tier_id: sea_orm::Set(None),
+            allow_archive: sea_orm::Set(true),
+            allow_trace: sea_orm::Set(true),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+
+        let u = user::ActiveModel {
+            address: sea_orm::Set(a.wallet(0).address().to_fixed_bytes().into()),
+            user_tier_id: tier.id,
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+
+        rpc_key::ActiveModel {
+            user_id: u.id,
+            secret_key: sea_orm::Set(rpc_secret_key.into()),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+    }
+    let key = Ulid::from(rpc_secret_key);
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.rate_limit_store = RateLimitStoreKind::Memory;
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let request = r.post(format!("{}rpc/{}", x.proxy_provider.url(), key));
+
+    count_down(request, 4).await;
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
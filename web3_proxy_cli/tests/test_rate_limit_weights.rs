@@ -0,0 +1,95 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::RateLimitStoreKind;
+use web3_proxy::prelude::entities::{rpc_key, user, user_tier};
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy::secrets::RpcSecretKey;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+const MAX_REQUESTS_PER_PERIOD: u64 = 10;
+
+/// a heavy method uses up more of the limit than a cheap one
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_rate_limit_weights() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    // a tier with a small limit so that two heavy requests go over it
+    let rpc_secret_key = RpcSecretKey::new();
+    {
+        let db_conn = db.conn().await;
+
+        let tier = user_tier::ActiveModel {
+            title: sea_orm::Set("Tiny".to_string()),
+            max_requests_per_period: sea_orm::Set(Some(MAX_REQUESTS_PER_PERIOD)),
+            max_concurrent_requests: sea_orm::Set(None),
+            downgrade_tier_id: sea_orm::Set(None),
+            allow_archive: sea_orm::Set(true),
+            allow_trace: sea_orm::Set(true),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+
+        let u = user::ActiveModel {
+            address: sea_orm::Set(a.wallet(0).address().to_fixed_bytes().into()),
+            user_tier_id: tier.id,
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+
+        rpc_key::ActiveModel {
+            user_id: u.id,
+            secret_key: sea_orm::Set(rpc_secret_key.into()),
+            ..Default::default()
+        }
+        .save(&db_conn)
+        .await
+        .unwrap();
+    }
+    let key = Ulid::from(rpc_secret_key);
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.rate_limit_store = RateLimitStoreKind::Memory;
+    top_config.app.rate_limit_weights = serde_json::from_value(json!({"eth_getLogs": 8})).unwrap();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let url = format!("{}rpc/{}", x.proxy_provider.url(), key);
+
+    let get_logs = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{"fromBlock": "0x0", "toBlock": "0x0"}]});
+
+    let response = r.post(&url).json(&get_logs).send().await.unwrap();
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+    info!(%status, %body);
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["result"].is_array(), "{}", body);
+
+    // 8 + 8 is more than the tier allows
+    let response = r.post(&url).json(&get_logs).send().await.unwrap();
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+    info!(%status, %body);
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["id"], 1);
+    assert_eq!(body["error"]["data"]["weight"], 8);
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+async fn get_balance(r: &reqwest::Client, x: &TestApp, address: Address) -> StatusCode {
+    let response = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "latest"]}))
+        // loopback is never rate limited. only used if the test trusts 127.0.0.1 as a proxy
+        .header("X-Forwarded-For", "192.0.2.1")
+        .send()
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+
+    info!(%status, %body);
+
+    status
+}
+
+/// no `volatile_redis_url` is set. limits are counted in memory instead of being skipped
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_public_rate_limit_without_redis() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    assert!(top_config.app.volatile_redis_url.is_none());
+    top_config.app.public_requests_per_period = Some(3);
+    top_config.app.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let mut statuses = vec![];
+    for i in 0..6 {
+        statuses.push(get_balance(&r, &x, Address::from_low_u64_be(i)).await);
+    }
+
+    info!(?statuses);
+
+    // the local limiter can let a request or two more through than redis would
+    assert_eq!(statuses[0], StatusCode::OK);
+    assert!(statuses.contains(&StatusCode::TOO_MANY_REQUESTS));
+
+    x.wait_for_stop();
+}
+
+/// a backend `hard_limit` used to need redis. without it, the app refused to start
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_hard_limit_without_redis() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mut top_config = TestApp::top_config(&a, None, None, None);
+    assert!(top_config.app.volatile_redis_url.is_none());
+
+    let hard_limit = 5;
+
+    for rpc in top_config.balanced_rpcs.values_mut() {
+        rpc.hard_limit = Some(hard_limit);
+        rpc.hard_limit_period = 60;
+    }
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // different addresses so that nothing is served from the cache
+    let mut ok = 0;
+    let mut saturated = 0;
+    for i in 0..20 {
+        match get_balance(&r, &x, Address::from_low_u64_be(i)).await {
+            StatusCode::OK => ok += 1,
+            StatusCode::SERVICE_UNAVAILABLE => saturated += 1,
+            status => panic!("unexpected status {}", status),
+        }
+    }
+
+    info!(ok, saturated);
+
+    // the app's own requests count against the limit too
+    assert!(ok <= hard_limit);
+    assert!(saturated > 0);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::incoming_requests::MIN_IN_FLIGHT_CAPACITY;
+use web3_proxy::prelude::ethers::prelude::{Address, Bytes};
+use web3_proxy::prelude::futures::stream::{self, StreamExt};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp};
+
+/// the value of the sample with exactly this name and labels
+fn sample(metrics: &str, name_and_labels: &str) -> Option<f64> {
+    metrics.lines().find_map(|x| {
+        let (k, v) = x.rsplit_once(' ')?;
+
+        (k == name_and_labels).then(|| v.parse().unwrap())
+    })
+}
+
+/// In-flight requests are only tracked by their cache key, so nothing keeps their params once they are answered.
+/// A burst of large, unique requests leaves the in-flight gauges at zero, a small in-flight map, and a response cache
+/// under its limit
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_burst_of_large_unique_requests() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let num_requests = 10_000;
+
+    let errors = stream::iter(0..num_requests)
+        .map(|i: u64| {
+            let proxy_provider = x.proxy_provider.clone();
+
+            async move {
+                // 4 KiB of calldata that is different for every request
+                let mut data = vec![0u8; 4096];
+                data[..8].copy_from_slice(&i.to_be_bytes());
+
+                let tx = json!({
+                    "to": Address::repeat_byte(0x42),
+                    "data": Bytes::from(data),
+                });
+
+                proxy_provider
+                    .request::<_, Bytes>("eth_call", (tx, "latest"))
+                    .await
+                    .is_err()
+            }
+        })
+        .buffer_unordered(100)
+        .filter(|x| std::future::ready(*x))
+        .count()
+        .await;
+
+    assert_eq!(errors, 0);
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let metrics = r
+        .get(format!("http://{}/metrics", x.prometheus_addr))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    info!(%metrics);
+
+    assert_eq!(sample(&metrics, "web3_proxy_requests_in_flight"), Some(0.0));
+    assert_eq!(
+        sample(&metrics, "web3_proxy_incoming_requests_in_flight"),
+        Some(0.0)
+    );
+
+    // the map held up to 100 cache keys at once. it must not keep room for all 10k
+    assert_eq!(x.app.incoming_requests.num_in_flight(), 0);
+    let capacity = x.app.incoming_requests.in_flight_capacity();
+    assert!(capacity <= MIN_IN_FLIGHT_CAPACITY * 4, "{}", capacity);
+
+    // TestApp's response_cache_max_bytes
+    let cache_bytes = sample(&metrics, "web3_proxy_response_cache_bytes").unwrap();
+    assert!(cache_bytes <= 10_u64.pow(7) as f64, "{}", cache_bytes);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,184 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::entities::{rpc_key, user, user_tier};
+use web3_proxy::prelude::ethers::prelude::{Address, U256};
+use web3_proxy::prelude::hashbrown::HashMap;
+use web3_proxy::prelude::migration::sea_orm::{self, ActiveModelTrait};
+use web3_proxy::prelude::parking_lot::Mutex;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy::secrets::RpcSecretKey;
+use web3_proxy_cli::test_utils::top_config::http_rpc_config;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestAnvil, TestApp, TestMysql};
+
+/// eth_getBalance requests for each address
+type BalanceRequests = Mutex<HashMap<String, u64>>;
+
+/// Forwards to anvil, but takes long enough on eth_getBalance that identical requests overlap
+async fn slow_backend(balance_requests: Arc<BalanceRequests>, request: MockRequest) -> Response {
+    if request.method() == "eth_getBalance" {
+        let address = request.body["params"][0].as_str().unwrap().to_lowercase();
+
+        *balance_requests.lock().entry(address).or_default() += 1;
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    request.forward().await
+}
+
+/// a user on its own tier with one key
+async fn create_key(
+    db: &TestMysql,
+    address: Address,
+    allow_skip_request_coalescing: bool,
+    skip_request_coalescing: bool,
+) -> Ulid {
+    let db_conn = db.conn().await;
+
+    let tier = user_tier::ActiveModel {
+        title: sea_orm::Set(format!("Coalescing {}", address)),
+        max_requests_per_period: sea_orm::Set(None),
+        max_concurrent_requests: sea_orm::Set(None),
+        downgrade_tier_id: sea_orm::Set(None),
+        allow_skip_request_coalescing: sea_orm::Set(allow_skip_request_coalescing),
+        ..Default::default()
+    }
+    .save(&db_conn)
+    .await
+    .unwrap();
+
+    let u = user::ActiveModel {
+        address: sea_orm::Set(address.to_fixed_bytes().into()),
+        user_tier_id: tier.id,
+        ..Default::default()
+    }
+    .save(&db_conn)
+    .await
+    .unwrap();
+
+    let rpc_secret_key = RpcSecretKey::new();
+
+    rpc_key::ActiveModel {
+        user_id: u.id,
+        secret_key: sea_orm::Set(rpc_secret_key.into()),
+        skip_request_coalescing: sea_orm::Set(skip_request_coalescing),
+        ..Default::default()
+    }
+    .save(&db_conn)
+    .await
+    .unwrap();
+
+    rpc_secret_key.into()
+}
+
+/// send two identical eth_getBalance requests at the same time and count how many reached the backend
+async fn concurrent_balance_requests(
+    x: &TestApp,
+    balance_requests: &BalanceRequests,
+    key: Ulid,
+    account: Address,
+) -> u64 {
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    let url = format!("{}rpc/{}", x.proxy_provider.url(), key);
+
+    let request_body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_getBalance",
+        "params": [account, "latest"],
+    });
+
+    let request = || async {
+        let body: Value = r
+            .post(&url)
+            .json(&request_body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        info!(%key, %body);
+
+        serde_json::from_value::<U256>(body["result"].clone()).unwrap()
+    };
+
+    let (first, second) = tokio::join!(request(), request());
+
+    assert_eq!(first, second);
+
+    balance_requests
+        .lock()
+        .get(&format!("{:?}", account))
+        .copied()
+        .unwrap_or_default()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_request_coalescing() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // a fresh head so the consensus isn't stale
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    let db = TestMysql::spawn().await;
+
+    let balance_requests = Arc::new(BalanceRequests::default());
+
+    let backend_url = spawn_mock_backend(&a, balance_requests.clone(), slow_backend);
+
+    let flagged_key = create_key(&db, a.wallet(0).address(), true, true).await;
+    let unflagged_key = create_key(&db, a.wallet(1).address(), true, false).await;
+    // the key asks to skip, but its tier doesn't allow it
+    let ungated_key = create_key(&db, a.wallet(2).address(), false, true).await;
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.balanced_rpcs =
+        HashMap::from([("slow_backend".to_string(), http_rpc_config(backend_url))]);
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // every check uses a different account so that nothing is already cached
+    assert_eq!(
+        concurrent_balance_requests(
+            &x,
+            &balance_requests,
+            unflagged_key,
+            Address::repeat_byte(1)
+        )
+        .await,
+        1,
+        "unflagged keys should coalesce"
+    );
+
+    assert_eq!(
+        concurrent_balance_requests(&x, &balance_requests, flagged_key, Address::repeat_byte(2))
+            .await,
+        2,
+        "flagged keys should both reach the backend"
+    );
+
+    assert_eq!(
+        concurrent_balance_requests(&x, &balance_requests, ungated_key, Address::repeat_byte(3))
+            .await,
+        1,
+        "the flag should be ignored without the tier's entitlement"
+    );
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,135 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::frontend::rpc_proxy_http::COST_HEADER;
+use web3_proxy::prelude::entities::rpc_accounting_v2;
+use web3_proxy::prelude::ethers::signers::Signer;
+use web3_proxy::prelude::migration::sea_orm::{
+    prelude::Decimal, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::prelude::ulid::Ulid;
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy_cli::test_utils::{create_user::create_user, TestAnvil, TestApp, TestMysql};
+
+async fn saved_cost(db_conn: &DatabaseConnection, rpc_key_id: u64) -> Decimal {
+    rpc_accounting_v2::Entity::find()
+        .filter(rpc_accounting_v2::Column::RpcKeyId.eq(rpc_key_id))
+        .all(db_conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|x| x.sum_incl_free_credits_used)
+        .sum()
+}
+
+/// the cost header matches what is saved to the accounting rows
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_request_cost() {
+    // chain_id 999_001_999 costs $.10/CU
+    let a = TestAnvil::spawn(999_001_999).await;
+
+    let db = TestMysql::spawn().await;
+
+    let db_conn = db.conn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key = user_login_response.rpc_keys.values().next().unwrap();
+
+    let rpc_url = format!(
+        "{}rpc/{}",
+        x.proxy_provider.url(),
+        Ulid::from(rpc_key.secret_key)
+    );
+
+    let requests = [
+        json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [user_wallet.address(), "latest"]}),
+        // the same again is a cache hit
+        json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBalance", "params": [user_wallet.address(), "latest"]}),
+        json!({"jsonrpc": "2.0", "id": 3, "method": "eth_blockNumber", "params": []}),
+        json!({"jsonrpc": "2.0", "id": 4, "method": "eth_getBlockByNumber", "params": ["0x0", false]}),
+        json!({"jsonrpc": "2.0", "id": 5, "method": "eth_chainId", "params": []}),
+        json!([
+            {"jsonrpc": "2.0", "id": 6, "method": "eth_gasPrice", "params": []},
+            {"jsonrpc": "2.0", "id": 7, "method": "eth_getCode", "params": [user_wallet.address(), "latest"]},
+        ]),
+    ];
+
+    let mut costs = vec![];
+
+    for request in requests {
+        let before = saved_cost(&db_conn, rpc_key.id).await;
+
+        let response = r.post(&rpc_url).json(&request).send().await.unwrap();
+
+        let cost: Decimal = response
+            .headers()
+            .get(COST_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let body: Value = response.json().await.unwrap();
+        info!(%request, %body, %cost);
+
+        let flushed = x.flush_stats_and_wait().await.unwrap();
+        info!(?flushed);
+
+        let after = saved_cost(&db_conn, rpc_key.id).await;
+
+        assert_eq!(after - before, cost, "{}", request);
+
+        costs.push(cost);
+    }
+
+    // cache hits are cheaper
+    assert!(costs[0] > costs[1]);
+    assert!(costs[1] > Decimal::ZERO);
+    assert_eq!(costs[4], Decimal::ZERO);
+
+    // estimates use the same prices
+    let client = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(user_login_response.bearer_token.to_string());
+
+    let estimate = client
+        .estimate(&json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [user_wallet.address(), "latest"]},
+            {"jsonrpc": "2.0", "id": 2, "method": "eth_sendRawTransaction", "params": ["0x"]},
+        ]))
+        .await
+        .unwrap();
+    info!(?estimate);
+
+    let get_balance = &estimate.requests[0];
+    assert_eq!(get_balance.method, "eth_getBalance");
+    assert!(get_balance.estimate.known_method);
+    assert_eq!(get_balance.estimate.cache_miss, costs[0]);
+    assert_eq!(get_balance.estimate.cache_hit, costs[1]);
+
+    // transactions are never cached
+    let send_raw = &estimate.requests[1].estimate;
+    assert_eq!(send_raw.cache_hit, send_raw.cache_miss);
+
+    // nothing was sent, so nothing more was charged
+    let before = saved_cost(&db_conn, rpc_key.id).await;
+    x.flush_stats_and_wait().await.unwrap();
+    assert_eq!(saved_cost(&db_conn, rpc_key.id).await, before);
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
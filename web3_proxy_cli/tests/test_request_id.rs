@@ -0,0 +1,46 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::json;
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_request_id_header() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let mut ids = vec![];
+
+    for _ in 0..2 {
+        let response = r
+            .post(x.proxy_provider.url().clone())
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}))
+            .send()
+            .await
+            .unwrap();
+
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        info!(%request_id);
+
+        assert_eq!(request_id.len(), 26, "request ids are ulids");
+
+        ids.push(request_id);
+    }
+
+    assert_ne!(ids[0], ids[1]);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,129 @@
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::sync::Arc;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::H256;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// eth_call to this address gets the quirky revert message
+const REVERTING_ADDRESS: &str = "0x000000000000000000000000000000000000dead";
+
+/// Forwards to anvil, but answers like the vendors with known quirks do
+async fn quirky_vendor(_: Arc<()>, request: MockRequest) -> Response {
+    if request.method() == "eth_call" && request.body["params"][0]["to"] == REVERTING_ADDRESS {
+        return request.error(json!({
+            "code": -32000,
+            "message": "VM Exception while processing transaction: revert not enough balance",
+        }));
+    }
+
+    let mut response = request.anvil_response().await;
+
+    if request.method() == "eth_getTransactionReceipt" && response["result"].is_null() {
+        response["result"] = json!("0x");
+    }
+
+    Json(response).into_response()
+}
+
+async fn proxy_request(x: &TestApp, method: &str, params: Value) -> Value {
+    let body: Value = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    info!(%method, %body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_response_rewrites() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // a fresh head so the consensus isn't stale
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    let vendor_url = spawn_mock_backend(&a, Arc::new(()), quirky_vendor);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "response_rewrites": [
+                {
+                    "name": "empty_receipt",
+                    "method": "eth_getTransactionReceipt",
+                    "rpcs": ["quirky_vendor"],
+                    "pointer": "/result",
+                    "equals": "0x",
+                },
+                {
+                    "name": "vm_exception_revert",
+                    "method": "eth_call",
+                    "pointer": "/error/message",
+                    "regex": "^VM Exception while processing transaction: revert ?(.*)$",
+                    "replace": "execution reverted: $1",
+                },
+            ],
+        }))
+        .http_rpc("quirky_vendor", vendor_url.clone())
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // the vendor really does send "0x"
+    let vendor_body: Value = reqwest::Client::new()
+        .post(&vendor_url)
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionReceipt", "params": [H256::repeat_byte(1)]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(vendor_body["result"], "0x");
+
+    // but users see null. twice, so the second one comes from the cache
+    for _ in 0..2 {
+        let body = proxy_request(
+            &x,
+            "eth_getTransactionReceipt",
+            json!([H256::repeat_byte(1)]),
+        )
+        .await;
+
+        assert!(body["result"].is_null(), "{}", body);
+    }
+
+    // reverts are worded like everyone else's. the rest of the error is kept
+    let body = proxy_request(
+        &x,
+        "eth_call",
+        json!([{"to": REVERTING_ADDRESS, "data": "0x"}, "latest"]),
+    )
+    .await;
+
+    assert_eq!(body["error"]["code"], -32000);
+    assert_eq!(
+        body["error"]["message"],
+        "execution reverted: not enough balance"
+    );
+
+    // everything else passes through untouched
+    let body = proxy_request(&x, "eth_chainId", json!([])).await;
+    assert_eq!(body["result"], "0x7a69");
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::{Block, TxHash, U64},
+    providers::{Middleware, Provider, Ws},
+};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+/// wait until the proxy's consensus head reaches `num`
+async fn wait_for_head(x: &TestApp, num: U64) {
+    for _ in 0..100 {
+        let head: U64 = x
+            .proxy_provider
+            .request("eth_blockNumber", ())
+            .await
+            .unwrap();
+
+        if head >= num {
+            return;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("proxy never saw block {}", num);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_resubscribe_replays_missed_heads() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    // follow heads like a normal client
+    let first = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let mut heads = first.subscribe_blocks().await.unwrap();
+
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+    let last_seen: Block<TxHash> = heads.next().await.unwrap();
+    let last_block = last_seen.number.unwrap();
+    info!(%last_block, "seen before the disconnect");
+
+    // the network blips. two blocks are mined while the client is gone
+    drop(heads);
+    drop(first);
+
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+    wait_for_head(&x, last_block + 2).await;
+
+    // reconnect and catch up
+    let second = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let resubscribed: Value = second
+        .request(
+            "web3proxy_resubscribe",
+            json!({"type": "newHeads", "last_block": last_block}),
+        )
+        .await
+        .unwrap();
+    info!(?resubscribed);
+
+    assert!(resubscribed["subscription"].is_string());
+    assert_eq!(resubscribed["gap"], Value::Null);
+
+    let replayed: Vec<U64> = resubscribed["heads"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| serde_json::from_value(x["number"].clone()).unwrap())
+        .collect();
+
+    assert_eq!(replayed, vec![last_block + 1, last_block + 2]);
+
+    // only newHeads can be replayed
+    let err = second
+        .request::<_, Value>(
+            "web3proxy_resubscribe",
+            json!({"type": "newPendingTransactions", "last_block": last_block}),
+        )
+        .await;
+    assert!(err.is_err());
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,134 @@
+use tracing::info;
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// owners can see and edit their key's label. only admins can see the internal tags
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_rpc_key_labels() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+    let admin_wallet = a.wallet(1);
+
+    let user_login = create_user(&x, &r, &user_wallet, None).await;
+    let admin_login = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login).await;
+
+    assert_eq!(rpc_key.label, None);
+
+    let keys_url = format!("{}user/keys", x.proxy_provider.url());
+
+    let updated: Value = r
+        .put(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "label": " data-team ",
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?updated);
+
+    assert_eq!(updated["label"], json!("data-team"));
+    assert!(updated.get("internal_tags").is_none());
+
+    let too_long = r
+        .put(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "label": "x".repeat(65),
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(too_long.status(), StatusCode::BAD_REQUEST);
+
+    let tags_url = format!(
+        "{}admin/rpc_key_tags/{}",
+        x.proxy_provider.url(),
+        rpc_key.id
+    );
+
+    // users can't set their own tags
+    let not_admin = r
+        .post(&tags_url)
+        .bearer_auth(user_login.bearer_token)
+        .json(&json!({"internal_tags": ["trusted"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(not_admin.status(), StatusCode::FORBIDDEN);
+
+    let tagged: Value = r
+        .post(&tags_url)
+        .bearer_auth(admin_login.bearer_token)
+        .json(&json!({"internal_tags": ["abuse-watch", " ", "vip"]}))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?tagged);
+
+    assert_eq!(tagged["internal_tags"], json!(["abuse-watch", "vip"]));
+    assert_eq!(tagged["label"], json!("data-team"));
+
+    let fetched: Value = r
+        .get(&tags_url)
+        .bearer_auth(admin_login.bearer_token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(fetched, tagged);
+
+    // the owner still only sees the label
+    let keys: Value = r
+        .get(&keys_url)
+        .bearer_auth(user_login.bearer_token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?keys);
+
+    let key = &keys["user_rpc_keys"][rpc_key.id.to_string()];
+
+    assert_eq!(key["label"], json!("data-team"));
+    assert!(key.get("internal_tags").is_none());
+    assert!(!keys.to_string().contains("abuse-watch"));
+
+    x.wait_for_stop();
+}
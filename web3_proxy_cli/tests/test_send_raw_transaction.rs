@@ -0,0 +1,155 @@
+use axum::response::Response;
+use std::sync::Arc;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::{
+    prelude::{Bytes, U256},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::top_config::http_rpc_config;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// eth_sendRawTransaction always fails with `send_error`. everything else is passed straight through
+async fn rejecting_vendor(send_error: Arc<Value>, request: MockRequest) -> Response {
+    if request.method() == "eth_sendRawTransaction" {
+        return request.error((*send_error).clone());
+    }
+
+    request.forward().await
+}
+
+fn spawn_rejecting_vendor(a: &TestAnvil, send_error: Value) -> Web3RpcConfig {
+    http_rpc_config(spawn_mock_backend(
+        a,
+        Arc::new(send_error),
+        rejecting_vendor,
+    ))
+}
+
+async fn spawn_app(a: &TestAnvil, private_rpcs: Vec<(&str, Web3RpcConfig)>) -> TestApp {
+    let mut top_config = TopConfigBuilder::new(31337).anvil_rpc("anvil", a);
+
+    for (name, rpc) in private_rpcs {
+        top_config = top_config.private_rpc(name, rpc);
+    }
+
+    TestApp::spawn_with_top_config(top_config.build()).await
+}
+
+/// a signed transaction and its hash
+async fn signed_tx(a: &TestAnvil, x: &TestApp) -> (Bytes, Value) {
+    let wallet = a.wallet(0);
+
+    let gas_price: U256 = x.proxy_provider.request("eth_gasPrice", ()).await.unwrap();
+
+    let tx = TypedTransaction::Eip1559(Eip1559TransactionRequest {
+        chain_id: Some(31337.into()),
+        to: Some(Address::repeat_byte(0x42).into()),
+        gas: Some(21000.into()),
+        value: Some(1.into()),
+        max_fee_per_gas: Some(gas_price * U256::from(2)),
+        nonce: Some(0.into()),
+        ..Default::default()
+    });
+
+    let sig = wallet.sign_transaction_sync(&tx).unwrap();
+
+    (tx.rlp_signed(&sig), json!(tx.hash(&sig)))
+}
+
+async fn send(x: &TestApp, raw_tx: &Bytes) -> Value {
+    let body: Value = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendRawTransaction",
+            "params": [raw_tx],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    info!(%body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_one_acceptance_is_enough() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let internal_error =
+        spawn_rejecting_vendor(&a, json!({"code": -32603, "message": "internal error"}));
+    let nonce_error =
+        spawn_rejecting_vendor(&a, json!({"code": -32000, "message": "nonce too low"}));
+
+    let x = spawn_app(
+        &a,
+        vec![
+            ("internal_error", internal_error),
+            ("nonce_error", nonce_error),
+            ("anvil_private", http_rpc_config(a.instance.endpoint())),
+        ],
+    )
+    .await;
+
+    let (raw_tx, tx_hash) = signed_tx(&a, &x).await;
+
+    // anvil takes it. the other rejections don't matter
+    let body = send(&x, &raw_tx).await;
+    assert_eq!(body["result"], tx_hash, "{}", body);
+
+    // now that it is mined, everyone rejects it. the most useful rejection wins
+    let body = send(&x, &raw_tx).await;
+    assert!(body["result"].is_null(), "{}", body);
+    assert!(
+        body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("nonce too low"),
+        "{}",
+        body
+    );
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_already_known_is_success() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let already_known =
+        spawn_rejecting_vendor(&a, json!({"code": -32000, "message": "already known"}));
+    let already_imported = spawn_rejecting_vendor(
+        &a,
+        json!({"code": -32010, "message": "Transaction with the same hash was already imported."}),
+    );
+
+    let x = spawn_app(
+        &a,
+        vec![
+            ("already_known", already_known),
+            ("already_imported", already_imported),
+        ],
+    )
+    .await;
+
+    let (raw_tx, tx_hash) = signed_tx(&a, &x).await;
+
+    // the hash is computed locally. neither backend returned it
+    let body = send(&x, &raw_tx).await;
+    assert_eq!(body["result"], tx_hash, "{}", body);
+
+    x.wait_for_stop();
+}
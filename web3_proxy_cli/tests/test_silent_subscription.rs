@@ -0,0 +1,156 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::providers::Middleware;
+use web3_proxy::prelude::ethers::types::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Instant},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::mock_backend::{serve, AnvilHttp};
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+struct Vendor {
+    anvil: AnvilHttp,
+    subscriptions: AtomicUsize,
+}
+
+async fn silent_ws(State(vendor): State<Arc<Vendor>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| silent_ws_socket(vendor, socket))
+}
+
+/// Accepts subscriptions but never sends anything on them. Every other request is answered by anvil
+async fn silent_ws_socket(vendor: Arc<Vendor>, mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let Message::Text(message) = message else {
+            continue;
+        };
+
+        let request: Value = serde_json::from_str(&message).unwrap();
+
+        let response = match request["method"].as_str() {
+            Some("eth_subscribe") => {
+                let id = vendor.subscriptions.fetch_add(1, Ordering::SeqCst) + 1;
+
+                json!({"jsonrpc": "2.0", "id": request["id"], "result": format!("{:#x}", id)})
+            }
+            Some("eth_unsubscribe") => {
+                json!({"jsonrpc": "2.0", "id": request["id"], "result": true})
+            }
+            _ => vendor.anvil.request(&request).await,
+        };
+
+        if socket
+            .send(Message::Text(response.to_string()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn spawn_silent_vendor(a: &TestAnvil) -> (String, Arc<Vendor>) {
+    let vendor = Arc::new(Vendor {
+        anvil: AnvilHttp::new(a),
+        subscriptions: AtomicUsize::new(0),
+    });
+
+    let router = Router::new()
+        .route("/", get(silent_ws))
+        .with_state(vendor.clone());
+
+    let url = format!("ws://{}", serve(router));
+
+    (url, vendor)
+}
+
+async fn proxy_block_number(r: &reqwest::Client, x: &TestApp) -> Option<U64> {
+    let body: Value = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []}))
+        .send()
+        .await
+        .ok()?
+        .json()
+        .await
+        .ok()?;
+
+    info!(%body);
+
+    serde_json::from_value(body["result"].clone()).ok()
+}
+
+/// a websocket that stays connected but stops sending heads is noticed and resubscribed
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_silent_subscription() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // a fresh head so the consensus isn't stale
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    let (vendor_url, vendor) = spawn_silent_vendor(&a);
+
+    // http goes straight to anvil. only the websocket is broken
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "block_interval_ms": 100,
+            "silent_head_subscription_blocks": 3,
+        }))
+        .balanced_rpc(
+            "silent_vendor",
+            Web3RpcConfig {
+                http_url: Some(a.instance.endpoint()),
+                ws_url: Some(vendor_url),
+                ..Default::default()
+            },
+        )
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // the node advances, but the subscription never says so
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    let anvil_head = a.provider.get_block_number().await.unwrap();
+
+    // the proxy notices and catches up by resubscribing
+    let start = Instant::now();
+    loop {
+        if proxy_block_number(&r, &x).await == Some(anvil_head) {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("the proxy never noticed the silent subscription");
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert!(vendor.subscriptions.load(Ordering::SeqCst) >= 2);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,164 @@
+use axum::response::Response;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// eth_getBalance answers with far more data than it should. everything else is passed through
+async fn bloated_vendor(huge_responses: Arc<AtomicUsize>, request: MockRequest) -> Response {
+    if request.method() == "eth_getBalance" {
+        huge_responses.fetch_add(1, Ordering::SeqCst);
+
+        return request.result(json!(format!("0x{}", "f".repeat(200_000))));
+    }
+
+    request.forward().await
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_size_limits() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let huge_responses = Arc::new(AtomicUsize::new(0));
+
+    let vendor_url = spawn_mock_backend(&a, huge_responses.clone(), bloated_vendor);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "max_batch_size": 3,
+            "max_request_bytes": 4_096,
+            "max_response_bytes": 100_000,
+        }))
+        .http_rpc("bloated_vendor", vendor_url)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::new();
+
+    let url = x.proxy_provider.url().clone();
+
+    // a body past max_request_bytes
+    let response = r
+        .post(url.clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": [{"data": format!("0x{}", "00".repeat(4_096))}, "latest"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let body: Value = response.json().await.unwrap();
+    info!(%body);
+    assert_eq!(body["error"]["code"], json!(-32600));
+    assert_eq!(body["error"]["data"]["max_request_bytes"], json!(4_096));
+
+    // a batch past max_batch_size
+    let batch: Vec<_> = (0..4)
+        .map(|i| json!({"jsonrpc": "2.0", "id": i, "method": "eth_chainId", "params": []}))
+        .collect();
+
+    let response = r.post(url.clone()).json(&batch).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let body: Value = response.json().await.unwrap();
+    info!(%body);
+    assert_eq!(body["error"]["data"]["batch_size"], json!(4));
+
+    // a batch at the limit is fine
+    let response: Value = r
+        .post(url.clone())
+        .json(&batch[..3])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.as_array().unwrap().len(), 3);
+
+    // a response past max_response_bytes
+    let response = r
+        .post(url.clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%body);
+    assert_eq!(body["error"]["code"], json!(-32005));
+    assert_eq!(body["error"]["data"]["max_response_bytes"], json!(100_000));
+
+    // it isn't retried. every backend would send the same thing
+    assert_eq!(huge_responses.load(Ordering::SeqCst), 1);
+
+    // and it isn't counted as a backend error
+    let metrics = r
+        .get(format!("http://{}/metrics", x.prometheus_addr))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("web3_proxy_too_large_total{kind=\"request\"} 1\n"));
+    assert!(metrics.contains("web3_proxy_too_large_total{kind=\"batch\"} 1\n"));
+    assert!(metrics.contains("web3_proxy_too_large_total{kind=\"response\"} 1\n"));
+
+    x.wait_for_stop();
+}
+
+/// Each oversized batch is a violation. Enough of them get the ip banned
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_oversized_batches_are_banned() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "ban_score_threshold": 100,
+            "max_batch_size": 3,
+        }))
+        .anvil_rpc("anvil", &a)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::new();
+
+    let url = x.proxy_provider.url().clone();
+
+    let batch: Vec<_> = (0..4)
+        .map(|i| json!({"jsonrpc": "2.0", "id": i, "method": "eth_chainId", "params": []}))
+        .collect();
+
+    // each one is worth 25
+    for _ in 0..4 {
+        let response = r.post(url.clone()).json(&batch).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    assert_eq!(x.app.bans.num_active(), 1);
+
+    // now even a small request is rejected
+    let response = r
+        .post(url.clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let body: Value = response.json().await.unwrap();
+    info!(%body);
+    assert!(body["error"]["data"]["retry_after"].as_u64().unwrap() > 0);
+
+    x.wait_for_stop();
+}
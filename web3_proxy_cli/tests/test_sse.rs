@@ -0,0 +1,152 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, timeout},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+#[derive(Debug)]
+struct SseEvent {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+}
+
+/// read one server-sent event. keep-alive comments are skipped
+async fn next_event(response: &mut reqwest::Response, buf: &mut String) -> SseEvent {
+    loop {
+        while let Some(i) = buf.find("\n\n") {
+            let raw: String = buf.drain(..i + 2).collect();
+
+            let mut event = SseEvent {
+                event: None,
+                data: String::new(),
+                id: None,
+            };
+
+            for line in raw.lines() {
+                if let Some(x) = line.strip_prefix("event:") {
+                    event.event = Some(x.trim().to_string());
+                } else if let Some(x) = line.strip_prefix("data:") {
+                    event.data.push_str(x.trim());
+                } else if let Some(x) = line.strip_prefix("id:") {
+                    event.id = Some(x.trim().to_string());
+                }
+            }
+
+            if event.event.is_some() {
+                return event;
+            }
+        }
+
+        let chunk = response
+            .chunk()
+            .await
+            .unwrap()
+            .expect("stream should not end");
+
+        buf.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+}
+
+async fn wait_for_proxy_head(x: &TestApp, num: u64) {
+    for _ in 0..100 {
+        let head: U64 = x
+            .proxy_provider
+            .request("eth_blockNumber", ())
+            .await
+            .unwrap();
+
+        if head.as_u64() >= num {
+            return;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("proxy never saw block {}", num);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_sse_heads_gap_detection() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let url = format!("{}sse/heads", x.proxy_provider.url());
+
+    let mut response = r.get(&url).send().await.unwrap();
+    assert!(response.status().is_success(), "{:?}", response);
+
+    let mut buf = String::new();
+
+    // the current head is sent right away
+    let first = timeout(Duration::from_secs(10), next_event(&mut response, &mut buf))
+        .await
+        .unwrap();
+    info!(?first);
+    assert_eq!(first.event.as_deref(), Some("newHeads"));
+
+    let start: u64 = first.id.unwrap().parse().unwrap();
+
+    // read a few more, one block at a time
+    let mut last_id = start;
+    while last_id < start + 3 {
+        a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+        let event = timeout(Duration::from_secs(10), next_event(&mut response, &mut buf))
+            .await
+            .unwrap();
+
+        if event.event.as_deref() == Some("newHeads") {
+            last_id = event.id.unwrap().parse().unwrap();
+        }
+    }
+
+    drop(response);
+
+    // these blocks are missed while disconnected
+    for _ in 0..3 {
+        a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+    }
+
+    wait_for_proxy_head(&x, last_id + 3).await;
+
+    let mut response = r
+        .get(&url)
+        .header("last-event-id", last_id.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{:?}", response);
+
+    let mut buf = String::new();
+
+    let gap = timeout(Duration::from_secs(10), next_event(&mut response, &mut buf))
+        .await
+        .unwrap();
+    info!(?gap);
+    assert_eq!(gap.event.as_deref(), Some("gap"));
+
+    let gap: serde_json::Value = serde_json::from_str(&gap.data).unwrap();
+    assert_eq!(gap["first"], json!(U64::from(last_id + 1)));
+    assert_eq!(gap["last"], json!(U64::from(last_id + 2)));
+
+    let head = timeout(Duration::from_secs(10), next_event(&mut response, &mut buf))
+        .await
+        .unwrap();
+    assert_eq!(head.event.as_deref(), Some("newHeads"));
+    assert_eq!(head.id, Some((last_id + 3).to_string()));
+
+    drop(response);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// eth_getBalance at "latest". returns the backends that answered (empty for a cache hit) and the stale head header
+async fn get_balance(r: &reqwest::Client, x: &TestApp, address: &str) -> (String, Option<String>) {
+    let response = r
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, "latest"]}))
+        .send()
+        .await
+        .unwrap();
+
+    let headers = response.headers();
+
+    let rpcs = headers
+        .get("X-W3P-BACKEND-RPCS")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let stale_head = headers
+        .get("X-W3P-STALE-HEAD")
+        .map(|x| x.to_str().unwrap().to_string());
+
+    let body: Value = response.json().await.unwrap();
+
+    assert!(body["result"].is_string(), "{}", body);
+
+    (rpcs, stale_head)
+}
+
+async fn health(r: &reqwest::Client, x: &TestApp) -> StatusCode {
+    r.get(format!("{}health", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn wait_for_consensus_head(r: &reqwest::Client, x: &TestApp, num: u64) {
+    for _ in 0..300 {
+        let status: Value = r
+            .get(format!("{}status", x.proxy_provider.url()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        if status["head_block_num"] == json!(U64::from(num)) {
+            return;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("consensus head never became {}", num);
+}
+
+/// anvil only makes blocks when told to, so not mining is a chain halt
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_stale_head_is_not_cached() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({"stale_head_ms": 2_000}))
+        .anvil_rpc("anvil", &a)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::new();
+
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+    wait_for_consensus_head(&r, &x, 1).await;
+
+    // fresh head. the second request is a cache hit
+    let address_1 = "0x0000000000000000000000000000000000000001";
+
+    let (rpcs, stale_head) = get_balance(&r, &x, address_1).await;
+    assert_eq!(rpcs, "anvil");
+    assert_eq!(stale_head, None);
+
+    let (rpcs, _) = get_balance(&r, &x, address_1).await;
+    assert_eq!(rpcs, "");
+
+    assert_eq!(health(&r, &x).await, StatusCode::OK);
+
+    // no new heads
+    sleep(Duration::from_secs(3)).await;
+
+    assert_eq!(health(&r, &x).await, StatusCode::SERVICE_UNAVAILABLE);
+
+    // stale head. nothing new goes into the cache
+    let address_2 = "0x0000000000000000000000000000000000000002";
+
+    for _ in 0..2 {
+        let (rpcs, stale_head) = get_balance(&r, &x, address_2).await;
+        assert_eq!(rpcs, "anvil");
+        assert!(stale_head.is_some());
+    }
+
+    // a new head clears it
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+    wait_for_consensus_head(&r, &x, 2).await;
+
+    // the health page is cached briefly
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(health(&r, &x).await, StatusCode::OK);
+
+    let (rpcs, stale_head) = get_balance(&r, &x, address_2).await;
+    assert_eq!(rpcs, "anvil");
+    assert_eq!(stale_head, None);
+
+    let (rpcs, _) = get_balance(&r, &x, address_2).await;
+    assert_eq!(rpcs, "");
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::signers::Signer;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    time::{sleep, Instant},
+};
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy::standby::WarmRequest;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// the status code, the body, and the names of the backends that served this request. the names are empty if it came
+/// from the cache
+async fn proxy_request(
+    r: &reqwest::Client,
+    x: &TestApp,
+    request: &Value,
+) -> (StatusCode, Value, Option<String>) {
+    let response = r
+        .post(x.proxy_provider.url().as_str())
+        .json(request)
+        .send()
+        .await
+        .unwrap();
+
+    let status = response.status();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string());
+
+    let body: Value = response.json().await.unwrap();
+    info!(%request, %status, %body, ?backend_rpcs);
+
+    (status, body, backend_rpcs)
+}
+
+async fn health(r: &reqwest::Client, x: &TestApp) -> (StatusCode, String) {
+    let response = r
+        .get(format!("{}health", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap();
+
+    (response.status(), response.text().await.unwrap())
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_promote_standby() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let balance_of = a.wallet(0).address();
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+    top_config.app.standby = true;
+    top_config.app.standby_warm_requests = vec![WarmRequest {
+        method: "eth_getBalance".to_string(),
+        params: json!([balance_of, "latest"]),
+    }];
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // wait for the standby to warm its cache
+    let status_url = format!("{}status", x.proxy_provider.url());
+    let start = Instant::now();
+    loop {
+        let status: Value = r
+            .get(&status_url)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        info!(standby=%status["standby"]);
+
+        assert_eq!(status["standby"]["standby"], true);
+
+        if status["standby"]["warmed"].as_u64().unwrap() > 0 {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("the standby never warmed its cache");
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [balance_of, "latest"]});
+
+    // jsonrpc is turned away
+    let (status, body, _) = proxy_request(&r, &x, &request).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["error"]["data"]["reason"], "standby");
+
+    // load balancers can tell a healthy standby from a broken server
+    assert_eq!(
+        health(&r, &x).await,
+        (StatusCode::SERVICE_UNAVAILABLE, "standby\n".to_string())
+    );
+
+    // admins can still log in while on standby
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &a.wallet(1)).await;
+
+    let promoted = Web3ProxyClient::new(x.proxy_provider.url().clone())
+        .with_http_client(r.clone())
+        .with_bearer_token(admin_login_response.bearer_token.to_string())
+        .admin_set_standby(false)
+        .await
+        .unwrap();
+    info!(?promoted);
+
+    assert_eq!(promoted.previous, Some(true));
+    assert!(!promoted.standby);
+
+    // the very first request is served from the warm cache
+    let (status, body, backend_rpcs) = proxy_request(&r, &x, &request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["result"].is_string(), "{}", body);
+    assert_eq!(backend_rpcs.as_deref(), Some(""));
+
+    // health is cached for a moment
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(health(&r, &x).await, (StatusCode::OK, "OK\n".to_string()));
+
+    // drop the app first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::balance::Balance;
+use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::Value;
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::{
+    create_user::create_user, rpc_key::user_get_provider, user_balance::user_get_balance,
+    TestAnvil, TestApp, TestMysql,
+};
+
+/// accounting that fails while the database is down is saved once it comes back
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_stat_retry() {
+    let a = TestAnvil::spawn(999_001_999).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(0);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+
+    let user_proxy_provider = user_get_provider(&x, &r, &user_login_response)
+        .await
+        .unwrap();
+
+    // the first request fills the caches that are needed while the database is down
+    user_proxy_provider
+        .request::<_, Option<U64>>("eth_blockNumber", ())
+        .await
+        .unwrap();
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+    assert_eq!(flushed.relational_frontend_requests, 1);
+    assert_eq!(flushed.relational_retry_queue, 0);
+
+    db.kill().await;
+
+    info!("making requests while the database is down");
+    for _ in 0..10 {
+        user_proxy_provider
+            .request::<_, Option<U64>>("eth_blockNumber", ())
+            .await
+            .unwrap();
+    }
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+    assert_eq!(flushed.relational_frontend_requests, 0);
+    assert!(flushed.relational_retry_queue > 0);
+    assert_eq!(flushed.relational_dropped, 0);
+
+    let status: Value = r
+        .get(format!("{}status", x.proxy_provider.url()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(stat_buffer=%status["stat_buffer"]);
+
+    assert!(
+        status["stat_buffer"]["relational_retry_queue"]
+            .as_u64()
+            .unwrap()
+            > 0
+    );
+    assert!(status["stat_buffer"]["relational_retry_oldest_secs"].is_u64());
+    assert_eq!(status["stat_buffer"]["relational_ok"], false);
+
+    db.start().await;
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+    assert_eq!(flushed.relational_frontend_requests, 10);
+    assert_eq!(flushed.relational_retry_queue, 0);
+    assert_eq!(flushed.relational_retry_oldest_ms, 0);
+
+    // what was served matches what was saved
+    let served: Balance = user_get_balance(&x, &r, &user_login_response).await;
+
+    let saved = Balance::try_from_db(&db.conn().await, user_login_response.user.id)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(served.total_frontend_requests, 11);
+    assert_eq!(saved.total_frontend_requests, 11);
+    assert_eq!(served.total_spent, saved.total_spent);
+    assert_eq!(
+        served.total_spent_paid_credits,
+        saved.total_spent_paid_credits
+    );
+
+    // drop x first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
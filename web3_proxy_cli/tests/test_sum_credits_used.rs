@@ -1,7 +1,8 @@
 use std::time::Duration;
 use tracing::info;
 use web3_proxy::balance::Balance;
-use web3_proxy::prelude::ethers::prelude::U64;
+use web3_proxy::prelude::ethers::prelude::{U256, U64};
+use web3_proxy::prelude::ethers::signers::Signer;
 use web3_proxy::prelude::migration::sea_orm::prelude::Decimal;
 use web3_proxy::prelude::reqwest;
 use web3_proxy::prelude::tokio;
@@ -84,7 +85,7 @@ async fn test_sum_credits_used() {
 
     let query_cost: Decimal = "1.00".parse().unwrap();
 
-    // let archive_multiplier: Decimal = "2.5".parse().unwrap();
+    let archive_multiplier: Decimal = "2.5".parse().unwrap();
 
     let cache_multipler: Decimal = "0.75".parse().unwrap();
 
@@ -194,6 +195,71 @@ async fn test_sum_credits_used() {
     assert!(balance.active_premium(), "active_premium");
     assert!(balance.was_ever_premium(), "was_ever_premium");
 
+    info!("make one archive request of 19 CU");
+    for _ in 0..5 {
+        a.provider.request::<_, U256>("evm_mine", ()).await.unwrap();
+    }
+
+    // the test app's archive_depth is 2, so block 1 is old enough
+    user_proxy_provider
+        .request::<_, U256>("eth_getBalance", (user_wallet.address(), "0x1"))
+        .await
+        .unwrap();
+
+    let archive_query_cost: Decimal = "1.90".parse::<Decimal>().unwrap() * archive_multiplier;
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+
+    let balance: Balance = user_get_balance(&x, &r, &user_login_response).await;
+
+    let expected_total_spent_paid_credits = expected_total_spent_paid_credits + archive_query_cost;
+
+    assert_eq!(
+        balance.total_frontend_requests, 13,
+        "total_frontend_requests"
+    );
+    assert_eq!(balance.total_cache_misses, 1, "total_cache_misses");
+    assert_eq!(
+        balance.total_spent_paid_credits, expected_total_spent_paid_credits,
+        "total_spent_paid_credits"
+    );
+    assert_eq!(
+        balance.remaining(),
+        Decimal::from(1000) - expected_total_spent_paid_credits
+    );
+
+    info!("make one request that fails because the backend is gone");
+    let other_address = a.wallet(2).address();
+
+    // stopping anvil makes the backend error. the proxy's own stats and databases are unaffected
+    drop(a);
+
+    user_proxy_provider
+        .request::<_, U256>("eth_getBalance", (other_address, "latest"))
+        .await
+        .unwrap_err();
+
+    let flushed = x.flush_stats_and_wait().await.unwrap();
+    info!(?flushed);
+
+    let balance: Balance = user_get_balance(&x, &r, &user_login_response).await;
+
+    // the failed request is counted, but it is free
+    assert_eq!(
+        balance.total_frontend_requests, 14,
+        "total_frontend_requests"
+    );
+    assert_eq!(
+        balance.total_spent_paid_credits, expected_total_spent_paid_credits,
+        "total_spent_paid_credits"
+    );
+    assert_eq!(
+        balance.total_spent,
+        expected_total_spent_paid_credits + cached_query_cost,
+        "total_spent"
+    );
+
     // TODO: make enough queries to push the user balance negative
 
     // check admin's balance to make sure nothing is leaking
@@ -217,6 +283,6 @@ async fn test_sum_credits_used() {
 
     // TODO: query "user 0" to get the public counts
 
-    // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
+    // drop x first to avoid spurious warnings about influx/mysql shutting down before the app
     drop(x);
 }
@@ -0,0 +1,131 @@
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::prelude::Signer;
+use web3_proxy::prelude::ethers::types::U64;
+use web3_proxy::prelude::migration::sea_orm::prelude::Decimal;
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::admin_increases_balance::admin_increase_balance;
+use web3_proxy_cli::test_utils::create_admin::create_user_as_admin;
+use web3_proxy_cli::test_utils::create_user::create_user;
+use web3_proxy_cli::test_utils::rpc_key::user_get_first_rpc_key;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+async fn historical_call(
+    r: &reqwest::Client,
+    x: &TestApp,
+    rpc_key: &str,
+    request: &Value,
+) -> (StatusCode, Value) {
+    let url = format!("{}rpc/{}", x.proxy_provider.url(), rpc_key);
+
+    let response = r.post(url).json(request).send().await.unwrap();
+
+    let status = response.status();
+
+    let body: Value = response.json().await.unwrap();
+    info!(%status, %body);
+
+    (status, body)
+}
+
+/// a free user and a premium user send the same eth_call for an old block. only the premium user is answered
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_tier_entitlements() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let free_wallet = a.wallet(0);
+    let premium_wallet = a.wallet(1);
+    let admin_wallet = a.wallet(2);
+
+    let admin_login = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+    let free_login = create_user(&x, &r, &free_wallet, None).await;
+    let premium_login = create_user(&x, &r, &premium_wallet, None).await;
+
+    // users without a balance are downgraded to a tier without archive access
+    admin_increase_balance(
+        &x,
+        &r,
+        &admin_login,
+        &premium_wallet,
+        Decimal::from_str("20").unwrap(),
+    )
+    .await;
+
+    let free_key = user_get_first_rpc_key(&x, &r, &free_login).await;
+    let premium_key = user_get_first_rpc_key(&x, &r, &premium_login).await;
+
+    // mine past the test app's archive_depth
+    for _ in 0..5 {
+        a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+    }
+
+    // give the proxy a moment to see the new head
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_call",
+        "params": [{"to": free_wallet.address(), "data": "0x"}, "0x1"],
+    });
+
+    let (status, body) = historical_call(&r, &x, &free_key.secret_key.to_string(), &request).await;
+
+    assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+    assert_eq!(body["error"]["data"]["entitlement"], json!("archive"));
+    assert_eq!(body["error"]["data"]["method"], json!("eth_call"));
+    assert_eq!(body["error"]["data"]["required_plan"], json!("Premium"));
+
+    let (status, body) =
+        historical_call(&r, &x, &premium_key.secret_key.to_string(), &request).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("error").is_none(), "{}", body);
+    assert_eq!(body["result"], json!("0x"));
+
+    // recent blocks are fine for everyone
+    let latest = json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "eth_call",
+        "params": [{"to": free_wallet.address(), "data": "0x"}, "latest"],
+    });
+
+    let (status, _) = historical_call(&r, &x, &free_key.secret_key.to_string(), &latest).await;
+
+    assert_eq!(status, StatusCode::OK);
+
+    let summary: Value = r
+        .get(format!("{}admin/summary", x.proxy_provider.url()))
+        .bearer_auth(admin_login.bearer_token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(%summary);
+
+    assert_eq!(
+        summary["requests"]["denied_by_tier"],
+        json!([{"method": "eth_call", "count": 1}])
+    );
+
+    // drop the app first to avoid spurious warnings about mysql shutting down before the app
+    drop(x);
+}
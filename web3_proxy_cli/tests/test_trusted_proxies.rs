@@ -0,0 +1,123 @@
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::parking_lot::Mutex;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// the X-Forwarded-For of every eth_getBalance
+type ForwardedFor = Mutex<Vec<Option<String>>>;
+
+async fn recording_vendor(forwarded_for: Arc<ForwardedFor>, request: MockRequest) -> Response {
+    if request.method() == "eth_getBalance" {
+        let x = request
+            .headers
+            .get("x-forwarded-for")
+            .map(|x| x.to_str().unwrap().to_string());
+
+        forwarded_for.lock().push(x);
+    }
+
+    request.forward().await
+}
+
+async fn spawn_app(a: &TestAnvil, trusted_proxies: Value) -> (Arc<ForwardedFor>, TestApp) {
+    let forwarded_for = Arc::new(ForwardedFor::default());
+
+    let vendor_url = spawn_mock_backend(a, forwarded_for.clone(), recording_vendor);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "public_requests_per_period": 3,
+            "rate_limit_store": "memory",
+            "trusted_proxies": trusted_proxies,
+        }))
+        .http_rpc("recording_vendor", vendor_url)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    (forwarded_for, x)
+}
+
+/// a random address so that nothing is served from the cache
+async fn get_balance(r: &reqwest::Client, x: &TestApp, forwarded_for: &str) -> StatusCode {
+    r.post(x.proxy_provider.url().clone())
+        .header("X-Forwarded-For", forwarded_for)
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_forwarded_client_ip() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (forwarded_for, x) = spawn_app(&a, json!(["127.0.0.0/8"])).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // the client made up the first hop. the rightmost hop that isn't a trusted proxy is the client
+    for _ in 0..3 {
+        assert_eq!(
+            get_balance(&r, &x, "1.1.1.1, 192.0.2.1, 127.0.0.2").await,
+            StatusCode::OK
+        );
+    }
+
+    // made up hops don't get the same client a new limit
+    assert_eq!(
+        get_balance(&r, &x, "8.8.8.8, 192.0.2.1").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // a different client has its own limit
+    assert_eq!(get_balance(&r, &x, "192.0.2.2").await, StatusCode::OK);
+
+    // backends see the client. not us
+    let forwarded_for = forwarded_for.lock().clone();
+    assert_eq!(forwarded_for.len(), 4);
+    assert!(forwarded_for[..3]
+        .iter()
+        .all(|x| x.as_deref() == Some("192.0.2.1")));
+    assert_eq!(forwarded_for[3].as_deref(), Some("192.0.2.2"));
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_untrusted_forwarded_for_is_ignored() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (forwarded_for, x) = spawn_app(&a, json!([])).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    // localhost is never limited. without a trusted proxy, the header can't make it look like anyone else
+    for _ in 0..5 {
+        assert_eq!(get_balance(&r, &x, "192.0.2.1").await, StatusCode::OK);
+    }
+
+    let forwarded_for = forwarded_for.lock().clone();
+    assert_eq!(forwarded_for.len(), 5);
+    assert!(forwarded_for
+        .iter()
+        .all(|x| x.as_deref() == Some("127.0.0.1")));
+
+    x.wait_for_stop();
+}
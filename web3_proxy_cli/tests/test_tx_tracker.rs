@@ -0,0 +1,115 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::{H256, U256, U64},
+    types::{transaction::eip2718::TypedTransaction, Address, Eip1559TransactionRequest},
+};
+use web3_proxy::prelude::http::StatusCode;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::Value;
+use web3_proxy::prelude::tokio::{self, time::sleep};
+use web3_proxy::prelude::web3_proxy_client::types::{TxState, TxStatus};
+use web3_proxy::prelude::web3_proxy_client::Web3ProxyClient;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+/// poll the status api until the transaction is in the given state
+async fn wait_for_tx_state(
+    client: &Web3ProxyClient,
+    tx_hash: H256,
+    state: fn(&TxState) -> bool,
+) -> TxStatus {
+    for _ in 0..100 {
+        if let Ok(x) = client.tx_status(tx_hash).await {
+            if state(&x.status.state) {
+                return x;
+            }
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("transaction never reached the expected state");
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_tx_tracker_confirms_relayed_tx() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let r = reqwest::Client::new();
+
+    let proxy_url = x.proxy_provider.url();
+
+    let client = Web3ProxyClient::new(proxy_url.clone()).with_http_client(r.clone());
+
+    // unknown transactions are a 404
+    let unknown = client.tx_status(H256::zero()).await.unwrap_err();
+    assert_eq!(unknown.status(), Some(StatusCode::NOT_FOUND));
+
+    // stop automining so that we can see the pending state
+    a.provider
+        .request::<_, ()>("evm_setAutomine", [false])
+        .await
+        .unwrap();
+
+    let wallet = a.wallet(0);
+
+    let gas_price: U256 = x.proxy_provider.request("eth_gasPrice", ()).await.unwrap();
+
+    let tx = TypedTransaction::Eip1559(Eip1559TransactionRequest {
+        chain_id: Some(31337.into()),
+        to: Some(Address::repeat_byte(0x42).into()),
+        gas: Some(21000.into()),
+        value: Some(1.into()),
+        max_fee_per_gas: Some(gas_price * U256::from(2)),
+        nonce: Some(0.into()),
+        ..Default::default()
+    });
+
+    let sig = wallet.sign_transaction_sync(&tx).unwrap();
+
+    let raw_tx = tx.rlp_signed(&sig);
+
+    let tx_hash: H256 = x
+        .proxy_provider
+        .request("eth_sendRawTransaction", [raw_tx])
+        .await
+        .unwrap();
+    info!(?tx_hash);
+
+    let pending = wait_for_tx_state(&client, tx_hash, |x| *x == TxState::Pending).await;
+    info!(?pending);
+    assert_eq!(pending.tx_hash, tx_hash);
+
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+    let mined: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let confirmed =
+        wait_for_tx_state(&client, tx_hash, |x| matches!(x, TxState::Confirmed { .. })).await;
+    info!(?confirmed);
+    let TxState::Confirmed { block_num, .. } = confirmed.status.state else {
+        unreachable!();
+    };
+    assert_eq!(block_num, mined);
+
+    let status: Value = r
+        .get(format!("{}status", proxy_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(tx_tracker=?status["tx_tracker"]);
+    assert_eq!(status["tx_tracker"]["processed_txs"], 1);
+    assert_eq!(status["tx_tracker"]["confirmed"], 1);
+    assert_eq!(status["tx_tracker"]["tracked"], 1);
+    assert_eq!(status["tx_tracker"]["evicted"], 0);
+    assert_eq!(status["tx_tracker"]["expired"], 0);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::prelude::ethers::{
+    prelude::{Block, TxHash, U64},
+    providers::{Middleware, Provider, Ws},
+};
+use web3_proxy::prelude::futures::StreamExt;
+use web3_proxy::prelude::tokio::{self, time::timeout};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::TestApp;
+
+/// unsubscribing stops one subscription and leaves the websocket and its other subscriptions alone
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_unsubscribe() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let ws_url = x.proxy_provider.url().as_str().replacen("http", "ws", 1);
+
+    let ws = Provider::<Ws>::connect(&ws_url).await.unwrap();
+
+    let first = ws.subscribe_blocks().await.unwrap();
+    let mut second = ws.subscribe_blocks().await.unwrap();
+
+    let first_id = first.id;
+    info!(?first_id, second_id=?second.id);
+
+    // ids are per connection
+    assert_ne!(first_id, second.id);
+
+    assert!(first.unsubscribe().await.unwrap());
+
+    // it's already gone
+    let again: bool = ws.request("eth_unsubscribe", [first_id]).await.unwrap();
+    assert!(!again);
+
+    // the websocket is still open and the other subscription still gets heads
+    a.provider.request::<_, U64>("evm_mine", ()).await.unwrap();
+
+    let head: Block<TxHash> = timeout(Duration::from_secs(5), second.next())
+        .await
+        .unwrap()
+        .unwrap();
+    info!(number=?head.number);
+
+    let block_number: U64 = ws.request("eth_blockNumber", ()).await.unwrap();
+    assert!(block_number >= head.number.unwrap());
+
+    x.wait_for_stop();
+}
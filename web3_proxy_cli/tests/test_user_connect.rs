@@ -0,0 +1,111 @@
+use std::net::TcpListener;
+use tracing::info;
+use web3_proxy::prelude::entities::rpc_key;
+use web3_proxy::prelude::ethers::prelude::{Http, Middleware, Provider, Ws, U64};
+use web3_proxy::prelude::migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use web3_proxy::prelude::reqwest::{self, StatusCode};
+use web3_proxy::prelude::serde_json::Value;
+use web3_proxy::prelude::tokio;
+use web3_proxy_cli::test_utils::create_user::login;
+use web3_proxy_cli::test_utils::{TestAnvil, TestApp, TestMysql};
+
+/// every login leaves the user with exactly one key, and `/user/connect` gives urls that work
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_user_connect() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    // the base url needs the port before the app starts
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut top_config = TestApp::top_config(&a, Some(&db), None, None);
+
+    top_config.app.public_base_url = Some(format!("http://127.0.0.1:{}/", port));
+
+    let x = TestApp::spawn_with_top_config_and_port(top_config, port).await;
+
+    let r = reqwest::Client::new();
+
+    let user_wallet = a.wallet(0);
+
+    let (status, first_login) = login(&x, &r, &user_wallet, None).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(first_login.rpc_keys.len(), 1);
+
+    for _ in 0..2 {
+        let (status, user_login) = login(&x, &r, &user_wallet, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            user_login.rpc_keys.keys().collect::<Vec<_>>(),
+            first_login.rpc_keys.keys().collect::<Vec<_>>()
+        );
+    }
+
+    // a user without any keys gets one at their next login. but only one
+    let db_conn = db.conn().await;
+
+    rpc_key::Entity::delete_many()
+        .filter(rpc_key::Column::UserId.eq(first_login.user.id))
+        .exec(&db_conn)
+        .await
+        .unwrap();
+
+    let (_, user_login) = login(&x, &r, &user_wallet, None).await;
+    assert_eq!(user_login.rpc_keys.len(), 1);
+
+    let (_, user_login) = login(&x, &r, &user_wallet, None).await;
+    assert_eq!(user_login.rpc_keys.len(), 1);
+
+    let num_keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(first_login.user.id))
+        .all(&db_conn)
+        .await
+        .unwrap()
+        .len();
+    assert_eq!(num_keys, 1);
+
+    let connect: Value = r
+        .get(format!("{}user/connect", x.proxy_provider.url()))
+        .bearer_auth(user_login.bearer_token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    info!(?connect);
+
+    let rpc_keys = connect["rpc_keys"].as_array().unwrap();
+    assert_eq!(rpc_keys.len(), 1);
+
+    let connect_key = &rpc_keys[0];
+
+    assert_eq!(connect_key["active"], true);
+    assert!(connect_key["limits"]["user_tier"].is_string());
+
+    let http_url = connect_key["http_url"].as_str().unwrap();
+    let ws_url = connect_key["ws_url"].as_str().unwrap();
+
+    assert!(http_url.starts_with(&format!("http://127.0.0.1:{}/rpc/", port)));
+    assert!(ws_url.starts_with(&format!("ws://127.0.0.1:{}/rpc/", port)));
+
+    let http_provider = Provider::<Http>::try_from(http_url).unwrap();
+
+    let chain_id: U64 = http_provider.request("eth_chainId", ()).await.unwrap();
+    assert_eq!(chain_id, 31337.into());
+
+    let ws_provider = Provider::<Ws>::connect(ws_url).await.unwrap();
+
+    let block_number = ws_provider.get_block_number().await.unwrap();
+    info!(%block_number);
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,129 @@
+use axum::response::Response;
+use std::sync::Arc;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::hashbrown::HashMap;
+use web3_proxy::prelude::parking_lot::Mutex;
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{json, Value};
+use web3_proxy::prelude::tokio;
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::{spawn_mock_backend, MockRequest, TestApp, TopConfigBuilder};
+
+/// how many times each ots_ method was called
+type OtterscanCalls = Mutex<HashMap<String, u64>>;
+
+/// Forwards to anvil, but also answers a few otterscan methods. Anvil says "method not found" for those
+async fn otterscan(calls: Arc<OtterscanCalls>, request: MockRequest) -> Response {
+    let method = request.method().to_string();
+
+    if method.starts_with("ots_") {
+        let num_calls = {
+            let mut calls = calls.lock();
+            let x = calls.entry(method.clone()).or_default();
+            *x += 1;
+            *x
+        };
+
+        return request.result(json!({"method": method, "num_calls": num_calls}));
+    }
+
+    request.forward().await
+}
+
+async fn proxy_request(x: &TestApp, method: &str, params: Value) -> Value {
+    let body: Value = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    info!(%method, %body);
+
+    body
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_vendor_methods() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // a fresh head so the consensus isn't stale
+    a.provider
+        .request::<_, Value>("evm_mine", ())
+        .await
+        .unwrap();
+
+    let otterscan_calls = Arc::new(OtterscanCalls::default());
+
+    let otterscan_url = spawn_mock_backend(&a, otterscan_calls.clone(), otterscan);
+
+    let top_config = TopConfigBuilder::new(31337)
+        .app(json!({
+            "unknown_methods": "block",
+            "vendor_methods": {
+                "ots_getApiLevel": {
+                    "rpcs": ["otterscan"],
+                    "cache": true,
+                },
+                "ots_searchTransactionsBefore": {
+                    "rpcs": ["otterscan"],
+                    "compute_units": 100,
+                    "timeout_secs": 30,
+                },
+            },
+        }))
+        .balanced_rpc(
+            "anvil",
+            Web3RpcConfig {
+                http_url: Some(a.instance.endpoint()),
+                soft_limit: 1_000_000,
+                ..Default::default()
+            },
+        )
+        .http_rpc("otterscan", otterscan_url)
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    // the second request is served from the cache
+    for _ in 0..2 {
+        let body = proxy_request(&x, "ots_getApiLevel", json!([])).await;
+
+        assert_eq!(body["result"]["num_calls"], 1, "{}", body);
+    }
+
+    // every request is sent to otterscan. none of them go to anvil even though it is preferred for everything else
+    for i in 1..=3 {
+        let body = proxy_request(
+            &x,
+            "ots_searchTransactionsBefore",
+            json!(["0x0000000000000000000000000000000000000001", 0, 25]),
+        )
+        .await;
+
+        assert_eq!(body["result"]["num_calls"], i, "{}", body);
+    }
+
+    // methods that aren't listed still get the unknown_methods policy
+    let body = proxy_request(&x, "ots_getInternalOperations", json!(["0x00"])).await;
+    assert_eq!(body["error"]["code"], -32601, "{}", body);
+
+    assert_eq!(
+        *otterscan_calls.lock(),
+        HashMap::from([
+            ("ots_getApiLevel".to_string(), 1),
+            ("ots_searchTransactionsBefore".to_string(), 3),
+        ])
+    );
+
+    // everything else still works
+    let body = proxy_request(&x, "eth_chainId", json!([])).await;
+    assert_eq!(body["result"], "0x7a69");
+
+    x.wait_for_stop();
+}
@@ -0,0 +1,237 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::prelude::ethers::prelude::Address;
+use web3_proxy::prelude::futures::{future::join_all, SinkExt, StreamExt};
+use web3_proxy::prelude::reqwest;
+use web3_proxy::prelude::serde_json::{self, json, Value};
+use web3_proxy::prelude::tokio::{
+    self,
+    sync::mpsc,
+    time::{sleep, Instant},
+};
+use web3_proxy::test_utils::TestAnvil;
+use web3_proxy_cli::test_utils::mock_backend::{serve, AnvilHttp};
+use web3_proxy_cli::test_utils::{TestApp, TopConfigBuilder};
+
+/// a backend that only has a websocket. requests are answered by anvil's http endpoint
+struct Vendor {
+    anvil: AnvilHttp,
+    /// close the socket instead of answering the next eth_getBalance
+    drop_next_balance: AtomicBool,
+    /// every eth_getBalance, including the ones that were dropped
+    balances: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+async fn ws_vendor(ws: WebSocketUpgrade, State(vendor): State<Arc<Vendor>>) -> Response {
+    vendor.connections.fetch_add(1, Ordering::SeqCst);
+
+    ws.on_upgrade(move |socket| handle_socket(socket, vendor))
+}
+
+async fn handle_socket(socket: WebSocket, vendor: Arc<Vendor>) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let close = matches!(msg, Message::Close(_));
+
+            if ws_tx.send(msg).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    while let Some(Ok(Message::Text(text))) = ws_rx.next().await {
+        let request: Value = serde_json::from_str(&text).unwrap();
+
+        let method = request["method"].as_str().unwrap().to_string();
+
+        match method.as_str() {
+            "eth_subscribe" => {
+                let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+                tx.send(Message::Text(response.to_string())).unwrap();
+
+                // anvil's heads. polled over http
+                let vendor = vendor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut last = Value::Null;
+
+                    loop {
+                        let block = vendor
+                            .anvil
+                            .request(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["latest", false]}))
+                            .await["result"]
+                            .clone();
+
+                        if block != last {
+                            let notification = json!({
+                                "jsonrpc": "2.0",
+                                "method": "eth_subscription",
+                                "params": {"subscription": "0x1", "result": block},
+                            });
+
+                            if tx.send(Message::Text(notification.to_string())).is_err() {
+                                break;
+                            }
+
+                            last = block;
+                        }
+
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                });
+            }
+            "eth_getBalance" => {
+                vendor.balances.fetch_add(1, Ordering::SeqCst);
+
+                if vendor.drop_next_balance.swap(false, Ordering::SeqCst) {
+                    let _ = tx.send(Message::Close(None));
+                    return;
+                }
+
+                answer(&vendor, &tx, request);
+            }
+            _ => answer(&vendor, &tx, request),
+        }
+    }
+}
+
+/// answered in the background so that responses come back out of order like they might from a real node
+fn answer(vendor: &Arc<Vendor>, tx: &mpsc::UnboundedSender<Message>, request: Value) {
+    let vendor = vendor.clone();
+    let tx = tx.clone();
+
+    tokio::spawn(async move {
+        let response = vendor.anvil.request(&request).await;
+        let _ = tx.send(Message::Text(response.to_string()));
+    });
+}
+
+async fn spawn_app(a: &TestAnvil) -> (Arc<Vendor>, TestApp) {
+    let vendor = Arc::new(Vendor {
+        anvil: AnvilHttp::new(a),
+        drop_next_balance: AtomicBool::new(false),
+        balances: AtomicUsize::new(0),
+        connections: AtomicUsize::new(0),
+    });
+
+    let router = Router::new()
+        .route("/", get(ws_vendor))
+        .with_state(vendor.clone());
+
+    let ws_url = format!("ws://{}", serve(router));
+
+    let top_config = TopConfigBuilder::new(31337)
+        .balanced_rpc(
+            "ws_vendor",
+            Web3RpcConfig {
+                ws_url: Some(ws_url),
+                // long enough that a hang would fail the test
+                ws_request_timeout_ms: 60_000,
+                ..Default::default()
+            },
+        )
+        .build();
+
+    let x = TestApp::spawn_with_top_config(top_config).await;
+
+    (vendor, x)
+}
+
+async fn get_balance(x: &TestApp) -> (Option<String>, Value) {
+    let response = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        // a random address so that nothing is served from the cache
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [Address::random(), "latest"]}))
+        .send()
+        .await
+        .unwrap();
+
+    let backend_rpcs = response
+        .headers()
+        .get("X-W3P-BACKEND-RPCS")
+        .map(|x| x.to_str().unwrap().to_string());
+
+    let body: Value = response.json().await.unwrap();
+
+    info!(%body);
+
+    (backend_rpcs, body)
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_requests_over_ws_only_backend() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (vendor, x) = spawn_app(&a).await;
+
+    let (backend_rpcs, body) = get_balance(&x).await;
+    assert_eq!(body["result"], "0x0", "{}", body);
+    assert_eq!(backend_rpcs.as_deref(), Some("ws_vendor"));
+
+    // concurrent requests share the socket and get their own answers back
+    let responses = join_all((0..20).map(|_| get_balance(&x))).await;
+    for (_, body) in responses {
+        assert_eq!(body["result"], "0x0", "{}", body);
+    }
+
+    assert_eq!(vendor.balances.load(Ordering::SeqCst), 21);
+
+    x.wait_for_stop();
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_ws_disconnect_fails_in_flight_requests() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let (vendor, x) = spawn_app(&a).await;
+
+    assert_eq!(get_balance(&x).await.1["result"], "0x0");
+
+    let connections = vendor.connections.load(Ordering::SeqCst);
+
+    vendor.drop_next_balance.store(true, Ordering::SeqCst);
+
+    // the request either errors or is retried on the new socket. it does not wait for ws_request_timeout_ms
+    let start = Instant::now();
+    let (_, body) = get_balance(&x).await;
+    assert!(start.elapsed() < Duration::from_secs(10), "{}", body);
+
+    // the rpc reconnects
+    let start = Instant::now();
+    while vendor.connections.load(Ordering::SeqCst) == connections {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "never reconnected"
+        );
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let start = Instant::now();
+    loop {
+        let (_, body) = get_balance(&x).await;
+
+        if body["result"] == "0x0" {
+            break;
+        }
+
+        assert!(start.elapsed() < Duration::from_secs(10), "{}", body);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    x.wait_for_stop();
+}